@@ -0,0 +1,78 @@
+//! Virtual clocks for the simulated nodes. Every node gets it's own offset
+//! from the simulation's (true) time and it's own drift rate, the engine
+//! only ever sees the node's clocks. Suspicion timers, TTLs and
+//! last-writer-wins all behave just fine with perfectly synchronized clocks,
+//! which real clusters never have.
+//!
+//! Times are in milliseconds since the simulation started.
+
+use std::rand::Rng;
+
+#[deriving(Show, Clone, PartialEq)]
+pub struct Clock {
+    /// How far (in milliseconds) the node's wall clock is ahead of the true
+    /// one when the simulation starts, negative if it's behind.
+    pub offset: i64,
+    /// How much faster the node's clocks run, in parts per million (i.e.,
+    /// 100 gains 100 milliseconds every 1000 seconds). Negative runs slow.
+    pub drift: i64
+}
+
+impl Clock {
+    pub fn synced() -> Clock {
+        Clock::skewed(0, 0)
+    }
+
+    pub fn skewed(offset: i64, drift: i64) -> Clock {
+        assert!(drift > -1_000_000, "clocks can't run backwards");
+        Clock {
+            offset: offset,
+            drift: drift
+        }
+    }
+
+    /// Up to `max_offset` milliseconds off, drifting up to `max_drift`
+    /// parts per million either way. Seed the generator to replay a run.
+    pub fn random<R: Rng>(rng: &mut R, max_offset: i64, max_drift: i64) -> Clock {
+        Clock::skewed(rng.gen_range(-max_offset, max_offset + 1),
+                      rng.gen_range(-max_drift, max_drift + 1))
+    }
+
+    /// The node's monotonic clock at the true time, it starts out at 0
+    /// with the simulation.
+    pub fn now(&self, time: u64) -> u64 {
+        (time as i64 + time as i64 * self.drift / 1_000_000) as u64
+    }
+
+    /// The node's wall clock at the true time, `epoch` being the true wall
+    /// clock when the simulation started.
+    pub fn wall(&self, epoch: u64, time: u64) -> u64 {
+        let wall = epoch as i64 + self.offset + self.now(time) as i64;
+        if wall < 0 { 0 } else { wall as u64 }
+    }
+}
+
+/// A clock for each of the `n` nodes, see `Clock::random`.
+pub fn skewed<R: Rng>(rng: &mut R, n: uint, max_offset: i64, max_drift: i64) -> Vec<Clock> {
+    range(0, n).map(|_| Clock::random(rng, max_offset, max_drift)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rand::{XorShiftRng, SeedableRng};
+
+    #[test]
+    fn offset_and_drift() {
+        let clock = Clock::skewed(-5_000, 1_000);
+        assert_eq!(clock.now(0), 0);
+        assert_eq!(clock.now(60_000), 60_060);
+        assert_eq!(clock.wall(1_000_000, 60_000), 1_055_060);
+        assert_eq!(Clock::synced().wall(1_000_000, 60_000), 1_060_000);
+
+        let mut rng: XorShiftRng = SeedableRng::from_seed([1, 2, 3, 4]);
+        for clock in skewed(&mut rng, 10, 500, 100).iter() {
+            assert!(clock.offset.abs() <= 500 && clock.drift.abs() <= 100);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Deferred acknowledgements for local delivery. Subscribers that opt in
+//! ack every broadcast once they're done processing it; anything left
+//! unacked past the timeout is delivered again, giving applications
+//! at-least-once processing. The node keeps what's still unacked on disk
+//! too (see `Config::ack_path`), so a restart doesn't lose any of it.
+
+use std::collections::HashMap;
+use uuid::Uuid;
+
+struct Pending<T> {
+    value: T,
+    deadline: u64,
+    deliveries: uint
+}
+
+pub struct AckTracker<T> {
+    timeout: u64,
+    pending: HashMap<Uuid, Pending<T>>
+}
+
+impl<T: Clone> AckTracker<T> {
+    pub fn new(timeout: u64) -> AckTracker<T> {
+        AckTracker {
+            timeout: timeout,
+            pending: HashMap::new()
+        }
+    }
+
+    pub fn len(&self) -> uint {
+        self.pending.len()
+    }
+
+    /// Everything waiting for an ack: the id, how many times it was
+    /// delivered and when it's delivered again.
+    pub fn pending(&self) -> Vec<(Uuid, uint, u64)> {
+        self.pending.iter().map(|(id, p)| (*id, p.deliveries, p.deadline)).collect()
+    }
+
+    /// The values waiting for an ack, i.e., to keep them around.
+    pub fn values<'a>(&'a self) -> Vec<&'a T> {
+        self.pending.values().map(|p| &p.value).collect()
+    }
+
+    /// Start waiting for an ack of the value that was just delivered.
+    pub fn track(&mut self, id: Uuid, value: T, now: u64) {
+        self.pending.insert(id, Pending {
+            value: value,
+            deadline: now + self.timeout,
+            deliveries: 1
+        });
+    }
+
+    /// Returns `false` if there was nothing to ack (i.e., it was already
+    /// acked before).
+    pub fn ack(&mut self, id: &Uuid) -> bool {
+        self.pending.remove(id)
+    }
+
+    /// The values whose ack is overdue. They're expected to be delivered
+    /// again, so their deadline starts over.
+    pub fn due(&mut self, now: u64) -> Vec<T> {
+        let mut due = Vec::new();
+
+        for (_, pending) in self.pending.mut_iter() {
+            if now >= pending.deadline {
+                pending.deadline = now + self.timeout;
+                pending.deliveries += 1;
+                due.push(pending.value.clone());
+            }
+        }
+
+        due
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn redeliver_until_acked() {
+        let mut tracker = AckTracker::new(100);
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+
+        tracker.track(a, 1u, 0);
+        tracker.track(b, 2u, 50);
+        assert_eq!(tracker.due(99), vec![]);
+        assert_eq!(tracker.due(100), vec![1u]);
+
+        assert!(tracker.ack(&b));
+        assert!(!tracker.ack(&b));
+        assert_eq!(tracker.due(150), vec![]);
+        assert_eq!(tracker.due(200), vec![1u]);
+
+        assert!(tracker.ack(&a));
+        assert_eq!(tracker.len(), 0);
+    }
+}
@@ -0,0 +1,184 @@
+//! Admin commands, applied one at a time in the order they were submitted
+//! (see `Node::submit`). Each caller gets told how it's command went: it
+//! applied, it was rejected (and why) or it didn't apply before
+//! `Config::admin_timeout`. An eviction without the admin key only applies
+//! once enough other members voted for it too, and nothing after it runs
+//! until then.
+
+use std::collections::{RingBuf, Deque};
+use uuid::Uuid;
+
+use config::Config;
+use dump;
+
+#[deriving(Show, Clone)]
+pub enum Command {
+    /// Evict the member from the cluster, see `Node::evict`.
+    EvictMember(Uuid),
+    Cordon,
+    Uncordon,
+    /// Use a new key for the (namespace qualified) topic, see
+    /// `Node::set_topic_secret`.
+    RotateKey(String, Vec<u8>),
+    /// Switch to the configuration. Only settings the node picks up while
+    /// running (see `LIVE`) may differ from the current ones.
+    Reconfigure(Config)
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum CommandResult {
+    Applied,
+    Rejected(String),
+    /// Still queued (or waiting on the other members) at the deadline.
+    TimedOut
+}
+
+/// The settings read anew every time they're needed, the others only take
+/// effect on restart.
+pub static LIVE: &'static [&'static str] = &[
+    "admin_key", "quorum", "minority_lockdown", "merkle_threshold", "max_updates_per_round",
+    "latency_sampling", "ihave_max_ids", "shutdown_stagger", "bootstrap_timeout",
+    "max_versions", "max_epoch_jump", "max_queued_frames"
+];
+
+/// The settings changed from one configuration to the other that only
+/// take effect on restart.
+pub fn needs_restart(current: &Config, new: &Config) -> Vec<String> {
+    let current = dump::settings(current);
+    dump::settings(new).move_iter()
+                       .filter(|setting| !current.contains(setting))
+                       .map(|(setting, _)| setting)
+                       .filter(|setting| !LIVE.contains(&setting.as_slice()))
+                       .collect()
+}
+
+struct Queued {
+    command: Command,
+    deadline: u64,
+    result: Sender<CommandResult>
+}
+
+pub struct CommandQueue {
+    timeout: u64,
+    queued: RingBuf<Queued>,
+    /// The command being applied. Nothing else runs until it's done.
+    running: Option<Queued>
+}
+
+impl CommandQueue {
+    pub fn new(timeout: u64) -> CommandQueue {
+        CommandQueue {
+            timeout: timeout,
+            queued: RingBuf::new(),
+            running: None
+        }
+    }
+
+    /// Queue the command up, it's result comes through the receiver.
+    pub fn submit(&mut self, command: Command, now: u64) -> Receiver<CommandResult> {
+        let (tx, rx) = channel();
+        self.queued.push_back(Queued {
+            command: command,
+            deadline: now + self.timeout,
+            result: tx
+        });
+        rx
+    }
+
+    /// The next command to apply, none until the running one is done.
+    /// Commands past their deadline time out instead.
+    pub fn next(&mut self, now: u64) -> Option<Command> {
+        let overdue = match self.running {
+            Some(ref running) => now >= running.deadline,
+            None => false
+        };
+        if overdue {
+            self.finish(TimedOut);
+        }
+        if self.running.is_some() {
+            return None;
+        }
+
+        loop {
+            match self.queued.pop_front() {
+                Some(queued) if now >= queued.deadline => {
+                    // Nobody might be listening anymore.
+                    let _ = queued.result.send_opt(TimedOut);
+                },
+                Some(queued) => {
+                    let command = queued.command.clone();
+                    self.running = Some(queued);
+                    return Some(command);
+                },
+                None => return None
+            }
+        }
+    }
+
+    pub fn running<'a>(&'a self) -> Option<&'a Command> {
+        self.running.as_ref().map(|running| &running.command)
+    }
+
+    /// Tell the caller how the running command went, letting the next one
+    /// run.
+    pub fn finish(&mut self, result: CommandResult) {
+        match self.running.take() {
+            Some(running) => {
+                let _ = running.result.send_opt(result);
+            },
+            None => {}
+        }
+    }
+
+    pub fn len(&self) -> uint {
+        self.queued.len() + if self.running.is_some() { 1 } else { 0 }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+    use config::Config;
+
+    #[test]
+    fn run_in_order_one_at_a_time() {
+        let mut queue = CommandQueue::new(1_000);
+        let target = Uuid::new_v4();
+        let evicted = queue.submit(EvictMember(target), 0);
+        let cordoned = queue.submit(Cordon, 0);
+        let late = queue.submit(Uncordon, 500);
+
+        match queue.next(0) {
+            Some(EvictMember(id)) => assert_eq!(id, target),
+            command => fail!("expected the eviction, got {}", command)
+        }
+        // The eviction is still waiting on the votes.
+        assert!(queue.next(100).is_none());
+        assert_eq!(queue.len(), 3);
+
+        // Out of time, and so is the cordon queued along with it.
+        match queue.next(1_000) {
+            Some(Uncordon) => {},
+            command => fail!("expected the uncordon, got {}", command)
+        }
+        queue.finish(Applied);
+        assert_eq!(evicted.recv(), TimedOut);
+        assert_eq!(cordoned.recv(), TimedOut);
+        assert_eq!(late.recv(), Applied);
+        assert!(queue.next(1_000).is_none());
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn only_reconfigure_live_settings() {
+        let current = Config::new();
+        let mut new = current.clone();
+        new.quorum = Some(3);
+        new.minority_lockdown = true;
+        assert_eq!(needs_restart(&current, &new), Vec::<String>::new());
+
+        new.relay = !current.relay;
+        assert_eq!(needs_restart(&current, &new), vec!["relay".to_string()]);
+    }
+}
@@ -0,0 +1,147 @@
+//! Anti-entropy, for whatever the rest of the protocol dropped. Every
+//! `Config::anti_entropy_interval` we pick a live member at random and tell
+//! it what we know in brief (a `SyncDigest`): every member with it's
+//! incarnation and liveness, ourselves included, and the ids of the
+//! broadcasts we retain. It sends back what we're missing or only know a
+//! stale version of, the changes as a `Digest` and the broadcasts as they
+//! are, along with it's own digest for us to do the same (push-pull). After
+//! a long partition or a burst of dropped messages, both sides eventually
+//! reconcile this way, one pair of members at a time.
+//!
+//! Members retaining more broadcasts than `Config::merkle_threshold` don't
+//! list them, the pair finds what differs through a Merkle tree instead,
+//! see `merkle`.
+
+use std::collections::{HashMap, HashSet};
+use std::rand::{task_rng, Rng, XorShiftRng};
+use uuid::Uuid;
+
+use member::{Membership, MemberState, Alive, Dead};
+use message::{Update, Joined, LivenessAt, Moved, Refuted};
+
+/// A member in brief: it's id, incarnation and liveness.
+pub type MemberDigest = (Uuid, u64, MemberState);
+
+pub struct AntiEntropy {
+    interval: Option<u64>,
+    next: u64,
+    rng: XorShiftRng
+}
+
+impl AntiEntropy {
+    pub fn new(interval: Option<u64>, now: u64) -> AntiEntropy {
+        AntiEntropy {
+            interval: interval,
+            next: now + interval.unwrap_or(0),
+            rng: task_rng().gen()
+        }
+    }
+
+    /// Whether a round is due, scheduling the next one if so.
+    pub fn due(&mut self, now: u64) -> bool {
+        match self.interval {
+            Some(interval) if now >= self.next => {
+                self.next = now + interval;
+                true
+            },
+            _ => false
+        }
+    }
+
+    /// Who to sync with this round.
+    pub fn pick(&mut self, candidates: &[Uuid]) -> Option<Uuid> {
+        self.rng.choose(candidates).map(|id| *id)
+    }
+}
+
+/// What we know of the members, in brief.
+pub fn digest(members: &Membership) -> Vec<MemberDigest> {
+    members.iter().map(|member| (member.id(), member.incarnation, member.state)).collect()
+}
+
+/// The changes the member (`from`) is missing, going by it's digest. It
+/// knows best about itself, and members it never heard of are only worth
+/// telling it about while they're alive. Members it takes for suspect or
+/// dead that refuted it since are alive again.
+pub fn missing(members: &Membership, from: &Uuid, theirs: &[MemberDigest]) -> Vec<Update> {
+    let theirs: HashMap<Uuid, (u64, MemberState)> = theirs.iter()
+        .map(|&(id, incarnation, state)| (id, (incarnation, state)))
+        .collect();
+
+    let mut updates = Vec::new();
+    for member in members.iter().filter(|member| member.id() != *from) {
+        match theirs.find(&member.id()) {
+            None if member.state == Alive => updates.push(Joined(member.peer.clone())),
+            None => {},
+            Some(&(incarnation, state)) => {
+                if member.incarnation > incarnation && member.state == Alive && state != Alive {
+                    updates.push(Refuted(member.peer.clone(), member.incarnation));
+                } else if member.incarnation > incarnation {
+                    updates.push(Moved(member.peer.clone(), member.incarnation));
+                }
+                if member.state == Dead && state != Dead && member.incarnation >= incarnation {
+                    updates.push(LivenessAt(member.id(), Dead, member.incarnation));
+                }
+            }
+        }
+    }
+    updates
+}
+
+/// Which of the broadcasts we retain the member is missing.
+pub fn unseen(retained: &[Uuid], theirs: &[Uuid]) -> Vec<Uuid> {
+    let theirs: HashSet<&Uuid> = theirs.iter().collect();
+    retained.iter().filter(|id| !theirs.contains(id)).map(|id| *id).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+    use member::{Membership, Alive, Suspect, Dead};
+    use message::{Joined, LivenessAt, Moved, Refuted};
+    use protocol::Peer;
+
+    #[test]
+    fn tell_what_they_are_missing() {
+        let mut members = Membership::new();
+        let (known, unknown, moved, dead, from) = (Peer::empty(), Peer::empty(), Peer::empty(),
+                                                   Peer::empty(), Peer::empty());
+        for peer in [&known, &unknown, &moved, &dead, &from].iter() {
+            members.insert((*peer).clone());
+        }
+        members.relocate(&moved.id(), moved.addr(), Some(3));
+        members.set_state(&dead.id(), Dead);
+
+        let theirs = vec![(known.id(), 0, Alive), (moved.id(), 1, Alive),
+                          (dead.id(), 0, Suspect)];
+        let mut updates = missing(&members, &from.id(), theirs.as_slice());
+        updates.sort_by(|a, b| format!("{}", a).cmp(&format!("{}", b)));
+        let mut expected = vec![Joined(unknown.clone()), Moved(moved.clone(), 3),
+                                LivenessAt(dead.id(), Dead, 0)];
+        expected.sort_by(|a, b| format!("{}", a).cmp(&format!("{}", b)));
+        assert_eq!(updates, expected);
+
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        assert_eq!(unseen(&[a, b], &[b]), vec![a]);
+
+        // It takes the member for dead, but it refuted that since.
+        let theirs = vec![(known.id(), 0, Alive), (moved.id(), 0, Dead), (dead.id(), 0, Dead),
+                          (unknown.id(), 0, Alive)];
+        assert_eq!(missing(&members, &from.id(), theirs.as_slice()),
+                   vec![Refuted(moved.clone(), 3)]);
+    }
+
+    #[test]
+    fn sync_every_interval() {
+        let mut rounds = AntiEntropy::new(Some(100), 0);
+        assert!(!rounds.due(99));
+        assert!(rounds.due(100));
+        assert!(!rounds.due(150));
+        assert!(rounds.due(200));
+
+        let mut never = AntiEntropy::new(None, 0);
+        assert!(!never.due(1_000_000));
+        assert_eq!(never.pick(&[]), None);
+    }
+}
@@ -0,0 +1,92 @@
+//! The non-blocking driver, meant to be embedded within an existing event
+//! loop. It never blocks, sleeps or spawns: the host calls `poll` whenever
+//! it's convenient and at the latest by the time `poll` asked for.
+
+use engine::{Engine, Input, WallClock, Tick};
+use driver::Handler;
+use driver;
+use clock;
+
+pub struct Async<H> {
+    engine: Engine,
+    handler: H,
+    tx: Sender<Input>,
+    rx: Receiver<Input>,
+    /// When (in milliseconds) the engine is due for it's next tick.
+    next_tick: u64
+}
+
+impl<H: Handler> Async<H> {
+    pub fn new(engine: Engine, handler: H) -> Async<H> {
+        let (tx, rx) = channel();
+
+        Async {
+            engine: engine,
+            handler: handler,
+            tx: tx,
+            rx: rx,
+            next_tick: 0
+        }
+    }
+
+    pub fn engine<'a>(&'a self) -> &'a Engine {
+        &self.engine
+    }
+
+    /// A sender other tasks can queue inputs through. They're handled on
+    /// the next `poll`.
+    pub fn sender(&self) -> Sender<Input> {
+        self.tx.clone()
+    }
+
+    /// Handle a single input right away.
+    pub fn push(&mut self, input: Input, now: u64) {
+        for output in driver::handle(&mut self.engine, input, now).move_iter() {
+            self.handler.perform(output);
+        }
+    }
+
+    /// Handle every queued input and tick the engine if it's due. Returns
+    /// when (in milliseconds) the driver wants to be polled again.
+    pub fn poll(&mut self, now: u64) -> u64 {
+        loop {
+            match self.rx.try_recv() {
+                Ok(input) => self.push(input, now),
+                Err(_) => break
+            }
+        }
+
+        if now >= self.next_tick {
+            self.push(WallClock(clock::wall()), now);
+            self.push(Tick, now);
+            self.next_tick = now + self.engine.config().tick_interval;
+        }
+
+        self.next_tick
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+    use engine::{Engine, Publish, Deliver};
+    use broadcast::Broadcast;
+    use config::Config;
+
+    #[test]
+    fn poll_queued_inputs() {
+        let (tx, rx) = channel();
+        let mut driver = Async::new(Engine::new(Uuid::new_v4(), Config::new()), tx);
+
+        driver.sender().send(Publish(Broadcast::new(vec![1u8]).unwrap()));
+        assert!(rx.try_recv().is_err());
+
+        assert_eq!(driver.poll(1000), 1050);
+        assert_eq!(driver.poll(1010), 1050);
+        match rx.try_recv() {
+            Ok(Deliver(broadcast)) => assert_eq!(broadcast.as_bytes(), &[1u8]),
+            _ => fail!("expected the broadcast to be delivered")
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Exponential backoff with full jitter, for everything that retries:
+//! restarting failed tasks, connecting to join a cluster and persisting
+//! the snapshot. Every attempt waits anywhere between nothing and twice
+//! as long as the one before (up to `max`), so members that failed at the
+//! same time (i.e., because the seed went away) don't retry in lockstep.
+//!
+//! A `Backoff` is the configuration, a `Retry` the attempts made under it
+//! so far. Retrying ends once the budget (attempts, time or both) is spent.
+
+use std::cmp;
+use std::io::timer;
+use std::rand::{task_rng, Rng};
+
+use clock;
+use result::GossipResult;
+
+#[deriving(Show, Clone, PartialEq)]
+pub struct Backoff {
+    /// The longest (in milliseconds) the first retry waits.
+    pub initial: u64,
+    pub max: u64,
+    /// The most attempts, the first one included. `None` doesn't limit
+    /// them.
+    pub max_attempts: Option<uint>,
+    /// How long (in milliseconds) to keep on retrying since the first
+    /// attempt. `None` doesn't limit it.
+    pub max_elapsed: Option<u64>
+}
+
+impl Backoff {
+    /// Retry forever, from `initial` up to `max`.
+    pub fn new(initial: u64, max: u64) -> Backoff {
+        Backoff {
+            initial: initial,
+            max: max,
+            max_attempts: None,
+            max_elapsed: None
+        }
+    }
+
+    /// The longest the retry after the nth failed attempt (from zero)
+    /// waits.
+    pub fn ceiling(&self, attempt: uint) -> u64 {
+        let mut ceiling = self.initial;
+        for _ in range(0, attempt) {
+            if ceiling >= self.max {
+                break;
+            }
+            ceiling *= 2;
+        }
+        cmp::min(ceiling, self.max)
+    }
+
+    pub fn start(&self, now: u64) -> Retry {
+        Retry {
+            backoff: self.clone(),
+            failures: 0,
+            started: now
+        }
+    }
+}
+
+pub struct Retry {
+    backoff: Backoff,
+    failures: uint,
+    started: u64
+}
+
+impl Retry {
+    /// An attempt failed: how long to wait before the next one, `None`
+    /// once the budget is spent. Never waits past `max_elapsed`.
+    pub fn next_delay<R: Rng>(&mut self, rng: &mut R, now: u64) -> Option<u64> {
+        self.failures += 1;
+        if self.backoff.max_attempts.map_or(false, |max| self.failures >= max) {
+            return None;
+        }
+
+        let delay = rng.gen_range(0, self.backoff.ceiling(self.failures - 1) + 1);
+        match self.backoff.max_elapsed {
+            Some(max) if now >= self.started + max => None,
+            Some(max) => Some(cmp::min(delay, self.started + max - now)),
+            None => Some(delay)
+        }
+    }
+
+    /// How many attempts failed so far.
+    pub fn failures(&self) -> uint {
+        self.failures
+    }
+}
+
+/// Run the operation until it works, a permanent error comes up or the
+/// budget is spent, sleeping in between. The last error is returned.
+pub fn retry<T>(backoff: &Backoff, op: || -> GossipResult<T>) -> GossipResult<T> {
+    let mut retry = backoff.start(clock::now());
+    let mut rng = task_rng();
+
+    loop {
+        let err = match op() {
+            Ok(value) => return Ok(value),
+            Err(err) => err
+        };
+
+        if !err.is_transient() {
+            return Err(err);
+        }
+
+        match retry.next_delay(&mut rng, clock::now()) {
+            Some(delay) => timer::sleep(delay),
+            None => return Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rand::{XorShiftRng, SeedableRng};
+    use result::{GossipResult, GossipError, NodeUnreachable, AuthFailure};
+
+    #[test]
+    fn jitter_within_the_ceiling() {
+        let backoff = Backoff::new(100, 1_000);
+        assert_eq!(backoff.ceiling(0), 100);
+        assert_eq!(backoff.ceiling(3), 800);
+        assert_eq!(backoff.ceiling(10), 1_000);
+
+        let mut rng: XorShiftRng = SeedableRng::from_seed([1, 2, 3, 4]);
+        let mut retry = backoff.start(0);
+        for attempt in range(0u, 20) {
+            let delay = retry.next_delay(&mut rng, 0).unwrap();
+            assert!(delay <= backoff.ceiling(attempt));
+        }
+        assert_eq!(retry.failures(), 20);
+    }
+
+    #[test]
+    fn stop_once_the_budget_is_spent() {
+        let mut rng: XorShiftRng = SeedableRng::from_seed([1, 2, 3, 4]);
+
+        let mut backoff = Backoff::new(100, 1_000);
+        backoff.max_attempts = Some(3);
+        let mut retry = backoff.start(0);
+        assert!(retry.next_delay(&mut rng, 0).is_some());
+        assert!(retry.next_delay(&mut rng, 0).is_some());
+        assert_eq!(retry.next_delay(&mut rng, 0), None);
+
+        let mut backoff = Backoff::new(1_000, 1_000);
+        backoff.max_elapsed = Some(500);
+        let mut retry = backoff.start(0);
+        assert!(retry.next_delay(&mut rng, 400).unwrap() <= 100);
+        assert_eq!(retry.next_delay(&mut rng, 500), None);
+    }
+
+    #[test]
+    fn only_retry_transient_errors() {
+        let mut backoff = Backoff::new(1, 1);
+        backoff.max_attempts = Some(3);
+
+        let mut attempts = 0u;
+        let result: GossipResult<()> = retry(&backoff, || {
+            attempts += 1;
+            Err(GossipError::new("unreachable", NodeUnreachable))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+
+        attempts = 0;
+        let result: GossipResult<()> = retry(&backoff, || {
+            attempts += 1;
+            Err(GossipError::new("bad key", AuthFailure))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
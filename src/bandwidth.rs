@@ -0,0 +1,190 @@
+//! Bytes sent to and received from every peer, and for every topic, over
+//! a sliding window. Meant for finding out who (or what) is behind a
+//! bandwidth spike, not for billing: the window is made of one second
+//! buckets, so totals are only as precise as that.
+
+use std::collections::{HashMap, RingBuf, Deque};
+use std::hash::Hash;
+use uuid::Uuid;
+
+#[deriving(Show, Clone, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound
+}
+
+/// Bytes moved within the window.
+#[deriving(Show, Clone, PartialEq)]
+pub struct Usage {
+    pub bytes_in: u64,
+    pub bytes_out: u64
+}
+
+/// Everything that moved within the last `window` milliseconds. Peers and
+/// topics without any traffic are left out.
+#[deriving(Show, Clone)]
+pub struct BandwidthReport {
+    pub window: u64,
+    pub peers: HashMap<Uuid, Usage>,
+    /// Keyed by the namespace qualified topic. Protocol traffic (as opposed
+    /// to broadcasts) isn't part of any topic.
+    pub topics: HashMap<String, Usage>
+}
+
+/// Bytes per one second bucket, oldest first.
+struct Window {
+    buckets: RingBuf<(u64, u64)>
+}
+
+impl Window {
+    fn new() -> Window {
+        Window {
+            buckets: RingBuf::new()
+        }
+    }
+
+    fn add(&mut self, bytes: u64, now: u64) {
+        let second = now / 1000;
+
+        match self.buckets.back_mut() {
+            Some(bucket) if *bucket.ref0() == second => {
+                *bucket.mut1() += bytes;
+                return;
+            },
+            _ => {}
+        }
+
+        self.buckets.push_back((second, bytes));
+    }
+
+    /// Forget the buckets that fell out of the window.
+    fn expire(&mut self, window: u64, now: u64) {
+        loop {
+            match self.buckets.front() {
+                Some(&(second, _)) if (second + 1) * 1000 + window <= now => {},
+                _ => break
+            }
+            self.buckets.pop_front();
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.buckets.iter().fold(0, |total, &(_, bytes)| total + bytes)
+    }
+}
+
+struct Traffic {
+    inbound: Window,
+    outbound: Window
+}
+
+impl Traffic {
+    fn new() -> Traffic {
+        Traffic {
+            inbound: Window::new(),
+            outbound: Window::new()
+        }
+    }
+
+    fn usage(&mut self, window: u64, now: u64) -> Usage {
+        self.inbound.expire(window, now);
+        self.outbound.expire(window, now);
+
+        Usage {
+            bytes_in: self.inbound.total(),
+            bytes_out: self.outbound.total()
+        }
+    }
+}
+
+pub struct Bandwidth {
+    window: u64,
+    peers: HashMap<Uuid, Traffic>,
+    topics: HashMap<String, Traffic>
+}
+
+impl Bandwidth {
+    pub fn new(window: u64) -> Bandwidth {
+        Bandwidth {
+            window: window,
+            peers: HashMap::new(),
+            topics: HashMap::new()
+        }
+    }
+
+    pub fn record(&mut self, peer: Uuid, topic: Option<String>, direction: Direction,
+                  bytes: uint, now: u64) {
+        add(&mut self.peers, peer, direction, bytes, now);
+
+        match topic {
+            Some(topic) => add(&mut self.topics, topic, direction, bytes, now),
+            None => {}
+        }
+    }
+
+    /// Report the usage within the window, forgetting about anybody who went
+    /// quiet.
+    pub fn report(&mut self, now: u64) -> BandwidthReport {
+        BandwidthReport {
+            window: self.window,
+            peers: usage(&mut self.peers, self.window, now),
+            topics: usage(&mut self.topics, self.window, now)
+        }
+    }
+}
+
+fn add<K: Hash + Eq>(traffic: &mut HashMap<K, Traffic>, key: K, direction: Direction,
+                     bytes: uint, now: u64) {
+    let traffic = traffic.find_or_insert_with(key, |_| Traffic::new());
+    match direction {
+        Inbound => traffic.inbound.add(bytes as u64, now),
+        Outbound => traffic.outbound.add(bytes as u64, now)
+    }
+}
+
+fn usage<K: Hash + Eq + Clone>(traffic: &mut HashMap<K, Traffic>, window: u64,
+                               now: u64) -> HashMap<K, Usage> {
+    let mut report = HashMap::new();
+    let mut quiet = Vec::new();
+
+    for (key, entry) in traffic.mut_iter() {
+        let usage = entry.usage(window, now);
+        if usage.bytes_in == 0 && usage.bytes_out == 0 {
+            quiet.push(key.clone());
+        } else {
+            report.insert(key.clone(), usage);
+        }
+    }
+
+    for key in quiet.iter() {
+        traffic.remove(key);
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn sliding_window() {
+        let mut bandwidth = Bandwidth::new(10_000);
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+
+        bandwidth.record(a, Some("billing/invoices".to_string()), Inbound, 100, 0);
+        bandwidth.record(a, None, Outbound, 20, 500);
+        bandwidth.record(b, Some("billing/invoices".to_string()), Outbound, 50, 5_000);
+
+        let report = bandwidth.report(5_000);
+        assert_eq!(report.peers.find(&a), Some(&Usage { bytes_in: 100, bytes_out: 20 }));
+        assert_eq!(report.topics.find_equiv(&"billing/invoices"),
+                   Some(&Usage { bytes_in: 100, bytes_out: 50 }));
+
+        let report = bandwidth.report(11_000);
+        assert!(report.peers.find(&a).is_none());
+        assert_eq!(report.topics.find_equiv(&"billing/invoices"),
+                   Some(&Usage { bytes_in: 0, bytes_out: 50 }));
+    }
+}
@@ -0,0 +1,85 @@
+//! Poking at a cluster from the command line. For now that's diffing
+//! state dumps (see `Node::dump_state`), the ones taken before and after an
+//! incident or on two nodes at once:
+//!
+//! ```notrust
+//! gossipctl diff before.json after.json
+//! ```
+//!
+//! Like `diff`, it exits with 0 when nothing changed, 1 when something did
+//! and 2 when it couldn't tell.
+
+extern crate gossip;
+
+use std::os;
+use std::fmt::Show;
+use gossip::dump;
+use gossip::dump::{StateDump, MemberDump, MemberAdded, MemberRemoved, MemberChanged};
+
+static USAGE: &'static str = "usage: gossipctl diff <before> <after>";
+
+fn main() {
+    let args = os::args();
+    let command = args.as_slice().get(1).map(|command| command.as_slice());
+    let status = match command {
+        Some("diff") if args.len() == 4 => {
+            diff(&Path::new(args.get(2).as_slice()), &Path::new(args.get(3).as_slice()))
+        },
+        _ => {
+            os::set_exit_status(2);
+            println!("{}", USAGE);
+            return;
+        }
+    };
+    os::set_exit_status(status);
+}
+
+fn diff(before: &Path, after: &Path) -> int {
+    let dumps = match (StateDump::load(before), StateDump::load(after)) {
+        (Ok(before), Ok(after)) => (before, after),
+        (Err(e), _) | (_, Err(e)) => {
+            println!("gossipctl: {}", e);
+            return 2;
+        }
+    };
+    let changes = dump::diff(dumps.ref0(), dumps.ref1());
+    if changes.is_empty() {
+        return 0;
+    }
+
+    if !changes.members.is_empty() {
+        println!("members:");
+    }
+    for change in changes.members.iter() {
+        match *change {
+            MemberAdded(ref member) => println!("  + {}", shown(member)),
+            MemberRemoved(ref member) => println!("  - {}", shown(member)),
+            MemberChanged(ref was, ref is) => println!("  ~ {} -> {}", shown(was), shown(is))
+        }
+    }
+    if !changes.links.is_empty() {
+        println!("links:");
+    }
+    for change in changes.links.iter() {
+        println!("  {}: {} -> {}", change.peer, or_none(&change.from), or_none(&change.to));
+    }
+    if !changes.config.is_empty() {
+        println!("config:");
+    }
+    for drift in changes.config.iter() {
+        println!("  {}: {} -> {}", drift.setting, or_none(&drift.from), or_none(&drift.to));
+    }
+    1
+}
+
+fn shown(member: &MemberDump) -> String {
+    format!("{} at {} ({}, {}, incarnation {})", member.id, member.addr, member.state,
+            member.status, member.incarnation)
+}
+
+fn or_none<T: Show>(value: &Option<T>) -> String {
+    match *value {
+        Some(ref value) => format!("{}", value),
+        None => "none".to_string()
+    }
+}
@@ -0,0 +1,111 @@
+//! Bootstrapping of critical topics after a join. Some topics (i.e., the
+//! current configuration) are useless to wait on until their next publish,
+//! so once the full-state sync is in, a joiner listing them in
+//! `Config::bootstrap_topics` asks the member that synced it for the latest
+//! broadcast it retains of each. Until the answer (or
+//! `Config::bootstrap_timeout`) the joiner advertises itself as
+//! `Bootstrapping` rather than `Active`.
+
+use std::collections::HashSet;
+use uuid::Uuid;
+
+use broadcast::Broadcast;
+
+pub struct Bootstrap {
+    /// Who we asked.
+    from: Uuid,
+    /// The topics nothing was delivered of yet.
+    pending: HashSet<String>,
+    deadline: u64
+}
+
+impl Bootstrap {
+    pub fn new(from: Uuid, topics: &[String], deadline: u64) -> Bootstrap {
+        Bootstrap {
+            from: from,
+            pending: topics.iter().map(|topic| topic.clone()).collect(),
+            deadline: deadline
+        }
+    }
+
+    pub fn from(&self) -> Uuid {
+        self.from
+    }
+
+    /// A broadcast of the topic was delivered, whatever is retained of it
+    /// can only be older.
+    pub fn delivered(&mut self, topic: &str) {
+        self.pending.remove(&topic.to_string());
+    }
+
+    /// Of the broadcasts we were answered with, the ones to deliver.
+    pub fn answered(&mut self, broadcasts: Vec<Broadcast>) -> Vec<Broadcast> {
+        let mut fresh = Vec::new();
+
+        for broadcast in broadcasts.move_iter() {
+            if self.pending.remove(&broadcast.topic()) {
+                fresh.push(broadcast);
+            }
+        }
+
+        fresh
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.deadline
+    }
+}
+
+/// The latest of the broadcasts (oldest first) for each of the topics, in
+/// the order they came.
+pub fn latest<'a>(broadcasts: Vec<&'a Broadcast>, topics: &[String]) -> Vec<&'a Broadcast> {
+    let mut seen = HashSet::new();
+    let mut latest = Vec::new();
+
+    for broadcast in broadcasts.move_iter().rev() {
+        let topic = broadcast.topic();
+        if topics.contains(&topic) && seen.insert(topic) {
+            latest.push(broadcast);
+        }
+    }
+
+    latest.reverse();
+    latest
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    use broadcast::Broadcast;
+
+    #[test]
+    fn latest_per_topic() {
+        let (a, b) = (Broadcast::new(vec![1u8]).unwrap(), Broadcast::new(vec![2u8]).unwrap());
+        let mut other = Broadcast::new(vec![3u8]).unwrap();
+        other.set_namespace("billing");
+        let (a_topic, other_topic) = (a.topic(), other.topic());
+
+        let found = latest(vec![&a, &other, &b], &[a_topic.clone()]);
+        assert_eq!(found.iter().map(|b| b.id()).collect::<Vec<Uuid>>(), vec![b.id()]);
+
+        let found = latest(vec![&a, &other, &b], &[a_topic, other_topic]);
+        assert_eq!(found.iter().map(|b| b.id()).collect::<Vec<Uuid>>(), vec![other.id(), b.id()]);
+    }
+
+    #[test]
+    fn skip_topics_delivered_meanwhile() {
+        let a = Broadcast::new(vec![1u8]).unwrap();
+        let mut other = Broadcast::new(vec![2u8]).unwrap();
+        other.set_namespace("billing");
+
+        let mut bootstrap = Bootstrap::new(Uuid::new_v4(), &[a.topic(), other.topic()], 100);
+        bootstrap.delivered(a.topic().as_slice());
+
+        let fresh = bootstrap.answered(vec![a.clone(), other.clone()]);
+        assert_eq!(fresh.iter().map(|b| b.id()).collect::<Vec<Uuid>>(), vec![other.id()]);
+        assert!(!bootstrap.is_expired(99));
+        assert!(bootstrap.is_expired(100));
+    }
+}
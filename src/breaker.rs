@@ -0,0 +1,151 @@
+//! Per-peer circuit breakers. Connecting to a peer that fails permanently
+//! (bad credentials, a different protocol) is pointless, so we stop trying
+//! until a reset interval has elapsed. Transient failures (timeouts,
+//! refused connections) only trip the breaker after several in a row.
+//! Once the interval elapses the breaker is half-open: a single trial goes
+//! through, and it's outcome decides whether the breaker closes or opens
+//! again.
+
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use result::{GossipError, Transient, Permanent};
+
+enum State {
+    Closed,
+    /// No attempts allowed until this time.
+    Open(u64),
+    /// A trial went through at this time, none other does until it's
+    /// outcome is in (or it got lost, after the reset interval).
+    HalfOpen(u64)
+}
+
+struct Breaker {
+    /// Consecutive transient failures.
+    failures: uint,
+    state: State
+}
+
+pub struct Breakers {
+    reset_interval: u64,
+    transient_threshold: uint,
+    peers: HashMap<Uuid, Breaker>
+}
+
+impl Breakers {
+    pub fn new(reset_interval: u64, transient_threshold: uint) -> Breakers {
+        Breakers {
+            reset_interval: reset_interval,
+            transient_threshold: transient_threshold,
+            peers: HashMap::new()
+        }
+    }
+
+    /// Whether we may attempt to talk to the peer. Once the reset interval
+    /// elapses a single attempt is let through as the trial; it's outcome
+    /// decides if the breaker closes again.
+    pub fn allow(&mut self, id: &Uuid, now: u64) -> bool {
+        if self.is_open(id, now) {
+            return false;
+        }
+        match self.peers.find_mut(id) {
+            Some(breaker) => match breaker.state {
+                Closed => {},
+                _ => breaker.state = HalfOpen(now)
+            },
+            None => {}
+        }
+        true
+    }
+
+    /// Whether attempts are refused, i.e., the breaker is open or it's
+    /// trial is still out.
+    pub fn is_open(&self, id: &Uuid, now: u64) -> bool {
+        match self.peers.find(id).map(|breaker| breaker.state) {
+            Some(Open(until)) => now < until,
+            Some(HalfOpen(since)) => now < since + self.reset_interval,
+            _ => false
+        }
+    }
+
+    pub fn succeeded(&mut self, id: &Uuid) {
+        self.peers.remove(id);
+    }
+
+    pub fn failed(&mut self, id: Uuid, err: &GossipError, now: u64) {
+        if !self.peers.contains_key(&id) {
+            self.peers.insert(id, Breaker { failures: 0, state: Closed });
+        }
+
+        let breaker = self.peers.find_mut(&id).unwrap();
+        let trip = match (breaker.state, err.class()) {
+            // The trial failed, whatever the reason.
+            (HalfOpen(_), _) | (_, Permanent) => true,
+            (_, Transient) => {
+                breaker.failures += 1;
+                breaker.failures >= self.transient_threshold
+            }
+        };
+
+        if trip {
+            breaker.state = Open(now + self.reset_interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use result::{GossipError, NodeUnreachable, ProtocolMismatch};
+    use uuid::Uuid;
+
+    #[test]
+    fn permanent_errors_trip_immediately() {
+        let mut breakers = Breakers::new(1000, 3);
+        let id = Uuid::new_v4();
+
+        breakers.failed(id, &GossipError::new("wrong protocol", ProtocolMismatch), 0);
+        assert!(breakers.is_open(&id, 999));
+        assert!(!breakers.allow(&id, 999));
+        assert!(breakers.allow(&id, 1000));
+
+        breakers.succeeded(&id);
+        assert!(breakers.allow(&id, 0));
+    }
+
+    #[test]
+    fn transient_errors_need_a_streak() {
+        let mut breakers = Breakers::new(1000, 3);
+        let id = Uuid::new_v4();
+        let err = GossipError::new("timed out", NodeUnreachable);
+
+        breakers.failed(id, &err, 0);
+        breakers.failed(id, &err, 0);
+        assert!(breakers.allow(&id, 0));
+
+        breakers.failed(id, &err, 0);
+        assert!(breakers.is_open(&id, 0));
+    }
+
+    #[test]
+    fn let_a_single_trial_through() {
+        let mut breakers = Breakers::new(1000, 3);
+        let id = Uuid::new_v4();
+        let err = GossipError::new("timed out", NodeUnreachable);
+        breakers.failed(id, &GossipError::new("wrong protocol", ProtocolMismatch), 0);
+
+        // Half-open: the trial goes through, nothing else until it's back.
+        assert!(breakers.allow(&id, 1000));
+        assert!(!breakers.allow(&id, 1500));
+
+        // A failed trial opens the breaker again, transient or not.
+        breakers.failed(id, &err, 1500);
+        assert!(breakers.is_open(&id, 2499));
+        assert!(breakers.allow(&id, 2500));
+
+        // A trial that got lost is followed by another.
+        assert!(breakers.allow(&id, 3500));
+        breakers.succeeded(&id);
+        assert!(breakers.allow(&id, 3500) && breakers.allow(&id, 3500));
+    }
+}
@@ -2,12 +2,15 @@
 
 use uuid::Uuid;
 use std::collections::hashmap::HashSet;
-use std::io::MemReader;
+use std::io::{MemReader, MemWriter};
+use std::str;
 
 use result::{GossipResult, GossipError, UnknownError, io_err};
 use stream::Stream;
+use namespace::{DEFAULT_NAMESPACE, qualify};
+use trace::TraceContext;
 
-#[deriving(PartialEq, Show)]
+#[deriving(PartialEq, Show, Clone)]
 pub struct Version(u8);
 
 /// Broadcast represents a single bi-directional communication with two
@@ -44,6 +47,23 @@ pub struct Broadcast {
     /// the response (if applicable).
     id: Uuid,
     version: Version,
+    /// The node that originally sent the broadcast out, which isn't
+    /// necessarily the node that relayed it to us.
+    origin: Uuid,
+    /// The tenant namespace the broadcast belongs to.
+    namespace: String,
+    /// How many times the broadcast has been relayed so far.
+    hops: u32,
+    /// The trace the broadcast is part of, if the origin provided one.
+    trace: Option<TraceContext>,
+    /// The origin's wall clock (in milliseconds) when it sent the broadcast,
+    /// zero until then.
+    sent_at: u64,
+    /// The origin's wall clock time after which the broadcast mustn't be
+    /// delivered anymore.
+    deadline: Option<u64>,
+    /// Whether the payload is sealed with the topic's key, see `Keyring`.
+    sealed: bool,
     /// A tag represents the type of message it is without needing a physical type to decode it to.
     /// Since we may not always have that information.
     tag: String,
@@ -60,12 +80,62 @@ impl Broadcast {
         let mut reader = MemReader::new(bytes.clone());
         let version = try!(reader.read_byte().map_err(io_err));
         let tag = "foo".to_string();
-        Ok(Broadcast {
+        let mut broadcast = Broadcast {
             id: Uuid::new_v4(),
             version: Version(version),
+            origin: Uuid::nil(),
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            hops: 0,
+            trace: None,
+            sent_at: 0,
+            deadline: None,
+            sealed: false,
             tag: tag,
             reader: reader,
             committed: HashSet::new()
+        };
+        // Tagged bytes carry their own tag, so it survives the wire.
+        let tagged = broadcast.tagged_data().map(|(tag, _)| tag.to_string());
+        match tagged {
+            Some(tag) => broadcast.tag = tag,
+            None => {}
+        }
+        Ok(broadcast)
+    }
+
+    /// A broadcast of the data, tagged in the format above.
+    pub fn tagged(tag: &str, data: &[u8]) -> Broadcast {
+        // Writing to memory never fails.
+        let mut wr = MemWriter::new();
+        wr.write_u8(1).unwrap();
+        wr.write_be_u32(tag.len() as u32).unwrap();
+        wr.write_str(tag).unwrap();
+        wr.write_be_u32(data.len() as u32).unwrap();
+        wr.write(data).unwrap();
+
+        Broadcast::new(wr.unwrap()).unwrap()
+    }
+
+    /// The tag and data of a broadcast in the format above, `None` for
+    /// bytes that aren't.
+    pub fn tagged_data<'a>(&'a self) -> Option<(&'a str, &'a [u8])> {
+        let bytes = self.as_bytes();
+        let len = |at: uint| -> Option<uint> {
+            if bytes.len() < at + 4 {
+                None
+            } else {
+                Some(bytes.slice(at, at + 4).iter().fold(0u, |len, &b| len << 8 | b as uint))
+            }
+        };
+
+        let tag_len = match len(1) { Some(len) => len, None => return None };
+        let data_at = 1 + 4 + tag_len;
+        let data_len = match len(data_at) { Some(len) => len, None => return None };
+        if bytes.len() != data_at + 4 + data_len {
+            return None;
+        }
+        str::from_utf8(bytes.slice(5, data_at)).map(|tag| {
+            (tag, bytes.slice_from(data_at + 4))
         })
     }
 
@@ -76,6 +146,130 @@ impl Broadcast {
     pub fn id(&self) -> Uuid {
         self.id
     }
+
+    /// The id travels with the broadcast, see `codec`.
+    pub fn set_id(&mut self, id: Uuid) {
+        self.id = id;
+    }
+
+    /// The topic the broadcast belongs to.
+    pub fn tag<'a>(&'a self) -> &'a str {
+        self.tag.as_slice()
+    }
+
+    pub fn namespace<'a>(&'a self) -> &'a str {
+        self.namespace.as_slice()
+    }
+
+    pub fn set_namespace(&mut self, namespace: &str) {
+        self.namespace = namespace.to_string();
+    }
+
+    /// The topic qualified with the namespace, i.e., `billing/config`.
+    pub fn topic(&self) -> String {
+        qualify(self.namespace(), self.tag())
+    }
+
+    pub fn origin(&self) -> Uuid {
+        self.origin
+    }
+
+    pub fn set_origin(&mut self, origin: Uuid) {
+        self.origin = origin;
+    }
+
+    pub fn hops(&self) -> u32 {
+        self.hops
+    }
+
+    /// Called by every relay before forwarding the broadcast.
+    pub fn hop(&mut self) {
+        self.hops += 1;
+    }
+
+    /// As many hops as the wire says it took so far.
+    pub fn set_hops(&mut self, hops: u32) {
+        self.hops = hops;
+    }
+
+    /// The trace context, with the parent being the span of the node that
+    /// last relayed the broadcast.
+    pub fn trace<'a>(&'a self) -> Option<&'a TraceContext> {
+        self.trace.as_ref()
+    }
+
+    /// Make the broadcast part of a trace, see `TraceContext::parse`.
+    pub fn set_trace(&mut self, trace: TraceContext) {
+        self.trace = Some(trace);
+    }
+
+    /// Continue the trace (if any) within a span of the given node.
+    pub fn trace_through(&mut self, node: &Uuid) {
+        let id = self.id;
+        self.trace = self.trace.as_ref().map(|trace| trace.child(&id, node));
+    }
+
+    pub fn sent_at(&self) -> u64 {
+        self.sent_at
+    }
+
+    pub fn set_sent_at(&mut self, sent_at: u64) {
+        self.sent_at = sent_at;
+    }
+
+    pub fn deadline(&self) -> Option<u64> {
+        self.deadline
+    }
+
+    /// Drop the broadcast instead of delivering it once the origin's wall
+    /// clock (in milliseconds since the unix epoch) passed `deadline`. The
+    /// hop count alone can't stop a node from acting on a command it gets
+    /// ten minutes late after a partition healed.
+    pub fn set_deadline(&mut self, deadline: u64) {
+        self.deadline = Some(deadline);
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+
+    pub fn set_sealed(&mut self, sealed: bool) {
+        self.sealed = sealed;
+    }
+
+    /// Replace the raw bytes, i.e., with the sealed ones.
+    pub fn set_bytes(&mut self, bytes: Vec<u8>) {
+        self.reader = MemReader::new(bytes);
+    }
+
+    /// The raw bytes of the full broadcast.
+    pub fn as_bytes<'a>(&'a self) -> &'a [u8] {
+        self.reader.get_ref()
+    }
+
+    /// The size of the raw broadcast in bytes.
+    pub fn len(&self) -> uint {
+        self.as_bytes().len()
+    }
+}
+
+impl Clone for Broadcast {
+    fn clone(&self) -> Broadcast {
+        Broadcast {
+            id: self.id,
+            version: self.version.clone(),
+            origin: self.origin,
+            namespace: self.namespace.clone(),
+            hops: self.hops,
+            trace: self.trace.clone(),
+            sent_at: self.sent_at,
+            deadline: self.deadline,
+            sealed: self.sealed,
+            tag: self.tag.clone(),
+            reader: MemReader::new(self.as_bytes().to_vec()),
+            committed: self.committed.clone()
+        }
+    }
 }
 
 #[cfg(test)]
@@ -93,4 +287,13 @@ mod test {
         let Version(ver) = broadcast.version;
         assert_eq!(ver, 1u8);
     }
+
+    #[test]
+    fn tag_the_data() {
+        let broadcast = Broadcast::tagged("config", &[7u8, 8]);
+        assert_eq!(broadcast.tag(), "config");
+        assert_eq!(broadcast.tagged_data(), Some(("config", [7u8, 8].as_slice())));
+        assert_eq!(Broadcast::new(broadcast.as_bytes().to_vec()).unwrap().tag(), "config");
+        assert_eq!(Broadcast::new(vec![1u8, 0, 0, 0, 9]).unwrap().tagged_data(), None);
+    }
 }
@@ -0,0 +1,70 @@
+//! A cap on the CPU time the protocol may take per interval, for hosts that
+//! can't afford gossip hogging their event loop. The drivers time every
+//! input they feed the engine and charge it here; once the budget of the
+//! current interval is spent the engine defers whatever can wait (flushing
+//! the retransmit queue, heartbeats) until the next one.
+//!
+//! Deferred work is only ever delayed, never dropped.
+
+pub struct Budget {
+    /// Nanoseconds per interval, `None` for no cap at all.
+    limit: Option<u64>,
+    /// The length of an interval in milliseconds.
+    interval: u64,
+    started: u64,
+    spent: u64
+}
+
+impl Budget {
+    pub fn new(limit: Option<u64>, interval: u64) -> Budget {
+        Budget {
+            limit: limit,
+            interval: interval,
+            started: 0,
+            spent: 0
+        }
+    }
+
+    /// Charge the nanoseconds spent processing at `now` (milliseconds).
+    pub fn charge(&mut self, spent: u64, now: u64) {
+        self.roll(now);
+        self.spent += spent;
+    }
+
+    /// Whether the budget of the current interval is spent.
+    pub fn exhausted(&mut self, now: u64) -> bool {
+        self.roll(now);
+        match self.limit {
+            Some(limit) => self.spent >= limit,
+            None => false
+        }
+    }
+
+    /// Start a new interval if the current one is over.
+    fn roll(&mut self, now: u64) {
+        if now >= self.started + self.interval {
+            self.started = now;
+            self.spent = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reset_every_interval() {
+        let mut budget = Budget::new(Some(1_000_000), 100);
+        budget.charge(600_000, 0);
+        assert!(!budget.exhausted(10));
+
+        budget.charge(600_000, 20);
+        assert!(budget.exhausted(30));
+        assert!(!budget.exhausted(100));
+
+        let mut unlimited = Budget::new(None, 100);
+        unlimited.charge(1_000_000_000, 0);
+        assert!(!unlimited.exhausted(0));
+    }
+}
@@ -0,0 +1,159 @@
+//! Recording inbound frames (with the time they arrived) to a file, and
+//! replaying them into an `Engine`, so a bug seen in production can be
+//! reproduced exactly on a developer's machine. The engine doesn't do any
+//! I/O nor read any clock, so the same frames at the same times lead to
+//! the same outputs.
+//!
+//! Format:
+//!
+//! ```notrust
+//! bitdata Cassette {
+//!     Cassette {
+//!         magic: [u8, ..4], // "GCAS"
+//!         version: u8,
+//!         frames: &[Frame]
+//!     }
+//! }
+//!
+//! bitdata Frame {
+//!     Frame {
+//!         at: u64, // monotonic milliseconds
+//!         size: u32,
+//!         frame: &[u8]
+//!     }
+//! }
+//! ```
+
+use std::io::{File, BufferedWriter, BufferedReader, EndOfFile};
+
+use engine::{Engine, Input, Tick};
+use driver::Handler;
+use driver;
+use result::{GossipResult, GossipError, ProtocolMismatch, io_err};
+
+static MAGIC: &'static [u8] = b"GCAS";
+static VERSION: u8 = 1;
+
+/// Appends frames to a cassette.
+pub struct Recorder {
+    writer: BufferedWriter<File>
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> GossipResult<Recorder> {
+        let mut writer = BufferedWriter::new(try!(File::create(path).map_err(io_err)));
+        try!(writer.write(MAGIC).map_err(io_err));
+        try!(writer.write_u8(VERSION).map_err(io_err));
+
+        Ok(Recorder {
+            writer: writer
+        })
+    }
+
+    /// Record a frame as it came off the wire. Frames are flushed right
+    /// away, so the cassette survives the crash we're after.
+    pub fn record(&mut self, at: u64, frame: &[u8]) -> GossipResult<()> {
+        try!(self.writer.write_be_u64(at).map_err(io_err));
+        try!(self.writer.write_be_u32(frame.len() as u32).map_err(io_err));
+        try!(self.writer.write(frame).map_err(io_err));
+        self.writer.flush().map_err(io_err)
+    }
+}
+
+/// Every frame of a cassette, in the order they were recorded.
+pub fn load(path: &Path) -> GossipResult<Vec<(u64, Vec<u8>)>> {
+    let mut reader = BufferedReader::new(try!(File::open(path).map_err(io_err)));
+
+    let magic = try!(reader.read_exact(MAGIC.len()).map_err(io_err));
+    if magic.as_slice() != MAGIC || try!(reader.read_u8().map_err(io_err)) != VERSION {
+        return Err(GossipError::new("not a cassette we understand", ProtocolMismatch));
+    }
+
+    let mut frames = Vec::new();
+    loop {
+        let at = match reader.read_be_u64() {
+            Ok(at) => at,
+            Err(ref err) if err.kind == EndOfFile => break,
+            Err(err) => return Err(io_err(err))
+        };
+
+        let size = try!(reader.read_be_u32().map_err(io_err)) as uint;
+        frames.push((at, try!(reader.read_exact(size).map_err(io_err))));
+    }
+
+    Ok(frames)
+}
+
+/// Feed the frames to the engine at the times they were recorded, decoding
+/// them with whatever the transport used. The engine is ticked every
+/// `Config::tick_interval` in between, like the drivers would. Frames that
+/// don't decode are skipped, returns how many were.
+pub fn replay<H: Handler>(engine: &mut Engine, handler: &mut H, frames: Vec<(u64, Vec<u8>)>,
+                          decode: |&[u8]| -> Option<Input>) -> uint {
+    let interval = engine.config().tick_interval;
+    let mut next_tick = match frames.as_slice().head() {
+        Some(&(at, _)) => at,
+        None => return 0
+    };
+    let mut skipped = 0;
+
+    for (at, frame) in frames.move_iter() {
+        while next_tick <= at {
+            for output in driver::handle(engine, Tick, next_tick).move_iter() {
+                handler.perform(output);
+            }
+            next_tick += interval;
+        }
+
+        match decode(frame.as_slice()) {
+            Some(input) => {
+                for output in driver::handle(engine, input, at).move_iter() {
+                    handler.perform(output);
+                }
+            },
+            None => skipped += 1
+        }
+    }
+
+    skipped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::TempDir;
+    use uuid::Uuid;
+    use engine::{Engine, Publish, Deliver};
+    use broadcast::Broadcast;
+    use config::Config;
+
+    #[test]
+    fn record_and_replay() {
+        let dir = TempDir::new("cassette").unwrap();
+        let path = dir.path().join("inbound");
+
+        {
+            let mut recorder = Recorder::create(&path).unwrap();
+            recorder.record(10, &[1u8, 2]).unwrap();
+            recorder.record(20, &[]).unwrap();
+            recorder.record(30, &[1u8, 3]).unwrap();
+        }
+
+        let frames = load(&path).unwrap();
+        assert_eq!(frames, vec![(10, vec![1u8, 2]), (20, vec![]), (30, vec![1u8, 3])]);
+
+        let (mut tx, rx) = channel();
+        let mut engine = Engine::new(Uuid::new_v4(), Config::new());
+        let skipped = replay(&mut engine, &mut tx, frames, |frame| {
+            Broadcast::new(frame.to_vec()).ok().map(|b| Publish(b))
+        });
+        drop(tx);
+
+        assert_eq!(skipped, 1);
+        let delivered: Vec<Vec<u8>> = rx.iter().filter_map(|output| match output {
+            Deliver(broadcast) => Some(broadcast.as_bytes().to_vec()),
+            _ => None
+        }).collect();
+        assert_eq!(delivered, vec![vec![1u8, 2], vec![1u8, 3]]);
+    }
+}
@@ -0,0 +1,172 @@
+//! End-to-end encryption of broadcast payloads with keys the application
+//! provides, on top of whatever the transport does. The origin seals the
+//! payload and only subscribers holding the topic's key can open it:
+//! relays forward the ciphertext as is, so members we trust less can take
+//! part in the broadcast tree without reading along.
+//!
+//! We don't ship any ciphers ourselves; plug in an AEAD (i.e., ChaCha20
+//! with Poly1305) from your crypto library of choice through `Cipher`. The
+//! broadcast id is unique and makes for the nonce, the version byte stays
+//! readable so relays can still make sense of the broadcast. It's
+//! authenticated along with the topic, so neither can be swapped out from
+//! under the ciphertext.
+//!
+//! Topics listed in `Config::sealed_topics` only ever carry sealed
+//! broadcasts: publishing on them fails until the key is set (whichever
+//! driver publishes), and plaintext ones are neither relayed nor delivered.
+
+use std::collections::HashMap;
+
+use broadcast::Broadcast;
+
+/// An authenticated cipher holding the key of a topic.
+pub trait Cipher: Send {
+    /// Seal the plaintext, authenticating the associated data (`aad`)
+    /// along with it without encrypting it.
+    fn seal(&self, nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Vec<u8>;
+
+    /// `None` if the ciphertext wasn't sealed with our key and the same
+    /// associated data, or got tampered with.
+    fn open(&self, nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// What's authenticated along with the payload, the part of the broadcast
+/// relays read: the version byte and the topic.
+fn aad(broadcast: &Broadcast) -> Vec<u8> {
+    let mut aad = vec![broadcast.as_bytes()[0]];
+    aad.push_all(broadcast.topic().as_bytes());
+    aad
+}
+
+/// The keys of every encrypted topic, keyed by the namespace qualified
+/// topic (see `Broadcast::topic`).
+pub struct Keyring {
+    ciphers: HashMap<String, Box<Cipher + Send>>
+}
+
+impl Keyring {
+    pub fn new() -> Keyring {
+        Keyring {
+            ciphers: HashMap::new()
+        }
+    }
+
+    pub fn insert(&mut self, topic: &str, cipher: Box<Cipher + Send>) {
+        self.ciphers.insert(topic.to_string(), cipher);
+    }
+
+    pub fn remove(&mut self, topic: &str) {
+        self.ciphers.remove(&topic.to_string());
+    }
+
+    /// Seal the payload if we hold a key for the broadcast's topic.
+    pub fn seal(&self, broadcast: &mut Broadcast) {
+        if broadcast.is_sealed() {
+            return;
+        }
+
+        let sealed = match self.ciphers.find(&broadcast.topic()) {
+            Some(cipher) => {
+                let bytes = broadcast.as_bytes();
+                let mut sealed = vec![bytes[0]];
+                let sealing = cipher.seal(broadcast.id().as_bytes(), aad(broadcast).as_slice(),
+                                          bytes.slice_from(1));
+                sealed.push_all(sealing.as_slice());
+                sealed
+            },
+            None => return
+        };
+
+        broadcast.set_bytes(sealed);
+        broadcast.set_sealed(true);
+    }
+
+    /// Open a sealed payload. Returns `false` if we can't: we don't hold
+    /// the key or it's not the one it was sealed with.
+    pub fn open(&self, broadcast: &mut Broadcast) -> bool {
+        if !broadcast.is_sealed() {
+            return true;
+        }
+
+        let opened = match self.ciphers.find(&broadcast.topic()) {
+            Some(cipher) => {
+                let bytes = broadcast.as_bytes();
+                let aad = aad(broadcast);
+                match cipher.open(broadcast.id().as_bytes(), aad.as_slice(), bytes.slice_from(1)) {
+                    Some(plaintext) => {
+                        let mut opened = vec![bytes[0]];
+                        opened.push_all(plaintext.as_slice());
+                        opened
+                    },
+                    None => return false
+                }
+            },
+            None => return false
+        };
+
+        broadcast.set_bytes(opened);
+        broadcast.set_sealed(false);
+        true
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use broadcast::Broadcast;
+
+    /// Not a cipher anybody should use: xor with the key, then append the
+    /// key and the associated data xor'ed together as the "tag".
+    pub struct Xor(pub u8);
+
+    fn tag(key: u8, aad: &[u8]) -> u8 {
+        aad.iter().fold(key, |tag, b| tag ^ *b)
+    }
+
+    impl Cipher for Xor {
+        fn seal(&self, _: &[u8], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+            let Xor(key) = *self;
+            let mut sealed: Vec<u8> = plaintext.iter().map(|b| *b ^ key).collect();
+            sealed.push(tag(key, aad));
+            sealed
+        }
+
+        fn open(&self, _: &[u8], aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+            let Xor(key) = *self;
+            match ciphertext.last() {
+                Some(&t) if t == tag(key, aad) => {
+                    let body = ciphertext.slice_to(ciphertext.len() - 1);
+                    Some(body.iter().map(|b| *b ^ key).collect())
+                },
+                _ => None
+            }
+        }
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let mut broadcast = Broadcast::new(vec![1u8, 2, 3]).unwrap();
+        let topic = broadcast.topic();
+
+        let mut keys = Keyring::new();
+        keys.insert(topic.as_slice(), box Xor(7) as Box<Cipher + Send>);
+        keys.seal(&mut broadcast);
+        assert!(broadcast.is_sealed());
+        assert_eq!(broadcast.as_bytes().slice_to(3), &[1u8, 2 ^ 7, 3 ^ 7]);
+
+        // A relay holding the wrong key (or none at all) can't read it.
+        let mut relay = Keyring::new();
+        assert!(!relay.open(&mut broadcast.clone()));
+        relay.insert(topic.as_slice(), box Xor(8) as Box<Cipher + Send>);
+        assert!(!relay.open(&mut broadcast.clone()));
+
+        // Nor can it be moved to another topic under the same key.
+        let mut moved = broadcast.clone();
+        moved.set_namespace("billing");
+        keys.insert(moved.topic().as_slice(), box Xor(7) as Box<Cipher + Send>);
+        assert!(!keys.open(&mut moved));
+
+        assert!(keys.open(&mut broadcast));
+        assert_eq!(broadcast.as_bytes(), &[1u8, 2, 3]);
+    }
+}
@@ -0,0 +1,21 @@
+//! Time helpers. Everything within the protocol works in milliseconds
+//! from an arbitrary (monotonic) epoch. The wall clock is only used for
+//! deadlines set by applications.
+
+use time;
+
+/// The current monotonic time in milliseconds.
+pub fn now() -> u64 {
+    time::precise_time_ns() / 1_000_000
+}
+
+/// The current monotonic time in nanoseconds, for timing ourselves.
+pub fn precise() -> u64 {
+    time::precise_time_ns()
+}
+
+/// The current wall clock time in milliseconds since the unix epoch.
+pub fn wall() -> u64 {
+    let now = time::get_time();
+    now.sec as u64 * 1000 + now.nsec as u64 / 1_000_000
+}
@@ -0,0 +1,488 @@
+//! How broadcasts and protocol messages are put into frames. The `Codec`
+//! is pluggable (see `Node::set_codec`) so clusters can pick whatever
+//! serialization suits them, or bring their own; `JsonCodec` is the one
+//! every node starts with, `MsgPackCodec` the compact one and
+//! `ProtobufCodec` the one with a schema for other languages. All members
+//! of a cluster have to use the same.
+//!
+//! Whichever codec it is, every frame starts with a byte telling what's in
+//! it, so the codec never has to guess:
+//!
+//! ```notrust
+//! bitdata Packet {
+//!     Packet {
+//!         kind: u8, // 0 for broadcasts, 1 for messages
+//!         encoded: &[u8] // by the codec
+//!     }
+//! }
+//! ```
+//!
+//! Compressed broadcasts (see `compress`) are a kind of their own, only
+//! ever sent to members that advertised the compression:
+//!
+//! ```notrust
+//! bitdata CompressedPacket {
+//!     CompressedPacket {
+//!         kind: u8, // 2
+//!         compression: u8,
+//!         compressed: &[u8] // what the codec made of the broadcast
+//!     }
+//! }
+//! ```
+//!
+//! So are broadcasts relayed together (see `Node::broadcast_batch`), packed
+//! into as few frames as will do for the members that advertised it (see
+//! `Capabilities::with_packing`):
+//!
+//! ```notrust
+//! bitdata PackedPacket {
+//!     PackedPacket {
+//!         kind: u8, // 3
+//!         frames: &[Packed]
+//!     }
+//! }
+//!
+//! bitdata Packed {
+//!     Packed {
+//!         len: u32,
+//!         frame: &[u8] // a broadcast's, compressed or not
+//!     }
+//! }
+//! ```
+
+use std::str;
+use std::io::{MemWriter, BufReader};
+use serialize::{json, Encodable, Decodable};
+use uuid::Uuid;
+use msgpack;
+
+use broadcast::Broadcast;
+use compress;
+use message::Message;
+use trace::TraceContext;
+use result::{GossipResult, GossipError, ProtocolMismatch, io_err};
+use topic::Compression;
+
+static BROADCAST: u8 = 0;
+static MESSAGE: u8 = 1;
+static COMPRESSED: u8 = 2;
+static PACKED: u8 = 3;
+
+/// Bumped whenever what goes over the wire changes, in any codec. It's
+/// not part of the frames, it's what the golden frames members have to
+/// keep on decoding are recorded under (see `compat`).
+pub static PROTOCOL_VERSION: uint = 14;
+/// The oldest version whose frames we still decode (see `compat`), nodes
+/// speaking an older one aren't let in.
+pub static MIN_PROTOCOL_VERSION: uint = 1;
+
+/// Encodes and decodes what goes over the wire. Decoding garbage (i.e., a
+/// member using another codec) is an error, not a failure.
+pub trait Codec: Send {
+    fn encode_broadcast(&self, broadcast: &Broadcast) -> GossipResult<Vec<u8>>;
+    fn decode_broadcast(&self, bytes: &[u8]) -> GossipResult<Broadcast>;
+    /// Membership messages included, they're all a `Message`.
+    fn encode_message(&self, msg: &Message) -> GossipResult<Vec<u8>>;
+    fn decode_message(&self, bytes: &[u8]) -> GossipResult<Message>;
+}
+
+/// What a frame turned out to carry.
+pub enum Packet {
+    BroadcastPacket(Broadcast),
+    MessagePacket(Message),
+    /// Broadcasts packed together, in the order they were.
+    BatchPacket(Vec<Broadcast>)
+}
+
+pub fn broadcast_frame(codec: &Codec, broadcast: &Broadcast) -> GossipResult<Vec<u8>> {
+    Ok(vec![BROADCAST].append(try!(codec.encode_broadcast(broadcast)).as_slice()))
+}
+
+/// The broadcast's frame compressed, `None` if compressing it wouldn't make
+/// it any smaller.
+pub fn compressed_frame(codec: &Codec, broadcast: &Broadcast,
+                        compression: Compression) -> GossipResult<Option<Vec<u8>>> {
+    let encoded = try!(codec.encode_broadcast(broadcast));
+    Ok(compress::compress(compression, encoded.as_slice()).map(|compressed| {
+        vec![COMPRESSED, compress::wire(compression)].append(compressed.as_slice())
+    }))
+}
+
+/// The broadcast frames packed into as few frames of up to `limit` bytes as
+/// will do. Frames that don't fit with any other go out as they are.
+pub fn packed_frames(frames: &[Vec<u8>], limit: uint) -> Vec<Vec<u8>> {
+    let mut groups: Vec<Vec<&Vec<u8>>> = Vec::new();
+    let mut size = limit;
+    for frame in frames.iter() {
+        size += 4 + frame.len();
+        if size > limit {
+            groups.push(Vec::new());
+            size = 1 + 4 + frame.len();
+        }
+        groups.mut_last().unwrap().push(frame);
+    }
+
+    groups.move_iter().map(|group| {
+        if group.len() == 1 {
+            return (**group.get(0)).clone();
+        }
+
+        // Writing to memory can't fail.
+        let mut wr = MemWriter::new();
+        wr.write_u8(PACKED).unwrap();
+        for frame in group.iter() {
+            wr.write_be_u32(frame.len() as u32).unwrap();
+            wr.write(frame.as_slice()).unwrap();
+        }
+        wr.unwrap()
+    }).collect()
+}
+
+pub fn message_frame(codec: &Codec, msg: &Message) -> GossipResult<Vec<u8>> {
+    Ok(vec![MESSAGE].append(try!(codec.encode_message(msg)).as_slice()))
+}
+
+pub fn decode_frame(codec: &Codec, frame: &[u8]) -> GossipResult<Packet> {
+    match frame.head() {
+        Some(&BROADCAST) => Ok(BroadcastPacket(try!(codec.decode_broadcast(frame.tail())))),
+        Some(&MESSAGE) => Ok(MessagePacket(try!(codec.decode_message(frame.tail())))),
+        Some(&COMPRESSED) => {
+            let compression = match frame.get(1).and_then(|&byte| compress::from_wire(byte)) {
+                Some(compression) => compression,
+                None => return Err(GossipError::new("unknown compression", ProtocolMismatch))
+            };
+            let encoded = try!(compress::decompress(compression, frame.slice_from(2)));
+            Ok(BroadcastPacket(try!(codec.decode_broadcast(encoded.as_slice()))))
+        },
+        Some(&PACKED) => {
+            let mut rd = BufReader::new(frame.tail());
+            let mut batch = Vec::new();
+            while !rd.eof() {
+                let len = try!(rd.read_be_u32().map_err(io_err)) as uint;
+                let packed = try!(rd.read_exact(len).map_err(io_err));
+                match packed.as_slice().head() {
+                    Some(&BROADCAST) | Some(&COMPRESSED) => {},
+                    _ => return Err(GossipError::new("only broadcasts are packed",
+                                                     ProtocolMismatch))
+                }
+                match try!(decode_frame(codec, packed.as_slice())) {
+                    BroadcastPacket(broadcast) => batch.push(broadcast),
+                    _ => unreachable!()
+                }
+            }
+            Ok(BatchPacket(batch))
+        },
+        _ => Err(GossipError::new("unknown kind of frame", ProtocolMismatch))
+    }
+}
+
+/// A broadcast as codecs see it: the raw bytes along with everything about
+/// it that isn't part of them.
+#[deriving(Show, Clone, PartialEq, Encodable, Decodable)]
+pub struct WireBroadcast {
+    pub id: Uuid,
+    pub origin: Uuid,
+    pub namespace: String,
+    pub hops: u32,
+    /// As a `traceparent` header.
+    pub trace: Option<String>,
+    pub sent_at: u64,
+    pub deadline: Option<u64>,
+    pub sealed: bool,
+    pub bytes: Vec<u8>
+}
+
+impl WireBroadcast {
+    pub fn new(broadcast: &Broadcast) -> WireBroadcast {
+        WireBroadcast {
+            id: broadcast.id(),
+            origin: broadcast.origin(),
+            namespace: broadcast.namespace().to_string(),
+            hops: broadcast.hops(),
+            trace: broadcast.trace().map(|trace| trace.to_header()),
+            sent_at: broadcast.sent_at(),
+            deadline: broadcast.deadline(),
+            sealed: broadcast.is_sealed(),
+            bytes: broadcast.as_bytes().to_vec()
+        }
+    }
+
+    pub fn to_broadcast(self) -> GossipResult<Broadcast> {
+        let mut broadcast = try!(Broadcast::new(self.bytes));
+        broadcast.set_id(self.id);
+        broadcast.set_origin(self.origin);
+        broadcast.set_namespace(self.namespace.as_slice());
+        broadcast.set_hops(self.hops);
+        match self.trace.as_ref().and_then(|header| TraceContext::parse(header.as_slice())) {
+            Some(trace) => broadcast.set_trace(trace),
+            None => {}
+        }
+        broadcast.set_sent_at(self.sent_at);
+        match self.deadline {
+            Some(deadline) => broadcast.set_deadline(deadline),
+            None => {}
+        }
+        broadcast.set_sealed(self.sealed);
+        Ok(broadcast)
+    }
+}
+
+/// The default codec. Not the most compact, but any language can read it.
+pub struct JsonCodec;
+
+impl JsonCodec {
+    fn encode<'a, T: Encodable<json::Encoder<'a>, ::std::io::IoError>>(value: &T) -> Vec<u8> {
+        json::Encoder::buffer_encode(value)
+    }
+
+    fn decode<T: Decodable<json::Decoder, json::DecoderError>>(bytes: &[u8]) -> GossipResult<T> {
+        JsonCodec::decode_json(try!(JsonCodec::parse(bytes)))
+    }
+
+    fn parse(bytes: &[u8]) -> GossipResult<json::Json> {
+        match str::from_utf8(bytes).and_then(|s| json::from_str(s).ok()) {
+            Some(json) => Ok(json),
+            None => Err(undecodable_json())
+        }
+    }
+
+    fn decode_json<T: Decodable<json::Decoder, json::DecoderError>>(json: json::Json)
+                                                                     -> GossipResult<T> {
+        Decodable::decode(&mut json::Decoder::new(json)).map_err(|_| undecodable_json())
+    }
+}
+
+fn undecodable_json() -> GossipError {
+    GossipError::new("undecodable json", ProtocolMismatch)
+}
+
+/// The messages carrying an admin proof as their last field.
+static PROOFS: &'static [&'static str] = &["ShutdownCluster", "FeatureToggle", "KillNodeAt"];
+
+/// Admin proofs used to be numbers (the tag truncated to it's first 8
+/// bytes), those of older nodes are turned into the bytes they stand for.
+fn upgrade_proof(json: json::Json) -> json::Json {
+    let mut object = match json {
+        json::Object(object) => object,
+        json => return json
+    };
+    let legacy = match object.find(&"variant".to_string()) {
+        Some(&json::String(ref variant)) => PROOFS.iter().any(|name| *name == variant.as_slice()),
+        _ => false
+    };
+    if !legacy {
+        return json::Object(object);
+    }
+
+    {
+        let proof = match object.find_mut(&"fields".to_string()) {
+            Some(fields) => match *fields {
+                json::List(ref mut fields) => fields.mut_last(),
+                _ => None
+            },
+            None => None
+        };
+        match proof {
+            Some(proof) => {
+                let truncated = match *proof {
+                    json::Number(n) => Some(n as u64),
+                    _ => None
+                };
+                match truncated {
+                    Some(n) => {
+                        *proof = json::List(range(0u, 8).rev().map(|i| {
+                            json::Number((n >> (i * 8)) as u8 as f64)
+                        }).collect());
+                    },
+                    None => {}
+                }
+            },
+            None => {}
+        }
+    }
+    json::Object(object)
+}
+
+impl Codec for JsonCodec {
+    fn encode_broadcast(&self, broadcast: &Broadcast) -> GossipResult<Vec<u8>> {
+        Ok(JsonCodec::encode(&WireBroadcast::new(broadcast)))
+    }
+
+    fn decode_broadcast(&self, bytes: &[u8]) -> GossipResult<Broadcast> {
+        let wire: WireBroadcast = try!(JsonCodec::decode(bytes));
+        wire.to_broadcast()
+    }
+
+    fn encode_message(&self, msg: &Message) -> GossipResult<Vec<u8>> {
+        Ok(JsonCodec::encode(msg))
+    }
+
+    fn decode_message(&self, bytes: &[u8]) -> GossipResult<Message> {
+        JsonCodec::decode_json(upgrade_proof(try!(JsonCodec::parse(bytes))))
+    }
+}
+
+/// MessagePack, so frames are small and still readable by tooling outside
+/// of Rust (i.e., to inspect captures).
+pub struct MsgPackCodec;
+
+fn undecodable_msgpack<E>(_: E) -> GossipError {
+    GossipError::new("undecodable msgpack", ProtocolMismatch)
+}
+
+impl Codec for MsgPackCodec {
+    fn encode_broadcast(&self, broadcast: &Broadcast) -> GossipResult<Vec<u8>> {
+        msgpack::Encoder::to_msgpack(&WireBroadcast::new(broadcast)).map_err(io_err)
+    }
+
+    fn decode_broadcast(&self, bytes: &[u8]) -> GossipResult<Broadcast> {
+        let wire: WireBroadcast = try!(msgpack::from_msgpack(bytes.to_vec())
+                                           .map_err(undecodable_msgpack));
+        wire.to_broadcast()
+    }
+
+    fn encode_message(&self, msg: &Message) -> GossipResult<Vec<u8>> {
+        msgpack::Encoder::to_msgpack(msg).map_err(io_err)
+    }
+
+    fn decode_message(&self, bytes: &[u8]) -> GossipResult<Message> {
+        msgpack::from_msgpack(bytes.to_vec()).map_err(undecodable_msgpack)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    use broadcast::Broadcast;
+    use message::{Metadata, Digest, Joined, StatusUpdate, Prune, KillNodeAt};
+    use member::Draining;
+    use merge::VersionVector;
+    use protocol::Peer;
+    use topic::Lz4;
+
+    #[test]
+    fn broadcasts_keep_everything_about_them() {
+        let mut broadcast = Broadcast::new(vec![1u8, 2, 3]).unwrap();
+        broadcast.set_namespace("billing");
+        broadcast.set_origin(Uuid::new_v4());
+        broadcast.hop();
+        broadcast.set_deadline(1_000);
+
+        let frame = broadcast_frame(&JsonCodec, &broadcast).unwrap();
+        let decoded = match decode_frame(&JsonCodec, frame.as_slice()).unwrap() {
+            BroadcastPacket(decoded) => decoded,
+            _ => fail!("expected a broadcast")
+        };
+        assert_eq!(WireBroadcast::new(&decoded), WireBroadcast::new(&broadcast));
+
+        // However many hops the wire claims, it's taken as is.
+        let mut wire = WireBroadcast::new(&broadcast);
+        wire.hops = ::std::u32::MAX;
+        assert_eq!(wire.to_broadcast().unwrap().hops(), ::std::u32::MAX);
+    }
+
+    #[test]
+    fn messages_roundtrip() {
+        let id = Uuid::new_v4();
+        let mut metadata = HashMap::new();
+        metadata.insert("role".to_string(), "web".to_string());
+
+        let msgs = vec![Metadata(id, metadata, VersionVector::new().bumped(id)),
+                        Digest(vec![Joined(Peer::empty()), StatusUpdate(id, Draining)])];
+
+        for msg in msgs.move_iter() {
+            let frame = message_frame(&JsonCodec, &msg).unwrap();
+            match decode_frame(&JsonCodec, frame.as_slice()).unwrap() {
+                MessagePacket(decoded) => assert_eq!(decoded, msg),
+                _ => fail!("expected {}", msg)
+            }
+        }
+
+        assert!(decode_frame(&JsonCodec, &[1u8, 0xff]).is_err());
+        assert!(decode_frame(&JsonCodec, &[7u8]).is_err());
+    }
+
+    #[test]
+    fn compressed_broadcasts() {
+        let broadcast = Broadcast::new(Vec::from_elem(2_000, 7u8)).unwrap();
+
+        let plain = broadcast_frame(&MsgPackCodec, &broadcast).unwrap();
+        let frame = compressed_frame(&MsgPackCodec, &broadcast, Lz4).unwrap().unwrap();
+        assert!(frame.len() < plain.len() / 4);
+        match decode_frame(&MsgPackCodec, frame.as_slice()).unwrap() {
+            BroadcastPacket(decoded) => {
+                assert_eq!(WireBroadcast::new(&decoded), WireBroadcast::new(&broadcast));
+            },
+            _ => fail!("expected a broadcast")
+        }
+        assert!(decode_frame(&MsgPackCodec, &[2u8, 9, 0]).is_err());
+    }
+
+    #[test]
+    fn pack_broadcasts_into_frames() {
+        let batch: Vec<Broadcast> = range(0u8, 3).map(|n| Broadcast::new(vec![n]).unwrap())
+                                                 .collect();
+        let frames: Vec<Vec<u8>> = batch.iter()
+                                        .map(|b| broadcast_frame(&JsonCodec, b).unwrap())
+                                        .collect();
+
+        // The first two fit together, the last doesn't fit with them.
+        let limit = 1 + 2 * 4 + frames.get(0).len() + frames.get(1).len();
+        let packed = packed_frames(frames.as_slice(), limit);
+        assert_eq!(packed.len(), 2);
+        assert_eq!(packed.get(1), frames.get(2));
+        match decode_frame(&JsonCodec, packed.get(0).as_slice()).unwrap() {
+            BatchPacket(decoded) => {
+                let wire = |batch: &[Broadcast]| -> Vec<WireBroadcast> {
+                    batch.iter().map(WireBroadcast::new).collect()
+                };
+                assert_eq!(wire(decoded.as_slice()), wire(batch.slice_to(2)));
+            },
+            _ => fail!("expected a batch")
+        }
+
+        // Messages sneaked into a packed frame aren't taken.
+        let msg = message_frame(&JsonCodec, &Prune(Uuid::new_v4())).unwrap();
+        let mut sneaky = vec![3u8, 0, 0, 0, msg.len() as u8];
+        sneaky.push_all(msg.as_slice());
+        assert!(decode_frame(&JsonCodec, sneaky.as_slice()).is_err());
+    }
+
+    #[test]
+    fn msgpack_is_compact() {
+        let mut broadcast = Broadcast::new(vec![1u8, 2, 3]).unwrap();
+        broadcast.set_namespace("billing");
+
+        let packed = broadcast_frame(&MsgPackCodec, &broadcast).unwrap();
+        assert!(packed.len() < broadcast_frame(&JsonCodec, &broadcast).unwrap().len());
+        match decode_frame(&MsgPackCodec, packed.as_slice()).unwrap() {
+            BroadcastPacket(decoded) => {
+                assert_eq!(WireBroadcast::new(&decoded), WireBroadcast::new(&broadcast));
+            },
+            _ => fail!("expected a broadcast")
+        }
+
+        let msg = Digest(vec![Joined(Peer::empty()), StatusUpdate(Uuid::new_v4(), Draining)]);
+        let packed = message_frame(&MsgPackCodec, &msg).unwrap();
+        match decode_frame(&MsgPackCodec, packed.as_slice()).unwrap() {
+            MessagePacket(decoded) => assert_eq!(decoded, msg),
+            _ => fail!("expected {}", msg)
+        }
+    }
+
+    #[test]
+    fn read_proofs_of_older_nodes() {
+        let id = Uuid::nil();
+        let nil = "00000000-0000-0000-0000-000000000000";
+        let legacy = format!(r#"{{"variant":"KillNodeAt","fields":["{}","{}",1,258]}}"#, nil, nil);
+        assert_eq!(JsonCodec.decode_message(legacy.as_bytes()).unwrap(),
+                   KillNodeAt(id, id, 1, vec![0u8, 0, 0, 0, 0, 0, 1, 2]));
+
+        let msg = KillNodeAt(id, id, 1, vec![1u8, 2, 3]);
+        let bytes = JsonCodec.encode_message(&msg).unwrap();
+        assert_eq!(JsonCodec.decode_message(bytes.as_slice()).unwrap(), msg);
+    }
+}
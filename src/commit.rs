@@ -0,0 +1,181 @@
+//! Group commit for what we persist: the broadcasts appended to the write
+//! ahead log (see `wal`) and the snapshots (see `Config::snapshot_path`).
+//! Every write comes with an fsync, so rather than one per broadcast (or
+//! snapshot) they're held for up to `Config::commit_max_delay`, or until
+//! `Config::commit_max_batch` of them piled up, and written at once. The
+//! latest snapshot supersedes whatever's still waiting, so only it is
+//! written on behalf of the whole batch, the log gets all of them appended.
+//!
+//! How long a batch took from it's first item until it was on disk, and
+//! how large batches get, is in `Node::log_stats` (and `Node::commit_stats`
+//! for the snapshots).
+
+use std::mem;
+use latency::Histogram;
+
+#[deriving(Show, Clone, PartialEq)]
+pub struct CommitStats {
+    /// Writes, each one on behalf of a batch.
+    pub commits: u64,
+    /// Items persisted (snapshots, or broadcasts for the log), as part of
+    /// any batch.
+    pub items: u64,
+    /// How many items each write was on behalf of.
+    pub batch_sizes: Histogram,
+    /// How long (in milliseconds) from a batch's first item until it was
+    /// written.
+    pub latency: Histogram
+}
+
+/// The batch being held, or written.
+struct Batch<T> {
+    /// What gets written.
+    items: Vec<T>,
+    /// How many items were pushed, superseded ones included.
+    size: uint,
+    since: u64
+}
+
+pub struct GroupCommit<T> {
+    max_delay: u64,
+    max_batch: uint,
+    /// Whether the latest item supersedes the others, as snapshots do.
+    latest: bool,
+    pending: Option<Batch<T>>,
+    /// Written, waiting for how long the write took.
+    writing: Option<Batch<T>>,
+    stats: CommitStats
+}
+
+impl<T> GroupCommit<T> {
+    /// Batching items that are written together.
+    pub fn new(max_delay: u64, max_batch: uint) -> GroupCommit<T> {
+        GroupCommit::with(max_delay, max_batch, false)
+    }
+
+    /// Batching items of which only the latest is written.
+    pub fn latest(max_delay: u64, max_batch: uint) -> GroupCommit<T> {
+        GroupCommit::with(max_delay, max_batch, true)
+    }
+
+    fn with(max_delay: u64, max_batch: uint, latest: bool) -> GroupCommit<T> {
+        GroupCommit {
+            max_delay: max_delay,
+            max_batch: max_batch,
+            latest: latest,
+            pending: None,
+            writing: None,
+            stats: CommitStats {
+                commits: 0,
+                items: 0,
+                batch_sizes: Histogram::new(),
+                latency: Histogram::new()
+            }
+        }
+    }
+
+    /// Add the item to the batch, in place of any earlier one if only the
+    /// latest is written.
+    pub fn push(&mut self, item: T, now: u64) {
+        let mut batch = match self.pending.take() {
+            Some(batch) => batch,
+            None => Batch { items: Vec::new(), size: 0, since: now }
+        };
+        if self.latest {
+            batch.items.clear();
+        }
+        batch.items.push(item);
+        batch.size += 1;
+        self.pending = Some(batch);
+    }
+
+    /// What to write, once the batch waited long enough or is full.
+    pub fn due(&mut self, now: u64) -> Option<Vec<T>> {
+        let due = match self.pending {
+            Some(ref batch) => batch.size >= self.max_batch || now >= batch.since + self.max_delay,
+            None => false
+        };
+        if !due {
+            return None;
+        }
+
+        let mut batch = self.pending.take().unwrap();
+        let items = mem::replace(&mut batch.items, Vec::new());
+        self.writing = Some(batch);
+        Some(items)
+    }
+
+    /// The write went through, having taken that long (in milliseconds).
+    pub fn committed(&mut self, took: u64, now: u64) {
+        match self.writing.take() {
+            Some(batch) => {
+                self.stats.commits += 1;
+                self.stats.items += batch.size as u64;
+                self.stats.batch_sizes.record(batch.size as u64);
+                self.stats.latency.record(now + took - batch.since);
+            },
+            None => {}
+        }
+    }
+
+    /// Forget about the batch, i.e., once persisting is given up on.
+    pub fn clear(&mut self) {
+        self.pending = None;
+        self.writing = None;
+    }
+
+    pub fn stats(&self) -> CommitStats {
+        self.stats.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+    use broadcast::Broadcast;
+    use codec::WireBroadcast;
+    use snapshot::Snapshot;
+
+    fn snapshot(n: u8) -> Snapshot {
+        let mut broadcast = Broadcast::new(vec![n]).unwrap();
+        broadcast.set_id(Uuid::nil());
+        Snapshot::new(Vec::new(), vec![WireBroadcast::new(&broadcast)])
+    }
+
+    #[test]
+    fn write_the_latest_once_due() {
+        let mut commit = GroupCommit::latest(100, 3);
+        commit.push(snapshot(1), 0);
+        commit.push(snapshot(2), 50);
+        assert_eq!(commit.due(99), None);
+        assert_eq!(commit.due(100), Some(vec![snapshot(2)]));
+        assert_eq!(commit.due(100), None);
+        commit.committed(5, 100);
+
+        // A full batch goes right away.
+        for n in range(3u8, 6) {
+            commit.push(snapshot(n), 200);
+        }
+        assert_eq!(commit.due(200), Some(vec![snapshot(5)]));
+        commit.committed(5, 200);
+
+        let stats = commit.stats();
+        assert_eq!((stats.commits, stats.items), (2, 5));
+        assert_eq!(stats.latency.sum, 105 + 5);
+        assert_eq!(stats.batch_sizes.sum, 5);
+    }
+
+    #[test]
+    fn write_every_item_of_a_batch() {
+        let mut commit = GroupCommit::new(100, 3);
+        commit.push(1u, 0);
+        commit.push(2u, 50);
+        assert_eq!(commit.due(100), Some(vec![1u, 2]));
+        commit.committed(5, 100);
+
+        let stats = commit.stats();
+        assert_eq!((stats.commits, stats.items), (1, 2));
+        assert_eq!(stats.batch_sizes.sum, 2);
+    }
+}
@@ -0,0 +1,266 @@
+//! Compression of broadcast frames, for topics asking for it (see
+//! `TopicSettings`) with payloads of at least `Config::compression_threshold`
+//! bytes. Only members that advertised the compression get the compressed
+//! frame, everybody else the plain one.
+//!
+//! LZ4 is built in, as a plain block prefixed with the size it decompresses
+//! to. Zstd isn't (yet), so it's neither advertised nor ever sent.
+//!
+//! ```notrust
+//! bitdata Lz4Block {
+//!     Lz4Block {
+//!         size: u32, // big-endian, decompressed
+//!         block: &[u8]
+//!     }
+//! }
+//! ```
+
+use std::cmp;
+use std::io::BufReader;
+
+use framing::MAX_FRAME;
+use result::{GossipResult, GossipError, ProtocolMismatch};
+use topic::{Compression, Uncompressed, Lz4, Zstd};
+
+static MIN_MATCH: uint = 4;
+static MAX_OFFSET: uint = 0xffff;
+static HASH_BITS: uint = 12;
+/// The format wants the last few bytes to be literals, and the last match
+/// to start further back than that.
+static END_LITERALS: uint = 5;
+/// The most a block decompresses to per byte of it: a match's length goes
+/// up by 255 per extra byte, at best.
+static MAX_RATIO: uint = 255;
+static MATCH_LIMIT: uint = 12;
+
+/// The byte telling the compression of a frame.
+pub fn wire(compression: Compression) -> u8 {
+    match compression {
+        Uncompressed => 0,
+        Lz4 => 1,
+        Zstd => 2
+    }
+}
+
+pub fn from_wire(byte: u8) -> Option<Compression> {
+    match byte {
+        0 => Some(Uncompressed),
+        1 => Some(Lz4),
+        2 => Some(Zstd),
+        _ => None
+    }
+}
+
+/// Compress the bytes, `None` if that doesn't make them any smaller (or the
+/// compression isn't built in).
+pub fn compress(compression: Compression, bytes: &[u8]) -> Option<Vec<u8>> {
+    let compressed = match compression {
+        Lz4 => lz4(bytes),
+        Uncompressed | Zstd => return None
+    };
+
+    if compressed.len() < bytes.len() {
+        Some(compressed)
+    } else {
+        None
+    }
+}
+
+pub fn decompress(compression: Compression, bytes: &[u8]) -> GossipResult<Vec<u8>> {
+    match compression {
+        Uncompressed => Ok(bytes.to_vec()),
+        Lz4 => unlz4(bytes),
+        Zstd => Err(GossipError::new("zstd isn't supported", ProtocolMismatch))
+    }
+}
+
+fn corrupted() -> GossipError {
+    GossipError::new("corrupted lz4 block", ProtocolMismatch)
+}
+
+fn hash(bytes: &[u8], i: uint) -> uint {
+    let word = bytes[i] as u32 | bytes[i + 1] as u32 << 8 | bytes[i + 2] as u32 << 16 |
+               bytes[i + 3] as u32 << 24;
+    ((word * 2654435761u32) >> (32 - HASH_BITS)) as uint
+}
+
+fn lz4(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(4 + bytes.len());
+    let size = bytes.len() as u32;
+    out.push_all(&[(size >> 24) as u8, (size >> 16) as u8, (size >> 8) as u8, size as u8]);
+
+    // Where each hashed 4 bytes were last seen.
+    let mut table = Vec::from_elem(1 << HASH_BITS, 0u);
+    let limit = if bytes.len() > MATCH_LIMIT { bytes.len() - MATCH_LIMIT } else { 0 };
+    let (mut anchor, mut i) = (0, 0);
+
+    while i < limit {
+        let h = hash(bytes, i);
+        let candidate = *table.get(h);
+        *table.get_mut(h) = i;
+
+        if candidate >= i || i - candidate > MAX_OFFSET ||
+           bytes.slice(candidate, candidate + MIN_MATCH) != bytes.slice(i, i + MIN_MATCH) {
+            i += 1;
+            continue;
+        }
+
+        let mut len = MIN_MATCH;
+        while i + len < bytes.len() - END_LITERALS && bytes[candidate + len] == bytes[i + len] {
+            len += 1;
+        }
+        sequence(&mut out, bytes.slice(anchor, i), Some((i - candidate, len)));
+        i += len;
+        anchor = i;
+    }
+
+    sequence(&mut out, bytes.slice_from(anchor), None);
+    out
+}
+
+fn sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(uint, uint)>) {
+    let extra = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push((cmp::min(literals.len(), 15) << 4 | cmp::min(extra, 15)) as u8);
+    if literals.len() >= 15 {
+        length(out, literals.len() - 15);
+    }
+    out.push_all(literals);
+
+    match matched {
+        Some((offset, _)) => {
+            out.push(offset as u8);
+            out.push((offset >> 8) as u8);
+            if extra >= 15 {
+                length(out, extra - 15);
+            }
+        },
+        None => {}
+    }
+}
+
+fn length(out: &mut Vec<u8>, mut len: uint) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn read_length(bytes: &[u8], i: &mut uint) -> GossipResult<uint> {
+    let mut len = 0;
+    loop {
+        if *i >= bytes.len() {
+            return Err(corrupted());
+        }
+        let byte = bytes[*i] as uint;
+        *i += 1;
+        len += byte;
+        if byte != 255 {
+            return Ok(len);
+        }
+    }
+}
+
+fn unlz4(bytes: &[u8]) -> GossipResult<Vec<u8>> {
+    if bytes.len() < 4 {
+        return Err(corrupted());
+    }
+    let size = BufReader::new(bytes.slice_to(4)).read_be_u32().unwrap() as uint;
+    if size > MAX_FRAME {
+        return Err(GossipError::new("frame too large", ProtocolMismatch));
+    }
+    // Nothing we're told is allocated before it's checked against what the
+    // block could possibly hold.
+    if size > (bytes.len() - 4) * MAX_RATIO {
+        return Err(corrupted());
+    }
+
+    let mut out = Vec::with_capacity(size);
+    let mut i = 4;
+    while i < bytes.len() {
+        let token = bytes[i] as uint;
+        i += 1;
+
+        let mut literals = token >> 4;
+        if literals == 15 {
+            literals += try!(read_length(bytes, &mut i));
+        }
+        if i + literals > bytes.len() || out.len() + literals > size {
+            return Err(corrupted());
+        }
+        out.push_all(bytes.slice(i, i + literals));
+        i += literals;
+
+        // The last sequence has nothing but literals.
+        if i == bytes.len() {
+            break;
+        }
+
+        if i + 2 > bytes.len() {
+            return Err(corrupted());
+        }
+        let offset = bytes[i] as uint | bytes[i + 1] as uint << 8;
+        i += 2;
+        let mut len = token & 0xf;
+        if len == 15 {
+            len += try!(read_length(bytes, &mut i));
+        }
+        len += MIN_MATCH;
+        if offset == 0 || offset > out.len() || out.len() + len > size {
+            return Err(corrupted());
+        }
+
+        // Byte by byte, the match can overlap what it's copying.
+        let start = out.len() - offset;
+        for k in range(0, len) {
+            let byte = *out.get(start + k);
+            out.push(byte);
+        }
+    }
+
+    if out.len() == size {
+        Ok(out)
+    } else {
+        Err(corrupted())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use topic::{Lz4, Zstd};
+
+    #[test]
+    fn lz4_roundtrip() {
+        let text = Vec::from_fn(10_000, |i| "gossip about the cluster ".as_bytes()[i % 25]);
+        let compressed = compress(Lz4, text.as_slice()).unwrap();
+        assert!(compressed.len() < text.len() / 10);
+        assert_eq!(decompress(Lz4, compressed.as_slice()).unwrap(), text);
+
+        let mut state = 7u32;
+        let noise = Vec::from_fn(1_000, |_| {
+            state = state * 1103515245 + 12345;
+            (state >> 16) as u8
+        });
+        assert_eq!(compress(Lz4, noise.as_slice()), None);
+        assert_eq!(compress(Zstd, text.as_slice()), None);
+        assert_eq!(compress(Lz4, &[]), None);
+    }
+
+    #[test]
+    fn refuse_corrupted_blocks() {
+        let text = Vec::from_fn(1_000, |i| (i % 10) as u8);
+        let compressed = compress(Lz4, text.as_slice()).unwrap();
+
+        let truncated = compressed.slice_to(compressed.len() - 1);
+        assert!(decompress(Lz4, truncated).is_err());
+
+        let mut oversized = compressed.clone();
+        *oversized.get_mut(0) = 0xff;
+        assert!(decompress(Lz4, oversized.as_slice()).is_err());
+
+        // A few bytes claiming to hold most of a frame.
+        assert!(decompress(Lz4, &[0x00, 0xff, 0xff, 0xff, 0x10, 7]).is_err());
+        assert!(decompress(Zstd, compressed.as_slice()).is_err());
+    }
+}
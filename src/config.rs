@@ -0,0 +1,594 @@
+//! Tunables for a single node.
+
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use quota::Quota;
+use topic::{TopicSettings, MsgPack, Uncompressed, Lz4};
+use eviction::AdminKey;
+use namespace::Namespace;
+use socket::SocketOptions;
+use stream::SockAddr;
+use transport::{TransportKind, Tcp};
+use version::CRATE_VERSION;
+use supervisor::RestartPolicy;
+use backoff::Backoff;
+use snapshot::{StoragePolicy, MemoryOnly};
+use subscriber::{StallPolicy, BufferStalled};
+use crypto::Crypto;
+use identity::{NodeId, RandomId};
+use validate::{Problem, validate};
+
+/// What `Config::cluster_name` defaults to, and the cluster nodes that
+/// predate the `Join` handshake take themselves to be part of.
+pub static DEFAULT_CLUSTER_NAME: &'static str = "gossip";
+
+/// Coherent defaults for the kind of network the cluster runs on, see
+/// `Config::with_profile`.
+#[deriving(Show, Clone, PartialEq)]
+pub enum Profile {
+    /// Every member within a single datacenter (or zone). What
+    /// `Config::new` gives you.
+    Lan,
+    /// Members spread across zones or regions: higher latencies, lossier
+    /// links and bandwidth that costs money. Everything is slower to judge
+    /// and payloads are compressed.
+    Wan,
+    /// Every member on the same host, i.e., tests and local development.
+    Local
+}
+
+/// Configuration for a `Node`. Every field has a sensible default, so
+/// start with `Config::new()` (or `Config::with_profile`) and tweak what
+/// you need.
+///
+/// ```rust
+/// use gossip::{Config, Node, Wan};
+/// let mut config = Config::with_profile(Wan);
+/// config.quorum = Some(3);
+/// let node = Node::with_config(config);
+/// ```
+#[deriving(Show, Clone)]
+pub struct Config {
+    /// The minimum number of voting members (ourselves included) that
+    /// must be reachable for the cluster to be considered quorate. `None`
+    /// disables the split-brain detector entirely.
+    pub quorum: Option<uint>,
+
+    /// When this node ends up on the minority side of a partition, mark
+    /// the health as `Red` and refuse application broadcasts until the
+    /// partition heals. Protocol traffic (probes, membership) keeps
+    /// flowing regardless.
+    pub minority_lockdown: bool,
+
+    /// The rate quota enforced on every origin whose broadcasts we relay.
+    /// `None` means origins are unlimited unless listed in `origin_quotas`.
+    pub origin_quota: Option<Quota>,
+
+    /// Per-origin overrides of `origin_quota`.
+    pub origin_quotas: HashMap<Uuid, Quota>,
+
+    /// The maximum number of joiners we serve a full-state sync to at the
+    /// same time. Everybody else waits in a queue.
+    pub join_concurrency: uint,
+
+    /// How long (in milliseconds) a joiner has to acknowledge it's sync
+    /// before we give it's slot to the next one in line.
+    pub join_sync_timeout: u64,
+
+    /// Membership changes made within this window (in milliseconds) are
+    /// collapsed into a single digest. Zero sends every change right away.
+    pub digest_window: u64,
+
+    /// Each membership change is retransmitted `retransmit_mult * log10(n + 1)`
+    /// times, where `n` is the size of the cluster.
+    pub retransmit_mult: uint,
+
+    /// The maximum number of membership changes sent out per round.
+    pub max_updates_per_round: uint,
+    /// How often (in milliseconds) we gossip a round, sending out the
+    /// membership changes due and announcing broadcasts to lazy peers.
+    /// Rounds come up to `gossip_jitter` milliseconds earlier or later at
+    /// random, so the members don't all gossip at once (see `schedule`).
+    /// `None` gossips every tick.
+    pub gossip_interval: Option<u64>,
+    pub gossip_jitter: u64,
+
+    /// The wire settings used for topics without an override.
+    pub topic_default: TopicSettings,
+
+    /// Per-topic overrides of `topic_default`, keyed by the namespace
+    /// qualified topic (i.e., `billing/config`). Settings a peer doesn't
+    /// support are downgraded when sending to it.
+    pub topics: HashMap<String, TopicSettings>,
+
+    /// Broadcasts with smaller payloads (in bytes) aren't worth compressing
+    /// and go out as they are, whatever their topic's settings.
+    pub compression_threshold: uint,
+
+    /// The topics (namespace qualified) whose broadcasts only go out sealed,
+    /// see `Node::set_topic_key`. Publishing on them fails until the key is
+    /// set, and plaintext broadcasts of theirs are dropped.
+    pub sealed_topics: Vec<String>,
+
+    /// The tenant namespaces served by the cluster. The default namespace
+    /// always exists; broadcasts for any other unconfigured namespace are
+    /// rejected.
+    pub namespaces: HashMap<String, Namespace>,
+
+    /// How long (in milliseconds) we leave a peer alone after it's circuit
+    /// breaker tripped.
+    pub breaker_reset_interval: u64,
+
+    /// The number of consecutive transient failures that trip a peer's
+    /// circuit breaker. Permanent failures trip it right away.
+    pub breaker_transient_threshold: uint,
+
+    /// When set, a `KillNode` only evicts a member once this many distinct
+    /// members asked for it within `eviction_window`. `None` trusts a single
+    /// request, which is dangerous with untrusted members around.
+    pub eviction_confirmations: Option<uint>,
+
+    /// The window (in milliseconds) eviction votes have to fall within.
+    pub eviction_window: u64,
+
+    /// Evictions carrying a valid proof made with this key apply right
+    /// away, regardless of `eviction_confirmations`.
+    pub admin_key: Option<AdminKey>,
+
+    /// How long (in milliseconds) an admin command (see `Node::submit`)
+    /// may take, waiting in line included, before it times out.
+    pub admin_timeout: u64,
+
+    /// How long (in milliseconds) subscribers created with
+    /// `Node::incoming_acked` have to ack a broadcast before it's delivered
+    /// to them again.
+    pub ack_timeout: u64,
+    /// Where the broadcasts those subscribers didn't ack yet are kept, so
+    /// they're delivered again after a restart. Without a path a restart
+    /// forgets them.
+    pub ack_path: Option<Path>,
+    /// How long (in milliseconds) a broadcast may wait on a subscriber to
+    /// read it before the subscriber counts as stalled, see `subscriber`.
+    /// `None` lets subscribers take as long as they like.
+    pub stall_threshold: Option<u64>,
+    pub stall_policy: StallPolicy,
+
+    /// Where the state snapshot served to joiners is persisted. Without a
+    /// path the snapshot only ever lives in memory.
+    pub snapshot_path: Option<Path>,
+    /// Where the broadcasts we deliver are logged, and replayed from once
+    /// we listen again, see `wal`. Without a path a restart forgets them.
+    pub wal_path: Option<Path>,
+    /// How long (in milliseconds) a broadcast (or snapshot) may wait to be
+    /// persisted, so those that come in the meantime (up to
+    /// `commit_max_batch`) are written at once, see `commit`. Zero writes
+    /// every one right away.
+    pub commit_max_delay: u64,
+    pub commit_max_batch: uint,
+
+    /// Where our id comes from (see `identity`), random by default. Standbys
+    /// take on the primary's id regardless.
+    pub node_id: NodeId,
+
+    /// The file a primary shares it's identity, subscriptions and delivery
+    /// cursor through with it's warm standby, see `standby`. Without a path
+    /// nothing is shared.
+    pub standby_path: Option<Path>,
+    /// Run as the warm standby of the primary sharing `standby_path`:
+    /// receive-only with the primary's identity, until `Node::take_over`.
+    pub standby: bool,
+
+    /// Where the members we know of are cached, so a restarted node can
+    /// rejoin through them (see `Node::rejoin`). Without a path nothing is
+    /// cached.
+    pub peer_cache_path: Option<Path>,
+
+    /// Debugging: record every inbound frame to this file so it can be
+    /// replayed later on, see `cassette`. Don't leave it on, it grows
+    /// without bounds.
+    pub cassette_path: Option<Path>,
+
+    /// What to do when persisting the snapshot (or the peer cache) fails,
+    /// once retrying per `storage_backoff` didn't help either.
+    pub storage_policy: StoragePolicy,
+    pub storage_backoff: Backoff,
+
+    /// How often (in milliseconds) the drivers tick the engine, see
+    /// `Threaded` and `Async`.
+    pub tick_interval: u64,
+
+    /// How long (in milliseconds) a member with a lower id has to stick
+    /// around before it takes over as the coordinator.
+    pub coordinator_hysteresis: u64,
+
+    /// The number of points every member gets on the hash ring, multiplied
+    /// by the `weight` in it's metadata (one without, `ring::MAX_WEIGHT` at
+    /// most).
+    pub ring_vnodes: uint,
+    /// How far ahead of ours a ring epoch from another member is taken at
+    /// once. One further ahead only moves ours by as much, so nobody can
+    /// push it to the end of the range (and wrap it around).
+    pub max_epoch_jump: u64,
+
+    /// Applied to every connection, see `SocketOptions`.
+    pub socket: SocketOptions,
+
+    /// What `Node::listen` moves our bytes with, unless a transport is set
+    /// (see `Node::set_transport`).
+    pub transport: TransportKind,
+    /// How many frames may wait on the transport before we refuse to
+    /// publish any more (with `Overloaded`), see `Node::broadcast`.
+    pub max_queued_frames: uint,
+    /// How long (in milliseconds) udp frames wait for their ack before
+    /// they're sent again, twice as long for every retransmit. Peers that
+    /// still haven't acked after `udp_max_retransmits` are considered gone.
+    pub udp_retransmit_timeout: u64,
+    pub udp_max_retransmits: uint,
+    /// The largest datagram (in bytes) every path is assumed to take. Paths
+    /// to each peer are probed for up to `udp_max_mtu`, every
+    /// `udp_mtu_probe_interval` (in milliseconds) once settled, and larger
+    /// frames are split into fragments. How long (in milliseconds) to wait
+    /// on the rest of a frame's fragments before giving up on it.
+    pub udp_mtu: uint,
+    pub udp_max_mtu: uint,
+    pub udp_mtu_probe_interval: u64,
+    pub udp_reassembly_timeout: u64,
+    /// The peers to dial over WebSocket (i.e., the ones behind an HTTP
+    /// load balancer) when the transport is `WebSocket`. Everybody else is
+    /// dialed over plain TCP.
+    pub websocket_peers: Vec<SockAddr>,
+    /// The resource upgrade requests go to.
+    pub websocket_path: String,
+
+    /// The sliding window (in milliseconds) bandwidth usage is reported
+    /// over, see `Node::bandwidth`.
+    pub bandwidth_window: u64,
+
+    /// How often (in milliseconds) we send a heartbeat once the application
+    /// set it's payload, see `Node::set_heartbeat`.
+    pub heartbeat_interval: u64,
+
+    /// How often (in milliseconds) to sample the resource pressure of our
+    /// host, see `pressure`. Sampling makes heartbeats go out even without
+    /// a payload. `None` doesn't sample.
+    pub pressure_interval: Option<u64>,
+    /// How long (in milliseconds) high pressure has to last before it
+    /// affects our health.
+    pub pressure_sustain: u64,
+
+    /// The version we advertise in our metadata, i.e., the application's.
+    /// Defaults to the version of this crate.
+    pub version: String,
+
+    /// How many versions may run within the cluster at once before we warn
+    /// with a `VersionSkew` event. Two allows for a rolling upgrade.
+    pub max_versions: Option<uint>,
+
+    /// How the internal tasks are restarted when they fail.
+    pub restart_policy: RestartPolicy,
+
+    /// The members (or their hosts) to join the cluster through as soon as
+    /// we `listen`, see `Node::rejoin`. None joins nobody, it's up to the
+    /// application to `join` then.
+    pub seeds: Vec<SockAddr>,
+    /// How connecting to join a cluster is retried, see `Node::join` and
+    /// `Node::rejoin`.
+    pub join_backoff: Backoff,
+    /// How long (in milliseconds) the member we join through has to hand
+    /// us the membership before we move on to the next, see `Node::rejoin`.
+    pub join_timeout: u64,
+    /// How long (in milliseconds) to cache what the hosts we join through
+    /// resolved to, unless the resolver tells (see `resolve`), and that
+    /// they didn't.
+    pub dns_ttl: u64,
+    pub dns_negative_ttl: u64,
+
+    /// Where the hashes, MACs and ciphers come from. Every member of the
+    /// cluster has to hash the same way.
+    pub crypto: Crypto,
+
+    /// How long (in milliseconds) members wait on each other when shutting
+    /// down the cluster, see `Node::shutdown_cluster`.
+    pub shutdown_stagger: u64,
+    /// How long (in milliseconds) leaving waits on members to ack it before
+    /// shutting down regardless, see `Node::leave`.
+    pub leave_timeout: u64,
+
+    /// How much CPU time (in microseconds) the protocol may take every
+    /// `cpu_budget_interval` milliseconds. Once it's spent, retransmits and
+    /// heartbeats wait for the next interval. `None` doesn't cap anything.
+    pub cpu_budget: Option<u64>,
+    pub cpu_budget_interval: u64,
+
+    /// How many changes to the broadcast tree to remember, see
+    /// `Node::topology_journal`.
+    pub journal_size: uint,
+    /// How many changes to our health (or the membership counts) to
+    /// remember, see `Node::health_history`.
+    pub health_history_size: uint,
+    /// How many broadcasts to remember what became of, see
+    /// `Node::recent_broadcasts`.
+    pub recent_broadcasts: uint,
+
+    /// Measure the delivery latency of one in this many broadcasts, see
+    /// `Node::delivery_latency`. `None` doesn't measure any.
+    pub latency_sampling: Option<uint>,
+
+    /// Peers whose score (out of 100) falls below these are demoted to the
+    /// lazy set, or disconnected from. See `score`.
+    pub score_demote_threshold: u64,
+    pub score_disconnect_threshold: u64,
+    /// How many points a second peers earn back.
+    pub score_recovery: u64,
+
+    /// How long (in milliseconds) to keep relayed broadcasts around, for
+    /// peers that missed them to pull. `None` keeps them until
+    /// `history_max_bytes` runs out.
+    pub history_retention: Option<u64>,
+    /// The most bytes worth of broadcasts to keep around, the oldest go
+    /// first. `None` doesn't limit them.
+    pub history_max_bytes: Option<uint>,
+    /// How many broadcast ids to remember, to drop the duplicates of. The
+    /// oldest are forgotten first, never those of broadcasts we retain.
+    pub max_seen: uint,
+
+    /// How much faster (in milliseconds) another peer has to relay the
+    /// broadcasts of an origin than our current parent for them, and for how
+    /// long, before we reshape the broadcast tree. See `parent`.
+    pub parent_margin: u64,
+    pub parent_hysteresis: u64,
+    /// How long (in milliseconds) to wait on a broadcast a lazy peer told
+    /// us about before grafting the peer for it, see `plumtree`. Should it
+    /// not come through either, the next peer to have told us is grafted
+    /// per `graft_backoff`, starting out shorter as the broadcast is late
+    /// already.
+    pub graft_timeout: u64,
+    pub graft_backoff: Backoff,
+    /// How many of our grafts for an origin's broadcasts another peer has
+    /// to answer within `graft_promotion_window` (in milliseconds) to take
+    /// over as our parent for them, see `parent`. `None` only ever goes by
+    /// `parent_margin`.
+    pub graft_promotions: Option<uint>,
+    pub graft_promotion_window: u64,
+    /// The most broadcast ids to announce to a lazy peer in one message.
+    pub ihave_max_ids: uint,
+    /// How often (in milliseconds) to reconcile with a member at random,
+    /// for whatever the rest of the protocol dropped. See `antientropy`.
+    /// `None` never does.
+    pub anti_entropy_interval: Option<u64>,
+    /// How many broadcasts we retain before reconciling them through a
+    /// Merkle tree (see `merkle`) rather than listing every id.
+    pub merkle_threshold: uint,
+
+    /// How often (in milliseconds) to probe a member, each in turn, see
+    /// `swim`. `None` (the default) never does, leaving members alive until
+    /// they're evicted.
+    pub probe_interval: Option<u64>,
+    /// How long (in milliseconds) to wait on the member's ack before asking
+    /// `indirect_probes` others to probe it for us. Should be well under
+    /// the interval, which is how long until it's suspect.
+    pub probe_timeout: u64,
+    pub indirect_probes: uint,
+    /// How long (in milliseconds) a member stays suspect before it's taken
+    /// for dead.
+    pub suspicion_timeout: u64,
+    /// Suspect members once their acks are that late going by how long they
+    /// usually take (see `detector`), rather than at the end of the probe
+    /// interval. Higher is more lenient, 8 is a good start on jittery
+    /// networks.
+    pub phi_threshold: Option<f64>,
+
+    /// Whether we relay broadcasts for others. Nodes that can't afford to
+    /// (i.e., on a cellular connection) still receive and originate them.
+    /// Advertised as the `relay` metadata, which can change later on.
+    pub relay: bool,
+
+    /// The name of the cluster, shown to nodes previewing it before they
+    /// join (see `Node::preview_join`). Joiners introducing themselves as
+    /// part of a cluster by another name are turned away.
+    pub cluster_name: String,
+    /// How long (in milliseconds) to wait for a seed to answer a preview.
+    pub preview_timeout: u64,
+
+    /// Topics (namespace qualified, see `Broadcast::topic`) to pull the
+    /// latest broadcast of right after joining, before going `Active`. See
+    /// `bootstrap`.
+    pub bootstrap_topics: Vec<String>,
+    /// How long (in milliseconds) to wait for them before going `Active`
+    /// anyway.
+    pub bootstrap_timeout: u64,
+
+    /// The sizes (in bytes) frames are padded up to, see `padding`. Every
+    /// member of the cluster has to pad, or none. Empty doesn't pad.
+    pub padding_buckets: Vec<uint>,
+    /// About how often (in milliseconds) dummy frames go out on every
+    /// connection when padding. `None` doesn't send any.
+    pub chatter_interval: Option<u64>
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            quorum: None,
+            minority_lockdown: false,
+            origin_quota: None,
+            origin_quotas: HashMap::new(),
+            join_concurrency: 4,
+            join_sync_timeout: 30_000,
+            digest_window: 200,
+            retransmit_mult: 4,
+            max_updates_per_round: 32,
+            gossip_interval: None,
+            gossip_jitter: 0,
+            topic_default: TopicSettings::new(MsgPack, Uncompressed),
+            topics: HashMap::new(),
+            compression_threshold: 1_024,
+            sealed_topics: Vec::new(),
+            namespaces: HashMap::new(),
+            breaker_reset_interval: 30_000,
+            breaker_transient_threshold: 5,
+            eviction_confirmations: None,
+            eviction_window: 10_000,
+            admin_key: None,
+            admin_timeout: 10_000,
+            ack_timeout: 30_000,
+            ack_path: None,
+            stall_threshold: Some(30_000),
+            stall_policy: BufferStalled(10_000),
+            snapshot_path: None,
+            wal_path: None,
+            commit_max_delay: 10,
+            commit_max_batch: 64,
+            peer_cache_path: None,
+            node_id: RandomId,
+            standby_path: None,
+            standby: false,
+            cassette_path: None,
+            storage_policy: MemoryOnly,
+            storage_backoff: Backoff {
+                initial: 10,
+                max: 100,
+                max_attempts: Some(3),
+                max_elapsed: Some(500)
+            },
+            tick_interval: 50,
+            coordinator_hysteresis: 5_000,
+            ring_vnodes: 64,
+            max_epoch_jump: 1_000,
+            socket: SocketOptions::new(),
+            transport: Tcp,
+            max_queued_frames: 10_000,
+            udp_retransmit_timeout: 200,
+            udp_max_retransmits: 5,
+            udp_mtu: 1_200,
+            udp_max_mtu: 9_000,
+            udp_mtu_probe_interval: 600_000,
+            udp_reassembly_timeout: 5_000,
+            websocket_peers: Vec::new(),
+            websocket_path: "/gossip".to_string(),
+            bandwidth_window: 60_000,
+            heartbeat_interval: 5_000,
+            pressure_interval: None,
+            pressure_sustain: 30_000,
+            version: CRATE_VERSION.to_string(),
+            max_versions: Some(2),
+            restart_policy: RestartPolicy::new(),
+            seeds: Vec::new(),
+            join_backoff: Backoff {
+                initial: 200,
+                max: 5_000,
+                max_attempts: Some(5),
+                max_elapsed: Some(30_000)
+            },
+            join_timeout: 5_000,
+            dns_ttl: 60_000,
+            dns_negative_ttl: 5_000,
+            crypto: Crypto::default(),
+            shutdown_stagger: 5_000,
+            leave_timeout: 5_000,
+            cpu_budget: None,
+            cpu_budget_interval: 100,
+            journal_size: 256,
+            health_history_size: 1_024,
+            recent_broadcasts: 256,
+            latency_sampling: Some(100),
+            score_demote_threshold: 50,
+            score_disconnect_threshold: 20,
+            score_recovery: 1,
+            history_retention: Some(60_000),
+            history_max_bytes: Some(16 * 1024 * 1024),
+            max_seen: 100_000,
+            parent_margin: 20,
+            parent_hysteresis: 10_000,
+            graft_timeout: 500,
+            graft_backoff: Backoff {
+                initial: 250,
+                max: 2_000,
+                max_attempts: None,
+                max_elapsed: Some(10_000)
+            },
+            graft_promotions: Some(3),
+            graft_promotion_window: 60_000,
+            ihave_max_ids: 256,
+            anti_entropy_interval: Some(30_000),
+            merkle_threshold: 1_024,
+            probe_interval: None,
+            probe_timeout: 300,
+            indirect_probes: 3,
+            suspicion_timeout: 5_000,
+            phi_threshold: None,
+            relay: true,
+            cluster_name: DEFAULT_CLUSTER_NAME.to_string(),
+            preview_timeout: 5_000,
+            bootstrap_topics: Vec::new(),
+            bootstrap_timeout: 10_000,
+            padding_buckets: Vec::new(),
+            chatter_interval: None
+        }
+    }
+
+    /// Everything incoherent about the configuration (fatal problems
+    /// first), i.e., settings that contradict each other. Nodes refuse to
+    /// start with fatal problems, see `Node::try_with_config`.
+    pub fn validate(&self) -> Vec<Problem> {
+        validate(self)
+    }
+
+    /// The defaults for the profile. Only timings, retransmits and
+    /// compression differ between profiles, so tweaking a field afterwards
+    /// works like it would with `Config::new`.
+    pub fn with_profile(profile: Profile) -> Config {
+        let mut config = Config::new();
+
+        match profile {
+            Lan => {},
+            Wan => {
+                config.tick_interval = 200;
+                config.digest_window = 1_000;
+                config.retransmit_mult = 6;
+                config.join_sync_timeout = 120_000;
+                config.bootstrap_timeout = 30_000;
+                config.ack_timeout = 60_000;
+                config.breaker_reset_interval = 60_000;
+                config.eviction_window = 30_000;
+                config.coordinator_hysteresis = 30_000;
+                config.heartbeat_interval = 15_000;
+                config.udp_retransmit_timeout = 1_000;
+                config.topic_default = TopicSettings::new(MsgPack, Lz4);
+            },
+            Local => {
+                config.tick_interval = 10;
+                config.digest_window = 20;
+                config.retransmit_mult = 2;
+                config.join_sync_timeout = 5_000;
+                config.bootstrap_timeout = 2_000;
+                config.ack_timeout = 5_000;
+                config.breaker_reset_interval = 5_000;
+                config.eviction_window = 2_000;
+                config.coordinator_hysteresis = 1_000;
+                config.heartbeat_interval = 1_000;
+                config.udp_retransmit_timeout = 50;
+            }
+        }
+
+        config
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use topic::Lz4;
+
+    #[test]
+    fn profiles() {
+        let lan = Config::with_profile(Lan);
+        let wan = Config::with_profile(Wan);
+        let local = Config::with_profile(Local);
+
+        assert_eq!(lan.tick_interval, Config::new().tick_interval);
+        assert!(wan.digest_window > lan.digest_window && lan.digest_window > local.digest_window);
+        assert_eq!(wan.topic_default.compression, Lz4);
+    }
+}
@@ -0,0 +1,324 @@
+//! A conformance tester for whatever speaks our wire protocol, this crate
+//! or another implementation of it. It connects to the peer like a joiner
+//! would and runs through a script of exchanges, checking each one got
+//! the answer it should have:
+//!
+//! * foreign cluster: a `Join` for another cluster (or an unsupported
+//!   protocol version) is answered with a `JoinRejected`.
+//! * handshake: a `Join` is answered with a `JoinSync`.
+//! * probe: a `Ping` is acked under it's sequence number.
+//! * graft: a broadcast we relayed is served back once we graft it.
+//! * bad frames: garbage is skipped, the peer still acks probes after.
+//!
+//! ```notrust
+//! let transport = box TcpTransport::new(SocketOptions::new(), RestartPolicy::new(), tx);
+//! let mut wire = try!(TransportWire::connect(transport as Box<Transport + Send>,
+//!                                            &SockAddr::new("10.0.0.4", 3444),
+//!                                            &SockAddr::new("10.0.0.5", 3444)));
+//! let conformance = Conformance::new(box ProtobufCodec, "gossip");
+//! for check in conformance.run(&mut wire).iter() {
+//!     println!("{}: {}", check.name, check.outcome);
+//! }
+//! ```
+//!
+//! We introduce ourselves by the address we listen on, so it has to be one
+//! the peer reaches us at. The peer has to relay broadcasts (see
+//! `member::RELAY_KEY`) for the graft to be served back.
+
+use uuid::Uuid;
+
+use broadcast::Broadcast;
+use clock;
+use codec::{Codec, Packet, BroadcastPacket, MessagePacket, PROTOCOL_VERSION};
+use codec;
+use message::{Message, Join, JoinSync, JoinSynced, JoinRejected, Ping, Ack, Graft};
+use protocol::Peer;
+use result::{GossipResult, GossipError, NodeUnreachable};
+use stream::SockAddr;
+use transport::{Transport, Connection, Frame, Closed};
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum Outcome {
+    Conforms,
+    /// What the peer did (or didn't do) instead.
+    Deviates(String)
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub struct Check {
+    /// Which of the exchanges, i.e., "probe".
+    pub name: &'static str,
+    pub outcome: Outcome
+}
+
+/// Whatever carries our frames to the peer under test, and it's back.
+pub trait Wire {
+    /// Where the peer reaches us, as we introduce ourselves.
+    fn addr(&self) -> SockAddr;
+
+    fn send(&mut self, frame: &[u8]) -> GossipResult<()>;
+
+    /// Start over on a fresh connection, like another node would. The peer
+    /// takes whoever introduces itself first on a connection to be the one
+    /// at the other end of it.
+    fn reconnect(&mut self) -> GossipResult<()>;
+
+    /// The next frame from the peer, `None` if none came within `timeout`
+    /// milliseconds.
+    fn recv(&mut self, timeout: u64) -> GossipResult<Option<Vec<u8>>>;
+}
+
+/// A connection to the peer over a transport. We listen as well, for peers
+/// that answer on connections of their own.
+pub struct TransportWire {
+    transport: Box<Transport + Send>,
+    conn: Connection,
+    addr: SockAddr,
+    peer: SockAddr
+}
+
+impl TransportWire {
+    pub fn connect(mut transport: Box<Transport + Send>, listen: &SockAddr,
+                   peer: &SockAddr) -> GossipResult<TransportWire> {
+        let addr = try!(transport.listen(listen));
+        let conn = try!(transport.connect(peer));
+
+        Ok(TransportWire {
+            transport: transport,
+            conn: conn,
+            addr: addr,
+            peer: peer.clone()
+        })
+    }
+}
+
+impl Wire for TransportWire {
+    fn addr(&self) -> SockAddr {
+        self.addr.clone()
+    }
+
+    fn send(&mut self, frame: &[u8]) -> GossipResult<()> {
+        self.transport.send(&self.conn, frame)
+    }
+
+    fn reconnect(&mut self) -> GossipResult<()> {
+        self.transport.close(&self.conn);
+        self.conn = try!(self.transport.connect(&self.peer));
+        Ok(())
+    }
+
+    fn recv(&mut self, timeout: u64) -> GossipResult<Option<Vec<u8>>> {
+        let deadline = clock::now() + timeout;
+        loop {
+            let now = clock::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+
+            match try!(self.transport.recv(deadline - now)) {
+                Some(Frame(_, frame)) => return Ok(Some(frame)),
+                Some(Closed(conn)) if conn == self.conn => {
+                    return Err(GossipError::new("the peer closed the connection",
+                                                NodeUnreachable));
+                },
+                _ => {}
+            }
+        }
+    }
+}
+
+pub struct Conformance {
+    codec: Box<Codec + Send>,
+    cluster: String,
+    /// How long (in milliseconds) to wait on every answer.
+    timeout: u64,
+    /// Who we are to the peer.
+    id: Uuid
+}
+
+impl Conformance {
+    pub fn new(codec: Box<Codec + Send>, cluster: &str) -> Conformance {
+        Conformance {
+            codec: codec,
+            cluster: cluster.to_string(),
+            timeout: 1_000,
+            id: Uuid::new_v4()
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: u64) {
+        self.timeout = timeout;
+    }
+
+    /// Run through the whole script, in order. Everything after the
+    /// handshake needs the peer to know us, so it's skipped if that failed.
+    pub fn run(&self, wire: &mut Wire) -> Vec<Check> {
+        let mut checks = vec![self.check("foreign cluster", self.foreign(wire))];
+        checks.push(self.check("handshake", self.handshake(wire)));
+        if checks.get(1).outcome != Conforms {
+            return checks;
+        }
+
+        checks.push(self.check("probe", self.probe(wire, 1)));
+        checks.push(self.check("graft", self.graft(wire)));
+        checks.push(self.check("bad frames", self.bad_frames(wire)));
+        checks
+    }
+
+    fn check(&self, name: &'static str, outcome: GossipResult<Outcome>) -> Check {
+        Check {
+            name: name,
+            outcome: match outcome {
+                Ok(outcome) => outcome,
+                Err(err) => Deviates(format!("{}", err))
+            }
+        }
+    }
+
+    fn handshake(&self, wire: &mut Wire) -> GossipResult<Outcome> {
+        try!(wire.reconnect());
+        let peer = Peer::new(self.id, wire.addr().ip.as_slice(), wire.addr().port);
+        try!(self.send(wire, Join(peer, self.cluster.clone(), PROTOCOL_VERSION as u32)));
+
+        let synced = try!(self.expect(wire, "a JoinSync", |packet| match *packet {
+            MessagePacket(JoinSync(..)) => true,
+            _ => false
+        }));
+        if synced == Conforms {
+            try!(self.send(wire, JoinSynced(self.id)));
+        }
+        Ok(synced)
+    }
+
+    /// Joiners from another cluster, or speaking a protocol version that
+    /// doesn't exist, are turned away. Each of them on it's own connection.
+    fn foreign(&self, wire: &mut Wire) -> GossipResult<Outcome> {
+        let joiners = [(format!("not-{}", self.cluster), PROTOCOL_VERSION as u32),
+                       (self.cluster.clone(), 0)];
+        for (i, &(ref cluster, version)) in joiners.iter().enumerate() {
+            if i > 0 {
+                try!(wire.reconnect());
+            }
+            let peer = Peer::new(Uuid::new_v4(), wire.addr().ip.as_slice(), wire.addr().port);
+            try!(self.send(wire, Join(peer, cluster.clone(), version)));
+
+            let rejected = try!(self.expect(wire, "a JoinRejected", |packet| match *packet {
+                MessagePacket(JoinRejected(..)) => true,
+                _ => false
+            }));
+            if rejected != Conforms {
+                return Ok(rejected);
+            }
+        }
+        Ok(Conforms)
+    }
+
+    fn probe(&self, wire: &mut Wire, seq: u64) -> GossipResult<Outcome> {
+        try!(self.send(wire, Ping(self.id, seq)));
+        self.expect(wire, "an Ack", |packet| match *packet {
+            MessagePacket(Ack(_, acked)) => acked == seq,
+            _ => false
+        })
+    }
+
+    fn graft(&self, wire: &mut Wire) -> GossipResult<Outcome> {
+        let mut broadcast = try!(Broadcast::new(vec![1u8, 2, 3]));
+        broadcast.set_origin(self.id);
+        let id = broadcast.id();
+        try!(wire.send(try!(codec::broadcast_frame(&*self.codec, &broadcast)).as_slice()));
+
+        // Whatever the peer relays on it's own goes before the graft, so
+        // it's not mistaken for the answer.
+        loop {
+            match try!(wire.recv(self.timeout)) {
+                Some(_) => {},
+                None => break
+            }
+        }
+
+        try!(self.send(wire, Graft(self.id, vec![id])));
+        self.expect(wire, "the grafted broadcast", |packet| match *packet {
+            BroadcastPacket(ref broadcast) => broadcast.id() == id,
+            _ => false
+        })
+    }
+
+    fn bad_frames(&self, wire: &mut Wire) -> GossipResult<Outcome> {
+        // Frames that can't be decoded, whatever the codec.
+        let frames = [vec![0xffu8, 1, 2, 3], vec![1u8, 0xff, 0xff], vec![]];
+        for frame in frames.iter() {
+            try!(wire.send(frame.as_slice()));
+        }
+        self.probe(wire, 2)
+    }
+
+    fn send(&self, wire: &mut Wire, msg: Message) -> GossipResult<()> {
+        wire.send(try!(codec::message_frame(&*self.codec, &msg)).as_slice())
+    }
+
+    /// Wait for a frame the predicate holds for, skipping the others (i.e.,
+    /// gossip). Frames we can't decode are a deviation on their own.
+    fn expect(&self, wire: &mut Wire, what: &str,
+              predicate: |&Packet| -> bool) -> GossipResult<Outcome> {
+        let deadline = clock::now() + self.timeout;
+        loop {
+            let now = clock::now();
+            let frame = if now < deadline { try!(wire.recv(deadline - now)) } else { None };
+            let frame = match frame {
+                Some(frame) => frame,
+                None => return Ok(Deviates(format!("expected {}, got nothing", what)))
+            };
+
+            match codec::decode_frame(&*self.codec, frame.as_slice()) {
+                Ok(packet) => if predicate(&packet) {
+                    return Ok(Conforms);
+                },
+                Err(err) => return Ok(Deviates(format!("expected {}, got {}", what, err)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::timer::sleep;
+    use codec::{Codec, JsonCodec};
+    use memory::{InMemoryTransport, Network};
+    use protocol::Node;
+    use stream::SockAddr;
+    use transport::Transport;
+
+    #[test]
+    fn this_crate_conforms() {
+        let network = Network::new();
+        let transport = box InMemoryTransport::new(&network) as Box<Transport + Send>;
+        let (listening_tx, listening_rx) = channel();
+        let (done_tx, done_rx) = channel::<()>();
+        spawn(proc() {
+            let mut node = Node::new();
+            node.set_transport(transport);
+            node.listen("127.0.0.1", 3444).unwrap();
+            listening_tx.send(());
+            while done_rx.try_recv().is_err() {
+                node.tick();
+                sleep(5);
+            }
+        });
+        listening_rx.recv();
+
+        let transport = box InMemoryTransport::new(&network) as Box<Transport + Send>;
+        let mut wire = TransportWire::connect(transport, &SockAddr::new("127.0.0.1", 4444),
+                                              &SockAddr::new("127.0.0.1", 3444)).unwrap();
+        let mut conformance = Conformance::new(box JsonCodec as Box<Codec + Send>, "gossip");
+        conformance.set_timeout(500);
+        let checks = conformance.run(&mut wire);
+        done_tx.send(());
+
+        let names: Vec<&str> = checks.iter().map(|check| check.name).collect();
+        assert_eq!(names, vec!["foreign cluster", "handshake", "probe", "graft", "bad frames"]);
+        for check in checks.iter() {
+            assert_eq!((check.name, check.outcome.clone()), (check.name, Conforms));
+        }
+    }
+}
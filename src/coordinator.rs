@@ -0,0 +1,101 @@
+//! A deterministic hint of which node should run the periodic jobs of a
+//! cluster that otherwise has no leader. This is not a consensus: while
+//! the membership views differ, two nodes might both think they're the
+//! coordinator. The hint only converges once the views do.
+//!
+//! The coordinator is the eligible member with the lowest id. To avoid
+//! flapping, a lower id has to stick around for the hysteresis before it
+//! takes over. A coordinator that's no longer eligible is replaced right
+//! away.
+
+use uuid::Uuid;
+
+pub struct Coordinator {
+    current: Option<Uuid>,
+    /// The lower id waiting to take over, along with when we first saw it.
+    pending: Option<(Uuid, u64)>,
+    hysteresis: u64
+}
+
+impl Coordinator {
+    pub fn new(current: Option<Uuid>, hysteresis: u64) -> Coordinator {
+        Coordinator {
+            current: current,
+            pending: None,
+            hysteresis: hysteresis
+        }
+    }
+
+    pub fn current(&self) -> Option<Uuid> {
+        self.current
+    }
+
+    /// Re-evaluate the hint given the ids currently eligible. Returns
+    /// `true` if the coordinator changed.
+    pub fn update(&mut self, eligible: &[Uuid], now: u64) -> bool {
+        let lowest = eligible.iter().min_by(|id| id.as_bytes()).map(|id| *id);
+
+        if lowest == self.current {
+            self.pending = None;
+            return false;
+        }
+
+        let stale = match self.current {
+            Some(ref id) => !eligible.contains(id),
+            None => true
+        };
+
+        if !stale {
+            let candidate = lowest.unwrap();
+            let since = match self.pending {
+                Some((id, since)) if id == candidate => since,
+                _ => now
+            };
+
+            self.pending = Some((candidate, since));
+            if now - since < self.hysteresis {
+                return false;
+            }
+        }
+
+        self.current = lowest;
+        self.pending = None;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    fn ids() -> (Uuid, Uuid) {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        if a.as_bytes() < b.as_bytes() { (a, b) } else { (b, a) }
+    }
+
+    #[test]
+    fn lower_ids_wait_for_the_hysteresis() {
+        let (low, high) = ids();
+        let mut coordinator = Coordinator::new(Some(high), 100);
+
+        assert!(!coordinator.update(&[low, high], 0));
+        assert!(!coordinator.update(&[low, high], 50));
+        assert_eq!(coordinator.current(), Some(high));
+
+        assert!(coordinator.update(&[low, high], 100));
+        assert_eq!(coordinator.current(), Some(low));
+    }
+
+    #[test]
+    fn replace_stale_coordinators_right_away() {
+        let (low, high) = ids();
+        let mut coordinator = Coordinator::new(Some(low), 100);
+
+        assert!(coordinator.update(&[high], 0));
+        assert_eq!(coordinator.current(), Some(high));
+
+        assert!(coordinator.update(&[], 0));
+        assert_eq!(coordinator.current(), None);
+    }
+}
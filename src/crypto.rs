@@ -0,0 +1,166 @@
+//! The primitives we rely on (hashing, MACs, ciphers) behind a provider,
+//! so organizations with a list of approved implementations (i.e., FIPS
+//! validated ones) can plug those in through `Config::crypto`.
+//!
+//! The builtin provider is gated behind the `builtin-crypto` feature (on by
+//! default). It uses SipHash for both hashing and MACs and ships no cipher.
+//! Without the feature a provider has to be configured before the node is
+//! started, the placeholder errors on every use (and `validate` turns the
+//! configuration down).
+
+use std::fmt;
+use std::sync::Arc;
+
+use cipher::Cipher;
+use result::GossipResult;
+#[cfg(not(feature = "builtin-crypto"))]
+use result::{GossipError, InvalidConfig};
+
+pub trait Provider: Send + Sync {
+    /// For logs, i.e., `builtin`.
+    fn name(&self) -> &'static str;
+
+    /// A well distributed 64-bit hash. Used to place members and keys on the
+    /// hash ring, so every member must use the same one.
+    fn hash(&self, data: &[u8]) -> GossipResult<u64>;
+
+    /// A MAC of the data with the key, the whole tag (i.e., HMAC-SHA256's
+    /// 32 bytes) rather than a truncated one.
+    fn mac(&self, key: &[u8], data: &[u8]) -> GossipResult<Vec<u8>>;
+
+    /// An authenticated cipher with the key, `None` if the provider doesn't
+    /// have any (or the key doesn't fit).
+    fn cipher(&self, key: &[u8]) -> Option<Box<Cipher + Send>>;
+}
+
+/// A shared handle on the configured provider.
+#[deriving(Clone)]
+pub struct Crypto {
+    provider: Arc<Box<Provider + Send + Sync>>
+}
+
+impl Crypto {
+    pub fn new(provider: Box<Provider + Send + Sync>) -> Crypto {
+        Crypto {
+            provider: Arc::new(provider)
+        }
+    }
+
+    #[cfg(feature = "builtin-crypto")]
+    pub fn default() -> Crypto {
+        Crypto::new(box builtin::Builtin as Box<Provider + Send + Sync>)
+    }
+
+    #[cfg(not(feature = "builtin-crypto"))]
+    pub fn default() -> Crypto {
+        Crypto::new(box Unconfigured as Box<Provider + Send + Sync>)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.provider.name()
+    }
+
+    pub fn hash(&self, data: &[u8]) -> GossipResult<u64> {
+        self.provider.hash(data)
+    }
+
+    pub fn mac(&self, key: &[u8], data: &[u8]) -> GossipResult<Vec<u8>> {
+        self.provider.mac(key, data)
+    }
+
+    pub fn cipher(&self, key: &[u8]) -> Option<Box<Cipher + Send>> {
+        self.provider.cipher(key)
+    }
+}
+
+impl fmt::Show for Crypto {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Crypto({})", self.name())
+    }
+}
+
+#[cfg(feature = "builtin-crypto")]
+mod builtin {
+    use std::hash::sip;
+    use std::io::{BufReader, MemWriter};
+
+    use cipher::Cipher;
+    use result::GossipResult;
+    use super::Provider;
+
+    pub struct Builtin;
+
+    impl Provider for Builtin {
+        fn name(&self) -> &'static str {
+            "builtin"
+        }
+
+        fn hash(&self, data: &[u8]) -> GossipResult<u64> {
+            Ok(sip::hash(&data))
+        }
+
+        /// SipHash keyed with the first 16 bytes of the key (padded with
+        /// zeroes), all 8 bytes of it.
+        fn mac(&self, key: &[u8], data: &[u8]) -> GossipResult<Vec<u8>> {
+            let mut padded = key.slice_to(::std::cmp::min(key.len(), 16)).to_vec();
+            padded.grow(16 - padded.len(), &0u8);
+
+            let mut reader = BufReader::new(padded.as_slice());
+            let k0 = reader.read_be_u64().unwrap();
+            let k1 = reader.read_be_u64().unwrap();
+
+            let mut tag = MemWriter::new();
+            // Writing to memory can't fail.
+            tag.write_be_u64(sip::hash_with_keys(k0, k1, &data)).unwrap();
+            Ok(tag.unwrap())
+        }
+
+        fn cipher(&self, _: &[u8]) -> Option<Box<Cipher + Send>> {
+            None
+        }
+    }
+}
+
+/// Stands in for a provider when the builtin one isn't compiled in.
+#[cfg(not(feature = "builtin-crypto"))]
+struct Unconfigured;
+
+#[cfg(not(feature = "builtin-crypto"))]
+fn unconfigured<T>() -> GossipResult<T> {
+    Err(GossipError::new("no crypto provider configured, see `Config::crypto`", InvalidConfig))
+}
+
+#[cfg(not(feature = "builtin-crypto"))]
+impl Provider for Unconfigured {
+    fn name(&self) -> &'static str {
+        "unconfigured"
+    }
+
+    fn hash(&self, _: &[u8]) -> GossipResult<u64> {
+        unconfigured()
+    }
+
+    fn mac(&self, _: &[u8], _: &[u8]) -> GossipResult<Vec<u8>> {
+        unconfigured()
+    }
+
+    fn cipher(&self, _: &[u8]) -> Option<Box<Cipher + Send>> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keyed_macs() {
+        let crypto = Crypto::default();
+        let mac = crypto.mac(b"secret", b"target").unwrap();
+        assert_eq!(mac.len(), 8);
+
+        assert_eq!(crypto.mac(b"secret", b"target").unwrap(), mac);
+        assert!(crypto.mac(b"secret!", b"target").unwrap() != mac);
+        assert!(crypto.mac(b"secret", b"other").unwrap() != mac);
+    }
+}
@@ -0,0 +1,159 @@
+//! Deciding when a member we probed (see `swim`) and haven't heard back
+//! from yet is failing. `Timeouts` gives every member until the end of the
+//! probe interval, however long it's acks usually take. `PhiAccrual` learns
+//! how long they do, and suspects the member once the wait gets unlikely
+//! enough: a phi of `Config::phi_threshold` means a one in 10^phi chance
+//! the ack was still on it's way. Members on jittery networks get more
+//! slack that way, those on steady ones get caught sooner.
+//!
+//! The next member is only probed once the detector made up it's mind, so
+//! waits go on `MAX_WAIT` probe intervals at most.
+
+use std::collections::{HashMap, RingBuf, Deque};
+use uuid::Uuid;
+
+/// Decides when the member is failing, see `Node::set_failure_detector`.
+pub trait FailureDetector: Send {
+    /// The member acked a probe after that many milliseconds.
+    fn acked(&mut self, id: &Uuid, rtt: u64);
+    /// Whether the member is failing, having waited that long (in
+    /// milliseconds) on it's ack.
+    fn is_failing(&self, id: &Uuid, waited: u64) -> bool;
+    /// The member is dead or gone, whatever we learned about it is moot.
+    fn forget(&mut self, id: &Uuid);
+}
+
+pub struct Timeouts {
+    timeout: u64
+}
+
+impl Timeouts {
+    pub fn new(timeout: u64) -> Timeouts {
+        Timeouts { timeout: timeout }
+    }
+}
+
+impl FailureDetector for Timeouts {
+    fn acked(&mut self, _: &Uuid, _: u64) {}
+
+    fn is_failing(&self, _: &Uuid, waited: u64) -> bool {
+        waited >= self.timeout
+    }
+
+    fn forget(&mut self, _: &Uuid) {}
+}
+
+/// How many round trips to each member we go by, the latest ones.
+pub static WINDOW: uint = 100;
+/// The least deviation (in milliseconds) we assume, so members that always
+/// ack within a millisecond aren't suspected over two.
+pub static MIN_DEVIATION: f64 = 50.0;
+/// How many probe intervals we wait on an ack at most, whatever phi says.
+pub static MAX_WAIT: u64 = 3;
+
+pub struct PhiAccrual {
+    threshold: f64,
+    /// Until we have a round trip to go by, and past `MAX_WAIT` intervals.
+    fallback: Timeouts,
+    max_wait: u64,
+    rtts: HashMap<Uuid, RingBuf<u64>>
+}
+
+impl PhiAccrual {
+    pub fn new(threshold: f64, interval: u64) -> PhiAccrual {
+        PhiAccrual {
+            threshold: threshold,
+            fallback: Timeouts::new(interval),
+            max_wait: interval * MAX_WAIT,
+            rtts: HashMap::new()
+        }
+    }
+
+    /// How suspicious having waited that long on the member is, `None`
+    /// until it acked at least once.
+    pub fn phi(&self, id: &Uuid, waited: u64) -> Option<f64> {
+        let rtts = match self.rtts.find(id) {
+            Some(rtts) if !rtts.is_empty() => rtts,
+            _ => return None
+        };
+
+        let n = rtts.len() as f64;
+        let mean = rtts.iter().fold(0.0, |sum, &rtt| sum + rtt as f64) / n;
+        let variance = rtts.iter().fold(0.0, |sum, &rtt| {
+            sum + (rtt as f64 - mean) * (rtt as f64 - mean)
+        }) / n;
+        Some(phi(waited as f64, mean, variance.sqrt().max(MIN_DEVIATION)))
+    }
+}
+
+impl FailureDetector for PhiAccrual {
+    fn acked(&mut self, id: &Uuid, rtt: u64) {
+        let rtts = self.rtts.find_or_insert(*id, RingBuf::new());
+        if rtts.len() == WINDOW {
+            rtts.pop_front();
+        }
+        rtts.push_back(rtt);
+    }
+
+    fn is_failing(&self, id: &Uuid, waited: u64) -> bool {
+        if waited >= self.max_wait {
+            return true;
+        }
+        match self.phi(id, waited) {
+            Some(phi) => phi >= self.threshold,
+            None => self.fallback.is_failing(id, waited)
+        }
+    }
+
+    fn forget(&mut self, id: &Uuid) {
+        self.rtts.remove(id);
+    }
+}
+
+/// `-log10` of the chance of still waiting after that long, round trips
+/// being normally distributed. Goes with the logistic approximation of the
+/// normal distribution, as the paper does.
+fn phi(waited: f64, mean: f64, deviation: f64) -> f64 {
+    let y = (waited - mean) / deviation;
+    let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+    if waited > mean {
+        -(e / (1.0 + e)).log10()
+    } else {
+        -(1.0 - 1.0 / (1.0 + e)).log10()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn time_out_at_the_interval() {
+        let timeouts = Timeouts::new(1_000);
+        let id = Uuid::new_v4();
+        assert!(!timeouts.is_failing(&id, 999));
+        assert!(timeouts.is_failing(&id, 1_000));
+    }
+
+    #[test]
+    fn give_jittery_members_more_slack() {
+        let mut detector = PhiAccrual::new(8.0, 1_000);
+        let (steady, jittery, unknown) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        for i in range(0u64, 50) {
+            detector.acked(&steady, 10);
+            detector.acked(&jittery, if i % 2 == 0 { 50 } else { 650 });
+        }
+
+        assert!(detector.phi(&steady, 5).unwrap() < detector.phi(&steady, 300).unwrap());
+        assert!(detector.is_failing(&steady, 500));
+        assert!(!detector.is_failing(&jittery, 1_500));
+        assert!(detector.is_failing(&jittery, 3_000));
+
+        // Nothing to go by, the interval it is.
+        assert!(!detector.is_failing(&unknown, 999));
+        assert!(detector.is_failing(&unknown, 1_000));
+        detector.forget(&steady);
+        assert_eq!(detector.phi(&steady, 500), None);
+    }
+}
@@ -0,0 +1,105 @@
+//! Coalescing of membership changes. When lots of changes happen within a
+//! short window (i.e., a mass failure) they're collapsed into a single
+//! digest instead of being sent out one by one.
+
+use std::mem;
+
+use message::{Update, Joined, StatusUpdate, Liveness, LivenessAt, Moved, Refuted, Departed};
+
+pub struct Coalescer {
+    /// How long (in milliseconds) to hold on to changes before flushing.
+    window: u64,
+    /// When the first pending change came in.
+    opened: Option<u64>,
+    pending: Vec<Update>
+}
+
+/// Whether two updates are about the same aspect of the same member, in
+/// which case the latest one wins.
+pub fn supersedes(a: &Update, b: &Update) -> bool {
+    match (a, b) {
+        (&Joined(ref a), &Joined(ref b)) => a.id() == b.id(),
+        (&StatusUpdate(a, _), &StatusUpdate(b, _)) => a == b,
+        (&Liveness(a, _), &Liveness(b, _)) | (&LivenessAt(a, _, _), &LivenessAt(b, _, _))
+            | (&Liveness(a, _), &LivenessAt(b, _, _))
+            | (&LivenessAt(a, _, _), &Liveness(b, _)) => a == b,
+        (&Moved(ref a, _), &Moved(ref b, _)) => a.id() == b.id(),
+        (&Refuted(ref a, _), &Refuted(ref b, _)) => a.id() == b.id(),
+        (&Departed(a, _), &Departed(b, _)) => a == b,
+        _ => false
+    }
+}
+
+impl Coalescer {
+    pub fn new(window: u64) -> Coalescer {
+        Coalescer {
+            window: window,
+            opened: None,
+            pending: Vec::new()
+        }
+    }
+
+    pub fn len(&self) -> uint {
+        self.pending.len()
+    }
+
+    pub fn push(&mut self, update: Update, now: u64) {
+        self.pending.retain(|pending| !supersedes(pending, &update));
+        self.pending.push(update);
+
+        if self.opened.is_none() {
+            self.opened = Some(now);
+        }
+    }
+
+    /// Take the pending changes right away, window or not (i.e., as we
+    /// leave).
+    pub fn take(&mut self) -> Vec<Update> {
+        self.opened = None;
+        mem::replace(&mut self.pending, Vec::new())
+    }
+
+    /// Take the pending changes once the window has elapsed.
+    pub fn flush(&mut self, now: u64) -> Option<Vec<Update>> {
+        match self.opened {
+            Some(opened) if now >= opened + self.window => {
+                self.opened = None;
+                Some(mem::replace(&mut self.pending, Vec::new()))
+            },
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use member::{Active, Draining, Dead};
+    use message::{StatusUpdate, Liveness};
+    use uuid::Uuid;
+
+    #[test]
+    fn collapse_within_window() {
+        let mut coalescer = Coalescer::new(100);
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+
+        coalescer.push(StatusUpdate(a, Draining), 0);
+        coalescer.push(Liveness(b, Dead), 10);
+        coalescer.push(StatusUpdate(a, Active), 20);
+        assert_eq!(coalescer.len(), 2);
+        assert_eq!(coalescer.flush(50), None);
+
+        let updates = coalescer.flush(100).unwrap();
+        assert_eq!(updates, vec![Liveness(b, Dead), StatusUpdate(a, Active)]);
+        assert_eq!(coalescer.flush(500), None);
+    }
+
+    #[test]
+    fn no_window() {
+        let mut coalescer = Coalescer::new(0);
+        let a = Uuid::new_v4();
+
+        coalescer.push(StatusUpdate(a, Draining), 0);
+        assert_eq!(coalescer.flush(0), Some(vec![StatusUpdate(a, Draining)]));
+    }
+}
@@ -0,0 +1,142 @@
+//! The endpoints of a service as a discovery source, for load balancers
+//! and RPC clients balancing over the members running it. A watch (see
+//! `Node::watch`) hands out every endpoint there is up front, then the
+//! endpoints added and removed as members come and go, get healthy or not,
+//! or change the metadata it selects on:
+//!
+//! ```notrust
+//! let mut selector = Selector::new("rpc");
+//! selector.require("role", "api");
+//!
+//! let changes = node.watch(selector);
+//! loop {
+//!     node.tick();
+//!     loop {
+//!         match changes.try_recv() {
+//!             Ok(EndpointAdded(endpoint)) => balancer.insert(endpoint.id, endpoint.addr),
+//!             Ok(EndpointRemoved(endpoint)) => balancer.remove(endpoint.id),
+//!             Err(_) => break
+//!         }
+//!     }
+//! }
+//! ```
+
+use std::collections::HashMap;
+
+use registry::Endpoint;
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum EndpointChange {
+    EndpointAdded(Endpoint),
+    EndpointRemoved(Endpoint)
+}
+
+/// Which endpoints to watch: those of the service, on members with every
+/// one of the metadata required.
+#[deriving(Show, Clone)]
+pub struct Selector {
+    pub service: String,
+    pub metadata: Vec<(String, String)>
+}
+
+impl Selector {
+    pub fn new(service: &str) -> Selector {
+        Selector {
+            service: service.to_string(),
+            metadata: Vec::new()
+        }
+    }
+
+    /// Only the members with the metadata (i.e., their `role`).
+    pub fn require(&mut self, key: &str, value: &str) {
+        self.metadata.push((key.to_string(), value.to_string()));
+    }
+
+    pub fn matches(&self, metadata: &HashMap<String, String>) -> bool {
+        self.metadata.iter().all(|&(ref key, ref value)| metadata.find(key) == Some(value))
+    }
+}
+
+/// The endpoints we last told the watcher about.
+pub struct Discovery {
+    selector: Selector,
+    endpoints: Vec<Endpoint>
+}
+
+impl Discovery {
+    pub fn new(selector: Selector) -> Discovery {
+        Discovery {
+            selector: selector,
+            endpoints: Vec::new()
+        }
+    }
+
+    pub fn selector<'a>(&'a self) -> &'a Selector {
+        &self.selector
+    }
+
+    pub fn endpoints<'a>(&'a self) -> &'a [Endpoint] {
+        self.endpoints.as_slice()
+    }
+
+    /// Take in the service's endpoints as they are now, along with the
+    /// metadata of the member of each. Returns what changed since the last
+    /// time, removals first.
+    pub fn refresh(&mut self, endpoints: Vec<(Endpoint, &HashMap<String, String>)>)
+                   -> Vec<EndpointChange> {
+        let current: Vec<Endpoint> = endpoints.move_iter()
+                                              .filter(|&(_, metadata)| {
+                                                  self.selector.matches(metadata)
+                                              })
+                                              .map(|(endpoint, _)| endpoint)
+                                              .collect();
+
+        let mut changes: Vec<EndpointChange> = self.endpoints.iter()
+            .filter(|endpoint| !current.contains(*endpoint))
+            .map(|endpoint| EndpointRemoved(endpoint.clone()))
+            .collect();
+        for endpoint in current.iter().filter(|endpoint| !self.endpoints.contains(*endpoint)) {
+            changes.push(EndpointAdded(endpoint.clone()));
+        }
+
+        self.endpoints = current;
+        changes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use uuid::Uuid;
+    use registry::Endpoint;
+    use stream::SockAddr;
+
+    fn endpoint(ip: &str) -> Endpoint {
+        Endpoint {
+            id: Uuid::new_v4(),
+            addr: SockAddr::new(ip, 8080)
+        }
+    }
+
+    #[test]
+    fn add_and_remove_the_selected_endpoints() {
+        let mut selector = Selector::new("rpc");
+        selector.require("role", "api");
+        let mut discovery = Discovery::new(selector);
+
+        let (mut api, mut worker) = (HashMap::new(), HashMap::new());
+        api.insert("role".to_string(), "api".to_string());
+        worker.insert("role".to_string(), "worker".to_string());
+        let (a, b, c) = (endpoint("10.0.0.1"), endpoint("10.0.0.2"), endpoint("10.0.0.3"));
+
+        assert_eq!(discovery.refresh(vec![(a.clone(), &api), (b.clone(), &worker)]),
+                   vec![EndpointAdded(a.clone())]);
+        assert_eq!(discovery.refresh(vec![(a.clone(), &api)]), vec![]);
+
+        // `a` went away as `c` came up, and `b` is an api server now.
+        assert_eq!(discovery.refresh(vec![(b.clone(), &api), (c.clone(), &api)]),
+                   vec![EndpointRemoved(a), EndpointAdded(b.clone()), EndpointAdded(c.clone())]);
+        assert_eq!(discovery.endpoints(), [b, c].as_slice());
+    }
+}
@@ -0,0 +1,37 @@
+//! What the drivers have in common. A driver owns an `Engine`, feeds it
+//! inputs and the time, and hands every output to a `Handler` that knows
+//! how to carry it out (i.e., by writing to sockets).
+//!
+//! Two drivers are available, each behind a feature flag:
+//!
+//! * `threaded` (the default): `Threaded` runs the engine within it's own
+//!   task and blocks on it's inputs. Good for CLI tools and anything
+//!   without an event loop. `Sharded` does the same but spreads the work
+//!   for the peers over several tasks, for very large clusters.
+//! * `async`: `Async` never blocks nor spawns anything, it handles what's
+//!   pending whenever the host's event loop polls it.
+
+use engine::{Engine, Input, Output};
+use clock;
+
+/// Carries out the outputs of the engine on behalf of a driver.
+pub trait Handler {
+    fn perform(&mut self, output: Output);
+}
+
+/// Hands the outputs to another task. Outputs sent after the other end
+/// hung up are dropped.
+impl Handler for Sender<Output> {
+    fn perform(&mut self, output: Output) {
+        let _ = self.send_opt(output);
+    }
+}
+
+/// Feed the input to the engine, charging the time it took against the
+/// CPU budget (see `Config::cpu_budget`).
+pub fn handle(engine: &mut Engine, input: Input, now: u64) -> Vec<Output> {
+    let started = clock::precise();
+    let outputs = engine.handle(input, now);
+    engine.charge(clock::precise() - started, now);
+    outputs
+}
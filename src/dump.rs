@@ -0,0 +1,387 @@
+//! State dumps, and what changed between two of them. A dump is a plain
+//! JSON copy of what a node knew at some point (see `Node::dump_state`):
+//! every member, our links to them in the broadcast tree and our
+//! configuration. Diffing the dumps taken before and after an incident (or
+//! on two nodes at once) lays out what moved, which is most of an incident
+//! timeline already.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Show;
+use std::hash::Hash;
+use std::io::File;
+use std::str;
+use serialize::json;
+use uuid::Uuid;
+
+use config::Config;
+use namespace::Namespace;
+use member::{MemberState, Status};
+use journal::Link;
+use stream::SockAddr;
+use result::{GossipResult, GossipError, InvalidSnapshot, io_err};
+
+#[deriving(Show, Clone, PartialEq, Encodable, Decodable)]
+pub struct MemberDump {
+    pub id: Uuid,
+    pub addr: SockAddr,
+    pub state: MemberState,
+    pub status: Status,
+    pub incarnation: u64
+}
+
+#[deriving(Show, Clone, PartialEq, Encodable, Decodable)]
+pub struct StateDump {
+    /// Of the node that took it.
+    pub id: Uuid,
+    /// When it was taken, by the node's wall clock (in milliseconds since
+    /// the unix epoch).
+    pub at: u64,
+    pub members: Vec<MemberDump>,
+    /// The members we're linked to in the broadcast tree, and how.
+    pub links: Vec<(Uuid, Link)>,
+    /// Every setting of the configuration, as it's shown.
+    pub config: Vec<(String, String)>
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum MemberChange {
+    MemberAdded(MemberDump),
+    MemberRemoved(MemberDump),
+    /// What the member was, and what it is.
+    MemberChanged(MemberDump, MemberDump)
+}
+
+/// A link to a member that changed, `None` for no link at all.
+#[deriving(Show, Clone, PartialEq)]
+pub struct LinkChange {
+    pub peer: Uuid,
+    pub from: Option<Link>,
+    pub to: Option<Link>
+}
+
+/// A setting that changed, `None` for one that wasn't there.
+#[deriving(Show, Clone, PartialEq)]
+pub struct Drift {
+    pub setting: String,
+    pub from: Option<String>,
+    pub to: Option<String>
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub struct StateDiff {
+    pub members: Vec<MemberChange>,
+    pub links: Vec<LinkChange>,
+    pub config: Vec<Drift>
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty() && self.links.is_empty() && self.config.is_empty()
+    }
+}
+
+fn invalid() -> GossipError {
+    GossipError::new("undecodable state dump", InvalidSnapshot)
+}
+
+impl StateDump {
+    pub fn encode(&self) -> String {
+        json::encode(self)
+    }
+
+    pub fn decode(bytes: &[u8]) -> GossipResult<StateDump> {
+        match str::from_utf8(bytes) {
+            Some(s) => json::decode(s).map_err(|_| invalid()),
+            None => Err(invalid())
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> GossipResult<()> {
+        let mut file = try!(File::create(path).map_err(io_err));
+        file.write(self.encode().as_bytes()).map_err(io_err)
+    }
+
+    pub fn load(path: &Path) -> GossipResult<StateDump> {
+        let bytes = try!(File::open(path).read_to_end().map_err(io_err));
+        StateDump::decode(bytes.as_slice())
+    }
+}
+
+/// What changed from one dump to the other: members first, then links and
+/// settings, each in the order of the dump they're from.
+pub fn diff(before: &StateDump, after: &StateDump) -> StateDiff {
+    let old: HashMap<Uuid, &MemberDump> = before.members.iter().map(|m| (m.id, m)).collect();
+    let new: HashSet<Uuid> = after.members.iter().map(|m| m.id).collect();
+
+    let mut members = Vec::new();
+    for member in after.members.iter() {
+        match old.find(&member.id) {
+            None => members.push(MemberAdded(member.clone())),
+            Some(was) if *was != member => {
+                members.push(MemberChanged((*was).clone(), member.clone()))
+            },
+            Some(_) => {}
+        }
+    }
+    for member in before.members.iter().filter(|m| !new.contains(&m.id)) {
+        members.push(MemberRemoved(member.clone()));
+    }
+
+    StateDiff {
+        members: members,
+        links: changes(before.links.as_slice(), after.links.as_slice())
+                   .move_iter()
+                   .map(|(peer, from, to)| LinkChange { peer: peer, from: from, to: to })
+                   .collect(),
+        config: changes(before.config.as_slice(), after.config.as_slice())
+                    .move_iter()
+                    .map(|(setting, from, to)| Drift { setting: setting, from: from, to: to })
+                    .collect()
+    }
+}
+
+/// The keys whose values differ between the two, with both values.
+fn changes<K: Clone + Eq + Hash, V: Clone + PartialEq>(before: &[(K, V)], after: &[(K, V)])
+                                                       -> Vec<(K, Option<V>, Option<V>)> {
+    let old: HashMap<K, V> = before.iter().map(|pair| pair.clone()).collect();
+    let new: HashMap<K, V> = after.iter().map(|pair| pair.clone()).collect();
+
+    let mut changes = Vec::new();
+    for &(ref key, ref value) in after.iter() {
+        match old.find(key) {
+            Some(was) if was == value => {},
+            was => changes.push((key.clone(), was.map(|v| v.clone()), Some(value.clone())))
+        }
+    }
+    for &(ref key, ref value) in before.iter().filter(|&&(ref key, _)| !new.contains_key(key)) {
+        changes.push((key.clone(), Some(value.clone()), None));
+    }
+    changes
+}
+
+/// Every setting of the configuration, secrets redacted. Maps (and sets)
+/// are listed by their keys, so the same configuration always dumps the same.
+pub fn settings(config: &Config) -> Vec<(String, String)> {
+    let settings = vec![
+        ("quorum", format!("{}", config.quorum)),
+        ("minority_lockdown", format!("{}", config.minority_lockdown)),
+        ("origin_quota", format!("{}", config.origin_quota)),
+        ("origin_quotas", entries(config.origin_quotas.iter())),
+        ("join_concurrency", format!("{}", config.join_concurrency)),
+        ("join_sync_timeout", format!("{}", config.join_sync_timeout)),
+        ("digest_window", format!("{}", config.digest_window)),
+        ("retransmit_mult", format!("{}", config.retransmit_mult)),
+        ("max_updates_per_round", format!("{}", config.max_updates_per_round)),
+        ("gossip_interval", format!("{}", config.gossip_interval)),
+        ("gossip_jitter", format!("{}", config.gossip_jitter)),
+        ("topic_default", format!("{}", config.topic_default)),
+        ("topics", entries(config.topics.iter())),
+        ("compression_threshold", format!("{}", config.compression_threshold)),
+        ("sealed_topics", format!("{}", config.sealed_topics)),
+        ("namespaces", entries(config.namespaces.iter().map(|(name, namespace)| {
+            (name, shown_namespace(namespace))
+        }))),
+        ("breaker_reset_interval", format!("{}", config.breaker_reset_interval)),
+        ("breaker_transient_threshold", format!("{}", config.breaker_transient_threshold)),
+        ("eviction_confirmations", format!("{}", config.eviction_confirmations)),
+        ("eviction_window", format!("{}", config.eviction_window)),
+        ("admin_key", redacted(&config.admin_key)),
+        ("admin_timeout", format!("{}", config.admin_timeout)),
+        ("ack_timeout", format!("{}", config.ack_timeout)),
+        ("ack_path", format!("{}", config.ack_path)),
+        ("stall_threshold", format!("{}", config.stall_threshold)),
+        ("stall_policy", format!("{}", config.stall_policy)),
+        ("snapshot_path", format!("{}", config.snapshot_path)),
+        ("wal_path", format!("{}", config.wal_path)),
+        ("commit_max_delay", format!("{}", config.commit_max_delay)),
+        ("commit_max_batch", format!("{}", config.commit_max_batch)),
+        ("node_id", format!("{}", config.node_id)),
+        ("standby_path", format!("{}", config.standby_path)),
+        ("standby", format!("{}", config.standby)),
+        ("peer_cache_path", format!("{}", config.peer_cache_path)),
+        ("cassette_path", format!("{}", config.cassette_path)),
+        ("storage_policy", format!("{}", config.storage_policy)),
+        ("storage_backoff", format!("{}", config.storage_backoff)),
+        ("tick_interval", format!("{}", config.tick_interval)),
+        ("coordinator_hysteresis", format!("{}", config.coordinator_hysteresis)),
+        ("ring_vnodes", format!("{}", config.ring_vnodes)),
+        ("max_epoch_jump", format!("{}", config.max_epoch_jump)),
+        ("socket", format!("{}", config.socket)),
+        ("transport", format!("{}", config.transport)),
+        ("max_queued_frames", format!("{}", config.max_queued_frames)),
+        ("udp_retransmit_timeout", format!("{}", config.udp_retransmit_timeout)),
+        ("udp_max_retransmits", format!("{}", config.udp_max_retransmits)),
+        ("udp_mtu", format!("{}", config.udp_mtu)),
+        ("udp_max_mtu", format!("{}", config.udp_max_mtu)),
+        ("udp_mtu_probe_interval", format!("{}", config.udp_mtu_probe_interval)),
+        ("udp_reassembly_timeout", format!("{}", config.udp_reassembly_timeout)),
+        ("websocket_peers", format!("{}", config.websocket_peers)),
+        ("websocket_path", format!("{}", config.websocket_path)),
+        ("bandwidth_window", format!("{}", config.bandwidth_window)),
+        ("heartbeat_interval", format!("{}", config.heartbeat_interval)),
+        ("pressure_interval", format!("{}", config.pressure_interval)),
+        ("pressure_sustain", format!("{}", config.pressure_sustain)),
+        ("version", format!("{}", config.version)),
+        ("max_versions", format!("{}", config.max_versions)),
+        ("restart_policy", format!("{}", config.restart_policy)),
+        ("seeds", format!("{}", config.seeds)),
+        ("join_backoff", format!("{}", config.join_backoff)),
+        ("join_timeout", format!("{}", config.join_timeout)),
+        ("dns_ttl", format!("{}", config.dns_ttl)),
+        ("dns_negative_ttl", format!("{}", config.dns_negative_ttl)),
+        ("crypto", format!("{}", config.crypto)),
+        ("shutdown_stagger", format!("{}", config.shutdown_stagger)),
+        ("leave_timeout", format!("{}", config.leave_timeout)),
+        ("cpu_budget", format!("{}", config.cpu_budget)),
+        ("cpu_budget_interval", format!("{}", config.cpu_budget_interval)),
+        ("journal_size", format!("{}", config.journal_size)),
+        ("health_history_size", format!("{}", config.health_history_size)),
+        ("recent_broadcasts", format!("{}", config.recent_broadcasts)),
+        ("latency_sampling", format!("{}", config.latency_sampling)),
+        ("score_demote_threshold", format!("{}", config.score_demote_threshold)),
+        ("score_disconnect_threshold", format!("{}", config.score_disconnect_threshold)),
+        ("score_recovery", format!("{}", config.score_recovery)),
+        ("history_retention", format!("{}", config.history_retention)),
+        ("history_max_bytes", format!("{}", config.history_max_bytes)),
+        ("max_seen", format!("{}", config.max_seen)),
+        ("parent_margin", format!("{}", config.parent_margin)),
+        ("parent_hysteresis", format!("{}", config.parent_hysteresis)),
+        ("graft_timeout", format!("{}", config.graft_timeout)),
+        ("graft_backoff", format!("{}", config.graft_backoff)),
+        ("graft_promotions", format!("{}", config.graft_promotions)),
+        ("graft_promotion_window", format!("{}", config.graft_promotion_window)),
+        ("ihave_max_ids", format!("{}", config.ihave_max_ids)),
+        ("anti_entropy_interval", format!("{}", config.anti_entropy_interval)),
+        ("merkle_threshold", format!("{}", config.merkle_threshold)),
+        ("probe_interval", format!("{}", config.probe_interval)),
+        ("probe_timeout", format!("{}", config.probe_timeout)),
+        ("indirect_probes", format!("{}", config.indirect_probes)),
+        ("suspicion_timeout", format!("{}", config.suspicion_timeout)),
+        ("phi_threshold", format!("{}", config.phi_threshold)),
+        ("relay", format!("{}", config.relay)),
+        ("cluster_name", format!("{}", config.cluster_name)),
+        ("preview_timeout", format!("{}", config.preview_timeout)),
+        ("bootstrap_topics", format!("{}", config.bootstrap_topics)),
+        ("bootstrap_timeout", format!("{}", config.bootstrap_timeout)),
+        ("padding_buckets", format!("{}", config.padding_buckets)),
+        ("chatter_interval", format!("{}", config.chatter_interval))
+    ];
+    settings.move_iter().map(|(setting, value)| (setting.to_string(), value)).collect()
+}
+
+/// Whether a secret is set, and nothing else.
+fn redacted<T>(secret: &Option<T>) -> String {
+    match *secret {
+        Some(_) => "<redacted>".to_string(),
+        None => "None".to_string()
+    }
+}
+
+fn shown_namespace(namespace: &Namespace) -> String {
+    let publishers = match namespace.publishers {
+        Some(ref publishers) => format!("Some({})", listed(publishers.iter())),
+        None => "None".to_string()
+    };
+    format!("Namespace {{ quota: {}, publishers: {} }}", namespace.quota, publishers)
+}
+
+fn entries<K: Show, V: Show, I: Iterator<(K, V)>>(entries: I) -> String {
+    listed(entries.map(|(key, value)| format!("{}: {}", key, value)))
+}
+
+fn listed<T: Show, I: Iterator<T>>(items: I) -> String {
+    let mut shown: Vec<String> = items.map(|item| format!("{}", item)).collect();
+    shown.sort();
+    format!("{{{}}}", shown.connect(", "))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+    use member::{MemberState, Status, Alive, Dead, Active, Draining};
+    use journal::{Eager, Lazy};
+    use stream::SockAddr;
+    use config::Config;
+    use eviction::AdminKey;
+    use topic::{TopicSettings, Bincode, Lz4};
+
+    fn member(id: Uuid, state: MemberState, status: Status) -> MemberDump {
+        MemberDump {
+            id: id,
+            addr: SockAddr::new("10.0.0.1", 3444),
+            state: state,
+            status: status,
+            incarnation: 0
+        }
+    }
+
+    #[test]
+    fn diff_members_links_and_settings() {
+        let (kept, gone, died, joined) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4(),
+                                          Uuid::new_v4());
+        let before = StateDump {
+            id: Uuid::new_v4(),
+            at: 0,
+            members: vec![member(kept, Alive, Active), member(gone, Alive, Active),
+                          member(died, Alive, Active)],
+            links: vec![(kept, Eager), (gone, Lazy)],
+            config: vec![("relay".to_string(), "true".to_string()),
+                         ("fanout".to_string(), "3".to_string())]
+        };
+        let after = StateDump {
+            id: before.id,
+            at: 1_000,
+            members: vec![member(kept, Alive, Active), member(died, Dead, Draining),
+                          member(joined, Alive, Active)],
+            links: vec![(kept, Lazy), (joined, Eager)],
+            config: vec![("relay".to_string(), "false".to_string()),
+                         ("fanout".to_string(), "3".to_string())]
+        };
+
+        let changes = diff(&before, &after);
+        assert_eq!(changes.members, vec![
+            MemberChanged(member(died, Alive, Active), member(died, Dead, Draining)),
+            MemberAdded(member(joined, Alive, Active)),
+            MemberRemoved(member(gone, Alive, Active))]);
+        assert_eq!(changes.links, vec![
+            LinkChange { peer: kept, from: Some(Eager), to: Some(Lazy) },
+            LinkChange { peer: joined, from: None, to: Some(Eager) },
+            LinkChange { peer: gone, from: Some(Lazy), to: None }]);
+        assert_eq!(changes.config, vec![Drift {
+            setting: "relay".to_string(),
+            from: Some("true".to_string()),
+            to: Some("false".to_string())
+        }]);
+
+        assert!(diff(&after, &after).is_empty());
+        assert_eq!(StateDump::decode(after.encode().as_bytes()).unwrap(), after);
+    }
+
+    #[test]
+    fn list_maps_by_their_keys() {
+        let (mut first, mut second) = (Config::new(), Config::new());
+        let topics = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        for topic in topics.iter() {
+            first.topics.insert(topic.to_string(), TopicSettings::new(Bincode, Lz4));
+        }
+        for topic in topics.iter().rev() {
+            second.topics.insert(topic.to_string(), TopicSettings::new(Bincode, Lz4));
+        }
+        assert_eq!(settings(&first), settings(&second));
+
+        let topics = settings(&first).move_iter().find(|&(ref setting, _)| {
+            setting.as_slice() == "topics"
+        }).unwrap();
+        assert!(topics.ref1().as_slice().starts_with("{a: "));
+    }
+
+    #[test]
+    fn redact_secrets() {
+        let mut config = Config::new();
+        config.admin_key = Some(AdminKey(1, 2));
+        let settings = settings(&config);
+        assert!(settings.contains(&("admin_key".to_string(), "<redacted>".to_string())));
+        assert!(settings.iter().any(|&(ref setting, _)| setting.as_slice() == "tick_interval"));
+    }
+}
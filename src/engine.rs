@@ -0,0 +1,4531 @@
+//! The protocol itself as a pure state machine. The engine never touches
+//! the network, the disk, channels or the clock: whoever drives it feeds
+//! it `Input`s along with the current time and carries out the `Output`s
+//! it hands back. That makes the protocol deterministic to test (and fuzz)
+//! and lets it run under different drivers.
+//!
+//! ```notrust
+//! let outputs = engine.handle(Received(msg), now);
+//! for output in outputs.move_iter() {
+//!     match output {
+//!         Gossip(msg) => // send to the cluster...
+//!         Deliver(broadcast) => // hand to local subscribers...
+//!         ...
+//!     }
+//! }
+//! ```
+
+use std::cmp;
+use std::mem;
+use std::num::Saturating;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+use broadcast::Broadcast;
+use config::{Config, DEFAULT_CLUSTER_NAME};
+use event::{Event, HealthChanged, Throttled, MemberJoined, MemberStateChanged, MemberEvicted};
+use event::{ServiceChanged, CoordinatorChanged, MetadataChanged, RingChanged};
+use event::{MemberMoved, VersionSkew, StorageFailed, ShutdownProgress, StandingChanged};
+use event::{FeatureToggled, FeatureRollout, JoinRefused, MemberLeft, AdminFailed};
+use member::{Membership, Status, Active, Draining, Bootstrapping, Alive, Dead, RELAY_KEY};
+use member::{MemberState, Suspect};
+use member;
+use message::{Message, StatusChange, QuotaExceeded, JoinRequest, JoinSync, JoinSynced, Digest};
+use message::{Update, Joined, StatusUpdate, Liveness, LivenessAt, Advertise, KillNode, Services};
+use message::{Metadata, RingEpoch, Moved, Heartbeat, ShutdownCluster, ShutdownReport};
+use message::{PreviewRequest, Preview, BootstrapRequest, BootstrapReply};
+use message::{FeatureToggle, FeatureAck, IHave, Graft, Prune, SyncDigest, TreeNodes, LeafIds};
+use message::{Ping, PingReq, Ack, Refuted, Join, JoinRejected, Leave, LeaveAck, Departed};
+use message::{Direct, KillNodeAt, BootstrapBroadcasts};
+use preview::ClusterInfo;
+use ops::{Operation, SyncingJoiner, QueuedJoiner, EvictionBallot, PendingGraft, ShuttingDown};
+use ops::{SuspectMember, MissingBroadcast};
+use protocol::{Peer, Health, Green, Yellow, Red};
+use codec::{PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, WireBroadcast};
+use result::{GossipResult, GossipError, NotQuorate, AccessDenied};
+use state::State;
+use stream::SockAddr;
+use namespace::{Tenants, OverQuota};
+use join::{JoinQueue, JoinProgress};
+use snapshot::{Snapshot, FailStop, MemoryOnly};
+use digest::Coalescer;
+use stats::{Counters, ProtocolStats, TopicStats};
+use retransmit::RetransmitQueue;
+use topic::{Topics, TopicSettings, Capabilities};
+use breaker::Breakers;
+use eviction::Evictions;
+use registry::{Registry, Service, Endpoint};
+use coordinator::Coordinator;
+use ring::Ring;
+use skew::Skew;
+use latency::Histogram;
+use latency;
+use bandwidth::{Bandwidth, BandwidthReport, Direction};
+use heartbeat::{Heartbeats, Heartbeat as LastHeartbeat};
+use version::{VersionReport, VERSION_KEY};
+use teardown::{VALIDITY, Stopping, Stopped};
+use teardown;
+use features::{Features, Rollout};
+use pressure::{Pressure, PressureLevel, High};
+use features;
+use budget::Budget;
+use journal::{Journal, TopologyChange, Link, Eager, Lazy, Reason, Failed, Added, Left};
+use journal::{Penalized, Recovered, Grafted, Pruned, LatencyDemoted, Cordoned, Uncordoned};
+use timeline::{Timeline, HealthRecord};
+use commit::{GroupCommit, CommitStats};
+use recent::{Recent, BroadcastRecord, Delivered, Late, OverLimit};
+use parent::Parents;
+use plumtree::Plumtree;
+use antientropy::AntiEntropy;
+use antientropy;
+use schedule::Schedule;
+use merkle;
+use swim::{Swim, Probe, ProbeThrough, Suspected, Confirmed, Answered, PassOn, Unsolicited};
+use detector::{FailureDetector, PhiAccrual};
+use dump::{StateDump, MemberDump};
+use dump;
+use bootstrap::Bootstrap;
+use merge::{VersionVector, MetadataMerge};
+use bootstrap;
+#[cfg(feature = "paranoid-checks")]
+use invariants;
+use score::{Scores, PeerScore, Violation, RateLimited, ProtocolViolation};
+use score::{Standing, Good, Demoted, Disconnected};
+
+/// Everything that can happen to the engine.
+pub enum Input {
+    /// A protocol message received from another member.
+    Received(Message),
+    /// Same, but the transport knows which member (the id) sent it: the one
+    /// that introduced itself on the connection. Messages claiming to be
+    /// from anybody else are dropped, and charged for (see `score`).
+    ReceivedFrom(Uuid, Message),
+    /// A broadcast relayed to us by another member.
+    Relayed(Broadcast),
+    /// Same, but the transport knows which member (the id) relayed it. Lets
+    /// us reshape the broadcast tree around slow parents, see `parent`.
+    RelayedBy(Uuid, Broadcast),
+    /// A broadcast published by the local application. Check it with
+    /// `Engine::can_publish` first, whatever it refuses is dropped.
+    Publish(Broadcast),
+    /// Broadcasts published by the local application as a unit, check each
+    /// of them with `Engine::can_publish` first.
+    PublishBatch(Vec<Broadcast>),
+    /// A broadcast published by the local application for a single member
+    /// (the id) instead of the whole cluster, see `Direct`. Check it with
+    /// `Engine::can_publish` first.
+    PublishTo(Uuid, Broadcast),
+    /// The transport is now listening on the address. Listening on another
+    /// address later on (i.e., after a DHCP change) moves us there.
+    Listening(SockAddr),
+    /// Change the status we advertise (i.e., start draining).
+    SetStatus(Status),
+    /// Ask the cluster to evict a member.
+    Evict(Uuid),
+    /// Advertise a local service, replacing any with the same name.
+    Register(Service),
+    /// Stop advertising the local service with the name.
+    Deregister(String),
+    /// Set one of the local metadata keys.
+    SetMetadata(String, String),
+    /// Replace the payload of our heartbeats.
+    SetHeartbeat(Vec<u8>),
+    /// The resource pressure of our host just now, see `pressure`.
+    SampledPressure(PressureLevel),
+    /// Ask every member (with the role) to shut down, see `teardown`. Needs
+    /// the admin key.
+    InitiateShutdown(Option<String>),
+    /// Leave the cluster on purpose and shut down, see `leave`.
+    LeaveCluster,
+    /// Flip the named toggle for every member, see `features`. Needs the
+    /// admin key.
+    SetFeature(String, bool),
+    /// Switch to the configuration. Only the settings read anew every time
+    /// (see `admin::LIVE`) change anything.
+    SetConfig(Config),
+    /// A member misbehaved on the transport, i.e., sent a frame that didn't
+    /// decode. It's charged for it, see `score`.
+    Misbehaved(Uuid, Violation),
+    /// The outcome of talking to a member over the transport.
+    TransportResult(Uuid, GossipResult<()>),
+    /// The outcome of carrying out a `Persist` (or `CachePeers`, `AppendLog`
+    /// and `RewriteLog`).
+    Persisted(GossipResult<()>),
+    /// Carrying out a `Persist` took that many milliseconds, see `commit`.
+    Committed(u64),
+    /// Carrying out an `AppendLog` (or `RewriteLog`) took that many
+    /// milliseconds.
+    Logged(u64),
+    /// The broadcasts replayed from the log (see `wal`), oldest first.
+    /// Drivers should feed them before anything else.
+    Replayed(Vec<Broadcast>),
+    /// What the failure detector running elsewhere (see `probe_elsewhere`)
+    /// made of the member: it acked, or is suspect or dead.
+    Detected(Uuid, MemberState),
+    /// The transport exchanged that many bytes with a member, optionally
+    /// for a broadcast of the (namespace qualified) topic.
+    Transferred(Uuid, Option<String>, Direction, uint),
+    /// The wall clock (in milliseconds since the unix epoch) currently
+    /// reads this. Drivers should feed it on startup and every now and then,
+    /// it's only used for the deadlines of broadcasts.
+    WallClock(u64),
+    /// Time passed. Drivers should tick every few dozen milliseconds.
+    Tick
+}
+
+/// Everything the engine wants done on it's behalf.
+pub enum Output {
+    /// Send a protocol message to the cluster.
+    Gossip(Message),
+    /// Send a protocol message to a single member.
+    SendTo(Uuid, Message),
+    /// Relay a broadcast to the cluster, pushed to our eager peers (see
+    /// `Engine::eager_peers`).
+    Relay(Broadcast),
+    /// Relay the broadcasts to our eager peers, packed together in the same
+    /// frames.
+    RelayBatch(Vec<Broadcast>),
+    /// Relay a broadcast to a single member, one that grafted it.
+    RelayTo(Uuid, Broadcast),
+    /// A member moved to the address, switch any connection to it over.
+    Reconnect(Uuid, SockAddr),
+    /// Hand a broadcast to the local subscribers.
+    Deliver(Broadcast),
+    /// Let the application know something happened.
+    Emit(Event),
+    /// Persist the snapshot to the configured `snapshot_path`.
+    Persist(Snapshot),
+    /// Append the broadcasts to the log at the configured `wal_path`.
+    AppendLog(Vec<Broadcast>),
+    /// Replace the log with one of the broadcasts, what we still retain.
+    RewriteLog(Vec<Broadcast>),
+    /// Drop the connection to a member, it misbehaved too much.
+    Disconnect(Uuid),
+    /// Cache the live members at the configured `peer_cache_path`.
+    CachePeers(Vec<Peer>),
+    /// Shut the node down, see `StoragePolicy` (or `leave`).
+    Shutdown
+}
+
+pub struct Engine {
+    id: Uuid,
+
+    /// A set of other members within the cluster.
+    members: Membership,
+
+    /// The operational status we advertise to the rest of the cluster.
+    status: Status,
+    config: Config,
+    state: State,
+
+    /// Set while we're on the minority side of a partition and refusing
+    /// application broadcasts.
+    locked: bool,
+
+    /// The tenant namespaces along with the rate usage of every origin we
+    /// relay broadcasts for.
+    tenants: Tenants,
+
+    /// Joiners waiting for (or receiving) a full-state sync from us.
+    joins: JoinQueue,
+
+    /// The address we're listening on, if any.
+    addr: Option<SockAddr>,
+    /// Bumped every time we move to a new address, or refute being
+    /// suspect (see `refute`).
+    incarnation: u64,
+    /// The members evicted (rather than taken for dead), which no
+    /// refutation brings back.
+    evicted: HashSet<Uuid>,
+
+    /// Membership changes waiting to go out as a single digest.
+    updates: Coalescer,
+    /// Membership changes that still need to be (re)transmitted.
+    retransmits: RetransmitQueue,
+    counters: Counters,
+    topics: Topics,
+    breakers: Breakers,
+    evictions: Evictions,
+    registry: Registry,
+    coordinator: Coordinator,
+    ring: Ring,
+    /// Bumped every time the ring changes, see `ring_epoch`.
+    epoch: u64,
+    /// What we advertise about ourselves.
+    metadata: HashMap<String, String>,
+    metadata_version: VersionVector,
+    /// Who we write metadata as, see `writer`. Members sharing an identity
+    /// (see `standby`) still write as themselves.
+    writer: Uuid,
+    /// Decides what concurrent metadata changes come down to, see `merge`.
+    merge: Option<Box<MetadataMerge + Send>>,
+    /// The wall clock minus the monotonic clock, see `WallClock`.
+    wall_offset: i64,
+    skew: Skew,
+    bandwidth: Bandwidth,
+    heartbeats: Heartbeats,
+    budget: Budget,
+    journal: Journal,
+    /// The snapshots waiting to be persisted, see `commit`.
+    commits: GroupCommit<Snapshot>,
+    /// The broadcasts waiting to be logged, see `wal`.
+    log: GroupCommit<Broadcast>,
+    /// How many broadcasts the log holds, as far as we know.
+    logged: uint,
+    /// Whether we run the probe rounds ourselves, see `probe_elsewhere`.
+    probing: bool,
+    timeline: Timeline,
+    recent: Recent,
+    latency: Histogram,
+    scores: Scores,
+    parents: Parents,
+    tree: Plumtree,
+    entropy: AntiEntropy,
+    /// When to gossip the next round, see `Config::gossip_interval`.
+    rounds: Schedule,
+    swim: Swim,
+    /// Set once persisting failed and we went on with the snapshot in memory
+    /// only.
+    degraded: bool,
+    /// Set from handing out our handshake until a seed's snapshot let us in,
+    /// snapshots are taken from nobody else.
+    joining: bool,
+    /// The members we last asked to be cached, sorted.
+    cached: Vec<Peer>,
+    /// The initiator of the cluster-wide shutdown we're part of, and when
+    /// it's our turn to shut down.
+    stopping: Option<(Uuid, u64)>,
+    /// The members yet to ack our `Leave`, and when we shut down
+    /// regardless.
+    leaving: Option<(HashSet<Uuid>, u64)>,
+    /// Whether we already warned about the versions running, see
+    /// `check_versions`.
+    skewed: bool,
+    /// Set while we pull the bootstrap-critical topics after joining.
+    bootstrap: Option<Bootstrap>,
+    features: Features,
+    pressure: Pressure,
+    /// Set while sustained pressure is what made our health yellow.
+    pressured: bool,
+    /// The toggles we flipped that not every member acked yet.
+    rollouts: HashMap<String, Rollout>,
+
+    /// Outputs produced while handling the current input.
+    out: Vec<Output>
+}
+
+impl Engine {
+    /// Who a member writes metadata as. It's own id for a primary, the
+    /// complement of it for it's standby, so a replay (see `cassette`) comes
+    /// down to the same versions.
+    fn writer(id: Uuid, standby: bool) -> Uuid {
+        if !standby {
+            return id;
+        }
+        let bytes: Vec<u8> = id.as_bytes().iter().map(|b| !*b).collect();
+        Uuid::from_bytes(bytes.as_slice()).unwrap()
+    }
+
+    pub fn new(id: Uuid, config: Config) -> Engine {
+        let tenants = Tenants::new(config.namespaces.clone(), config.origin_quota.clone(),
+                                   config.origin_quotas.clone());
+        let joins = JoinQueue::new(config.join_concurrency, config.join_sync_timeout);
+        let updates = Coalescer::new(config.digest_window);
+        let retransmits = RetransmitQueue::new(config.retransmit_mult);
+        let topics = Topics::new(config.topic_default.clone(), config.topics.clone());
+        let breakers = Breakers::new(config.breaker_reset_interval,
+                                     config.breaker_transient_threshold);
+        let evictions = Evictions::new(config.eviction_confirmations.unwrap_or(1),
+                                       config.eviction_window);
+        // On our own we're the coordinator.
+        let coordinator = Coordinator::new(Some(id), config.coordinator_hysteresis);
+        let mut ring = Ring::new(config.ring_vnodes, config.crypto.clone());
+        ring.rebuild(vec![(id, 1)]);
+        let bandwidth = Bandwidth::new(config.bandwidth_window);
+        let mut heartbeats = Heartbeats::new(config.heartbeat_interval);
+        // Our pressure goes out with the heartbeats, payload or not.
+        if config.pressure_interval.is_some() {
+            heartbeats.set_payload(Vec::new());
+        }
+        let pressure = Pressure::new(config.pressure_sustain);
+        let journal = Journal::new(config.journal_size);
+        let timeline = Timeline::new(config.health_history_size);
+        let recent = Recent::new(config.recent_broadcasts);
+        let mut parents = Parents::new(config.parent_margin, config.parent_hysteresis);
+        match config.graft_promotions {
+            Some(grafts) => parents.promote_after(grafts, config.graft_promotion_window),
+            None => {}
+        }
+        let state = State::with_retention(config.history_retention, config.history_max_bytes,
+                                          config.max_seen);
+        let scores = Scores::new(config.score_demote_threshold,
+                                 config.score_disconnect_threshold, config.score_recovery);
+        let budget = Budget::new(config.cpu_budget.map(|us| us * 1000), config.cpu_budget_interval);
+        let mut metadata = HashMap::new();
+        metadata.insert(VERSION_KEY.to_string(), config.version.clone());
+        if !config.relay {
+            metadata.insert(RELAY_KEY.to_string(), "false".to_string());
+        }
+        let writer = Engine::writer(id, config.standby);
+        let mut swim = Swim::new(config.probe_interval, config.probe_timeout,
+                                 config.indirect_probes, config.suspicion_timeout, 0);
+        match (config.phi_threshold, config.probe_interval) {
+            (Some(threshold), Some(interval)) => {
+                swim.set_detector(box PhiAccrual::new(threshold, interval)
+                                  as Box<FailureDetector + Send>);
+            },
+            _ => {}
+        }
+
+        Engine {
+            id: id,
+            members: Membership::new(),
+            status: Active,
+            config: config,
+            state: state,
+            locked: false,
+            tenants: tenants,
+            joins: joins,
+            addr: None,
+            incarnation: 0,
+            evicted: HashSet::new(),
+            updates: updates,
+            retransmits: retransmits,
+            counters: Counters::new(),
+            topics: topics,
+            breakers: breakers,
+            evictions: evictions,
+            registry: Registry::new(),
+            coordinator: coordinator,
+            ring: ring,
+            epoch: 0,
+            metadata: metadata,
+            metadata_version: VersionVector::new().bumped(writer),
+            writer: writer,
+            merge: None,
+            wall_offset: 0,
+            skew: Skew::new(),
+            bandwidth: bandwidth,
+            heartbeats: heartbeats,
+            budget: budget,
+            journal: journal,
+            commits: GroupCommit::latest(config.commit_max_delay, config.commit_max_batch),
+            log: GroupCommit::new(config.commit_max_delay, config.commit_max_batch),
+            logged: 0,
+            probing: true,
+            timeline: timeline,
+            recent: recent,
+            latency: Histogram::new(),
+            scores: scores,
+            parents: parents,
+            tree: Plumtree::new(config.graft_timeout, config.graft_backoff.clone()),
+            entropy: AntiEntropy::new(config.anti_entropy_interval, 0),
+            rounds: Schedule::new(config.gossip_interval, config.gossip_jitter, 0),
+            swim: swim,
+            degraded: false,
+            joining: false,
+            cached: Vec::new(),
+            stopping: None,
+            leaving: None,
+            skewed: false,
+            bootstrap: None,
+            features: Features::new(),
+            pressure: pressure,
+            pressured: false,
+            rollouts: HashMap::new(),
+            out: Vec::new()
+        }
+    }
+
+    /// Handle a single input at time `now` (in milliseconds), returning what
+    /// needs to be done as a result.
+    pub fn handle(&mut self, input: Input, now: u64) -> Vec<Output> {
+        match input {
+            Received(msg) => self.receive(None, msg, now),
+            ReceivedFrom(sender, msg) => self.receive(Some(sender), msg, now),
+            Relayed(broadcast) => self.relayed(None, broadcast, now),
+            RelayedBy(sender, broadcast) => self.relayed(Some(sender), broadcast, now),
+            Publish(broadcast) => self.publish(broadcast, now),
+            PublishBatch(batch) => self.publish_batch(batch, now),
+            PublishTo(id, broadcast) => self.publish_to(id, broadcast, now),
+            Listening(addr) => {
+                let moved = match self.addr {
+                    Some(ref old) => *old != addr,
+                    None => false
+                };
+
+                self.addr = Some(addr.clone());
+                self.gossip(Advertise(self.id, Capabilities::local()));
+
+                if moved {
+                    self.incarnation += 1;
+                    let peer = Peer::new(self.id, addr.ip.as_slice(), addr.port);
+                    self.announce(Moved(peer, self.incarnation), now);
+                }
+            },
+            SetStatus(status) => self.set_status(status, now),
+            Evict(target) => {
+                let issued_at = self.wall(now);
+                // Without an admin key it's a plain vote. With one it's
+                // proven or not sent, a failing provider doesn't get to
+                // downgrade it.
+                let proof = match self.config.admin_key {
+                    Some(ref key) => key.prove(&self.config.crypto, &target, issued_at)
+                                        .map(|proof| Some(proof)),
+                    None => Ok(None)
+                };
+                match proof {
+                    Ok(proof) => {
+                        match proof {
+                            Some(ref proof) => {
+                                self.gossip(KillNodeAt(self.id, target, issued_at, proof.clone()));
+                            },
+                            None => self.gossip(KillNode(self.id, target, None))
+                        }
+                        self.kill_node(self.id, target, proof.map(|proof| (issued_at, proof)),
+                                       now);
+                        self.check_quorum();
+                    },
+                    Err(err) => self.emit(AdminFailed("evict".to_string(), format!("{}", err)))
+                }
+            },
+            Register(service) => {
+                let name = service.name.clone();
+                if self.registry.register(service) {
+                    self.advertise_services(name);
+                }
+            },
+            Deregister(name) => {
+                if self.registry.deregister(name.as_slice()) {
+                    self.advertise_services(name);
+                }
+            },
+            SetMetadata(key, value) => {
+                if self.metadata.find_equiv(&key.as_slice()) != Some(&value) {
+                    self.metadata.insert(key, value);
+                    self.metadata_version.bump(self.writer);
+                    self.advertise_metadata();
+                }
+            },
+            SetHeartbeat(payload) => {
+                self.heartbeats.set_payload(payload);
+                self.heartbeat(now);
+            },
+            SampledPressure(level) => {
+                self.pressure.observe(level, now);
+                self.check_pressure(now);
+            },
+            InitiateShutdown(role) => {
+                let issued_at = self.wall(now);
+                let signed = teardown::signed(&self.id, role.as_ref().map(|r| r.as_slice()),
+                                              issued_at);
+                match self.admin_sign("shutdown_cluster", signed.as_slice()) {
+                    Some(proof) => self.gossip(ShutdownCluster(self.id, role, issued_at, proof)),
+                    None => {}
+                }
+            },
+            LeaveCluster => self.leave(now),
+            SetFeature(name, enabled) => {
+                let issued_at = self.wall(now);
+                let signed = features::signed(&self.id, name.as_slice(), enabled, issued_at);
+                match self.admin_sign("set_feature", signed.as_slice()) {
+                    Some(proof) => self.set_feature(name, enabled, issued_at, proof, now),
+                    None => {}
+                }
+            },
+            SetConfig(config) => {
+                self.config = config;
+                self.check_quorum();
+            },
+            Misbehaved(id, violation) => self.penalize(id, violation, now),
+            TransportResult(id, result) => match result {
+                Ok(()) => self.breakers.succeeded(&id),
+                Err(err) => self.breakers.failed(id, &err, now)
+            },
+            Persisted(result) => match result {
+                Ok(()) => {},
+                Err(err) => self.storage_failed(err)
+            },
+            Committed(took) => self.commits.committed(took, now),
+            Logged(took) => self.log.committed(took, now),
+            Detected(id, Alive) => {
+                self.declare(id, Alive, now);
+                self.check_liveness(true);
+            },
+            Detected(id, state) => self.declare(id, state, now),
+            Replayed(broadcasts) => {
+                self.logged = broadcasts.len();
+                for broadcast in broadcasts.move_iter() {
+                    self.state.observe(broadcast.id());
+                    self.state.record(broadcast, now);
+                }
+            },
+            Transferred(id, topic, direction, bytes) => {
+                self.bandwidth.record(id, topic, direction, bytes, now);
+            },
+            WallClock(wall) => self.wall_offset = wall as i64 - now as i64,
+            Tick => {
+                self.state.expire(now);
+                for (demoted, promoted) in self.parents.expire(now).move_iter() {
+                    self.reparent(demoted, promoted, now);
+                }
+                if self.bootstrap.as_ref().map(|b| b.is_expired(now)).unwrap_or(false) {
+                    self.finish_bootstrap(now);
+                }
+                if self.budget.exhausted(now) {
+                    self.counters.deferred();
+                } else {
+                    if self.rounds.due(now) {
+                        self.flush_updates(now);
+                        self.lazy_push(now);
+                    }
+                    // Grafts go by their own timers, not the rounds.
+                    self.graft(now);
+                    self.anti_entropy(now);
+                    self.heartbeat(now);
+                    self.retry_rollouts(now);
+                    self.cache_peers();
+
+                    for (id, standing) in self.scores.recover(now).move_iter() {
+                        self.restand(id, standing, Recovered, now);
+                    }
+                }
+                // Not deferred, members taking long to ack is one thing but
+                // not probing them at all is another.
+                self.probe(now);
+                self.stop_if_due(now);
+                self.leave_if_done(now);
+                self.check_pressure(now);
+                self.record_health(now);
+            }
+        }
+
+        self.elect(now);
+        self.rebalance();
+        self.check_versions();
+        self.check_invariants(now);
+        match self.commits.due(now).and_then(|mut snapshots| snapshots.pop()) {
+            Some(snapshot) => self.out.push(Persist(snapshot)),
+            None => {}
+        }
+        match self.log.due(now) {
+            Some(broadcasts) => self.append_log(broadcasts),
+            None => {}
+        }
+        mem::replace(&mut self.out, Vec::new())
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn incarnation(&self) -> u64 {
+        self.incarnation
+    }
+
+    pub fn config<'a>(&'a self) -> &'a Config {
+        &self.config
+    }
+
+    /// The feature toggles, see `features`.
+    pub fn features<'a>(&'a self) -> &'a Features {
+        &self.features
+    }
+
+    pub fn members<'a>(&'a self) -> &'a Membership {
+        &self.members
+    }
+
+    pub fn state<'a>(&'a self) -> &'a State {
+        &self.state
+    }
+
+    /// The members we push broadcasts to in full, see `plumtree`. Everybody
+    /// else only hears of them through `IHave`.
+    pub fn eager_peers(&self) -> Vec<Uuid> {
+        self.members.iter()
+            .map(|member| member.id())
+            .filter(|id| self.state.link(id.to_string().as_slice()) == Some(Eager))
+            .collect()
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn health(&self) -> Health {
+        self.state.health()
+    }
+
+    /// Whether the local application may publish the broadcast right now.
+    /// When the minority lockdown is enabled and we can't see a quorum this
+    /// fails with `NotQuorate`, rather than letting both sides of a
+    /// split-brain diverge.
+    pub fn can_publish(&self, broadcast: &Broadcast) -> GossipResult<()> {
+        if self.locked {
+            return Err(GossipError::new("not enough members reachable for a quorum",
+                                        NotQuorate));
+        }
+        if self.is_plaintext(broadcast) {
+            return Err(GossipError::new("the topic's broadcasts go out sealed only",
+                                        AccessDenied));
+        }
+
+        match self.tenants.permits(broadcast.namespace(), &self.id) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(GossipError::new("may not publish within the namespace", AccessDenied))
+        }
+    }
+
+    /// Whether enough voting members (ourselves included) are reachable to
+    /// satisfy the configured quorum. Always true without a quorum.
+    pub fn is_quorate(&self) -> bool {
+        let local = if self.status == Active { 1u } else { 0u };
+
+        match self.config.quorum {
+            Some(quorum) => self.members.voting() + local >= quorum,
+            None => true
+        }
+    }
+
+    /// Whether this node is currently the coordinator. This is only a hint
+    /// meant for running periodic jobs, see `Coordinator`.
+    pub fn is_coordinator(&self) -> bool {
+        self.coordinator.current() == Some(self.id)
+    }
+
+    pub fn coordinator(&self) -> Option<Uuid> {
+        self.coordinator.current()
+    }
+
+    /// The member owning `key` on the hash ring.
+    pub fn owner(&self, key: &[u8]) -> Option<Uuid> {
+        self.ring.owner(key)
+    }
+
+    /// The `n` members responsible for `key` on the hash ring, the owner
+    /// first.
+    pub fn preference_list(&self, key: &[u8], n: uint) -> Vec<Uuid> {
+        self.ring.preference_list(key, n)
+    }
+
+    /// The bandwidth used per member and per topic over the last
+    /// `Config::bandwidth_window`.
+    pub fn bandwidth(&mut self, now: u64) -> BandwidthReport {
+        self.bandwidth.report(now)
+    }
+
+    /// The epoch of the hash ring. It only ever grows: we bump it whenever
+    /// our ring changes and adopt any higher epoch seen in the cluster, so
+    /// nodes that saw the same changes converge on the same epoch.
+    pub fn ring_epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// The versions every live member runs.
+    pub fn versions(&self) -> VersionReport {
+        // The application might have overridden the version since.
+        let version = match self.metadata.find_equiv(&VERSION_KEY) {
+            Some(version) => version.as_slice(),
+            None => self.config.version.as_slice()
+        };
+
+        VersionReport::new(self.id, version, &self.members)
+    }
+
+    /// Our own metadata, as we advertise it.
+    pub fn metadata<'a>(&'a self) -> &'a HashMap<String, String> {
+        &self.metadata
+    }
+
+    /// Merge concurrent changes to a member's metadata with the hook,
+    /// rather than going with the last ones we heard of.
+    pub fn set_metadata_merge(&mut self, merge: Box<MetadataMerge + Send>) {
+        self.merge = Some(merge);
+    }
+
+    /// Leave the probe rounds to whoever feeds us `Detected`, i.e., the
+    /// shards of `sharded`. We still ping members on behalf of others, and
+    /// time the suspicions we hear of.
+    pub fn probe_elsewhere(&mut self) {
+        self.probing = false;
+    }
+
+    /// Decide when the members we probe are failing with the detector,
+    /// instead of the configured one.
+    pub fn set_failure_detector(&mut self, detector: Box<FailureDetector + Send>) {
+        self.swim.set_detector(detector);
+    }
+
+    /// The resource pressure of our host, as last sampled.
+    pub fn pressure(&self) -> PressureLevel {
+        self.pressure.level()
+    }
+
+    /// What the failure detector should scale it's timeouts by (Lifeguard's
+    /// local health multiplier), 1 unless our host is under sustained
+    /// pressure.
+    pub fn local_health_multiplier(&self, now: u64) -> u64 {
+        self.pressure.multiplier(now)
+    }
+
+    /// The last heartbeat heard from the member, if any.
+    pub fn last_heartbeat<'a>(&'a self, id: &Uuid) -> Option<&'a LastHeartbeat> {
+        self.heartbeats.last(id)
+    }
+
+    /// How many joiners we're syncing and how many are still waiting.
+    pub fn join_progress(&self) -> JoinProgress {
+        self.joins.progress()
+    }
+
+    /// The latest changes to the broadcast tree, oldest first.
+    pub fn topology_journal(&self) -> Vec<TopologyChange> {
+        self.journal.changes()
+    }
+
+    /// How the snapshots we persist were batched, see `commit`.
+    pub fn commit_stats(&self) -> CommitStats {
+        self.commits.stats()
+    }
+
+    /// How the broadcasts we log were batched, see `wal`.
+    pub fn log_stats(&self) -> CommitStats {
+        self.log.stats()
+    }
+
+    /// Our health and the membership counts every time they changed, oldest
+    /// first.
+    pub fn health_history(&self) -> Vec<HealthRecord> {
+        self.timeline.records()
+    }
+
+    /// The latest broadcasts we saw, newest first.
+    pub fn recent_broadcasts(&self, topic: Option<&str>, limit: uint) -> Vec<BroadcastRecord> {
+        self.recent.list(topic, limit)
+    }
+
+    /// Account for the nanoseconds spent handling an input, see
+    /// `driver::handle`.
+    pub fn charge(&mut self, spent: u64, now: u64) {
+        self.budget.charge(spent, now);
+    }
+
+    /// Statistics on how efficient the broadcast tree currently is.
+    pub fn stats(&self) -> ProtocolStats {
+        self.counters.stats(self.state.eager().len(), self.state.lazy().len())
+    }
+
+    pub fn topic_stats(&self) -> Vec<(String, TopicStats)> {
+        self.counters.topics()
+    }
+
+    /// A broadcast we still retain (see `Config::history_retention`), to
+    /// serve to a peer that missed it.
+    pub fn retained(&self, id: &Uuid) -> Option<Broadcast> {
+        self.state.retained(id).map(|broadcast| broadcast.clone())
+    }
+
+    /// The members that misbehaved recently, along with their scores.
+    pub fn peer_scores(&self) -> Vec<(Uuid, PeerScore)> {
+        self.scores.list()
+    }
+
+    /// How long the sampled broadcasts took to reach us from their origin,
+    /// see `Config::latency_sampling`.
+    pub fn delivery_latency(&self) -> Histogram {
+        self.latency.clone()
+    }
+
+    /// The wire settings to use when sending a broadcast of `topic` (namespace
+    /// qualified, see `Broadcast::topic`) to the member `id`, negotiated
+    /// against what it advertised. Unknown members only get the baseline.
+    pub fn wire_settings(&self, topic: &str, id: &Uuid) -> TopicSettings {
+        match self.members.find(id) {
+            Some(member) => self.topics.negotiate(topic, &member.capabilities),
+            None => self.topics.negotiate(topic, &Capabilities::baseline())
+        }
+    }
+
+    /// Whether the member `id` takes broadcasts packed together, see
+    /// `codec::packed_frames`.
+    pub fn takes_packed(&self, id: &Uuid) -> bool {
+        self.members.find(id).map_or(false, |member| member.capabilities.supports_packing())
+    }
+
+    /// The healthy endpoints of the service `name` across the cluster,
+    /// ourselves included.
+    pub fn lookup(&self, name: &str) -> Vec<Endpoint> {
+        let mut endpoints = self.registry.lookup(name, &self.members);
+
+        match self.addr {
+            Some(ref addr) => {
+                for service in self.registry.local().iter() {
+                    if service.name.as_slice() == name && service.healthy {
+                        endpoints.push(Endpoint {
+                            id: self.id,
+                            addr: SockAddr::new(addr.ip.as_slice(), service.port)
+                        });
+                    }
+                }
+            },
+            None => {}
+        }
+
+        endpoints
+    }
+
+    /// Take a snapshot of the current membership (ourselves included) and
+    /// broadcast history.
+    pub fn snapshot(&self) -> Snapshot {
+        let broadcasts = self.state.broadcasts().iter().map(|b| WireBroadcast::new(*b)).collect();
+        Snapshot::new(self.peers(), broadcasts)
+    }
+
+    /// What we know right now, for diffing against another dump (see
+    /// `dump`).
+    pub fn dump(&self, now: u64) -> StateDump {
+        let mut members: Vec<MemberDump> = self.members.iter().map(|member| MemberDump {
+            id: member.id(),
+            addr: member.peer.addr().clone(),
+            state: member.state,
+            status: member.status,
+            incarnation: member.incarnation
+        }).collect();
+        members.sort_by(|a, b| a.id.to_string().cmp(&b.id.to_string()));
+
+        let links = members.iter().filter_map(|member| {
+            self.state.link(member.id.to_string().as_slice()).map(|link| (member.id, link))
+        }).collect();
+
+        StateDump {
+            id: self.id,
+            at: self.wall(now),
+            members: members,
+            links: links,
+            config: dump::settings(&self.config)
+        }
+    }
+
+    /// The protocol operations in flight, see `ops`.
+    pub fn operations(&self) -> Vec<Operation> {
+        let mut ops = Vec::new();
+
+        for (id, deadline) in self.joins.syncing().move_iter() {
+            ops.push(SyncingJoiner(id, deadline));
+        }
+        for id in self.joins.queued().move_iter() {
+            ops.push(QueuedJoiner(id));
+        }
+        for (target, votes, closes) in self.evictions.ballots().move_iter() {
+            ops.push(EvictionBallot(target, votes, closes));
+        }
+        for (origin, peer, at) in self.parents.pending().move_iter() {
+            ops.push(PendingGraft(origin, peer, at));
+        }
+        for (id, confirmed) in self.swim.suspects().move_iter() {
+            ops.push(SuspectMember(id, confirmed));
+        }
+        for (id, peer, due) in self.tree.pending().move_iter() {
+            ops.push(MissingBroadcast(id, peer, due));
+        }
+        match self.stopping {
+            Some((initiator, due)) => ops.push(ShuttingDown(initiator, due)),
+            None => {}
+        }
+
+        ops
+    }
+
+    /// What we introduce ourselves to a seed with (on first contact), once
+    /// we're listening. From then on we're joining, until a seed's snapshot
+    /// (see `JoinSync`) lets us in.
+    pub fn handshake(&mut self) -> Option<Message> {
+        let handshake = self.addr.as_ref().map(|addr| {
+            Join(Peer::new(self.id, addr.ip.as_slice(), addr.port),
+                 self.config.cluster_name.clone(), PROTOCOL_VERSION as u32)
+        });
+        self.joining = self.joining || handshake.is_some();
+        handshake
+    }
+
+    /// What we introduce ourselves with on the connections we open to
+    /// members, so they know who's on the other end.
+    pub fn hello(&self) -> Message {
+        Advertise(self.id, Capabilities::local())
+    }
+
+    /// What we tell nodes previewing the cluster, see `Node::preview_join`.
+    pub fn cluster_info(&self) -> ClusterInfo {
+        ClusterInfo {
+            name: self.config.cluster_name.clone(),
+            versions: self.versions().list(),
+            size: self.members.iter().filter(|m| m.state == Alive).count() + 1,
+            health: self.health(),
+            members: self.peers()
+        }
+    }
+
+    /// Every member, ourselves included once we're listening.
+    fn peers(&self) -> Vec<Peer> {
+        let mut peers: Vec<Peer> = self.members.iter().map(|m| m.peer.clone()).collect();
+        match self.addr {
+            Some(ref addr) => {
+                peers.push(Peer::new(self.id, addr.ip.as_slice(), addr.port));
+            },
+            None => {}
+        }
+
+        peers
+    }
+
+    /// Local subscribers always receive our own broadcasts. Without any
+    /// peers that's all there is to it, so a single-node cluster never
+    /// touches the transport or the dedup machinery.
+    fn publish(&mut self, mut broadcast: Broadcast, now: u64) {
+        if !self.may_publish(&broadcast) {
+            return;
+        }
+        broadcast.set_origin(self.id);
+        broadcast.set_sent_at(self.wall(now));
+
+        if self.members.len() == 0 {
+            self.deliver(broadcast, now);
+            return;
+        }
+
+        self.state.observe(broadcast.id());
+        self.state.record(broadcast.clone(), now);
+        self.deliver(broadcast.clone(), now);
+        self.tree.announce(broadcast.id());
+        self.out.push(Relay(broadcast));
+    }
+
+    /// An unsealed broadcast of a topic that's sealed only, see
+    /// `Config::sealed_topics`.
+    fn is_plaintext(&self, broadcast: &Broadcast) -> bool {
+        !broadcast.is_sealed() && self.config.sealed_topics.contains(&broadcast.topic())
+    }
+
+    /// Drivers are supposed to check `can_publish` before handing us a
+    /// broadcast, but the ACL doesn't depend on them doing so.
+    fn may_publish(&mut self, broadcast: &Broadcast) -> bool {
+        let allowed = self.can_publish(broadcast).is_ok();
+        if !allowed {
+            self.counters.dropped(broadcast.topic().as_slice());
+        }
+        allowed
+    }
+
+    /// Like `publish`, only the member gets it (ourselves included).
+    fn publish_to(&mut self, id: Uuid, mut broadcast: Broadcast, now: u64) {
+        if !self.may_publish(&broadcast) {
+            return;
+        }
+        broadcast.set_origin(self.id);
+        broadcast.set_sent_at(self.wall(now));
+
+        if id == self.id {
+            self.deliver(broadcast, now);
+        } else {
+            self.send_to(id, Direct(self.id, WireBroadcast::new(&broadcast)), now);
+        }
+    }
+
+    /// Like `publish`, with the whole batch relayed at once.
+    fn publish_batch(&mut self, batch: Vec<Broadcast>, now: u64) {
+        // All or nothing, like `Node::broadcast_batch`.
+        if !batch.iter().all(|broadcast| self.can_publish(broadcast).is_ok()) {
+            for broadcast in batch.iter() {
+                self.counters.dropped(broadcast.topic().as_slice());
+            }
+            return;
+        }
+        let sent_at = self.wall(now);
+        let mut relayed = Vec::with_capacity(batch.len());
+
+        for mut broadcast in batch.move_iter() {
+            broadcast.set_origin(self.id);
+            broadcast.set_sent_at(sent_at);
+
+            if self.members.len() > 0 {
+                self.state.observe(broadcast.id());
+                self.state.record(broadcast.clone(), now);
+                self.tree.announce(broadcast.id());
+                relayed.push(broadcast.clone());
+            }
+            self.deliver(broadcast, now);
+        }
+
+        if relayed.len() > 0 {
+            self.out.push(RelayBatch(relayed));
+        }
+    }
+
+    /// A broadcast we're supposed to relay. Origins that go over their rate
+    /// quota have the broadcast dropped and get told about it. So are
+    /// broadcasts past their deadline, according to the origin's clock.
+    fn relayed(&mut self, sender: Option<Uuid>, mut broadcast: Broadcast, now: u64) {
+        let origin = broadcast.origin();
+        let first = self.state.observe(broadcast.id());
+        // Whoever put it on our connection, relaying or not.
+        let relayer = sender;
+
+        // Leaves are nobody's parent, so there's no point timing them.
+        let sender = match sender {
+            Some(id) if self.members.find(&id).map(|m| m.relays()).unwrap_or(true) => Some(id),
+            _ => None
+        };
+        match sender {
+            Some(sender) => {
+                match self.parents.arrived(broadcast.id(), origin, sender, first, now) {
+                    Some((demoted, promoted)) => self.reparent(demoted, promoted, now),
+                    None => {}
+                }
+                // The parent never got it to us in time, the sender did.
+                if first && self.tree.answered(&broadcast.id(), &sender) {
+                    self.parents.grafted(&broadcast.id());
+                }
+            },
+            None => {}
+        }
+
+        if !first {
+            self.counters.duplicate(broadcast.topic().as_slice());
+            // The tree already got it to us, it's parent aside (see
+            // `parent`) the sender doesn't have to anymore.
+            match sender {
+                Some(sender) if self.parents.parent(&origin) != Some(sender) => {
+                    self.prune(sender, now);
+                },
+                _ => {}
+            }
+            return;
+        }
+        self.tree.received(&broadcast.id());
+
+        let wall = self.wall(now);
+        // Deadlines go by what earlier broadcasts told of the origin's
+        // clock, this one would vouch for it's own.
+        let remote_now = self.skew.remote_now(&origin, wall);
+        if broadcast.sent_at() != 0 {
+            self.skew.sample(origin, broadcast.sent_at(), wall);
+
+            match self.config.latency_sampling {
+                Some(rate) if latency::sampled(&broadcast.id(), rate) => {
+                    // Faster than the average transit counts as none,
+                    // rather than not at all.
+                    let remote_now = self.skew.smoothed_now(&origin, wall);
+                    let sent_at = broadcast.sent_at();
+                    let took = if remote_now > sent_at { remote_now - sent_at } else { 0 };
+                    self.latency.record(took);
+                },
+                _ => {}
+            }
+        }
+
+        match broadcast.deadline() {
+            Some(deadline) if remote_now > deadline => {
+                self.counters.expired(broadcast.topic().as_slice());
+                self.recent.record(BroadcastRecord::new(&broadcast, Late, wall));
+                return;
+            },
+            _ => {}
+        }
+
+        // Somebody let the plaintext out, at least we don't pass it on.
+        if self.is_plaintext(&broadcast) {
+            self.counters.dropped(broadcast.topic().as_slice());
+            return;
+        }
+
+        match self.tenants.admit(broadcast.namespace(), origin, broadcast.len(), now) {
+            Ok(()) => {},
+            Err(OverQuota) => {
+                self.counters.dropped(broadcast.topic().as_slice());
+                self.recent.record(BroadcastRecord::new(&broadcast, OverLimit, wall));
+                // The origin might not even be a member, the connection
+                // flooding us is.
+                match relayer {
+                    Some(relayer) => self.penalize(relayer, RateLimited, now),
+                    None => {}
+                }
+                self.send_to(origin, QuotaExceeded(self.id), now);
+                return;
+            },
+            Err(_) => return
+        }
+
+        self.counters.unique(broadcast.hops());
+
+        // Our subscribers and the next hops are both part of our span.
+        broadcast.trace_through(&self.id);
+        self.deliver(broadcast.clone(), now);
+
+        // Leaves don't relay, nor serve what others missed.
+        if member::relays(&self.metadata) {
+            broadcast.hop();
+            self.state.record(broadcast.clone(), now);
+            self.tree.announce(broadcast.id());
+            self.out.push(Relay(broadcast));
+        }
+    }
+
+    /// A broadcast handed to us alone. Only it's origin gets to do that,
+    /// and only once it's a member. It stops with us, so it's never
+    /// recorded for grafts or anti-entropy either.
+    fn direct(&mut self, sender: Option<Uuid>, from: Uuid, wire: WireBroadcast, now: u64) {
+        if wire.origin != from {
+            match sender {
+                Some(sender) => self.penalize(sender, ProtocolViolation, now),
+                None => {}
+            }
+            return;
+        }
+        if !self.is_known(&from) {
+            return;
+        }
+
+        match wire.to_broadcast() {
+            Ok(ref broadcast) if self.is_plaintext(broadcast) => {
+                self.counters.dropped(broadcast.topic().as_slice());
+            },
+            Ok(broadcast) => if self.state.observe(broadcast.id()) {
+                self.deliver(broadcast, now);
+            },
+            Err(_) => {}
+        }
+    }
+
+    fn receive(&mut self, sender: Option<Uuid>, msg: Message, now: u64) {
+        match (sender, msg.sender()) {
+            (Some(sender), Some(claimed)) if claimed != sender => {
+                self.penalize(sender, ProtocolViolation, now);
+                return;
+            },
+            _ => {}
+        }
+
+        match msg {
+            StatusChange(id, status) => {
+                self.apply(StatusUpdate(id, status), now);
+            },
+            // Nodes we never admitted (i.e., of another cluster) don't get to
+            // tell us who's in ours.
+            Digest(_) if sender.map_or(false, |id| !self.is_known(&id)) => {},
+            Digest(updates) => {
+                // Apply the whole digest before looking at the quorum so a
+                // batch of changes can't make us flap in and out of lockdown.
+                for update in updates.move_iter() {
+                    // Members announce their own moves, nobody gets to move
+                    // them elsewhere on their behalf.
+                    let spoofed = match (sender, &update) {
+                        (Some(sender), &Moved(ref peer, _)) |
+                        (Some(sender), &Refuted(ref peer, _)) => peer.id() != sender,
+                        _ => false
+                    };
+                    if spoofed {
+                        self.penalize(sender.unwrap(), ProtocolViolation, now);
+                        continue;
+                    }
+                    match (sender, &update) {
+                        // Nor do they leave on anybody else's word. Only the
+                        // member it's connection is bound to can tell us.
+                        (Some(sender), &Departed(id, _)) if id != sender => {
+                            self.penalize(sender, ProtocolViolation, now);
+                            continue;
+                        },
+                        (None, &Departed(..)) => continue,
+                        _ => {}
+                    }
+                    self.apply(update, now);
+                }
+            },
+            QuotaExceeded(relay) => {
+                self.emit(Throttled(relay));
+            },
+            // Nodes that predate the handshake can't tell us their cluster
+            // nor their version, they only get into one by the default name.
+            JoinRequest(peer) => {
+                self.join(peer, DEFAULT_CLUSTER_NAME, MIN_PROTOCOL_VERSION as u32, now);
+            },
+            Join(peer, cluster, version) => self.join(peer, cluster.as_slice(), version, now),
+            JoinRejected(from, reason) => self.emit(JoinRefused(from, reason)),
+            Leave(id) if sender == Some(id) => {
+                let incarnation = self.members.find(&id).map(|member| member.incarnation);
+                self.left(id, incarnation.unwrap_or(0), now);
+            },
+            Leave(_) => {},
+            Direct(from, wire) => self.direct(sender, from, wire, now),
+            LeaveAck(id) => {
+                match self.leaving {
+                    Some((ref mut waiting, _)) => { waiting.remove(&id); },
+                    None => {}
+                }
+                self.leave_if_done(now);
+            },
+            // Nobody gets to hand us their membership and broadcasts but a
+            // seed, while we're joining through it.
+            JoinSync(..) if !self.joining => {},
+            JoinSync(from, blob) => {
+                // A corrupted snapshot is simply ignored. We don't ack it,
+                // so the sender's slot times out and we get to retry.
+                match Snapshot::decode(blob.as_slice()) {
+                    Ok(snapshot) => {
+                        self.joining = false;
+                        self.restore(snapshot, now);
+                        self.announce_metadata();
+                        self.send_to(from, JoinSynced(self.id), now);
+                        self.start_bootstrap(from, now);
+                    },
+                    Err(_) => {}
+                }
+            },
+            JoinSynced(id) => {
+                let started = self.joins.complete(&id, now);
+                self.sync(started, now);
+            },
+            Advertise(id, capabilities) => {
+                self.members.set_capabilities(&id, capabilities);
+            },
+            // Without a time bound to it, a proof could be replayed forever.
+            KillNode(voter, target, _) => self.kill_node(voter, target, None, now),
+            KillNodeAt(voter, target, issued_at, proof) => {
+                self.kill_node(voter, target, Some((issued_at, proof)), now);
+            },
+            Metadata(id, metadata, version) => {
+                self.merge_metadata(id, metadata, version, now);
+            },
+            Heartbeat(id, payload, pressure) => {
+                if id != self.id && self.members.find(&id).is_some() {
+                    self.heartbeats.record(id, payload, pressure, now);
+                }
+            },
+            ShutdownCluster(initiator, role, issued_at, proof) => {
+                self.shutdown_cluster(initiator, role, issued_at, proof, now);
+            },
+            ShutdownReport(id, phase) => self.emit(ShutdownProgress(id, phase)),
+            FeatureToggle(initiator, name, enabled, issued_at, proof) => {
+                self.feature_toggled(initiator, name, enabled, issued_at, proof, now);
+            },
+            FeatureAck(id, name, issued_at) => self.feature_acked(id, name, issued_at),
+            // Looking doesn't make the asker a member, so it's not even
+            // added to the membership.
+            PreviewRequest(id) => {
+                let info = self.cluster_info();
+                self.send_to(id, Preview(info), now);
+            },
+            // The transport hands previews to whoever asked for them.
+            Preview(_) => {},
+            BootstrapRequest(id, topics) => {
+                let broadcasts = bootstrap::latest(self.state.broadcasts(), topics.as_slice())
+                    .iter()
+                    .map(|broadcast| WireBroadcast::new(*broadcast))
+                    .collect();
+                self.send_to(id, BootstrapBroadcasts(self.id, broadcasts), now);
+            },
+            // Older members only send the payloads, they're taken for new
+            // broadcasts of ours.
+            BootstrapReply(from, blobs) => {
+                let broadcasts = blobs.move_iter()
+                                      .filter_map(|blob| Broadcast::new(blob).ok())
+                                      .collect();
+                self.bootstrapped(from, broadcasts, now);
+            },
+            BootstrapBroadcasts(from, wires) => {
+                let broadcasts = wires.move_iter()
+                                      .filter_map(|wire| wire.to_broadcast().ok())
+                                      .collect();
+                self.bootstrapped(from, broadcasts, now);
+            },
+            IHave(from, ids) => {
+                for id in ids.move_iter() {
+                    if !self.state.has_seen(&id) {
+                        self.tree.announced(id, from, now);
+                    }
+                }
+            },
+            Graft(from, ids) => {
+                if self.is_alive(&from) {
+                    self.relink(from, Some(Eager), Grafted, now);
+                }
+                for id in ids.iter() {
+                    match self.state.retained(id) {
+                        Some(broadcast) => self.out.push(RelayTo(from, broadcast.clone())),
+                        None => {}
+                    }
+                }
+            },
+            Prune(from) => {
+                if self.is_alive(&from) {
+                    self.relink(from, Some(Lazy), Pruned, now);
+                }
+            },
+            // Nor to sync with us, the same goes for the tree and leaves.
+            SyncDigest(..) | TreeNodes(..) | LeafIds(..)
+                if sender.map_or(false, |id| !self.is_known(&id)) => {},
+            SyncDigest(from, members, ids, reply) => {
+                // Replies go to whoever's on the connection.
+                let from = sender.unwrap_or(from);
+                let suspected = members.iter().find(|&&(id, incarnation, state)| {
+                    id == self.id && state != Alive && incarnation >= self.incarnation
+                });
+                match suspected {
+                    Some(&(_, incarnation, _)) => self.refute(incarnation, now),
+                    None => {}
+                }
+                let mut updates = antientropy::missing(&self.members, &from, members.as_slice());
+                match self.addr {
+                    Some(ref addr) if !members.iter().any(|&(id, _, _)| id == self.id) => {
+                        updates.push(Joined(Peer::new(self.id, addr.ip.as_slice(), addr.port)));
+                    },
+                    _ => {}
+                }
+                if !updates.is_empty() {
+                    self.send_to(from, Digest(updates), now);
+                }
+
+                // Either of us retaining too much to list goes through the
+                // tree instead, started by whoever answers. Lists longer than
+                // that aren't looked at.
+                let listed = !ids.is_empty() && ids.len() <= self.config.merkle_threshold
+                             && !self.through_tree();
+                if listed {
+                    let retained: Vec<Uuid> = self.state.broadcasts().iter()
+                                                  .map(|b| b.id())
+                                                  .collect();
+                    for id in antientropy::unseen(retained.as_slice(), ids.as_slice()).iter() {
+                        self.relay_retained(from, id);
+                    }
+                } else if !reply {
+                    let root = self.state.tree().root();
+                    self.send_to(from, TreeNodes(self.id, 0, vec![(0, root)]), now);
+                }
+
+                if !reply {
+                    let digest = self.sync_digest(true);
+                    self.send_to(from, digest, now);
+                }
+            },
+            TreeNodes(from, level, nodes) => {
+                let from = sender.unwrap_or(from);
+                let level = level as uint;
+                let differ: Vec<u32> = nodes.iter().filter(|&&(index, hash)| {
+                    match self.state.tree().hash(level, index) {
+                        Some(ours) => ours != hash,
+                        None => false
+                    }
+                }).map(|&(index, _)| index).collect();
+
+                // Nothing differs below nodes that match.
+                let msg = if differ.is_empty() {
+                    None
+                } else if level >= merkle::DEPTH {
+                    Some(LeafIds(self.id, differ.clone(), self.leaf_ids(differ.as_slice()), false))
+                } else {
+                    let below = level + 1;
+                    let children = differ.iter().flat_map(|&index| {
+                        vec![2 * index, 2 * index + 1].move_iter()
+                    }).map(|index| {
+                        (index, self.state.tree().hash(below, index).unwrap())
+                    }).collect();
+                    Some(TreeNodes(self.id, below as u32, children))
+                };
+                match msg {
+                    Some(msg) => self.send_to(from, msg, now),
+                    None => {}
+                }
+            },
+            LeafIds(from, leaves, ids, reply) => {
+                let from = sender.unwrap_or(from);
+                let ours = self.leaf_ids(leaves.as_slice());
+                for id in antientropy::unseen(ours.as_slice(), ids.as_slice()).iter() {
+                    self.relay_retained(from, id);
+                }
+                if !reply {
+                    self.send_to(from, LeafIds(self.id, leaves, ours, true), now);
+                }
+            },
+            Ping(from, seq) => self.send_to(from, Ack(self.id, seq), now),
+            PingReq(from, target, seq) => {
+                if self.members.find(&target).is_some() {
+                    let seq = self.swim.probe_for(from, target, seq, now);
+                    self.send_to(target, Ping(self.id, seq), now);
+                }
+            },
+            Ack(from, seq) => {
+                // Acks don't say who sent them, the connection does.
+                let via = sender.unwrap_or(from);
+                match self.swim.ack(&from, &via, seq, now) {
+                    Answered => {
+                        self.declare(from, Alive, now);
+                        self.check_liveness(true);
+                    },
+                    PassOn(origin, seq) => self.send_to(origin, Ack(from, seq), now),
+                    Unsolicited => {}
+                }
+            },
+            // It doesn't say who sent it, the connection does. Only members
+            // get to move ours.
+            RingEpoch(epoch) => match sender {
+                Some(id) if id != self.id && self.is_known(&id) && epoch > self.epoch => {
+                    let bound = self.epoch.saturating_add(self.config.max_epoch_jump);
+                    self.epoch = cmp::min(epoch, bound);
+                },
+                _ => {}
+            },
+            Services(id, services) => {
+                if id != self.id {
+                    for name in self.registry.update(id, services).move_iter() {
+                        self.emit(ServiceChanged(name));
+                    }
+                }
+            }
+        }
+
+        self.check_quorum();
+    }
+
+    /// Let the joiner in, syncing it up once there's a free slot.
+    fn admit(&mut self, peer: Peer, now: u64) {
+        let id = peer.id();
+        // A member we already know of joining from somewhere else is the
+        // same member after a move, not a new one.
+        if self.members.insert(peer.clone()) {
+            self.relink(id, Some(Eager), Added, now);
+        } else {
+            self.relocate(&peer, None);
+        }
+        self.send_to(id, Advertise(self.id, Capabilities::local()), now);
+        if !self.registry.local().is_empty() {
+            let services = self.registry.local().clone();
+            self.send_to(id, Services(self.id, services), now);
+        }
+        if !self.metadata.is_empty() {
+            let metadata = self.metadata.clone();
+            let version = self.metadata_version.clone();
+            self.send_to(id, Metadata(self.id, metadata, version), now);
+        }
+        let started = self.joins.push(id, now);
+        self.sync(started, now);
+    }
+
+    /// Admit the joiner, unless it's part of another cluster or speaks a
+    /// protocol version we don't.
+    fn join(&mut self, peer: Peer, cluster: &str, version: u32, now: u64) {
+        match self.refusal(cluster, version) {
+            Some(reason) => self.send_to(peer.id(), JoinRejected(self.id, reason), now),
+            None => self.admit(peer, now)
+        }
+    }
+
+    /// Why a joiner with the cluster name and protocol version can't join,
+    /// `None` if it can.
+    fn refusal(&self, cluster: &str, version: u32) -> Option<String> {
+        if cluster != self.config.cluster_name.as_slice() {
+            Some(format!("this is the {} cluster, not {}", self.config.cluster_name, cluster))
+        } else if (version as uint) < MIN_PROTOCOL_VERSION {
+            Some(format!("protocol version {} is too old, {} is the oldest supported", version,
+                         MIN_PROTOCOL_VERSION))
+        } else if (version as uint) > PROTOCOL_VERSION {
+            Some(format!("protocol version {} is too new, {} is the newest supported", version,
+                         PROTOCOL_VERSION))
+        } else {
+            None
+        }
+    }
+
+    /// A vote to evict the target, or an eviction proven with the admin key
+    /// (when it was issued, and the proof).
+    fn kill_node(&mut self, voter: Uuid, target: Uuid, proof: Option<(u64, Vec<u8>)>,
+                 now: u64) {
+        // We're not about to evict ourselves.
+        if target == self.id || self.members.find(&target).is_none() {
+            return;
+        }
+
+        let wall = self.wall(now);
+        let authenticated = match (self.config.admin_key.clone(), proof) {
+            (Some(key), Some((issued_at, proof))) => {
+                key.verify(&self.config.crypto, &target, issued_at, proof.as_slice()) &&
+                    self.is_fresh(issued_at, now) &&
+                    // Nor is a proof good twice, so it can't be replayed.
+                    self.evictions.redeem(proof, issued_at, wall)
+            },
+            _ => false
+        };
+
+        // Unknown members don't get a say.
+        if !authenticated && voter != self.id && self.members.find(&voter).is_none() {
+            return;
+        }
+
+        if authenticated || self.evictions.vote(target, voter, now) {
+            self.evicted.insert(target);
+            if self.members.set_state(&target, Dead) {
+                self.heartbeats.forget(&target);
+                self.relink(target, None, Failed, now);
+                self.emit(MemberEvicted(target));
+            }
+        }
+    }
+
+    /// Take part in a cluster-wide shutdown if the command is genuine and
+    /// meant for us: drain right away and shut down once it's our turn.
+    fn shutdown_cluster(&mut self, initiator: Uuid, role: Option<String>, issued_at: u64,
+                        proof: Vec<u8>, now: u64) {
+        if initiator == self.id || self.stopping.is_some() {
+            return;
+        }
+
+        let signed = teardown::signed(&initiator, role.as_ref().map(|r| r.as_slice()),
+                                      issued_at);
+        let authenticated = match self.config.admin_key {
+            Some(ref key) => {
+                key.verify_signed(&self.config.crypto, signed.as_slice(), proof.as_slice())
+            },
+            None => false
+        };
+
+        if !authenticated || !self.is_fresh(issued_at, now) {
+            return;
+        }
+
+        let role = role.as_ref().map(|r| r.as_slice());
+        if !teardown::has_role(&self.metadata, role) {
+            return;
+        }
+
+        let mut targets: Vec<Uuid> = self.members.iter()
+                                         .filter(|m| m.state == Alive)
+                                         .filter(|m| teardown::has_role(&m.metadata, role))
+                                         .map(|m| m.id())
+                                         .collect();
+        targets.push(self.id);
+
+        let turn = teardown::rank(&self.id, targets) as u64 * self.config.shutdown_stagger;
+        self.stopping = Some((initiator, now + turn));
+        self.set_status(Draining, now);
+        self.send_to(initiator, ShutdownReport(self.id, Stopping), now);
+    }
+
+    /// Whether an admin command issued at the time (by the initiator's wall
+    /// clock) is recent enough to act on, so old ones can't be replayed.
+    fn is_fresh(&self, issued_at: u64, now: u64) -> bool {
+        let wall = self.wall(now);
+        if wall > issued_at {
+            wall - issued_at <= VALIDITY
+        } else {
+            issued_at - wall <= VALIDITY
+        }
+    }
+
+    /// Flip the toggle ourselves, and have every live member follow.
+    fn set_feature(&mut self, name: String, enabled: bool, issued_at: u64, proof: Vec<u8>,
+                   now: u64) {
+        self.toggle(name.as_slice(), enabled, issued_at);
+
+        let targets: Vec<Uuid> = self.members.iter()
+                                     .filter(|m| m.state == Alive)
+                                     .map(|m| m.id())
+                                     .collect();
+        let rollout = Rollout::new(issued_at, enabled, proof.clone(), targets, now);
+        let (acked, total) = rollout.progress();
+        if !rollout.is_done() {
+            self.rollouts.insert(name.clone(), rollout);
+        }
+
+        self.emit(FeatureRollout(name.clone(), acked, total));
+        self.gossip(FeatureToggle(self.id, name, enabled, issued_at, proof));
+    }
+
+    /// Apply a toggle command, letting the application know if it changed
+    /// anything. Whether it's the latest command for the toggle.
+    fn toggle(&mut self, name: &str, enabled: bool, issued_at: u64) -> bool {
+        let was = self.features.is_enabled(name);
+        if !self.features.apply(name, enabled, issued_at) {
+            return false;
+        }
+
+        if was != enabled {
+            self.emit(FeatureToggled(name.to_string(), enabled));
+        }
+        true
+    }
+
+    /// Apply a toggle command if it's genuine, and ack it to the initiator.
+    fn feature_toggled(&mut self, initiator: Uuid, name: String, enabled: bool,
+                       issued_at: u64, proof: Vec<u8>, now: u64) {
+        if initiator == self.id {
+            return;
+        }
+
+        let signed = features::signed(&initiator, name.as_slice(), enabled, issued_at);
+        let authenticated = match self.config.admin_key {
+            Some(ref key) => {
+                key.verify_signed(&self.config.crypto, signed.as_slice(), proof.as_slice())
+            },
+            None => false
+        };
+        if !authenticated || !self.is_fresh(issued_at, now) {
+            return;
+        }
+
+        // A later command already won, the initiator hears from that one.
+        if self.toggle(name.as_slice(), enabled, issued_at) {
+            self.send_to(initiator, FeatureAck(self.id, name, issued_at), now);
+        }
+    }
+
+    fn feature_acked(&mut self, id: Uuid, name: String, issued_at: u64) {
+        let (progress, done) = match self.rollouts.find_mut(&name) {
+            Some(rollout) => {
+                if !rollout.ack(id, issued_at) {
+                    return;
+                }
+                (rollout.progress(), rollout.is_done())
+            },
+            None => return
+        };
+
+        if done {
+            self.rollouts.remove(&name);
+        }
+        let (acked, total) = progress;
+        self.emit(FeatureRollout(name, acked, total));
+    }
+
+    /// Send the toggles we're rolling out again to whoever didn't ack them
+    /// yet, for as long as the commands are valid. Those that died since
+    /// aren't waited on.
+    fn retry_rollouts(&mut self, now: u64) {
+        let alive: Vec<Uuid> = self.members.iter()
+                                   .filter(|m| m.state == Alive)
+                                   .map(|m| m.id())
+                                   .collect();
+        let names: Vec<String> = self.rollouts.keys().map(|name| name.clone()).collect();
+        for name in names.move_iter() {
+            let (issued_at, enabled, proof, done, due) = {
+                let rollout = self.rollouts.find_mut(&name).unwrap();
+                rollout.retain(alive.as_slice());
+                (rollout.issued_at, rollout.enabled, rollout.proof.clone(), rollout.is_done(),
+                 rollout.due(now))
+            };
+
+            if done || !self.is_fresh(issued_at, now) {
+                let (acked, total) = self.rollouts.pop(&name).unwrap().progress();
+                self.emit(FeatureRollout(name, acked, total));
+                continue;
+            }
+            for id in due.unwrap_or(Vec::new()).move_iter() {
+                let toggle = FeatureToggle(self.id, name.clone(), enabled, issued_at,
+                                           proof.clone());
+                self.send_to(id, toggle, now);
+            }
+        }
+    }
+
+    fn stop_if_due(&mut self, now: u64) {
+        match self.stopping {
+            Some((initiator, due)) if due <= now => {
+                self.send_to(initiator, ShutdownReport(self.id, Stopped), now);
+                self.out.push(Shutdown);
+                self.stopping = None;
+            },
+            _ => {}
+        }
+    }
+
+    /// Tell every member we're leaving on purpose, once we gossiped
+    /// whatever we still had to, so they don't have to wait on the failure
+    /// detector to notice we're gone. We shut down once every live member
+    /// acked it, or after `Config::leave_timeout`.
+    fn leave(&mut self, now: u64) {
+        if self.leaving.is_some() {
+            return;
+        }
+
+        self.set_status(Draining, now);
+        // Along with whatever else we still had to gossip, and retransmitted
+        // like any other change until every member acked it.
+        self.updates.push(Departed(self.id, self.incarnation), now);
+        for update in self.updates.take().move_iter() {
+            self.retransmits.push(update);
+        }
+        let updates = self.retransmits.take(self.retransmits.len(), self.members.len());
+        if !updates.is_empty() {
+            self.gossip(Digest(updates));
+        }
+        self.lazy_push(now);
+
+        let waiting = self.members.iter()
+                                  .filter(|member| member.state == Alive)
+                                  .map(|member| member.id())
+                                  .collect();
+        self.leaving = Some((waiting, now + self.config.leave_timeout));
+        self.leave_if_done(now);
+    }
+
+    fn leave_if_done(&mut self, now: u64) {
+        let done = match self.leaving {
+            Some((ref waiting, due)) => waiting.is_empty() || due <= now,
+            None => false
+        };
+        if done {
+            self.out.push(Shutdown);
+            self.leaving = None;
+        }
+    }
+
+    /// A member leaving on purpose is gone right away, rather than once the
+    /// failure detector takes it for dead. Unless it left before refuting
+    /// something since, that is. Every copy of the leave is acked, in case
+    /// the earlier acks got lost.
+    fn left(&mut self, id: Uuid, incarnation: u64, now: u64) {
+        let stale = match self.members.find(&id) {
+            Some(member) => incarnation < member.incarnation,
+            None => true
+        };
+        if id == self.id || stale {
+            return;
+        }
+
+        self.send_to(id, LeaveAck(self.id), now);
+        self.members.set_incarnation(&id, incarnation);
+        if self.members.set_state(&id, Dead) {
+            self.heartbeats.forget(&id);
+            self.relink(id, None, Left, now);
+            self.emit(MemberLeft(id));
+        }
+    }
+
+    /// Apply a single membership change received from the cluster.
+    fn apply(&mut self, update: Update, now: u64) {
+        match update {
+            Joined(peer) => {
+                let id = peer.id();
+                if id != self.id && self.members.insert(peer) {
+                    self.relink(id, Some(Eager), Added, now);
+                    self.emit(MemberJoined(id));
+                    // The snapshot it joined with doesn't carry our metadata,
+                    // and leaves especially need it to know we don't relay.
+                    if !self.metadata.is_empty() {
+                        let metadata = self.metadata.clone();
+                        let version = self.metadata_version.clone();
+                        self.send_to(id, Metadata(self.id, metadata, version), now);
+                    }
+                }
+            },
+            StatusUpdate(id, status) => {
+                if self.members.set_status(&id, status) {
+                    self.emit(Event::for_status(id, status));
+                }
+            },
+            Liveness(id, state) => self.liveness(id, state, 0, now),
+            LivenessAt(id, state, incarnation) => self.liveness(id, state, incarnation, now),
+            Departed(id, incarnation) => self.left(id, incarnation, now),
+            Moved(peer, incarnation) => {
+                if peer.id() != self.id {
+                    self.relocate(&peer, Some(incarnation));
+                }
+            },
+            Refuted(peer, incarnation) => {
+                let id = peer.id();
+                let newer = match self.members.find(&id) {
+                    Some(member) => incarnation > member.incarnation,
+                    None => false
+                };
+                if !newer || self.evicted.contains(&id) {
+                    return;
+                }
+
+                self.relocate(&peer, Some(incarnation));
+                if self.members.set_state(&id, Alive) {
+                    self.swim.clear(&id);
+                    self.relink(id, Some(Eager), Added, now);
+                    self.emit(MemberStateChanged(id, Alive));
+                    self.check_liveness(false);
+                }
+            }
+        }
+    }
+
+    /// A member's liveness as of the incarnation, ordered the way SWIM has
+    /// it: a higher incarnation wins, and at the same one a worse state
+    /// does. Whatever's older than what we know is stale, so a suspicion
+    /// (or death) we outdid once doesn't come back to haunt us.
+    fn liveness(&mut self, id: Uuid, state: MemberState, incarnation: u64, now: u64) {
+        if id == self.id {
+            if state != Alive && incarnation >= self.incarnation {
+                self.refute(incarnation, now);
+            }
+            return;
+        }
+
+        let wins = match self.members.find(&id) {
+            Some(member) => incarnation > member.incarnation
+                || (incarnation == member.incarnation
+                    && state.severity() > member.state.severity()),
+            None => false
+        };
+        if !wins || (state == Alive && self.evicted.contains(&id)) {
+            return;
+        }
+        self.members.set_incarnation(&id, incarnation);
+        self.set_liveness(id, state, now);
+    }
+
+    /// Switch a member's liveness, whatever the incarnation.
+    fn set_liveness(&mut self, id: Uuid, state: MemberState, now: u64) {
+        let was = self.members.find(&id).map(|member| member.state);
+        if !self.members.set_state(&id, state) {
+            return;
+        }
+
+        match state {
+            Suspect => self.swim.suspect(id, now),
+            _ => self.swim.clear(&id)
+        }
+        match (was, state) {
+            (_, Dead) => self.relink(id, None, Failed, now),
+            (Some(Dead), _) => self.relink(id, Some(Eager), Added, now),
+            _ => {}
+        }
+        self.emit(MemberStateChanged(id, state));
+        self.check_liveness(false);
+    }
+
+    /// Outdo whoever takes us for suspect or dead as of the incarnation:
+    /// the cluster hears we're alive at the next one. Hearing it again
+    /// doesn't bump us any further.
+    fn refute(&mut self, incarnation: u64, now: u64) {
+        let peer = match self.addr {
+            Some(ref addr) => Peer::new(self.id, addr.ip.as_slice(), addr.port),
+            // Nobody can probe us yet, let alone suspect us.
+            None => return
+        };
+        self.incarnation = cmp::max(self.incarnation, incarnation) + 1;
+        self.announce(Refuted(peer, self.incarnation), now);
+    }
+
+    /// Move a peer within the broadcast tree, journaling the change.
+    /// Cordoned peers never make it past the lazy set.
+    fn relink(&mut self, id: Uuid, link: Option<Link>, reason: Reason, now: u64) {
+        let link = match link {
+            Some(Eager) if self.is_cordoned(&id) => Some(Lazy),
+            link => link
+        };
+        let peer = id.to_string();
+        let from = self.state.set_link(peer.as_slice(), link.clone());
+        if from == link {
+            return;
+        }
+
+        // Whatever it was a parent for, it's not relaying to us anymore.
+        if link.is_none() {
+            self.parents.forget(&id);
+            self.tree.forget(&id);
+        }
+
+        let at = self.wall(now);
+        self.journal.record(TopologyChange {
+            at: at,
+            peer: peer,
+            from: from,
+            to: link,
+            reason: reason
+        });
+    }
+
+    /// Make the peer that kept outperforming a parent (or answering our
+    /// grafts in it's place) a tree edge, and the parent a lazy one.
+    fn reparent(&mut self, demoted: Uuid, promoted: Uuid, now: u64) {
+        if self.is_cordoned(&promoted) {
+            return;
+        }
+        if self.is_alive(&promoted) {
+            self.relink(promoted, Some(Eager), Grafted, now);
+            self.send_to(promoted, Graft(self.id, Vec::new()), now);
+        }
+        if self.is_alive(&demoted) {
+            self.relink(demoted, Some(Lazy), LatencyDemoted, now);
+            self.send_to(demoted, Prune(self.id), now);
+        }
+    }
+
+    /// Take an eager peer that sent us a duplicate off the tree, on both
+    /// ends.
+    fn prune(&mut self, id: Uuid, now: u64) {
+        if self.state.link(id.to_string().as_slice()) != Some(Eager) {
+            return;
+        }
+
+        self.relink(id, Some(Lazy), Pruned, now);
+        self.send_to(id, Prune(self.id), now);
+    }
+
+    /// Tell our lazy peers about what we relayed since the last round.
+    fn lazy_push(&mut self, now: u64) {
+        let batches = self.tree.take_announcements(self.config.ihave_max_ids);
+        if !batches.is_empty() {
+            let lazy: Vec<Uuid> = self.members.iter()
+                                      .map(|member| member.id())
+                                      .filter(|id| {
+                                          self.state.link(id.to_string().as_slice()) == Some(Lazy)
+                                      })
+                                      .collect();
+            for id in lazy.move_iter() {
+                for ids in batches.iter() {
+                    self.send_to(id, IHave(self.id, ids.clone()), now);
+                }
+            }
+        }
+    }
+
+    /// Graft whoever announced what never reached us in time. Cordoned
+    /// peers are passed over, like they are for eager push.
+    fn graft(&mut self, now: u64) {
+        for (broadcast, peer) in self.tree.due(now).move_iter() {
+            if self.state.has_seen(&broadcast) || !self.is_alive(&peer) || self.is_cordoned(&peer) {
+                continue;
+            }
+            self.relink(peer, Some(Eager), Grafted, now);
+            self.send_to(peer, Graft(self.id, vec![broadcast]), now);
+        }
+    }
+
+    /// Sync with a live member at random, when it's time to. See
+    /// `antientropy`.
+    fn anti_entropy(&mut self, now: u64) {
+        if !self.entropy.due(now) {
+            return;
+        }
+
+        let alive: Vec<Uuid> = self.members.iter()
+                                   .filter(|member| member.state == Alive)
+                                   .map(|member| member.id())
+                                   .collect();
+        match self.entropy.pick(alive.as_slice()) {
+            Some(id) => {
+                let digest = self.sync_digest(false);
+                self.send_to(id, digest, now);
+            },
+            None => {}
+        }
+    }
+
+    /// Probe the members and act on what the failure detector makes of
+    /// it, see `swim`.
+    fn probe(&mut self, now: u64) {
+        self.swim.set_multiplier(self.pressure.multiplier(now));
+        let members: Vec<Uuid> = self.members.iter()
+                                     .filter(|member| self.probing && member.state != Dead)
+                                     .map(|member| member.id())
+                                     .collect();
+        for action in self.swim.tick(members.as_slice(), now).move_iter() {
+            match action {
+                Probe(id, seq) => self.send_to(id, Ping(self.id, seq), now),
+                ProbeThrough(helpers, id, seq) => {
+                    for helper in helpers.move_iter() {
+                        self.send_to(helper, PingReq(self.id, id, seq), now);
+                    }
+                },
+                Suspected(id) => self.declare(id, Suspect, now),
+                Confirmed(id) => self.declare(id, Dead, now)
+            }
+        }
+    }
+
+    /// Change the member's liveness as the failure detector sees it, and
+    /// let the cluster know. Dead members only come back by rejoining, or
+    /// refuting it (see `Refuted`). Others only take a suspect for alive
+    /// again on it's refutation.
+    fn declare(&mut self, id: Uuid, state: MemberState, now: u64) {
+        let member = self.members.find(&id).map(|member| (member.state, member.incarnation));
+        let incarnation = match (member, state) {
+            (Some((Alive, incarnation)), Suspect) | (Some((Suspect, incarnation)), Dead)
+                | (Some((Suspect, incarnation)), Alive) => incarnation,
+            _ => return
+        };
+        self.set_liveness(id, state, now);
+        self.announce(LivenessAt(id, state, incarnation), now);
+    }
+
+    /// Turn our health yellow while members are suspect, and green once
+    /// none are and a member acked (one just did if `acked`). Worse health
+    /// is left alone, and so is the yellow of sustained pressure.
+    fn check_liveness(&mut self, acked: bool) {
+        let failing = self.members.iter().any(|member| member.state == Suspect);
+        if failing && self.health() == Green {
+            self.set_health(Yellow);
+        } else if acked && !failing && self.health() == Yellow && !self.pressured
+                  && !self.locked {
+            self.set_health(Green);
+        }
+    }
+
+    fn sync_digest(&self, reply: bool) -> Message {
+        let mut members = antientropy::digest(&self.members);
+        members.push((self.id, self.incarnation, Alive));
+        let ids = if self.through_tree() {
+            Vec::new()
+        } else {
+            self.state.broadcasts().iter().map(|broadcast| broadcast.id()).collect()
+        };
+        SyncDigest(self.id, members, ids, reply)
+    }
+
+    /// Whether we retain too many broadcasts to list them, see `merkle`.
+    fn through_tree(&self) -> bool {
+        self.state.retained_len() > self.config.merkle_threshold
+    }
+
+    /// The broadcasts we retain in those leaves of the tree.
+    fn leaf_ids(&self, leaves: &[u32]) -> Vec<Uuid> {
+        let leaves: HashSet<u32> = leaves.iter().map(|leaf| *leaf).collect();
+        self.state.broadcasts().iter()
+                  .map(|broadcast| broadcast.id())
+                  .filter(|id| leaves.contains(&merkle::leaf_of(id)))
+                  .collect()
+    }
+
+    fn relay_retained(&mut self, to: Uuid, id: &Uuid) {
+        match self.state.retained(id) {
+            Some(broadcast) => self.out.push(RelayTo(to, broadcast.clone())),
+            None => {}
+        }
+    }
+
+    fn is_known(&self, id: &Uuid) -> bool {
+        *id == self.id || self.members.find(id).is_some()
+    }
+
+    fn is_alive(&self, id: &Uuid) -> bool {
+        self.members.find(id).map(|m| m.state == Alive).unwrap_or(false)
+    }
+
+    fn is_cordoned(&self, id: &Uuid) -> bool {
+        self.members.find(id).map(|m| m.is_cordoned()).unwrap_or(false)
+    }
+
+    /// Take a member that was (un)cordoned out of the eager set, or back
+    /// into it unless it's standing says otherwise.
+    fn recordon(&mut self, id: Uuid, was: bool, now: u64) {
+        let cordoned = self.is_cordoned(&id);
+        let link = self.state.link(id.to_string().as_slice());
+
+        if cordoned && !was && link == Some(Eager) {
+            self.relink(id, Some(Lazy), Cordoned, now);
+        } else if was && !cordoned && link == Some(Lazy) && self.is_alive(&id) {
+            let good = self.scores.find(&id).map(|s| s.standing == Good).unwrap_or(true);
+            if good {
+                self.relink(id, Some(Eager), Uncordoned, now);
+            }
+        }
+    }
+
+    /// Charge a member for misbehaving.
+    fn penalize(&mut self, id: Uuid, violation: Violation, now: u64) {
+        if id == self.id {
+            return;
+        }
+
+        match self.scores.penalize(id, violation, now) {
+            Some(standing) => self.restand(id, standing, Penalized, now),
+            None => {}
+        }
+    }
+
+    /// Move a member within the broadcast tree according to it's new
+    /// standing. Dead members stay out of it.
+    fn restand(&mut self, id: Uuid, standing: Standing, reason: Reason, now: u64) {
+        let alive = self.is_alive(&id);
+
+        match standing {
+            Good if alive => self.relink(id, Some(Eager), reason, now),
+            Demoted if alive => self.relink(id, Some(Lazy), reason, now),
+            Disconnected => {
+                self.relink(id, None, reason, now);
+                self.out.push(Disconnect(id));
+            },
+            _ => {}
+        }
+
+        self.emit(StandingChanged(id, standing));
+    }
+
+    /// Switch a member over to the peer's address in one go: it keeps it's
+    /// state and the transport swaps the connections.
+    fn relocate(&mut self, peer: &Peer, incarnation: Option<u64>) {
+        let id = peer.id();
+        if self.members.relocate(&id, peer.addr(), incarnation) {
+            self.out.push(Reconnect(id, peer.addr().clone()));
+            self.emit(MemberMoved(id, peer.addr().clone()));
+        }
+    }
+
+    /// Send out a heartbeat if one is due.
+    fn heartbeat(&mut self, now: u64) {
+        match self.heartbeats.due(now) {
+            Some(payload) => self.gossip(Heartbeat(self.id, payload, self.pressure.level())),
+            None => {}
+        }
+    }
+
+    /// Queue a membership change to be disseminated.
+    fn announce(&mut self, update: Update, now: u64) {
+        self.updates.push(update, now);
+        self.flush_updates(now);
+    }
+
+    /// Move the coalesced changes onto the retransmit queue, then send out
+    /// whatever the queue deems most urgent this round.
+    fn flush_updates(&mut self, now: u64) {
+        match self.updates.flush(now) {
+            Some(updates) => {
+                for update in updates.move_iter() {
+                    self.retransmits.push(update);
+                }
+            },
+            None => {}
+        }
+
+        let mut updates = self.retransmits.take(self.config.max_updates_per_round,
+                                                self.members.len());
+        match updates.len() {
+            0 => {},
+            1 => match updates.pop().unwrap() {
+                StatusUpdate(id, status) => self.gossip(StatusChange(id, status)),
+                update => self.gossip(Digest(vec![update]))
+            },
+            _ => self.gossip(Digest(updates))
+        }
+    }
+
+    /// Enter or leave the minority lockdown depending on the current
+    /// membership. We keep probing while locked, so this is re-evaluated
+    /// as members come back and the partition heals.
+    fn check_quorum(&mut self) {
+        if !self.config.minority_lockdown {
+            return;
+        }
+
+        let quorate = self.is_quorate();
+
+        if !quorate && !self.locked {
+            self.locked = true;
+            self.set_health(Red);
+        } else if quorate && self.locked {
+            self.locked = false;
+            self.set_health(if self.degraded { Red } else { Yellow });
+        }
+    }
+
+    /// Carry out the storage policy. Membership doesn't depend on the disk,
+    /// so either way the rest of the cluster is none the wiser.
+    fn storage_failed(&mut self, err: GossipError) {
+        if self.degraded {
+            return;
+        }
+
+        self.emit(StorageFailed(format!("{}", err)));
+        match self.config.storage_policy {
+            FailStop => self.out.push(Shutdown),
+            MemoryOnly => {
+                self.degraded = true;
+                self.commits.clear();
+                self.log.clear();
+                self.set_health(Red);
+            }
+        }
+    }
+
+    /// Apply a snapshot received from another node. The snapshot has
+    /// already been fully decoded and verified at this point.
+    fn restore(&mut self, snapshot: Snapshot, now: u64) {
+        for peer in snapshot.members.move_iter() {
+            let id = peer.id();
+            if id != self.id && self.members.insert(peer) {
+                self.relink(id, Some(Eager), Added, now);
+            }
+        }
+
+        for wire in snapshot.broadcasts.move_iter() {
+            match wire.to_broadcast() {
+                Ok(broadcast) => {
+                    self.state.observe(broadcast.id());
+                    self.state.record(broadcast, now);
+                },
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Serve a full-state sync to each of the joiners. The snapshot is
+    /// encoded once and the same blob is streamed to all of them (and
+    /// persisted, if configured).
+    fn sync(&mut self, joiners: Vec<Uuid>, now: u64) {
+        if joiners.is_empty() {
+            return;
+        }
+
+        let snapshot = self.snapshot();
+        let blob = snapshot.encode();
+
+        if self.config.snapshot_path.is_some() && !self.degraded {
+            self.commits.push(snapshot, now);
+        }
+
+        for id in joiners.move_iter() {
+            self.send_to(id, JoinSync(self.id, blob.clone()), now);
+        }
+    }
+
+    /// Tell the cluster our metadata, i.e., once we're let in: nobody
+    /// heard of it before.
+    fn announce_metadata(&mut self) {
+        let (metadata, version) = (self.metadata.clone(), self.metadata_version.clone());
+        self.gossip(Metadata(self.id, metadata, version));
+    }
+
+    fn advertise_metadata(&mut self) {
+        self.announce_metadata();
+        self.emit(MetadataChanged(self.id));
+    }
+
+    /// Metadata we heard of, at the version. Older versions are dropped,
+    /// newer ones replace ours and concurrent ones go through the merge
+    /// hook (without one, theirs win).
+    fn merge_metadata(&mut self, id: Uuid, theirs: HashMap<String, String>,
+                      version: VersionVector, now: u64) {
+        if id == self.id {
+            // Written by another member with our identity. Without a hook
+            // we stick with ours, we're the one advertising it.
+            if self.metadata_version.descends(&version) || self.merge.is_none() {
+                return;
+            }
+
+            let merged = if version.descends(&self.metadata_version) {
+                theirs
+            } else {
+                self.merge.get_ref().merge(&id, &self.metadata, &theirs)
+            };
+            self.metadata = merged;
+            // A version of our own so everyone ends up with the outcome.
+            self.metadata_version = self.metadata_version.merged(&version).bumped(self.writer);
+            self.advertise_metadata();
+            return;
+        }
+
+        let (ours, current) = match self.members.find(&id) {
+            Some(member) => (member.metadata.clone(), member.metadata_version.clone()),
+            None => return
+        };
+        if current.descends(&version) {
+            return;
+        }
+
+        let metadata = match self.merge {
+            Some(ref merge) if !version.descends(&current) => merge.merge(&id, &ours, &theirs),
+            _ => theirs
+        };
+        let was = self.is_cordoned(&id);
+        if self.members.set_metadata(&id, metadata, current.merged(&version)) {
+            self.emit(MetadataChanged(id));
+            self.recordon(id, was, now);
+        }
+    }
+
+    /// Ask the member that synced us for the bootstrap-critical topics,
+    /// unless there are none.
+    fn start_bootstrap(&mut self, from: Uuid, now: u64) {
+        if self.config.bootstrap_topics.is_empty() || self.status != Active
+            || self.bootstrap.is_some() {
+            return;
+        }
+
+        let topics = self.config.bootstrap_topics.clone();
+        let deadline = now + self.config.bootstrap_timeout;
+        self.bootstrap = Some(Bootstrap::new(from, topics.as_slice(), deadline));
+        self.set_status(Bootstrapping, now);
+        self.send_to(from, BootstrapRequest(self.id, topics), now);
+    }
+
+    fn bootstrapped(&mut self, from: Uuid, broadcasts: Vec<Broadcast>, now: u64) {
+        let fresh = match self.bootstrap {
+            Some(ref mut bootstrap) if bootstrap.from() == from => bootstrap.answered(broadcasts),
+            _ => return
+        };
+
+        // Whatever was gossiped to us meanwhile was delivered already.
+        for broadcast in fresh.move_iter() {
+            if self.state.observe(broadcast.id()) {
+                self.state.record(broadcast.clone(), now);
+                self.deliver(broadcast, now);
+            }
+        }
+        self.finish_bootstrap(now);
+    }
+
+    /// Go `Active`, unless the application started draining meanwhile.
+    fn finish_bootstrap(&mut self, now: u64) {
+        self.bootstrap = None;
+        if self.status == Bootstrapping {
+            self.set_status(Active, now);
+        }
+    }
+
+    /// Ask for the live members to be cached if they changed since.
+    fn cache_peers(&mut self) {
+        if self.config.peer_cache_path.is_none() || self.degraded {
+            return;
+        }
+
+        let mut peers: Vec<Peer> = self.members.iter()
+                                       .filter(|m| m.state == Alive)
+                                       .map(|m| m.peer.clone())
+                                       .collect();
+        peers.sort_by(|a, b| a.id().as_bytes().cmp(&b.id().as_bytes()));
+
+        if peers != self.cached {
+            self.cached = peers.clone();
+            self.out.push(CachePeers(peers));
+        }
+    }
+
+    /// Let the cluster know our services changed. The whole set is sent
+    /// every time, it's never big.
+    fn advertise_services(&mut self, name: String) {
+        let services = self.registry.local().clone();
+        self.gossip(Services(self.id, services));
+        self.emit(ServiceChanged(name));
+    }
+
+    /// The ids of the members that may take on work (coordinating, owning
+    /// keys), ourselves included. A draining node shouldn't be picking up
+    /// anything new.
+    fn eligible(&self) -> Vec<Uuid> {
+        let mut eligible: Vec<Uuid> = self.members.iter()
+                                                  .filter(|m| m.is_voting())
+                                                  .map(|m| m.id())
+                                                  .collect();
+        if self.status == Active {
+            eligible.push(self.id);
+        }
+
+        eligible
+    }
+
+    /// Re-evaluate the coordinator hint.
+    fn elect(&mut self, now: u64) {
+        let eligible = self.eligible();
+
+        if self.coordinator.update(eligible.as_slice(), now) {
+            let current = self.coordinator.current();
+            self.emit(CoordinatorChanged(current));
+        }
+    }
+
+    /// Rebuild the hash ring if the eligible members (or their weights)
+    /// changed.
+    fn rebalance(&mut self) {
+        let members: Vec<(Uuid, uint)> = self.eligible().move_iter().map(|id| {
+            let metadata = if id == self.id {
+                Some(&self.metadata)
+            } else {
+                self.members.find(&id).map(|m| &m.metadata)
+            };
+
+            let weight = metadata.and_then(|m| m.find_equiv(&"weight"))
+                                 .and_then(|w| from_str::<uint>(w.as_slice()))
+                                 .unwrap_or(1);
+            (id, weight)
+        }).collect();
+
+        if self.ring.rebuild(members) {
+            self.epoch = self.epoch.saturating_add(1);
+            self.gossip(RingEpoch(self.epoch));
+            self.emit(RingChanged(self.epoch));
+        }
+    }
+
+    #[cfg(feature = "paranoid-checks")]
+    fn check_invariants(&self, now: u64) {
+        invariants::check(&self.id, &self.members, &self.state, now);
+    }
+
+    #[cfg(not(feature = "paranoid-checks"))]
+    fn check_invariants(&self, _: u64) {}
+
+    /// Warn once the cluster runs more versions than the policy allows,
+    /// and get ready to warn again once it's back within.
+    fn check_versions(&mut self) {
+        let max = match self.config.max_versions {
+            Some(max) => max,
+            None => return
+        };
+
+        let report = self.versions();
+        let skewed = report.is_skewed(max);
+        if skewed && !self.skewed {
+            self.emit(VersionSkew(report.list()));
+        }
+        self.skewed = skewed;
+    }
+
+    /// Turn our health yellow while the host is under sustained high
+    /// pressure, and back once it's over. Worse health is left alone.
+    fn check_pressure(&mut self, now: u64) {
+        let pressured = self.pressure.sustained(now) == High;
+        if pressured && !self.pressured && self.health() == Green {
+            self.pressured = true;
+            self.set_health(Yellow);
+        } else if !pressured && self.pressured {
+            self.pressured = false;
+            if self.health() == Yellow {
+                self.set_health(Green);
+            }
+        }
+    }
+
+    /// Add to the timeline, if our health or the membership changed. Only
+    /// sampled on ticks, counting the members on every input adds up.
+    fn record_health(&mut self, now: u64) {
+        let (mut alive, mut suspect, mut dead) = (0, 0, 0);
+        for member in self.members.iter() {
+            match member.state {
+                Alive => alive += 1,
+                Suspect => suspect += 1,
+                Dead => dead += 1
+            }
+        }
+        let record = HealthRecord {
+            at: self.wall(now),
+            health: self.health(),
+            alive: alive,
+            suspect: suspect,
+            dead: dead
+        };
+        self.timeline.observe(record);
+    }
+
+    fn set_health(&mut self, health: Health) {
+        if self.state.set_health(health.clone()) {
+            self.emit(HealthChanged(health));
+        }
+    }
+
+    fn set_status(&mut self, status: Status, now: u64) {
+        if self.status == status {
+            return;
+        }
+
+        self.status = status;
+        self.emit(Event::for_status(self.id, status));
+        self.announce(StatusUpdate(self.id, status), now);
+        self.check_quorum();
+    }
+
+    /// Our wall clock at the monotonic time `now`.
+    fn wall(&self, now: u64) -> u64 {
+        let wall = now as i64 + self.wall_offset;
+        if wall < 0 { 0 } else { wall as u64 }
+    }
+
+    fn deliver(&mut self, broadcast: Broadcast, now: u64) {
+        let wall = self.wall(now);
+        self.recent.record(BroadcastRecord::new(&broadcast, Delivered, wall));
+        let topic = broadcast.topic();
+        self.counters.delivered(topic.as_slice(), broadcast.len());
+        match self.bootstrap {
+            Some(ref mut bootstrap) => bootstrap.delivered(topic.as_slice()),
+            None => {}
+        }
+        if self.config.wal_path.is_some() && !self.degraded {
+            self.log.push(broadcast.clone(), now);
+        }
+        self.out.push(Deliver(broadcast));
+    }
+
+    /// Log the broadcasts, or rewrite the log with what we retain once it
+    /// holds twice as much (see `wal`).
+    fn append_log(&mut self, broadcasts: Vec<Broadcast>) {
+        let retained = self.state.retained_len();
+        if self.logged + broadcasts.len() > 2 * retained + self.config.commit_max_batch {
+            let broadcasts: Vec<Broadcast> = self.state.broadcasts().iter()
+                                                 .map(|broadcast| (*broadcast).clone())
+                                                 .collect();
+            self.logged = broadcasts.len();
+            self.out.push(RewriteLog(broadcasts));
+        } else {
+            self.logged += broadcasts.len();
+            self.out.push(AppendLog(broadcasts));
+        }
+    }
+
+    fn emit(&mut self, event: Event) {
+        self.out.push(Emit(event));
+    }
+
+    /// Sign an admin command with the admin key, if there is one. When the
+    /// provider fails it's an `AdminFailed` and the command isn't sent.
+    fn admin_sign(&mut self, command: &str, data: &[u8]) -> Option<Vec<u8>> {
+        let signed = match self.config.admin_key {
+            Some(ref key) => key.sign(&self.config.crypto, data),
+            None => return None
+        };
+        match signed {
+            Ok(proof) => Some(proof),
+            Err(err) => {
+                self.emit(AdminFailed(command.to_string(), format!("{}", err)));
+                None
+            }
+        }
+    }
+
+    fn gossip(&mut self, msg: Message) {
+        self.out.push(Gossip(msg));
+    }
+
+    /// Like `gossip` but only meant for a single member. Nothing is sent
+    /// while the member's circuit breaker is open.
+    fn send_to(&mut self, id: Uuid, msg: Message, now: u64) {
+        if self.breakers.allow(&id, now) {
+            self.out.push(SendTo(id, msg));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+    use member::{Active, Draining, Bootstrapping, Alive, Dead, CORDON_KEY};
+    use message::{BootstrapRequest, BootstrapBroadcasts};
+    use merge::{VersionVector, MetadataMerge};
+    use message::{StatusChange, QuotaExceeded, JoinRequest, JoinSync, JoinSynced, Digest};
+    use message::{Joined, StatusUpdate, Advertise, KillNode, Services, Metadata, RingEpoch};
+    use message::{Moved, Heartbeat, Liveness, LivenessAt, PreviewRequest, Preview};
+    use version::{CRATE_VERSION, VERSION_KEY};
+    use ops::{SyncingJoiner, QueuedJoiner, EvictionBallot, SuspectMember, MissingBroadcast};
+    use std::collections::HashMap;
+    use event::{Event, MemberDraining, MemberActive, MemberJoined, MemberEvicted, Throttled};
+    use event::{ServiceChanged, CoordinatorChanged, RingChanged, MemberMoved, VersionSkew};
+    use event::{StorageFailed, ShutdownProgress, StandingChanged, MetadataChanged};
+    use message::{ShutdownCluster, ShutdownReport, FeatureToggle, FeatureAck};
+    use event::{FeatureToggled, FeatureRollout, HealthChanged};
+    use pressure::{Low, Elevated, High};
+    use recent::{Delivery, Delivered, Late};
+    use teardown::{Stopping, Stopped, VALIDITY};
+    use teardown;
+    use message::{IHave, Graft, Prune, SyncDigest, TreeNodes, Ping, PingReq, Ack, Refuted};
+    use message::{Join, JoinRejected, LeaveAck, Departed, Direct, KillNodeAt};
+    use merkle;
+    use member::Suspect;
+    use event::{MemberStateChanged, JoinRefused, MemberLeft, AdminFailed};
+    use journal::{Reason, Eager, Lazy, Added, Failed, Penalized, Recovered, Cordoned, Uncordoned};
+    use score::{Undecodable, ProtocolViolation, Good, Demoted, Disconnected};
+    use snapshot::FailStop;
+    use result::{GossipResult, GossipError, UnknownError, io_err};
+    use crypto::{Crypto, Provider};
+    use cipher::Cipher;
+    use std::io;
+    use registry::Service;
+    use stream::SockAddr;
+    use trace::TraceContext;
+    use eviction::AdminKey;
+    use topic::{TopicSettings, Capabilities, Raw, MsgPack, Bincode, Uncompressed, Lz4};
+    use protocol::{Peer, Green, Red, Yellow};
+    use broadcast::Broadcast;
+    use config::Config;
+    use codec::{PROTOCOL_VERSION, WireBroadcast};
+    use quota::Quota;
+
+    fn engine(config: Config) -> Engine {
+        Engine::new(Uuid::new_v4(), config)
+    }
+
+    fn events(outputs: Vec<Output>) -> Vec<Event> {
+        outputs.move_iter().filter_map(|output| match output {
+            Emit(event) => Some(event),
+            _ => None
+        }).collect()
+    }
+
+    fn delivered(outputs: &Vec<Output>) -> bool {
+        outputs.iter().any(|output| match *output {
+            Deliver(_) => true,
+            _ => false
+        })
+    }
+
+    #[test]
+    fn publish_without_members() {
+        let mut engine = engine(Config::new());
+        let outputs = engine.handle(Publish(Broadcast::new(vec![1u8]).unwrap()), 0);
+
+        assert_eq!(outputs.len(), 1);
+        match *outputs.get(0) {
+            Deliver(ref broadcast) => assert_eq!(broadcast.origin(), engine.id()),
+            _ => fail!("expected the broadcast to be delivered")
+        }
+    }
+
+    #[test]
+    fn peer_draining_drops_out_of_quorum() {
+        let mut engine = engine(Config::new());
+        let peer = Peer::empty();
+        let id = peer.id();
+        engine.members.insert(peer);
+
+        let outputs = engine.handle(Received(StatusChange(id, Draining)), 0);
+        assert_eq!(engine.members.voting(), 0);
+        assert_eq!(events(outputs), vec![MemberDraining(id)]);
+    }
+
+    #[test]
+    fn minority_lockdown() {
+        let mut config = Config::new();
+        config.quorum = Some(2);
+        config.minority_lockdown = true;
+
+        let mut engine = engine(config);
+        let peer = Peer::empty();
+        let id = peer.id();
+        engine.members.insert(peer);
+        assert!(engine.is_quorate());
+
+        engine.handle(Received(StatusChange(id, Draining)), 0);
+        assert!(!engine.is_quorate());
+        assert_eq!(engine.health(), Red);
+        assert!(engine.can_publish(&Broadcast::new(vec![1u8]).unwrap()).is_err());
+
+        engine.handle(Received(StatusChange(id, Active)), 0);
+        assert!(engine.is_quorate());
+        assert_eq!(engine.health(), Yellow);
+    }
+
+    #[test]
+    fn drop_broadcasts_over_quota() {
+        let mut config = Config::new();
+        config.origin_quota = Some(Quota::new(1, 1024));
+
+        let mut engine = engine(config);
+        let origin = Uuid::new_v4();
+        let from = |origin: Uuid| {
+            let mut broadcast = Broadcast::new(vec![1u8]).unwrap();
+            broadcast.set_origin(origin);
+            Relayed(broadcast)
+        };
+
+        assert!(delivered(&engine.handle(from(origin), 0)));
+        assert!(!delivered(&engine.handle(from(origin), 0)));
+        assert!(delivered(&engine.handle(from(Uuid::new_v4()), 0)));
+
+        // A second later the origin has it's quota back.
+        assert!(delivered(&engine.handle(from(origin), 1000)));
+
+        // Whoever relayed what's over the quota is charged for it.
+        let relayer = Uuid::new_v4();
+        let mut broadcast = Broadcast::new(vec![1u8]).unwrap();
+        broadcast.set_origin(origin);
+        engine.handle(RelayedBy(relayer, broadcast), 1000);
+        let charged: Vec<Uuid> = engine.peer_scores().iter().map(|&(id, _)| id).collect();
+        assert_eq!(charged, vec![relayer]);
+    }
+
+    #[test]
+    fn count_duplicates() {
+        let mut engine = engine(Config::new());
+        let mut broadcast = Broadcast::new(vec![1u8]).unwrap();
+        broadcast.hop();
+
+        assert!(delivered(&engine.handle(Relayed(broadcast.clone()), 0)));
+        assert!(!delivered(&engine.handle(Relayed(broadcast.clone()), 0)));
+        assert!(!delivered(&engine.handle(Relayed(broadcast), 0)));
+
+        let stats = engine.stats();
+        assert_eq!(stats.unique, 1);
+        assert_eq!(stats.duplicates, 2);
+        assert_eq!(stats.redundancy, Some(2.0));
+        assert_eq!(stats.average_hops, Some(1.0));
+    }
+
+    #[test]
+    fn evictions_need_confirmations() {
+        let mut config = Config::new();
+        config.eviction_confirmations = Some(2);
+
+        let mut engine = engine(config);
+        let (target, a, b) = (Peer::empty(), Peer::empty(), Peer::empty());
+        let (id, a, b) = (target.id(), a.id(), b.id());
+        engine.members.insert(target);
+        engine.members.insert(Peer::new(a, "localhost", 1));
+        engine.members.insert(Peer::new(b, "localhost", 2));
+
+        engine.handle(Received(KillNode(a, id, None)), 0);
+        engine.handle(Received(KillNode(Uuid::new_v4(), id, None)), 0);
+        assert_eq!(engine.members.find(&id).unwrap().state, Alive);
+
+        let outputs = engine.handle(Received(KillNode(b, id, None)), 0);
+        assert_eq!(engine.members.find(&id).unwrap().state, Dead);
+        assert_eq!(events(outputs), vec![MemberEvicted(id), RingChanged(2)]);
+    }
+
+    #[test]
+    fn admin_evictions() {
+        let key = AdminKey(7, 11);
+        let mut config = Config::new();
+        config.eviction_confirmations = Some(3);
+        config.admin_key = Some(key.clone());
+
+        let mut engine = engine(config);
+        let target = Peer::empty();
+        let id = target.id();
+        engine.members.insert(target);
+
+        let proof = key.prove(&engine.config().crypto, &id, 0).unwrap();
+        let mut forged = proof.clone();
+        *forged.get_mut(0) ^= 1;
+        engine.handle(Received(KillNodeAt(Uuid::new_v4(), id, 0, forged)), 0);
+        assert_eq!(engine.members.find(&id).unwrap().state, Alive);
+
+        // Nor do proofs that aren't bound to a time, or are stale by now.
+        let legacy = key.sign(&engine.config().crypto, id.as_bytes()).unwrap();
+        let legacy = legacy.iter().fold(0u64, |n, b| n << 8 | *b as u64);
+        engine.handle(Received(KillNode(Uuid::new_v4(), id, Some(legacy))), 0);
+        engine.handle(Received(KillNodeAt(Uuid::new_v4(), id, 0, proof.clone())), VALIDITY + 1);
+        assert_eq!(engine.members.find(&id).unwrap().state, Alive);
+
+        engine.handle(Received(KillNodeAt(Uuid::new_v4(), id, 0, proof)), 0);
+        assert_eq!(engine.members.find(&id).unwrap().state, Dead);
+    }
+
+    #[test]
+    fn negotiate_topic_settings() {
+        let mut config = Config::new();
+        config.topics.insert("blobs".to_string(), TopicSettings::new(Bincode, Lz4));
+
+        let mut engine = engine(config);
+        let peer = Peer::empty();
+        let id = peer.id();
+        engine.members.insert(peer);
+        assert_eq!(engine.wire_settings("blobs", &id), TopicSettings::new(Raw, Uncompressed));
+
+        engine.handle(Received(Advertise(id, Capabilities::local())), 0);
+        assert_eq!(engine.wire_settings("blobs", &id), TopicSettings::new(Bincode, Lz4));
+        assert_eq!(engine.wire_settings("beacon", &id), TopicSettings::new(MsgPack, Uncompressed));
+    }
+
+    #[test]
+    fn throttled_event() {
+        let mut engine = engine(Config::new());
+        let relay = Uuid::new_v4();
+
+        let outputs = engine.handle(Received(QuotaExceeded(relay)), 0);
+        assert_eq!(events(outputs), vec![Throttled(relay)]);
+    }
+
+    #[test]
+    fn pace_join_syncs() {
+        let mut config = Config::new();
+        config.join_concurrency = 1;
+
+        let mut engine = engine(config);
+        let (a, b) = (Peer::empty(), Peer::empty());
+
+        engine.handle(Received(JoinRequest(a.clone())), 0);
+        engine.handle(Received(JoinRequest(b)), 0);
+        assert_eq!(engine.members.len(), 2);
+        assert_eq!(engine.join_progress().syncing, 1);
+        assert_eq!(engine.join_progress().queued, 1);
+
+        engine.handle(Received(JoinSynced(a.id())), 0);
+        assert_eq!(engine.join_progress().syncing, 1);
+        assert_eq!(engine.join_progress().queued, 0);
+        assert_eq!(engine.join_progress().completed, 1);
+    }
+
+    #[test]
+    fn join_from_snapshot() {
+        let mut seed = engine(Config::new());
+        seed.members.insert(Peer::empty());
+        seed.state.record(Broadcast::new(vec![1u8, 2, 3]).unwrap(), 0);
+
+        // Not unless we're joining.
+        let mut joiner = engine(Config::new());
+        joiner.handle(Received(JoinSync(seed.id(), seed.snapshot().encode())), 0);
+        assert_eq!(joiner.members.len(), 0);
+        assert!(joiner.state.broadcasts().is_empty());
+
+        joiner.handle(Listening(SockAddr::new("10.0.0.1", 3444)), 0);
+        assert!(joiner.handshake().is_some());
+        joiner.handle(Received(JoinSync(seed.id(), vec![1u8, 2, 3])), 0);
+        assert_eq!(joiner.members.len(), 0);
+        joiner.handle(Received(JoinSync(seed.id(), seed.snapshot().encode())), 0);
+        assert_eq!(joiner.members.len(), 1);
+        assert_eq!(joiner.state.broadcasts().len(), 1);
+
+        // Once in, nobody gets to hand us another.
+        let mut forged = engine(Config::new());
+        forged.members.insert(Peer::empty());
+        forged.members.insert(Peer::empty());
+        joiner.handle(Received(JoinSync(forged.id(), forged.snapshot().encode())), 0);
+        assert_eq!(joiner.members.len(), 1);
+    }
+
+    #[test]
+    fn apply_digest_atomically() {
+        let mut config = Config::new();
+        config.quorum = Some(2);
+        config.minority_lockdown = true;
+
+        let mut engine = engine(config);
+        let peer = Peer::empty();
+        let id = peer.id();
+
+        let outputs = engine.handle(Received(Digest(vec![Joined(peer), StatusUpdate(id, Draining),
+                                                         StatusUpdate(id, Active)])), 0);
+        assert_eq!(engine.members.voting(), 1);
+        assert_eq!(engine.health(), Yellow);
+        assert_eq!(events(outputs), vec![MemberJoined(id), MemberDraining(id), MemberActive(id),
+                                         RingChanged(1)]);
+    }
+
+    #[test]
+    fn discover_services() {
+        let mut engine = engine(Config::new());
+        let peer = Peer::empty();
+        let id = peer.id();
+        engine.members.insert(peer);
+
+        let outputs = engine.handle(Received(Services(id, vec![Service::new("billing", 80)])), 0);
+        assert!(events(outputs).contains(&ServiceChanged("billing".to_string())));
+        assert_eq!(engine.lookup("billing").get(0).id, id);
+
+        engine.handle(Listening(SockAddr::new("10.0.0.1", 4000)), 0);
+        engine.handle(Register(Service::new("billing", 81)), 0);
+        assert_eq!(engine.lookup("billing").len(), 2);
+        assert!(engine.lookup("search").is_empty());
+    }
+
+    #[test]
+    fn hand_over_the_coordinator() {
+        let mut config = Config::new();
+        config.coordinator_hysteresis = 0;
+
+        let mut engine = engine(config);
+        let peer = Peer::empty();
+        let id = peer.id();
+        assert!(engine.is_coordinator());
+
+        let lower = id.as_bytes() < engine.id().as_bytes();
+        let outputs = engine.handle(Received(Digest(vec![Joined(peer)])), 0);
+        assert_eq!(engine.is_coordinator(), !lower);
+
+        let outputs = events(outputs);
+        if lower {
+            assert!(outputs.contains(&CoordinatorChanged(Some(id))));
+        }
+
+        // Draining nodes never coordinate.
+        let outputs = engine.handle(SetStatus(Draining), 0);
+        assert_eq!(engine.coordinator(), Some(id));
+        assert_eq!(events(outputs).contains(&CoordinatorChanged(Some(id))), !lower);
+    }
+
+    #[test]
+    fn weigh_members_on_the_ring() {
+        let mut engine = engine(Config::new());
+        let peer = Peer::empty();
+        let id = peer.id();
+        assert_eq!(engine.owner(b"key"), Some(engine.id()));
+
+        let outputs = engine.handle(Received(Digest(vec![Joined(peer)])), 0);
+        assert!(events(outputs).contains(&RingChanged(1)));
+        assert_eq!(engine.preference_list(b"key", 3).len(), 2);
+
+        let mut metadata = HashMap::new();
+        metadata.insert("weight".to_string(), "0".to_string());
+        let version = VersionVector::new().bumped(id);
+        let outputs = engine.handle(Received(Metadata(id, metadata, version)), 0);
+        assert!(events(outputs).contains(&RingChanged(2)));
+        assert_eq!(engine.preference_list(b"key", 3), vec![engine.id()]);
+    }
+
+    #[test]
+    fn adopt_higher_ring_epochs() {
+        let mut config = Config::new();
+        config.max_epoch_jump = 10;
+        let mut engine = engine(config);
+        let peer = Peer::empty();
+        let id = peer.id();
+        engine.handle(Received(Digest(vec![Joined(peer)])), 0);
+        let epoch = engine.ring_epoch();
+
+        engine.handle(ReceivedFrom(id, RingEpoch(epoch + 7)), 0);
+        assert_eq!(engine.ring_epoch(), epoch + 7);
+        engine.handle(ReceivedFrom(id, RingEpoch(epoch + 3)), 0);
+        assert_eq!(engine.ring_epoch(), epoch + 7);
+
+        // Neither strangers nor unbound connections get to move it, and
+        // it's only ever moved so far at once.
+        engine.handle(ReceivedFrom(Uuid::new_v4(), RingEpoch(epoch + 9)), 0);
+        engine.handle(Received(RingEpoch(epoch + 9)), 0);
+        assert_eq!(engine.ring_epoch(), epoch + 7);
+        engine.handle(ReceivedFrom(id, RingEpoch(::std::u64::MAX)), 0);
+        assert_eq!(engine.ring_epoch(), epoch + 17);
+
+        let outputs = engine.handle(Received(Digest(vec![Joined(Peer::empty())])), 0);
+        assert!(events(outputs).contains(&RingChanged(epoch + 18)));
+    }
+
+    #[test]
+    fn relay_trace_context() {
+        let mut engine = engine(Config::new());
+        let trace = TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+                    .unwrap();
+        let mut broadcast = Broadcast::new(vec![1u8]).unwrap();
+        broadcast.set_trace(trace.clone());
+
+        for output in engine.handle(Relayed(broadcast), 0).move_iter() {
+            match output {
+                Deliver(b) | Relay(b) => {
+                    let relayed = b.trace().unwrap();
+                    assert_eq!(relayed.trace_id, trace.trace_id);
+                    assert!(relayed.parent_id != trace.parent_id);
+                },
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn drop_broadcasts_past_their_deadline() {
+        let mut engine = engine(Config::new());
+        let origin = Uuid::new_v4();
+        engine.handle(WallClock(1_000_000), 0);
+
+        // The origin's clock is a minute ahead of ours.
+        let from = |deadline: u64| {
+            let mut broadcast = Broadcast::new(vec![1u8]).unwrap();
+            broadcast.set_origin(origin);
+            broadcast.set_sent_at(1_060_000);
+            broadcast.set_deadline(deadline);
+            Relayed(broadcast)
+        };
+
+        assert!(delivered(&engine.handle(from(1_061_000), 0)));
+        assert!(!delivered(&engine.handle(from(1_061_000), 5_000)));
+        assert_eq!(engine.stats().expired, 1);
+
+        // A newcomer claiming to be behind is taken to be in sync until
+        // other broadcasts tell otherwise.
+        let mut late = Broadcast::new(vec![2u8]).unwrap();
+        late.set_origin(Uuid::new_v4());
+        late.set_sent_at(900_000);
+        late.set_deadline(950_000);
+        assert!(!delivered(&engine.handle(Relayed(late), 5_000)));
+        assert_eq!(engine.stats().expired, 2);
+    }
+
+    #[test]
+    fn follow_members_that_move() {
+        let mut engine = engine(Config::new());
+        let peer = Peer::empty();
+        let id = peer.id();
+        engine.members.insert(peer);
+
+        let moved = Peer::new(id, "10.0.0.2", 3444);
+        let outputs = engine.handle(Received(Digest(vec![Moved(moved.clone(), 1)])), 0);
+        assert!(outputs.iter().any(|output| match *output {
+            Reconnect(to, ref addr) => to == id && addr == moved.addr(),
+            _ => false
+        }));
+        assert!(events(outputs).contains(&MemberMoved(id, moved.addr().clone())));
+        assert_eq!(engine.members.len(), 1);
+
+        // Stale moves lose.
+        engine.handle(Received(Digest(vec![Moved(Peer::new(id, "10.0.0.1", 3444), 1)])), 0);
+        assert_eq!(engine.members.find(&id).unwrap().peer.addr(), moved.addr());
+
+        // So do moves from anybody but the member.
+        let spoofed = Digest(vec![Moved(Peer::new(id, "10.6.6.6", 3444), 2)]);
+        let outputs = engine.handle(ReceivedFrom(Uuid::new_v4(), spoofed), 0);
+        assert!(!outputs.iter().any(|output| match *output {
+            Reconnect(..) => true,
+            _ => false
+        }));
+        assert_eq!(engine.members.find(&id).unwrap().peer.addr(), moved.addr());
+
+        let moved = Peer::new(id, "10.0.0.3", 3444);
+        engine.handle(ReceivedFrom(id, Digest(vec![Moved(moved.clone(), 2)])), 0);
+        assert_eq!(engine.members.find(&id).unwrap().peer.addr(), moved.addr());
+    }
+
+    #[test]
+    fn announce_our_own_moves() {
+        let mut config = Config::new();
+        config.digest_window = 0;
+
+        let mut engine = engine(config);
+        engine.members.insert(Peer::empty());
+        engine.handle(Listening(SockAddr::new("10.0.0.1", 3444)), 0);
+
+        let outputs = engine.handle(Listening(SockAddr::new("10.0.0.2", 3444)), 0);
+        assert!(outputs.iter().any(|output| match *output {
+            Gossip(Digest(ref updates)) => updates.iter().any(|update| match *update {
+                Moved(ref peer, 1) => peer.addr().ip.as_slice() == "10.0.0.2",
+                _ => false
+            }),
+            _ => false
+        }));
+    }
+
+    #[test]
+    fn cache_heartbeats() {
+        let mut config = Config::new();
+        config.probe_interval = None;
+        let mut engine = engine(config);
+        let peer = Peer::empty();
+        let id = peer.id();
+        engine.members.insert(peer);
+
+        let outputs = engine.handle(SetHeartbeat(vec![1u8]), 0);
+        assert!(outputs.iter().any(|output| match *output {
+            Gossip(Heartbeat(from, ref payload, _)) => from == engine.id() && *payload == vec![1u8],
+            _ => false
+        }));
+        assert!(engine.handle(Tick, 1_000).is_empty());
+
+        engine.handle(Received(Heartbeat(id, vec![2u8], Elevated)), 2_000);
+        let heartbeat = engine.last_heartbeat(&id).unwrap();
+        assert_eq!(heartbeat.payload, vec![2u8]);
+        assert_eq!(heartbeat.pressure, Elevated);
+        assert_eq!(heartbeat.received_at, 2_000);
+    }
+
+    #[test]
+    fn warn_about_version_skew() {
+        let mut config = Config::new();
+        config.version = "1.0.0".to_string();
+        config.max_versions = Some(1);
+
+        let mut engine = engine(config);
+        let peer = Peer::empty();
+        let id = peer.id();
+        engine.members.insert(peer);
+
+        let mut metadata = HashMap::new();
+        metadata.insert("version".to_string(), "1.1.0".to_string());
+        let version = VersionVector::new().bumped(id);
+        let outputs = engine.handle(Received(Metadata(id, metadata.clone(), version.clone())), 0);
+        assert!(events(outputs).contains(&VersionSkew(vec!["1.0.0".to_string(),
+                                                            "1.1.0".to_string()])));
+        assert_eq!(engine.versions().versions.len(), 2);
+
+        metadata.insert("load".to_string(), "0.5".to_string());
+        let outputs = engine.handle(Received(Metadata(id, metadata, version.bumped(id))), 0);
+        assert!(!events(outputs).iter().any(|event| match *event {
+            VersionSkew(_) => true,
+            _ => false
+        }));
+    }
+
+    fn disk_full() -> GossipError {
+        let mut err = io::standard_error(io::OtherIoError);
+        err.desc = "no space left on device";
+        io_err(err)
+    }
+
+    #[test]
+    fn degrade_to_memory_only() {
+        let mut config = Config::new();
+        config.snapshot_path = Some(Path::new("/var/lib/gossip/snapshot"));
+
+        let mut engine = engine(config);
+        let outputs = engine.handle(Persisted(Err(disk_full())), 0);
+        assert!(events(outputs).iter().any(|event| match *event {
+            StorageFailed(_) => true,
+            _ => false
+        }));
+        assert_eq!(engine.health(), Red);
+
+        // We keep serving joiners, just without touching the disk.
+        let outputs = engine.handle(Received(JoinRequest(Peer::empty())), 0);
+        assert!(!outputs.iter().any(|output| match *output {
+            Persist(_) => true,
+            _ => false
+        }));
+        assert!(outputs.iter().any(|output| match *output {
+            SendTo(_, JoinSync(..)) => true,
+            _ => false
+        }));
+    }
+
+    #[test]
+    fn fail_stop() {
+        let mut config = Config::new();
+        config.storage_policy = FailStop;
+
+        let mut engine = engine(config);
+        let outputs = engine.handle(Persisted(Err(disk_full())), 0);
+        assert!(outputs.iter().any(|output| match *output {
+            Shutdown => true,
+            _ => false
+        }));
+    }
+
+    #[test]
+    fn staggered_cluster_shutdown() {
+        let mut config = Config::new();
+        config.admin_key = Some(AdminKey(7, 11));
+        config.shutdown_stagger = 1_000;
+
+        let mut initiator = engine(config.clone());
+        let mut member = engine(config);
+        member.members.insert(Peer::new(initiator.id(), "10.0.0.1", 3444));
+
+        let command = initiator.handle(InitiateShutdown(None), 0).move_iter().filter_map(|o| {
+            match o {
+                Gossip(msg @ ShutdownCluster(..)) => Some(msg),
+                _ => None
+            }
+        }).next().unwrap();
+
+        // Forged commands are ignored.
+        let forged = match command.clone() {
+            ShutdownCluster(id, role, issued_at, mut proof) => {
+                *proof.get_mut(0) ^= 1;
+                ShutdownCluster(id, role, issued_at, proof)
+            },
+            _ => unreachable!()
+        };
+        member.handle(Received(forged), 0);
+        assert_eq!(member.status(), Active);
+
+        let outputs = member.handle(Received(command), 0);
+        assert_eq!(member.status(), Draining);
+        let report = outputs.move_iter().filter_map(|o| match o {
+            SendTo(_, msg @ ShutdownReport(..)) => Some(msg),
+            _ => None
+        }).next().unwrap();
+        assert_eq!(report, ShutdownReport(member.id(), Stopping));
+
+        // The initiator sorts before or after us, so we go first or second.
+        let due = teardown::rank(&member.id(), vec![member.id(), initiator.id()]) as u64 * 1_000;
+        let outputs = member.handle(Tick, due);
+        assert!(outputs.iter().any(|output| match *output {
+            Shutdown => true,
+            _ => false
+        }));
+        assert!(outputs.iter().any(|output| match *output {
+            SendTo(_, ShutdownReport(_, Stopped)) => true,
+            _ => false
+        }));
+
+        let outputs = initiator.handle(Received(ShutdownReport(member.id(), Stopped)), due);
+        assert!(events(outputs).contains(&ShutdownProgress(member.id(), Stopped)));
+    }
+
+    #[test]
+    fn defer_work_over_budget() {
+        let mut config = Config::new();
+        config.cpu_budget = Some(1_000);
+        config.cpu_budget_interval = 100;
+
+        let mut engine = engine(config);
+        engine.members.insert(Peer::empty());
+        engine.handle(SetHeartbeat(vec![1u8]), 0);
+
+        engine.charge(2_000_000, 0);
+        engine.handle(Tick, 10);
+        assert_eq!(engine.stats().budget_exceeded, 1);
+
+        engine.handle(Tick, 100);
+        assert_eq!(engine.stats().budget_exceeded, 1);
+    }
+
+    #[test]
+    fn cache_peers_when_they_change() {
+        let mut config = Config::new();
+        config.peer_cache_path = Some(Path::new("/var/lib/gossip/peers"));
+
+        let mut engine = engine(config);
+        let peer = Peer::empty();
+        engine.members.insert(peer.clone());
+
+        let cached = |outputs: Vec<Output>| outputs.move_iter().filter_map(|o| match o {
+            CachePeers(peers) => Some(peers),
+            _ => None
+        }).next();
+
+        assert_eq!(cached(engine.handle(Tick, 0)), Some(vec![peer]));
+        assert_eq!(cached(engine.handle(Tick, 100)), None);
+    }
+
+    #[test]
+    fn journal_topology_changes() {
+        let mut engine = engine(Config::new());
+        let peer = Peer::empty();
+        let id = peer.id();
+
+        engine.handle(Received(Digest(vec![Joined(peer)])), 0);
+        engine.handle(Received(Digest(vec![Liveness(id, Dead)])), 0);
+
+        let journal = engine.topology_journal();
+        assert_eq!(journal.len(), 2);
+        assert_eq!(journal.get(0).reason, Added);
+        assert_eq!(journal.get(1).from, Some(Eager));
+        assert_eq!(journal.get(1).to, None);
+        assert_eq!(journal.get(1).reason, Failed);
+    }
+
+    #[test]
+    fn sample_delivery_latency() {
+        let mut config = Config::new();
+        config.latency_sampling = Some(1);
+
+        let mut engine = engine(config);
+        let origin = Uuid::new_v4();
+        engine.handle(WallClock(1_000_000), 0);
+
+        // The origin's clock is a minute ahead of ours, it shouldn't count.
+        let from = |sent_at: u64| {
+            let mut broadcast = Broadcast::new(vec![1u8]).unwrap();
+            broadcast.set_origin(origin);
+            broadcast.set_sent_at(sent_at);
+            Relayed(broadcast)
+        };
+
+        engine.handle(from(1_060_000), 0);
+        engine.handle(from(1_060_000), 30);
+
+        // The second took 30ms longer than the first, which moved the
+        // average by an eighth of that.
+        let latency = engine.delivery_latency();
+        assert_eq!(latency.count, 2);
+        assert_eq!(latency.sum, 26);
+        assert_eq!(latency.quantile(1.0), Some(50));
+    }
+
+    #[test]
+    fn demote_then_disconnect_misbehaving_peers() {
+        let mut config = Config::new();
+        config.score_recovery = 10;
+
+        let mut engine = engine(config);
+        let peer = Peer::empty();
+        let id = peer.id();
+        engine.handle(Received(Digest(vec![Joined(peer)])), 0);
+
+        for _ in range(0u, 5) {
+            assert!(events(engine.handle(Misbehaved(id, Undecodable), 0)).is_empty());
+        }
+        let outputs = engine.handle(Misbehaved(id, ProtocolViolation), 0);
+        assert!(events(outputs).contains(&StandingChanged(id, Demoted)));
+        assert_eq!(engine.state.link(id.to_string().as_slice()), Some(Lazy));
+
+        let outputs = engine.handle(Misbehaved(id, ProtocolViolation), 0);
+        assert!(outputs.iter().any(|o| match *o { Disconnect(d) => d == id, _ => false }));
+        assert_eq!(engine.state.link(id.to_string().as_slice()), None);
+        assert_eq!(engine.peer_scores().get(0).ref1().standing, Disconnected);
+
+        assert!(events(engine.handle(Tick, 5_000)).contains(&StandingChanged(id, Good)));
+        assert_eq!(engine.state.link(id.to_string().as_slice()), Some(Eager));
+
+        let reasons: Vec<Reason> = engine.topology_journal().move_iter()
+                                         .map(|change| change.reason).collect();
+        assert_eq!(reasons, vec![Added, Penalized, Penalized, Recovered]);
+    }
+
+
+    #[test]
+    fn retain_relayed_broadcasts() {
+        let mut config = Config::new();
+        config.history_retention = Some(1_000);
+
+        let mut engine = engine(config);
+        let broadcast = Broadcast::new(vec![1u8]).unwrap();
+        let id = broadcast.id();
+
+        engine.handle(Relayed(broadcast.clone()), 0);
+        engine.handle(Tick, 999);
+        assert!(engine.retained(&id).is_some());
+
+        // Still a duplicate once it's gone.
+        engine.handle(Tick, 1_000);
+        assert!(engine.retained(&id).is_none());
+        assert!(!delivered(&engine.handle(Relayed(broadcast), 1_000)));
+    }
+
+
+    #[test]
+    fn reshape_around_slow_parents() {
+        let mut config = Config::new();
+        config.parent_margin = 20;
+        config.parent_hysteresis = 1_000;
+
+        let mut engine = engine(config);
+        let (parent, candidate) = (Peer::empty(), Peer::empty());
+        let (p, c) = (parent.id(), candidate.id());
+        engine.handle(Received(Digest(vec![Joined(parent), Joined(candidate)])), 0);
+        engine.state.set_link(c.to_string().as_slice(), Some(Lazy));
+
+        let origin = Uuid::new_v4();
+        let race = |engine: &mut Engine, at: u64| {
+            let mut broadcast = Broadcast::new(vec![1u8]).unwrap();
+            broadcast.set_origin(origin);
+            engine.handle(RelayedBy(c, broadcast.clone()), at);
+            engine.handle(RelayedBy(p, broadcast), at + 50);
+        };
+
+        let mut broadcast = Broadcast::new(vec![1u8]).unwrap();
+        broadcast.set_origin(origin);
+        engine.handle(RelayedBy(p, broadcast), 0);
+
+        race(&mut engine, 100);
+        race(&mut engine, 600);
+        assert_eq!(engine.state.link(c.to_string().as_slice()), Some(Lazy));
+
+        race(&mut engine, 1_100);
+        assert_eq!(engine.state.link(c.to_string().as_slice()), Some(Eager));
+        assert_eq!(engine.state.link(p.to_string().as_slice()), Some(Lazy));
+    }
+
+    #[test]
+    fn promote_peers_that_keep_answering_grafts() {
+        let mut config = Config::new();
+        config.graft_promotions = Some(2);
+        let mut engine = engine(config);
+        let (parent, candidate) = (Peer::empty(), Peer::empty());
+        let (p, c) = (parent.id(), candidate.id());
+        engine.handle(Received(Digest(vec![Joined(parent), Joined(candidate)])), 0);
+
+        let origin = Uuid::new_v4();
+        let mut broadcast = Broadcast::new(vec![1u8]).unwrap();
+        broadcast.set_origin(origin);
+        engine.handle(RelayedBy(p, broadcast), 0);
+
+        // The parent never sends them, we have to graft them off the candidate.
+        for at in [1_000u64, 2_000].iter() {
+            let mut broadcast = Broadcast::new(vec![1u8]).unwrap();
+            broadcast.set_origin(origin);
+            engine.state.set_link(c.to_string().as_slice(), Some(Lazy));
+            engine.handle(Received(IHave(c, vec![broadcast.id()])), *at);
+            assert!(engine.handle(Tick, *at + 500).iter().any(|o| match *o {
+                SendTo(id, Graft(_, ref ids)) => id == c && *ids == vec![broadcast.id()],
+                _ => false
+            }));
+            assert_eq!(engine.state.link(p.to_string().as_slice()), Some(Eager));
+            engine.handle(RelayedBy(c, broadcast), *at + 600);
+        }
+
+        // Nor does it catch up, by the hysteresis it's clear it lags.
+        assert_eq!(engine.parents.parent(&origin), Some(p));
+        engine.handle(Tick, 12_600);
+        assert_eq!(engine.state.link(p.to_string().as_slice()), Some(Lazy));
+        assert_eq!(engine.parents.parent(&origin), Some(c));
+    }
+
+
+    #[test]
+    fn leaves_dont_relay() {
+        let mut config = Config::new();
+        config.relay = false;
+
+        let mut engine = engine(config);
+        engine.members.insert(Peer::empty());
+
+        let outputs = engine.handle(Relayed(Broadcast::new(vec![1u8]).unwrap()), 0);
+        assert!(delivered(&outputs));
+        assert!(!outputs.iter().any(|o| match *o { Relay(_) => true, _ => false }));
+
+        let outputs = engine.handle(Publish(Broadcast::new(vec![1u8]).unwrap()), 0);
+        assert!(outputs.iter().any(|o| match *o { Relay(_) => true, _ => false }));
+    }
+
+
+    #[test]
+    fn relay_batches_at_once() {
+        let mut engine = engine(Config::new());
+        engine.members.insert(Peer::empty());
+
+        let batch = vec![Broadcast::new(vec![1u8]).unwrap(), Broadcast::new(vec![2u8]).unwrap()];
+        let outputs = engine.handle(PublishBatch(batch), 0);
+
+        let delivered = outputs.iter().filter(|o| match **o { Deliver(_) => true, _ => false });
+        assert_eq!(delivered.count(), 2);
+        let id = engine.id();
+        assert!(outputs.iter().any(|o| match *o {
+            RelayBatch(ref batch) => batch.len() == 2 && batch.iter().all(|b| b.origin() == id),
+            _ => false
+        }));
+    }
+
+
+    #[test]
+    fn preview_without_joining() {
+        let mut config = Config::new();
+        config.cluster_name = "staging".to_string();
+
+        let mut engine = engine(config);
+        engine.members.insert(Peer::empty());
+
+        let asker = Uuid::new_v4();
+        let outputs = engine.handle(Received(PreviewRequest(asker)), 0);
+        let info = outputs.move_iter().filter_map(|o| match o {
+            SendTo(id, Preview(info)) if id == asker => Some(info),
+            _ => None
+        }).next().unwrap();
+
+        assert_eq!(info.name.as_slice(), "staging");
+        assert_eq!(info.size, 2);
+        assert_eq!(info.versions, vec![CRATE_VERSION.to_string()]);
+        assert!(engine.members.find(&asker).is_none());
+    }
+
+
+    #[test]
+    fn list_operations_in_flight() {
+        let mut config = Config::new();
+        config.join_concurrency = 1;
+        config.join_sync_timeout = 1_000;
+        config.eviction_confirmations = Some(2);
+        config.eviction_window = 500;
+
+        let mut engine = engine(config);
+        let (a, b) = (Peer::empty(), Peer::empty());
+        engine.handle(Received(JoinRequest(a.clone())), 100);
+        engine.handle(Received(JoinRequest(b.clone())), 100);
+        engine.handle(Evict(a.id()), 200);
+        let missing = Uuid::new_v4();
+        engine.swim.suspect(b.id(), 300);
+        engine.tree.announced(missing, b.id(), 300);
+
+        let (suspicion, graft) = (engine.config.suspicion_timeout, engine.config.graft_timeout);
+        let ops = engine.operations();
+        assert_eq!(ops.len(), 5);
+        assert!(ops.contains(&SyncingJoiner(a.id(), 1_100)));
+        assert!(ops.contains(&QueuedJoiner(b.id())));
+        assert!(ops.contains(&EvictionBallot(a.id(), 1, 700)));
+        assert!(ops.contains(&SuspectMember(b.id(), 300 + suspicion)));
+        assert!(ops.contains(&MissingBroadcast(missing, Some(b.id()), 300 + graft)));
+    }
+
+    #[test]
+    fn count_per_topic() {
+        let mut engine = engine(Config::new());
+        let broadcast = Broadcast::new(vec![1u8, 2, 3, 4]).unwrap();
+        let topic = broadcast.topic();
+
+        engine.handle(Publish(Broadcast::new(vec![1u8, 2]).unwrap()), 0);
+        engine.handle(Relayed(broadcast.clone()), 0);
+        engine.handle(Relayed(broadcast), 0);
+
+        let topics = engine.topic_stats();
+        assert_eq!(topics.len(), 1);
+        let &(ref name, ref stats) = topics.get(0);
+        assert_eq!(name, &topic);
+        assert_eq!((stats.delivered, stats.deduped, stats.dropped), (2, 1, 0));
+        assert_eq!(stats.average_size(), Some(3.0));
+    }
+
+    #[test]
+    fn bootstrap_critical_topics_before_going_active() {
+        let mut seed = engine(Config::new());
+        let mut latest = Broadcast::new(vec![2u8]).unwrap();
+        latest.set_origin(seed.id());
+        seed.state.record(Broadcast::new(vec![1u8]).unwrap(), 0);
+        seed.state.record(latest.clone(), 0);
+
+        let mut config = Config::new();
+        config.bootstrap_topics = vec![Broadcast::new(vec![1u8]).unwrap().topic()];
+        let mut joiner = engine(config);
+        joiner.joining = true;
+
+        let outputs = joiner.handle(Received(JoinSync(seed.id(), seed.snapshot().encode())), 0);
+        assert_eq!(joiner.status(), Bootstrapping);
+        let request = outputs.move_iter().filter_map(|output| match output {
+            SendTo(_, request @ BootstrapRequest(..)) => Some(request),
+            _ => None
+        }).next().unwrap();
+
+        let outputs = seed.handle(Received(request), 0);
+        let reply = outputs.move_iter().filter_map(|output| match output {
+            SendTo(_, reply @ BootstrapBroadcasts(..)) => Some(reply),
+            _ => None
+        }).next().unwrap();
+        match reply {
+            BootstrapBroadcasts(_, ref wires) => {
+                assert_eq!(wires, &vec![WireBroadcast::new(&latest)])
+            },
+            _ => unreachable!()
+        }
+
+        let outputs = joiner.handle(Received(reply), 10);
+        assert!(delivered(&outputs));
+        assert_eq!(joiner.status(), Active);
+
+        // It's the same broadcast, not a new one of the joiner's.
+        let outputs = joiner.handle(Relayed(latest.clone()), 20);
+        assert!(!delivered(&outputs));
+    }
+
+    #[test]
+    fn go_active_when_bootstrap_times_out() {
+        let mut config = Config::new();
+        config.bootstrap_topics = vec!["default/config".to_string()];
+        config.bootstrap_timeout = 1_000;
+
+        let mut joiner = engine(config);
+        joiner.joining = true;
+        let seed = engine(Config::new());
+        joiner.handle(Received(JoinSync(seed.id(), seed.snapshot().encode())), 0);
+        assert_eq!(joiner.status(), Bootstrapping);
+
+        joiner.handle(Tick, 999);
+        assert_eq!(joiner.status(), Bootstrapping);
+        joiner.handle(Tick, 1_000);
+        assert_eq!(joiner.status(), Active);
+    }
+
+    struct Union;
+
+    impl MetadataMerge for Union {
+        fn merge(&self, _: &Uuid, ours: &HashMap<String, String>,
+                 theirs: &HashMap<String, String>) -> HashMap<String, String> {
+            let mut merged = ours.clone();
+            for (key, value) in theirs.iter() {
+                merged.insert(key.clone(), value.clone());
+            }
+            merged
+        }
+    }
+
+    #[test]
+    fn merge_concurrent_metadata() {
+        let mut engine = engine(Config::new());
+        engine.set_metadata_merge(box Union as Box<MetadataMerge + Send>);
+        let peer = Peer::empty();
+        let id = peer.id();
+        engine.members.insert(peer);
+
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut zone = HashMap::new();
+        zone.insert("zone".to_string(), "east".to_string());
+        let mut rack = HashMap::new();
+        rack.insert("rack".to_string(), "7".to_string());
+
+        let (ours, theirs) = (VersionVector::new().bumped(a), VersionVector::new().bumped(b));
+        engine.handle(Received(Metadata(id, zone, ours.clone())), 0);
+        let outputs = engine.handle(Received(Metadata(id, rack, theirs)), 0);
+        assert!(events(outputs).contains(&MetadataChanged(id)));
+
+        // Stale versions don't undo the merge.
+        engine.handle(Received(Metadata(id, HashMap::new(), ours)), 0);
+        let metadata = &engine.members.find(&id).unwrap().metadata;
+        assert_eq!(metadata.len(), 2);
+        assert!(metadata.contains_key_equiv(&"zone") && metadata.contains_key_equiv(&"rack"));
+    }
+
+    #[test]
+    fn roll_out_feature_toggle() {
+        let mut config = Config::new();
+        config.admin_key = Some(AdminKey(7, 11));
+
+        let mut initiator = engine(config.clone());
+        let mut member = engine(config);
+        initiator.members.insert(Peer::new(member.id(), "10.0.0.2", 3444));
+        member.members.insert(Peer::new(initiator.id(), "10.0.0.1", 3444));
+
+        let outputs = initiator.handle(SetFeature("enable-compression".to_string(), true), 0);
+        assert!(initiator.features().is_enabled("enable-compression"));
+        let command = outputs.iter().filter_map(|o| match *o {
+            Gossip(ref msg @ FeatureToggle(..)) => Some(msg.clone()),
+            _ => None
+        }).next().unwrap();
+        assert!(events(outputs).contains(&FeatureRollout("enable-compression".to_string(), 0, 1)));
+
+        // Forged commands are ignored.
+        let forged = match command.clone() {
+            FeatureToggle(id, name, _, issued_at, proof) => {
+                FeatureToggle(id, name, false, issued_at, proof)
+            },
+            _ => unreachable!()
+        };
+        member.handle(Received(forged), 0);
+        assert!(!member.features().is_enabled("enable-compression"));
+
+        let outputs = member.handle(Received(command), 0);
+        assert!(member.features().is_enabled("enable-compression"));
+        let ack = outputs.iter().filter_map(|o| match *o {
+            SendTo(_, ref msg @ FeatureAck(..)) => Some(msg.clone()),
+            _ => None
+        }).next().unwrap();
+        assert!(events(outputs).contains(&FeatureToggled("enable-compression".to_string(), true)));
+
+        // Until it's ack comes in, the member gets the command again.
+        assert!(initiator.handle(Tick, 1_000).iter().any(|o| match *o {
+            SendTo(id, FeatureToggle(..)) => id == member.id(),
+            _ => false
+        }));
+
+        let outputs = initiator.handle(Received(ack), 1_000);
+        assert!(events(outputs).contains(&FeatureRollout("enable-compression".to_string(), 1, 1)));
+        assert!(initiator.rollouts.is_empty());
+    }
+
+    #[test]
+    fn sustained_pressure_turns_health_yellow() {
+        let mut config = Config::new();
+        config.pressure_interval = Some(1_000);
+        config.pressure_sustain = 5_000;
+
+        let mut engine = engine(config);
+        engine.members.insert(Peer::empty());
+        engine.set_health(Green);
+        let outputs = engine.handle(Tick, 0);
+        assert!(outputs.iter().any(|output| match *output {
+            Gossip(Heartbeat(_, _, Low)) => true,
+            _ => false
+        }));
+
+        engine.handle(SampledPressure(High), 1_000);
+        assert_eq!(engine.health(), Green);
+        assert_eq!(engine.local_health_multiplier(5_999), 1);
+
+        let outputs = engine.handle(Tick, 6_000);
+        assert!(events(outputs).contains(&HealthChanged(Yellow)));
+        assert_eq!(engine.local_health_multiplier(6_000), 3);
+
+        let outputs = engine.handle(SampledPressure(Low), 7_000);
+        assert!(events(outputs).contains(&HealthChanged(Green)));
+        assert_eq!(engine.local_health_multiplier(7_000), 1);
+    }
+
+    #[test]
+    fn remember_what_became_of_recent_broadcasts() {
+        let mut engine = engine(Config::new());
+        let origin = Uuid::new_v4();
+        engine.handle(WallClock(1_000_000), 0);
+
+        let from = |deadline: u64| {
+            let mut broadcast = Broadcast::new(vec![1u8, 2]).unwrap();
+            broadcast.set_origin(origin);
+            broadcast.set_sent_at(1_000_000);
+            broadcast.set_deadline(deadline);
+            Relayed(broadcast)
+        };
+        engine.handle(from(1_001_000), 0);
+        engine.handle(from(1_001_000), 5_000);
+        engine.handle(Publish(Broadcast::new(vec![3u8]).unwrap()), 6_000);
+
+        let recent = engine.recent_broadcasts(None, 10);
+        let deliveries: Vec<Delivery> = recent.iter().map(|r| r.delivery.clone()).collect();
+        assert_eq!(deliveries, vec![Delivered, Late, Delivered]);
+        assert_eq!(recent.get(1).origin, origin);
+        assert_eq!(recent.get(1).at, 1_005_000);
+        assert_eq!(recent.get(1).size, 2);
+        assert_eq!(engine.recent_broadcasts(None, 1).len(), 1);
+    }
+
+    #[test]
+    fn keep_cordoned_members_out_of_the_eager_set() {
+        let mut engine = engine(Config::new());
+        let peer = Peer::empty();
+        let id = peer.id();
+        engine.handle(Received(JoinRequest(peer)), 0);
+        let link = |engine: &Engine| engine.state.link(id.to_string().as_slice());
+        assert_eq!(link(&engine), Some(Eager));
+
+        let cordon = |cordoned: &str, count: u64| {
+            let mut metadata = HashMap::new();
+            metadata.insert(CORDON_KEY.to_string(), cordoned.to_string());
+            Received(Metadata(id, metadata, VersionVector::from_counters(vec![(id, count)])))
+        };
+        engine.handle(cordon("true", 1), 0);
+        assert_eq!(link(&engine), Some(Lazy));
+
+        // Nor does announcing what we're missing.
+        engine.handle(Received(IHave(id, vec![Uuid::new_v4()])), 0);
+        assert!(!engine.handle(Tick, 1_000).iter().any(|o| match *o {
+            SendTo(_, Graft(..)) => true,
+            _ => false
+        }));
+        assert_eq!(link(&engine), Some(Lazy));
+
+        // Outperforming the parent doesn't get it grafted either.
+        let parent = Uuid::new_v4();
+        engine.reparent(parent, id, 0);
+        assert_eq!(link(&engine), Some(Lazy));
+
+        engine.handle(cordon("false", 2), 0);
+        assert_eq!(link(&engine), Some(Eager));
+        let reasons: Vec<Reason> = engine.topology_journal().move_iter()
+                                         .map(|change| change.reason)
+                                         .collect();
+        assert_eq!(reasons, vec![Added, Cordoned, Uncordoned]);
+    }
+
+    #[test]
+    fn prune_duplicates_and_graft_what_went_missing() {
+        let mut config = Config::new();
+        config.gossip_interval = Some(1_000);
+        let mut engine = engine(config);
+        let (first, second) = (Peer::empty(), Peer::empty());
+        let (a, b) = (first.id(), second.id());
+        engine.handle(Received(Digest(vec![Joined(first), Joined(second)])), 0);
+        let link = |engine: &Engine| engine.state.link(b.to_string().as_slice());
+        assert_eq!(link(&engine), Some(Eager));
+
+        let broadcast = Broadcast::new(vec![1u8]).unwrap();
+        engine.handle(RelayedBy(a, broadcast.clone()), 0);
+        let outputs = engine.handle(RelayedBy(b, broadcast.clone()), 10);
+        let me = engine.id();
+        assert!(outputs.iter().any(|o| match *o {
+            SendTo(id, Prune(from)) => id == b && from == me,
+            _ => false
+        }));
+        assert_eq!(link(&engine), Some(Lazy));
+
+        // Lazy peers only hear about it.
+        let outputs = engine.handle(Tick, 20);
+        assert!(outputs.iter().any(|o| match *o {
+            SendTo(id, IHave(_, ref ids)) => id == b && ids.contains(&broadcast.id()),
+            _ => false
+        }));
+
+        // One that never shows up gets it's announcer grafted, on time rather
+        // than with the next round.
+        let missing = Uuid::new_v4();
+        engine.handle(Received(IHave(b, vec![missing])), 100);
+        assert!(!engine.handle(Tick, 599).iter().any(|o| match *o {
+            SendTo(_, Graft(..)) => true,
+            _ => false
+        }));
+        assert!(engine.handle(Tick, 600).iter().any(|o| match *o {
+            SendTo(id, Graft(_, ref ids)) => id == b && *ids == vec![missing],
+            _ => false
+        }));
+        assert_eq!(link(&engine), Some(Eager));
+
+        // And a peer grafting us gets what it missed.
+        let outputs = engine.handle(Received(Graft(a, vec![broadcast.id()])), 700);
+        assert!(outputs.iter().any(|o| match *o {
+            RelayTo(id, ref relayed) => id == a && relayed.id() == broadcast.id(),
+            _ => false
+        }));
+    }
+
+    #[test]
+    fn reconcile_with_a_member_at_random() {
+        let mut config = Config::new();
+        config.anti_entropy_interval = Some(1_000);
+        let mut engine = engine(config);
+        let (known, behind) = (Peer::empty(), Peer::empty());
+        let (known_id, id) = (known.id(), behind.id());
+        engine.handle(Received(Digest(vec![Joined(known.clone()), Joined(behind)])), 0);
+        let broadcast = Broadcast::new(vec![1u8]).unwrap();
+        engine.state.record(broadcast.clone(), 0);
+
+        assert!(engine.handle(Tick, 1_000).iter().any(|o| match *o {
+            SendTo(_, SyncDigest(_, ref members, ref ids, false)) => {
+                members.len() == 3 && *ids == vec![broadcast.id()]
+            },
+            _ => false
+        }));
+
+        // It knows of nothing, so it gets all of it and our digest.
+        let outputs = engine.handle(Received(SyncDigest(id, vec![], vec![Uuid::new_v4()], false)),
+                                    1_100);
+        assert!(outputs.iter().any(|o| match *o {
+            SendTo(to, Digest(ref updates)) => to == id && updates.contains(&Joined(known.clone())),
+            _ => false
+        }));
+        assert!(outputs.iter().any(|o| match *o {
+            RelayTo(to, ref relayed) => to == id && relayed.id() == broadcast.id(),
+            _ => false
+        }));
+        assert!(outputs.iter().any(|o| match *o {
+            SendTo(to, SyncDigest(_, _, _, true)) => to == id,
+            _ => false
+        }));
+
+        // Replies aren't answered with a digest, and there's nothing left to
+        // tell once it's caught up.
+        let members = vec![(known_id, 0, Alive), (id, 0, Alive)];
+        let outputs = engine.handle(Received(SyncDigest(id, members, vec![broadcast.id()], true)),
+                                    1_200);
+        assert!(!outputs.iter().any(|o| match *o {
+            SendTo(_, Digest(_)) | SendTo(_, SyncDigest(..)) | RelayTo(..) => true,
+            _ => false
+        }));
+    }
+
+    #[test]
+    fn sync_with_known_members_only() {
+        let mut config = Config::new();
+        config.merkle_threshold = 2;
+        let mut engine = engine(config);
+        let peer = Peer::empty();
+        let id = peer.id();
+        engine.handle(Received(Digest(vec![Joined(peer)])), 0);
+        engine.state.record(Broadcast::new(vec![1u8]).unwrap(), 0);
+
+        // Strangers don't hear back at all.
+        let stranger = Uuid::new_v4();
+        let digest = SyncDigest(stranger, vec![], vec![], false);
+        assert!(engine.handle(ReceivedFrom(stranger, digest), 100).is_empty());
+
+        // Listing more than the threshold, it's asked to go through the tree.
+        let ids = Vec::from_fn(3, |_| Uuid::new_v4());
+        let outputs = engine.handle(ReceivedFrom(id, SyncDigest(id, vec![], ids, false)), 200);
+        assert!(!outputs.iter().any(|o| match *o { RelayTo(..) => true, _ => false }));
+        assert!(outputs.iter().any(|o| match *o {
+            SendTo(to, TreeNodes(_, 0, _)) => to == id,
+            _ => false
+        }));
+    }
+
+    #[test]
+    fn reconcile_large_histories_through_the_tree() {
+        let mut config = Config::new();
+        config.merkle_threshold = 10;
+        let (mut a, mut b) = (engine(config.clone()), engine(config));
+        let shared = Vec::from_fn(50, |_| Broadcast::new(vec![1u8]).unwrap());
+        let only_a = Vec::from_fn(3, |_| Broadcast::new(vec![2u8]).unwrap());
+        let only_b = Vec::from_fn(2, |_| Broadcast::new(vec![3u8]).unwrap());
+        for broadcast in shared.iter().chain(only_a.iter()) {
+            a.state.record(broadcast.clone(), 0);
+        }
+        for broadcast in shared.iter().chain(only_b.iter()) {
+            b.state.record(broadcast.clone(), 0);
+        }
+
+        // Pass whatever one sends on to the other until they're done.
+        let (a_id, b_id) = (a.id(), b.id());
+        let digest = a.sync_digest(false);
+        let mut pending = vec![(b_id, digest)];
+        let (mut relayed, mut walked) = (Vec::new(), 0u);
+        while !pending.is_empty() {
+            let (to, msg) = pending.remove(0).unwrap();
+            match msg { TreeNodes(..) => walked += 1, _ => {} }
+            let (engine, other) = if to == a_id { (&mut a, b_id) } else { (&mut b, a_id) };
+            for output in engine.handle(Received(msg), 1_000).move_iter() {
+                match output {
+                    SendTo(_, msg) => pending.push((other, msg)),
+                    RelayTo(_, broadcast) => relayed.push((other, broadcast)),
+                    _ => {}
+                }
+            }
+        }
+        for (to, broadcast) in relayed.move_iter() {
+            let engine = if to == a_id { &mut a } else { &mut b };
+            engine.state.record(broadcast, 1_000);
+        }
+
+        // A message per level of the tree, however many there are.
+        assert_eq!(walked, merkle::DEPTH + 1);
+        assert_eq!(a.state.retained_len(), 55);
+        assert_eq!(b.state.retained_len(), 55);
+        assert_eq!(a.state.tree().root(), b.state.tree().root());
+    }
+
+    #[test]
+    fn suspect_members_that_never_ack_then_confirm_them_dead() {
+        let mut config = Config::new();
+        config.probe_interval = Some(1_000);
+        let mut engine = engine(config);
+        let (a, b) = (Peer::empty(), Peer::empty());
+        let (a_id, b_id) = (a.id(), b.id());
+        engine.handle(Received(Digest(vec![Joined(a), Joined(b)])), 0);
+
+        let pinged = |outputs: &Vec<Output>| outputs.iter().filter_map(|o| match *o {
+            SendTo(to, Ping(_, seq)) => Some((to, seq)),
+            _ => None
+        }).next().unwrap();
+        let (target, seq) = pinged(&engine.handle(Tick, 1_000));
+        let other = if target == a_id { b_id } else { a_id };
+
+        // No ack in time, so the other one is asked to probe it for us.
+        assert!(engine.handle(Tick, 1_300).iter().any(|o| match *o {
+            SendTo(to, PingReq(_, probed, s)) => to == other && probed == target && s == seq,
+            _ => false
+        }));
+
+        let outputs = engine.handle(Tick, 2_000);
+        let (next, seq) = pinged(&outputs);
+        assert_eq!(next, other);
+        assert!(events(outputs).contains(&MemberStateChanged(target, Suspect)));
+        engine.handle(Received(Ack(other, seq)), 2_100);
+        assert_eq!(engine.health(), Yellow);
+
+        let outputs = engine.handle(Tick, 7_000);
+        let (next, seq) = pinged(&outputs);
+        assert_eq!(next, other);
+        assert!(events(outputs).contains(&MemberStateChanged(target, Dead)));
+
+        // Everybody left acks, all is well.
+        let outputs = engine.handle(Received(Ack(other, seq)), 7_100);
+        assert!(events(outputs).contains(&HealthChanged(Green)));
+    }
+
+    #[test]
+    fn answer_and_relay_probes() {
+        let mut config = Config::new();
+        config.probe_interval = Some(1_000);
+        let mut engine = engine(config);
+        let (a, b) = (Peer::empty(), Peer::empty());
+        let (a_id, b_id, me) = (a.id(), b.id(), engine.id());
+        engine.handle(Received(Digest(vec![Joined(a), Joined(b)])), 0);
+
+        let outputs = engine.handle(Received(Ping(a_id, 5)), 0);
+        assert!(outputs.iter().any(|o| match *o {
+            SendTo(to, Ack(from, 5)) => to == a_id && from == me,
+            _ => false
+        }));
+
+        let outputs = engine.handle(Received(PingReq(a_id, b_id, 9)), 0);
+        let seq = outputs.iter().filter_map(|o| match *o {
+            SendTo(to, Ping(from, seq)) if to == b_id && from == me => Some(seq),
+            _ => None
+        }).next().unwrap();
+        let outputs = engine.handle(Received(Ack(b_id, seq)), 10);
+        assert!(outputs.iter().any(|o| match *o {
+            SendTo(to, Ack(from, 9)) => to == a_id && from == b_id,
+            _ => false
+        }));
+    }
+
+    #[test]
+    fn suspect_steady_members_sooner_by_phi() {
+        let mut config = Config::new();
+        config.phi_threshold = Some(8.0);
+        config.probe_interval = Some(1_000);
+        let mut engine = engine(config);
+        let a = Peer::empty();
+        let a_id = a.id();
+        engine.handle(Received(Digest(vec![Joined(a)])), 0);
+
+        let pinged = |outputs: &Vec<Output>| outputs.iter().filter_map(|o| match *o {
+            SendTo(to, Ping(_, seq)) if to == a_id => Some(seq),
+            _ => None
+        }).next().unwrap();
+        let seq = pinged(&engine.handle(Tick, 1_000));
+        engine.handle(Received(Ack(a_id, seq)), 1_010);
+
+        // It always acked within 10ms, so it's suspect well before the interval is up.
+        // Not before we asked the others to probe it, though.
+        pinged(&engine.handle(Tick, 2_000));
+        let suspected = MemberStateChanged(a_id, Suspect);
+        assert!(!events(engine.handle(Tick, 2_200)).contains(&suspected));
+        assert!(!events(engine.handle(Tick, 2_300)).contains(&suspected));
+        assert!(events(engine.handle(Tick, 2_400)).contains(&suspected));
+    }
+
+    #[test]
+    fn refute_suspicions_with_a_higher_incarnation() {
+        let mut config = Config::new();
+        config.digest_window = 0;
+        config.probe_interval = None;
+        let mut engine = engine(config);
+        let me = engine.id();
+        engine.members.insert(Peer::empty());
+        engine.handle(Listening(SockAddr::new("10.0.0.1", 3444)), 0);
+
+        let refuted = |outputs: Vec<Output>, incarnation: u64| outputs.iter().any(|output| {
+            let refutation = Refuted(Peer::new(me, "10.0.0.1", 3444), incarnation);
+            match *output {
+                Gossip(Digest(ref updates)) => updates.contains(&refutation),
+                _ => false
+            }
+        });
+        assert!(refuted(engine.handle(Received(Digest(vec![Liveness(me, Suspect)])), 0), 1));
+
+        // Hearing the same suspicion again is old news, a newer one isn't.
+        let suspicion = Received(Digest(vec![LivenessAt(me, Suspect, 0)]));
+        assert!(!refuted(engine.handle(suspicion, 0), 2));
+        assert_eq!(engine.incarnation, 1);
+        let suspicion = Received(Digest(vec![LivenessAt(me, Dead, 4)]));
+        assert!(refuted(engine.handle(suspicion, 0), 5));
+
+        // Members taken for dead come back by refuting it, evicted ones don't.
+        let (dead, evicted) = (Peer::empty(), Peer::empty());
+        engine.handle(Received(Digest(vec![Joined(dead.clone()), Joined(evicted.clone())])), 0);
+        engine.handle(Received(Digest(vec![Liveness(dead.id(), Dead)])), 0);
+        engine.handle(Evict(evicted.id()), 0);
+
+        let refutations = vec![Refuted(dead.clone(), 1), Refuted(evicted.clone(), 1)];
+        let outputs = engine.handle(Received(Digest(refutations)), 0);
+        assert!(events(outputs).contains(&MemberStateChanged(dead.id(), Alive)));
+        assert_eq!(engine.members().find(&dead.id()).unwrap().incarnation, 1);
+        assert_eq!(engine.members().find(&evicted.id()).unwrap().state, Dead);
+
+        // A death from before the refutation is stale, one since isn't.
+        engine.handle(Received(Digest(vec![Liveness(dead.id(), Dead)])), 0);
+        engine.handle(Received(Digest(vec![LivenessAt(dead.id(), Dead, 0)])), 0);
+        assert_eq!(engine.members().find(&dead.id()).unwrap().state, Alive);
+        engine.handle(Received(Digest(vec![LivenessAt(dead.id(), Suspect, 1)])), 0);
+        engine.handle(Received(Digest(vec![LivenessAt(dead.id(), Alive, 1)])), 0);
+        assert_eq!(engine.members().find(&dead.id()).unwrap().state, Suspect);
+
+        // And it comes back only ever by outdoing what we know of it.
+        engine.handle(Received(Digest(vec![LivenessAt(dead.id(), Dead, 1)])), 0);
+        engine.handle(Received(Digest(vec![Refuted(dead.clone(), 1)])), 0);
+        assert_eq!(engine.members().find(&dead.id()).unwrap().state, Dead);
+    }
+
+    #[test]
+    fn gossip_rounds_every_interval() {
+        let mut config = Config::new();
+        config.digest_window = 0;
+        config.probe_interval = None;
+        config.gossip_interval = Some(1_000);
+        let mut engine = engine(config);
+        engine.members.insert(Peer::empty());
+        let gossiped = |outputs: Vec<Output>| outputs.iter().any(|output| match *output {
+            Gossip(StatusChange(_, Draining)) => true,
+            _ => false
+        });
+
+        // News goes out right away, it's retransmits wait for the rounds.
+        assert!(gossiped(engine.handle(SetStatus(Draining), 0)));
+        assert!(!gossiped(engine.handle(Tick, 500)));
+        assert!(gossiped(engine.handle(Tick, 1_000)));
+        assert!(!gossiped(engine.handle(Tick, 1_500)));
+        assert!(gossiped(engine.handle(Tick, 2_000)));
+    }
+
+    #[test]
+    fn keep_a_history_of_our_health() {
+        let mut config = Config::new();
+        config.probe_interval = None;
+        let mut engine = engine(config);
+        let a = Peer::empty();
+        let a_id = a.id();
+
+        // Sampled on ticks only.
+        engine.handle(Received(Digest(vec![Joined(a)])), 0);
+        assert!(engine.health_history().is_empty());
+        engine.handle(Tick, 500);
+        engine.handle(Tick, 1_000);
+        engine.handle(Received(Digest(vec![Liveness(a_id, Suspect)])), 2_000);
+        engine.handle(Tick, 2_500);
+        engine.handle(Received(Digest(vec![LivenessAt(a_id, Alive, 1)])), 3_000);
+        engine.handle(Tick, 3_500);
+
+        let history = engine.health_history();
+        let at: Vec<u64> = history.iter().map(|record| record.at).collect();
+        assert_eq!(at, vec![500, 2_500, 3_500]);
+        assert_eq!((history.get(1).alive, history.get(1).suspect), (0, 1));
+        assert_eq!((history.get(2).alive, history.get(2).suspect), (1, 0));
+        assert_eq!(history.get(2).health, engine.health());
+    }
+
+    #[test]
+    fn turn_joiners_from_other_clusters_away() {
+        let mut config = Config::new();
+        config.cluster_name = "staging".to_string();
+        let mut engine = engine(config);
+        let (stranger, joiner) = (Peer::empty(), Peer::empty());
+        let (stranger_id, joiner_id) = (stranger.id(), joiner.id());
+
+        let outputs = engine.handle(Received(Join(stranger, "prod".to_string(), 7)), 0);
+        assert!(engine.members.find(&stranger_id).is_none());
+        assert!(outputs.iter().any(|o| match *o {
+            SendTo(to, JoinRejected(_, ref reason)) => {
+                to == stranger_id && reason.as_slice().contains("prod")
+            },
+            _ => false
+        }));
+
+        engine.handle(Received(Join(joiner, "staging".to_string(), 7)), 0);
+        assert!(engine.members.find(&joiner_id).is_some());
+
+        // Nor do nodes that can't tell, or speak a protocol from the future.
+        let (legacy, future) = (Peer::empty(), Peer::empty());
+        let (legacy_id, future_id) = (legacy.id(), future.id());
+        engine.handle(Received(JoinRequest(legacy)), 0);
+        let version = PROTOCOL_VERSION as u32 + 1;
+        engine.handle(Received(Join(future, "staging".to_string(), version)), 0);
+        assert!(engine.members.find(&legacy_id).is_none());
+        assert!(engine.members.find(&future_id).is_none());
+
+        // Nor merge their gossip.
+        let gossip = Digest(vec![Joined(Peer::empty())]);
+        engine.handle(ReceivedFrom(stranger_id, gossip), 0);
+        assert_eq!(engine.members.len(), 1);
+
+        // And on the other end.
+        assert!(engine.handshake().is_none());
+        engine.handle(Listening(SockAddr::new("10.0.0.1", 3444)), 0);
+        match engine.handshake() {
+            Some(Join(ref peer, ref cluster, _)) => {
+                assert_eq!((peer.id(), cluster.as_slice()), (engine.id(), "staging"))
+            },
+            msg => fail!("expected a join, got {}", msg)
+        }
+        let seed = Uuid::new_v4();
+        let outputs = engine.handle(Received(JoinRejected(seed, "nope".to_string())), 0);
+        assert!(events(outputs).contains(&JoinRefused(seed, "nope".to_string())));
+    }
+
+    #[test]
+    fn log_what_we_deliver() {
+        let mut config = Config::new();
+        config.wal_path = Some(Path::new("/var/lib/gossip/wal"));
+        config.commit_max_delay = 100;
+
+        let logged = |outputs: &Vec<Output>| outputs.iter().filter_map(|output| match *output {
+            AppendLog(ref broadcasts) => Some(broadcasts.len()),
+            _ => None
+        }).next();
+
+        let mut engine = engine(config.clone());
+        let first = Broadcast::new(vec![1u8]).unwrap();
+        let second = Broadcast::new(vec![2u8]).unwrap();
+        assert_eq!(logged(&engine.handle(Relayed(first.clone()), 0)), None);
+        assert_eq!(logged(&engine.handle(Relayed(second), 50)), None);
+
+        // Both of them go in the same write.
+        assert_eq!(logged(&engine.handle(Tick, 100)), Some(2));
+        engine.handle(Logged(5), 100);
+        let stats = engine.log_stats();
+        assert_eq!((stats.commits, stats.items), (1, 2));
+        assert_eq!(stats.latency.sum, 105);
+
+        // Once replayed after a restart, we know of it already.
+        let mut restarted = Engine::new(Uuid::new_v4(), config);
+        restarted.handle(Replayed(vec![first.clone()]), 0);
+        assert_eq!(restarted.state.retained_len(), 1);
+        assert!(!delivered(&restarted.handle(Relayed(first), 0)));
+    }
+
+    #[test]
+    fn batch_the_snapshots_we_persist() {
+        let mut config = Config::new();
+        config.snapshot_path = Some(Path::new("/var/lib/gossip/snapshot"));
+        config.join_concurrency = 8;
+        config.commit_max_delay = 100;
+
+        let persisted = |outputs: Vec<Output>| outputs.iter().filter(|output| match **output {
+            Persist(_) => true,
+            _ => false
+        }).count();
+
+        let mut engine = engine(config);
+        for i in range(0u, 3) {
+            let now = i as u64 * 10;
+            let joiner = Peer::new(Uuid::new_v4(), format!("10.0.0.{}", i + 1).as_slice(), 3444);
+            assert_eq!(persisted(engine.handle(Received(JoinRequest(joiner)), now)), 0);
+        }
+
+        // The three joiners' snapshots are written at once.
+        assert_eq!(persisted(engine.handle(Tick, 100)), 1);
+        engine.handle(Committed(5), 100);
+        let stats = engine.commit_stats();
+        assert_eq!((stats.commits, stats.items), (1, 3));
+        assert_eq!(stats.latency.sum, 105);
+    }
+
+    #[test]
+    fn leave_once_every_member_acked() {
+        let mut leaver = engine(Config::new());
+        let mut member = engine(Config::new());
+        leaver.members.insert(Peer::new(member.id(), "10.0.0.2", 3444));
+        member.members.insert(Peer::new(leaver.id(), "10.0.0.1", 3444));
+
+        let shutdown = |outputs: &Vec<Output>| outputs.iter().any(|output| match *output {
+            Shutdown => true,
+            _ => false
+        });
+
+        let departed = Departed(leaver.id(), 0);
+        let gossiped = |outputs: &Vec<Output>| outputs.iter().any(|output| match *output {
+            Gossip(Digest(ref updates)) => updates.contains(&departed),
+            _ => false
+        });
+        let outputs = leaver.handle(LeaveCluster, 0);
+        assert!(!shutdown(&outputs));
+        assert!(gossiped(&outputs));
+
+        // Nobody else gets to tell the member we left.
+        let other = Uuid::new_v4();
+        member.handle(Received(Digest(vec![departed.clone()])), 0);
+        member.handle(ReceivedFrom(other, Digest(vec![departed.clone()])), 0);
+        assert_eq!(member.members().find(&leaver.id()).unwrap().state, Alive);
+
+        // We do, and it drops us right away and acks it, every time.
+        let outputs = member.handle(ReceivedFrom(leaver.id(), Digest(vec![departed.clone()])), 0);
+        assert!(outputs.iter().any(|output| match *output {
+            SendTo(to, LeaveAck(id)) => (to, id) == (leaver.id(), member.id()),
+            _ => false
+        }));
+        assert!(events(outputs).contains(&MemberLeft(leaver.id())));
+        let outputs = member.handle(ReceivedFrom(leaver.id(), Digest(vec![departed.clone()])), 1);
+        assert!(outputs.iter().any(|output| match *output {
+            SendTo(_, LeaveAck(_)) => true,
+            _ => false
+        }));
+
+        // Until then, the leave goes out with every round.
+        assert!(gossiped(&leaver.handle(Tick, 1_000)));
+
+        assert!(shutdown(&leaver.handle(Received(LeaveAck(member.id())), 1_010)));
+
+        // Nobody acking doesn't keep us around forever.
+        let mut leaver = engine(Config::new());
+        leaver.members.insert(Peer::new(member.id(), "10.0.0.2", 3444));
+        leaver.handle(LeaveCluster, 0);
+        assert!(!shutdown(&leaver.handle(Tick, 4_999)));
+        assert!(shutdown(&leaver.handle(Tick, 5_000)));
+    }
+
+    #[test]
+    fn drop_messages_not_from_who_they_claim() {
+        let mut engine = engine(Config::new());
+        let (peer, spoofer) = (Peer::empty(), Uuid::new_v4());
+        let id = peer.id();
+        engine.members.insert(peer);
+
+        engine.handle(ReceivedFrom(spoofer, StatusChange(id, Draining)), 0);
+        assert_eq!(engine.members.voting(), 1);
+        assert!(engine.peer_scores().iter().any(|&(who, _)| who == spoofer));
+
+        engine.handle(ReceivedFrom(id, StatusChange(id, Draining)), 0);
+        assert_eq!(engine.members.voting(), 0);
+    }
+
+    #[test]
+    fn relay_to_eager_peers_only() {
+        let mut engine = engine(Config::new());
+        let (a, b) = (Peer::new(Uuid::new_v4(), "10.0.0.1", 3444),
+                      Peer::new(Uuid::new_v4(), "10.0.0.2", 3444));
+        engine.handle(Received(JoinRequest(a.clone())), 0);
+        engine.handle(Received(JoinRequest(b.clone())), 0);
+        assert_eq!(engine.eager_peers().len(), 2);
+
+        engine.handle(ReceivedFrom(b.id(), Prune(b.id())), 0);
+        assert_eq!(engine.eager_peers(), vec![a.id()]);
+    }
+
+    #[test]
+    fn only_acks_for_our_probes_prove_members_alive() {
+        let mut config = Config::new();
+        config.probe_interval = Some(1_000);
+        let mut engine = engine(config);
+        let a = Peer::empty();
+        let a_id = a.id();
+        engine.handle(Received(Digest(vec![Joined(a)])), 0);
+        engine.handle(Received(Digest(vec![Liveness(a_id, Suspect)])), 0);
+
+        let seq = engine.handle(Tick, 1_000).iter().filter_map(|o| match *o {
+            SendTo(to, Ping(_, seq)) if to == a_id => Some(seq),
+            _ => None
+        }).next().unwrap();
+
+        // Somebody else acking on it's behalf, or acking what we never sent.
+        engine.handle(ReceivedFrom(Uuid::new_v4(), Ack(a_id, seq)), 1_010);
+        engine.handle(ReceivedFrom(a_id, Ack(a_id, seq + 1)), 1_010);
+        assert_eq!(engine.members.find(&a_id).unwrap().state, Suspect);
+
+        engine.handle(ReceivedFrom(a_id, Ack(a_id, seq)), 1_010);
+        assert_eq!(engine.members.find(&a_id).unwrap().state, Alive);
+    }
+
+    #[test]
+    fn hand_direct_broadcasts_to_the_member_only() {
+        let mut engine = engine(Config::new());
+        let (member, stranger) = (Peer::empty(), Peer::empty());
+        let id = member.id();
+        engine.handle(Received(Digest(vec![Joined(member)])), 0);
+
+        let outputs = engine.handle(PublishTo(id, Broadcast::new(vec![1u8]).unwrap()), 0);
+        let me = engine.id();
+        assert!(outputs.iter().all(|o| match *o {
+            SendTo(to, Direct(from, ref wire)) => (to, from, wire.origin) == (id, me, me),
+            Relay(_) | Deliver(_) => false,
+            _ => true
+        }));
+
+        // Delivered, not relayed, and only by it's origin.
+        let mut wire = WireBroadcast::new(&Broadcast::new(vec![2u8]).unwrap());
+        wire.origin = id;
+        let outputs = engine.handle(ReceivedFrom(id, Direct(id, wire.clone())), 10);
+        assert!(delivered(&outputs));
+        assert!(!outputs.iter().any(|o| match *o { Relay(_) => true, _ => false }));
+        assert!(engine.handle(ReceivedFrom(id, Direct(id, wire.clone())), 20).is_empty());
+
+        let mut spoofed = WireBroadcast::new(&Broadcast::new(vec![3u8]).unwrap());
+        spoofed.origin = stranger.id();
+        assert!(!delivered(&engine.handle(ReceivedFrom(id, Direct(id, spoofed)), 30)));
+        wire.origin = stranger.id();
+        let outputs = engine.handle(ReceivedFrom(stranger.id(), Direct(stranger.id(), wire)), 40);
+        assert!(!delivered(&outputs));
+    }
+
+    #[test]
+    fn refuse_to_publish_where_we_may_not() {
+        let mut engine = engine(Config::new());
+        let member = Peer::empty();
+        let id = member.id();
+        engine.handle(Received(Digest(vec![Joined(member)])), 0);
+
+        let mut denied = Broadcast::new(vec![1u8]).unwrap();
+        denied.set_namespace("billing");
+        assert!(engine.can_publish(&denied).is_err());
+        let outputs = engine.handle(Publish(denied.clone()), 0);
+        assert!(!outputs.iter().any(|o| match *o { Deliver(_) | Relay(_) => true, _ => false }));
+        let outputs = engine.handle(PublishTo(id, denied.clone()), 0);
+        assert!(!outputs.iter().any(|o| match *o { Deliver(_) | SendTo(..) => true, _ => false }));
+
+        // One denied broadcast takes the whole batch down with it.
+        let batch = vec![Broadcast::new(vec![2u8]).unwrap(), denied];
+        let outputs = engine.handle(PublishBatch(batch), 0);
+        assert!(!outputs.iter().any(|o| match *o {
+            Deliver(_) | RelayBatch(_) => true,
+            _ => false
+        }));
+    }
+
+    #[test]
+    fn keep_sealed_topics_sealed() {
+        let mut plaintext = Broadcast::new(vec![1u8]).unwrap();
+        let mut config = Config::new();
+        config.sealed_topics = vec![plaintext.topic()];
+        let mut engine = engine(config);
+
+        // Not until it's sealed, whoever publishes.
+        assert!(engine.can_publish(&plaintext).is_err());
+        assert!(!delivered(&engine.handle(Publish(plaintext.clone()), 0)));
+
+        // Nor do we pass on what anybody else let out.
+        plaintext.set_origin(Uuid::new_v4());
+        assert!(!delivered(&engine.handle(Relayed(plaintext.clone()), 0)));
+
+        let mut sealed = Broadcast::new(vec![1u8, 2]).unwrap();
+        sealed.set_sealed(true);
+        assert!(delivered(&engine.handle(Publish(sealed), 0)));
+    }
+
+    #[test]
+    fn announce_our_metadata_once_in() {
+        let seed = engine(Config::new());
+        let mut joiner = engine(Config::new());
+        joiner.joining = true;
+        let id = joiner.id();
+
+        let outputs = joiner.handle(Received(JoinSync(seed.id(), seed.snapshot().encode())), 0);
+        assert!(outputs.iter().any(|output| match *output {
+            Gossip(Metadata(from, ref metadata, _)) => {
+                from == id && metadata.find_equiv(&VERSION_KEY).map(|v| v.as_slice())
+                    == Some(CRATE_VERSION)
+            },
+            _ => false
+        }));
+    }
+
+    #[test]
+    fn tell_newcomers_we_dont_relay() {
+        let mut config = Config::new();
+        config.relay = false;
+        let mut leaf = engine(config);
+        let peer = Peer::empty();
+        let id = peer.id();
+
+        let outputs = leaf.handle(Received(Digest(vec![Joined(peer)])), 0);
+        let metadata = match outputs.move_iter().find(|output| match *output {
+            SendTo(to, Metadata(..)) => to == id,
+            _ => false
+        }) {
+            Some(SendTo(_, metadata)) => metadata,
+            _ => fail!("the newcomer didn't get our metadata")
+        };
+
+        // Which keeps it from taking us for a parent.
+        let mut newcomer = engine(Config::new());
+        newcomer.members.insert(Peer::new(leaf.id(), "10.0.0.1", 3444));
+        newcomer.handle(Received(metadata), 0);
+        assert!(!newcomer.members.find(&leaf.id()).unwrap().relays());
+    }
+
+    #[test]
+    fn write_metadata_as_the_same_writer_every_time() {
+        let id = Uuid::new_v4();
+        let mut standby = Config::new();
+        standby.standby = true;
+        let (mut a, mut b) = (Engine::new(id, Config::new()), Engine::new(id, Config::new()));
+        let mut c = Engine::new(id, standby);
+        for engine in [&mut a, &mut b, &mut c].mut_iter() {
+            engine.handle(SetMetadata("zone".to_string(), "eu".to_string()), 0);
+        }
+
+        // Replaying the same inputs comes down to the same versions, while
+        // the standby writes as someone else.
+        assert_eq!(a.metadata_version, b.metadata_version);
+        assert!(!a.metadata_version.descends(&c.metadata_version));
+        assert!(!c.metadata_version.descends(&a.metadata_version));
+    }
+
+    /// Hashes like the default provider but can't make a MAC, like a key
+    /// store that went away.
+    struct NoMacs;
+
+    impl Provider for NoMacs {
+        fn name(&self) -> &'static str {
+            "no-macs"
+        }
+
+        fn hash(&self, data: &[u8]) -> GossipResult<u64> {
+            Crypto::default().hash(data)
+        }
+
+        fn mac(&self, _: &[u8], _: &[u8]) -> GossipResult<Vec<u8>> {
+            Err(GossipError::new("the key store is gone", UnknownError))
+        }
+
+        fn cipher(&self, _: &[u8]) -> Option<Box<Cipher + Send>> {
+            None
+        }
+    }
+
+    #[test]
+    fn report_admin_commands_that_cant_be_signed() {
+        let mut config = Config::new();
+        config.admin_key = Some(AdminKey(7, 11));
+        config.crypto = Crypto::new(box NoMacs as Box<Provider + Send + Sync>);
+
+        let mut engine = engine(config);
+        let target = Peer::empty();
+        let id = target.id();
+        engine.members.insert(target);
+
+        fn failed(outputs: Vec<Output>, command: &str) -> bool {
+            events(outputs).iter().any(|event| match *event {
+                AdminFailed(ref c, _) => c.as_slice() == command,
+                _ => false
+            })
+        }
+
+        // Not even as a plain vote.
+        let outputs = engine.handle(Evict(id), 0);
+        assert!(!outputs.iter().any(|output| match *output {
+            Gossip(KillNode(..)) | Gossip(KillNodeAt(..)) => true,
+            _ => false
+        }));
+        assert!(failed(outputs, "evict"));
+        assert_eq!(engine.members.find(&id).unwrap().state, Alive);
+
+        let outputs = engine.handle(InitiateShutdown(None), 0);
+        assert!(!outputs.iter().any(|output| match *output {
+            Gossip(ShutdownCluster(..)) => true,
+            _ => false
+        }));
+        assert!(failed(outputs, "shutdown_cluster"));
+
+        let outputs = engine.handle(SetFeature("enable-compression".to_string(), true), 0);
+        assert!(failed(outputs, "set_feature"));
+        assert!(!engine.features().is_enabled("enable-compression"));
+    }
+}
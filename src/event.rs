@@ -0,0 +1,140 @@
+//! Events emitted by a node so applications can react to changes within
+//! the cluster (members draining, health changing, etc...).
+
+use uuid::Uuid;
+
+use member::{Status, MemberState, Active, Draining, Bootstrapping};
+use protocol::Health;
+use stream::SockAddr;
+use teardown::ShutdownPhase;
+use score::Standing;
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum Event {
+    /// A new member joined the cluster.
+    MemberJoined(Uuid),
+    /// The failure detector changed it's mind about a member.
+    MemberStateChanged(Uuid, MemberState),
+    /// A member moved to a new address. It's still the same member, so it
+    /// keeps it's state.
+    MemberMoved(Uuid, SockAddr),
+    /// A member was evicted from the cluster.
+    MemberEvicted(Uuid),
+    /// A member left the cluster on purpose (see `Node::leave`), rather
+    /// than failing.
+    MemberLeft(Uuid),
+    /// A member (possibly ourselves) has started draining. Work should
+    /// be migrated off of it.
+    MemberDraining(Uuid),
+    /// A member has left the draining (or bootstrapping) state and is
+    /// active.
+    MemberActive(Uuid),
+    /// A member (possibly ourselves) joined and is catching up on the
+    /// bootstrap-critical topics.
+    MemberBootstrapping(Uuid),
+    /// The local view of the cluster's health has changed.
+    HealthChanged(Health),
+    /// A relay (the id) dropped one of our broadcasts because we went over
+    /// our rate quota. Applications should back off.
+    Throttled(Uuid),
+    /// The endpoints of a service (by it's name) changed, see
+    /// `Node::lookup`.
+    ServiceChanged(String),
+    /// The coordinator hint moved to another node (or to nobody), see
+    /// `Node::is_coordinator`.
+    CoordinatorChanged(Option<Uuid>),
+    /// The metadata of a member (possibly ourselves) changed.
+    MetadataChanged(Uuid),
+    /// The hash ring was rebuilt (the new epoch), so some keys changed
+    /// owners. See `Node::owner`.
+    RingChanged(u64),
+    /// More versions (listed) run within the cluster than
+    /// `Config::max_versions` allows. Only sent once until the skew is gone.
+    VersionSkew(Vec<String>),
+    /// An internal task (the name) failed and was restarted, for the nth
+    /// time.
+    SubsystemRestarted(String, uint),
+    /// An internal task (the name) failed too often, the node shut down.
+    SubsystemFailed(String),
+    /// Persisting the snapshot failed (the error). Depending on the
+    /// `Config::storage_policy` the node either shuts down or keeps going
+    /// without touching the disk again.
+    StorageFailed(String),
+    /// A member got as far as the phase with the cluster-wide shutdown we
+    /// initiated, see `Node::shutdown_cluster`.
+    ShutdownProgress(Uuid, ShutdownPhase),
+    /// A member misbehaved enough (or earned enough of it's score back) to
+    /// change standing, see `Node::peer_scores`.
+    StandingChanged(Uuid, Standing),
+    /// A feature toggle was flipped (on or off) for the cluster, see
+    /// `Node::set_feature`.
+    FeatureToggled(String, bool),
+    /// How many members acked the toggle we flipped, out of how many.
+    FeatureRollout(String, uint, uint),
+    /// A local subscriber within the namespace (and the group, if it's a
+    /// member of one) stopped reading broadcasts, this many are queued for
+    /// it. See `Config::stall_policy` for what's done about it.
+    SubscriberStalled(String, Option<String>, uint),
+    /// A seed (the id) wouldn't let us join, and why: it's cluster has
+    /// another name (see `Config::cluster_name`) or we're too far behind.
+    JoinRefused(Uuid, String),
+    /// The admin command (i.e., `evict`) couldn't be signed with the admin
+    /// key (the error), so it wasn't sent at all.
+    AdminFailed(String, String)
+}
+
+impl Event {
+    /// The event that corresponds to a member switching to `status`.
+    pub fn for_status(id: Uuid, status: Status) -> Event {
+        match status {
+            Active => MemberActive(id),
+            Draining => MemberDraining(id),
+            Bootstrapping => MemberBootstrapping(id)
+        }
+    }
+}
+
+/// Fans out every event to all the subscribers. Subscribers that have
+/// hung up are dropped on the next emit.
+pub struct EventBus {
+    subscribers: Vec<Sender<Event>>
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus {
+            subscribers: Vec::new()
+        }
+    }
+
+    pub fn subscribe(&mut self) -> Receiver<Event> {
+        let (tx, rx) = channel();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    pub fn emit(&mut self, event: Event) {
+        self.subscribers.retain(|tx| tx.send_opt(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn emit_to_subscribers() {
+        let mut bus = EventBus::new();
+        let rx = bus.subscribe();
+        let id = Uuid::new_v4();
+
+        {
+            let _gone = bus.subscribe();
+        }
+
+        bus.emit(MemberDraining(id));
+        assert_eq!(rx.recv(), MemberDraining(id));
+        assert_eq!(bus.subscribers.len(), 1);
+    }
+}
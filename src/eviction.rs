@@ -0,0 +1,184 @@
+//! Eviction voting. A single node claiming another one should be evicted
+//! isn't trustworthy on it's own (it might be buggy, or malicious), so
+//! evictions can require confirmations from several distinct members
+//! within a time window, or a proof signed with the admin key.
+
+use std::collections::{HashMap, HashSet};
+use std::io::MemWriter;
+use uuid::Uuid;
+
+use crypto::Crypto;
+use result::GossipResult;
+use teardown::VALIDITY;
+
+/// A shared secret held by the operators. Evictions carrying a valid proof
+/// made with this key apply right away.
+#[deriving(Show, Clone, PartialEq)]
+pub struct AdminKey(pub u64, pub u64);
+
+impl AdminKey {
+    /// A MAC of the eviction target and when (the issuer's wall clock) the
+    /// eviction was issued, made by the configured provider.
+    pub fn prove(&self, crypto: &Crypto, target: &Uuid,
+                 issued_at: u64) -> GossipResult<Vec<u8>> {
+        let mut wr = MemWriter::new();
+        // Writing to memory can't fail.
+        wr.write(target.as_bytes()).unwrap();
+        wr.write_be_u64(issued_at).unwrap();
+        self.sign(crypto, wr.get_ref())
+    }
+
+    /// A MAC of any admin command, the whole tag of it.
+    pub fn sign(&self, crypto: &Crypto, data: &[u8]) -> GossipResult<Vec<u8>> {
+        let AdminKey(k0, k1) = *self;
+        let mut key = MemWriter::new();
+        // Writing to memory can't fail.
+        key.write_be_u64(k0).unwrap();
+        key.write_be_u64(k1).unwrap();
+        crypto.mac(key.get_ref(), data)
+    }
+
+    pub fn verify(&self, crypto: &Crypto, target: &Uuid, issued_at: u64, proof: &[u8]) -> bool {
+        match self.prove(crypto, target, issued_at) {
+            Ok(tag) => tag.as_slice() == proof,
+            Err(_) => false
+        }
+    }
+
+    /// Whether the admin command was signed with the key. Nothing is
+    /// without a provider to tell.
+    pub fn verify_signed(&self, crypto: &Crypto, data: &[u8], proof: &[u8]) -> bool {
+        match self.sign(crypto, data) {
+            Ok(tag) => tag.as_slice() == proof,
+            Err(_) => false
+        }
+    }
+}
+
+struct Ballot {
+    opened: u64,
+    voters: HashSet<Uuid>
+}
+
+pub struct Evictions {
+    confirmations: uint,
+    window: u64,
+    ballots: HashMap<Uuid, Ballot>,
+    /// The admin proofs spent so far, along with when they were issued.
+    redeemed: HashMap<Vec<u8>, u64>
+}
+
+impl Evictions {
+    /// Evictions need votes from `confirmations` distinct members, all
+    /// within `window` milliseconds of the first one.
+    pub fn new(confirmations: uint, window: u64) -> Evictions {
+        Evictions {
+            confirmations: confirmations,
+            window: window,
+            ballots: HashMap::new(),
+            redeemed: HashMap::new()
+        }
+    }
+
+    /// The open ballots: the target, how many votes it has and when the
+    /// ballot closes.
+    pub fn ballots(&self) -> Vec<(Uuid, uint, u64)> {
+        self.ballots.iter().map(|(target, ballot)| {
+            (*target, ballot.voters.len(), ballot.opened + self.window)
+        }).collect()
+    }
+
+    /// Spend an admin proof issued at the time, `false` if it was spent
+    /// already. Proofs are forgotten once they're past `VALIDITY`, they're
+    /// turned away as stale by then anyway.
+    pub fn redeem(&mut self, proof: Vec<u8>, issued_at: u64, wall: u64) -> bool {
+        let stale: Vec<Vec<u8>> = self.redeemed.iter()
+                                               .filter(|&(_, &issued)| issued + VALIDITY < wall)
+                                               .map(|(proof, _)| proof.clone())
+                                               .collect();
+        for proof in stale.iter() {
+            self.redeemed.remove(proof);
+        }
+        self.redeemed.insert(proof, issued_at)
+    }
+
+    /// Record a vote to evict `target`. Returns `true` once enough distinct
+    /// members agree, at which point the ballot is closed.
+    pub fn vote(&mut self, target: Uuid, voter: Uuid, now: u64) -> bool {
+        let expired = match self.ballots.find(&target) {
+            Some(ballot) => now >= ballot.opened + self.window,
+            None => true
+        };
+
+        if expired {
+            self.ballots.insert(target, Ballot { opened: now, voters: HashSet::new() });
+        }
+
+        let votes = {
+            let ballot = self.ballots.find_mut(&target).unwrap();
+            ballot.voters.insert(voter);
+            ballot.voters.len()
+        };
+
+        if votes >= self.confirmations {
+            self.ballots.remove(&target);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::Crypto;
+    use uuid::Uuid;
+    use teardown::VALIDITY;
+
+    #[test]
+    fn distinct_confirmations() {
+        let mut evictions = Evictions::new(2, 1000);
+        let (target, a, b) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        assert!(!evictions.vote(target, a, 0));
+        assert!(!evictions.vote(target, a, 10));
+        assert!(evictions.vote(target, b, 20));
+    }
+
+    #[test]
+    fn votes_expire() {
+        let mut evictions = Evictions::new(2, 1000);
+        let (target, a, b) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        assert!(!evictions.vote(target, a, 0));
+        assert!(!evictions.vote(target, b, 1000));
+    }
+
+    #[test]
+    fn admin_proofs() {
+        let crypto = Crypto::default();
+        let key = AdminKey(1, 2);
+        let target = Uuid::new_v4();
+        let proof = key.prove(&crypto, &target, 1_000).unwrap();
+
+        assert!(key.verify(&crypto, &target, 1_000, proof.as_slice()));
+        assert!(!AdminKey(1, 3).verify(&crypto, &target, 1_000, proof.as_slice()));
+        assert!(!key.verify(&crypto, &Uuid::new_v4(), 1_000, proof.as_slice()));
+        assert!(!key.verify(&crypto, &target, 1_001, proof.as_slice()));
+        // Nor does a truncated tag.
+        assert!(!key.verify(&crypto, &target, 1_000, proof.slice_to(4)));
+    }
+
+    #[test]
+    fn spend_proofs_once() {
+        let mut evictions = Evictions::new(1, 1000);
+
+        assert!(evictions.redeem(vec![7], 1_000, 1_000));
+        assert!(!evictions.redeem(vec![7], 1_000, 2_000));
+        assert!(evictions.redeem(vec![8], 1_000, 2_000));
+
+        // Long stale by now, so there's no need to remember it.
+        assert!(evictions.redeem(vec![7], 1_000, 1_000 + VALIDITY + 1));
+    }
+}
@@ -0,0 +1,183 @@
+//! Runtime feature toggles. An admin flips a named protocol toggle (i.e.,
+//! "enable-compression") for the whole cluster with `Node::set_feature`:
+//! the command is gossiped signed with the admin key, every member applies
+//! it and acks back to the initiator, which follows the rollout through
+//! `FeatureRollout` events. Members that didn't ack get the command again
+//! every `RETRY` for as long as it's valid, members that die are no longer
+//! waited on. The latest command (by when it was issued) for
+//! a toggle wins, so flipping one back and forth settles the same way
+//! everywhere.
+//!
+//! Toggles aren't part of the full-state sync, members that join later
+//! start with all of them off.
+
+use std::collections::{HashMap, HashSet};
+use std::io::MemWriter;
+use uuid::Uuid;
+
+/// How often (in milliseconds) to send a command again to the members that
+/// didn't ack it yet.
+pub static RETRY: u64 = 1_000;
+
+/// What the admin key signs: the initiator, the toggle, it's new state and
+/// when (the initiator's wall clock) the command was issued.
+pub fn signed(initiator: &Uuid, name: &str, enabled: bool, issued_at: u64) -> Vec<u8> {
+    let mut wr = MemWriter::new();
+
+    // Writing to memory can't fail.
+    wr.write(initiator.as_bytes()).unwrap();
+    wr.write_be_u64(issued_at).unwrap();
+    wr.write_u8(enabled as u8).unwrap();
+    wr.write_str(name).unwrap();
+
+    wr.unwrap()
+}
+
+/// The state of every toggle we heard of, along with when the command that
+/// set it was issued.
+pub struct Features {
+    toggles: HashMap<String, (bool, u64)>
+}
+
+impl Features {
+    pub fn new() -> Features {
+        Features {
+            toggles: HashMap::new()
+        }
+    }
+
+    /// Toggles nobody flipped yet are off.
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.toggles.find_equiv(&name).map(|&(enabled, _)| enabled).unwrap_or(false)
+    }
+
+    /// Apply the command, unless a later one already was. Whether it's (now)
+    /// the latest one for the toggle.
+    pub fn apply(&mut self, name: &str, enabled: bool, issued_at: u64) -> bool {
+        match self.toggles.find_equiv(&name) {
+            Some(&(_, latest)) if latest > issued_at => return false,
+            _ => {}
+        }
+
+        self.toggles.insert(name.to_string(), (enabled, issued_at));
+        true
+    }
+
+    /// The names of the toggles that are on, sorted.
+    pub fn enabled(&self) -> Vec<String> {
+        let mut enabled: Vec<String> = self.toggles.iter()
+                                           .filter(|&(_, &(enabled, _))| enabled)
+                                           .map(|(name, _)| name.clone())
+                                           .collect();
+        enabled.sort();
+        enabled
+    }
+}
+
+/// A command we initiated, and who acked it so far.
+pub struct Rollout {
+    pub issued_at: u64,
+    pub enabled: bool,
+    /// What the command was signed with, to send it again.
+    pub proof: Vec<u8>,
+    targets: HashSet<Uuid>,
+    acked: HashSet<Uuid>,
+    /// When to send it again to whoever didn't ack it.
+    retry: u64
+}
+
+impl Rollout {
+    /// The targets are the members alive when the command was issued.
+    pub fn new(issued_at: u64, enabled: bool, proof: Vec<u8>, targets: Vec<Uuid>,
+               now: u64) -> Rollout {
+        Rollout {
+            issued_at: issued_at,
+            enabled: enabled,
+            proof: proof,
+            targets: targets.move_iter().collect(),
+            acked: HashSet::new(),
+            retry: now + RETRY
+        }
+    }
+
+    /// Stop waiting on the targets that aren't among the (live) members.
+    pub fn retain(&mut self, alive: &[Uuid]) {
+        let gone: Vec<Uuid> = self.targets.iter()
+                                  .filter(|id| !alive.contains(*id))
+                                  .map(|id| *id)
+                                  .collect();
+        for id in gone.iter() {
+            self.targets.remove(id);
+            self.acked.remove(id);
+        }
+    }
+
+    /// The targets to send the command to again, if it's time to.
+    pub fn due(&mut self, now: u64) -> Option<Vec<Uuid>> {
+        if now < self.retry {
+            return None;
+        }
+
+        self.retry = now + RETRY;
+        Some(self.targets.iter().filter(|id| !self.acked.contains(*id)).map(|id| *id).collect())
+    }
+
+    /// Whether the ack was news, acks of earlier commands don't count.
+    pub fn ack(&mut self, id: Uuid, issued_at: u64) -> bool {
+        issued_at == self.issued_at && self.targets.contains(&id) && self.acked.insert(id)
+    }
+
+    /// How many of the targets acked, and how many there are.
+    pub fn progress(&self) -> (uint, uint) {
+        (self.acked.len(), self.targets.len())
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.acked.len() == self.targets.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn latest_command_wins() {
+        let mut features = Features::new();
+        assert!(!features.is_enabled("enable-compression"));
+
+        assert!(features.apply("enable-compression", true, 20));
+        assert!(!features.apply("enable-compression", false, 10));
+        assert!(features.is_enabled("enable-compression"));
+        assert_eq!(features.enabled(), vec!["enable-compression".to_string()]);
+
+        assert!(features.apply("enable-compression", false, 30));
+        assert!(features.enabled().is_empty());
+    }
+
+    #[test]
+    fn count_acks_of_the_targets() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut rollout = Rollout::new(10, true, vec![], vec![a, b], 0);
+        assert_eq!(rollout.due(RETRY - 1), None);
+        assert_eq!(rollout.due(RETRY).map(|ids| ids.len()), Some(2));
+
+        assert!(rollout.ack(a, 10));
+        assert!(!rollout.ack(a, 10));
+        assert!(!rollout.ack(b, 9));
+        assert!(!rollout.ack(Uuid::new_v4(), 10));
+        assert_eq!(rollout.progress(), (1, 2));
+        assert!(!rollout.is_done());
+
+        assert_eq!(rollout.due(2 * RETRY), Some(vec![b]));
+        assert!(rollout.ack(b, 10));
+        assert!(rollout.is_done());
+
+        // Dead targets aren't waited on.
+        let mut rollout = Rollout::new(10, true, vec![], vec![a, b], 0);
+        rollout.ack(a, 10);
+        rollout.retain(&[a]);
+        assert!(rollout.is_done());
+    }
+}
@@ -0,0 +1,248 @@
+//! Frames too large for a single datagram (over `Config::udp_mtu`) are
+//! split into fragments and put back together on the other side. Every
+//! fragment is sent (and acked, and retransmitted) on it's own, and
+//! carries which frame it belongs to, it's place and how many there are:
+//!
+//! ```notrust
+//! bitdata Fragment {
+//!     Fragment {
+//!         frame: u32, // per peer
+//!         index: u16,
+//!         count: u16,
+//!         chunk: &[u8]
+//!     }
+//! }
+//! ```
+//!
+//! Frames still missing fragments after `Config::udp_reassembly_timeout`
+//! are given up on, and there's only so many put back together at once
+//! (`MAX_PARTIAL`), each of only so many fragments (`MAX_FRAGMENTS`).
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io::{BufReader, MemWriter};
+
+use framing::MAX_FRAME;
+
+pub static HEADER: uint = 8;
+
+/// How many fragments a frame takes at most. The largest frame takes
+/// about 12k at the default `Config::udp_mtu`.
+pub static MAX_FRAGMENTS: uint = 16_384;
+
+/// How many frames are put back together at once, at most.
+pub static MAX_PARTIAL: uint = 64;
+
+/// Split the frame into fragments of up to `size` bytes each (their header
+/// included). `None` if that takes more than `MAX_FRAGMENTS`.
+pub fn split(frame: &[u8], id: u32, size: uint) -> Option<Vec<Vec<u8>>> {
+    let chunk = size - HEADER;
+    let count = (frame.len() + chunk - 1) / chunk;
+    if count > MAX_FRAGMENTS {
+        return None;
+    }
+
+    Some(frame.chunks(chunk).enumerate().map(|(index, chunk)| {
+        let mut wr = MemWriter::with_capacity(HEADER + chunk.len());
+        // Writing to memory can't fail.
+        wr.write_be_u32(id).unwrap();
+        wr.write_be_u16(index as u16).unwrap();
+        wr.write_be_u16(count as u16).unwrap();
+        wr.write(chunk).unwrap();
+        wr.unwrap()
+    }).collect())
+}
+
+/// The frame id, index and count of the fragment.
+fn parse(fragment: &[u8]) -> Option<(u32, uint, uint)> {
+    if fragment.len() < HEADER {
+        return None;
+    }
+
+    let mut rd = BufReader::new(fragment.slice_to(HEADER));
+    match (rd.read_be_u32(), rd.read_be_u16(), rd.read_be_u16()) {
+        (Ok(id), Ok(index), Ok(count)) => Some((id, index as uint, count as uint)),
+        _ => None
+    }
+}
+
+struct Partial {
+    chunks: Vec<Option<Vec<u8>>>,
+    missing: uint,
+    bytes: uint,
+    started: u64
+}
+
+/// The frames we got some of the fragments of, per peer (the key).
+pub struct Reassembly<K> {
+    timeout: u64,
+    partial: HashMap<(K, u32), Partial>
+}
+
+impl<K: Hash + Eq + Clone> Reassembly<K> {
+    pub fn new(timeout: u64) -> Reassembly<K> {
+        Reassembly {
+            timeout: timeout,
+            partial: HashMap::new()
+        }
+    }
+
+    /// Take in a fragment from the peer, the whole frame once it's the
+    /// last one missing. Malformed fragments are dropped.
+    pub fn add(&mut self, peer: K, fragment: &[u8], now: u64) -> Option<Vec<u8>> {
+        let (id, index, count) = match parse(fragment) {
+            Some(header) => header,
+            None => return None
+        };
+        let chunk = fragment.slice_from(HEADER);
+        if index >= count || count > MAX_FRAGMENTS {
+            return None;
+        }
+
+        let key = (peer, id);
+        if !self.partial.contains_key(&key) && self.partial.len() >= MAX_PARTIAL {
+            return None;
+        }
+        let done = {
+            let partial = self.partial.find_or_insert_with(key.clone(), |_| Partial {
+                chunks: Vec::from_fn(count, |_| None),
+                missing: count,
+                bytes: 0,
+                started: now
+            });
+            if partial.chunks.len() != count || partial.chunks.get(index).is_some() {
+                return None;
+            }
+
+            partial.bytes += chunk.len();
+            if partial.bytes > MAX_FRAME {
+                None
+            } else {
+                *partial.chunks.get_mut(index) = Some(chunk.to_vec());
+                partial.missing -= 1;
+                Some(partial.missing == 0)
+            }
+        };
+
+        match done {
+            Some(false) => None,
+            Some(true) => self.partial.pop(&key).map(|partial| {
+                let mut frame = Vec::with_capacity(partial.bytes);
+                for chunk in partial.chunks.move_iter() {
+                    frame.push_all(chunk.unwrap().as_slice());
+                }
+                frame
+            }),
+            None => {
+                // Too large to ever put back together.
+                self.partial.remove(&key);
+                None
+            }
+        }
+    }
+
+    /// Whether the fragment would be taken in, i.e., it's frame is coming
+    /// together already or there's room for another. Malformed ones are
+    /// taken in, only to be dropped.
+    pub fn has_room(&self, peer: K, fragment: &[u8]) -> bool {
+        if self.partial.len() < MAX_PARTIAL {
+            return true;
+        }
+        match parse(fragment) {
+            Some((id, _, _)) => self.partial.contains_key(&(peer, id)),
+            None => true
+        }
+    }
+
+    /// Give up on frames that took too long, how many there were.
+    pub fn expire(&mut self, now: u64) -> uint {
+        let expired: Vec<(K, u32)> = self.partial.iter()
+                                         .filter(|&(_, p)| p.started + self.timeout <= now)
+                                         .map(|(key, _)| key.clone())
+                                         .collect();
+        for key in expired.iter() {
+            self.partial.remove(key);
+        }
+        expired.len()
+    }
+
+    /// Forget everything from the peer, i.e., once it's gone.
+    pub fn forget(&mut self, peer: &K) {
+        let keys: Vec<(K, u32)> = self.partial.keys()
+                                      .filter(|&&(ref p, _)| p == peer)
+                                      .map(|key| key.clone())
+                                      .collect();
+        for key in keys.iter() {
+            self.partial.remove(key);
+        }
+    }
+
+    pub fn len(&self) -> uint {
+        self.partial.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn put_frames_back_together() {
+        let frame: Vec<u8> = range(0u, 100).map(|i| i as u8).collect();
+        let fragments = split(frame.as_slice(), 7, HEADER + 30).unwrap();
+        assert_eq!(fragments.len(), 4);
+
+        let mut reassembly = Reassembly::new(1_000);
+        // Out of order, with a duplicate.
+        assert_eq!(reassembly.add(1u, fragments.get(2).as_slice(), 0), None);
+        assert_eq!(reassembly.add(1u, fragments.get(0).as_slice(), 0), None);
+        assert_eq!(reassembly.add(1u, fragments.get(0).as_slice(), 0), None);
+        assert_eq!(reassembly.add(1u, fragments.get(3).as_slice(), 0), None);
+        assert_eq!(reassembly.add(1u, fragments.get(1).as_slice(), 0), Some(frame));
+        assert_eq!(reassembly.len(), 0);
+    }
+
+    #[test]
+    fn give_up_on_incomplete_frames() {
+        let fragments = split(&[1u8, 2, 3, 4], 1, HEADER + 2).unwrap();
+        let mut reassembly = Reassembly::new(1_000);
+
+        reassembly.add(1u, fragments.get(0).as_slice(), 0);
+        reassembly.add(2u, fragments.get(0).as_slice(), 500);
+        assert_eq!(reassembly.expire(999), 0);
+        assert_eq!(reassembly.expire(1_000), 1);
+
+        // The rest comes too late to make up a frame.
+        assert_eq!(reassembly.add(1u, fragments.get(1).as_slice(), 1_100), None);
+        reassembly.forget(&2u);
+        assert_eq!(reassembly.len(), 1);
+    }
+
+    #[test]
+    fn bound_the_reassemblies() {
+        let mut reassembly = Reassembly::new(1_000);
+
+        // More fragments than any frame takes.
+        let mut huge = split(&[1u8, 2], 0, HEADER + 1).unwrap().get(0).clone();
+        *huge.get_mut(6) = 0xff;
+        *huge.get_mut(7) = 0xff;
+        assert_eq!(reassembly.add(1u, huge.as_slice(), 0), None);
+        assert_eq!(reassembly.len(), 0);
+
+        let fragments: Vec<Vec<Vec<u8>>> = range(0, MAX_PARTIAL as u32 + 1).map(|id| {
+            split(&[1u8, 2], id, HEADER + 1).unwrap()
+        }).collect();
+        for frame in fragments.iter().take(MAX_PARTIAL) {
+            reassembly.add(1u, frame.get(0).as_slice(), 0);
+        }
+        assert_eq!(reassembly.len(), MAX_PARTIAL);
+
+        // No room for another frame, but the ones coming together finish.
+        let last = fragments.get(MAX_PARTIAL);
+        assert!(!reassembly.has_room(1u, last.get(0).as_slice()));
+        assert_eq!(reassembly.add(1u, last.get(0).as_slice(), 0), None);
+        assert!(reassembly.has_room(1u, fragments.get(0).get(1).as_slice()));
+        assert_eq!(reassembly.add(1u, fragments.get(0).get(1).as_slice(), 0), Some(vec![1u8, 2]));
+        assert!(reassembly.has_room(1u, last.get(0).as_slice()));
+    }
+}
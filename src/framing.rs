@@ -0,0 +1,147 @@
+//! How frames go over byte streams (`TcpTransport`, `TlsTransport` and
+//! `WebSocketTransport` when not speaking WebSocket): prefixed with their
+//! length and followed by a checksum, so a truncated or corrupted frame is
+//! refused (and it's connection closed) instead of handed to the codec.
+//!
+//! ```notrust
+//! bitdata Frame {
+//!     Frame {
+//!         len: u32, // big-endian, of the payload
+//!         payload: &[u8],
+//!         checksum: u32 // crc32 of the payload
+//!     }
+//! }
+//! ```
+
+use std::io::{BufReader, IoResult, IoError, InvalidInput};
+
+use crc::crc32;
+use result::{GossipResult, GossipError, ProtocolMismatch};
+
+/// Frames larger than this (in bytes) are refused, and their connection
+/// closed.
+pub static MAX_FRAME: uint = 16 * 1024 * 1024;
+
+/// What a frame takes on top of it's payload.
+pub static OVERHEAD: uint = 8;
+
+pub fn write_frame<W: Writer>(wr: &mut W, frame: &[u8]) -> IoResult<()> {
+    try!(wr.write_be_u32(frame.len() as u32));
+    try!(wr.write(frame));
+    try!(wr.write_be_u32(crc32(frame)));
+    wr.flush()
+}
+
+pub fn read_frame<R: Reader>(rd: &mut R) -> IoResult<Vec<u8>> {
+    let len = try!(rd.read_be_u32()) as uint;
+    read_payload(rd, len)
+}
+
+/// The rest of a frame, once it's length was read.
+pub fn read_payload<R: Reader>(rd: &mut R, len: uint) -> IoResult<Vec<u8>> {
+    if len > MAX_FRAME {
+        return Err(IoError {
+            kind: InvalidInput,
+            desc: "frame too large",
+            detail: Some(format!("{} bytes", len))
+        });
+    }
+
+    let payload = try!(rd.read_exact(len));
+    if try!(rd.read_be_u32()) != crc32(payload.as_slice()) {
+        return Err(IoError {
+            kind: InvalidInput,
+            desc: "corrupted frame",
+            detail: None
+        });
+    }
+
+    Ok(payload)
+}
+
+/// Take every whole frame off the front of the buffer, leaving whatever's
+/// left of the next one.
+pub fn split(buffer: &mut Vec<u8>) -> GossipResult<Vec<Vec<u8>>> {
+    let mut frames = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let rest = buffer.slice_from(offset);
+        if rest.len() < 4 {
+            break;
+        }
+
+        let len = BufReader::new(rest).read_be_u32().unwrap() as uint;
+        if len > MAX_FRAME {
+            return Err(GossipError::new("frame too large", ProtocolMismatch));
+        }
+        if rest.len() < len + OVERHEAD {
+            break;
+        }
+
+        let payload = rest.slice(4, 4 + len);
+        let checksum = BufReader::new(rest.slice_from(4 + len)).read_be_u32().unwrap();
+        if checksum != crc32(payload) {
+            return Err(GossipError::new("corrupted frame", ProtocolMismatch));
+        }
+
+        frames.push(payload.to_vec());
+        offset += len + OVERHEAD;
+    }
+
+    let rest = buffer.slice_from(offset).to_vec();
+    *buffer = rest;
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{MemWriter, MemReader};
+
+    #[test]
+    fn frames_roundtrip() {
+        let mut wr = MemWriter::new();
+        write_frame(&mut wr, &[1u8, 2, 3]).unwrap();
+        write_frame(&mut wr, &[]).unwrap();
+
+        let mut rd = MemReader::new(wr.unwrap());
+        assert_eq!(read_frame(&mut rd).unwrap(), vec![1u8, 2, 3]);
+        assert_eq!(read_frame(&mut rd).unwrap(), vec![]);
+        assert!(read_frame(&mut rd).is_err());
+
+        let mut rd = MemReader::new(vec![0xffu8, 0xff, 0xff, 0xff]);
+        assert!(read_frame(&mut rd).is_err());
+    }
+
+    #[test]
+    fn refuse_truncated_and_corrupted_frames() {
+        let mut wr = MemWriter::new();
+        write_frame(&mut wr, &[1u8, 2, 3]).unwrap();
+        let frame = wr.unwrap();
+
+        let truncated = frame.slice_to(frame.len() - 1).to_vec();
+        assert!(read_frame(&mut MemReader::new(truncated)).is_err());
+
+        let mut corrupted = frame.clone();
+        *corrupted.get_mut(5) ^= 0x01;
+        assert!(read_frame(&mut MemReader::new(corrupted.clone())).is_err());
+        assert!(split(&mut corrupted).is_err());
+    }
+
+    #[test]
+    fn split_whole_frames_off() {
+        let mut wr = MemWriter::new();
+        write_frame(&mut wr, &[1u8, 2, 3]).unwrap();
+        write_frame(&mut wr, &[4u8]).unwrap();
+        let bytes = wr.unwrap();
+
+        let mut buffer = bytes.slice_to(bytes.len() - 2).to_vec();
+        assert_eq!(split(&mut buffer).unwrap(), vec![vec![1u8, 2, 3]]);
+        assert_eq!(buffer.len(), 1 + OVERHEAD - 2);
+
+        buffer.push_all(bytes.slice_from(bytes.len() - 2));
+        assert_eq!(split(&mut buffer).unwrap(), vec![vec![4u8]]);
+        assert!(buffer.is_empty());
+    }
+}
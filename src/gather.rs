@@ -0,0 +1,227 @@
+//! Scatter/gather over broadcasts: a query goes out to every member, and
+//! their answers come back to the asker alone (see `Node::broadcast_to`),
+//! correlated with the query by it's id, until enough members answered or
+//! we gave up:
+//!
+//! ```notrust
+//! let owners: Gather<ShardQuery, bool> = Gather::new("default", "shard-owner");
+//!
+//! // Every member answers...
+//! for (query, _) in owners.queries(&mut node) {
+//!     owners.answer(&mut node, &query, owns(query.body.shard)).unwrap();
+//! }
+//!
+//! // ...whoever asks collects the answers.
+//! let answers = try!(owners.gather(&mut node, ShardQuery { shard: 7 }, Fraction(0.5), 2_000));
+//! ```
+//!
+//! Queries and answers are typed topics (see `typed`), `<name>` and
+//! `<name>/answers` within the namespace.
+
+use std::io::IoError;
+use std::io::timer;
+use serialize::{json, Encodable, Decodable};
+use uuid::Uuid;
+
+use clock;
+use protocol::Node;
+use result::GossipResult;
+use typed::{Topic, Subscription};
+
+/// How long (in milliseconds) to wait between ticks while gathering.
+static POLL: u64 = 10;
+
+#[deriving(Show, Clone, PartialEq, Encodable, Decodable)]
+pub struct Query<Q> {
+    pub id: Uuid,
+    /// Whoever's gathering.
+    pub from: Uuid,
+    pub body: Q
+}
+
+#[deriving(Show, Clone, PartialEq, Encodable, Decodable)]
+pub struct Answer<R> {
+    /// The id of the query.
+    pub query: Uuid,
+    /// Whoever answered, as the origin of the broadcast tells once it's
+    /// gathered (not as the member claimed).
+    pub from: Uuid,
+    pub body: R
+}
+
+/// How many answers are enough to stop gathering.
+#[deriving(Show, Clone, PartialEq)]
+pub enum Enough {
+    AtLeast(uint),
+    /// Of the other members alive as the answers come in. We don't count,
+    /// we're busy gathering rather than answering.
+    Fraction(f64)
+}
+
+impl Enough {
+    pub fn reached(&self, answers: uint, alive: uint) -> bool {
+        match *self {
+            AtLeast(n) => answers >= n,
+            Fraction(fraction) => answers as f64 >= fraction * alive as f64
+        }
+    }
+}
+
+pub struct Gather<Q, R> {
+    queries: Topic<Query<Q>>,
+    answers: Topic<Answer<R>>
+}
+
+impl<Q, R> Gather<Q, R> {
+    pub fn new(namespace: &str, name: &str) -> Gather<Q, R> {
+        Gather {
+            queries: Topic::new(namespace, name),
+            answers: Topic::new(namespace, format!("{}/answers", name).as_slice())
+        }
+    }
+}
+
+impl<'a, Q: Encodable<json::Encoder<'a>, IoError> + Decodable<json::Decoder, json::DecoderError>,
+         R: Encodable<json::Encoder<'a>, IoError> + Decodable<json::Decoder, json::DecoderError>>
+    Gather<Q, R> {
+    /// The queries to answer, ours included.
+    pub fn queries(&self, node: &mut Node) -> Subscription<Query<Q>> {
+        self.queries.subscribe(node)
+    }
+
+    /// Answer whoever asked, none of the other members hear of it.
+    pub fn answer(&self, node: &mut Node, query: &Query<Q>, body: R) -> GossipResult<()> {
+        let answer = Answer { query: query.id, from: node.id(), body: body };
+        self.answers.publish_to(node, query.from, &answer)
+    }
+
+    /// Ask every member, ticking the node until enough of them answered or
+    /// `timeout` milliseconds went by. Returns the answers gathered either
+    /// way, one per member.
+    pub fn gather(&self, node: &mut Node, body: Q, enough: Enough,
+                  timeout: u64) -> GossipResult<Vec<Answer<R>>> {
+        // Before the query goes out, so no answer comes in unheard.
+        let mut answers = self.answers.subscribe(node);
+        let query = Query { id: Uuid::new_v4(), from: node.id(), body: body };
+        try!(self.queries.publish(node, &query));
+
+        let deadline = clock::now() + timeout;
+        let mut gathered: Vec<Answer<R>> = Vec::new();
+        loop {
+            loop {
+                match answers.try_next_from() {
+                    Some((mut answer, origin, _)) => {
+                        let known = gathered.iter().any(|a| a.from == origin);
+                        if answer.query == query.id && !known {
+                            answer.from = origin;
+                            gathered.push(answer);
+                        }
+                    },
+                    None => break
+                }
+            }
+
+            // Everyone but ourselves.
+            let others = node.alive_members() - 1;
+            if enough.reached(gathered.len(), others) || clock::now() >= deadline {
+                return Ok(gathered);
+            }
+            timer::sleep(POLL);
+            node.tick();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::timer::sleep;
+    use uuid::Uuid;
+    use memory::{InMemoryTransport, Network};
+    use protocol::Node;
+    use transport::Transport;
+
+    #[test]
+    fn enough_answers() {
+        assert!(AtLeast(2).reached(2, 5));
+        assert!(!AtLeast(3).reached(2, 5));
+        assert!(Fraction(0.5).reached(2, 4));
+        assert!(!Fraction(0.5).reached(2, 5));
+        assert!(Fraction(1.0).reached(0, 0));
+    }
+
+    #[test]
+    fn give_up_at_the_deadline() {
+        let mut node = Node::new();
+        let owners: Gather<u32, bool> = Gather::new("default", "shard-owner");
+        let mut queries = owners.queries(&mut node);
+
+        // Alone in the cluster, and not serving queries while we gather.
+        let answers = owners.gather(&mut node, 7, AtLeast(1), 50).unwrap();
+        assert!(answers.is_empty());
+
+        // Our own query was delivered to us all the same.
+        let (query, _) = queries.try_next().unwrap();
+        assert_eq!((query.from, query.body), (node.id(), 7));
+        assert!(queries.try_next().is_none());
+    }
+
+    #[test]
+    fn gather_one_answer_per_member() {
+        let network = Network::new();
+        let transport = box InMemoryTransport::new(&network) as Box<Transport + Send>;
+        let (listening_tx, listening_rx) = channel();
+        let (done_tx, done_rx) = channel::<()>();
+        spawn(proc() {
+            let mut node = Node::new();
+            node.set_transport(transport);
+            node.listen("127.0.0.1", 3444).unwrap();
+            let owners: Gather<u32, bool> = Gather::new("default", "shard-owner");
+            let mut queries = owners.queries(&mut node);
+            listening_tx.send(node.id());
+
+            while done_rx.try_recv().is_err() {
+                match queries.try_next() {
+                    Some((query, _)) => {
+                        owners.answer(&mut node, &query, query.body == 7).unwrap();
+                        // Neither answering again as somebody else, nor
+                        // answering some other query counts.
+                        let posing = Answer { query: query.id, from: Uuid::new_v4(), body: false };
+                        owners.answers.publish_to(&mut node, query.from, &posing).unwrap();
+                        let other = Answer { query: Uuid::new_v4(), from: node.id(), body: false };
+                        owners.answers.publish_to(&mut node, query.from, &other).unwrap();
+                    },
+                    None => {}
+                }
+                node.tick();
+                sleep(5);
+            }
+        });
+        let owner = listening_rx.recv();
+
+        let mut node = Node::new();
+        node.set_transport(box InMemoryTransport::new(&network) as Box<Transport + Send>);
+        node.listen("127.0.0.1", 4444).unwrap();
+        node.join("127.0.0.1", 3444).unwrap();
+        for _ in range(0u, 100) {
+            if node.alive_members() == 2 {
+                break;
+            }
+            node.tick();
+            sleep(5);
+        }
+        assert_eq!(node.alive_members(), 2);
+
+        // The only other member answered, that's all of them.
+        let owners: Gather<u32, bool> = Gather::new("default", "shard-owner");
+        let answers = owners.gather(&mut node, 7, Fraction(1.0), 2_000).unwrap();
+        assert_eq!(answers.len(), 1);
+        assert_eq!((answers.get(0).from, answers.get(0).body), (owner, true));
+
+        // Waiting for more doesn't get more out of it.
+        let answers = owners.gather(&mut node, 8, AtLeast(2), 300).unwrap();
+        done_tx.send(());
+        assert_eq!(answers.len(), 1);
+        assert_eq!((answers.get(0).from, answers.get(0).body), (owner, false));
+    }
+}
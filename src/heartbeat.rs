@@ -0,0 +1,115 @@
+//! Periodic heartbeats carrying a tiny application supplied blob (i.e.,
+//! the load or the version a node runs). Every node keeps the last one it
+//! heard from everybody else, which is enough to build a dashboard without
+//! setting up a separate channel.
+//!
+//! The blob is opaque to us and travels with every heartbeat, so keep it
+//! small.
+
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use pressure::PressureLevel;
+
+/// The last heartbeat heard from a member.
+#[deriving(Show, Clone, PartialEq)]
+pub struct Heartbeat {
+    pub payload: Vec<u8>,
+    /// The resource pressure of the member's host, see `pressure`.
+    pub pressure: PressureLevel,
+    /// When (in milliseconds, monotonic) it arrived.
+    pub received_at: u64
+}
+
+pub struct Heartbeats {
+    interval: u64,
+    /// What we send. Nothing goes out until the application sets it.
+    payload: Option<Vec<u8>>,
+    next: u64,
+    last: HashMap<Uuid, Heartbeat>
+}
+
+impl Heartbeats {
+    pub fn new(interval: u64) -> Heartbeats {
+        Heartbeats {
+            interval: interval,
+            payload: None,
+            next: 0,
+            last: HashMap::new()
+        }
+    }
+
+    /// Replace the payload, it goes out right away.
+    pub fn set_payload(&mut self, payload: Vec<u8>) {
+        self.payload = Some(payload);
+        self.next = 0;
+    }
+
+    /// The payload to send if a heartbeat is due.
+    pub fn due(&mut self, now: u64) -> Option<Vec<u8>> {
+        if now < self.next {
+            return None;
+        }
+
+        match self.payload {
+            Some(ref payload) => {
+                self.next = now + self.interval;
+                Some(payload.clone())
+            },
+            None => None
+        }
+    }
+
+    pub fn record(&mut self, id: Uuid, payload: Vec<u8>, pressure: PressureLevel, now: u64) {
+        self.last.insert(id, Heartbeat {
+            payload: payload,
+            pressure: pressure,
+            received_at: now
+        });
+    }
+
+    pub fn last<'a>(&'a self, id: &Uuid) -> Option<&'a Heartbeat> {
+        self.last.find(id)
+    }
+
+    /// Forget about a member that left.
+    pub fn forget(&mut self, id: &Uuid) {
+        self.last.remove(id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+    use pressure::{Low, High};
+
+    #[test]
+    fn send_once_per_interval() {
+        let mut heartbeats = Heartbeats::new(1000);
+        assert_eq!(heartbeats.due(0), None);
+
+        heartbeats.set_payload(vec![1u8]);
+        assert_eq!(heartbeats.due(0), Some(vec![1u8]));
+        assert_eq!(heartbeats.due(999), None);
+        assert_eq!(heartbeats.due(1000), Some(vec![1u8]));
+
+        // New payloads don't wait for the interval.
+        heartbeats.set_payload(vec![2u8]);
+        assert_eq!(heartbeats.due(1001), Some(vec![2u8]));
+    }
+
+    #[test]
+    fn keep_the_last_heartbeat() {
+        let mut heartbeats = Heartbeats::new(1000);
+        let id = Uuid::new_v4();
+
+        heartbeats.record(id, vec![1u8], Low, 10);
+        heartbeats.record(id, vec![2u8], High, 20);
+        let last = Heartbeat { payload: vec![2u8], pressure: High, received_at: 20 };
+        assert_eq!(heartbeats.last(&id), Some(&last));
+
+        heartbeats.forget(&id);
+        assert!(heartbeats.last(&id).is_none());
+    }
+}
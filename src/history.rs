@@ -0,0 +1,159 @@
+//! The broadcasts we keep around after relaying them, so slow peers that
+//! missed one can still pull it from us (and joiners get them with their
+//! snapshot). They're kept for `Config::history_retention` milliseconds and
+//! up to `Config::history_max_bytes`, whichever runs out first.
+//!
+//! This is separate from dropping duplicates: a broadcast we no longer
+//! serve is still recognized as one we've seen.
+//!
+//! What we have is summed up in a Merkle tree as we go, see `merkle`.
+
+use std::collections::{HashSet, RingBuf, Deque};
+use uuid::Uuid;
+
+use broadcast::Broadcast;
+use merkle::Merkle;
+
+pub struct History {
+    /// In milliseconds, `None` keeps them for as long as the bytes allow.
+    max_age: Option<u64>,
+    max_bytes: Option<uint>,
+    bytes: uint,
+    /// Oldest first, along with when we recorded them.
+    broadcasts: RingBuf<(u64, Broadcast)>,
+    /// The ids of those, a broadcast is only kept once.
+    ids: HashSet<Uuid>,
+    tree: Merkle
+}
+
+impl History {
+    pub fn new(max_age: Option<u64>, max_bytes: Option<uint>) -> History {
+        History {
+            max_age: max_age,
+            max_bytes: max_bytes,
+            bytes: 0,
+            broadcasts: RingBuf::new(),
+            ids: HashSet::new(),
+            tree: Merkle::new()
+        }
+    }
+
+    /// Keep the broadcast, forgetting the oldest ones if that takes us over
+    /// the byte limit. A broadcast larger than the limit isn't kept at all,
+    /// nor is one we already have (it would toggle it's id out of the tree).
+    pub fn record(&mut self, broadcast: Broadcast, now: u64) {
+        match self.max_bytes {
+            Some(max) if broadcast.len() > max => return,
+            _ => {}
+        }
+        if !self.ids.insert(broadcast.id()) {
+            return;
+        }
+
+        self.bytes += broadcast.len();
+        self.tree.toggle(&broadcast.id());
+        self.broadcasts.push_back((now, broadcast));
+
+        loop {
+            match self.max_bytes {
+                Some(max) if self.bytes > max => self.pop(),
+                _ => break
+            }
+        }
+    }
+
+    /// Forget the broadcasts older than the retention window.
+    pub fn expire(&mut self, now: u64) {
+        let max_age = match self.max_age {
+            Some(max_age) => max_age,
+            None => return
+        };
+
+        loop {
+            match self.broadcasts.front() {
+                Some(&(at, _)) if at + max_age <= now => self.pop(),
+                _ => break
+            }
+        }
+    }
+
+    /// The broadcast with the id, if we still have it.
+    pub fn find<'a>(&'a self, id: &Uuid) -> Option<&'a Broadcast> {
+        self.broadcasts.iter().map(|entry| entry.ref1()).find(|b| b.id() == *id)
+    }
+
+    /// Every broadcast we still have, oldest first.
+    pub fn broadcasts<'a>(&'a self) -> Vec<&'a Broadcast> {
+        self.broadcasts.iter().map(|entry| entry.ref1()).collect()
+    }
+
+    pub fn len(&self) -> uint {
+        self.broadcasts.len()
+    }
+
+    pub fn tree<'a>(&'a self) -> &'a Merkle {
+        &self.tree
+    }
+
+    /// The size of every broadcast we have.
+    pub fn bytes(&self) -> uint {
+        self.bytes
+    }
+
+    fn pop(&mut self) {
+        match self.broadcasts.pop_front() {
+            Some((_, broadcast)) => {
+                self.bytes -= broadcast.len();
+                self.ids.remove(&broadcast.id());
+                self.tree.toggle(&broadcast.id());
+            },
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use broadcast::Broadcast;
+
+    #[test]
+    fn retain_by_age_and_size() {
+        let mut history = History::new(Some(1_000), Some(10));
+        let first = Broadcast::new(vec![1u8, 2, 3, 4]).unwrap();
+        let id = first.id();
+
+        history.record(first, 0);
+        history.record(Broadcast::new(vec![5u8, 6, 7, 8]).unwrap(), 500);
+        assert!(history.find(&id).is_some());
+
+        // Over the limit, the oldest has to go.
+        history.record(Broadcast::new(vec![9u8, 10, 11, 12]).unwrap(), 600);
+        assert!(history.find(&id).is_none());
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.bytes(), 8);
+
+        history.record(Broadcast::new(Vec::from_elem(11, 0u8)).unwrap(), 600);
+        assert_eq!(history.len(), 2);
+
+        history.expire(1_500);
+        assert_eq!(history.len(), 1);
+        history.expire(1_600);
+        assert_eq!(history.len(), 0);
+        assert_eq!(history.bytes(), 0);
+        assert_eq!(history.tree().root(), 0);
+    }
+
+    #[test]
+    fn record_a_broadcast_once() {
+        let mut history = History::new(None, None);
+        let broadcast = Broadcast::new(vec![1u8, 2, 3, 4]).unwrap();
+        history.record(broadcast.clone(), 0);
+        let root = history.tree().root();
+
+        history.record(broadcast, 100);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.bytes(), 4);
+        assert_eq!(history.tree().root(), root);
+    }
+}
@@ -0,0 +1,89 @@
+//! Where a node's id comes from, see `Config::node_id`. Random ids are
+//! different every start, derived ones the same for the same inputs: the
+//! host's name within a namespace (i.e., the cluster's), or a name of the
+//! operator's choosing. Infrastructure-as-code can then tell the ids apart
+//! ahead of time (see `derive_id`), i.e., to put them on an allowlist.
+//!
+//! Derived ids hash the inputs with the crypto provider (see
+//! `Config::crypto`), so whoever predicts them has to use the same one.
+
+use std::io::Command;
+use std::str;
+use uuid::Uuid;
+
+use crypto::Crypto;
+use result::{GossipResult, GossipError, InvalidConfig, io_err};
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum NodeId {
+    RandomId,
+    /// Derived from the host's name within the namespace.
+    FromHostname(String),
+    /// Derived from the name only, whatever the host.
+    FromName(String)
+}
+
+/// The id to start with.
+pub fn node_id(source: &NodeId, crypto: &Crypto) -> GossipResult<Uuid> {
+    match *source {
+        RandomId => Ok(Uuid::new_v4()),
+        FromHostname(ref namespace) => {
+            let host = try!(hostname());
+            derive_id(crypto, format!("{}/{}", namespace, host).as_slice())
+        },
+        FromName(ref name) => derive_id(crypto, name.as_slice())
+    }
+}
+
+/// The id derived from the name, a `FromHostname` one being derived from
+/// `<namespace>/<hostname>`. Marked as a custom (version 8) UUID so it's
+/// never mistaken for a random one. Fails without a crypto provider to hash
+/// with.
+pub fn derive_id(crypto: &Crypto, name: &str) -> GossipResult<Uuid> {
+    let mut bytes = Vec::with_capacity(16);
+    for half in range(0u8, 2) {
+        let mut data = vec![half];
+        data.push_all(name.as_bytes());
+        let hash = try!(crypto.hash(data.as_slice()));
+        for i in range(0u, 8).rev() {
+            bytes.push((hash >> (i * 8)) as u8);
+        }
+    }
+
+    let (version, variant) = (*bytes.get(6), *bytes.get(8));
+    *bytes.get_mut(6) = version & 0x0f | 0x80;
+    *bytes.get_mut(8) = variant & 0x3f | 0x80;
+    Ok(Uuid::from_bytes(bytes.as_slice()).unwrap())
+}
+
+fn hostname() -> GossipResult<String> {
+    let output = try!(Command::new("hostname").output().map_err(io_err));
+    let host = match str::from_utf8(output.output.as_slice()) {
+        Some(host) if output.status.success() => host.trim().to_string(),
+        _ => String::new()
+    };
+
+    if host.is_empty() {
+        Err(GossipError::new("couldn't tell the host's name", InvalidConfig))
+    } else {
+        Ok(host)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config::Config;
+
+    #[test]
+    fn derive_the_same_id_from_the_same_name() {
+        let crypto = Config::new().crypto;
+        let id = node_id(&FromName("web-1".to_string()), &crypto).unwrap();
+        assert_eq!(id, derive_id(&crypto, "web-1").unwrap());
+        assert!(id != derive_id(&crypto, "web-2").unwrap());
+        assert!(node_id(&RandomId, &crypto).unwrap() != node_id(&RandomId, &crypto).unwrap());
+
+        let bytes = id.as_bytes();
+        assert_eq!((bytes[6] >> 4, bytes[8] >> 6), (8, 2));
+    }
+}
@@ -0,0 +1,80 @@
+//! Invariants of the engine's state, checked after every input when the
+//! `paranoid-checks` feature is on. Way too slow for production, but the
+//! simulation and the tests catch a broken transition right where it
+//! happened instead of a few thousand inputs later.
+
+use uuid::Uuid;
+
+use member::Membership;
+use state::State;
+
+/// Everything wrong with the state, nothing if it's all fine.
+pub fn violations(id: &Uuid, members: &Membership, state: &State) -> Vec<String> {
+    let mut violations = Vec::new();
+    let local = id.to_string();
+
+    for peer in state.eager().intersection(state.lazy()) {
+        violations.push(format!("{} is both eager and lazy", peer));
+    }
+
+    if members.find(id).is_some() {
+        violations.push("we're a member of our own membership".to_string());
+    }
+
+    for peer in state.eager().iter().chain(state.lazy().iter()) {
+        if *peer == local {
+            violations.push("we're part of our own broadcast tree".to_string());
+        } else if !members.iter().any(|m| m.id().to_string() == *peer) {
+            violations.push(format!("{} is part of the broadcast tree, but not a member", peer));
+        }
+    }
+
+    for broadcast in state.broadcasts().iter() {
+        if !state.has_seen(&broadcast.id()) {
+            violations.push(format!("{} is retained, but wasn't seen", broadcast.id()));
+        }
+    }
+
+    violations
+}
+
+/// Fail with a report of everything that's wrong, if anything is.
+pub fn check(id: &Uuid, members: &Membership, state: &State, now: u64) {
+    let violations = violations(id, members, state);
+    if violations.is_empty() {
+        return;
+    }
+
+    fail!("broken invariants at {}ms on {}:\n  {}\neager: {}\nlazy: {}\nmembers: {}", now, id,
+          violations.as_slice().connect("\n  "), state.eager(), state.lazy(),
+          members.iter().map(|m| m.id()).collect::<Vec<Uuid>>());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    use journal::{Eager, Lazy};
+    use member::Membership;
+    use protocol::Peer;
+    use broadcast::Broadcast;
+    use state::State;
+
+    #[test]
+    fn report_every_violation() {
+        let id = Uuid::new_v4();
+        let mut members = Membership::new();
+        let mut state = State::new();
+
+        let peer = Peer::empty();
+        state.set_link(peer.id().to_string().as_slice(), Some(Eager));
+        members.insert(peer);
+        assert!(violations(&id, &members, &state).is_empty());
+
+        state.set_link(id.to_string().as_slice(), Some(Lazy));
+        state.set_link(Uuid::new_v4().to_string().as_slice(), Some(Lazy));
+        state.record(Broadcast::new(vec![1u8]).unwrap(), 0);
+        assert_eq!(violations(&id, &members, &state).len(), 3);
+    }
+}
@@ -0,0 +1,141 @@
+//! Pacing of full-state syncs served to joining nodes. When hundreds of
+//! nodes join at once (i.e., a deployment rollout) we don't want to serve
+//! every full-state transfer simultaneously, so joiners wait their turn.
+
+use std::cmp;
+use std::collections::{HashMap, RingBuf, Deque};
+use uuid::Uuid;
+
+/// A snapshot of how far along the join queue is.
+#[deriving(Show, Clone, PartialEq)]
+pub struct JoinProgress {
+    /// Joiners we're currently syncing.
+    pub syncing: uint,
+    /// Joiners waiting for a free slot.
+    pub queued: uint,
+    /// Syncs that finished (or timed out) since we started.
+    pub completed: u64
+}
+
+pub struct JoinQueue {
+    limit: uint,
+    timeout: u64,
+    /// Joiners being synced along with when their sync started.
+    syncing: HashMap<Uuid, u64>,
+    pending: RingBuf<Uuid>,
+    completed: u64
+}
+
+impl JoinQueue {
+    /// Allow at most `limit` concurrent syncs. A sync that hasn't been
+    /// acknowledged within `timeout` milliseconds gives up it's slot.
+    pub fn new(limit: uint, timeout: u64) -> JoinQueue {
+        JoinQueue {
+            limit: cmp::max(limit, 1),
+            timeout: timeout,
+            syncing: HashMap::new(),
+            pending: RingBuf::new(),
+            completed: 0
+        }
+    }
+
+    /// Queue a joiner. Returns the joiners that should be synced right
+    /// away, which may or may not include this one.
+    pub fn push(&mut self, id: Uuid, now: u64) -> Vec<Uuid> {
+        // Joiners retry, so we might already know about this one.
+        if !self.syncing.contains_key(&id) && !self.pending.iter().any(|p| *p == id) {
+            self.pending.push_back(id);
+        }
+
+        self.expire(now);
+        self.start(now)
+    }
+
+    /// The joiner acknowledged it's sync, freeing up a slot.
+    pub fn complete(&mut self, id: &Uuid, now: u64) -> Vec<Uuid> {
+        if self.syncing.remove(id) {
+            self.completed += 1;
+        }
+
+        self.expire(now);
+        self.start(now)
+    }
+
+    pub fn progress(&self) -> JoinProgress {
+        JoinProgress {
+            syncing: self.syncing.len(),
+            queued: self.pending.len(),
+            completed: self.completed
+        }
+    }
+
+    /// The joiners being synced, along with when their sync times out.
+    pub fn syncing(&self) -> Vec<(Uuid, u64)> {
+        self.syncing.iter().map(|(id, started)| (*id, *started + self.timeout)).collect()
+    }
+
+    /// The joiners waiting for a slot, first in line first.
+    pub fn queued(&self) -> Vec<Uuid> {
+        self.pending.iter().map(|id| *id).collect()
+    }
+
+    fn expire(&mut self, now: u64) {
+        let timeout = self.timeout;
+        let expired: Vec<Uuid> = self.syncing.iter()
+            .filter(|&(_, started)| now >= *started + timeout)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in expired.iter() {
+            self.syncing.remove(id);
+            self.completed += 1;
+        }
+    }
+
+    fn start(&mut self, now: u64) -> Vec<Uuid> {
+        let mut started = Vec::new();
+
+        while self.syncing.len() < self.limit {
+            match self.pending.pop_front() {
+                Some(id) => {
+                    self.syncing.insert(id, now);
+                    started.push(id);
+                },
+                None => break
+            }
+        }
+
+        started
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn limit_concurrent_syncs() {
+        let mut queue = JoinQueue::new(2, 1000);
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        assert_eq!(queue.push(a, 0), vec![a]);
+        assert_eq!(queue.push(b, 0), vec![b]);
+        assert_eq!(queue.push(c, 0), vec![]);
+        assert_eq!(queue.push(c, 0), vec![]);
+        assert_eq!(queue.progress(), JoinProgress { syncing: 2, queued: 1, completed: 0 });
+
+        assert_eq!(queue.complete(&a, 10), vec![c]);
+        assert_eq!(queue.progress(), JoinProgress { syncing: 2, queued: 0, completed: 1 });
+    }
+
+    #[test]
+    fn stale_syncs_give_up_their_slot() {
+        let mut queue = JoinQueue::new(1, 1000);
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+
+        assert_eq!(queue.push(a, 0), vec![a]);
+        assert_eq!(queue.push(b, 500), vec![]);
+        assert_eq!(queue.push(b, 1000), vec![b]);
+    }
+}
@@ -0,0 +1,108 @@
+//! A bounded journal of the changes to our broadcast tree: which peer
+//! moved between the eager and the lazy set (or out of both), why, and
+//! when. Dissemination latency spikes usually trace back to one of those,
+//! so keeping the last few hundred around answers most "what happened at
+//! 14:02" questions. See `Node::topology_journal`.
+
+use std::collections::{RingBuf, Deque};
+
+/// The set a peer is in.
+#[deriving(Show, Clone, PartialEq, Encodable, Decodable)]
+pub enum Link {
+    /// Receives payloads right away (a tree edge).
+    Eager,
+    /// Only receives announcements of what we have.
+    Lazy
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum Reason {
+    /// The peer sent us a duplicate, so it isn't needed as a tree edge.
+    Pruned,
+    /// A payload we missed was pulled from the peer, it's a tree edge now.
+    Grafted,
+    /// A faster path exists, the peer was demoted for being slow.
+    LatencyDemoted,
+    /// The peer died, or was evicted.
+    Failed,
+    /// The peer left on purpose, see `Node::leave`.
+    Left,
+    /// The peer joined.
+    Added,
+    /// The peer's score dropped, see `score`.
+    Penalized,
+    /// The peer earned back enough of it's score.
+    Recovered,
+    /// The peer was cordoned, see `Node::cordon`.
+    Cordoned,
+    Uncordoned
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub struct TopologyChange {
+    /// Our wall clock (in milliseconds since the unix epoch).
+    pub at: u64,
+    pub peer: String,
+    /// `None` if the peer wasn't in either set.
+    pub from: Option<Link>,
+    /// `None` if the peer left both sets.
+    pub to: Option<Link>,
+    pub reason: Reason
+}
+
+pub struct Journal {
+    capacity: uint,
+    changes: RingBuf<TopologyChange>
+}
+
+impl Journal {
+    pub fn new(capacity: uint) -> Journal {
+        Journal {
+            capacity: capacity,
+            changes: RingBuf::new()
+        }
+    }
+
+    /// Record a change, forgetting the oldest one if we're full.
+    pub fn record(&mut self, change: TopologyChange) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.changes.len() == self.capacity {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(change);
+    }
+
+    /// Every change we remember, oldest first.
+    pub fn changes(&self) -> Vec<TopologyChange> {
+        self.changes.iter().map(|change| change.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn change(at: u64) -> TopologyChange {
+        TopologyChange {
+            at: at,
+            peer: "10.0.0.1:3444".to_string(),
+            from: Some(Eager),
+            to: Some(Lazy),
+            reason: Pruned
+        }
+    }
+
+    #[test]
+    fn bounded_history() {
+        let mut journal = Journal::new(2);
+        journal.record(change(1));
+        journal.record(change(2));
+        journal.record(change(3));
+
+        let at: Vec<u64> = journal.changes().iter().map(|c| c.at).collect();
+        assert_eq!(at, vec![2, 3]);
+    }
+}
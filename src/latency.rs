@@ -0,0 +1,104 @@
+//! How long broadcasts take to reach us from their origin. Only a sample
+//! of the broadcasts is measured, picked by their id so every node samples
+//! the same ones and the histograms of different nodes can be compared
+//! (or merged). The origin's send time travels with the broadcast and the
+//! latency is measured against the origin's clock (see `skew`).
+
+use uuid::Uuid;
+
+/// The upper bounds (in milliseconds) of the histogram's buckets. Anything
+/// slower lands in the last, unbounded, bucket.
+pub static BOUNDS: [u64, ..13] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1_000, 2_000, 5_000,
+                                  10_000];
+
+#[deriving(Show, Clone, PartialEq)]
+pub struct Histogram {
+    /// How many samples fell within each of `BOUNDS`, plus one for the
+    /// slower ones.
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    /// The sum of every sample in milliseconds.
+    pub sum: u64
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            buckets: Vec::from_elem(BOUNDS.len() + 1, 0u64),
+            count: 0,
+            sum: 0
+        }
+    }
+
+    pub fn record(&mut self, latency: u64) {
+        let bucket = BOUNDS.iter().position(|bound| latency <= *bound).unwrap_or(BOUNDS.len());
+        *self.buckets.get_mut(bucket) += 1;
+        self.count += 1;
+        self.sum += latency;
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.sum as f64 / self.count as f64)
+        }
+    }
+
+    /// The upper bound of the bucket the `q` quantile (i.e., 0.99) falls
+    /// within. `None` without samples, or if it's within the last bucket.
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = (q * self.count as f64).ceil() as u64;
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += *n;
+            if seen >= rank {
+                return BOUNDS.get(i).map(|bound| *bound);
+            }
+        }
+
+        None
+    }
+}
+
+/// Whether the broadcast is part of the sample of one in `rate`. Ids are
+/// random, so their first bytes are as good as a hash.
+pub fn sampled(id: &Uuid, rate: uint) -> bool {
+    let hash = id.as_bytes().iter().take(8).fold(0u64, |hash, b| hash << 8 | *b as u64);
+    rate != 0 && hash % rate as u64 == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn quantiles() {
+        let mut histogram = Histogram::new();
+        assert_eq!(histogram.quantile(0.5), None);
+
+        for latency in range(0u64, 100) {
+            histogram.record(latency);
+        }
+        histogram.record(60_000);
+
+        assert_eq!(histogram.count, 101);
+        assert_eq!(histogram.quantile(0.5), Some(50));
+        assert_eq!(histogram.quantile(0.99), Some(100));
+        assert_eq!(histogram.quantile(1.0), None);
+    }
+
+    #[test]
+    fn sample_by_id() {
+        let ids: Vec<Uuid> = range(0u, 1000).map(|_| Uuid::new_v4()).collect();
+        let n = ids.iter().filter(|id| sampled(*id, 10)).count();
+        assert!(n > 50 && n < 150);
+        assert!(ids.iter().all(|id| sampled(id, 1)));
+        assert!(!ids.iter().any(|id| sampled(id, 0)));
+    }
+}
@@ -24,14 +24,176 @@ extern crate core;
 extern crate sync;
 extern crate time;
 extern crate msgpack;
+#[cfg(test)]
+extern crate test;
 
-pub use result::{GossipResult, GossipError};
-pub use protocol::{Node};
+pub use result::{GossipResult, GossipError, GossipErrorKind, ErrorClass};
+pub use protocol::{Node, Peer, Health, Incoming};
+pub use config::{Config, Profile, Lan, Wan, Local};
+pub use broadcast::Broadcast;
+pub use typed::{Topic, Subscription};
+pub use gather::{Gather, Query, Answer, Enough, AtLeast, Fraction};
 pub use stream::{Callback, SockAddr};
+pub use member::{Member, MemberState, Status};
+pub use event::Event;
+pub use quota::Quota;
+pub use join::JoinProgress;
+pub use snapshot::Snapshot;
+pub use stats::{ProtocolStats, TopicStats, OTHER_TOPICS};
+pub use topic::{TopicSettings, Capabilities, Encoding, Compression};
+pub use eviction::AdminKey;
+pub use namespace::{Namespace, DEFAULT_NAMESPACE};
+pub use engine::{Engine, Input, Output};
+pub use driver::Handler;
+pub use registry::{Service, Endpoint};
+pub use discovery::{Selector, EndpointChange, EndpointAdded, EndpointRemoved};
+pub use trace::TraceContext;
+pub use socket::SocketOptions;
+pub use bandwidth::{BandwidthReport, Usage, Direction};
+pub use heartbeat::Heartbeat;
+pub use version::VersionReport;
+pub use supervisor::RestartPolicy;
+pub use backoff::Backoff;
+pub use snapshot::{StoragePolicy, FailStop, MemoryOnly};
+pub use subscriber::{StallPolicy, BufferStalled, DropStalled, DisconnectStalled};
+pub use cipher::Cipher;
+pub use crypto::{Crypto, Provider};
+pub use identity::{NodeId, RandomId, FromHostname, FromName, derive_id};
+pub use teardown::{ShutdownPhase, Stopping, Stopped};
+pub use journal::{TopologyChange, Link, Eager, Lazy};
+pub use journal::{Reason, Pruned, Grafted, LatencyDemoted, Failed, Added, Penalized, Recovered};
+pub use journal::{Cordoned, Uncordoned, Left};
+pub use timeline::HealthRecord;
+pub use commit::CommitStats;
+pub use conformance::{Conformance, Check, Outcome, Conforms, Deviates, Wire, TransportWire};
+pub use recent::{BroadcastRecord, Delivery, Delivered, Late, OverLimit};
+pub use latency::Histogram;
+pub use score::{PeerScore, Violation, Undecodable, RateLimited, ProtocolViolation};
+pub use score::{Standing, Good, Demoted, Disconnected};
+pub use preview::ClusterInfo;
+pub use ops::{Operation, SyncingJoiner, QueuedJoiner, EvictionBallot, PendingGraft, ShuttingDown};
+pub use ops::{Unacked, SuspectMember, MissingBroadcast};
+pub use admin::{Command, EvictMember, Cordon, Uncordon, RotateKey, Reconfigure};
+pub use admin::{CommandResult, Applied, Rejected, TimedOut};
+pub use validate::{Problem, Severity, Fatal, Warning};
+pub use transport::{Transport, TransportEvent, Connection, Accepted, Frame, Closed};
+pub use tcp::TcpTransport;
+pub use udp::UdpTransport;
+pub use websocket::WebSocketTransport;
+pub use memory::{InMemoryTransport, Network};
+pub use tls::{TlsTransport, TlsConfig, TlsSession};
+pub use padding::PaddedTransport;
+pub use pcap::{CaptureLimit, FrameCount, Duration};
+pub use merge::{MetadataMerge, VersionVector};
+pub use detector::{FailureDetector, Timeouts, PhiAccrual};
+pub use codec::{Codec, JsonCodec, MsgPackCodec, WireBroadcast, PROTOCOL_VERSION};
+pub use protobuf::ProtobufCodec;
+pub use pressure::{PressureLevel, Low, Elevated, High};
+pub use transport::{TransportKind, Tcp, Udp, WebSocket};
+
+#[cfg(feature = "threaded")]
+pub use threaded::Threaded;
+#[cfg(feature = "threaded")]
+pub use sharded::{Sharded, MemberView};
+#[cfg(feature = "async")]
+pub use async::Async;
 
 mod result;
+mod config;
 mod stream;
 mod tag;
 mod state;
 mod protocol;
 mod broadcast;
+mod member;
+pub mod message;
+mod event;
+mod clock;
+mod quota;
+mod join;
+mod crc;
+mod framing;
+mod fragment;
+mod compress;
+mod mtu;
+mod snapshot;
+mod digest;
+mod stats;
+mod retransmit;
+mod topic;
+mod breaker;
+mod eviction;
+mod namespace;
+mod subscriber;
+mod typed;
+mod gather;
+mod ack;
+pub mod engine;
+mod registry;
+mod discovery;
+mod coordinator;
+mod ring;
+mod trace;
+mod skew;
+mod socket;
+mod bandwidth;
+mod heartbeat;
+mod version;
+mod supervisor;
+mod cipher;
+mod crypto;
+mod teardown;
+mod budget;
+mod peers;
+pub mod cassette;
+pub mod dump;
+mod identity;
+mod journal;
+mod timeline;
+mod commit;
+mod wal;
+mod conformance;
+mod recent;
+mod latency;
+mod score;
+mod history;
+mod parent;
+mod plumtree;
+mod antientropy;
+mod schedule;
+mod merkle;
+mod swim;
+mod detector;
+mod preview;
+mod ops;
+mod admin;
+mod validate;
+mod transport;
+mod tcp;
+mod udp;
+mod websocket;
+mod standby;
+mod memory;
+mod tls;
+mod padding;
+mod backoff;
+mod resolve;
+mod codec;
+mod protobuf;
+mod features;
+mod pressure;
+mod bootstrap;
+mod merge;
+mod pcap;
+mod driver;
+
+#[cfg(feature = "threaded")]
+mod threaded;
+#[cfg(feature = "threaded")]
+mod sharded;
+#[cfg(feature = "async")]
+mod async;
+#[cfg(feature = "paranoid-checks")]
+mod invariants;
+#[cfg(test)]
+mod compat;
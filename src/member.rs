@@ -0,0 +1,291 @@
+//! Membership bookkeeping for the other nodes within the cluster.
+
+use std::collections::HashMap;
+use std::collections::hashmap::Values;
+use uuid::Uuid;
+
+use protocol::Peer;
+use stream::SockAddr;
+use topic::Capabilities;
+use merge::VersionVector;
+
+/// The metadata key members advertise `false` under when they don't relay
+/// broadcasts for others (i.e., devices on a metered connection). They stay
+/// leaves of the broadcast tree: they receive and originate broadcasts, but
+/// are never anybody's parent.
+pub static RELAY_KEY: &'static str = "relay";
+
+/// Whether the metadata leaves the member relaying broadcasts.
+pub fn relays(metadata: &HashMap<String, String>) -> bool {
+    metadata.find_equiv(&RELAY_KEY).map(|r| r.as_slice()) != Some("false")
+}
+
+/// The metadata key members advertise `true` under while they're cordoned
+/// (see `Node::cordon`). Peers keep them in their lazy set and never graft
+/// them, so no new payloads are routed through them.
+pub static CORDON_KEY: &'static str = "cordoned";
+
+pub fn cordoned(metadata: &HashMap<String, String>) -> bool {
+    metadata.find_equiv(&CORDON_KEY).map(|c| c.as_slice()) == Some("true")
+}
+
+/// Liveness of a member as far as the local node can tell.
+#[deriving(Show, Clone, PartialEq, Eq, Encodable, Decodable)]
+pub enum MemberState {
+    Alive,
+    Suspect,
+    Dead
+}
+
+impl MemberState {
+    /// How bad the state is, which decides between changes at the same
+    /// incarnation (see `LivenessAt`).
+    pub fn severity(&self) -> uint {
+        match *self {
+            Alive => 0,
+            Suspect => 1,
+            Dead => 2
+        }
+    }
+}
+
+/// The operational status a node advertises about itself. Unlike
+/// `MemberState`, this is decided by the node (or it's operator) and
+/// not by the failure detector.
+#[deriving(Show, Clone, PartialEq, Eq, Encodable, Decodable)]
+pub enum Status {
+    /// Business as usual.
+    Active,
+    /// Just joined, still pulling the bootstrap-critical topics (see
+    /// `Config::bootstrap_topics`). Not counted toward quorum yet.
+    Bootstrapping,
+    /// The node is about to be restarted. It still acks probes and
+    /// relays broadcasts, but peers stop counting it toward quorum
+    /// and applications should migrate work off of it.
+    Draining
+}
+
+/// A single member of the cluster along with everything we currently
+/// know about it.
+#[deriving(Show, Clone)]
+pub struct Member {
+    pub peer: Peer,
+    pub state: MemberState,
+    pub status: Status,
+    /// What the member advertised it can understand on the wire.
+    pub capabilities: Capabilities,
+    /// Free-form key/values the member advertised about itself, i.e., it's
+    /// `weight` on the hash ring.
+    pub metadata: HashMap<String, String>,
+    pub metadata_version: VersionVector,
+    /// Bumped by the member every time it moves to a new address, so stale
+    /// addresses never win over newer ones.
+    pub incarnation: u64
+}
+
+impl Member {
+    pub fn new(peer: Peer) -> Member {
+        Member {
+            peer: peer,
+            state: Alive,
+            status: Active,
+            capabilities: Capabilities::baseline(),
+            metadata: HashMap::new(),
+            metadata_version: VersionVector::new(),
+            incarnation: 0
+        }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.peer.id()
+    }
+
+    /// Whether this member should be counted when figuring out if the
+    /// cluster still has a quorum. Draining members are alive, but
+    /// they're on their way out, so we don't rely on them.
+    pub fn is_voting(&self) -> bool {
+        self.state == Alive && self.status == Active
+    }
+
+    /// Whether the member relays broadcasts for others, see `RELAY_KEY`.
+    pub fn relays(&self) -> bool {
+        relays(&self.metadata)
+    }
+
+    /// See `CORDON_KEY`.
+    pub fn is_cordoned(&self) -> bool {
+        cordoned(&self.metadata)
+    }
+}
+
+/// The set of other members within the cluster, keyed by their id.
+pub struct Membership {
+    members: HashMap<Uuid, Member>
+}
+
+impl Membership {
+    pub fn new() -> Membership {
+        Membership {
+            members: HashMap::new()
+        }
+    }
+
+    pub fn len(&self) -> uint {
+        self.members.len()
+    }
+
+    /// Add a new peer to the membership. Returns `false` if the peer
+    /// was already known.
+    pub fn insert(&mut self, peer: Peer) -> bool {
+        let id = peer.id();
+        if self.members.contains_key(&id) {
+            return false;
+        }
+
+        self.members.insert(id, Member::new(peer))
+    }
+
+    pub fn find<'a>(&'a self, id: &Uuid) -> Option<&'a Member> {
+        self.members.find(id)
+    }
+
+    pub fn iter<'a>(&'a self) -> Values<'a, Uuid, Member> {
+        self.members.values()
+    }
+
+    /// Update the advertised status of a member. Returns `true` only if
+    /// the member is known and the status actually changed.
+    pub fn set_status(&mut self, id: &Uuid, status: Status) -> bool {
+        match self.members.find_mut(id) {
+            Some(member) => {
+                if member.status == status {
+                    false
+                } else {
+                    member.status = status;
+                    true
+                }
+            },
+            None => false
+        }
+    }
+
+    pub fn set_capabilities(&mut self, id: &Uuid, capabilities: Capabilities) {
+        match self.members.find_mut(id) {
+            Some(member) => member.capabilities = capabilities,
+            None => {}
+        }
+    }
+
+    /// Replace the metadata of a member. Returns `true` only if the member
+    /// is known and the metadata actually changed.
+    pub fn set_metadata(&mut self, id: &Uuid, metadata: HashMap<String, String>,
+                        version: VersionVector) -> bool {
+        match self.members.find_mut(id) {
+            Some(member) => {
+                member.metadata_version = version;
+                if member.metadata == metadata {
+                    false
+                } else {
+                    member.metadata = metadata;
+                    true
+                }
+            },
+            None => false
+        }
+    }
+
+    /// Switch a member over to a new address. With an incarnation, the move
+    /// is only applied if it's newer than what we know of; without one it's
+    /// applied regardless (i.e., the member itself asked to join again).
+    /// Returns `true` only if the address actually changed.
+    pub fn relocate(&mut self, id: &Uuid, addr: &SockAddr, incarnation: Option<u64>) -> bool {
+        match self.members.find_mut(id) {
+            Some(member) => {
+                match incarnation {
+                    Some(incarnation) if incarnation <= member.incarnation => return false,
+                    Some(incarnation) => member.incarnation = incarnation,
+                    None => {}
+                }
+
+                if member.peer.addr() == addr {
+                    false
+                } else {
+                    member.peer = Peer::new(*id, addr.ip.as_slice(), addr.port);
+                    true
+                }
+            },
+            None => false
+        }
+    }
+
+    /// Update the liveness of a member. Returns `true` only if the member
+    /// is known and the state actually changed.
+    pub fn set_state(&mut self, id: &Uuid, state: MemberState) -> bool {
+        match self.members.find_mut(id) {
+            Some(member) => {
+                if member.state == state {
+                    false
+                } else {
+                    member.state = state;
+                    true
+                }
+            },
+            None => false
+        }
+    }
+
+    /// Catch up with a newer incarnation of a member. Returns `false` for
+    /// unknown members.
+    pub fn set_incarnation(&mut self, id: &Uuid, incarnation: u64) -> bool {
+        match self.members.find_mut(id) {
+            Some(member) => {
+                member.incarnation = incarnation;
+                true
+            },
+            None => false
+        }
+    }
+
+    /// The number of members that count toward quorum.
+    pub fn voting(&self) -> uint {
+        self.iter().filter(|m| m.is_voting()).count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use protocol::Peer;
+    use stream::SockAddr;
+
+    #[test]
+    fn draining_members_do_not_vote() {
+        let mut members = Membership::new();
+        let peer = Peer::empty();
+        let id = peer.id();
+
+        assert!(members.insert(peer.clone()));
+        assert!(!members.insert(peer));
+        assert_eq!(members.voting(), 1);
+
+        assert!(members.set_status(&id, Draining));
+        assert!(!members.set_status(&id, Draining));
+        assert_eq!(members.voting(), 0);
+        assert_eq!(members.len(), 1);
+    }
+
+    #[test]
+    fn relocate_with_newer_incarnations() {
+        let mut members = Membership::new();
+        let peer = Peer::empty();
+        let id = peer.id();
+        let addr = SockAddr::new("10.0.0.2", 3444);
+        members.insert(peer);
+
+        assert!(members.relocate(&id, &addr, Some(2)));
+        assert!(!members.relocate(&id, &SockAddr::new("10.0.0.3", 3444), Some(1)));
+        assert!(!members.relocate(&id, &addr, Some(3)));
+        assert_eq!(members.find(&id).unwrap().incarnation, 3);
+        assert_eq!(members.find(&id).unwrap().peer.addr(), &addr);
+    }
+}
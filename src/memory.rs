@@ -0,0 +1,314 @@
+//! A transport over channels, for spinning up dozens of nodes within a
+//! single test process without touching a socket. Transports find each
+//! other through the `Network` they're created on: listening registers the
+//! address there, connecting looks it up. The network can also be cut in
+//! between addresses (see `Network::partition`), frames across the cut are
+//! silently dropped.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Timer;
+use std::sync::{Arc, Mutex};
+
+use stream::SockAddr;
+use result::{GossipResult, GossipError, NodeUnreachable, UnknownError, io_err};
+use transport::{Transport, TransportEvent, Connection, Accepted, Frame, Closed};
+
+/// A connection as seen from the other side: the transport and it's
+/// connection.
+type Remote = (u64, u64);
+
+enum Packet {
+    /// Someone connected, from their address, and that's where to reply.
+    Open(Remote, SockAddr, Sender<Packet>),
+    /// Our connection (the second) was accepted.
+    Accept(Remote, u64),
+    Data(Remote, Vec<u8>),
+    HangUp(Remote)
+}
+
+struct Registry {
+    listeners: HashMap<SockAddr, Sender<Packet>>,
+    /// Both directions of every partition.
+    cut: HashSet<(SockAddr, SockAddr)>,
+    next_id: u64,
+    next_port: u16
+}
+
+/// Where the in-memory transports meet. Clones share the same network.
+#[deriving(Clone)]
+pub struct Network {
+    registry: Arc<Mutex<Registry>>
+}
+
+impl Network {
+    pub fn new() -> Network {
+        Network {
+            registry: Arc::new(Mutex::new(Registry {
+                listeners: HashMap::new(),
+                cut: HashSet::new(),
+                next_id: 0,
+                next_port: 10_000
+            }))
+        }
+    }
+
+    /// Drop every frame between the two addresses, both ways, until
+    /// `heal`.
+    pub fn partition(&self, a: &SockAddr, b: &SockAddr) {
+        let mut registry = self.registry.lock();
+        registry.cut.insert((a.clone(), b.clone()));
+        registry.cut.insert((b.clone(), a.clone()));
+    }
+
+    pub fn heal(&self) {
+        self.registry.lock().cut.clear();
+    }
+
+    fn is_cut(&self, from: &SockAddr, to: &SockAddr) -> bool {
+        self.registry.lock().cut.contains(&(from.clone(), to.clone()))
+    }
+}
+
+struct Link {
+    addr: SockAddr,
+    tx: Sender<Packet>
+}
+
+pub struct InMemoryTransport {
+    network: Network,
+    id: u64,
+    /// Where the others reach us, made up until we `listen`.
+    addr: SockAddr,
+    listening: bool,
+    links: HashMap<Connection, Link>,
+    /// Our connection for each of the remote ones.
+    remotes: HashMap<Remote, Connection>,
+    next: u64,
+    tx: Sender<Packet>,
+    rx: Receiver<Packet>
+}
+
+impl InMemoryTransport {
+    pub fn new(network: &Network) -> InMemoryTransport {
+        let (tx, rx) = channel();
+        let (id, port) = {
+            let mut registry = network.registry.lock();
+            registry.next_id += 1;
+            registry.next_port += 1;
+            (registry.next_id, registry.next_port)
+        };
+
+        InMemoryTransport {
+            network: network.clone(),
+            id: id,
+            addr: SockAddr::new("memory", port),
+            listening: false,
+            links: HashMap::new(),
+            remotes: HashMap::new(),
+            next: 0,
+            tx: tx,
+            rx: rx
+        }
+    }
+
+    fn link(&mut self, remote: Remote, addr: SockAddr, tx: Sender<Packet>) -> Connection {
+        let conn = Connection(self.next);
+        self.next += 1;
+        self.links.insert(conn, Link { addr: addr, tx: tx });
+        self.remotes.insert(remote, conn);
+        conn
+    }
+
+    fn packet(&mut self, packet: Packet) -> Option<TransportEvent> {
+        match packet {
+            Open(remote, addr, tx) => {
+                let conn = self.link(remote, addr.clone(), tx.clone());
+                let Connection(ours) = conn;
+                let _ = tx.send_opt(Accept((self.id, ours), remote.val1()));
+                Some(Accepted(conn, addr))
+            },
+            Accept(remote, dialed) => {
+                self.remotes.insert(remote, Connection(dialed));
+                None
+            },
+            Data(remote, frame) => {
+                self.remotes.find_copy(&remote).map(|conn| Frame(conn, frame))
+            },
+            HangUp(remote) => {
+                self.remotes.pop(&remote).map(|conn| {
+                    self.links.remove(&conn);
+                    Closed(conn)
+                })
+            }
+        }
+    }
+}
+
+impl Transport for InMemoryTransport {
+    fn listen(&mut self, addr: &SockAddr) -> GossipResult<SockAddr> {
+        let mut registry = self.network.registry.lock();
+
+        let addr = if addr.port == 0 {
+            registry.next_port += 1;
+            SockAddr::new(addr.ip.as_slice(), registry.next_port)
+        } else {
+            addr.clone()
+        };
+        if registry.listeners.contains_key(&addr) {
+            return Err(GossipError::new("the address is taken", UnknownError));
+        }
+
+        registry.listeners.insert(addr.clone(), self.tx.clone());
+        self.addr = addr.clone();
+        self.listening = true;
+        Ok(addr)
+    }
+
+    fn connect(&mut self, addr: &SockAddr) -> GossipResult<Connection> {
+        let tx = match self.network.registry.lock().listeners.find(addr) {
+            Some(tx) => tx.clone(),
+            None => return Err(GossipError::new("nobody listens there", NodeUnreachable))
+        };
+
+        let conn = Connection(self.next);
+        self.next += 1;
+        let Connection(ours) = conn;
+        if tx.send_opt(Open((self.id, ours), self.addr.clone(), self.tx.clone())).is_err() {
+            return Err(GossipError::new("nobody listens there", NodeUnreachable));
+        }
+
+        self.links.insert(conn, Link { addr: addr.clone(), tx: tx });
+        Ok(conn)
+    }
+
+    fn send(&mut self, conn: &Connection, frame: &[u8]) -> GossipResult<()> {
+        let link = match self.links.find(conn) {
+            Some(link) => link,
+            None => return Err(GossipError::new("the connection is closed", NodeUnreachable))
+        };
+
+        if self.network.is_cut(&self.addr, &link.addr) {
+            return Ok(());
+        }
+
+        let Connection(ours) = *conn;
+        match link.tx.send_opt(Data((self.id, ours), frame.to_vec())) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(GossipError::new("the other side is gone", NodeUnreachable))
+        }
+    }
+
+    fn recv(&mut self, timeout: u64) -> GossipResult<Option<TransportEvent>> {
+        let mut timer = try!(Timer::new().map_err(io_err));
+        let expired = timer.oneshot(timeout);
+
+        loop {
+            let packet = {
+                let rx = &self.rx;
+                select! {
+                    packet = rx.recv_opt() => packet.ok(),
+                    () = expired.recv() => None
+                }
+            };
+
+            match packet {
+                Some(packet) => match self.packet(packet) {
+                    Some(event) => return Ok(Some(event)),
+                    None => {}
+                },
+                None => return Ok(None)
+            }
+        }
+    }
+
+    fn close(&mut self, conn: &Connection) {
+        match self.links.pop(conn) {
+            Some(link) => {
+                let Connection(ours) = *conn;
+                let _ = link.tx.send_opt(HangUp((self.id, ours)));
+            },
+            None => {}
+        }
+
+        let remotes: Vec<Remote> = self.remotes.iter()
+                                       .filter(|&(_, c)| c == conn)
+                                       .map(|(remote, _)| *remote)
+                                       .collect();
+        for remote in remotes.iter() {
+            self.remotes.remove(remote);
+        }
+    }
+
+    fn shutdown(&mut self) {
+        if self.listening {
+            self.network.registry.lock().listeners.remove(&self.addr);
+            self.listening = false;
+        }
+
+        let conns: Vec<Connection> = self.links.keys().map(|conn| *conn).collect();
+        for conn in conns.iter() {
+            self.close(conn);
+        }
+    }
+}
+
+impl Drop for InMemoryTransport {
+    fn drop(&mut self) {
+        if self.listening {
+            self.network.registry.lock().listeners.remove(&self.addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use stream::SockAddr;
+    use transport::{Transport, Connection, Accepted, Frame, Closed};
+
+    fn accept(transport: &mut InMemoryTransport) -> Connection {
+        match transport.recv(100).unwrap() {
+            Some(Accepted(conn, _)) => conn,
+            event => fail!("unexpected {}", event)
+        }
+    }
+
+    #[test]
+    fn talk_both_ways() {
+        let network = Network::new();
+        let (mut a, mut b) = (InMemoryTransport::new(&network), InMemoryTransport::new(&network));
+        let addr = a.listen(&SockAddr::new("memory", 0)).unwrap();
+        assert!(b.connect(&SockAddr::new("memory", 1)).is_err());
+
+        let dialed = b.connect(&addr).unwrap();
+        b.send(&dialed, &[1u8]).unwrap();
+        let accepted = accept(&mut a);
+        assert_eq!(a.recv(100).unwrap(), Some(Frame(accepted, vec![1u8])));
+
+        a.send(&accepted, &[2u8]).unwrap();
+        assert_eq!(b.recv(100).unwrap(), Some(Frame(dialed, vec![2u8])));
+
+        b.close(&dialed);
+        assert_eq!(a.recv(100).unwrap(), Some(Closed(accepted)));
+        assert_eq!(a.recv(10).unwrap(), None);
+    }
+
+    #[test]
+    fn drop_frames_across_partitions() {
+        let network = Network::new();
+        let (mut a, mut b) = (InMemoryTransport::new(&network), InMemoryTransport::new(&network));
+        let a_addr = a.listen(&SockAddr::new("memory", 0)).unwrap();
+        let b_addr = b.listen(&SockAddr::new("memory", 0)).unwrap();
+
+        let dialed = b.connect(&a_addr).unwrap();
+        let accepted = accept(&mut a);
+
+        network.partition(&a_addr, &b_addr);
+        b.send(&dialed, &[1u8]).unwrap();
+        assert_eq!(a.recv(10).unwrap(), None);
+
+        network.heal();
+        b.send(&dialed, &[2u8]).unwrap();
+        assert_eq!(a.recv(100).unwrap(), Some(Frame(accepted, vec![2u8])));
+    }
+}
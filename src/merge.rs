@@ -0,0 +1,95 @@
+//! Concurrent changes to a member's metadata. Metadata is versioned with a
+//! version vector of everyone who wrote it, so changes made without
+//! knowing of each other (i.e., by a primary and it's standby on both sides
+//! of a partition, see `standby`) are told apart from plain updates. Those
+//! are handed to the application's `MetadataMerge` rather than the last
+//! one we heard of silently winning.
+
+use std::cmp;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// How many changes each writer made to the metadata.
+#[deriving(Show, Clone, PartialEq, Encodable, Decodable)]
+pub struct VersionVector {
+    counters: HashMap<Uuid, u64>
+}
+
+impl VersionVector {
+    pub fn new() -> VersionVector {
+        VersionVector {
+            counters: HashMap::new()
+        }
+    }
+
+    /// The writer changed the metadata once more.
+    pub fn bump(&mut self, writer: Uuid) {
+        *self.counters.find_or_insert(writer, 0) += 1;
+    }
+
+    pub fn bumped(mut self, writer: Uuid) -> VersionVector {
+        self.bump(writer);
+        self
+    }
+
+    /// Every writer along with it's count, i.e., for codecs.
+    pub fn counters(&self) -> Vec<(Uuid, u64)> {
+        self.counters.iter().map(|(writer, count)| (*writer, *count)).collect()
+    }
+
+    pub fn from_counters(counters: Vec<(Uuid, u64)>) -> VersionVector {
+        VersionVector {
+            counters: counters.move_iter().collect()
+        }
+    }
+
+    /// Whether we know of every change the other one does. Neither
+    /// descending from the other means they're concurrent.
+    pub fn descends(&self, other: &VersionVector) -> bool {
+        other.counters.iter().all(|(writer, count)| {
+            self.counters.find(writer).map(|c| c >= count).unwrap_or(false)
+        })
+    }
+
+    /// Every change either of us knows of.
+    pub fn merged(&self, other: &VersionVector) -> VersionVector {
+        let mut merged = self.clone();
+        for (writer, count) in other.counters.iter() {
+            let current = merged.counters.find_or_insert(*writer, 0);
+            *current = cmp::max(*current, *count);
+        }
+        merged
+    }
+}
+
+/// Decides what concurrent changes to a member's metadata come down to, see
+/// `Node::set_metadata_merge`.
+pub trait MetadataMerge: Send {
+    /// Every member that held either version ends up merging them, so
+    /// which one is `ours` shouldn't matter to the outcome.
+    fn merge(&self, id: &Uuid, ours: &HashMap<String, String>,
+             theirs: &HashMap<String, String>) -> HashMap<String, String>;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn tell_concurrent_changes_apart() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let base = VersionVector::new().bumped(a);
+
+        let ours = base.clone().bumped(a);
+        assert!(ours.descends(&base));
+        assert!(!base.descends(&ours));
+
+        let theirs = base.clone().bumped(b);
+        assert!(!ours.descends(&theirs) && !theirs.descends(&ours));
+
+        let merged = ours.merged(&theirs);
+        assert!(merged.descends(&ours) && merged.descends(&theirs));
+        assert_eq!(merged, VersionVector::new().bumped(a).bumped(a).bumped(b));
+    }
+}
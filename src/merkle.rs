@@ -0,0 +1,114 @@
+//! A Merkle tree over the ids of the broadcasts we retain, for anti-entropy
+//! rounds with histories too large to list (past
+//! `Config::merkle_threshold`). Ids fall into one of `LEAVES` leaves by
+//! their leading bits, a leaf's hash being the XOR of the hashes of the ids
+//! in it and every other node's the hash of it's two children. Every member
+//! builds the same shape of tree, so two of them find where their histories
+//! differ by comparing nodes top-down (`TreeNodes`), descending only into
+//! those that differ, and end up exchanging the ids of the few leaves that
+//! do (`LeafIds`). That's `DEPTH` round trips, however long the history.
+
+use std::hash;
+use uuid::Uuid;
+
+pub static DEPTH: uint = 10;
+pub static LEAVES: uint = 1 << DEPTH;
+
+/// The leaf the broadcast falls into.
+pub fn leaf_of(id: &Uuid) -> u32 {
+    let bytes = id.as_bytes();
+    (bytes[0] as u32 << 8 | bytes[1] as u32) >> (16 - DEPTH)
+}
+
+fn combine(left: u64, right: u64) -> u64 {
+    if left == 0 && right == 0 {
+        0
+    } else {
+        hash::hash(&(left, right))
+    }
+}
+
+pub struct Merkle {
+    /// The hash of every node, level by level from the root down to the
+    /// leaves.
+    levels: Vec<Vec<u64>>
+}
+
+impl Merkle {
+    pub fn new() -> Merkle {
+        Merkle {
+            levels: Vec::from_fn(DEPTH + 1, |level| Vec::from_elem(1 << level, 0u64))
+        }
+    }
+
+    /// Add or remove (it's the same) an id, updating the hashes on it's way
+    /// to the root.
+    pub fn toggle(&mut self, id: &Uuid) {
+        let mut index = leaf_of(id) as uint;
+        *self.levels.get_mut(DEPTH).get_mut(index) ^= hash::hash(id);
+
+        for level in range(0, DEPTH).rev() {
+            index /= 2;
+            let hash = {
+                let below = self.levels.get(level + 1);
+                combine(*below.get(2 * index), *below.get(2 * index + 1))
+            };
+            *self.levels.get_mut(level).get_mut(index) = hash;
+        }
+    }
+
+    /// The hash of the node, `None` if there's no such node.
+    pub fn hash(&self, level: uint, index: u32) -> Option<u64> {
+        if level > DEPTH || index as uint >= 1 << level {
+            return None;
+        }
+        Some(*self.levels.get(level).get(index as uint))
+    }
+
+    pub fn root(&self) -> u64 {
+        *self.levels.get(0).get(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn hash_the_set_not_the_order() {
+        let ids = Vec::from_fn(100, |_| Uuid::new_v4());
+        let (mut a, mut b) = (Merkle::new(), Merkle::new());
+        for id in ids.iter() {
+            a.toggle(id);
+        }
+        for id in ids.iter().rev() {
+            b.toggle(id);
+        }
+        assert!(a.root() != 0);
+        assert_eq!(a.root(), b.root());
+
+        // Removing puts it back the way it was.
+        let extra = Uuid::new_v4();
+        a.toggle(&extra);
+        assert!(a.root() != b.root());
+        a.toggle(&extra);
+        assert_eq!(a.root(), b.root());
+    }
+
+    #[test]
+    fn differ_only_along_the_path() {
+        let (mut a, b) = (Merkle::new(), Merkle::new());
+        let id = Uuid::new_v4();
+        a.toggle(&id);
+
+        let leaf = leaf_of(&id);
+        for level in range(0, DEPTH + 1) {
+            let index = leaf >> (DEPTH - level);
+            assert!(a.hash(level, index) != b.hash(level, index));
+            assert_eq!(a.hash(level, index ^ 1), b.hash(level, index ^ 1));
+        }
+        assert_eq!(a.hash(DEPTH + 1, 0), None);
+        assert_eq!(a.hash(1, 2), None);
+    }
+}
@@ -0,0 +1,161 @@
+//! Messages exchanged between nodes as part of the protocol itself, as
+//! opposed to application broadcasts.
+
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use member::{Status, MemberState};
+use protocol::Peer;
+use topic::Capabilities;
+use registry::Service;
+use teardown::ShutdownPhase;
+use preview::ClusterInfo;
+use merge::VersionVector;
+use codec::WireBroadcast;
+use pressure::PressureLevel;
+
+#[deriving(Show, Clone, PartialEq, Encodable, Decodable)]
+pub enum Message {
+    /// A node announcing a change of it's own operational status.
+    StatusChange(Uuid, Status),
+    /// Sent by a relay (the id) to the origin of a broadcast it dropped
+    /// because the origin went over it's rate quota.
+    QuotaExceeded(Uuid),
+    /// A new node asking to join the cluster.
+    JoinRequest(Peer),
+    /// An encoded `Snapshot` of the full state sent to a joiner by the node
+    /// (the id) that handled it's join request.
+    JoinSync(Uuid, Vec<u8>),
+    /// A joiner acknowledging that it applied the full state sync.
+    JoinSynced(Uuid),
+    /// A batch of membership changes that should be applied as a whole.
+    Digest(Vec<Update>),
+    /// A node (the id) advertising what it understands on the wire.
+    Advertise(Uuid, Capabilities),
+    /// A node (the first id) asking for another node (the second id) to be
+    /// evicted from the cluster. Nodes before `KillNodeAt` optionally sent
+    /// a proof made with the admin key, which isn't bound to a time and is
+    /// taken for a plain vote.
+    KillNode(Uuid, Uuid, Option<u64>),
+    /// A node (the id) advertising every service it currently runs.
+    Services(Uuid, Vec<Service>),
+    /// A node (the id) advertising all of it's metadata, at the version.
+    Metadata(Uuid, HashMap<String, String>, VersionVector),
+    /// The epoch of the sender's hash ring.
+    RingEpoch(u64),
+    /// A node (the id) beating with the application's payload, and how much
+    /// resource pressure it's host is under.
+    Heartbeat(Uuid, Vec<u8>, PressureLevel),
+    /// An admin asking every member (with the role) to shut down, through
+    /// the initiator (the id). Carries when it was issued (the initiator's
+    /// wall clock) and the admin key's signature.
+    ShutdownCluster(Uuid, Option<String>, u64, Vec<u8>),
+    /// A member (the id) reporting back to the shutdown's initiator.
+    ShutdownReport(Uuid, ShutdownPhase),
+    /// A node (the id) looking at the cluster without joining it.
+    PreviewRequest(Uuid),
+    /// What the seed told the node looking at it's cluster.
+    Preview(ClusterInfo),
+    /// A joiner (the id) asking for the latest broadcast of each of the
+    /// topics, see `bootstrap`.
+    BootstrapRequest(Uuid, Vec<String>),
+    /// The encoded broadcasts the member (the id) retains of them. Only the
+    /// payloads, older members still reply with it.
+    BootstrapReply(Uuid, Vec<Vec<u8>>),
+    /// An admin flipping the named toggle (on or off) for every member,
+    /// through the initiator (the id). Carries when it was issued and the
+    /// admin key's signature, see `features`.
+    FeatureToggle(Uuid, String, bool, u64, Vec<u8>),
+    /// A member (the id) acking the command issued at the time.
+    FeatureAck(Uuid, String, u64),
+    /// A member (the id) announcing the broadcasts it got lately to us, one
+    /// of it's lazy peers, see `plumtree`.
+    IHave(Uuid, Vec<Uuid>),
+    /// A member (the id) taking us into it's eager peers, and asking for
+    /// the broadcasts it missed (if any).
+    Graft(Uuid, Vec<Uuid>),
+    /// A member (the id) we sent a duplicate to, moving us to it's lazy
+    /// peers.
+    Prune(Uuid),
+    /// A member (the id) telling what it knows in brief: every member with
+    /// it's incarnation and liveness, and the broadcasts it retains. Asking
+    /// for ours in return unless it's a reply, see `antientropy`.
+    SyncDigest(Uuid, Vec<(Uuid, u64, MemberState)>, Vec<Uuid>, bool),
+    /// A member (the id) telling the hashes of some nodes at a level of the
+    /// tree over the broadcasts it retains, by their index, see `merkle`.
+    TreeNodes(Uuid, u32, Vec<(u32, u64)>),
+    /// A member (the id) telling the broadcasts it retains in those leaves
+    /// of the tree. Asking for ours in return unless it's a reply.
+    LeafIds(Uuid, Vec<u32>, Vec<Uuid>, bool),
+    /// A member (the id) probing us, see `swim`. Acked under the sequence
+    /// number.
+    Ping(Uuid, u64),
+    /// A member (the first id) asking us to probe another one for it.
+    PingReq(Uuid, Uuid, u64),
+    /// A member (the id) answering a probe, relayed or not.
+    Ack(Uuid, u64),
+    /// A new node asking to join the cluster with the name, speaking the
+    /// protocol version (see `PROTOCOL_VERSION`). Nodes that predate it
+    /// send a `JoinRequest` instead.
+    Join(Peer, String, u32),
+    /// A member (the id) turning our `Join` down, and why.
+    JoinRejected(Uuid, String),
+    /// A member (the id) leaving the cluster on purpose, as nodes before
+    /// `Departed` told it.
+    Leave(Uuid),
+    /// A member (the id) acking our `Departed` (or `Leave`).
+    LeaveAck(Uuid),
+    /// A member (the id) handing us one of it's broadcasts, for us only.
+    /// It's delivered, but never relayed any further.
+    Direct(Uuid, WireBroadcast),
+    /// Like `KillNode`, with the proof made with the admin key and when (the
+    /// first node's wall clock) it was issued, see `AdminKey::prove`.
+    KillNodeAt(Uuid, Uuid, u64, Vec<u8>),
+    /// Like `BootstrapReply`, with the whole broadcasts (their id, origin
+    /// and namespace included) so the joiner doesn't take them for new ones.
+    BootstrapBroadcasts(Uuid, Vec<WireBroadcast>)
+}
+
+/// A single change to the membership.
+#[deriving(Show, Clone, PartialEq, Encodable, Decodable)]
+pub enum Update {
+    Joined(Peer),
+    StatusUpdate(Uuid, Status),
+    /// A member's liveness as nodes before `LivenessAt` sent it, taken to be
+    /// as of incarnation 0.
+    Liveness(Uuid, MemberState),
+    /// A member's liveness as of it's incarnation. At a higher incarnation
+    /// it always wins, at the same one only a worse state does (suspect
+    /// over alive, dead over both), see `Engine::liveness`.
+    LivenessAt(Uuid, MemberState, u64),
+    /// A member moved to a new address. Only applied when the incarnation
+    /// is higher than the one we know of.
+    Moved(Peer, u64),
+    /// A member suspected (or taken for dead) refuting it, alive at the
+    /// incarnation (and the address). Only applied when the incarnation is
+    /// higher than the one we know of.
+    Refuted(Peer, u64),
+    /// A member leaving the cluster on purpose as of it's incarnation, see
+    /// `Node::leave`. Only taken from the member itself.
+    Departed(Uuid, u64)
+}
+
+impl Message {
+    /// Who sent the message, as far as it says. Acks don't say: they're
+    /// passed on to whoever asked for the probe, see `swim`.
+    pub fn sender(&self) -> Option<Uuid> {
+        match *self {
+            StatusChange(id, _) | QuotaExceeded(id) | JoinSync(id, _) | JoinSynced(id) |
+            Advertise(id, _) | KillNode(id, _, _) | Services(id, _) | Metadata(id, _, _) |
+            Heartbeat(id, _, _) | ShutdownCluster(id, _, _, _) | ShutdownReport(id, _) |
+            PreviewRequest(id) | BootstrapRequest(id, _) | BootstrapReply(id, _) |
+            FeatureToggle(id, _, _, _, _) | FeatureAck(id, _, _) | IHave(id, _) | Graft(id, _) |
+            Prune(id) | SyncDigest(id, _, _, _) | TreeNodes(id, _, _) | LeafIds(id, _, _, _) |
+            Ping(id, _) | PingReq(id, _, _) | JoinRejected(id, _) | Leave(id) |
+            LeaveAck(id) | Direct(id, _) | KillNodeAt(id, _, _, _) |
+            BootstrapBroadcasts(id, _) => Some(id),
+            JoinRequest(ref peer) | Join(ref peer, _, _) => Some(peer.id()),
+            Digest(_) | RingEpoch(_) | Preview(_) | Ack(_, _) => None
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! Path MTU discovery for `UdpTransport`, per peer. Datagrams start out at
+//! `Config::udp_mtu` (what every path is assumed to take) and grow up to
+//! `Config::udp_max_mtu` as probes of larger sizes make it through: a
+//! probe is a datagram padded to the size, acked by the peer only if it
+//! arrived whole. The search bisects between the largest size known to
+//! work and the smallest known not to, after trying the largest.
+//!
+//! Nothing relies on ICMP making it back to us. A probe that isn't acked
+//! after a few tries is taken as too large, and a path that stops taking
+//! the size we settled on (i.e., a route change onto a tunnel that drops
+//! large datagrams silently, a blackhole) falls back to the base size and
+//! searches again from there. Settled paths are probed again every
+//! `Config::udp_mtu_probe_interval`, in case they got larger.
+//!
+//! Only peers that finished the handshake are probed (or get their probes
+//! acked), see `udp`.
+
+/// How many times a probe is sent before it's size is taken as too large.
+static MAX_PROBES: uint = 3;
+
+/// Stop searching once within this many bytes of the answer.
+static STEP: uint = 32;
+
+struct Probe {
+    size: uint,
+    sent: u64,
+    attempts: uint
+}
+
+pub struct PathMtu {
+    base: uint,
+    max: uint,
+    /// How long (in milliseconds) to wait on a probe's ack.
+    timeout: u64,
+    /// How long to stay settled before probing for more again.
+    interval: u64,
+    /// The largest size known to make it through.
+    current: uint,
+    /// The smallest size known not to, past `max` while there's none.
+    ceiling: uint,
+    probe: Option<Probe>,
+    next_probe: u64
+}
+
+impl PathMtu {
+    pub fn new(base: uint, max: uint, timeout: u64, interval: u64, now: u64) -> PathMtu {
+        PathMtu {
+            base: base,
+            max: max,
+            timeout: timeout,
+            interval: interval,
+            current: base,
+            ceiling: max + 1,
+            probe: None,
+            next_probe: now
+        }
+    }
+
+    /// The largest datagram to send for now.
+    pub fn current(&self) -> uint {
+        self.current
+    }
+
+    /// The size of the probe to send now, if any. Unacked probes are sent
+    /// again once they time out.
+    pub fn probe(&mut self, now: u64) -> Option<uint> {
+        match self.probe.take() {
+            Some(mut probe) => {
+                if now < probe.sent + self.timeout {
+                    self.probe = Some(probe);
+                    return None;
+                }
+                if probe.attempts < MAX_PROBES {
+                    probe.attempts += 1;
+                    probe.sent = now;
+                    let size = probe.size;
+                    self.probe = Some(probe);
+                    return Some(size);
+                }
+                // Never made it, the path is smaller than that.
+                self.ceiling = probe.size;
+            },
+            None => if now < self.next_probe {
+                return None;
+            }
+        }
+
+        if self.ceiling <= self.current + STEP {
+            // Settled, until it's time to look for a larger path again.
+            self.ceiling = self.max + 1;
+            self.next_probe = now + self.interval;
+            return None;
+        }
+
+        // Straight for the largest first, most paths either take it or are
+        // the base size anyway.
+        let size = if self.ceiling > self.max {
+            self.max
+        } else {
+            self.current + (self.ceiling - self.current) / 2
+        };
+        self.probe = Some(Probe { size: size, sent: now, attempts: 1 });
+        Some(size)
+    }
+
+    /// The peer acked the probe of the size.
+    pub fn acked(&mut self, size: uint, now: u64) {
+        let probed = self.probe.as_ref().map_or(false, |probe| probe.size == size);
+        if !probed {
+            return;
+        }
+
+        self.current = size;
+        self.probe = None;
+        self.next_probe = now;
+    }
+
+    /// Datagrams of the current size stopped making it through. Falls back
+    /// to the base size (true) unless that's what it is already.
+    pub fn blackhole(&mut self, now: u64) -> bool {
+        if self.current <= self.base {
+            return false;
+        }
+
+        self.ceiling = self.current;
+        self.current = self.base;
+        self.probe = None;
+        self.next_probe = now;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Probe a path that takes up to `limit` bytes until it settles.
+    fn settle(path: &mut PathMtu, limit: uint, mut now: u64) -> u64 {
+        loop {
+            match path.probe(now) {
+                Some(size) if size <= limit => path.acked(size, now),
+                Some(_) => {},
+                None if path.probe.is_none() => return now,
+                None => {}
+            }
+            now += 10;
+        }
+    }
+
+    #[test]
+    fn settle_just_below_the_path() {
+        let mut path = PathMtu::new(1_200, 9_000, 100, 60_000, 0);
+        let now = settle(&mut path, 1_500, 0);
+        assert!(path.current() <= 1_500 && path.current() > 1_500 - 32);
+
+        // Settled until the interval is up.
+        assert_eq!(path.probe(now + 10), None);
+        assert!(path.probe(now + 60_000).is_some());
+
+        let mut jumbo = PathMtu::new(1_200, 9_000, 100, 60_000, 0);
+        settle(&mut jumbo, 65_000, 0);
+        assert_eq!(jumbo.current(), 9_000);
+    }
+
+    #[test]
+    fn fall_back_on_blackholes() {
+        let mut path = PathMtu::new(1_200, 9_000, 100, 60_000, 0);
+        let now = settle(&mut path, 9_000, 0);
+        assert!(path.blackhole(now));
+        assert_eq!(path.current(), 1_200);
+        assert!(!path.blackhole(now));
+
+        // Searching again right away, under what stopped working.
+        settle(&mut path, 4_000, now);
+        assert!(path.current() <= 4_000 && path.current() > 4_000 - 32);
+    }
+}
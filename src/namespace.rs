@@ -0,0 +1,155 @@
+//! Tenant namespaces. A single cluster can serve several applications
+//! without cross-talk: every broadcast belongs to a namespace, and topics,
+//! rate quotas, publishing ACLs and local subscribers are all scoped to it.
+
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+use quota::{Quota, Quotas};
+
+/// Broadcasts that don't say otherwise belong to this namespace. It always
+/// exists, even when it isn't configured.
+pub static DEFAULT_NAMESPACE: &'static str = "default";
+
+#[deriving(Show, Clone)]
+pub struct Namespace {
+    /// The rate quota for every origin publishing within the namespace.
+    /// Falls back to the node-wide `origin_quota`.
+    pub quota: Option<Quota>,
+    /// The origins allowed to publish within the namespace. `None` lets
+    /// everybody publish.
+    pub publishers: Option<HashSet<Uuid>>
+}
+
+impl Namespace {
+    pub fn new() -> Namespace {
+        Namespace {
+            quota: None,
+            publishers: None
+        }
+    }
+
+    pub fn may_publish(&self, origin: &Uuid) -> bool {
+        match self.publishers {
+            Some(ref publishers) => publishers.contains(origin),
+            None => true
+        }
+    }
+}
+
+/// Qualify a topic with it's namespace so identically named topics of two
+/// tenants never collide.
+pub fn qualify(namespace: &str, topic: &str) -> String {
+    format!("{}/{}", namespace, topic)
+}
+
+/// Why a broadcast wasn't admitted into a namespace.
+#[deriving(Show, Clone, PartialEq)]
+pub enum Denial {
+    UnknownNamespace,
+    NotPermitted,
+    OverQuota
+}
+
+/// Every configured namespace along with the quota usage within each.
+pub struct Tenants {
+    namespaces: HashMap<String, Namespace>,
+    origin_quota: Option<Quota>,
+    origin_quotas: HashMap<Uuid, Quota>,
+    quotas: HashMap<String, Quotas>
+}
+
+impl Tenants {
+    pub fn new(namespaces: HashMap<String, Namespace>, origin_quota: Option<Quota>,
+               origin_quotas: HashMap<Uuid, Quota>) -> Tenants {
+        Tenants {
+            namespaces: namespaces,
+            origin_quota: origin_quota,
+            origin_quotas: origin_quotas,
+            quotas: HashMap::new()
+        }
+    }
+
+    fn namespace(&self, name: &str) -> Option<Namespace> {
+        match self.namespaces.find_equiv(&name) {
+            Some(namespace) => Some(namespace.clone()),
+            None if name == DEFAULT_NAMESPACE => Some(Namespace::new()),
+            None => None
+        }
+    }
+
+    /// Check whether `origin` may publish within the namespace at all.
+    pub fn permits(&self, name: &str, origin: &Uuid) -> Result<Namespace, Denial> {
+        match self.namespace(name) {
+            Some(namespace) => {
+                if namespace.may_publish(origin) {
+                    Ok(namespace)
+                } else {
+                    Err(NotPermitted)
+                }
+            },
+            None => Err(UnknownNamespace)
+        }
+    }
+
+    /// Check whether `origin` may publish a broadcast of `bytes` within the
+    /// namespace, accounting for it against the namespace's quota.
+    pub fn admit(&mut self, name: &str, origin: Uuid, bytes: uint,
+                 now: u64) -> Result<(), Denial> {
+        let namespace = try!(self.permits(name, &origin));
+
+        let key = name.to_string();
+        if !self.quotas.contains_key(&key) {
+            let quota = namespace.quota.or(self.origin_quota.clone());
+            self.quotas.insert(key.clone(), Quotas::new(quota, self.origin_quotas.clone()));
+        }
+
+        if self.quotas.find_mut(&key).unwrap().admit(origin, bytes, now) {
+            Ok(())
+        } else {
+            Err(OverQuota)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+    use uuid::Uuid;
+    use quota::Quota;
+
+    #[test]
+    fn default_namespace_always_exists() {
+        let mut tenants = Tenants::new(HashMap::new(), None, HashMap::new());
+        assert_eq!(tenants.admit(DEFAULT_NAMESPACE, Uuid::new_v4(), 10, 0), Ok(()));
+        assert_eq!(tenants.admit("billing", Uuid::new_v4(), 10, 0), Err(UnknownNamespace));
+    }
+
+    #[test]
+    fn quotas_and_acls_are_scoped() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let mut billing = Namespace::new();
+        billing.quota = Some(Quota::new(1, 1000));
+        let mut search = Namespace::new();
+        let mut publishers = HashSet::new();
+        publishers.insert(a);
+        search.publishers = Some(publishers);
+
+        let mut namespaces = HashMap::new();
+        namespaces.insert("billing".to_string(), billing);
+        namespaces.insert("search".to_string(), search);
+        let mut tenants = Tenants::new(namespaces, None, HashMap::new());
+
+        assert_eq!(tenants.admit("billing", a, 10, 0), Ok(()));
+        assert_eq!(tenants.admit("billing", a, 10, 0), Err(OverQuota));
+        assert_eq!(tenants.admit("search", a, 10, 0), Ok(()));
+        assert_eq!(tenants.admit("search", b, 10, 0), Err(NotPermitted));
+    }
+
+    #[test]
+    fn qualified_topics() {
+        assert_eq!(qualify("billing", "config"), "billing/config".to_string());
+    }
+}
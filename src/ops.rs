@@ -0,0 +1,33 @@
+//! The protocol operations in flight, for figuring out why a cluster seems
+//! stuck, see `Node::operations`. Times are on the node's monotonic clock
+//! (in milliseconds), compare them against `clock::now`.
+
+use uuid::Uuid;
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum Operation {
+    /// Serving a full-state sync to the joiner, giving up on it at the time.
+    SyncingJoiner(Uuid, u64),
+    /// A joiner waiting for a free sync slot.
+    QueuedJoiner(Uuid),
+    /// A ballot to evict the member, with that many votes so far. Closes at
+    /// the time.
+    EvictionBallot(Uuid, uint, u64),
+    /// The peer (the second id) outperforming our parent for the broadcasts
+    /// of the origin (the first id). It's grafted at the time unless the
+    /// parent catches up.
+    PendingGraft(Uuid, Uuid, u64),
+    /// The member is suspected, and taken for dead at the time unless it
+    /// refutes it.
+    SuspectMember(Uuid, u64),
+    /// A broadcast a lazy peer told us about but we haven't got. At the time
+    /// the peer is grafted for it, or we give up on it with nobody left to
+    /// graft.
+    MissingBroadcast(Uuid, Option<Uuid>, u64),
+    /// Taking part in the cluster-wide shutdown initiated by the member,
+    /// shutting down at the time.
+    ShuttingDown(Uuid, u64),
+    /// A broadcast delivered that many times to an acked subscriber of the
+    /// namespace, delivered again at the time unless it's acked.
+    Unacked(Uuid, String, uint, u64)
+}
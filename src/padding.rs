@@ -0,0 +1,239 @@
+//! Traffic-analysis resistance, for deployments where even the size and
+//! timing of our frames say too much. Every frame is padded up to the
+//! smallest of `Config::padding_buckets` it fits in (frames larger than all
+//! of them to a multiple of the largest), and with a
+//! `Config::chatter_interval` every connection also carries dummy frames
+//! at random-ish times so quiet periods don't stand out. The receiving side
+//! strips both before the node sees anything.
+//!
+//! Padding wraps whatever transport is in use (TLS included), so it's only
+//! worth it with encryption underneath: a passive observer then sees
+//! bucket-sized frames and nothing else.
+//!
+//! Format:
+//!
+//! ```notrust
+//! bitdata Padded {
+//!     Padded {
+//!         kind: u8, // 0 for frames, 1 for chatter
+//!         len: u32, // of the frame (big-endian)
+//!         frame: &[u8],
+//!         padding: &[u8] // zeroes, up to the bucket
+//!     }
+//! }
+//! ```
+
+use std::collections::{HashSet, RingBuf, Deque};
+use std::cmp;
+use std::io::BufReader;
+use std::rand::{task_rng, Rng};
+
+use clock;
+use stream::SockAddr;
+use result::GossipResult;
+use transport::{Transport, TransportEvent, Connection, Accepted, Frame, Closed};
+
+static HEADER: uint = 5;
+static DATA: u8 = 0;
+static CHATTER: u8 = 1;
+
+/// The size (header included) the frame is padded to.
+pub fn padded_size(buckets: &[uint], len: uint) -> uint {
+    let len = len + HEADER;
+    match buckets.iter().find(|bucket| **bucket >= len) {
+        Some(bucket) => *bucket,
+        None => match buckets.last() {
+            Some(&largest) if largest > 0 => (len + largest - 1) / largest * largest,
+            _ => len
+        }
+    }
+}
+
+pub fn pad(buckets: &[uint], kind: u8, frame: &[u8]) -> Vec<u8> {
+    let size = padded_size(buckets, frame.len());
+    let mut padded = Vec::with_capacity(size);
+    padded.push(kind);
+    let len = frame.len() as u32;
+    padded.push_all(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+    padded.push_all(frame);
+    padded.grow(size - HEADER - frame.len(), &0u8);
+    padded
+}
+
+/// The frame within, `None` for chatter and garbage.
+pub fn unpad(padded: &[u8]) -> Option<Vec<u8>> {
+    if padded.len() < HEADER || padded[0] != DATA {
+        return None;
+    }
+
+    let len = BufReader::new(padded.slice(1, HEADER)).read_be_u32().unwrap() as uint;
+    if padded.len() < HEADER + len {
+        return None;
+    }
+    Some(padded.slice(HEADER, HEADER + len).to_vec())
+}
+
+pub struct PaddedTransport<T> {
+    inner: T,
+    buckets: Vec<uint>,
+    chatter_interval: Option<u64>,
+    connections: HashSet<Connection>,
+    /// When the next chatter goes out.
+    next_chatter: u64,
+    /// Events waiting to be returned by `recv`.
+    events: RingBuf<TransportEvent>
+}
+
+impl<T: Transport> PaddedTransport<T> {
+    /// The buckets are sorted, smallest first.
+    pub fn new(inner: T, mut buckets: Vec<uint>,
+               chatter_interval: Option<u64>) -> PaddedTransport<T> {
+        buckets.sort();
+
+        let mut transport = PaddedTransport {
+            inner: inner,
+            buckets: buckets,
+            chatter_interval: chatter_interval,
+            connections: HashSet::new(),
+            next_chatter: 0,
+            events: RingBuf::new()
+        };
+        transport.schedule_chatter(clock::now());
+        transport
+    }
+
+    /// Somewhere within half an interval of it, so chatter doesn't tick
+    /// like a clock either.
+    fn schedule_chatter(&mut self, now: u64) {
+        match self.chatter_interval {
+            Some(interval) if interval > 0 => {
+                self.next_chatter = now + interval / 2 + task_rng().gen_range(0, interval + 1);
+            },
+            _ => {}
+        }
+    }
+
+    fn chatter(&mut self, now: u64) {
+        if self.chatter_interval.is_none() || now < self.next_chatter {
+            return;
+        }
+
+        let chatter = pad(self.buckets.as_slice(), CHATTER, &[]);
+        for conn in self.connections.iter() {
+            let _ = self.inner.send(conn, chatter.as_slice());
+        }
+        self.schedule_chatter(now);
+    }
+
+    fn event(&mut self, event: TransportEvent) {
+        match event {
+            Accepted(conn, addr) => {
+                self.connections.insert(conn);
+                self.events.push_back(Accepted(conn, addr));
+            },
+            Frame(conn, padded) => match unpad(padded.as_slice()) {
+                Some(frame) => self.events.push_back(Frame(conn, frame)),
+                None => {}
+            },
+            Closed(conn) => {
+                self.connections.remove(&conn);
+                self.events.push_back(Closed(conn));
+            }
+        }
+    }
+}
+
+impl<T: Transport> Transport for PaddedTransport<T> {
+    fn listen(&mut self, addr: &SockAddr) -> GossipResult<SockAddr> {
+        self.inner.listen(addr)
+    }
+
+    fn connect(&mut self, addr: &SockAddr) -> GossipResult<Connection> {
+        let conn = try!(self.inner.connect(addr));
+        self.connections.insert(conn);
+        Ok(conn)
+    }
+
+    fn send(&mut self, conn: &Connection, frame: &[u8]) -> GossipResult<()> {
+        self.inner.send(conn, pad(self.buckets.as_slice(), DATA, frame).as_slice())
+    }
+
+    fn recv(&mut self, timeout: u64) -> GossipResult<Option<TransportEvent>> {
+        let deadline = clock::now() + timeout;
+
+        loop {
+            let now = clock::now();
+            self.chatter(now);
+
+            match self.events.pop_front() {
+                Some(event) => return Ok(Some(event)),
+                None => {}
+            }
+            if now >= deadline {
+                return Ok(None);
+            }
+
+            // Wake up in time for the next chatter.
+            let mut wait = deadline - now;
+            if self.chatter_interval.is_some() && self.next_chatter > now {
+                wait = cmp::min(wait, self.next_chatter - now);
+            }
+            match try!(self.inner.recv(wait)) {
+                Some(event) => self.event(event),
+                None => {}
+            }
+        }
+    }
+
+    fn close(&mut self, conn: &Connection) {
+        self.connections.remove(conn);
+        self.inner.close(conn);
+    }
+
+    fn shutdown(&mut self) {
+        self.connections.clear();
+        self.inner.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use memory::{InMemoryTransport, Network};
+    use stream::SockAddr;
+    use transport::{Transport, Accepted, Frame};
+
+    #[test]
+    fn pad_to_buckets() {
+        let buckets: &[uint] = &[64u, 256];
+        assert_eq!(padded_size(buckets, 0), 64);
+        assert_eq!(padded_size(buckets, 59), 64);
+        assert_eq!(padded_size(buckets, 60), 256);
+        assert_eq!(padded_size(buckets, 600), 768);
+
+        let padded = pad(buckets, 0, &[1u8, 2, 3]);
+        assert_eq!(padded.len(), 64);
+        assert_eq!(unpad(padded.as_slice()), Some(vec![1u8, 2, 3]));
+        assert_eq!(unpad(pad(buckets, 1, &[]).as_slice()), None);
+    }
+
+    #[test]
+    fn strip_padding_and_chatter() {
+        let network = Network::new();
+        let mut a = PaddedTransport::new(InMemoryTransport::new(&network), vec![128], Some(10));
+        let mut b = PaddedTransport::new(InMemoryTransport::new(&network), vec![128], Some(10));
+        let addr = a.listen(&SockAddr::new("memory", 0)).unwrap();
+
+        let dialed = b.connect(&addr).unwrap();
+        let accepted = match a.recv(100).unwrap() {
+            Some(Accepted(conn, _)) => conn,
+            event => fail!("unexpected {}", event)
+        };
+
+        // Plenty of chatter goes by, only the frame shows up.
+        b.send(&dialed, &[7u8]).unwrap();
+        assert_eq!(b.recv(50).unwrap(), None);
+        assert_eq!(a.recv(100).unwrap(), Some(Frame(accepted, vec![7u8])));
+        assert_eq!(a.recv(50).unwrap(), None);
+    }
+}
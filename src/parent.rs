@@ -0,0 +1,302 @@
+//! Sticky eager parents. The first peer to relay us a broadcast from an
+//! origin becomes our parent for it. Another peer beating the parent only
+//! reshapes the tree once it's done so by at least `Config::parent_margin`
+//! milliseconds, every time, for `Config::parent_hysteresis` milliseconds.
+//! Without it two equally good peers keep trading places, and every trade
+//! costs a graft and a prune.
+//!
+//! A parent so far behind we have to graft somebody else for the origin's
+//! broadcasts might make no lead to measure. The peer answering our grafts
+//! takes over once it's done so `Config::graft_promotions` times within
+//! `Config::graft_promotion_window` instead, never on a single graft. Only
+//! grafts the parent actually lagged behind count: it's copy showed up
+//! later by the margin, or never within the hysteresis.
+
+use std::collections::HashMap;
+use uuid::Uuid;
+
+struct Parent {
+    current: Uuid,
+    /// The peer outperforming the parent, along with since when.
+    pending: Option<(Uuid, u64)>,
+    /// Who answered our grafts for the origin's broadcasts, and when.
+    grafts: Vec<(Uuid, u64)>
+}
+
+/// A broadcast that reached us through someone other than it's parent,
+/// waiting for the parent's copy.
+struct Arrival {
+    origin: Uuid,
+    /// Who was first, and when.
+    sender: Uuid,
+    at: u64,
+    /// Whether it was first answering our graft.
+    grafted: bool
+}
+
+pub struct Parents {
+    margin: u64,
+    hysteresis: u64,
+    /// How many grafts within how long (in milliseconds) promote a peer,
+    /// `None` for grafts never to.
+    promotions: Option<(uint, u64)>,
+    /// Per origin.
+    parents: HashMap<Uuid, Parent>,
+    /// Per broadcast id.
+    arrivals: HashMap<Uuid, Arrival>
+}
+
+impl Parents {
+    pub fn new(margin: u64, hysteresis: u64) -> Parents {
+        Parents {
+            margin: margin,
+            hysteresis: hysteresis,
+            promotions: None,
+            parents: HashMap::new(),
+            arrivals: HashMap::new()
+        }
+    }
+
+    /// Promote peers answering `grafts` of our grafts within the window
+    /// (in milliseconds).
+    pub fn promote_after(&mut self, grafts: uint, window: u64) {
+        self.promotions = Some((grafts, window));
+    }
+
+    pub fn parent(&self, origin: &Uuid) -> Option<Uuid> {
+        self.parents.find(origin).map(|parent| parent.current)
+    }
+
+    /// The peers outperforming a parent: the origin, the peer and when it
+    /// takes over unless the parent catches up.
+    pub fn pending(&self) -> Vec<(Uuid, Uuid, u64)> {
+        self.parents.iter().filter_map(|(origin, parent)| match parent.pending {
+            Some((candidate, since)) => Some((*origin, candidate, since + self.hysteresis)),
+            None => None
+        }).collect()
+    }
+
+    /// A copy of the broadcast `id` from the origin reached us through the
+    /// sender, the first one to if `first`. Returns the parent to demote and
+    /// the peer to promote in it's place once the tree should be reshaped.
+    pub fn arrived(&mut self, id: Uuid, origin: Uuid, sender: Uuid, first: bool,
+                   now: u64) -> Option<(Uuid, Uuid)> {
+        if first {
+            let parent = self.parents.find_or_insert_with(origin, |_| Parent {
+                current: sender,
+                pending: None,
+                grafts: Vec::new()
+            });
+
+            if parent.current == sender {
+                // The parent holds it's ground.
+                parent.pending = None;
+            } else {
+                self.arrivals.insert(id, Arrival {
+                    origin: origin,
+                    sender: sender,
+                    at: now,
+                    grafted: false
+                });
+            }
+
+            return None;
+        }
+
+        let from_parent = match self.arrivals.find(&id) {
+            Some(arrival) => self.parent(&arrival.origin) == Some(sender),
+            None => false
+        };
+        if !from_parent {
+            return None;
+        }
+
+        let arrival = self.arrivals.pop(&id).unwrap();
+        let lag = now - arrival.at;
+        if arrival.grafted && lag >= self.margin {
+            match self.answered(arrival.origin, arrival.sender, now) {
+                Some(reshaped) => return Some(reshaped),
+                None => {}
+            }
+        }
+        self.outperformed(arrival.origin, arrival.sender, lag, now)
+    }
+
+    /// The broadcast's first copy (see `arrived`) answered our graft for it.
+    /// Counts towards promoting whoever sent it once the parent turns out to
+    /// lag behind.
+    pub fn grafted(&mut self, id: &Uuid) {
+        match self.arrivals.find_mut(id) {
+            Some(arrival) => arrival.grafted = true,
+            None => {}
+        }
+    }
+
+    /// The peer answered our graft for a broadcast from the origin, and the
+    /// parent lagged behind. Returns the parent to demote and the peer to
+    /// promote once it answered enough of them within the window.
+    fn answered(&mut self, origin: Uuid, peer: Uuid, now: u64) -> Option<(Uuid, Uuid)> {
+        let (promotions, window) = match self.promotions {
+            Some(promotions) => promotions,
+            None => return None
+        };
+        let parent = match self.parents.find_mut(&origin) {
+            Some(parent) if parent.current != peer => parent,
+            _ => return None
+        };
+
+        parent.grafts.retain(|&(_, at)| at + window > now);
+        parent.grafts.push((peer, now));
+        if parent.grafts.iter().filter(|&&(id, _)| id == peer).count() < promotions {
+            return None;
+        }
+
+        let demoted = parent.current;
+        parent.current = peer;
+        parent.pending = None;
+        parent.grafts.clear();
+        Some((demoted, peer))
+    }
+
+    /// Forget the arrivals the parent's copy never showed up for (i.e., it
+    /// pruned us), older than the hysteresis. Those we grafted count as the
+    /// parent lagging behind, returns the parents to demote and the peers to
+    /// promote as in `arrived`.
+    pub fn expire(&mut self, now: u64) -> Vec<(Uuid, Uuid)> {
+        let hysteresis = self.hysteresis;
+        let stale: Vec<Uuid> = self.arrivals.iter()
+                                   .filter(|&(_, arrival)| arrival.at + hysteresis <= now)
+                                   .map(|(id, _)| *id)
+                                   .collect();
+
+        let mut reshaped = Vec::new();
+        for id in stale.iter() {
+            let arrival = self.arrivals.pop(id).unwrap();
+            if arrival.grafted {
+                match self.answered(arrival.origin, arrival.sender, now) {
+                    Some(change) => reshaped.push(change),
+                    None => {}
+                }
+            }
+        }
+        reshaped
+    }
+
+    /// Forget the peer wherever it's a parent, i.e., once it died.
+    pub fn forget(&mut self, peer: &Uuid) {
+        let orphans: Vec<Uuid> = self.parents.iter()
+                                     .filter(|&(_, parent)| parent.current == *peer)
+                                     .map(|(origin, _)| *origin)
+                                     .collect();
+
+        for origin in orphans.iter() {
+            self.parents.remove(origin);
+        }
+    }
+
+    /// The candidate beat the origin's parent by `lead` milliseconds.
+    fn outperformed(&mut self, origin: Uuid, candidate: Uuid, lead: u64,
+                    now: u64) -> Option<(Uuid, Uuid)> {
+        let parent = match self.parents.find_mut(&origin) {
+            Some(parent) => parent,
+            None => return None
+        };
+
+        if lead < self.margin {
+            parent.pending = None;
+            return None;
+        }
+
+        let since = match parent.pending {
+            Some((id, since)) if id == candidate => since,
+            _ => now
+        };
+
+        if now - since < self.hysteresis {
+            parent.pending = Some((candidate, since));
+            return None;
+        }
+
+        let demoted = parent.current;
+        parent.current = candidate;
+        parent.pending = None;
+        Some((demoted, candidate))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn outperform_for_the_hysteresis() {
+        let mut parents = Parents::new(20, 1_000);
+        let (origin, parent, candidate) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        // Whoever's first becomes the parent.
+        assert_eq!(parents.arrived(Uuid::new_v4(), origin, parent, true, 0), None);
+        assert_eq!(parents.parent(&origin), Some(parent));
+
+        let mut race = |lead: u64, at: u64| {
+            let id = Uuid::new_v4();
+            parents.arrived(id, origin, candidate, true, at);
+            parents.arrived(id, origin, parent, false, at + lead)
+        };
+
+        // Not by enough of a margin.
+        assert_eq!(race(10, 0), None);
+        assert_eq!(race(30, 100), None);
+        assert_eq!(race(30, 600), None);
+        assert_eq!(race(30, 1_100), Some((parent, candidate)));
+    }
+
+    #[test]
+    fn parents_holding_their_ground_reset_the_hysteresis() {
+        let mut parents = Parents::new(20, 1_000);
+        let (origin, parent, candidate) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        parents.arrived(Uuid::new_v4(), origin, parent, true, 0);
+
+        let id = Uuid::new_v4();
+        parents.arrived(id, origin, candidate, true, 0);
+        assert_eq!(parents.arrived(id, origin, parent, false, 50), None);
+
+        parents.arrived(Uuid::new_v4(), origin, parent, true, 500);
+
+        let id = Uuid::new_v4();
+        parents.arrived(id, origin, candidate, true, 1_100);
+        assert_eq!(parents.arrived(id, origin, parent, false, 1_150), None);
+        assert_eq!(parents.parent(&origin), Some(parent));
+    }
+
+    #[test]
+    fn promote_peers_that_keep_answering_grafts() {
+        let mut parents = Parents::new(20, 5_000);
+        parents.promote_after(3, 10_000);
+        let (origin, parent, peer) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        parents.arrived(Uuid::new_v4(), origin, parent, true, 0);
+
+        // The parent's copy shows up (late by `lag`) or never does.
+        let mut graft = |lag: Option<u64>, at: u64| {
+            let id = Uuid::new_v4();
+            parents.arrived(id, origin, peer, true, at);
+            parents.grafted(&id);
+            match lag {
+                Some(lag) => parents.arrived(id, origin, parent, false, at + lag),
+                None => None
+            }
+        };
+
+        // Not lagging by enough doesn't count, and the first one's out of
+        // the window by the third.
+        assert_eq!(graft(Some(30), 0), None);
+        assert_eq!(graft(Some(10), 5_000), None);
+        assert_eq!(graft(Some(30), 9_000), None);
+        assert_eq!(graft(Some(30), 11_000), None);
+        assert_eq!(graft(None, 12_000), None);
+        assert_eq!(parents.parent(&origin), Some(parent));
+        assert_eq!(parents.expire(16_999), vec![]);
+        assert_eq!(parents.expire(17_000), vec![(parent, peer)]);
+        assert_eq!(parents.parent(&origin), Some(peer));
+    }
+}
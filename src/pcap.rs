@@ -0,0 +1,206 @@
+//! Captures of the frames exchanged with a single peer, taken from within
+//! the transport (see `Node::capture`) so nobody needs root for tcpdump on
+//! a production host. Captures are pcapng files, which Wireshark and
+//! tcpdump read: frames are packets of the `USER0` link type (they're ours,
+//! not ethernet), each with a comment on what we made of it.
+//!
+//! Only the blocks we need are written: one section header, one interface
+//! and an enhanced packet block per frame, all little-endian.
+
+use std::io::{File, MemWriter, IoResult};
+
+use stream::SockAddr;
+use transport::Connection;
+use result::{GossipResult, io_err};
+
+static SECTION_HEADER: u32 = 0x0A0D0D0A;
+static INTERFACE: u32 = 1;
+static ENHANCED_PACKET: u32 = 6;
+static BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+static LINKTYPE_USER0: u16 = 147;
+static OPT_COMMENT: u16 = 1;
+
+/// When a capture stops.
+#[deriving(Show, Clone, PartialEq)]
+pub enum CaptureLimit {
+    /// After that many frames (both ways).
+    FrameCount(uint),
+    /// After that many milliseconds.
+    Duration(u64)
+}
+
+pub struct PcapWriter {
+    file: File
+}
+
+impl PcapWriter {
+    pub fn create(path: &Path) -> GossipResult<PcapWriter> {
+        let mut file = try!(File::create(path).map_err(io_err));
+        try!(file.write(header().as_slice()).map_err(io_err));
+        Ok(PcapWriter { file: file })
+    }
+
+    /// Write the frame captured at `at` (wall clock, in milliseconds).
+    pub fn write(&mut self, at: u64, frame: &[u8], comment: &str) -> GossipResult<()> {
+        let block = packet(at, frame, comment);
+        try!(self.file.write(block.as_slice()).map_err(io_err));
+        self.file.flush().map_err(io_err)
+    }
+}
+
+/// The section header and interface description blocks.
+fn header() -> Vec<u8> {
+    let mut wr = MemWriter::new();
+
+    // Writing to memory can't fail.
+    block(&mut wr, SECTION_HEADER, |wr| {
+        try!(wr.write_le_u32(BYTE_ORDER_MAGIC));
+        try!(wr.write_le_u16(1));
+        try!(wr.write_le_u16(0));
+        // The section's length isn't known up front.
+        wr.write_le_i64(-1)
+    }).unwrap();
+    block(&mut wr, INTERFACE, |wr| {
+        try!(wr.write_le_u16(LINKTYPE_USER0));
+        try!(wr.write_le_u16(0));
+        // No snapshot length, frames are captured whole.
+        wr.write_le_u32(0)
+    }).unwrap();
+
+    wr.unwrap()
+}
+
+fn packet(at: u64, frame: &[u8], comment: &str) -> Vec<u8> {
+    let micros = at * 1000;
+    let mut wr = MemWriter::new();
+
+    block(&mut wr, ENHANCED_PACKET, |wr| {
+        try!(wr.write_le_u32(0));
+        try!(wr.write_le_u32((micros >> 32) as u32));
+        try!(wr.write_le_u32(micros as u32));
+        try!(wr.write_le_u32(frame.len() as u32));
+        try!(wr.write_le_u32(frame.len() as u32));
+        try!(padded(wr, frame));
+
+        try!(wr.write_le_u16(OPT_COMMENT));
+        try!(wr.write_le_u16(comment.len() as u16));
+        try!(padded(wr, comment.as_bytes()));
+        // The end of the options.
+        wr.write_le_u32(0)
+    }).unwrap();
+
+    wr.unwrap()
+}
+
+/// A block of the type, it's length written on both ends.
+fn block(wr: &mut MemWriter, kind: u32, body: |&mut MemWriter| -> IoResult<()>) -> IoResult<()> {
+    let mut inner = MemWriter::new();
+    try!(body(&mut inner));
+    let body = inner.unwrap();
+    let len = body.len() as u32 + 12;
+
+    try!(wr.write_le_u32(kind));
+    try!(wr.write_le_u32(len));
+    try!(wr.write(body.as_slice()));
+    wr.write_le_u32(len)
+}
+
+/// Everything within a block is padded to 32 bits.
+fn padded(wr: &mut MemWriter, bytes: &[u8]) -> IoResult<()> {
+    try!(wr.write(bytes));
+    let padding = (4 - bytes.len() % 4) % 4;
+    wr.write(Vec::from_elem(padding, 0u8).as_slice())
+}
+
+/// A capture in progress, of every frame exchanged with the peer.
+pub struct Capture {
+    peer: SockAddr,
+    writer: PcapWriter,
+    limit: CaptureLimit,
+    started: u64,
+    captured: uint
+}
+
+impl Capture {
+    pub fn new(peer: SockAddr, writer: PcapWriter, limit: CaptureLimit, now: u64) -> Capture {
+        Capture {
+            peer: peer,
+            writer: writer,
+            limit: limit,
+            started: now,
+            captured: 0
+        }
+    }
+
+    pub fn peer<'a>(&'a self) -> &'a SockAddr {
+        &self.peer
+    }
+
+    /// Capture a frame exchanged with the peer on the connection, sent by
+    /// us unless `inbound`. `now` is the wall clock.
+    pub fn frame(&mut self, conn: &Connection, inbound: bool, frame: &[u8],
+                 now: u64) -> GossipResult<()> {
+        let Connection(conn) = *conn;
+        let comment = format!("{} {} on connection {}, {} bytes{}",
+                              if inbound { "from" } else { "to" }, describe(&self.peer), conn,
+                              frame.len(), decoded(frame));
+        self.captured += 1;
+        self.writer.write(now, frame, comment.as_slice())
+    }
+
+    /// Whether the capture reached it's limit.
+    pub fn is_done(&self, now: u64) -> bool {
+        match self.limit {
+            FrameCount(frames) => self.captured >= frames,
+            Duration(duration) => now >= self.started + duration
+        }
+    }
+}
+
+fn describe(addr: &SockAddr) -> String {
+    format!("{}:{}", addr.ip, addr.port)
+}
+
+/// What we make of the frame. Every frame is a broadcast, for now.
+fn decoded(frame: &[u8]) -> String {
+    match frame.get(0) {
+        Some(version) => format!(", broadcast version {}", version),
+        None => ", empty".to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{File, TempDir};
+
+    use stream::SockAddr;
+    use transport::Connection;
+
+    #[test]
+    fn write_pcapng_blocks() {
+        let dir = TempDir::new("pcap").unwrap();
+        let path = dir.path().join("capture.pcapng");
+
+        let writer = PcapWriter::create(&path).unwrap();
+        let mut capture = Capture::new(SockAddr::new("10.0.0.2", 3444), writer, FrameCount(2), 0);
+        capture.frame(&Connection(1), true, &[1u8, 2, 3], 1_000).unwrap();
+        assert!(!capture.is_done(1_000));
+        capture.frame(&Connection(1), false, &[], 1_100).unwrap();
+        assert!(capture.is_done(1_100));
+
+        let blob = File::open(&path).read_to_end().unwrap();
+        assert_eq!(blob.slice_to(4), &[0x0Au8, 0x0D, 0x0D, 0x0A]);
+
+        // Every block's length is on both ends of it.
+        let mut offset = 0;
+        let mut blocks = 0u;
+        while offset < blob.len() {
+            let len = blob[offset + 4] as uint | (blob[offset + 5] as uint << 8);
+            assert_eq!(blob[offset + len - 4] as uint, len & 0xff);
+            offset += len;
+            blocks += 1;
+        }
+        assert_eq!((offset, blocks), (blob.len(), 4));
+    }
+}
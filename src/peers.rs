@@ -0,0 +1,57 @@
+//! A cache of the members we last knew of, persisted so a restarted node
+//! can find it's way back into the cluster even after the seeds it was
+//! originally configured with are long gone. The cache is a `Snapshot`
+//! without any broadcasts, so it gets the same checksum.
+
+use stream::SockAddr;
+use protocol::Peer;
+use snapshot::Snapshot;
+use result::GossipResult;
+
+pub fn save(path: &Path, peers: &[Peer]) -> GossipResult<()> {
+    Snapshot::new(peers.to_vec(), Vec::new()).save(path)
+}
+
+pub fn load(path: &Path) -> GossipResult<Vec<Peer>> {
+    Snapshot::load(path).map(|snapshot| snapshot.members)
+}
+
+/// Where to try joining, in order: the cached peers first and the seeds
+/// last, every address only once.
+pub fn candidates(cached: &[Peer], seeds: &[SockAddr]) -> Vec<SockAddr> {
+    let mut candidates: Vec<SockAddr> = Vec::new();
+
+    for addr in cached.iter().map(|peer| peer.addr()).chain(seeds.iter()) {
+        if !candidates.contains(addr) {
+            candidates.push(addr.clone());
+        }
+    }
+
+    candidates
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::TempDir;
+    use uuid::Uuid;
+    use protocol::Peer;
+    use stream::SockAddr;
+
+    #[test]
+    fn cached_peers_before_seeds() {
+        let cached = vec![Peer::new(Uuid::new_v4(), "10.0.0.2", 3444),
+                          Peer::new(Uuid::new_v4(), "10.0.0.1", 3444)];
+        let seeds = vec![SockAddr::new("10.0.0.1", 3444), SockAddr::new("10.0.0.9", 3444)];
+
+        let dir = TempDir::new("peers").unwrap();
+        let path = dir.path().join("peers");
+        save(&path, cached.as_slice()).unwrap();
+        let loaded = load(&path).unwrap();
+        assert_eq!(loaded, cached);
+
+        assert_eq!(candidates(loaded.as_slice(), seeds.as_slice()),
+                   vec![SockAddr::new("10.0.0.2", 3444), SockAddr::new("10.0.0.1", 3444),
+                        SockAddr::new("10.0.0.9", 3444)]);
+    }
+}
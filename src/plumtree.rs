@@ -0,0 +1,221 @@
+//! The lazy half of Plumtree (epidemic broadcast trees). Broadcasts are
+//! pushed whole to our eager peers only, the edges of the broadcast tree,
+//! while our lazy peers just hear about them: every tick they're sent the
+//! ids of the broadcasts we got since (an `IHave`). A peer sending us a
+//! broadcast we already had is taken off the tree (we `Prune` it), and a
+//! broadcast we heard about but never got within `Config::graft_timeout`
+//! means the tree is broken somewhere: we `Graft` the peer that announced
+//! it, asking for the broadcast and making it an eager peer again. Those
+//! that don't answer either are passed over for the next one to have
+//! announced it, backing off per `Config::graft_backoff`. Announcements come
+//! in batches of up to `Config::ihave_max_ids`.
+//!
+//! Which of the peers sending us broadcasts first stays our parent is up
+//! to `parent`, peers that keep answering our grafts included.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::mem;
+use std::rand::{task_rng, Rng, XorShiftRng};
+use uuid::Uuid;
+
+use backoff::{Backoff, Retry};
+
+struct Missing {
+    /// Who announced it, the next one to graft first.
+    announcers: Vec<Uuid>,
+    /// When to graft the next one, unless it showed up by then.
+    due: u64,
+    /// The grafts for it so far.
+    retry: Retry
+}
+
+pub struct Plumtree {
+    graft_timeout: u64,
+    backoff: Backoff,
+    rng: XorShiftRng,
+    /// The broadcasts to announce to our lazy peers next tick.
+    announcements: Vec<Uuid>,
+    /// The broadcasts we heard about but haven't got.
+    missing: HashMap<Uuid, Missing>,
+    /// The broadcasts we grafted a peer for, and which one we did last.
+    grafted: HashMap<Uuid, Uuid>
+}
+
+impl Plumtree {
+    pub fn new(graft_timeout: u64, backoff: Backoff) -> Plumtree {
+        Plumtree {
+            graft_timeout: graft_timeout,
+            backoff: backoff,
+            rng: task_rng().gen(),
+            announcements: Vec::new(),
+            missing: HashMap::new(),
+            grafted: HashMap::new()
+        }
+    }
+
+    /// We pushed the broadcast to our eager peers, the lazy ones get to
+    /// hear about it.
+    pub fn announce(&mut self, id: Uuid) {
+        self.announcements.push(id);
+    }
+
+    /// What to announce, in batches of up to `max` ids (at least one).
+    pub fn take_announcements(&mut self, max: uint) -> Vec<Vec<Uuid>> {
+        let ids = mem::replace(&mut self.announcements, Vec::new());
+        ids.as_slice().chunks(cmp::max(max, 1)).map(|chunk| chunk.to_vec()).collect()
+    }
+
+    /// The peer announced a broadcast we haven't got.
+    pub fn announced(&mut self, id: Uuid, by: Uuid, now: u64) {
+        let (timeout, retry) = (self.graft_timeout, self.backoff.start(now));
+        let missing = self.missing.find_or_insert_with(id, |_| Missing {
+            announcers: Vec::new(),
+            due: now + timeout,
+            retry: retry
+        });
+        if !missing.announcers.contains(&by) {
+            missing.announcers.push(by);
+        }
+    }
+
+    /// Whether the peer that got the broadcast to us is the one we grafted
+    /// for it, i.e., it's our graft it answered. Ask before `received`.
+    pub fn answered(&self, id: &Uuid, by: &Uuid) -> bool {
+        self.grafted.find(id) == Some(by)
+    }
+
+    /// We got the broadcast, there's no need to graft anybody for it.
+    pub fn received(&mut self, id: &Uuid) {
+        self.missing.remove(id);
+        self.grafted.remove(id);
+    }
+
+    /// The broadcasts still missing once their time was up, along with the
+    /// peer to graft for each. Broadcasts nobody's left to graft for, or
+    /// that we backed off from for long enough, are given up on.
+    pub fn due(&mut self, now: u64) -> Vec<(Uuid, Uuid)> {
+        let mut grafts = Vec::new();
+        let mut exhausted = Vec::new();
+        let max = self.backoff.max;
+
+        for (id, missing) in self.missing.mut_iter() {
+            if missing.due > now {
+                continue;
+            }
+            if missing.announcers.is_empty() {
+                exhausted.push(*id);
+                continue;
+            }
+
+            grafts.push((*id, missing.announcers.remove(0).unwrap()));
+            match missing.retry.next_delay(&mut self.rng, now) {
+                Some(delay) => missing.due = now + delay,
+                // Whoever we grafted just now is the last one we ask.
+                None => {
+                    missing.announcers.clear();
+                    missing.due = now + max;
+                }
+            }
+        }
+
+        for id in exhausted.iter() {
+            self.missing.remove(id);
+            self.grafted.remove(id);
+        }
+        for &(id, peer) in grafts.iter() {
+            self.grafted.insert(id, peer);
+        }
+        grafts
+    }
+
+    /// The broadcasts we heard about but haven't got, along with the peer
+    /// to graft next (if anybody's left to) and when.
+    pub fn pending(&self) -> Vec<(Uuid, Option<Uuid>, u64)> {
+        self.missing.iter().map(|(id, missing)| {
+            (*id, missing.announcers.as_slice().head().map(|peer| *peer), missing.due)
+        }).collect()
+    }
+
+    /// Stop counting on the peer to have anything, i.e., once it died.
+    pub fn forget(&mut self, peer: &Uuid) {
+        for (_, missing) in self.missing.mut_iter() {
+            missing.announcers.retain(|id| id != peer);
+        }
+    }
+
+    /// How many broadcasts we heard about but haven't got.
+    pub fn missing(&self) -> uint {
+        self.missing.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+    use backoff::Backoff;
+
+    #[test]
+    fn graft_announcers_in_turn() {
+        let mut tree = Plumtree::new(100, Backoff::new(50, 50));
+        let (id, a, b) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        tree.announced(id, a, 0);
+        tree.announced(id, b, 50);
+        tree.announced(id, a, 60);
+        assert!(tree.due(99).is_empty());
+        assert_eq!(tree.due(100), vec![(id, a)]);
+        let next = range(100u64, 151).map(|now| tree.due(now)).find(|due| !due.is_empty());
+        assert_eq!(next, Some(vec![(id, b)]));
+
+        // Nobody's left to ask.
+        assert!(tree.due(200).is_empty());
+        assert_eq!(tree.missing(), 0);
+
+        // Nor once we backed off for long enough.
+        let mut backoff = Backoff::new(50, 50);
+        backoff.max_attempts = Some(1);
+        let mut tree = Plumtree::new(100, backoff);
+        tree.announced(id, a, 0);
+        tree.announced(id, b, 0);
+        assert_eq!(tree.due(100), vec![(id, a)]);
+        assert!(tree.due(150).is_empty());
+        assert_eq!(tree.missing(), 0);
+    }
+
+    #[test]
+    fn forget_what_showed_up() {
+        let mut tree = Plumtree::new(100, Backoff::new(50, 50));
+        let (id, other, a) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        tree.announced(id, a, 0);
+        tree.announced(other, a, 0);
+        tree.received(&id);
+        tree.forget(&a);
+        assert!(tree.due(100).is_empty());
+
+        tree.announce(id);
+        tree.announce(other);
+        tree.announce(a);
+        assert_eq!(tree.take_announcements(2), vec![vec![id, other], vec![a]]);
+        assert!(tree.take_announcements(2).is_empty());
+    }
+
+    #[test]
+    fn tell_who_answered_the_graft() {
+        let mut tree = Plumtree::new(100, Backoff::new(50, 50));
+        let (id, a, b) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        tree.announced(id, a, 0);
+        tree.announced(id, b, 0);
+        assert!(!tree.answered(&id, &a));
+        tree.due(100);
+        assert!(tree.answered(&id, &a));
+        tree.due(150);
+        assert!(tree.answered(&id, &b) && !tree.answered(&id, &a));
+
+        tree.received(&id);
+        assert!(!tree.answered(&id, &b));
+    }
+}
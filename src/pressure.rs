@@ -0,0 +1,162 @@
+//! Resource pressure of the host we run on. Every `Config::pressure_interval`
+//! the node samples the CPU load, memory use and file descriptors in use,
+//! and boils them down to a coarse `PressureLevel` that goes out with our
+//! heartbeats. Pressure that stays high (for `Config::pressure_sustain`)
+//! turns our health yellow, and scales the local health multiplier the
+//! failure detector should slow it's own judgments down by: a node that's
+//! struggling itself is the last one to trust on who else is.
+
+use std::io::File;
+use std::io::fs;
+use std::os;
+
+#[deriving(Show, Clone, PartialEq, Eq, PartialOrd, Encodable, Decodable)]
+pub enum PressureLevel {
+    Low,
+    Elevated,
+    High
+}
+
+/// How much of each resource is in use, as a fraction of what's there.
+#[deriving(Show, Clone, PartialEq)]
+pub struct Sample {
+    /// The load average over a minute, per core.
+    pub cpu: f64,
+    pub memory: f64,
+    /// Of the open file soft limit.
+    pub fds: f64
+}
+
+impl Sample {
+    /// Going by whichever resource is the scarcest.
+    pub fn level(&self) -> PressureLevel {
+        let worst = self.cpu.max(self.memory).max(self.fds);
+        if worst >= 0.9 {
+            High
+        } else if worst >= 0.7 {
+            Elevated
+        } else {
+            Low
+        }
+    }
+}
+
+/// Sample the host, `None` where we don't know how to.
+#[cfg(target_os = "linux")]
+pub fn sample() -> Option<Sample> {
+    let load = read("/proc/loadavg").and_then(|s| field(s.as_slice(), 0));
+
+    let meminfo = read("/proc/meminfo");
+    let memory = meminfo.as_ref().and_then(|s| {
+        let kb = |key: &str| {
+            s.as_slice().lines().find(|line| line.starts_with(key))
+                        .and_then(|line| field(line, 1))
+        };
+        match (kb("MemTotal:"), kb("MemAvailable:")) {
+            (Some(total), Some(available)) if total > 0.0 => Some(1.0 - available / total),
+            _ => None
+        }
+    });
+
+    let limits = read("/proc/self/limits");
+    let limit = limits.as_ref().and_then(|s| {
+        s.as_slice().lines().find(|line| line.starts_with("Max open files"))
+                    .and_then(|line| field(line, 3))
+    });
+    let open = fs::readdir(&Path::new("/proc/self/fd")).ok().map(|fds| fds.len() as f64);
+
+    match (load, memory, open, limit) {
+        (Some(load), Some(memory), Some(open), Some(limit)) if limit > 0.0 => Some(Sample {
+            cpu: load / os::num_cpus() as f64,
+            memory: memory,
+            fds: open / limit
+        }),
+        _ => None
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read(path: &str) -> Option<String> {
+    File::open(&Path::new(path)).read_to_string().ok()
+}
+
+/// The nth whitespace separated number on the line.
+#[cfg(target_os = "linux")]
+fn field(line: &str, n: uint) -> Option<f64> {
+    line.words().nth(n).and_then(|word| from_str::<f64>(word))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn sample() -> Option<Sample> {
+    None
+}
+
+/// Our own pressure over time.
+pub struct Pressure {
+    /// How long (in milliseconds) pressure has to last before it counts.
+    sustain: u64,
+    level: PressureLevel,
+    /// When we went to the current level.
+    since: u64
+}
+
+impl Pressure {
+    pub fn new(sustain: u64) -> Pressure {
+        Pressure {
+            sustain: sustain,
+            level: Low,
+            since: 0
+        }
+    }
+
+    pub fn level(&self) -> PressureLevel {
+        self.level.clone()
+    }
+
+    pub fn observe(&mut self, level: PressureLevel, now: u64) {
+        if level != self.level {
+            self.level = level;
+            self.since = now;
+        }
+    }
+
+    /// The level, once it lasted long enough.
+    pub fn sustained(&self, now: u64) -> PressureLevel {
+        if now >= self.since + self.sustain { self.level() } else { Low }
+    }
+
+    /// What the failure detector's timeouts should be multiplied by.
+    pub fn multiplier(&self, now: u64) -> u64 {
+        match self.sustained(now) {
+            Low => 1,
+            Elevated => 2,
+            High => 3
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scarcest_resource_decides() {
+        assert_eq!(Sample { cpu: 0.1, memory: 0.2, fds: 0.0 }.level(), Low);
+        assert_eq!(Sample { cpu: 0.1, memory: 0.75, fds: 0.0 }.level(), Elevated);
+        assert_eq!(Sample { cpu: 1.5, memory: 0.2, fds: 0.0 }.level(), High);
+    }
+
+    #[test]
+    fn only_sustained_pressure_counts() {
+        let mut pressure = Pressure::new(1_000);
+        pressure.observe(High, 100);
+        assert_eq!(pressure.sustained(1_099), Low);
+        assert_eq!(pressure.multiplier(1_100), 3);
+
+        // Dipping resets the clock.
+        pressure.observe(Elevated, 1_200);
+        pressure.observe(High, 1_300);
+        assert_eq!(pressure.sustained(2_000), Low);
+        assert_eq!(pressure.sustained(2_300), High);
+    }
+}
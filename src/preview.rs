@@ -0,0 +1,20 @@
+//! What a seed tells a node that's only looking at it's cluster before
+//! joining, see `Node::preview_join`. Operators get to check they're
+//! pointing the node at the right cluster (and the right environment)
+//! before it's part of it.
+
+use protocol::{Peer, Health};
+
+#[deriving(Show, Clone, PartialEq, Encodable, Decodable)]
+pub struct ClusterInfo {
+    /// See `Config::cluster_name`.
+    pub name: String,
+    /// The versions running within the cluster, sorted.
+    pub versions: Vec<String>,
+    /// How many members are alive, the seed included.
+    pub size: uint,
+    /// The seed's view of the cluster's health.
+    pub health: Health,
+    /// Every member the seed knows of, itself included.
+    pub members: Vec<Peer>
+}
@@ -0,0 +1,908 @@
+//! The protobuf codec, so implementations in other languages can speak to
+//! our clusters. The schema lives in `proto/gossip.proto` and the code
+//! below is written against it by hand rather than generated, so keep the
+//! two in sync: new fields get new numbers, old ones are never reused.
+//!
+//! Unknown fields are skipped (that's what makes newer members readable),
+//! unknown messages are a `ProtocolMismatch`.
+
+use std::collections::HashMap;
+use std::str;
+use uuid::Uuid;
+
+use broadcast::Broadcast;
+use codec::{Codec, WireBroadcast};
+use member::{Status, Active, Bootstrapping, Draining, MemberState, Alive, Suspect, Dead};
+use message::{Message, StatusChange, QuotaExceeded, JoinRequest, JoinSync, JoinSynced, Digest};
+use message::{Advertise, KillNode, Services, Metadata, RingEpoch, Heartbeat, ShutdownCluster};
+use message::{ShutdownReport, PreviewRequest, Preview, BootstrapRequest, BootstrapReply};
+use message::{FeatureToggle, FeatureAck, IHave, Graft, Prune, SyncDigest, TreeNodes, LeafIds};
+use message::{Ping, PingReq, Ack, Join, JoinRejected, Leave, LeaveAck, Direct, KillNodeAt};
+use message::BootstrapBroadcasts;
+use message::{Update, Joined, StatusUpdate, Liveness, LivenessAt, Moved, Refuted, Departed};
+use merge::VersionVector;
+use pressure::{PressureLevel, Low, Elevated, High};
+use preview::ClusterInfo;
+use protocol::{Peer, Health, Green, Yellow, Red};
+use registry::Service;
+use teardown::{ShutdownPhase, Stopping, Stopped};
+use topic::Capabilities;
+use result::{GossipResult, GossipError, ProtocolMismatch};
+
+static VARINT: u64 = 0;
+static FIXED64: u64 = 1;
+static DELIMITED: u64 = 2;
+static FIXED32: u64 = 5;
+
+static EMPTY: &'static [u8] = &[];
+
+/// How many variants the `update` and `message` oneofs have.
+static UPDATES: u32 = 7;
+static MESSAGES: u32 = 36;
+
+fn malformed() -> GossipError {
+    GossipError::new("malformed protobuf", ProtocolMismatch)
+}
+
+struct Writer {
+    buf: Vec<u8>
+}
+
+impl Writer {
+    fn new() -> Writer {
+        Writer {
+            buf: Vec::new()
+        }
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire: u64) {
+        self.varint(field as u64 << 3 | wire);
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        self.key(field, VARINT);
+        self.varint(value);
+    }
+
+    fn bool(&mut self, field: u32, value: bool) {
+        self.uint(field, value as u64);
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, DELIMITED);
+        self.varint(value.len() as u64);
+        self.buf.push_all(value);
+    }
+
+    fn string(&mut self, field: u32, value: &str) {
+        self.bytes(field, value.as_bytes());
+    }
+
+    fn uuid(&mut self, field: u32, id: &Uuid) {
+        self.bytes(field, id.as_bytes());
+    }
+
+    fn message(&mut self, field: u32, f: |&mut Writer|) {
+        let mut inner = Writer::new();
+        f(&mut inner);
+        self.bytes(field, inner.buf.as_slice());
+    }
+}
+
+#[deriving(Clone)]
+enum Value<'a> {
+    Varint(u64),
+    Delimited(&'a [u8])
+}
+
+fn read_varint(bytes: &[u8], pos: &mut uint) -> GossipResult<u64> {
+    let mut value = 0u64;
+    let mut shift = 0u;
+
+    loop {
+        if *pos >= bytes.len() || shift > 63 {
+            return Err(malformed());
+        }
+
+        let byte = bytes[*pos];
+        *pos += 1;
+        value |= (byte & 0x7f) as u64 << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+/// The fields of a single message, in the order they came.
+struct Fields<'a> {
+    fields: Vec<(u32, Value<'a>)>
+}
+
+impl<'a> Fields<'a> {
+    fn parse(bytes: &'a [u8]) -> GossipResult<Fields<'a>> {
+        let mut fields = Vec::new();
+        let mut pos = 0u;
+
+        while pos < bytes.len() {
+            let key = try!(read_varint(bytes, &mut pos));
+            let field = (key >> 3) as u32;
+            let wire = key & 7;
+
+            if wire == VARINT {
+                fields.push((field, Varint(try!(read_varint(bytes, &mut pos)))));
+            } else if wire == DELIMITED {
+                let len = try!(read_varint(bytes, &mut pos)) as uint;
+                if len > bytes.len() - pos {
+                    return Err(malformed());
+                }
+                fields.push((field, Delimited(bytes.slice(pos, pos + len))));
+                pos += len;
+            } else if wire == FIXED64 {
+                pos += 8;
+            } else if wire == FIXED32 {
+                pos += 4;
+            } else {
+                return Err(malformed());
+            }
+        }
+
+        if pos > bytes.len() {
+            return Err(malformed());
+        }
+        Ok(Fields { fields: fields })
+    }
+
+    /// The last value of the field wins, as protobuf wants.
+    fn last(&self, field: u32) -> Option<Value<'a>> {
+        self.fields.iter().rev().find(|&&(f, _)| f == field).map(|&(_, ref value)| value.clone())
+    }
+
+    /// Missing fields are zero.
+    fn uint(&self, field: u32) -> GossipResult<u64> {
+        match try!(self.optional_uint(field)) {
+            Some(value) => Ok(value),
+            None => Ok(0)
+        }
+    }
+
+    fn optional_uint(&self, field: u32) -> GossipResult<Option<u64>> {
+        match self.last(field) {
+            Some(Varint(value)) => Ok(Some(value)),
+            Some(Delimited(_)) => Err(malformed()),
+            None => Ok(None)
+        }
+    }
+
+    fn bool(&self, field: u32) -> GossipResult<bool> {
+        Ok(try!(self.uint(field)) != 0)
+    }
+
+    /// Missing fields are empty.
+    fn bytes(&self, field: u32) -> GossipResult<&'a [u8]> {
+        match self.last(field) {
+            Some(Delimited(bytes)) => Ok(bytes),
+            Some(Varint(_)) => Err(malformed()),
+            None => Ok(EMPTY)
+        }
+    }
+
+    fn string(&self, field: u32) -> GossipResult<String> {
+        str::from_utf8(try!(self.bytes(field))).map(|s| s.to_string()).ok_or(malformed())
+    }
+
+    fn optional_string(&self, field: u32) -> GossipResult<Option<String>> {
+        match self.last(field) {
+            Some(_) => Ok(Some(try!(self.string(field)))),
+            None => Ok(None)
+        }
+    }
+
+    fn uuid(&self, field: u32) -> GossipResult<Uuid> {
+        Uuid::from_bytes(try!(self.bytes(field))).ok_or(malformed())
+    }
+
+    fn message(&self, field: u32) -> GossipResult<Fields<'a>> {
+        Fields::parse(try!(self.bytes(field)))
+    }
+
+    /// Every value of the repeated field, in order.
+    fn repeated(&self, field: u32) -> GossipResult<Vec<&'a [u8]>> {
+        let mut values = Vec::new();
+        for &(f, ref value) in self.fields.iter() {
+            match *value {
+                Delimited(bytes) if f == field => values.push(bytes),
+                Varint(_) if f == field => return Err(malformed()),
+                _ => {}
+            }
+        }
+        Ok(values)
+    }
+
+    /// Every value of the repeated field, unpacked.
+    fn uints(&self, field: u32) -> GossipResult<Vec<u64>> {
+        let mut values = Vec::new();
+        for &(f, ref value) in self.fields.iter() {
+            match *value {
+                Varint(value) if f == field => values.push(value),
+                Delimited(_) if f == field => return Err(malformed()),
+                _ => {}
+            }
+        }
+        Ok(values)
+    }
+
+    fn uuids(&self, field: u32) -> GossipResult<Vec<Uuid>> {
+        let mut ids = Vec::new();
+        for bytes in try!(self.repeated(field)).move_iter() {
+            ids.push(try!(Uuid::from_bytes(bytes).ok_or(malformed())));
+        }
+        Ok(ids)
+    }
+
+    fn strings(&self, field: u32) -> GossipResult<Vec<String>> {
+        let mut strings = Vec::new();
+        for bytes in try!(self.repeated(field)).move_iter() {
+            match str::from_utf8(bytes) {
+                Some(s) => strings.push(s.to_string()),
+                None => return Err(malformed())
+            }
+        }
+        Ok(strings)
+    }
+
+    fn messages<T>(&self, field: u32, read: |&Fields<'a>| -> GossipResult<T>)
+                   -> GossipResult<Vec<T>> {
+        let mut messages = Vec::new();
+        for bytes in try!(self.repeated(field)).move_iter() {
+            messages.push(try!(read(&try!(Fields::parse(bytes)))));
+        }
+        Ok(messages)
+    }
+
+    /// Which field of a oneof (numbered 1 to `variants`) is set, and to
+    /// what. Fields outside of it, a newer member's say, don't count.
+    fn oneof(&self, variants: u32) -> GossipResult<(u32, Fields<'a>)> {
+        let set = self.fields.iter().rev().find(|&&(f, _)| f >= 1 && f <= variants);
+        match set {
+            Some(&(field, Delimited(bytes))) => Ok((field, try!(Fields::parse(bytes)))),
+            _ => Err(malformed())
+        }
+    }
+}
+
+fn status(status: &Status) -> u64 {
+    match *status {
+        Active => 0,
+        Bootstrapping => 1,
+        Draining => 2
+    }
+}
+
+fn read_status(value: u64) -> GossipResult<Status> {
+    match value {
+        0 => Ok(Active),
+        1 => Ok(Bootstrapping),
+        2 => Ok(Draining),
+        _ => Err(malformed())
+    }
+}
+
+fn member_state(state: &MemberState) -> u64 {
+    match *state {
+        Alive => 0,
+        Suspect => 1,
+        Dead => 2
+    }
+}
+
+fn read_member_state(value: u64) -> GossipResult<MemberState> {
+    match value {
+        0 => Ok(Alive),
+        1 => Ok(Suspect),
+        2 => Ok(Dead),
+        _ => Err(malformed())
+    }
+}
+
+fn phase(phase: &ShutdownPhase) -> u64 {
+    match *phase {
+        Stopping => 0,
+        Stopped => 1
+    }
+}
+
+fn read_phase(value: u64) -> GossipResult<ShutdownPhase> {
+    match value {
+        0 => Ok(Stopping),
+        1 => Ok(Stopped),
+        _ => Err(malformed())
+    }
+}
+
+fn health(health: &Health) -> u64 {
+    match *health {
+        Green => 0,
+        Yellow => 1,
+        Red => 2
+    }
+}
+
+fn read_health(value: u64) -> GossipResult<Health> {
+    match value {
+        0 => Ok(Green),
+        1 => Ok(Yellow),
+        2 => Ok(Red),
+        _ => Err(malformed())
+    }
+}
+
+fn pressure(level: &PressureLevel) -> u64 {
+    match *level {
+        Low => 0,
+        Elevated => 1,
+        High => 2
+    }
+}
+
+fn read_pressure(value: u64) -> GossipResult<PressureLevel> {
+    match value {
+        0 => Ok(Low),
+        1 => Ok(Elevated),
+        2 => Ok(High),
+        _ => Err(malformed())
+    }
+}
+
+fn write_peer(w: &mut Writer, peer: &Peer) {
+    w.uuid(1, &peer.id());
+    w.string(2, peer.addr().ip.as_slice());
+    w.uint(3, peer.addr().port as u64);
+}
+
+fn read_peer(f: &Fields) -> GossipResult<Peer> {
+    Ok(Peer::new(try!(f.uuid(1)), try!(f.string(2)).as_slice(), try!(f.uint(3)) as u16))
+}
+
+/// Admin proofs go out whole (as `tag`) as well as truncated to a number (as
+/// `truncated`), the way nodes before whole tags sent and checked them.
+fn write_proof(w: &mut Writer, truncated: u32, tag: u32, proof: &[u8]) {
+    let mut legacy = 0u64;
+    for i in range(0u, 8) {
+        legacy = legacy << 8 | proof.get(i).map(|b| *b as u64).unwrap_or(0);
+    }
+    w.uint(truncated, legacy);
+    w.bytes(tag, proof);
+}
+
+/// The whole tag, or what's left of it when an older node sent the proof.
+fn read_proof(f: &Fields, truncated: u32, tag: u32) -> GossipResult<Vec<u8>> {
+    match f.last(tag) {
+        Some(_) => Ok(try!(f.bytes(tag)).to_vec()),
+        None => {
+            let legacy = try!(f.uint(truncated));
+            Ok(range(0u, 8).rev().map(|i| (legacy >> (i * 8)) as u8).collect())
+        }
+    }
+}
+
+fn write_version(w: &mut Writer, version: &VersionVector) {
+    for &(ref writer, count) in version.counters().iter() {
+        w.message(1, |w| {
+            w.uuid(1, writer);
+            w.uint(2, count);
+        });
+    }
+}
+
+fn read_version(f: &Fields) -> GossipResult<VersionVector> {
+    let counters = try!(f.messages(1, |f| Ok((try!(f.uuid(1)), try!(f.uint(2))))));
+    Ok(VersionVector::from_counters(counters))
+}
+
+fn write_update(w: &mut Writer, update: &Update) {
+    match *update {
+        Joined(ref peer) => w.message(1, |w| write_peer(w, peer)),
+        StatusUpdate(ref id, ref s) => w.message(2, |w| {
+            w.uuid(1, id);
+            w.uint(2, status(s));
+        }),
+        Liveness(ref id, ref state) => w.message(3, |w| {
+            w.uuid(1, id);
+            w.uint(2, member_state(state));
+        }),
+        Moved(ref peer, incarnation) => w.message(4, |w| {
+            w.message(1, |w| write_peer(w, peer));
+            w.uint(2, incarnation);
+        }),
+        Refuted(ref peer, incarnation) => w.message(5, |w| {
+            w.message(1, |w| write_peer(w, peer));
+            w.uint(2, incarnation);
+        }),
+        LivenessAt(ref id, ref state, incarnation) => w.message(6, |w| {
+            w.uuid(1, id);
+            w.uint(2, member_state(state));
+            w.uint(3, incarnation);
+        }),
+        Departed(ref id, incarnation) => w.message(7, |w| {
+            w.uuid(1, id);
+            w.uint(2, incarnation);
+        })
+    }
+}
+
+fn read_update(f: &Fields) -> GossipResult<Update> {
+    let (variant, f) = try!(f.oneof(UPDATES));
+    Ok(match variant {
+        1 => Joined(try!(read_peer(&f))),
+        2 => StatusUpdate(try!(f.uuid(1)), try!(read_status(try!(f.uint(2))))),
+        3 => Liveness(try!(f.uuid(1)), try!(read_member_state(try!(f.uint(2))))),
+        4 => Moved(try!(read_peer(&try!(f.message(1)))), try!(f.uint(2))),
+        5 => Refuted(try!(read_peer(&try!(f.message(1)))), try!(f.uint(2))),
+        6 => LivenessAt(try!(f.uuid(1)), try!(read_member_state(try!(f.uint(2)))),
+                        try!(f.uint(3))),
+        7 => Departed(try!(f.uuid(1)), try!(f.uint(2))),
+        _ => return Err(GossipError::new("unknown update", ProtocolMismatch))
+    })
+}
+
+fn write_cluster_info(w: &mut Writer, info: &ClusterInfo) {
+    w.string(1, info.name.as_slice());
+    for version in info.versions.iter() {
+        w.string(2, version.as_slice());
+    }
+    w.uint(3, info.size as u64);
+    w.uint(4, health(&info.health));
+    for peer in info.members.iter() {
+        w.message(5, |w| write_peer(w, peer));
+    }
+}
+
+fn read_cluster_info(f: &Fields) -> GossipResult<ClusterInfo> {
+    Ok(ClusterInfo {
+        name: try!(f.string(1)),
+        versions: try!(f.strings(2)),
+        size: try!(f.uint(3)) as uint,
+        health: try!(read_health(try!(f.uint(4)))),
+        members: try!(f.messages(5, |f| read_peer(f)))
+    })
+}
+
+fn write_message(w: &mut Writer, msg: &Message) {
+    match *msg {
+        StatusChange(ref id, ref s) => w.message(1, |w| {
+            w.uuid(1, id);
+            w.uint(2, status(s));
+        }),
+        QuotaExceeded(ref id) => w.message(2, |w| w.uuid(1, id)),
+        JoinRequest(ref peer) => w.message(3, |w| write_peer(w, peer)),
+        JoinSync(ref id, ref snapshot) => w.message(4, |w| {
+            w.uuid(1, id);
+            w.bytes(2, snapshot.as_slice());
+        }),
+        JoinSynced(ref id) => w.message(5, |w| w.uuid(1, id)),
+        Digest(ref updates) => w.message(6, |w| {
+            for update in updates.iter() {
+                w.message(1, |w| write_update(w, update));
+            }
+        }),
+        Advertise(ref id, Capabilities(bits)) => w.message(7, |w| {
+            w.uuid(1, id);
+            w.uint(2, bits as u64);
+        }),
+        KillNode(ref voter, ref target, proof) => w.message(8, |w| {
+            w.uuid(1, voter);
+            w.uuid(2, target);
+            match proof {
+                Some(proof) => w.uint(3, proof),
+                None => {}
+            }
+        }),
+        Services(ref id, ref services) => w.message(9, |w| {
+            w.uuid(1, id);
+            for service in services.iter() {
+                w.message(2, |w| {
+                    w.string(1, service.name.as_slice());
+                    w.uint(2, service.port as u64);
+                    w.bool(3, service.healthy);
+                });
+            }
+        }),
+        Metadata(ref id, ref metadata, ref version) => w.message(10, |w| {
+            w.uuid(1, id);
+            for (key, value) in metadata.iter() {
+                w.message(2, |w| {
+                    w.string(1, key.as_slice());
+                    w.string(2, value.as_slice());
+                });
+            }
+            w.message(3, |w| write_version(w, version));
+        }),
+        RingEpoch(epoch) => w.message(11, |w| w.uint(1, epoch)),
+        Heartbeat(ref id, ref payload, ref level) => w.message(12, |w| {
+            w.uuid(1, id);
+            w.bytes(2, payload.as_slice());
+            w.uint(3, pressure(level));
+        }),
+        ShutdownCluster(ref initiator, ref role, issued_at, ref proof) => w.message(13, |w| {
+            w.uuid(1, initiator);
+            match *role {
+                Some(ref role) => w.string(2, role.as_slice()),
+                None => {}
+            }
+            w.uint(3, issued_at);
+            write_proof(w, 4, 5, proof.as_slice());
+        }),
+        ShutdownReport(ref id, ref p) => w.message(14, |w| {
+            w.uuid(1, id);
+            w.uint(2, phase(p));
+        }),
+        PreviewRequest(ref id) => w.message(15, |w| w.uuid(1, id)),
+        Preview(ref info) => w.message(16, |w| write_cluster_info(w, info)),
+        BootstrapRequest(ref id, ref topics) => w.message(17, |w| {
+            w.uuid(1, id);
+            for topic in topics.iter() {
+                w.string(2, topic.as_slice());
+            }
+        }),
+        BootstrapReply(ref id, ref broadcasts) => w.message(18, |w| {
+            w.uuid(1, id);
+            for broadcast in broadcasts.iter() {
+                w.bytes(2, broadcast.as_slice());
+            }
+        }),
+        FeatureToggle(ref initiator, ref name, enabled, issued_at, ref proof) => {
+            w.message(19, |w| {
+                w.uuid(1, initiator);
+                w.string(2, name.as_slice());
+                w.bool(3, enabled);
+                w.uint(4, issued_at);
+                write_proof(w, 5, 6, proof.as_slice());
+            })
+        },
+        FeatureAck(ref id, ref name, issued_at) => w.message(20, |w| {
+            w.uuid(1, id);
+            w.string(2, name.as_slice());
+            w.uint(3, issued_at);
+        }),
+        IHave(ref id, ref ids) => w.message(21, |w| {
+            w.uuid(1, id);
+            for id in ids.iter() {
+                w.uuid(2, id);
+            }
+        }),
+        Graft(ref id, ref ids) => w.message(22, |w| {
+            w.uuid(1, id);
+            for id in ids.iter() {
+                w.uuid(2, id);
+            }
+        }),
+        Prune(ref id) => w.message(23, |w| w.uuid(1, id)),
+        SyncDigest(ref id, ref members, ref ids, reply) => w.message(24, |w| {
+            w.uuid(1, id);
+            for &(ref member, incarnation, ref state) in members.iter() {
+                w.message(2, |w| {
+                    w.uuid(1, member);
+                    w.uint(2, incarnation);
+                    w.uint(3, member_state(state));
+                });
+            }
+            for id in ids.iter() {
+                w.uuid(3, id);
+            }
+            w.bool(4, reply);
+        }),
+        TreeNodes(ref id, level, ref nodes) => w.message(25, |w| {
+            w.uuid(1, id);
+            w.uint(2, level as u64);
+            for &(index, hash) in nodes.iter() {
+                w.message(3, |w| {
+                    w.uint(1, index as u64);
+                    w.uint(2, hash);
+                });
+            }
+        }),
+        LeafIds(ref id, ref leaves, ref ids, reply) => w.message(26, |w| {
+            w.uuid(1, id);
+            for &leaf in leaves.iter() {
+                w.uint(2, leaf as u64);
+            }
+            for id in ids.iter() {
+                w.uuid(3, id);
+            }
+            w.bool(4, reply);
+        }),
+        Ping(ref id, seq) => w.message(27, |w| {
+            w.uuid(1, id);
+            w.uint(2, seq);
+        }),
+        PingReq(ref id, ref target, seq) => w.message(28, |w| {
+            w.uuid(1, id);
+            w.uuid(2, target);
+            w.uint(3, seq);
+        }),
+        Ack(ref id, seq) => w.message(29, |w| {
+            w.uuid(1, id);
+            w.uint(2, seq);
+        }),
+        Join(ref peer, ref cluster, version) => w.message(30, |w| {
+            w.message(1, |w| write_peer(w, peer));
+            w.string(2, cluster.as_slice());
+            w.uint(3, version as u64);
+        }),
+        JoinRejected(ref id, ref reason) => w.message(31, |w| {
+            w.uuid(1, id);
+            w.string(2, reason.as_slice());
+        }),
+        Leave(ref id) => w.message(32, |w| w.uuid(1, id)),
+        LeaveAck(ref id) => w.message(33, |w| w.uuid(1, id)),
+        Direct(ref id, ref broadcast) => w.message(34, |w| {
+            w.uuid(1, id);
+            w.message(2, |w| write_broadcast(w, broadcast));
+        }),
+        KillNodeAt(ref voter, ref target, issued_at, ref proof) => w.message(35, |w| {
+            w.uuid(1, voter);
+            w.uuid(2, target);
+            w.uint(3, issued_at);
+            write_proof(w, 4, 5, proof.as_slice());
+        }),
+        BootstrapBroadcasts(ref id, ref broadcasts) => w.message(36, |w| {
+            w.uuid(1, id);
+            for broadcast in broadcasts.iter() {
+                w.message(2, |w| write_broadcast(w, broadcast));
+            }
+        })
+    }
+}
+
+fn read_message(f: &Fields) -> GossipResult<Message> {
+    let (variant, f) = try!(f.oneof(MESSAGES));
+    Ok(match variant {
+        1 => StatusChange(try!(f.uuid(1)), try!(read_status(try!(f.uint(2))))),
+        2 => QuotaExceeded(try!(f.uuid(1))),
+        3 => JoinRequest(try!(read_peer(&f))),
+        4 => JoinSync(try!(f.uuid(1)), try!(f.bytes(2)).to_vec()),
+        5 => JoinSynced(try!(f.uuid(1))),
+        6 => Digest(try!(f.messages(1, |f| read_update(f)))),
+        7 => Advertise(try!(f.uuid(1)), Capabilities(try!(f.uint(2)) as u32)),
+        8 => KillNode(try!(f.uuid(1)), try!(f.uuid(2)), try!(f.optional_uint(3))),
+        9 => Services(try!(f.uuid(1)), try!(f.messages(2, |f| {
+            Ok(Service {
+                name: try!(f.string(1)),
+                port: try!(f.uint(2)) as u16,
+                healthy: try!(f.bool(3))
+            })
+        }))),
+        10 => {
+            let entries = try!(f.messages(2, |f| Ok((try!(f.string(1)), try!(f.string(2))))));
+            let metadata: HashMap<String, String> = entries.move_iter().collect();
+            Metadata(try!(f.uuid(1)), metadata, try!(read_version(&try!(f.message(3)))))
+        },
+        11 => RingEpoch(try!(f.uint(1))),
+        12 => Heartbeat(try!(f.uuid(1)), try!(f.bytes(2)).to_vec(),
+                        try!(read_pressure(try!(f.uint(3))))),
+        13 => ShutdownCluster(try!(f.uuid(1)), try!(f.optional_string(2)), try!(f.uint(3)),
+                              try!(read_proof(&f, 4, 5))),
+        14 => ShutdownReport(try!(f.uuid(1)), try!(read_phase(try!(f.uint(2))))),
+        15 => PreviewRequest(try!(f.uuid(1))),
+        16 => Preview(try!(read_cluster_info(&f))),
+        17 => BootstrapRequest(try!(f.uuid(1)), try!(f.strings(2))),
+        18 => BootstrapReply(try!(f.uuid(1)), try!(f.repeated(2)).iter()
+                                                             .map(|b| b.to_vec())
+                                                             .collect()),
+        19 => FeatureToggle(try!(f.uuid(1)), try!(f.string(2)), try!(f.bool(3)),
+                            try!(f.uint(4)), try!(read_proof(&f, 5, 6))),
+        20 => FeatureAck(try!(f.uuid(1)), try!(f.string(2)), try!(f.uint(3))),
+        21 => IHave(try!(f.uuid(1)), try!(f.uuids(2))),
+        22 => Graft(try!(f.uuid(1)), try!(f.uuids(2))),
+        23 => Prune(try!(f.uuid(1))),
+        24 => SyncDigest(try!(f.uuid(1)), try!(f.messages(2, |f| {
+            Ok((try!(f.uuid(1)), try!(f.uint(2)), try!(read_member_state(try!(f.uint(3))))))
+        })), try!(f.uuids(3)), try!(f.bool(4))),
+        25 => TreeNodes(try!(f.uuid(1)), try!(f.uint(2)) as u32, try!(f.messages(3, |f| {
+            Ok((try!(f.uint(1)) as u32, try!(f.uint(2))))
+        }))),
+        26 => LeafIds(try!(f.uuid(1)), try!(f.uints(2)).iter().map(|&leaf| leaf as u32).collect(),
+                      try!(f.uuids(3)), try!(f.bool(4))),
+        27 => Ping(try!(f.uuid(1)), try!(f.uint(2))),
+        28 => PingReq(try!(f.uuid(1)), try!(f.uuid(2)), try!(f.uint(3))),
+        29 => Ack(try!(f.uuid(1)), try!(f.uint(2))),
+        30 => Join(try!(read_peer(&try!(f.message(1)))), try!(f.string(2)),
+                   try!(f.uint(3)) as u32),
+        31 => JoinRejected(try!(f.uuid(1)), try!(f.string(2))),
+        32 => Leave(try!(f.uuid(1))),
+        33 => LeaveAck(try!(f.uuid(1))),
+        34 => Direct(try!(f.uuid(1)), try!(read_broadcast(&try!(f.message(2))))),
+        35 => KillNodeAt(try!(f.uuid(1)), try!(f.uuid(2)), try!(f.uint(3)),
+                         try!(read_proof(&f, 4, 5))),
+        36 => BootstrapBroadcasts(try!(f.uuid(1)), try!(f.messages(2, read_broadcast))),
+        _ => return Err(GossipError::new("unknown message", ProtocolMismatch))
+    })
+}
+
+fn write_broadcast(w: &mut Writer, wire: &WireBroadcast) {
+    w.uuid(1, &wire.id);
+    w.uuid(2, &wire.origin);
+    w.string(3, wire.namespace.as_slice());
+    w.uint(4, wire.hops as u64);
+    match wire.trace {
+        Some(ref trace) => w.string(5, trace.as_slice()),
+        None => {}
+    }
+    w.uint(6, wire.sent_at);
+    match wire.deadline {
+        Some(deadline) => w.uint(7, deadline),
+        None => {}
+    }
+    w.bool(8, wire.sealed);
+    w.bytes(9, wire.bytes.as_slice());
+}
+
+fn read_broadcast(f: &Fields) -> GossipResult<WireBroadcast> {
+    Ok(WireBroadcast {
+        id: try!(f.uuid(1)),
+        origin: try!(f.uuid(2)),
+        namespace: try!(f.string(3)),
+        hops: try!(f.uint(4)) as u32,
+        trace: try!(f.optional_string(5)),
+        sent_at: try!(f.uint(6)),
+        deadline: try!(f.optional_uint(7)),
+        sealed: try!(f.bool(8)),
+        bytes: try!(f.bytes(9)).to_vec()
+    })
+}
+
+/// See `proto/gossip.proto`.
+pub struct ProtobufCodec;
+
+impl Codec for ProtobufCodec {
+    fn encode_broadcast(&self, broadcast: &Broadcast) -> GossipResult<Vec<u8>> {
+        let mut w = Writer::new();
+        write_broadcast(&mut w, &WireBroadcast::new(broadcast));
+        Ok(w.buf)
+    }
+
+    fn decode_broadcast(&self, bytes: &[u8]) -> GossipResult<Broadcast> {
+        let wire = try!(read_broadcast(&try!(Fields::parse(bytes))));
+        wire.to_broadcast()
+    }
+
+    fn encode_message(&self, msg: &Message) -> GossipResult<Vec<u8>> {
+        let mut w = Writer::new();
+        write_message(&mut w, msg);
+        Ok(w.buf)
+    }
+
+    fn decode_message(&self, bytes: &[u8]) -> GossipResult<Message> {
+        read_message(&try!(Fields::parse(bytes)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::{Writer, Fields};
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    use broadcast::Broadcast;
+    use codec::{Codec, WireBroadcast};
+    use member::{Bootstrapping, Suspect, Dead};
+    use message::{Message, StatusChange, QuotaExceeded, JoinRequest, JoinSync, JoinSynced};
+    use message::{Digest, Advertise, KillNode, Services, Metadata, RingEpoch, Heartbeat};
+    use message::{ShutdownCluster, ShutdownReport, PreviewRequest, Preview, BootstrapRequest};
+    use message::{BootstrapReply, FeatureToggle, FeatureAck, Joined, StatusUpdate, Liveness};
+    use message::{Moved, IHave, Graft, Prune, SyncDigest, TreeNodes, LeafIds, Ping, PingReq, Ack};
+    use message::{Refuted, Join, JoinRejected, Leave, LeaveAck, Direct, KillNodeAt};
+    use message::BootstrapBroadcasts;
+    use merge::VersionVector;
+    use pressure::Elevated;
+    use preview::ClusterInfo;
+    use protocol::{Peer, Yellow};
+    use registry::Service;
+    use teardown::Stopped;
+    use topic::Capabilities;
+
+    #[test]
+    fn every_message_roundtrips() {
+        let id = Uuid::new_v4();
+        let peer = Peer::new(id, "10.0.0.1", 3444);
+        let mut metadata = HashMap::new();
+        metadata.insert("role".to_string(), "web".to_string());
+        let info = ClusterInfo {
+            name: "gossip".to_string(),
+            versions: vec!["0.1.0".to_string()],
+            size: 1,
+            health: Yellow,
+            members: vec![peer.clone()]
+        };
+        let updates = vec![Joined(peer.clone()), StatusUpdate(id, Bootstrapping),
+                           Liveness(id, Suspect), Moved(peer.clone(), 300),
+                           Refuted(peer.clone(), 301), LivenessAt(id, Dead, 302),
+                           Departed(id, 303)];
+
+        let msgs: Vec<Message> = vec![
+            StatusChange(id, Bootstrapping), QuotaExceeded(id), JoinRequest(peer.clone()),
+            JoinSync(id, vec![1u8, 2, 3]), JoinSynced(id), Digest(updates),
+            Advertise(id, Capabilities(7)), KillNode(id, id, Some(1)), KillNode(id, id, None),
+            Services(id, vec![Service::new("web", 80)]),
+            Metadata(id, metadata, VersionVector::new().bumped(id)), RingEpoch(0),
+            Heartbeat(id, vec![], Elevated),
+            ShutdownCluster(id, Some("web".to_string()), 1, vec![2u8, ..32]),
+            ShutdownCluster(id, None, 1, vec![]), ShutdownReport(id, Stopped), PreviewRequest(id),
+            Preview(info), BootstrapRequest(id, vec!["default/config".to_string()]),
+            BootstrapReply(id, vec![vec![1u8], vec![]]),
+            FeatureToggle(id, "enable-compression".to_string(), true, 1, vec![2u8]),
+            FeatureAck(id, "enable-compression".to_string(), 1),
+            IHave(id, vec![Uuid::new_v4(), Uuid::new_v4()]), Graft(id, vec![]), Prune(id),
+            SyncDigest(id, vec![(Uuid::new_v4(), 3, Dead)], vec![], false),
+            TreeNodes(id, 10, vec![(0, 0), (1023, 1 << 63)]), LeafIds(id, vec![7, 0], vec![], true),
+            Ping(id, 1), PingReq(id, Uuid::new_v4(), 2), Ack(id, 3),
+            Join(peer.clone(), "gossip".to_string(), 7), JoinRejected(id, "no".to_string()),
+            Leave(id), LeaveAck(Uuid::new_v4()),
+            Direct(id, WireBroadcast::new(&Broadcast::new(vec![1u8]).unwrap())),
+            KillNodeAt(id, Uuid::new_v4(), 1, vec![2u8, 0, 0, 0, 0, 0, 0, 1]),
+            BootstrapBroadcasts(id, vec![WireBroadcast::new(&Broadcast::new(vec![1u8]).unwrap())])
+        ];
+
+        for msg in msgs.move_iter() {
+            let bytes = ProtobufCodec.encode_message(&msg).unwrap();
+            assert_eq!(ProtobufCodec.decode_message(bytes.as_slice()).unwrap(), msg);
+        }
+
+        let mut broadcast = Broadcast::new(vec![1u8, 2, 3]).unwrap();
+        broadcast.set_deadline(1_000);
+        let bytes = ProtobufCodec.encode_broadcast(&broadcast).unwrap();
+        let decoded = ProtobufCodec.decode_broadcast(bytes.as_slice()).unwrap();
+        assert_eq!(WireBroadcast::new(&decoded), WireBroadcast::new(&broadcast));
+    }
+
+    #[test]
+    fn match_the_schema() {
+        // Field 11 (ring_epoch) holding field 1 (epoch) set to 300.
+        let bytes = vec![0x5au8, 0x03, 0x08, 0xac, 0x02];
+        assert_eq!(ProtobufCodec.encode_message(&RingEpoch(300)).unwrap(), bytes);
+
+        // Newer members may send fields we don't know of.
+        let newer = vec![0x5au8, 0x09, 0x08, 0xac, 0x02, 0xf8, 0x01, 0x01, 0x12, 0x01, 0x00];
+        assert_eq!(ProtobufCodec.decode_message(newer.as_slice()).unwrap(), RingEpoch(300));
+
+        // Even next to the message.
+        let mut w = Writer::new();
+        w.message(11, |w| w.uint(1, 300));
+        w.uint(90, 1);
+        assert_eq!(ProtobufCodec.decode_message(w.buf.as_slice()).unwrap(), RingEpoch(300));
+
+        // But not messages we don't know of.
+        assert!(ProtobufCodec.decode_message(&[0xfau8, 0x01, 0x00]).is_err());
+        assert!(ProtobufCodec.decode_message(&[0x5au8, 0x05, 0x08]).is_err());
+    }
+
+    #[test]
+    fn read_truncated_proofs() {
+        let id = Uuid::new_v4();
+        let tag = vec![1u8, 2, 3, 4, 5, 6, 7, 8, 9];
+        let bytes = ProtobufCodec.encode_message(&KillNodeAt(id, id, 1, tag.clone())).unwrap();
+        let fields = Fields::parse(bytes.as_slice()).unwrap().message(35).unwrap();
+        assert_eq!(fields.uint(4).unwrap(), 0x0102030405060708);
+
+        // Older nodes only send (and check) the first 8 bytes of the tag.
+        let mut w = Writer::new();
+        w.message(35, |w| {
+            w.uuid(1, &id);
+            w.uuid(2, &id);
+            w.uint(3, 1);
+            w.uint(4, 0x0102030405060708);
+        });
+        assert_eq!(ProtobufCodec.decode_message(w.buf.as_slice()).unwrap(),
+                   KillNodeAt(id, id, 1, tag.slice_to(8).to_vec()));
+    }
+}
@@ -1,12 +1,85 @@
 use std::task::TaskBuilder;
-use std::io::{TcpListener, TcpStream, Acceptor, Listener};
-use std::io::net::tcp::TcpAcceptor;
-use std::collections::{HashSet, HashMap};
+use std::collections::{HashMap, HashSet};
+use std::cmp;
+use std::mem;
+use std::io::Timer;
+use std::io::timer;
+use std::rand::task_rng;
+use std::comm::{Empty, Disconnected};
+use std::sync::Arc;
+use std::sync::atomics::{AtomicUint, SeqCst};
 
 use uuid::Uuid;
 use stream::{Stream, Response, SockAddr, Callback, BroadcastFrom};
 use broadcast::Broadcast;
-use result::{GossipResult, GossipError, NotListening};
+use result::{GossipResult, GossipError, NotListening, Unsupported, AccessDenied};
+use result::{NodeUnreachable, InvalidConfig, Overloaded, io_err};
+use member::{Status, Active, Draining, Alive, Dead, RELAY_KEY, CORDON_KEY};
+use member;
+use message::{Message, Join, JoinRequest, JoinSync, JoinRejected, Advertise};
+use message::{PreviewRequest, Preview};
+use event::{Event, EventBus, SubsystemRestarted, SubsystemFailed, SubscriberStalled};
+use config::Config;
+use namespace::DEFAULT_NAMESPACE;
+use subscriber::{Subscribers, Taken};
+use ack::AckTracker;
+use join::JoinProgress;
+use snapshot;
+use snapshot::Snapshot;
+use stats::{ProtocolStats, TopicStats};
+use topic::{TopicSettings, Uncompressed};
+use engine::{Engine, Input, Output, Received, Relayed, Publish, Listening, SetStatus, Evict};
+use engine::{ReceivedFrom, RelayedBy, Misbehaved};
+use engine::{TransportResult, Tick, Gossip, SendTo, Relay, Reconnect, Deliver, Emit, Persist};
+use engine::{Register, Deregister, SetMetadata, SetHeartbeat, WallClock, Transferred};
+use engine::{Persisted, Shutdown, InitiateShutdown, CachePeers, Disconnect, PublishBatch};
+use engine::PublishTo;
+use engine::{RelayBatch, RelayTo, SetFeature, SampledPressure, SetConfig, Committed};
+use engine::LeaveCluster;
+use engine::{AppendLog, RewriteLog, Logged, Replayed};
+use bandwidth::{BandwidthReport, Direction, Inbound, Outbound};
+use heartbeat::Heartbeat;
+use version::VersionReport;
+use registry::{Service, Endpoint};
+use discovery::{Discovery, Selector, EndpointChange};
+use cipher::{Cipher, Keyring};
+use supervisor::{Supervision, Restarted, Escalated};
+use backoff;
+use backoff::Retry;
+use resolve::{Dns, Resolver, SystemResolver};
+use clock;
+use identity;
+use peers;
+use wal;
+use cassette::Recorder;
+use journal::TopologyChange;
+use timeline::HealthRecord;
+use commit::CommitStats;
+use recent::BroadcastRecord;
+use latency::Histogram;
+use score::{PeerScore, Undecodable};
+use preview::ClusterInfo;
+use dump::StateDump;
+use typed::Topic;
+use ops::{Operation, Unacked};
+use admin::{Command, CommandResult, CommandQueue, EvictMember, Cordon, Uncordon, RotateKey};
+use admin::{Reconfigure, Applied, Rejected};
+use admin;
+use validate::{Fatal, Warning};
+use standby::{Shared, Cursor, Standby};
+use transport::{Transport, Connection, Accepted, Frame, Closed, Tcp, Udp, WebSocket};
+use tcp::TcpTransport;
+use udp::UdpTransport;
+use websocket::WebSocketTransport;
+use pcap::{Capture, CaptureLimit, PcapWriter};
+use merge::MetadataMerge;
+use detector::FailureDetector;
+use padding::PaddedTransport;
+use codec;
+use pressure;
+use pressure::PressureLevel;
+use codec::{Codec, JsonCodec, BroadcastPacket, MessagePacket, BatchPacket, WireBroadcast};
+use driver;
 
 /// A health represents the current state of the cluster. This will be extremely useful
 /// to ping the health of a cluster and determine the high-level status of it.
@@ -14,7 +87,7 @@ use result::{GossipResult, GossipError, NotListening};
 /// Green = Perfect state.
 /// Yellow = Nodes are failing, but the cluster is still operational.
 /// Red = Not good. Cluster might be completely dead.
-#[deriving(Show, PartialEq, Clone)]
+#[deriving(Show, PartialEq, Clone, Encodable, Decodable)]
 pub enum Health {
     Green,
     Yellow,
@@ -24,14 +97,18 @@ pub enum Health {
 /// An iterator that receives new broadcasts and iterates over them.
 pub struct Incoming {
     node_tx: Sender<BroadcastFrom>,
-    tx: Sender<(Broadcast, Stream)>,
-    rx: Receiver<(Broadcast, Stream)>,
+    tx: Sender<(Broadcast, Option<Stream>)>,
+    rx: Receiver<(Broadcast, Option<Stream>)>,
+    /// Where acks go, if the subscriber is expected to ack.
+    acks: Option<Sender<Uuid>>,
+    /// How many broadcasts were taken off `rx`, see `subscriber`.
+    taken: Taken,
     listening: bool
 }
 
 impl Incoming {
     pub fn new(node_tx: Sender<BroadcastFrom>, 
-               sender: Sender<Sender<(Broadcast, Stream)>>) -> Incoming {
+               sender: Sender<Sender<(Broadcast, Option<Stream>)>>) -> Incoming {
         let (tx, rx) = channel();
 
         sender.send(tx.clone());
@@ -40,9 +117,37 @@ impl Incoming {
             node_tx: node_tx,
             tx: tx,
             rx: rx,
+            acks: None,
+            taken: Arc::new(AtomicUint::new(0)),
             listening: true
         }
     }
+
+    pub fn with_acks(node_tx: Sender<BroadcastFrom>,
+                     sender: Sender<Sender<(Broadcast, Option<Stream>)>>,
+                     acks: Sender<Uuid>) -> Incoming {
+        let mut incoming = Incoming::new(node_tx, sender);
+        incoming.acks = Some(acks);
+        incoming
+    }
+
+    /// Like `next`, but `None` right away unless a broadcast is waiting.
+    pub fn try_next(&mut self) -> Option<Callback> {
+        match self.rx.try_recv() {
+            Ok((broadcast, stream)) if self.listening => Some(self.callback(broadcast, stream)),
+            _ => None
+        }
+    }
+
+    fn callback(&mut self, broadcast: Broadcast, stream: Option<Stream>) -> Callback {
+        self.taken.fetch_add(1, SeqCst);
+        let id = broadcast.id().clone();
+        let response = match self.acks {
+            Some(ref acks) => Response::with_acks(id, stream, acks.clone()),
+            None => Response::new(id, stream)
+        };
+        (broadcast, response)
+    }
 }
 
 impl Iterator<Callback> for Incoming {
@@ -52,139 +157,346 @@ impl Iterator<Callback> for Incoming {
     fn next(&mut self) -> Option<Callback> {
         if self.listening {
             let (broadcast, stream) = self.rx.recv();
-            let id = broadcast.id().clone();
-            Some((broadcast, Response::new(id, stream)))
+            Some(self.callback(broadcast, stream))
         } else {
             None
         }
     }
 }
 
-/// A dedicated Rust task to manage a single `TcpStream`. Each
-/// stream is then associated to a given peer (although the peer
-/// isn't always set right away).
-///
-/// This task then has channels to communicate with the main
-/// AcceptorTask which can communicate with other StreamTasks.
-struct StreamTask {
-    peer: Option<Peer>,
-    pub stream: Stream
+/// A local subscriber that has to ack every broadcast it's delivered.
+struct AckedSubscriber {
+    namespace: String,
+    tx: Sender<(Broadcast, Option<Stream>)>,
+    acks: Receiver<Uuid>,
+    tracker: AckTracker<Broadcast>
 }
 
-impl StreamTask {
-    pub fn new(stream: Stream) -> StreamTask {
-        StreamTask {
-            peer: None,
-            stream: stream
-        }
-    }
+/// Who to send a frame to: the member, and where to connect to it unless
+/// we are already (`None` for those we only know by the connection they
+/// came in on, i.e., a node previewing the cluster).
+type Target = (Uuid, Option<SockAddr>);
+
+/// What the node asks of the task moving it's bytes, see `TransportTask`.
+/// Frames come encoded by the node's `Codec`.
+enum TaskMessage {
+    /// A frame for each of the members.
+    SendMsg(Vec<Target>, Vec<u8>),
+    /// Frames for each of the members, one after the other. Broadcasts
+    /// relayed together come packed into them, see `codec::packed_frames`.
+    BatchMsg(Vec<Target>, Vec<Vec<u8>>),
+    /// The member (the id) introduced itself on the connection.
+    BindMsg(Connection, Uuid),
+    /// What we introduce ourselves with on the connections we open to
+    /// members, see `Engine::hello`.
+    HelloMsg(Vec<u8>),
+    /// Swap the connection to the member for one to the new address.
+    ReconnectMsg(Uuid, SockAddr),
+    /// Close the connection to the member.
+    DisconnectMsg(Uuid),
+    /// Close the connection, one somebody introduced themself on as a
+    /// member that's on another one already.
+    CloseMsg(Connection),
+    /// Ask the seed for a preview of it's cluster with the frame (a
+    /// `PreviewRequest`) without joining it, handing over the frame it
+    /// answers with.
+    PreviewMsg(SockAddr, Vec<u8>, Sender<Vec<u8>>),
+    /// Open a connection to the address, replying once it's up (or failed).
+    /// The frame (our handshake) goes out on it first.
+    ConnectMsg(SockAddr, Option<Vec<u8>>, Sender<GossipResult<()>>),
+    /// Start capturing the frames exchanged with a peer.
+    CaptureMsg(Capture),
+    /// Shut the transport down, and the task along with it.
+    ShutdownMsg
+}
 
-    pub fn incoming(&mut self) {
+impl TaskMessage {
+    /// How many frames it takes off the queue, see `Config::max_queued_frames`.
+    fn frames(&self) -> uint {
+        match *self {
+            SendMsg(..) => 1,
+            BatchMsg(_, ref frames) => frames.len(),
+            _ => 0
+        }
     }
 }
 
+/// What the task tells the node, in the order it happened.
+enum TaskEvent {
+    /// A whole frame read off the connection.
+    FrameOn(Connection, Vec<u8>),
+    /// We connected to the member (the id) to send it something.
+    Dialled(Connection, Uuid),
+    /// We connected to a seed to join through it.
+    DialledSeed(Connection),
+    /// The connection is gone, whichever side closed it.
+    HungUp(Connection)
+}
 
-/// A dedicated task to handle the incoming connections
-/// over a tcp socket (called streams). This task does
-/// not need to communicate with the streams themselves, that's
-/// done at another task that handles the core logic of the
-/// protocol.
-struct AcceptorTask {
-    acceptor: TcpAcceptor,
-    server_tx: Sender<TaskMessage>,
-    tx: Sender<Broadcast>,
-    rx: Receiver<Broadcast>
+/// Who introduced itself with the message, binding the connection it came
+/// in on to them. Joining, previewing and dialling members start with one.
+fn introduced(msg: &Message) -> Option<Uuid> {
+    match *msg {
+        Join(ref peer, _, _) | JoinRequest(ref peer) => Some(peer.id()),
+        JoinSync(id, _) | JoinRejected(id, _) | PreviewRequest(id) | Advertise(id, _) => Some(id),
+        _ => None
+    }
 }
 
-enum TaskMessage {
-    StreamMsg(Stream),
-    BroadcastMsg(Broadcast)
+/// Drives the transport (`Config::transport` unless another one is set,
+/// see `Node::set_transport`). Every frame read off a connection goes to the
+/// node as is, along with the connection. Frames for a member go out on the
+/// connection it introduced itself on, or one we open to it.
+struct TransportTask {
+    transport: Box<Transport + Send>,
+    connections: Vec<Connection>,
+    /// Who's on the other end of each connection, for captures.
+    addrs: HashMap<Connection, SockAddr>,
+    /// The connection to each member, see `BindMsg`.
+    members: HashMap<Uuid, Connection>,
+    hello: Option<Vec<u8>>,
+    /// The connections to seeds we're waiting on a preview from.
+    previews: HashMap<Connection, Sender<Vec<u8>>>,
+    captures: Vec<Capture>,
+    /// How long to wait on the transport before looking for messages again.
+    poll: u64,
+    /// The frames the node handed us that we didn't send yet, shared with
+    /// the node.
+    queued: Arc<AtomicUint>,
+    events_tx: Sender<TaskEvent>,
+    tx: Sender<TaskMessage>,
+    rx: Receiver<TaskMessage>
 }
 
-impl AcceptorTask {
-    pub fn new(host: &str, port: u16, server_tx: Sender<TaskMessage>,
-               inter_tx: Sender<Sender<Broadcast>>) -> AcceptorTask {
-        let listener = TcpListener::bind(host, port).unwrap();
+impl TransportTask {
+    pub fn new(transport: Box<Transport + Send>, poll: u64, queued: Arc<AtomicUint>,
+               events_tx: Sender<TaskEvent>) -> TransportTask {
         let (tx, rx) = channel();
 
-        inter_tx.send(tx.clone());
-
-        AcceptorTask {
-            acceptor: listener.listen().unwrap(),
-            server_tx: server_tx,
+        TransportTask {
+            transport: transport,
+            connections: Vec::new(),
+            addrs: HashMap::new(),
+            members: HashMap::new(),
+            hello: None,
+            previews: HashMap::new(),
+            captures: Vec::new(),
+            poll: poll,
+            queued: queued,
+            events_tx: events_tx,
             tx: tx,
             rx: rx
         }
     }
 
     pub fn run(&mut self) {
-        for stream in self.acceptor.incoming() {
-            match stream {
-                Ok(s) => {
-                    // Handle the joining here...
-                    let stream_send = s.clone();
-                    let server = self.server_tx.clone();
-                    spawn(proc() {
-                        let stream = Stream::new(stream_send);
-                        server.send(StreamMsg(stream.clone()));
-                        StreamTask::new(stream).incoming();
-                    });
+        loop {
+            loop {
+                match self.rx.try_recv() {
+                    Ok(ShutdownMsg) | Err(Disconnected) => return self.close(),
+                    Ok(msg) => {
+                        let frames = msg.frames();
+                        self.handle(msg);
+                        self.queued.fetch_sub(frames, SeqCst);
+                    },
+                    Err(Empty) => break
+                }
+            }
+
+            let now = clock::now();
+            self.captures.retain(|capture| !capture.is_done(now));
+
+            match self.transport.recv(self.poll) {
+                Ok(Some(Accepted(conn, addr))) => {
+                    self.connections.push(conn);
+                    self.addrs.insert(conn, addr);
                 },
+                Ok(Some(Frame(conn, frame))) => {
+                    self.capture(&conn, true, frame.as_slice());
+                    match self.previews.pop(&conn) {
+                        Some(tx) => {
+                            let _ = tx.send_opt(frame);
+                            self.hang_up(&conn);
+                        },
+                        None => if self.events_tx.send_opt(FrameOn(conn, frame)).is_err() {
+                            return self.close();
+                        }
+                    }
+                },
+                Ok(Some(Closed(conn))) => self.forget(&conn),
+                Ok(None) => {},
                 Err(e) => println!("Error: {}", e)
             }
         }
     }
-}
 
-struct ServerTask {
-    streams: HashMap<Peer, TcpStream>,
-    acceptor_tx: Sender<Broadcast>,
-    tx: Sender<TaskMessage>,
-    rx: Receiver<TaskMessage>
-}
+    fn handle(&mut self, msg: TaskMessage) {
+        match msg {
+            SendMsg(targets, frame) => {
+                for target in targets.iter() {
+                    self.send_to(target, frame.as_slice());
+                }
+            },
+            BatchMsg(targets, batch) => {
+                for target in targets.iter() {
+                    for frame in batch.iter() {
+                        self.send_to(target, frame.as_slice());
+                    }
+                }
+            },
+            BindMsg(conn, id) => {
+                // A live member's connection isn't up for grabs.
+                let bound = self.members.find_copy(&id).map_or(false, |c| {
+                    c != conn && self.connections.contains(&c)
+                });
+                if bound {
+                    self.hang_up(&conn);
+                } else if self.connections.contains(&conn) {
+                    self.members.insert(id, conn);
+                }
+            },
+            CloseMsg(conn) => self.hang_up(&conn),
+            HelloMsg(frame) => self.hello = Some(frame),
+            ReconnectMsg(id, addr) => {
+                match self.members.pop(&id) {
+                    Some(conn) => self.hang_up(&conn),
+                    None => {}
+                }
+                self.dial(id, &addr);
+            },
+            DisconnectMsg(id) => match self.members.pop(&id) {
+                Some(conn) => self.hang_up(&conn),
+                None => {}
+            },
+            PreviewMsg(addr, frame, tx) => match self.transport.connect(&addr) {
+                Ok(conn) => {
+                    self.connections.push(conn);
+                    self.addrs.insert(conn, addr);
+                    self.previews.insert(conn, tx);
+                    self.send_on(&conn, frame.as_slice());
+                },
+                // Nobody answers the node, it moves on to the next seed.
+                Err(e) => println!("Error: {}", e)
+            },
+            ConnectMsg(addr, handshake, tx) => {
+                let result = match self.transport.connect(&addr) {
+                    Ok(conn) => {
+                        self.connections.push(conn);
+                        self.addrs.insert(conn, addr);
+                        let _ = self.events_tx.send_opt(DialledSeed(conn));
+                        match handshake {
+                            Some(frame) => self.send_on(&conn, frame.as_slice()),
+                            None => {}
+                        }
+                        Ok(())
+                    },
+                    Err(e) => Err(e)
+                };
+                let _ = tx.send_opt(result);
+            },
+            CaptureMsg(capture) => self.captures.push(capture),
+            // Taken care of by `run`.
+            ShutdownMsg => {}
+        }
+    }
 
-impl ServerTask {
-    pub fn new(host: String, port: u16) -> ServerTask {
-        // Local channels that deal with broadcasts.
-        let (tx, rx) = channel();
+    /// Put the frame on the member's connection, connecting to it first if
+    /// there's none yet (and we know where to).
+    fn send_to(&mut self, target: &Target, frame: &[u8]) {
+        let (id, ref addr) = *target;
+        let bound = self.members.find(&id).map(|conn| *conn);
+        let conn = match bound {
+            Some(conn) => Some(conn),
+            None => addr.as_ref().and_then(|addr| self.dial(id, addr))
+        };
 
-        // Intermediate channels for the acceptor task.
-        // We'll use this to retrieve the sender of the
-        // acceptor task.
-        let (acceptor_tx, acceptor_rx) = channel();
+        match conn {
+            Some(conn) => self.send_on(&conn, frame),
+            None => {}
+        }
+    }
 
-        let server_tx = tx.clone();
-        spawn(proc() {
-            AcceptorTask::new(host.as_slice(), port, server_tx, acceptor_tx).run();
-        });
+    /// Connect to the member, introducing ourselves on the connection.
+    fn dial(&mut self, id: Uuid, addr: &SockAddr) -> Option<Connection> {
+        let conn = match self.transport.connect(addr) {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Error: {}", e);
+                return None;
+            }
+        };
 
-        ServerTask {
-            streams: HashMap::new(),
-            acceptor_tx: acceptor_rx.recv(),
-            tx: tx,
-            rx: rx
+        self.connections.push(conn);
+        self.addrs.insert(conn, addr.clone());
+        self.members.insert(id, conn);
+        let _ = self.events_tx.send_opt(Dialled(conn, id));
+        match self.hello.clone() {
+            Some(hello) => self.send_on(&conn, hello.as_slice()),
+            None => {}
         }
+        Some(conn)
     }
 
-    pub fn broadcast(&self, peer: &Peer) -> GossipResult<()> {
-        Ok(())
+    fn send_on(&mut self, conn: &Connection, frame: &[u8]) {
+        match self.transport.send(conn, frame) {
+            Ok(()) => self.capture(conn, false, frame),
+            Err(e) => println!("Error: {}", e)
+        }
     }
 
-    pub fn run(&mut self) {
-        for msg in self.rx.iter() {
-            match msg {
-                StreamMsg(stream) => {
-                    // self.streams.insert(peer, stream);
-                },
-                BroadcastMsg(broadcast) => {}
+    /// Close the connection ourselves.
+    fn hang_up(&mut self, conn: &Connection) {
+        self.transport.close(conn);
+        self.forget(conn);
+    }
+
+    fn forget(&mut self, conn: &Connection) {
+        self.connections.retain(|c| c != conn);
+        self.addrs.remove(conn);
+        self.previews.remove(conn);
+        let ids: Vec<Uuid> = self.members.iter()
+                                 .filter(|&(_, c)| c == conn)
+                                 .map(|(id, _)| *id)
+                                 .collect();
+        for id in ids.iter() {
+            self.members.remove(id);
+        }
+        let _ = self.events_tx.send_opt(HungUp(*conn));
+    }
+
+    /// Hand the frame to every capture of whoever is on the connection.
+    fn capture(&mut self, conn: &Connection, inbound: bool, frame: &[u8]) {
+        if self.captures.is_empty() {
+            return;
+        }
+
+        let addr = match self.addrs.find(conn) {
+            Some(addr) => addr.clone(),
+            None => return
+        };
+
+        let (now, wall) = (clock::now(), clock::wall());
+        for capture in self.captures.mut_iter() {
+            if *capture.peer() != addr {
+                continue;
+            }
+            match capture.frame(conn, inbound, frame, wall) {
+                Ok(()) => {},
+                Err(e) => println!("Error: {}", e)
             }
         }
+        self.captures.retain(|capture| !capture.is_done(now));
+    }
+
+    fn close(&mut self) {
+        self.transport.shutdown();
+        self.connections.clear();
     }
 }
 
 /// A peer describes a member within the cluster/network that
 /// is not the current one.
-#[deriving(Clone, Show, PartialEq, Hash, Eq)]
+#[deriving(Clone, Show, PartialEq, Hash, Eq, Encodable, Decodable)]
 pub struct Peer {
     id: Uuid,
     addr: SockAddr
@@ -204,6 +516,14 @@ impl Peer {
             addr: SockAddr::new("localhost", 3444)
         }
     }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn addr<'a>(&'a self) -> &'a SockAddr {
+        &self.addr
+    }
 }
 
 /// A `Node` is a single member within the gossip protocol. Nodes that
@@ -214,23 +534,95 @@ impl Peer {
 /// single leader. This has a significant trade-off and one must understand
 /// it before being able to use the system correctly.
 ///
-/// Node: Handle the state.
+/// Node: Drive the protocol (see `Engine`) and carry out what it asks for.
 /// Incoming: Handle incoming connections and broadcasts.
 pub struct Node {
-    /// Each node generates their own unique Uuid (v4) to uniquely
-    /// identify each other within the cluster. Instead of saying
-    /// "I'm node A", you would say "I'm node 123e4567-e89b-12d3-a456-426655440000".
-    id: Uuid,
-
-    /// A set of other members within the cluster. This forms the basic
-    /// information about each Node. This doesn't, however, contain connection
-    /// information and what not.
-    members: Vec<Peer>,
-    incoming_tx: Option<Sender<(Broadcast, Stream)>>,
+    /// The protocol state machine. Everything the node knows about the
+    /// cluster lives in here.
+    engine: Engine,
+    events: EventBus,
+    server_tx: Option<Sender<TaskMessage>>,
+    /// The frames handed to the transport task it didn't send yet, see
+    /// `Config::max_queued_frames`.
+    queued: Arc<AtomicUint>,
+    /// Used by `listen` instead of tcp when set, see `set_transport`.
+    transport: Option<Box<Transport + Send>>,
+    /// What the transport task read (or dialled, see `TaskEvent`), waiting
+    /// for the next tick.
+    task_rx: Option<Receiver<TaskEvent>>,
+    /// Who introduced itself on each connection, see `receive_frames`.
+    conns: HashMap<Connection, Uuid>,
+    /// The connections we opened to seeds to join through them, the only
+    /// ones snapshots are taken from.
+    seeds: HashSet<Connection>,
+    /// Local subscribers, each bound to a single namespace.
+    subscribers: Subscribers<(Broadcast, Option<Stream>)>,
+    acked: Vec<AckedSubscriber>,
+    /// What was left unacked before a restart, waiting on an acked
+    /// subscriber of it's namespace (see `incoming_acked`).
+    unacked: Vec<Broadcast>,
+    /// Set when what's unacked changed since it was last kept, see
+    /// `Config::ack_path`.
+    acks_changed: bool,
+    /// Set for nodes driven by the embedder's event loop, see `embedded`.
+    embedded: bool,
+    /// Network outputs waiting for the embedder to pick them up.
+    outbox: Vec<Output>,
+    /// Events waiting to be returned by `poll`.
+    pending: Vec<Event>,
+    /// Records inbound frames while debugging, see `Config::cassette_path`.
+    recorder: Option<Recorder>,
+    /// When to sample the resource pressure next, see
+    /// `Config::pressure_interval`.
+    next_sample: u64,
+    /// What we share with our warm standby, see `Config::standby_path`.
+    cursor: Option<Cursor>,
+    /// Set while we're the standby, see `take_over`.
+    standby: Option<Standby>,
+    /// The keys of the topics we encrypt end-to-end.
+    keys: Keyring,
+    /// How frames are encoded, see `set_codec`.
+    codec: Box<Codec + Send>,
+    /// Resolves the hosts we join through, see `set_resolver`.
+    dns: Dns,
+    /// The admin commands waiting to be applied, see `submit`.
+    commands: CommandQueue,
+    /// Who to tell about the endpoints that change, see `watch`.
+    watchers: Vec<(Discovery, Sender<EndpointChange>)>,
+    /// Reports about the internal tasks, see `supervise`.
+    supervisor_tx: Sender<Supervision>,
+    supervisor_rx: Receiver<Supervision>,
+    /// Set while we join through the seeds in the background, see `listen`.
+    rejoining: Option<Rejoining>,
     tx: Sender<(Peer, Broadcast)>,
     rx: Receiver<(Peer, Broadcast)>
 }
 
+/// Joining through the candidates (see `rejoin`) one at a time, until one
+/// of them hands us the membership or the `Config::join_backoff` is spent.
+struct Rejoining {
+    candidates: Vec<SockAddr>,
+    retry: Retry,
+    /// When to move on to the next candidate.
+    due: u64,
+    /// Set once the last candidate was asked, we give up at `due`.
+    spent: bool
+}
+
+/// How long (in milliseconds) to wait between ticks while rejoining.
+static POLL: u64 = 10;
+
+/// How large (in bytes) packed frames get over the other transports.
+static MAX_PACKED: uint = 64 * 1024;
+
+/// The broadcasts kept at `Config::ack_path`, see `Node::save_acks`.
+fn load_acks(path: &Path) -> GossipResult<Vec<Broadcast>> {
+    let records = try!(wal::replay(path));
+    Ok(records.iter().filter_map(|record| {
+        snapshot::decode_broadcast(record.as_slice()).ok().and_then(|wire| wire.to_broadcast().ok())
+    }).collect())
+}
+
 impl Node {
     /// Usage:
     ///
@@ -239,28 +631,272 @@ impl Node {
     /// let mut node = Node::new();
     /// ```
     pub fn new() -> Node {
+        Node::with_config(Config::new())
+    }
+
+    /// Fails if the configuration has fatal problems, see
+    /// `try_with_config`.
+    pub fn with_config(config: Config) -> Node {
+        match Node::try_with_config(config) {
+            Ok(node) => node,
+            Err(err) => fail!("{}", err)
+        }
+    }
+
+    /// A node with the configuration, unless it has fatal problems (see
+    /// `Config::validate`). Warnings are printed, but don't stop anything.
+    pub fn try_with_config(config: Config) -> GossipResult<Node> {
+        let problems = config.validate();
+        let fatal: Vec<String> = problems.iter()
+                                         .filter(|p| p.severity == Fatal)
+                                         .map(|p| format!("{}: {}", p.field, p.message))
+                                         .collect();
+        if !fatal.is_empty() {
+            return Err(GossipError::new(fatal.as_slice().connect(", "), InvalidConfig));
+        }
+
+        for problem in problems.iter().filter(|p| p.severity == Warning) {
+            println!("Warning: {}: {}", problem.field, problem.message);
+        }
+
         let (tx, rx) = channel();
+        let (supervisor_tx, supervisor_rx) = channel();
 
-        Node {
-            id: Uuid::new_v4(),
-            members: Vec::new(),
-            incoming_tx: None,
+        // Each node generates their own unique Uuid (v4, see `identity`) to uniquely
+        // identify each other within the cluster. Instead of saying
+        // "I'm node A", you would say "I'm node 123e4567-e89b-12d3-a456-426655440000".
+        let recorder = config.cassette_path.as_ref().and_then(|path| {
+            match Recorder::create(path) {
+                Ok(recorder) => Some(recorder),
+                Err(e) => {
+                    println!("Error: {}", e);
+                    None
+                }
+            }
+        });
+        // Standbys take on the primary's identity, and a restarted primary
+        // keeps it's own.
+        let shared = match config.standby_path {
+            Some(ref path) => match Shared::load(path) {
+                Ok(shared) => Some(shared),
+                Err(_) if !config.standby => None,
+                Err(e) => return Err(e)
+            },
+            None => None
+        };
+        let id = match shared {
+            Some(ref shared) => shared.id,
+            None => try!(identity::node_id(&config.node_id, &config.crypto))
+        };
+        let (cursor, standby) = match shared {
+            Some(ref shared) if config.standby => (None, Some(Standby::new(shared))),
+            _ if config.standby_path.is_some() => (Some(Cursor::new()), None),
+            _ => (None, None)
+        };
+
+        let unacked = match config.ack_path {
+            Some(ref path) => try!(load_acks(path)),
+            None => Vec::new()
+        };
+
+        // Standbys stay passive until they take over.
+        let mut config = config;
+        if config.standby {
+            config.relay = false;
+        }
+
+        let dns = Dns::new(box SystemResolver as Box<Resolver + Send>, config.dns_ttl,
+                           config.dns_negative_ttl);
+        let commands = CommandQueue::new(config.admin_timeout);
+        let mut engine = Engine::new(id, config);
+        engine.handle(WallClock(clock::wall()), clock::now());
+
+        Ok(Node {
+            engine: engine,
+            events: EventBus::new(),
+            server_tx: None,
+            queued: Arc::new(AtomicUint::new(0)),
+            transport: None,
+            task_rx: None,
+            conns: HashMap::new(),
+            seeds: HashSet::new(),
+            subscribers: Subscribers::new(),
+            acked: Vec::new(),
+            unacked: unacked,
+            acks_changed: false,
+            embedded: false,
+            outbox: Vec::new(),
+            pending: Vec::new(),
+            recorder: recorder,
+            next_sample: 0,
+            cursor: cursor,
+            standby: standby,
+            keys: Keyring::new(),
+            codec: box JsonCodec as Box<Codec + Send>,
+            dns: dns,
+            commands: commands,
+            watchers: Vec::new(),
+            supervisor_tx: supervisor_tx,
+            supervisor_rx: supervisor_rx,
+            rejoining: None,
             tx: tx,
             rx: rx
-        }
+        })
+    }
+
+    /// A node that never spawns a task nor touches a socket, for embedding
+    /// within an existing event loop. The embedder reads from the network
+    /// and feeds what it got to `handle_io`, puts whatever `outgoing`
+    /// returns on the wire and calls `poll` regularly.
+    ///
+    /// ```notrust
+    /// let mut node = Node::embedded(Config::new());
+    ///
+    /// loop {
+    ///     for msg in my_socket.read_messages() {
+    ///         node.handle_io(Received(msg), now());
+    ///     }
+    ///
+    ///     for event in node.poll(now()).move_iter() { ... }
+    ///     for output in node.outgoing().move_iter() { my_socket.write(output); }
+    /// }
+    /// ```
+    pub fn embedded(config: Config) -> Node {
+        let mut node = Node::with_config(config);
+        node.embedded = true;
+        node
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.engine.id()
+    }
+
+    /// Bumped every time we move to a new address, or refute the suspicion
+    /// of some member that we're failing (see `Refuted`).
+    pub fn incarnation(&self) -> u64 {
+        self.engine.incarnation()
     }
 
     /// Initialize the Node to listen on the specified address/port
     /// combination. This will bootup the appropriate tasks to allow
-    /// incoming connections and broadcasts.
-    #[unimplemented]
+    /// incoming connections and broadcasts. Over `Config::transport` unless
+    /// another transport is set, see `set_transport`.
+    ///
+    /// With `Config::seeds` we join the cluster through them (the members
+    /// cached from last time first, see `rejoin`) in the background, one
+    /// per `Config::join_backoff` retry until one of them hands us the
+    /// membership. Keep ticking meanwhile.
     pub fn listen(&mut self, host: &str, port: u16) -> GossipResult<()> {
-        let host = host.to_string();
+        try!(self.replay_log());
+        let addr = SockAddr::new(host, port);
+        let mut transport = match self.transport.take() {
+            Some(transport) => transport,
+            None => {
+                let config = self.engine.config();
+                match config.transport {
+                    Tcp => box TcpTransport::new(config.socket.clone(),
+                                                 config.restart_policy.clone(),
+                                                 self.supervisor_tx.clone())
+                        as Box<Transport + Send>,
+                    Udp => box UdpTransport::new(config.udp_retransmit_timeout,
+                                                 config.udp_max_retransmits,
+                                                 config.udp_mtu,
+                                                 config.udp_max_mtu,
+                                                 config.udp_mtu_probe_interval,
+                                                 config.udp_reassembly_timeout)
+                        as Box<Transport + Send>,
+                    WebSocket => box WebSocketTransport::new(config.socket.clone(),
+                                                             config.restart_policy.clone(),
+                                                             self.supervisor_tx.clone(),
+                                                             config.websocket_peers.clone(),
+                                                             config.websocket_path.as_slice())
+                        as Box<Transport + Send>
+                }
+            }
+        };
+
+        let config = self.engine.config();
+        if !config.padding_buckets.is_empty() {
+            transport = box PaddedTransport::new(transport, config.padding_buckets.clone(),
+                                                 config.chatter_interval)
+                as Box<Transport + Send>;
+        }
+
+        let bound = try!(transport.listen(&addr));
+        let poll = self.engine.config().tick_interval;
+        let hello = try!(codec::message_frame(&*self.codec, &self.engine.hello()));
+        let (events_tx, events_rx) = channel();
+        let (tx, rx) = channel();
+        // Whatever a previous task left unsent is gone with it.
+        self.queued = Arc::new(AtomicUint::new(0));
+        let queued = self.queued.clone();
 
         spawn(proc() {
-            ServerTask::new(host, port).run();
+            let mut server = TransportTask::new(transport, poll, queued, events_tx);
+            tx.send(server.tx.clone());
+            server.run();
         });
 
+        self.server_tx = Some(rx.recv());
+        self.task_rx = Some(events_rx);
+        self.send(HelloMsg(hello));
+        match self.run(Listening(bound)) {
+            Ok(()) => {},
+            Err(e) => {
+                self.shutdown();
+                return Err(e);
+            }
+        }
+
+        let seeds = self.engine.config().seeds.clone();
+        if !seeds.is_empty() {
+            let now = clock::now();
+            let backoff = self.engine.config().join_backoff.clone();
+            self.rejoining = Some(Rejoining {
+                candidates: self.candidates(seeds.as_slice()),
+                retry: backoff.start(now),
+                due: now,
+                spent: false
+            });
+            self.keep_rejoining(now);
+        }
+        Ok(())
+    }
+
+    /// Encode what we put on the wire with the codec instead of
+    /// `JsonCodec`. Every member has to use the same one.
+    pub fn set_codec(&mut self, codec: Box<Codec + Send>) {
+        self.codec = codec;
+    }
+
+    /// Move our bytes with the transport instead of tcp once we `listen`,
+    /// i.e., udp or an in-memory one for tests. Has no effect afterwards.
+    pub fn set_transport(&mut self, transport: Box<Transport + Send>) {
+        self.transport = Some(transport);
+    }
+
+    /// Resolve the hosts we join through with the resolver instead of the
+    /// system's, i.e., one asking a DNS server directly. Cached all the
+    /// same, see `resolve`.
+    pub fn set_resolver(&mut self, resolver: Box<Resolver + Send>) {
+        let (ttl, negative_ttl) = {
+            let config = self.engine.config();
+            (config.dns_ttl, config.dns_negative_ttl)
+        };
+        self.dns = Dns::new(resolver, ttl, negative_ttl);
+    }
+
+    /// Write the next frames exchanged with the peer (at the address the
+    /// transport knows it by) to a pcapng file, until the limit. Each
+    /// frame comes with a comment on what we made of it, see `pcap`.
+    /// The file is created right away, so it's errors surface here.
+    pub fn capture(&self, peer: &SockAddr, path: &Path, limit: CaptureLimit) -> GossipResult<()> {
+        if self.server_tx.is_none() {
+            return Err(GossipError::new("the node isn't listening", NotListening));
+        }
+
+        let writer = try!(PcapWriter::create(path));
+        self.send(CaptureMsg(Capture::new(peer.clone(), writer, limit, clock::now())));
         Ok(())
     }
 
@@ -268,59 +904,2006 @@ impl Node {
     /// creates their own cluster automatically. Joining multiple nodes together
     /// is an explicit process. The peer node doesn't need to be the same one,
     /// but it's not a bad idea.
-    #[unimplemented]
+    ///
+    /// We introduce ourselves with our cluster's name (see
+    /// `Config::cluster_name`), a peer of another cluster turns us away with
+    /// a `JoinRefused` event. Connecting is retried per `Config::join_backoff`.
     pub fn join(&mut self, host: &str, port: u16) -> GossipResult<()> {
+        let addr = SockAddr::new(host, port);
+        let backoff = self.engine.config().join_backoff.clone();
+        backoff::retry(&backoff, || self.connect(&addr))
+    }
+
+    fn connect(&mut self, addr: &SockAddr) -> GossipResult<()> {
+        let server_tx = match self.server_tx {
+            Some(ref tx) => tx.clone(),
+            None => return Ok(())
+        };
+
+        // Resolved here rather than by the transport, so slow DNS holds up
+        // nobody but us.
+        let addr = try!(self.dns.resolve(addr, clock::now()));
+        let handshake = match self.engine.handshake() {
+            Some(msg) => Some(try!(codec::message_frame(&*self.codec, &msg))),
+            None => None
+        };
+        let (tx, rx) = channel();
+        if server_tx.send_opt(ConnectMsg(addr, handshake, tx)).is_err() {
+            return Err(GossipError::new("the server is gone", NotListening));
+        }
+
+        match rx.recv_opt() {
+            Ok(result) => result,
+            Err(()) => Err(GossipError::new("the server is gone", NotListening))
+        }
+    }
+
+    /// Look at the cluster the seeds are part of without joining it: it's
+    /// name, the versions it runs, it's size and health, and every member.
+    /// Seeds are tried in order until one answers within
+    /// `Config::preview_timeout`. Nothing about us changes, and the cluster
+    /// never learns of us beyond the seed that answered.
+    pub fn preview_join(&self, seeds: &[SockAddr]) -> GossipResult<ClusterInfo> {
+        if self.server_tx.is_none() {
+            return Err(GossipError::new("the node isn't listening", NotListening));
+        }
+
+        let request = try!(codec::message_frame(&*self.codec, &PreviewRequest(self.engine.id())));
+        let mut timer = try!(Timer::new().map_err(io_err));
+        for seed in seeds.iter() {
+            let seed = match self.dns.resolve(seed, clock::now()) {
+                Ok(seed) => seed,
+                Err(_) => continue
+            };
+            let (tx, rx) = channel();
+            self.send(PreviewMsg(seed, request.clone(), tx));
+
+            let timeout = timer.oneshot(self.engine.config().preview_timeout);
+            select! {
+                frame = rx.recv_opt() => match frame {
+                    Ok(frame) => match codec::decode_frame(&*self.codec, frame.as_slice()) {
+                        Ok(MessagePacket(Preview(info))) => return Ok(info),
+                        _ => {}
+                    },
+                    Err(()) => {}
+                },
+                () = timeout.recv() => {}
+            }
+        }
+
+        Err(GossipError::new("none of the seeds answered", NodeUnreachable))
+    }
+
+    /// Join the cluster again after a restart: through the members cached
+    /// at `Config::peer_cache_path` first, falling back to the `seeds` only
+    /// if none of them works out. Seeds might have left the cluster since
+    /// (or been replaced altogether), the cached members are our best bet.
+    /// Every retry (see `Config::join_backoff`) goes through all of them
+    /// again. One only counts once it handed us the membership, within
+    /// `Config::join_timeout`; it's ticking the node until then.
+    pub fn rejoin(&mut self, seeds: &[SockAddr]) -> GossipResult<()> {
+        let candidates = self.candidates(seeds);
+        let backoff = self.engine.config().join_backoff.clone();
+        self.rejoining = None;
+
+        backoff::retry(&backoff, || {
+            let mut result = Err(GossipError::new("no peers nor seeds to join", NodeUnreachable));
+            for addr in candidates.iter() {
+                result = match self.connect(addr) {
+                    Ok(()) => self.await_membership(),
+                    Err(e) => Err(e)
+                };
+                if result.is_ok() {
+                    break;
+                }
+            }
+            result
+        })
+    }
+
+    /// The members cached from last time, then the seeds.
+    fn candidates(&self, seeds: &[SockAddr]) -> Vec<SockAddr> {
+        let cached = match self.engine.config().peer_cache_path {
+            Some(ref path) => peers::load(path).unwrap_or(Vec::new()),
+            None => Vec::new()
+        };
+        peers::candidates(cached.as_slice(), seeds)
+    }
+
+    fn has_joined(&self) -> bool {
+        self.engine.members().len() > 0
+    }
+
+    fn await_membership(&mut self) -> GossipResult<()> {
+        let deadline = clock::now() + self.engine.config().join_timeout;
+        while !self.has_joined() {
+            if clock::now() >= deadline {
+                return Err(GossipError::new("no membership from the member we joined through",
+                                            NodeUnreachable));
+            }
+            timer::sleep(POLL);
+            self.tick();
+        }
         Ok(())
     }
 
-    /// Shutdown all the running tasks that are listening to new broadcasts
-    /// and incoming connections. This will send one last broadcast
-    /// to the current cluster notifying all other nodes of the shutdown.
-    ///
-    /// Afterwhich tasks will shutdown and the node will be terminated.
-    #[unimplemented]
-    pub fn shutdown(&mut self) {}
+    /// Join through the next candidate once it's time, see `listen`.
+    fn keep_rejoining(&mut self, now: u64) {
+        if self.has_joined() {
+            self.rejoining = None;
+        }
+        let addr = match self.rejoining {
+            Some(ref rejoining) if now >= rejoining.due && rejoining.spent => None,
+            Some(ref rejoining) if now >= rejoining.due => {
+                let next = rejoining.retry.failures() % rejoining.candidates.len();
+                Some(rejoining.candidates.get(next).clone())
+            },
+            _ => return
+        };
+        let addr = match addr {
+            Some(addr) => addr,
+            None => {
+                println!("Error: no peer nor seed handed us the membership");
+                self.rejoining = None;
+                return;
+            }
+        };
 
-    /// Create a new `Incoming` iterator that iterates over newly received
-    /// broadcasts that the user can handle.
-    ///
-    /// Usage:
-    ///
-    /// ```notrust
-    /// use gossip::Node;
-    /// spawn(proc() {
-    ///     let mut node = Node::new();
-    ///
-    ///     node.listen("localhost", 4888).unwrap();
+        let _ = self.connect(&addr);
+        let timeout = self.engine.config().join_timeout;
+        match self.rejoining {
+            Some(ref mut rejoining) => match rejoining.retry.next_delay(&mut task_rng(), now) {
+                Some(delay) => rejoining.due = now + delay,
+                // The last one still gets to answer.
+                None => {
+                    rejoining.spent = true;
+                    rejoining.due = now + timeout;
+                }
+            },
+            None => {}
+        }
+    }
+
+    /// Stop the transport task: it stops accepting connections and closes
+    /// every open one. The members only notice once they take us for dead,
+    /// `leave` tells them right away.
+    pub fn shutdown(&mut self) {
+        match self.server_tx.take() {
+            // It's gone already if the transport failed on it.
+            Some(tx) => { let _ = tx.send_opt(ShutdownMsg); },
+            None => {}
+        }
+        self.task_rx = None;
+    }
+
+    /// Leave the cluster on purpose: drain, gossip whatever updates are
+    /// still pending and tell every member we're leaving, so they drop us
+    /// right away rather than once they take us for dead. The node shuts
+    /// down once every live member acked it (or after
+    /// `Config::leave_timeout`), keep ticking until then.
+    pub fn leave(&mut self) {
+        let _ = self.run(LeaveCluster);
+    }
+
+    /// Ask every member (or only those with the `role` in their metadata) to
+    /// drain and shut down, one after the other. Needs the admin key, as
+    /// does every member for accepting the command. Watch `events` for
+    /// `ShutdownProgress` and shut this node down once everybody is done.
+    pub fn shutdown_cluster(&mut self, role: Option<&str>) -> GossipResult<()> {
+        if self.engine.config().admin_key.is_none() {
+            return Err(GossipError::new("shutting the cluster down needs the admin key",
+                                        AccessDenied));
+        }
+
+        self.run(InitiateShutdown(role.map(|r| r.to_string())))
+    }
+
+    /// Flip the named protocol toggle (i.e., "enable-compression") on or
+    /// off for every member. Needs the admin key, as does every member for
+    /// accepting the command. Watch `events` for `FeatureRollout` to see
+    /// how many members acked it so far.
+    pub fn set_feature(&mut self, name: &str, enabled: bool) -> GossipResult<()> {
+        if self.engine.config().admin_key.is_none() {
+            return Err(GossipError::new("flipping a feature toggle needs the admin key",
+                                        AccessDenied));
+        }
+
+        self.run(SetFeature(name.to_string(), enabled))
+    }
+
+    /// Whether the toggle is on, see `set_feature`.
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.engine.features().is_enabled(name)
+    }
+
+    /// Mark this node as draining ahead of a restart. The node keeps acking
+    /// probes and relaying broadcasts like normal, but peers stop counting
+    /// it toward quorum and subscribers receive a `MemberDraining` event so
+    /// they can migrate work off of it.
+    pub fn drain(&mut self) {
+        let _ = self.run(SetStatus(Draining));
+    }
+
+    /// Exit the draining state and go back to being a regular member.
+    pub fn undrain(&mut self) {
+        let _ = self.run(SetStatus(Active));
+    }
+
+    /// Bleed traffic off this node ahead of an investigation or a restart.
+    /// It stays a member and keeps receiving broadcasts, but peers move it
+    /// to their lazy set and stop grafting it, so no new payloads are routed
+    /// through it. Advertised through the metadata, see `CORDON_KEY`.
+    pub fn cordon(&mut self) {
+        let _ = self.run(SetMetadata(CORDON_KEY.to_string(), "true".to_string()));
+    }
+
+    /// Let peers route payloads through this node again.
+    pub fn uncordon(&mut self) {
+        let _ = self.run(SetMetadata(CORDON_KEY.to_string(), "false".to_string()));
+    }
+
+    pub fn is_cordoned(&self) -> bool {
+        member::cordoned(self.engine.metadata())
+    }
+
+    pub fn status(&self) -> Status {
+        self.engine.status()
+    }
+
+    pub fn health(&self) -> Health {
+        self.engine.health()
+    }
+
+    /// Send an application broadcast out to the cluster. When the minority
+    /// lockdown is enabled and we can't see a quorum, this fails with
+    /// `NotQuorate` rather than letting both sides of a split-brain diverge.
+    /// While the transport is too far behind on what we handed it already,
+    /// it fails with `Overloaded` (see `Config::max_queued_frames`).
     ///
-    ///     for (broadcast, mut res) in node.incoming() {
-    ///         println!("Broadcast ...");
-    ///         // ...
-    ///     }
-    /// });
-    /// ```
-    pub fn incoming(&mut self) -> Incoming {
-        let (tx, rx) = channel();
-        let incoming = Incoming::new(self.tx.clone(), tx);
+    /// Local subscribers always receive our own broadcasts. Without any
+    /// peers that's all there is to it, so a single-node cluster never
+    /// touches the transport and works without even listening.
+    pub fn broadcast(&mut self, mut broadcast: Broadcast) -> GossipResult<()> {
+        self.keys.seal(&mut broadcast);
+        try!(self.engine.can_publish(&broadcast));
+        try!(self.room_for(1));
+        self.run(Publish(broadcast))
+    }
 
-        self.incoming_tx = Some(rx.recv());
+    /// Publish a burst of related broadcasts as a unit: either every one of
+    /// them is accepted, or none is (i.e., while we're locked down, or the
+    /// transport has no room for all of them). They go out packed together
+    /// instead of one frame each.
+    pub fn broadcast_batch(&mut self, mut batch: Vec<Broadcast>) -> GossipResult<()> {
+        for broadcast in batch.mut_iter() {
+            self.keys.seal(broadcast);
+        }
 
-        incoming
+        for broadcast in batch.iter() {
+            try!(self.engine.can_publish(broadcast));
+        }
+        try!(self.room_for(batch.len()));
+        self.run(PublishBatch(batch))
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    /// Like `broadcast`, only the member `id` gets it (none of it's peers),
+    /// i.e., to answer whoever asked.
+    pub fn broadcast_to(&mut self, id: Uuid, mut broadcast: Broadcast) -> GossipResult<()> {
+        self.keys.seal(&mut broadcast);
+        try!(self.engine.can_publish(&broadcast));
+        try!(self.room_for(1));
+        self.run(PublishTo(id, broadcast))
+    }
 
-    #[test]
-    fn empty_member_set() {
-        let mut node = Node::new();
-        assert_eq!(node.members.len(), 0);
+    /// Encrypt the broadcasts of `topic` (namespace qualified, see
+    /// `Broadcast::topic`) end-to-end. Every subscriber needs the same key,
+    /// members without it relay the ciphertext but never deliver it.
+    pub fn set_topic_key(&mut self, topic: &str, cipher: Box<Cipher + Send>) {
+        self.keys.insert(topic, cipher);
     }
 
-    #[test]
-    fn bind_listening() {
-        let mut node = Node::new();
+    /// Like `set_topic_key`, with the cipher made from the key by the crypto
+    /// provider (see `Config::crypto`). Fails if it doesn't have any.
+    pub fn set_topic_secret(&mut self, topic: &str, key: &[u8]) -> GossipResult<()> {
+        match self.engine.config().crypto.cipher(key) {
+            Some(cipher) => {
+                self.set_topic_key(topic, cipher);
+                Ok(())
+            },
+            None => Err(GossipError::new("the crypto provider has no cipher", Unsupported))
+        }
+    }
+
+    pub fn remove_topic_key(&mut self, topic: &str) {
+        self.keys.remove(topic);
+    }
+
+    /// Handle a broadcast received from another member that we're supposed
+    /// to relay. Returns whether it was accepted.
+    pub fn handle_broadcast(&mut self, broadcast: Broadcast) -> bool {
+        let now = clock::now();
+        let outputs = driver::handle(&mut self.engine, Relayed(broadcast), now);
+        let accepted = outputs.iter().any(|output| match *output {
+            Deliver(_) => true,
+            _ => false
+        });
+
+        let _ = self.perform(outputs, now);
+        accepted
+    }
+
+    /// The wire settings to use when sending a broadcast of `topic` (namespace
+    /// qualified, see `Broadcast::topic`) to the member `id`, negotiated
+    /// against what it advertised. Unknown members only get the baseline.
+    pub fn wire_settings(&self, topic: &str, id: &Uuid) -> TopicSettings {
+        self.engine.wire_settings(topic, id)
+    }
+
+    /// Statistics on how efficient the broadcast tree currently is.
+    pub fn stats(&self) -> ProtocolStats {
+        self.engine.stats()
+    }
+
+    /// What each topic costs us (namespace qualified, see
+    /// `Broadcast::topic`): how much of it we delivered, deduped, let
+    /// expire or shed because the origin went over it's quota. Sorted by
+    /// topic.
+    pub fn topic_stats(&self) -> Vec<(String, TopicStats)> {
+        self.engine.topic_stats()
+    }
+
+    /// The latest changes to the broadcast tree (up to
+    /// `Config::journal_size`), oldest first: who moved between the eager
+    /// and the lazy set and why. Look here first when dissemination got
+    /// slow at some point.
+    pub fn topology_journal(&self) -> Vec<TopologyChange> {
+        self.engine.topology_journal()
+    }
+
+    /// How the snapshots we persisted were batched (see
+    /// `Config::commit_max_delay`): how many writes they took, how many
+    /// snapshots each was on behalf of and how long they took to reach the
+    /// disk.
+    pub fn commit_stats(&self) -> CommitStats {
+        self.engine.commit_stats()
+    }
+
+    /// Same for the broadcasts we logged (see `Config::wal_path`), how many
+    /// each write was on behalf of.
+    pub fn log_stats(&self) -> CommitStats {
+        self.engine.log_stats()
+    }
+
+    /// Our health along with how many members we knew alive, suspect and
+    /// dead, every tick either changed on (up to
+    /// `Config::health_history_size` of them), oldest first. Shows a node
+    /// flapping to red hours after the health events went by.
+    pub fn health_history(&self) -> Vec<HealthRecord> {
+        self.engine.health_history()
+    }
+
+    /// Up to `limit` of the latest broadcasts we saw (of the namespace
+    /// qualified topic, any without one), newest first: who sent them,
+    /// when we saw them, how large they were and whether they made it to
+    /// our subscribers. Goes back `Config::recent_broadcasts` at most.
+    pub fn recent_broadcasts(&self, topic: Option<&str>, limit: uint) -> Vec<BroadcastRecord> {
+        self.engine.recent_broadcasts(topic, limit)
+    }
+
+    /// The score of every member that misbehaved recently (the others are
+    /// at `score::MAX_SCORE`), lowest first. Transports report decode errors
+    /// and protocol violations with `handle_io(Misbehaved(..))`.
+    pub fn peer_scores(&self) -> Vec<(Uuid, PeerScore)> {
+        let mut scores = self.engine.peer_scores();
+        scores.sort_by(|a, b| a.ref1().score.cmp(&b.ref1().score));
+        scores
+    }
+
+    /// Every protocol operation in flight: joiners being synced (or waiting
+    /// to be), eviction ballots, pending grafts, the cluster-wide shutdown
+    /// we're part of and the broadcasts acked subscribers haven't acked yet.
+    /// Look here when the cluster seems stuck.
+    pub fn operations(&self) -> Vec<Operation> {
+        let mut ops = self.engine.operations();
+
+        for sub in self.acked.iter() {
+            for (id, deliveries, due) in sub.tracker.pending().move_iter() {
+                ops.push(Unacked(id, sub.namespace.clone(), deliveries, due));
+            }
+        }
+
+        ops
+    }
+
+    /// A histogram of how long (in milliseconds, on the origin's clock) the
+    /// sampled broadcasts took to reach us, see `Config::latency_sampling`.
+    /// Comparing them across nodes shows how far out dissemination gets
+    /// slow.
+    pub fn delivery_latency(&self) -> Histogram {
+        self.engine.delivery_latency()
+    }
+
+    /// How many members we take for alive, ourselves included.
+    pub fn alive_members(&self) -> uint {
+        self.engine.members().iter().filter(|member| member.state == Alive).count() + 1
+    }
+
+    /// Whether enough voting members (ourselves included) are reachable to
+    /// satisfy the configured quorum. Always true without a quorum.
+    pub fn is_quorate(&self) -> bool {
+        self.engine.is_quorate()
+    }
+
+    /// Subscribe to the events emitted by this node, such as members
+    /// starting or stopping to drain.
+    pub fn events(&mut self) -> Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Handle a protocol message received from another member.
+    pub fn handle(&mut self, msg: Message) {
+        let _ = self.run(Received(msg));
+    }
+
+    /// Ask the cluster to evict a member. With the admin key configured the
+    /// request carries a proof and applies right away; otherwise it counts
+    /// as our vote. Should the provider fail to make the proof nothing is
+    /// sent, watch `events` for `AdminFailed`.
+    pub fn evict(&mut self, target: Uuid) {
+        let _ = self.run(Evict(target));
+    }
+
+    /// Queue up an admin command, applied on `tick` once every command
+    /// submitted before it is done. The receiver gets told how it went:
+    /// `Applied`, `Rejected` (and why) or `TimedOut` past
+    /// `Config::admin_timeout`.
+    pub fn submit(&mut self, command: Command) -> Receiver<CommandResult> {
+        self.commands.submit(command, clock::now())
+    }
+
+    /// Periodic housekeeping. This needs to be called regularly (every
+    /// few dozen milliseconds) by whoever drives the node.
+    pub fn tick(&mut self) {
+        self.housekeeping(clock::now());
+    }
+
+    /// Like `tick` for embedded nodes, with the monotonic time (in
+    /// milliseconds) given by the embedder. Returns the events emitted since
+    /// the last poll.
+    pub fn poll(&mut self, now: u64) -> Vec<Event> {
+        self.housekeeping(now);
+        mem::replace(&mut self.pending, Vec::new())
+    }
+
+    /// Feed something the embedder received from the network (or learned
+    /// about it) to the protocol.
+    pub fn handle_io(&mut self, input: Input, now: u64) -> GossipResult<()> {
+        let outputs = driver::handle(&mut self.engine, input, now);
+        self.perform(outputs, now)
+    }
+
+    /// Transports (and embedders) hand every frame they read off the wire
+    /// here before decoding it. Does nothing unless `Config::cassette_path`
+    /// is set.
+    pub fn record_frame(&mut self, frame: &[u8], now: u64) {
+        let failed = match self.recorder {
+            Some(ref mut recorder) => recorder.record(now, frame).is_err(),
+            None => false
+        };
+
+        // Debugging mustn't get in the way, stop recording instead.
+        if failed {
+            self.recorder = None;
+        }
+    }
+
+    /// Take whatever the embedder has to put on the wire: the `Gossip`,
+    /// `SendTo`, `Relay` and `Reconnect` outputs. Always empty unless
+    /// `embedded`.
+    pub fn outgoing(&mut self) -> Vec<Output> {
+        mem::replace(&mut self.outbox, Vec::new())
+    }
+
+    fn housekeeping(&mut self, now: u64) {
+        self.engine.handle(WallClock(clock::wall()), now);
+        let outputs = driver::handle(&mut self.engine, Tick, now);
+        let _ = self.perform(outputs, now);
+        self.sample_pressure(now);
+        self.receive_frames(now);
+        self.redeliver(now);
+        self.check_stalls(now);
+        self.supervision(now);
+        self.run_commands(now);
+        self.refresh_watchers();
+        self.share();
+        self.keep_rejoining(now);
+    }
+
+    /// Apply the queued admin commands, in order.
+    fn run_commands(&mut self, now: u64) {
+        loop {
+            // An eviction is done once the member is dead, however long the
+            // votes take.
+            let evicted = match self.commands.running() {
+                Some(&EvictMember(ref target)) => self.is_dead(target),
+                _ => false
+            };
+            if evicted {
+                self.commands.finish(Applied);
+            }
+
+            let command = match self.commands.next(now) {
+                Some(command) => command,
+                None => return
+            };
+            match self.apply_command(command) {
+                Some(result) => self.commands.finish(result),
+                None => {}
+            }
+        }
+    }
+
+    /// `None` for an eviction waiting on the votes of the other members.
+    fn apply_command(&mut self, command: Command) -> Option<CommandResult> {
+        let result = match command {
+            EvictMember(target) => {
+                if target == self.engine.id() {
+                    Err("we don't evict ourselves".to_string())
+                } else if self.engine.members().find(&target).is_none() {
+                    Err("no such member".to_string())
+                } else if self.is_dead(&target) {
+                    Err("the member is dead already".to_string())
+                } else {
+                    self.evict(target);
+                    return None;
+                }
+            },
+            Cordon => Ok(self.cordon()),
+            Uncordon => Ok(self.uncordon()),
+            RotateKey(topic, key) => {
+                self.set_topic_secret(topic.as_slice(), key.as_slice())
+                    .map_err(|e| e.desc().to_string())
+            },
+            Reconfigure(config) => self.reconfigure(config)
+        };
+
+        Some(match result {
+            Ok(()) => Applied,
+            Err(why) => Rejected(why)
+        })
+    }
+
+    fn reconfigure(&mut self, config: Config) -> Result<(), String> {
+        let fatal: Vec<String> = config.validate()
+                                       .iter()
+                                       .filter(|p| p.severity == Fatal)
+                                       .map(|p| format!("{}: {}", p.field, p.message))
+                                       .collect();
+        if !fatal.is_empty() {
+            return Err(fatal.as_slice().connect(", "));
+        }
+
+        let restart = admin::needs_restart(self.engine.config(), &config);
+        if !restart.is_empty() {
+            return Err(format!("only applies on restart: {}", restart.as_slice().connect(", ")));
+        }
+        self.run(SetConfig(config)).map_err(|e| e.desc().to_string())
+    }
+
+    fn is_dead(&self, id: &Uuid) -> bool {
+        self.engine.members().find(id).map_or(true, |member| member.state == Dead)
+    }
+
+    /// Sample our host's resource pressure, when it's time to.
+    fn sample_pressure(&mut self, now: u64) {
+        let interval = match self.engine.config().pressure_interval {
+            Some(interval) if now >= self.next_sample => interval,
+            _ => return
+        };
+        self.next_sample = now + interval;
+
+        match pressure::sample() {
+            Some(sample) => {
+                let level = sample.level();
+                let outputs = driver::handle(&mut self.engine, SampledPressure(level), now);
+                let _ = self.perform(outputs, now);
+            },
+            None => {}
+        }
+    }
+
+    /// Share our state with our standby, or catch up with the primary's if
+    /// we're the standby.
+    fn share(&mut self) {
+        let path = match self.engine.config().standby_path {
+            Some(ref path) => path.clone(),
+            None => return
+        };
+
+        match self.standby {
+            Some(ref mut standby) => {
+                // The primary might be writing it, or gone.
+                match Shared::load(&path) {
+                    Ok(shared) => standby.mirror(&shared),
+                    Err(_) => {}
+                }
+                return;
+            },
+            None => {}
+        }
+
+        let namespaces = self.namespaces();
+        let shared = match self.cursor {
+            Some(ref mut cursor) => cursor.share(self.engine.id(), namespaces),
+            None => None
+        };
+
+        match shared.map(|shared| shared.save(&path)) {
+            Some(Err(e)) => println!("Error: {}", e),
+            _ => {}
+        }
+    }
+
+    /// Every namespace we have subscribers in.
+    fn namespaces(&self) -> Vec<String> {
+        let mut namespaces = self.subscribers.namespaces();
+        namespaces.extend(self.acked.iter().map(|sub| sub.namespace.clone()));
+        namespaces
+    }
+
+    /// Fail over from the primary, as it's warm standby (see
+    /// `Config::standby`): deliver every broadcast the primary didn't get to
+    /// (going by what it last shared), start relaying and carry on as the
+    /// primary. Nothing is missed nor delivered twice, as long as the
+    /// primary is really gone. Does nothing unless we're the standby.
+    pub fn take_over(&mut self) -> GossipResult<()> {
+        let mut standby = match self.standby.take() {
+            Some(standby) => standby,
+            None => return Ok(())
+        };
+
+        match self.engine.config().standby_path {
+            Some(ref path) => match Shared::load(path) {
+                Ok(shared) => standby.mirror(&shared),
+                Err(e) => println!("Error: {}", e)
+            },
+            None => {}
+        }
+
+        let now = clock::now();
+        let (pending, cursor) = standby.take();
+        self.cursor = Some(cursor);
+        for broadcast in pending.move_iter() {
+            self.deliver(broadcast, now);
+        }
+
+        self.run(SetMetadata(RELAY_KEY.to_string(), "true".to_string()))
+    }
+
+    /// Hand the frames the transport read (if any) to the protocol, as
+    /// decoded by our `Codec`, along with who sent them. A connection
+    /// belongs to whoever introduced itself on it (or whoever we dialled),
+    /// until then nothing but introductions gets a hearing.
+    fn receive_frames(&mut self, now: u64) {
+        let mut events = Vec::new();
+        match self.task_rx {
+            Some(ref rx) => loop {
+                match rx.try_recv() {
+                    Ok(event) => events.push(event),
+                    Err(_) => break
+                }
+            },
+            None => return
+        }
+
+        for event in events.move_iter() {
+            let (conn, frame) = match event {
+                FrameOn(conn, frame) => (conn, frame),
+                Dialled(conn, id) => {
+                    self.conns.insert(conn, id);
+                    continue;
+                },
+                DialledSeed(conn) => {
+                    self.seeds.insert(conn);
+                    continue;
+                },
+                HungUp(conn) => {
+                    self.conns.remove(&conn);
+                    self.seeds.remove(&conn);
+                    continue;
+                }
+            };
+
+            self.record_frame(frame.as_slice(), now);
+            let sender = self.conns.find(&conn).map(|id| *id);
+            let size = frame.len();
+            let input = match (sender, codec::decode_frame(&*self.codec, frame.as_slice())) {
+                // Snapshots come from the seeds we dialled, nobody else.
+                (_, Ok(MessagePacket(JoinSync(..)))) if !self.seeds.contains(&conn) => continue,
+                (Some(sender), Ok(BroadcastPacket(broadcast))) => {
+                    self.transferred(sender, Some(broadcast.topic()), Inbound, size, now);
+                    RelayedBy(sender, broadcast)
+                },
+                (Some(sender), Ok(MessagePacket(msg))) => {
+                    self.transferred(sender, None, Inbound, size, now);
+                    ReceivedFrom(sender, msg)
+                },
+                (Some(sender), Ok(BatchPacket(batch))) => {
+                    // Whatever isn't the payloads is the packing's.
+                    let payloads = batch.iter().fold(0, |sum, broadcast| sum + broadcast.len());
+                    self.transferred(sender, None, Inbound, size - cmp::min(payloads, size), now);
+                    for broadcast in batch.move_iter() {
+                        let topic = Some(broadcast.topic());
+                        self.transferred(sender, topic, Inbound, broadcast.len(), now);
+                        let outputs = driver::handle(&mut self.engine, RelayedBy(sender, broadcast),
+                                                     now);
+                        let _ = self.perform(outputs, now);
+                    }
+                    continue;
+                },
+                (Some(sender), Err(_)) => {
+                    self.transferred(sender, None, Inbound, size, now);
+                    Misbehaved(sender, Undecodable)
+                },
+                (None, Ok(MessagePacket(msg))) => match introduced(&msg) {
+                    // Somebody claiming to be a member we're talking to
+                    // already, on another connection.
+                    Some(id) if self.conns.iter().any(|(c, i)| *i == id && *c != conn) => {
+                        println!("Error: {} introduced itself twice", id);
+                        self.send(CloseMsg(conn));
+                        continue;
+                    },
+                    Some(id) => {
+                        self.conns.insert(conn, id);
+                        self.send(BindMsg(conn, id));
+                        self.transferred(id, None, Inbound, size, now);
+                        ReceivedFrom(id, msg)
+                    },
+                    None => continue
+                },
+                (None, Ok(BroadcastPacket(_))) | (None, Ok(BatchPacket(_))) => continue,
+                (None, Err(e)) => {
+                    println!("Error: {}", e);
+                    continue;
+                }
+            };
+            let outputs = driver::handle(&mut self.engine, input, now);
+            let _ = self.perform(outputs, now);
+        }
+    }
+
+    /// Let the application know about restarted tasks, and shut down once
+    /// one of them is given up on.
+    fn supervision(&mut self, now: u64) {
+        loop {
+            let event = match self.supervisor_rx.try_recv() {
+                Ok(Restarted(name, restarts)) => SubsystemRestarted(name, restarts),
+                Ok(Escalated(name)) => {
+                    self.shutdown();
+                    SubsystemFailed(name)
+                },
+                Err(_) => break
+            };
+
+            let _ = self.perform(vec![Emit(event)], now);
+        }
+    }
+
+    /// Advertise a service running on this node to the rest of the cluster,
+    /// replacing any service with the same name. Re-register it with
+    /// `healthy` unset to take it out of lookups without forgetting it.
+    pub fn register(&mut self, service: Service) {
+        let _ = self.run(Register(service));
+    }
+
+    pub fn deregister(&mut self, name: &str) {
+        let _ = self.run(Deregister(name.to_string()));
+    }
+
+    /// The healthy endpoints of the service `name`, as far as this node
+    /// knows. Subscribe to `events` for `ServiceChanged` notifications.
+    pub fn lookup(&self, name: &str) -> Vec<Endpoint> {
+        self.engine.lookup(name)
+    }
+
+    /// Watch the endpoints of a service, i.e., to balance over them. The
+    /// receiver gets every endpoint selected right away, then those added
+    /// and removed on every `tick`. See `discovery`.
+    pub fn watch(&mut self, selector: Selector) -> Receiver<EndpointChange> {
+        let (tx, rx) = channel();
+        let mut discovery = Discovery::new(selector);
+        for change in self.discover(&mut discovery).move_iter() {
+            tx.send(change);
+        }
+        self.watchers.push((discovery, tx));
+        rx
+    }
+
+    fn discover(&self, discovery: &mut Discovery) -> Vec<EndpointChange> {
+        let endpoints = self.engine.lookup(discovery.selector().service.as_slice());
+        let members = self.engine.members();
+        let unknown = HashMap::new();
+        discovery.refresh(endpoints.move_iter().map(|endpoint| {
+            let metadata = if endpoint.id == self.engine.id() {
+                self.engine.metadata()
+            } else {
+                members.find(&endpoint.id).map(|member| &member.metadata).unwrap_or(&unknown)
+            };
+            (endpoint, metadata)
+        }).collect())
+    }
+
+    /// Tell the watchers what changed, forgetting those that went away.
+    fn refresh_watchers(&mut self) {
+        let watchers = mem::replace(&mut self.watchers, Vec::new());
+        for (mut discovery, tx) in watchers.move_iter() {
+            let changes = self.discover(&mut discovery);
+            if changes.move_iter().all(|change| tx.send_opt(change).is_ok()) {
+                self.watchers.push((discovery, tx));
+            }
+        }
+    }
+
+    /// Whether this node should run the cluster's periodic jobs. This is a
+    /// convergent hint rather than a consensus: while membership views
+    /// differ, more than one node might think it's the coordinator. Watch
+    /// for `CoordinatorChanged` events to find out when it moves.
+    pub fn is_coordinator(&self) -> bool {
+        self.engine.is_coordinator()
+    }
+
+    /// Advertise a piece of metadata about this node, such as it's `weight`
+    /// on the hash ring.
+    pub fn set_metadata(&mut self, key: &str, value: &str) {
+        let _ = self.run(SetMetadata(key.to_string(), value.to_string()));
+    }
+
+    /// Decide what concurrent changes to the same member's metadata come
+    /// down to (i.e., made by a primary and it's standby on both sides of
+    /// a partition), instead of the last ones heard of winning. See `merge`.
+    pub fn set_metadata_merge(&mut self, merge: Box<MetadataMerge + Send>) {
+        self.engine.set_metadata_merge(merge);
+    }
+
+    /// Decide when the members we probe are failing with the detector,
+    /// instead of `Config::phi_threshold` (or plain timeouts). See
+    /// `detector`.
+    pub fn set_failure_detector(&mut self, detector: Box<FailureDetector + Send>) {
+        self.engine.set_failure_detector(detector);
+    }
+
+    /// The versions every live member (ourselves included) runs, going by
+    /// their metadata. Handy to follow a rolling upgrade.
+    pub fn versions(&self) -> VersionReport {
+        self.engine.versions()
+    }
+
+    /// Start (or keep) sending heartbeats every `Config::heartbeat_interval`
+    /// carrying the payload, i.e., the load or the version we run.
+    pub fn set_heartbeat(&mut self, payload: Vec<u8>) {
+        let _ = self.run(SetHeartbeat(payload));
+    }
+
+    /// The resource pressure of our host as last sampled, see
+    /// `Config::pressure_interval`.
+    pub fn pressure(&self) -> PressureLevel {
+        self.engine.pressure()
+    }
+
+    /// The last heartbeat heard from the member, if any. Check it's
+    /// `received_at` against `clock::now()` to tell how fresh it is.
+    pub fn last_heartbeat<'a>(&'a self, id: &Uuid) -> Option<&'a Heartbeat> {
+        self.engine.last_heartbeat(id)
+    }
+
+    /// The member owning `key` on the hash ring derived from the membership.
+    /// Watch for `RingChanged` events to find out when keys move.
+    pub fn owner(&self, key: &[u8]) -> Option<Uuid> {
+        self.engine.owner(key)
+    }
+
+    /// The first `n` distinct members responsible for `key` (the owner
+    /// first), i.e., where to put it's replicas.
+    pub fn preference_list(&self, key: &[u8], n: uint) -> Vec<Uuid> {
+        self.engine.preference_list(key, n)
+    }
+
+    /// The current epoch of the hash ring. Tag ownership decisions with it
+    /// and fence (refuse) operations made under an older epoch: the keys
+    /// they relied on might have moved since.
+    pub fn ring_epoch(&self) -> u64 {
+        self.engine.ring_epoch()
+    }
+
+    /// How many joiners we're syncing and how many are still waiting.
+    pub fn join_progress(&self) -> JoinProgress {
+        self.engine.join_progress()
+    }
+
+    /// Take a snapshot of the current membership (ourselves included) and
+    /// broadcast history.
+    pub fn snapshot(&self) -> Snapshot {
+        self.engine.snapshot()
+    }
+
+    /// A dump of what we know right now: the members, our links to them and
+    /// our configuration. Diff it against another one with `dump::diff`.
+    pub fn dump_state(&self) -> StateDump {
+        self.engine.dump(clock::now())
+    }
+
+    /// Report the outcome of talking to a member over the transport so the
+    /// member's circuit breaker can be updated.
+    pub fn transport_result(&mut self, id: Uuid, result: GossipResult<()>) {
+        let _ = self.run(TransportResult(id, result));
+    }
+
+    /// Report bytes exchanged with a member so they show up in `bandwidth`.
+    /// `topic` is the namespace qualified topic for broadcasts, and `None`
+    /// for protocol traffic. Only for embedders moving the bytes themselves
+    /// (see `embedded`), what goes through our transport is counted as is.
+    pub fn record_traffic(&mut self, id: Uuid, topic: Option<&str>, direction: Direction,
+                          bytes: uint) {
+        let topic = topic.map(|topic| topic.to_string());
+        let _ = self.run(Transferred(id, topic, direction, bytes));
+    }
+
+    /// How many bytes went to and came from every member, and for every
+    /// topic, over the last `Config::bandwidth_window`. Handy when figuring
+    /// out who's behind a bandwidth spike.
+    pub fn bandwidth(&mut self) -> BandwidthReport {
+        self.engine.bandwidth(clock::now())
+    }
+
+    /// Feed a single input to the engine and carry out the outputs.
+    fn run(&mut self, input: Input) -> GossipResult<()> {
+        let now = clock::now();
+        let outputs = driver::handle(&mut self.engine, input, now);
+        self.perform(outputs, now)
+    }
+
+    /// Carry out everything the engine asked for. All the outputs are
+    /// performed even if one of them fails, the last error is returned.
+    fn perform(&mut self, outputs: Vec<Output>, now: u64) -> GossipResult<()> {
+        let mut result = Ok(());
+
+        for output in outputs.move_iter() {
+            match output {
+                Gossip(_) | SendTo(..) | Relay(_) | RelayBatch(_) | RelayTo(..) |
+                Reconnect(..) | Disconnect(_) if self.embedded => {
+                    self.outbox.push(output);
+                },
+                Gossip(msg) => match codec::message_frame(&*self.codec, &msg) {
+                    Ok(frame) => {
+                        let cluster = self.cluster();
+                        self.sent(cluster.as_slice(), None, frame.len(), now);
+                        self.send(SendMsg(cluster, frame));
+                    },
+                    Err(e) => result = Err(e)
+                },
+                SendTo(id, msg) => match codec::message_frame(&*self.codec, &msg) {
+                    Ok(frame) => {
+                        self.transferred(id, None, Outbound, frame.len(), now);
+                        self.send(SendMsg(vec![self.target(&id)], frame));
+                    },
+                    Err(e) => result = Err(e)
+                },
+                RelayTo(id, broadcast) => match codec::broadcast_frame(&*self.codec, &broadcast) {
+                    Ok(frame) => {
+                        self.transferred(id, Some(broadcast.topic()), Outbound, frame.len(), now);
+                        self.send(SendMsg(vec![self.target(&id)], frame));
+                    },
+                    Err(e) => result = Err(e)
+                },
+                Reconnect(id, addr) => self.send(ReconnectMsg(id, addr)),
+                Disconnect(id) => self.send(DisconnectMsg(id)),
+                Relay(broadcast) => {
+                    if self.server_tx.is_none() {
+                        result = Err(GossipError::new("the node isn't listening", NotListening));
+                    }
+                    match self.relay_frames(&broadcast, self.eager()) {
+                        Ok(frames) => for (targets, frame) in frames.move_iter() {
+                            self.sent(targets.as_slice(), Some(broadcast.topic()), frame.len(),
+                                      now);
+                            self.send(SendMsg(targets, frame));
+                        },
+                        Err(e) => result = Err(e)
+                    }
+                },
+                RelayBatch(batch) => {
+                    if self.server_tx.is_none() {
+                        result = Err(GossipError::new("the node isn't listening", NotListening));
+                    }
+                    let eager = self.eager();
+                    let mut frames = Vec::new();
+                    for broadcast in batch.iter() {
+                        match self.relay_frames(broadcast, eager.clone()) {
+                            Ok(mut relayed) => if relayed.len() == 1 {
+                                let frame = relayed.pop().unwrap().val1();
+                                self.sent(eager.as_slice(), Some(broadcast.topic()), frame.len(),
+                                          now);
+                                frames.push(frame);
+                            } else {
+                                // Compressed frames differ per member, they go out
+                                // on their own.
+                                for (targets, frame) in relayed.move_iter() {
+                                    self.sent(targets.as_slice(), Some(broadcast.topic()),
+                                              frame.len(), now);
+                                    self.send(SendMsg(targets, frame));
+                                }
+                            },
+                            Err(e) => result = Err(e)
+                        }
+                    }
+                    if !frames.is_empty() {
+                        let (packing, plain) = eager.partition(|target| {
+                            self.engine.takes_packed(target.ref0())
+                        });
+                        if !packing.is_empty() {
+                            let limit = self.packing_limit();
+                            self.send(BatchMsg(packing,
+                                               codec::packed_frames(frames.as_slice(), limit)));
+                        }
+                        if !plain.is_empty() {
+                            self.send(BatchMsg(plain, frames));
+                        }
+                    }
+                },
+                Deliver(broadcast) => self.deliver(broadcast, now),
+                Emit(event) => {
+                    if self.embedded {
+                        self.pending.push(event.clone());
+                    }
+                    self.events.emit(event);
+                },
+                Persist(snapshot) => {
+                    let started = clock::now();
+                    let saved = {
+                        let config = self.engine.config();
+                        match config.snapshot_path {
+                            Some(ref path) => {
+                                backoff::retry(&config.storage_backoff, || snapshot.save(path))
+                            },
+                            None => Ok(())
+                        }
+                    };
+
+                    let mut outputs = Vec::new();
+                    if saved.is_ok() {
+                        let took = clock::now() - started;
+                        outputs.extend(self.engine.handle(Committed(took), now).move_iter());
+                    }
+                    outputs.extend(self.engine.handle(Persisted(saved), now).move_iter());
+                    let _ = self.perform(outputs, now);
+                },
+                AppendLog(broadcasts) => self.log(broadcasts, false, now),
+                RewriteLog(broadcasts) => self.log(broadcasts, true, now),
+                CachePeers(cached) => {
+                    let saved = {
+                        let config = self.engine.config();
+                        match config.peer_cache_path {
+                            Some(ref path) => backoff::retry(&config.storage_backoff, || {
+                                peers::save(path, cached.as_slice())
+                            }),
+                            None => Ok(())
+                        }
+                    };
+
+                    let outputs = self.engine.handle(Persisted(saved), now);
+                    let _ = self.perform(outputs, now);
+                },
+                Shutdown => self.shutdown()
+            }
+        }
+
+        result
+    }
+
+    /// What relaying the broadcast to the members takes: it's frame, and
+    /// the same compressed for the members supporting the compression of
+    /// it's topic when the payload is large enough (see
+    /// `Config::compression_threshold`). The plain frame always comes first,
+    /// even with nobody left to send it to.
+    fn relay_frames(&self, broadcast: &Broadcast,
+                    targets: Vec<Target>) -> GossipResult<Vec<(Vec<Target>, Vec<u8>)>> {
+        let frame = try!(codec::broadcast_frame(&*self.codec, broadcast));
+        // Sealed payloads don't compress.
+        if broadcast.is_sealed() || broadcast.len() < self.engine.config().compression_threshold {
+            return Ok(vec![(targets, frame)]);
+        }
+
+        // Negotiating either keeps the topic's compression or drops it.
+        let topic = broadcast.topic();
+        let mut compression = Uncompressed;
+        let (mut plain, mut compressing) = (Vec::new(), Vec::new());
+        for target in targets.move_iter() {
+            let settings = self.engine.wire_settings(topic.as_slice(), target.ref0());
+            if settings.compression != Uncompressed {
+                compression = settings.compression;
+                compressing.push(target);
+            } else {
+                plain.push(target);
+            }
+        }
+        if compressing.is_empty() {
+            return Ok(vec![(plain, frame)]);
+        }
+
+        match try!(codec::compressed_frame(&*self.codec, broadcast, compression)) {
+            Some(compressed) => Ok(vec![(plain, frame), (compressing, compressed)]),
+            None => {
+                plain.extend(compressing.move_iter());
+                Ok(vec![(plain, frame)])
+            }
+        }
+    }
+
+    /// Count the bytes moved with the member towards `bandwidth`, under the
+    /// broadcast's topic (`None` for protocol traffic).
+    fn transferred(&mut self, id: Uuid, topic: Option<String>, direction: Direction,
+                   bytes: uint, now: u64) {
+        driver::handle(&mut self.engine, Transferred(id, topic, direction, bytes), now);
+    }
+
+    /// The frame of `bytes` is going out to every one of the members.
+    fn sent(&mut self, targets: &[Target], topic: Option<String>, bytes: uint, now: u64) {
+        for &(id, _) in targets.iter() {
+            self.transferred(id, topic.clone(), Outbound, bytes, now);
+        }
+    }
+
+    /// How large packed frames get: about a datagram over udp, so they don't
+    /// have to be split up again (see `fragment`).
+    fn packing_limit(&self) -> uint {
+        let config = self.engine.config();
+        match config.transport {
+            Udp => config.udp_mtu,
+            _ => MAX_PACKED
+        }
+    }
+
+    /// Where to send the member frames: the address it advertised, unless
+    /// it's no member of ours (yet), in which case only the connection it
+    /// introduced itself on will do.
+    fn target(&self, id: &Uuid) -> Target {
+        (*id, self.engine.members().find(id).map(|member| member.peer.addr().clone()))
+    }
+
+    /// Whoever broadcasts are pushed to, see `Engine::eager_peers`.
+    fn eager(&self) -> Vec<Target> {
+        self.engine.eager_peers().iter().map(|id| self.target(id)).collect()
+    }
+
+    /// Every member not known to be dead.
+    fn cluster(&self) -> Vec<Target> {
+        self.engine.members().iter()
+            .filter(|member| member.state != Dead)
+            .map(|member| (member.id(), Some(member.peer.addr().clone())))
+            .collect()
+    }
+
+    /// Hand a message off to the server task. Until we're listening there's
+    /// nobody to tell.
+    fn send(&self, msg: TaskMessage) {
+        match self.server_tx {
+            Some(ref tx) => {
+                self.queued.fetch_add(msg.frames(), SeqCst);
+                tx.send(msg);
+            },
+            None => {}
+        }
+    }
+
+    /// Refuse to publish `n` more broadcasts while the transport task is
+    /// too far behind, see `Config::max_queued_frames`.
+    fn room_for(&self, n: uint) -> GossipResult<()> {
+        if self.queued.load(SeqCst) + n > self.engine.config().max_queued_frames {
+            return Err(GossipError::new("too many frames waiting on the transport",
+                                        Overloaded));
+        }
+        Ok(())
+    }
+
+    /// Hand the engine the broadcasts logged at `Config::wal_path` (if any),
+    /// the ones that don't decode are left out.
+    fn replay_log(&mut self) -> GossipResult<()> {
+        let records = match self.engine.config().wal_path {
+            Some(ref path) => try!(wal::replay(path)),
+            None => return Ok(())
+        };
+        let broadcasts = records.iter().filter_map(|record| {
+            self.codec.decode_broadcast(record.as_slice()).ok()
+        }).collect();
+        let now = clock::now();
+        let outputs = self.engine.handle(Replayed(broadcasts), now);
+        self.perform(outputs, now)
+    }
+
+    /// Carry out an `AppendLog`, or a `RewriteLog` if `rewrite`.
+    fn log(&mut self, broadcasts: Vec<Broadcast>, rewrite: bool, now: u64) {
+        let started = clock::now();
+        let mut records = Vec::new();
+        let mut saved = Ok(());
+        for broadcast in broadcasts.iter() {
+            match self.codec.encode_broadcast(broadcast) {
+                Ok(record) => records.push(record),
+                Err(e) => saved = Err(e)
+            }
+        }
+        if saved.is_ok() {
+            let config = self.engine.config();
+            saved = match config.wal_path {
+                Some(ref path) => backoff::retry(&config.storage_backoff, || {
+                    if rewrite {
+                        wal::rewrite(path, records.as_slice())
+                    } else {
+                        wal::append(path, records.as_slice())
+                    }
+                }),
+                None => Ok(())
+            };
+        }
+
+        let mut outputs = Vec::new();
+        if saved.is_ok() {
+            let took = clock::now() - started;
+            outputs.extend(self.engine.handle(Logged(took), now).move_iter());
+        }
+        outputs.extend(self.engine.handle(Persisted(saved), now).move_iter());
+        let _ = self.perform(outputs, now);
+    }
+
+    /// Hand a broadcast to the local subscribers of it's namespace.
+    fn deliver(&mut self, mut broadcast: Broadcast, now: u64) {
+        // The primary delivers it, unless we have to take over.
+        match self.standby {
+            Some(ref mut standby) => return standby.hold(broadcast),
+            None => {}
+        }
+
+        // Not for us to read, we only relay it.
+        if !self.keys.open(&mut broadcast) {
+            return;
+        }
+
+        // Delivered already, by the primary we took over from if not by us.
+        if self.cursor.as_ref().map_or(false, |cursor| cursor.has_delivered(&broadcast.id())) {
+            return;
+        }
+        match self.cursor {
+            Some(ref mut cursor) => cursor.delivered(broadcast.id()),
+            None => {}
+        }
+
+        self.acked.retain(|sub| sub.namespace.as_slice() != broadcast.namespace() ||
+                                sub.tx.send_opt((broadcast.clone(), None)).is_ok());
+        for sub in self.acked.mut_iter() {
+            if sub.namespace.as_slice() == broadcast.namespace() {
+                sub.tracker.track(broadcast.id(), broadcast.clone(), now);
+                self.acks_changed = true;
+            }
+        }
+
+        let namespace = broadcast.namespace().to_string();
+        let (policy, sent) = {
+            let config = self.engine.config();
+            (config.stall_policy.clone(), config.stall_threshold.map(|_| now))
+        };
+        self.subscribers.deliver(namespace.as_slice(), (broadcast, None), &policy, sent);
+    }
+
+    /// Process the acks of the acked subscribers and deliver whatever
+    /// they didn't ack in time again. What's still unacked is kept once a
+    /// tick, if it changed.
+    fn redeliver(&mut self, now: u64) {
+        let mut i = 0;
+
+        while i < self.acked.len() {
+            let (alive, acked) = {
+                let sub = self.acked.get_mut(i);
+                let mut acked = false;
+                loop {
+                    match sub.acks.try_recv() {
+                        Ok(id) => acked = sub.tracker.ack(&id) || acked,
+                        Err(_) => break
+                    }
+                }
+
+                let due = sub.tracker.due(now);
+                (due.move_iter().all(|b| sub.tx.send_opt((b, None)).is_ok()), acked)
+            };
+
+            self.acks_changed = self.acks_changed || acked || !alive;
+            if alive {
+                i += 1;
+            } else {
+                self.acked.remove(i);
+            }
+        }
+
+        if self.acks_changed {
+            self.save_acks(now);
+        }
+    }
+
+    /// Rewrite `Config::ack_path` with every broadcast still waiting on an
+    /// ack, once each.
+    fn save_acks(&mut self, now: u64) {
+        self.acks_changed = false;
+        let records: Vec<Vec<u8>> = {
+            let mut pending: Vec<&Broadcast> = self.unacked.iter().collect();
+            for sub in self.acked.iter() {
+                pending.extend(sub.tracker.values().move_iter());
+            }
+
+            let mut ids = HashSet::new();
+            pending.move_iter()
+                   .filter(|broadcast| ids.insert(broadcast.id()))
+                   .map(|broadcast| snapshot::encode_broadcast(&WireBroadcast::new(broadcast)))
+                   .collect()
+        };
+
+        let saved = {
+            let config = self.engine.config();
+            match config.ack_path {
+                Some(ref path) => backoff::retry(&config.storage_backoff, || {
+                    wal::rewrite(path, records.as_slice())
+                }),
+                None => return
+            }
+        };
+        let outputs = self.engine.handle(Persisted(saved), now);
+        let _ = self.perform(outputs, now);
+    }
+
+    /// Let the application know about subscribers that stopped reading, see
+    /// `subscriber`.
+    fn check_stalls(&mut self, now: u64) {
+        let (threshold, policy) = {
+            let config = self.engine.config();
+            match config.stall_threshold {
+                Some(threshold) => (threshold, config.stall_policy.clone()),
+                None => return
+            }
+        };
+
+        let events = self.subscribers.stalled(threshold, &policy, now).move_iter()
+                                     .map(|(namespace, group, queued)| {
+                                         Emit(SubscriberStalled(namespace, group, queued))
+                                     })
+                                     .collect();
+        let _ = self.perform(events, now);
+    }
+
+    /// Create a new `Incoming` iterator that iterates over newly received
+    /// broadcasts that the user can handle. Only broadcasts within the
+    /// default namespace are received, see `incoming_in`.
+    ///
+    /// Usage:
+    ///
+    /// ```notrust
+    /// use gossip::Node;
+    /// spawn(proc() {
+    ///     let mut node = Node::new();
+    ///
+    ///     node.listen("localhost", 4888).unwrap();
+    ///
+    ///     for (broadcast, mut res) in node.incoming() {
+    ///         println!("Broadcast ...");
+    ///         // ...
+    ///     }
+    /// });
+    /// ```
+    pub fn incoming(&mut self) -> Incoming {
+        self.incoming_in(DEFAULT_NAMESPACE)
+    }
+
+    /// Like `incoming`, but bound to the given tenant namespace. Broadcasts
+    /// of other namespaces are never seen through it.
+    pub fn incoming_in(&mut self, namespace: &str) -> Incoming {
+        let (tx, rx) = channel();
+        let incoming = Incoming::new(self.tx.clone(), tx);
+
+        self.subscribers.subscribe(namespace, rx.recv(), incoming.taken.clone());
+
+        incoming
+    }
+
+    /// Like `incoming_in`, but every broadcast has to be acked (see
+    /// `Response::ack`) once it's been processed. Broadcasts that aren't
+    /// acked within `Config::ack_timeout` are delivered again, so processing
+    /// is at-least-once. Unacked broadcasts are kept at `Config::ack_path`
+    /// (if any), the ones left over from before a restart go to the first
+    /// acked subscriber of their namespace.
+    pub fn incoming_acked(&mut self, namespace: &str) -> Incoming {
+        let (tx, rx) = channel();
+        let (acks_tx, acks_rx) = channel();
+        let incoming = Incoming::with_acks(self.tx.clone(), tx, acks_tx);
+
+        let mut sub = AckedSubscriber {
+            namespace: namespace.to_string(),
+            tx: rx.recv(),
+            acks: acks_rx,
+            tracker: AckTracker::new(self.engine.config().ack_timeout)
+        };
+        let unacked = mem::replace(&mut self.unacked, Vec::new());
+        let (restored, rest) = unacked.partition(|b| b.namespace() == namespace);
+        self.unacked = rest;
+        let now = clock::now();
+        for broadcast in restored.move_iter() {
+            if sub.tx.send_opt((broadcast.clone(), None)).is_ok() {
+                sub.tracker.track(broadcast.id(), broadcast, now);
+            }
+        }
+        self.acked.push(sub);
+
+        incoming
+    }
+
+    /// A typed handle on the topic called `name` within the default
+    /// namespace, see `Topic`.
+    pub fn topic<T>(&self, name: &str) -> Topic<T> {
+        Topic::new(DEFAULT_NAMESPACE, name)
+    }
+
+    /// Like `topic`, but within the given tenant namespace.
+    pub fn topic_in<T>(&self, namespace: &str, name: &str) -> Topic<T> {
+        Topic::new(namespace, name)
+    }
+
+    /// Join the subscriber group called `group` within the namespace. Every
+    /// broadcast is received by only one of the group's `Incoming`s
+    /// (round-robin), letting several tasks share the processing of a heavy
+    /// topic.
+    pub fn incoming_group(&mut self, namespace: &str, group: &str) -> Incoming {
+        let (tx, rx) = channel();
+        let incoming = Incoming::new(self.tx.clone(), tx);
+
+        self.subscribers.join(namespace, group, rx.recv(), incoming.taken.clone());
+
+        incoming
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use member::{Active, Draining};
+    use namespace::{Namespace, DEFAULT_NAMESPACE};
+    use event::{MemberDraining, MemberActive, SubsystemRestarted, SubsystemFailed};
+    use event::StorageFailed;
+    use config::Config;
+    use supervisor::{Restarted, Escalated};
+    use cipher::Cipher;
+    use cipher::test::Xor;
+    use broadcast::Broadcast;
+    use engine::{Received, Relayed, SendTo, Relay};
+    use message::{JoinRequest, Advertise, Join};
+    use topic::{TopicSettings, Capabilities, Raw, Lz4};
+    use uuid::Uuid;
+    use std::io::TempDir;
+    use std::sync::{Arc, Mutex};
+    use std::io::timer::sleep;
+    use stream::SockAddr;
+    use std::sync::atomics::SeqCst;
+    use result::{GossipResult, Overloaded};
+    use transport::{Transport, TransportEvent, Connection, Frame, Closed};
+    use memory::{InMemoryTransport, Network};
+    use codec;
+    use codec::{JsonCodec, BroadcastPacket, MessagePacket};
+    use admin::{Reconfigure, EvictMember, Cordon, Applied, Rejected};
+    use identity::{FromName, derive_id};
+
+    #[test]
+    fn empty_member_set() {
+        let mut node = Node::new();
+        assert_eq!(node.engine.members().len(), 0);
+    }
+
+    #[test]
+    fn bind_listening() {
+        let mut node = Node::new();
+    }
+
+    #[test]
+    fn refuse_fatal_configs() {
+        let mut config = Config::new();
+        config.tick_interval = 0;
+        assert!(Node::try_with_config(config).is_err());
+        assert!(Node::try_with_config(Config::new()).is_ok());
+    }
+
+    #[test]
+    fn drain_and_undrain() {
+        let mut node = Node::new();
+        let events = node.events();
+
+        node.drain();
+        assert_eq!(node.status(), Draining);
+        assert_eq!(events.recv(), MemberDraining(node.id()));
+
+        node.undrain();
+        assert_eq!(node.status(), Active);
+        assert_eq!(events.recv(), MemberActive(node.id()));
+    }
+
+    #[test]
+    fn single_node_loopback() {
+        let mut node = Node::new();
+        let mut incoming = node.incoming();
+
+        node.broadcast(Broadcast::new(vec![1u8, 2, 3]).unwrap()).unwrap();
+
+        let (broadcast, res) = incoming.next().unwrap();
+        assert_eq!(broadcast.origin(), node.id());
+        assert_eq!(broadcast.as_bytes(), &[1u8, 2, 3]);
+        assert!(res.ok().is_ok());
+    }
+
+    #[test]
+    fn namespaces_are_isolated() {
+        let mut config = Config::new();
+        config.namespaces.insert("billing".to_string(), Namespace::new());
+
+        let mut node = Node::with_config(config);
+        let mut billing = node.incoming_in("billing");
+        let mut default = node.incoming();
+
+        let mut broadcast = Broadcast::new(vec![1u8]).unwrap();
+        broadcast.set_namespace("billing");
+        node.broadcast(broadcast).unwrap();
+        node.broadcast(Broadcast::new(vec![2u8]).unwrap()).unwrap();
+
+        assert_eq!(billing.next().unwrap().val0().as_bytes(), &[1u8]);
+        assert_eq!(default.next().unwrap().val0().as_bytes(), &[2u8]);
+
+        let mut broadcast = Broadcast::new(vec![3u8]).unwrap();
+        broadcast.set_namespace("search");
+        assert!(node.broadcast(broadcast).is_err());
+    }
+
+    #[test]
+    fn batches_are_all_or_nothing() {
+        let mut node = Node::new();
+        let mut incoming = node.incoming();
+
+        let mut unknown = Broadcast::new(vec![2u8]).unwrap();
+        unknown.set_namespace("search");
+        let batch = vec![Broadcast::new(vec![1u8]).unwrap(), unknown];
+        assert!(node.broadcast_batch(batch).is_err());
+
+        let batch = vec![Broadcast::new(vec![3u8]).unwrap(), Broadcast::new(vec![4u8]).unwrap()];
+        node.broadcast_batch(batch).unwrap();
+        assert_eq!(incoming.next().unwrap().val0().as_bytes(), &[3u8]);
+        assert_eq!(incoming.next().unwrap().val0().as_bytes(), &[4u8]);
+
+        // Nor while the transport only has room for part of it.
+        node.queued.store(node.engine.config().max_queued_frames - 1, SeqCst);
+        let batch = vec![Broadcast::new(vec![5u8]).unwrap(), Broadcast::new(vec![6u8]).unwrap()];
+        match node.broadcast_batch(batch) {
+            Err(ref err) => match *err.kind() {
+                Overloaded => {},
+                _ => fail!("unexpected {}", err)
+            },
+            Ok(()) => fail!("took the batch")
+        }
+        assert!(incoming.try_next().is_none());
+        node.broadcast(Broadcast::new(vec![7u8]).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn subscriber_groups_share_broadcasts() {
+        let mut node = Node::new();
+        let mut a = node.incoming_group(DEFAULT_NAMESPACE, "workers");
+        let mut b = node.incoming_group(DEFAULT_NAMESPACE, "workers");
+
+        for i in range(0u8, 4) {
+            node.broadcast(Broadcast::new(vec![i]).unwrap()).unwrap();
+        }
+
+        assert_eq!(a.next().unwrap().val0().as_bytes(), &[0u8]);
+        assert_eq!(b.next().unwrap().val0().as_bytes(), &[1u8]);
+        assert_eq!(a.next().unwrap().val0().as_bytes(), &[2u8]);
+        assert_eq!(b.next().unwrap().val0().as_bytes(), &[3u8]);
+    }
+
+    #[test]
+    fn redeliver_unacked_broadcasts() {
+        let mut config = Config::new();
+        config.ack_timeout = 0;
+
+        let mut node = Node::with_config(config);
+        let mut incoming = node.incoming_acked(DEFAULT_NAMESPACE);
+
+        node.broadcast(Broadcast::new(vec![1u8]).unwrap()).unwrap();
+        let (first, _) = incoming.next().unwrap();
+
+        node.tick();
+        let (again, res) = incoming.next().unwrap();
+        assert_eq!(again.id(), first.id());
+
+        res.ack().unwrap();
+        node.tick();
+        assert_eq!(node.acked.get(0).tracker.len(), 0);
+    }
+
+    #[test]
+    fn keep_unacked_broadcasts_across_restarts() {
+        let dir = TempDir::new("acks").unwrap();
+        let mut config = Config::new();
+        config.ack_path = Some(dir.path().join("acks"));
+
+        let first = {
+            let mut node = Node::with_config(config.clone());
+            let mut incoming = node.incoming_acked(DEFAULT_NAMESPACE);
+            node.broadcast(Broadcast::new(vec![1u8]).unwrap()).unwrap();
+            node.broadcast(Broadcast::new(vec![2u8]).unwrap()).unwrap();
+            let (first, _) = incoming.next().unwrap();
+            let (_, res) = incoming.next().unwrap();
+            res.ack().unwrap();
+            node.tick();
+            first
+        };
+
+        // Only the one that wasn't acked comes back, to whoever subscribes.
+        let mut node = Node::with_config(config);
+        let mut incoming = node.incoming_acked(DEFAULT_NAMESPACE);
+        let (again, res) = incoming.try_next().unwrap();
+        assert_eq!(again.id(), first.id());
+        assert_eq!(again.as_bytes(), &[1u8]);
+        assert!(incoming.try_next().is_none());
+
+        res.ack().unwrap();
+        node.tick();
+        assert!(Node::with_config(node.engine.config().clone()).unacked.is_empty());
+    }
+
+    #[test]
+    fn drive_an_embedded_node() {
+        let mut node = Node::embedded(Config::new());
+        let peer = Peer::empty();
+        let id = peer.id();
+
+        node.handle_io(Received(JoinRequest(peer)), 0).unwrap();
+        assert!(node.outgoing().iter().any(|output| match *output {
+            SendTo(to, _) => to == id,
+            _ => false
+        }));
+
+        node.drain();
+        assert!(node.poll(0).contains(&MemberDraining(node.id())));
+        assert!(node.poll(0).is_empty());
+
+        node.broadcast(Broadcast::new(vec![1u8]).unwrap()).unwrap();
+        assert!(node.outgoing().iter().any(|output| match *output {
+            Relay(_) => true,
+            _ => false
+        }));
+    }
+
+    #[test]
+    fn report_supervised_tasks() {
+        let mut node = Node::embedded(Config::new());
+
+        node.supervisor_tx.send(Restarted("acceptor".to_string(), 1));
+        node.supervisor_tx.send(Escalated("acceptor".to_string()));
+        let events = node.poll(0);
+        assert!(events.contains(&SubsystemRestarted("acceptor".to_string(), 1)));
+        assert!(events.contains(&SubsystemFailed("acceptor".to_string())));
+        assert!(node.server_tx.is_none());
+    }
+
+    #[test]
+    fn shut_the_transport_down() {
+        let network = Network::new();
+        let mut node = Node::new();
+        node.set_transport(box InMemoryTransport::new(&network) as Box<Transport + Send>);
+        node.listen("127.0.0.1", 3444).unwrap();
+
+        let addr = SockAddr::new("127.0.0.1", 3444);
+        let mut peer = InMemoryTransport::new(&network);
+        let conn = peer.connect(&addr).unwrap();
+        // Long enough for the task to accept it.
+        sleep(50);
+
+        node.shutdown();
+        assert_eq!(peer.recv(1_000).unwrap(), Some(Closed(conn)));
+        assert!(peer.connect(&addr).is_err());
+    }
+
+    #[test]
+    fn survive_a_broken_disk() {
+        let mut config = Config::new();
+        config.snapshot_path = Some(Path::new("/nonexistent/gossip/snapshot"));
+        config.commit_max_delay = 0;
+
+        let mut node = Node::embedded(config);
+        node.handle_io(Received(JoinRequest(Peer::empty())), 0).unwrap();
+
+        assert!(node.poll(0).iter().any(|event| match *event {
+            StorageFailed(_) => true,
+            _ => false
+        }));
+        assert_eq!(node.health(), Red);
+    }
+
+    #[test]
+    fn relay_sealed_broadcasts() {
+        let broadcast = Broadcast::new(vec![1u8, 2, 3]).unwrap();
+        let mut config = Config::new();
+        config.sealed_topics = vec![broadcast.topic()];
+        let mut node = Node::embedded(config);
+        node.handle_io(Received(JoinRequest(Peer::empty())), 0).unwrap();
+        node.outgoing();
+        let mut incoming = node.incoming();
+
+        // Nothing goes out before the key is set.
+        assert!(node.broadcast(broadcast.clone()).is_err());
+        assert!(node.outgoing().is_empty());
+
+        node.set_topic_key(broadcast.topic().as_slice(), box Xor(7) as Box<Cipher + Send>);
+        node.broadcast(broadcast).unwrap();
+
+        assert!(node.outgoing().iter().any(|output| match *output {
+            Relay(ref broadcast) => broadcast.is_sealed() && broadcast.as_bytes() != &[1u8, 2, 3],
+            _ => false
+        }));
+
+        let (broadcast, _) = incoming.next().unwrap();
+        assert_eq!(broadcast.as_bytes(), &[1u8, 2, 3]);
+    }
+
+    #[test]
+    fn compress_for_members_that_support_it() {
+        let mut config = Config::new();
+        config.topic_default = TopicSettings::new(Raw, Lz4);
+        let mut node = Node::embedded(config);
+
+        let (a, b) = (Peer::new(Uuid::new_v4(), "10.0.0.1", 3444),
+                      Peer::new(Uuid::new_v4(), "10.0.0.2", 3444));
+        node.handle_io(Received(JoinRequest(a.clone())), 0).unwrap();
+        node.handle_io(Received(JoinRequest(b.clone())), 0).unwrap();
+        node.handle_io(Received(Advertise(a.id(), Capabilities::local())), 0).unwrap();
+
+        let broadcast = Broadcast::new(Vec::from_elem(2_000, 7u8)).unwrap();
+        let relayed = node.relay_frames(&broadcast, node.cluster()).unwrap();
+        assert_eq!(relayed.len(), 2);
+        let (ref plain, ref frame) = *relayed.get(0);
+        let (ref compressing, ref compressed) = *relayed.get(1);
+        assert!(compressed.len() < frame.len());
+        assert_eq!(plain.iter().map(|&(id, _)| id).collect::<Vec<Uuid>>(), vec![b.id()]);
+        assert_eq!(compressing.iter().map(|&(id, _)| id).collect::<Vec<Uuid>>(), vec![a.id()]);
+
+        // Too small to bother.
+        let relayed = node.relay_frames(&Broadcast::new(vec![7u8]).unwrap(), node.cluster());
+        assert_eq!(relayed.unwrap().len(), 1);
+    }
+
+    /// Remembers what's sent, and reads the inbound frames one per `recv`.
+    struct Wire {
+        sent: Arc<Mutex<Vec<Vec<u8>>>>,
+        inbound: Vec<Vec<u8>>
+    }
+
+    impl Transport for Wire {
+        fn listen(&mut self, addr: &SockAddr) -> GossipResult<SockAddr> {
+            Ok(addr.clone())
+        }
+
+        fn connect(&mut self, _: &SockAddr) -> GossipResult<Connection> {
+            Ok(Connection(1))
+        }
+
+        fn send(&mut self, _: &Connection, frame: &[u8]) -> GossipResult<()> {
+            self.sent.lock().push(frame.to_vec());
+            Ok(())
+        }
+
+        fn recv(&mut self, timeout: u64) -> GossipResult<Option<TransportEvent>> {
+            match self.inbound.pop() {
+                Some(frame) => Ok(Some(Frame(Connection(1), frame))),
+                None => {
+                    sleep(timeout);
+                    Ok(None)
+                }
+            }
+        }
+
+        fn close(&mut self, _: &Connection) {}
+
+        fn shutdown(&mut self) {}
+    }
+
+    #[test]
+    fn refuse_a_second_introduction_as_the_same_member() {
+        let network = Network::new();
+        let mut node = Node::new();
+        node.set_transport(box InMemoryTransport::new(&network) as Box<Transport + Send>);
+        node.listen("127.0.0.1", 3444).unwrap();
+
+        let addr = SockAddr::new("127.0.0.1", 3444);
+        let advertise = codec::message_frame(&JsonCodec, &Advertise(Uuid::new_v4(),
+                                                                    Capabilities(0))).unwrap();
+        let (mut member, mut stranger) = (InMemoryTransport::new(&network),
+                                          InMemoryTransport::new(&network));
+        let conn = member.connect(&addr).unwrap();
+        member.send(&conn, advertise.as_slice()).unwrap();
+        for _ in range(0u, 10) {
+            node.tick();
+            sleep(5);
+        }
+
+        // The stranger claims the member's id, and is hung up on.
+        let claimed = stranger.connect(&addr).unwrap();
+        stranger.send(&claimed, advertise.as_slice()).unwrap();
+        for _ in range(0u, 10) {
+            node.tick();
+            sleep(5);
+        }
+        assert_eq!(stranger.recv(1_000).unwrap(), Some(Closed(claimed)));
+        // The member keeps it's own.
+        assert_eq!(node.conns.len(), 1);
+    }
+
+    #[test]
+    fn move_broadcasts_with_the_transport() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let relayed = Broadcast::new(vec![4u8, 5]).unwrap();
+        // The seed introduces itself before relaying anything, or we
+        // wouldn't know who it's from (frames are read from the back).
+        let seed = Join(Peer::new(Uuid::new_v4(), "127.0.0.1", 8777), Config::new().cluster_name,
+                        codec::PROTOCOL_VERSION as u32);
+        let inbound = vec![codec::broadcast_frame(&JsonCodec, &relayed).unwrap(),
+                           codec::message_frame(&JsonCodec, &seed).unwrap()];
+        let wire = Wire { sent: sent.clone(), inbound: inbound };
+
+        let mut node = Node::new();
+        node.set_transport(box wire as Box<Transport + Send>);
+        node.listen("127.0.0.1", 0).unwrap();
+        node.join("127.0.0.1", 8777).unwrap();
+        node.broadcast(Broadcast::new(vec![1u8, 2, 3]).unwrap()).unwrap();
+
+        // Ours goes out, and so does the relayed one once we tick.
+        for _ in range(0u, 100) {
+            node.tick();
+            {
+                let bytes: Vec<Vec<u8>> = sent.lock().iter().filter_map(|frame| {
+                    match codec::decode_frame(&JsonCodec, frame.as_slice()) {
+                        Ok(BroadcastPacket(broadcast)) => Some(broadcast.as_bytes().to_vec()),
+                        _ => None
+                    }
+                }).collect();
+                if bytes.contains(&vec![1u8, 2, 3]) && bytes.contains(&vec![4u8, 5]) {
+                    // Both ways, as far as the bandwidth goes.
+                    let usage = node.bandwidth().peers.find(&seed.sender().unwrap())
+                                                      .map(|usage| usage.clone());
+                    assert!(usage.map_or(false, |u| u.bytes_in > 0 && u.bytes_out > 0));
+                    return;
+                }
+            }
+            sleep(10);
+        }
+        fail!("the broadcasts never went through the transport");
+    }
+
+    #[test]
+    fn join_the_seeds_once_listening() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let wire = Wire { sent: sent.clone(), inbound: Vec::new() };
+        let mut config = Config::new();
+        config.seeds = vec![SockAddr::new("127.0.0.1", 8777)];
+        config.join_timeout = 50;
+        config.join_backoff.max_attempts = Some(1);
+
+        let mut node = Node::with_config(config);
+        node.set_transport(box wire as Box<Transport + Send>);
+        node.listen("127.0.0.1", 0).unwrap();
+
+        // Our handshake went out on the connection to the seed.
+        assert!(sent.lock().iter().any(|frame| {
+            match codec::decode_frame(&JsonCodec, frame.as_slice()) {
+                Ok(MessagePacket(Join(..))) => true,
+                _ => false
+            }
+        }));
+        // The seed never handed us the membership, so we're not done.
+        assert!(node.rejoining.is_some());
+
+        // Nor is rejoining, connecting isn't enough.
+        assert!(node.rejoin(&[SockAddr::new("127.0.0.1", 8777)]).is_err());
+        assert!(node.rejoining.is_none());
+    }
+
+    #[test]
+    fn preview_a_cluster_through_a_seed() {
+        let network = Network::new();
+        let transport = box InMemoryTransport::new(&network) as Box<Transport + Send>;
+        let (listening_tx, listening_rx) = channel();
+        let (done_tx, done_rx) = channel::<()>();
+        spawn(proc() {
+            let mut seed = Node::new();
+            seed.set_transport(transport);
+            seed.listen("127.0.0.1", 3444).unwrap();
+            listening_tx.send(seed.id());
+            while done_rx.try_recv().is_err() {
+                seed.tick();
+                sleep(5);
+            }
+        });
+        let seed = listening_rx.recv();
+
+        let mut node = Node::new();
+        node.set_transport(box InMemoryTransport::new(&network) as Box<Transport + Send>);
+        node.listen("127.0.0.1", 0).unwrap();
+        let info = node.preview_join(&[SockAddr::new("127.0.0.1", 3444)]).unwrap();
+        done_tx.send(());
+
+        assert_eq!(info.name, Config::new().cluster_name);
+        assert!(info.members.iter().any(|peer| peer.id() == seed));
+        assert_eq!(node.alive_members(), 1);
+    }
+
+    #[test]
+    fn standby_takes_over_where_the_primary_left_off() {
+        let dir = TempDir::new("standby").unwrap();
+        let mut config = Config::new();
+        config.standby_path = Some(dir.path().join("standby"));
+
+        let mut primary = Node::embedded(config.clone());
+        let _incoming = primary.incoming();
+        let (a, b) = (Broadcast::new(vec![1u8]).unwrap(), Broadcast::new(vec![2u8]).unwrap());
+        let c = Broadcast::new(vec![3u8]).unwrap();
+        primary.handle_io(Relayed(a.clone()), 0).unwrap();
+        primary.handle_io(Relayed(c.clone()), 0).unwrap();
+        primary.poll(0);
+
+        config.standby = true;
+        let mut standby = Node::embedded(config);
+        assert_eq!(standby.id(), primary.id());
+        let mut incoming = standby.incoming();
+        standby.handle_io(Relayed(a), 0).unwrap();
+        standby.handle_io(Relayed(b.clone()), 0).unwrap();
+        assert!(standby.outgoing().is_empty());
+
+        // The primary delivered `a` before it went away.
+        standby.take_over().unwrap();
+        let (broadcast, _) = incoming.next().unwrap();
+        assert_eq!(broadcast.id(), b.id());
+
+        // Nor is `c` delivered again, though it only reaches us now.
+        standby.handle_io(Relayed(c), 0).unwrap();
+        assert!(incoming.try_next().is_none());
+    }
+
+    #[test]
+    fn apply_admin_commands_in_order() {
+        let mut node = Node::new();
+        let mut config = Config::new();
+        config.quorum = Some(1);
+        let reconfigured = node.submit(Reconfigure(config.clone()));
+        config.relay = false;
+        let restart = node.submit(Reconfigure(config));
+        let unknown = node.submit(EvictMember(Uuid::new_v4()));
+        let cordoned = node.submit(Cordon);
+        assert!(!node.is_cordoned());
+
+        node.tick();
+        assert_eq!(reconfigured.recv(), Applied);
+        assert_eq!(node.engine.config().quorum, Some(1));
+        assert_eq!(restart.recv(), Rejected("only applies on restart: relay".to_string()));
+        assert_eq!(unknown.recv(), Rejected("no such member".to_string()));
+        assert_eq!(cordoned.recv(), Applied);
+        assert!(node.is_cordoned());
+    }
+
+    #[test]
+    fn derive_the_node_id() {
+        let mut config = Config::new();
+        config.node_id = FromName("web-1".to_string());
+        let node = Node::with_config(config.clone());
+        assert_eq!(node.id(), derive_id(&config.crypto, "web-1").unwrap());
+
+        config.node_id = FromName(String::new());
+        assert!(Node::try_with_config(config).is_err());
     }
 }
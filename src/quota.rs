@@ -0,0 +1,152 @@
+//! Per-origin rate quotas enforced by relays. This prevents a single
+//! application instance from monopolizing the cluster's bandwidth.
+
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// The maximum rate a single origin may broadcast at.
+#[deriving(Show, Clone, PartialEq)]
+pub struct Quota {
+    pub messages_per_sec: u32,
+    pub bytes_per_sec: u64
+}
+
+impl Quota {
+    pub fn new(messages_per_sec: u32, bytes_per_sec: u64) -> Quota {
+        Quota {
+            messages_per_sec: messages_per_sec,
+            bytes_per_sec: bytes_per_sec
+        }
+    }
+}
+
+/// A token bucket that holds at most one second worth of tokens.
+struct Bucket {
+    tokens: f64,
+    rate: f64,
+    last: u64
+}
+
+impl Bucket {
+    fn new(rate: f64, now: u64) -> Bucket {
+        Bucket {
+            tokens: rate,
+            rate: rate,
+            last: now
+        }
+    }
+
+    fn refill(&mut self, now: u64) {
+        if now > self.last {
+            let elapsed = (now - self.last) as f64 / 1000.0;
+            self.tokens = self.tokens + elapsed * self.rate;
+            if self.tokens > self.rate {
+                self.tokens = self.rate;
+            }
+            self.last = now;
+        }
+    }
+}
+
+struct Usage {
+    messages: Bucket,
+    bytes: Bucket
+}
+
+/// Tracks the usage of every origin we relay broadcasts for.
+pub struct Quotas {
+    default: Option<Quota>,
+    overrides: HashMap<Uuid, Quota>,
+    usage: HashMap<Uuid, Usage>
+}
+
+impl Quotas {
+    /// `default` applies to every origin without an entry in `overrides`.
+    /// Origins without any quota are never throttled.
+    pub fn new(default: Option<Quota>, overrides: HashMap<Uuid, Quota>) -> Quotas {
+        Quotas {
+            default: default,
+            overrides: overrides,
+            usage: HashMap::new()
+        }
+    }
+
+    fn quota(&self, origin: &Uuid) -> Option<Quota> {
+        match self.overrides.find(origin) {
+            Some(quota) => Some(quota.clone()),
+            None => self.default.clone()
+        }
+    }
+
+    /// Account for a broadcast of `bytes` from `origin`. Returns `false`
+    /// if the origin is over it's quota, in which case nothing is
+    /// consumed and the broadcast should be dropped.
+    pub fn admit(&mut self, origin: Uuid, bytes: uint, now: u64) -> bool {
+        let quota = match self.quota(&origin) {
+            Some(quota) => quota,
+            None => return true
+        };
+
+        if !self.usage.contains_key(&origin) {
+            self.usage.insert(origin, Usage {
+                messages: Bucket::new(quota.messages_per_sec as f64, now),
+                bytes: Bucket::new(quota.bytes_per_sec as f64, now)
+            });
+        }
+
+        let usage = self.usage.find_mut(&origin).unwrap();
+        usage.messages.refill(now);
+        usage.bytes.refill(now);
+
+        let bytes = bytes as f64;
+        if usage.messages.tokens < 1.0 || usage.bytes.tokens < bytes {
+            return false;
+        }
+
+        usage.messages.tokens -= 1.0;
+        usage.bytes.tokens -= bytes;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    #[test]
+    fn unlimited_without_quota() {
+        let mut quotas = Quotas::new(None, HashMap::new());
+        for _ in range(0u, 1000) {
+            assert!(quotas.admit(Uuid::new_v4(), 1 << 20, 0));
+        }
+    }
+
+    #[test]
+    fn message_rate() {
+        let mut quotas = Quotas::new(Some(Quota::new(2, 1000)), HashMap::new());
+        let origin = Uuid::new_v4();
+
+        assert!(quotas.admit(origin, 10, 0));
+        assert!(quotas.admit(origin, 10, 0));
+        assert!(!quotas.admit(origin, 10, 0));
+        assert!(quotas.admit(Uuid::new_v4(), 10, 0));
+
+        // Half a second later we've got one message worth of budget back.
+        assert!(quotas.admit(origin, 10, 500));
+        assert!(!quotas.admit(origin, 10, 500));
+    }
+
+    #[test]
+    fn byte_rate_with_override() {
+        let origin = Uuid::new_v4();
+        let mut overrides = HashMap::new();
+        overrides.insert(origin, Quota::new(100, 100));
+
+        let mut quotas = Quotas::new(Some(Quota::new(100, 10000)), overrides);
+        assert!(quotas.admit(origin, 80, 0));
+        assert!(!quotas.admit(origin, 80, 0));
+        assert!(quotas.admit(Uuid::new_v4(), 8000, 0));
+    }
+}
@@ -0,0 +1,112 @@
+//! What we know about the last few hundred broadcasts we saw (up to
+//! `Config::recent_broadcasts`), whether they made it to our subscribers
+//! or not. Unlike the history only the metadata is kept, so it's cheap to
+//! keep around for inspecting recent traffic. See `Node::recent_broadcasts`.
+
+use std::collections::{RingBuf, Deque};
+use uuid::Uuid;
+
+use broadcast::Broadcast;
+
+/// What became of a broadcast.
+#[deriving(Show, Clone, PartialEq)]
+pub enum Delivery {
+    /// Handed to our subscribers, ours included.
+    Delivered,
+    /// Arrived after it's deadline.
+    Late,
+    /// The origin was over it's rate quota.
+    OverLimit
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub struct BroadcastRecord {
+    pub id: Uuid,
+    pub origin: Uuid,
+    /// Namespace qualified, see `Broadcast::topic`.
+    pub topic: String,
+    /// When we saw it, by our wall clock (in milliseconds since the unix
+    /// epoch).
+    pub at: u64,
+    /// In bytes.
+    pub size: uint,
+    pub delivery: Delivery
+}
+
+impl BroadcastRecord {
+    pub fn new(broadcast: &Broadcast, delivery: Delivery, at: u64) -> BroadcastRecord {
+        BroadcastRecord {
+            id: broadcast.id(),
+            origin: broadcast.origin(),
+            topic: broadcast.topic(),
+            at: at,
+            size: broadcast.len(),
+            delivery: delivery
+        }
+    }
+}
+
+pub struct Recent {
+    capacity: uint,
+    records: RingBuf<BroadcastRecord>
+}
+
+impl Recent {
+    pub fn new(capacity: uint) -> Recent {
+        Recent {
+            capacity: capacity,
+            records: RingBuf::new()
+        }
+    }
+
+    /// Record a broadcast, forgetting the oldest one if we're full.
+    pub fn record(&mut self, record: BroadcastRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Up to `limit` of the broadcasts of the topic (any without one),
+    /// newest first.
+    pub fn list(&self, topic: Option<&str>, limit: uint) -> Vec<BroadcastRecord> {
+        self.records.iter().rev()
+                    .filter(|record| topic.map_or(true, |t| record.topic.as_slice() == t))
+                    .take(limit)
+                    .map(|record| record.clone())
+                    .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use broadcast::Broadcast;
+
+    #[test]
+    fn newest_of_the_topic_first() {
+        let mut recent = Recent::new(3);
+        let mut billing = Broadcast::new(vec![1u8, 2]).unwrap();
+        billing.set_namespace("billing");
+        let other = Broadcast::new(vec![3u8]).unwrap();
+
+        recent.record(BroadcastRecord::new(&billing, Delivered, 1));
+        recent.record(BroadcastRecord::new(&other, Late, 2));
+        recent.record(BroadcastRecord::new(&billing, OverLimit, 3));
+        recent.record(BroadcastRecord::new(&other, Delivered, 4));
+
+        let at: Vec<u64> = recent.list(None, 10).iter().map(|r| r.at).collect();
+        assert_eq!(at, vec![4, 3, 2]);
+
+        let topic = billing.topic();
+        let records = recent.list(Some(topic.as_slice()), 10);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records.get(0).delivery, OverLimit);
+        assert_eq!(records.get(0).size, 2);
+        assert_eq!(recent.list(None, 1).len(), 1);
+    }
+}
@@ -0,0 +1,171 @@
+//! A lightweight service registry on top of the gossip. Every node
+//! advertises the services it runs (see `Node::register`) and any node
+//! can look up the healthy endpoints of a service by it's name.
+
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use member::{Membership, Alive};
+use stream::SockAddr;
+
+/// A named service running on a node, reachable on the node's ip.
+#[deriving(Show, Clone, PartialEq, Encodable, Decodable)]
+pub struct Service {
+    pub name: String,
+    pub port: u16,
+    /// Unhealthy services are still advertised but never returned by a
+    /// lookup.
+    pub healthy: bool
+}
+
+impl Service {
+    pub fn new(name: &str, port: u16) -> Service {
+        Service {
+            name: name.to_string(),
+            port: port,
+            healthy: true
+        }
+    }
+}
+
+/// Where a service can be reached, along with the node running it.
+#[deriving(Show, Clone, PartialEq)]
+pub struct Endpoint {
+    pub id: Uuid,
+    pub addr: SockAddr
+}
+
+pub struct Registry {
+    /// The services running on this node.
+    local: Vec<Service>,
+    /// Whatever the other members advertised last.
+    remote: HashMap<Uuid, Vec<Service>>
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry {
+            local: Vec::new(),
+            remote: HashMap::new()
+        }
+    }
+
+    pub fn local<'a>(&'a self) -> &'a Vec<Service> {
+        &self.local
+    }
+
+    /// Register a local service, replacing any with the same name. Returns
+    /// `false` if nothing changed.
+    pub fn register(&mut self, service: Service) -> bool {
+        match self.local.iter().position(|s| s.name == service.name) {
+            Some(i) if *self.local.get(i) == service => false,
+            Some(i) => {
+                *self.local.get_mut(i) = service;
+                true
+            },
+            None => {
+                self.local.push(service);
+                true
+            }
+        }
+    }
+
+    pub fn deregister(&mut self, name: &str) -> bool {
+        let len = self.local.len();
+        self.local.retain(|s| s.name.as_slice() != name);
+        self.local.len() != len
+    }
+
+    /// Replace everything the member `id` advertises. Returns the names of
+    /// the services whose endpoints changed as a result.
+    pub fn update(&mut self, id: Uuid, services: Vec<Service>) -> Vec<String> {
+        let previous = self.remote.pop(&id).unwrap_or(Vec::new());
+        let mut changed = Vec::new();
+
+        for service in services.iter() {
+            if !previous.contains(service) && !changed.contains(&service.name) {
+                changed.push(service.name.clone());
+            }
+        }
+
+        for service in previous.iter() {
+            if !services.contains(service) && !changed.contains(&service.name) {
+                changed.push(service.name.clone());
+            }
+        }
+
+        if !services.is_empty() {
+            self.remote.insert(id, services);
+        }
+
+        changed
+    }
+
+    /// The healthy endpoints of `name` among the alive members.
+    pub fn lookup(&self, name: &str, members: &Membership) -> Vec<Endpoint> {
+        let mut endpoints = Vec::new();
+
+        for (id, services) in self.remote.iter() {
+            let member = match members.find(id) {
+                Some(member) if member.state == Alive => member,
+                _ => continue
+            };
+
+            for service in services.iter() {
+                if service.name.as_slice() == name && service.healthy {
+                    endpoints.push(Endpoint {
+                        id: *id,
+                        addr: SockAddr::new(member.peer.addr().ip.as_slice(), service.port)
+                    });
+                }
+            }
+        }
+
+        endpoints
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use member::{Membership, Dead};
+    use protocol::Peer;
+
+    #[test]
+    fn lookup_healthy_endpoints() {
+        let mut registry = Registry::new();
+        let mut members = Membership::new();
+        let (a, b) = (Peer::empty(), Peer::empty());
+        members.insert(a.clone());
+        members.insert(b.clone());
+
+        let mut failing = Service::new("billing", 80);
+        failing.healthy = false;
+
+        assert_eq!(registry.update(a.id(), vec![Service::new("billing", 8080)]),
+                   vec!["billing".to_string()]);
+        assert_eq!(registry.update(b.id(), vec![failing]), vec!["billing".to_string()]);
+        assert!(registry.update(a.id(), vec![Service::new("billing", 8080)]).is_empty());
+
+        let endpoints = registry.lookup("billing", &members);
+        assert_eq!(endpoints.len(), 1);
+        assert_eq!(endpoints.get(0).id, a.id());
+        assert_eq!(endpoints.get(0).addr.port, 8080);
+
+        members.set_state(&a.id(), Dead);
+        assert!(registry.lookup("billing", &members).is_empty());
+    }
+
+    #[test]
+    fn register_local_services() {
+        let mut registry = Registry::new();
+
+        assert!(registry.register(Service::new("search", 9200)));
+        assert!(!registry.register(Service::new("search", 9200)));
+        assert!(registry.register(Service::new("search", 9300)));
+        assert_eq!(registry.local().len(), 1);
+
+        assert!(registry.deregister("search"));
+        assert!(!registry.deregister("search"));
+    }
+}
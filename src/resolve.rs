@@ -0,0 +1,210 @@
+//! Resolving the hosts we join (or rejoin) through, before the transport
+//! ever sees them: the transport task only gets ips, so slow DNS never holds
+//! up the frames of everybody else. Resolution goes through a `Resolver`,
+//! the system's (`getaddrinfo`) unless another one is set (see
+//! `Node::set_resolver`), i.e., one talking to a DNS server directly.
+//!
+//! Answers are cached for their TTL, or `Config::dns_ttl` if the resolver
+//! can't tell (the system's can't), and failures for
+//! `Config::dns_negative_ttl` so a host that doesn't resolve isn't asked
+//! about on every retry. An expired answer is still handed out while it's
+//! refreshed in the background, but only once: a host that stopped
+//! resolving fails the next time around.
+
+use std::collections::HashMap;
+use std::io::net::addrinfo::get_host_addresses;
+use std::io::net::ip::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use clock;
+use result::{GossipResult, GossipError, NodeUnreachable, io_err};
+use stream::SockAddr;
+
+/// What a host resolved to.
+pub struct Resolution {
+    pub ips: Vec<IpAddr>,
+    /// How long (in milliseconds) the answer is good for, if the resolver
+    /// knows.
+    pub ttl: Option<u64>
+}
+
+pub trait Resolver: Send {
+    fn resolve(&mut self, host: &str) -> GossipResult<Resolution>;
+}
+
+/// The system's resolver, `/etc/hosts` and all.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&mut self, host: &str) -> GossipResult<Resolution> {
+        let ips = try!(get_host_addresses(host).map_err(io_err));
+        Ok(Resolution { ips: ips, ttl: None })
+    }
+}
+
+enum Answer {
+    Resolved(Vec<IpAddr>),
+    Failed(String)
+}
+
+struct Entry {
+    answer: Answer,
+    expires: u64,
+    /// Whether it's being refreshed already.
+    refreshing: bool
+}
+
+struct Cache {
+    ttl: u64,
+    negative_ttl: u64,
+    entries: HashMap<String, Entry>
+}
+
+/// The resolver and it's cache. Clones share both.
+#[deriving(Clone)]
+pub struct Dns {
+    resolver: Arc<Mutex<Box<Resolver + Send>>>,
+    cache: Arc<Mutex<Cache>>
+}
+
+impl Dns {
+    pub fn new(resolver: Box<Resolver + Send>, ttl: u64, negative_ttl: u64) -> Dns {
+        Dns {
+            resolver: Arc::new(Mutex::new(resolver)),
+            cache: Arc::new(Mutex::new(Cache {
+                ttl: ttl,
+                negative_ttl: negative_ttl,
+                entries: HashMap::new()
+            }))
+        }
+    }
+
+    /// The address with it's host resolved to an ip, the first one it
+    /// resolved to. Addresses with an ip already are left alone.
+    pub fn resolve(&self, addr: &SockAddr, now: u64) -> GossipResult<SockAddr> {
+        let ips = try!(self.lookup(addr.ip.as_slice(), now));
+        match ips.move_iter().next() {
+            Some(ip) => Ok(SockAddr::new(ip.to_string().as_slice(), addr.port)),
+            None => Err(GossipError::new("the host doesn't resolve", NodeUnreachable))
+        }
+    }
+
+    pub fn lookup(&self, host: &str, now: u64) -> GossipResult<Vec<IpAddr>> {
+        match from_str::<IpAddr>(host) {
+            Some(ip) => return Ok(vec![ip]),
+            None => {}
+        }
+
+        let stale = {
+            let mut cache = self.cache.lock();
+            match cache.entries.find_mut(&host.to_string()) {
+                Some(entry) if now < entry.expires => return entry.answer.result(),
+                Some(entry) => match entry.answer {
+                    Resolved(ref ips) if !entry.refreshing => {
+                        entry.refreshing = true;
+                        Some(ips.clone())
+                    },
+                    _ => None
+                },
+                None => None
+            }
+        };
+
+        match stale {
+            Some(ips) => {
+                let (dns, host) = (self.clone(), host.to_string());
+                spawn(proc() {
+                    let _ = dns.refresh(host.as_slice(), clock::now());
+                });
+                Ok(ips)
+            },
+            None => self.refresh(host, now)
+        }
+    }
+
+    /// Ask the resolver, without holding up lookups of what's cached.
+    fn refresh(&self, host: &str, now: u64) -> GossipResult<Vec<IpAddr>> {
+        let resolved = self.resolver.lock().resolve(host);
+
+        let mut cache = self.cache.lock();
+        let (answer, ttl) = match resolved {
+            Ok(Resolution { ref ips, .. }) if ips.is_empty() => {
+                (Failed("the host doesn't resolve".to_string()), cache.negative_ttl)
+            },
+            Ok(resolution) => (Resolved(resolution.ips), resolution.ttl.unwrap_or(cache.ttl)),
+            Err(e) => (Failed(e.desc().to_string()), cache.negative_ttl)
+        };
+        let result = answer.result();
+        cache.entries.insert(host.to_string(), Entry {
+            answer: answer,
+            expires: now + ttl,
+            refreshing: false
+        });
+        result
+    }
+}
+
+impl Answer {
+    fn result(&self) -> GossipResult<Vec<IpAddr>> {
+        match *self {
+            Resolved(ref ips) => Ok(ips.clone()),
+            Failed(ref desc) => Err(GossipError::new(desc.clone(), NodeUnreachable))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::net::ip::Ipv4Addr;
+    use std::sync::{Arc, Mutex};
+    use result::{GossipResult, GossipError, NodeUnreachable};
+    use stream::SockAddr;
+
+    /// Resolves `up` to 10.0.0.n, n being how many lookups there were.
+    struct Counting {
+        lookups: Arc<Mutex<u8>>
+    }
+
+    impl Resolver for Counting {
+        fn resolve(&mut self, host: &str) -> GossipResult<Resolution> {
+            let mut lookups = self.lookups.lock();
+            *lookups += 1;
+            if host == "up" {
+                Ok(Resolution { ips: vec![Ipv4Addr(10, 0, 0, *lookups)], ttl: Some(1_000) })
+            } else {
+                Err(GossipError::new("no such host", NodeUnreachable))
+            }
+        }
+    }
+
+    fn dns() -> (Dns, Arc<Mutex<u8>>) {
+        let lookups = Arc::new(Mutex::new(0u8));
+        let resolver = box Counting { lookups: lookups.clone() } as Box<Resolver + Send>;
+        (Dns::new(resolver, 60_000, 5_000), lookups)
+    }
+
+    #[test]
+    fn cache_answers_for_their_ttl() {
+        let (dns, lookups) = dns();
+        let up = SockAddr::new("up", 7_000);
+        assert_eq!(dns.resolve(&up, 0).unwrap(), SockAddr::new("10.0.0.1", 7_000));
+        assert_eq!(dns.resolve(&up, 999).unwrap(), SockAddr::new("10.0.0.1", 7_000));
+        assert_eq!(*lookups.lock(), 1);
+
+        // Ips never go through the resolver.
+        assert_eq!(dns.lookup("10.1.2.3", 0).unwrap(), vec![Ipv4Addr(10, 1, 2, 3)]);
+        assert_eq!(*lookups.lock(), 1);
+    }
+
+    #[test]
+    fn cache_failures_for_a_while() {
+        let (dns, lookups) = dns();
+        assert!(dns.lookup("down", 0).is_err());
+        assert!(dns.lookup("down", 4_999).is_err());
+        assert_eq!(*lookups.lock(), 1);
+
+        assert!(dns.lookup("down", 5_000).is_err());
+        assert_eq!(*lookups.lock(), 2);
+    }
+}
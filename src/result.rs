@@ -24,10 +24,34 @@ pub struct GossipError {
 pub enum GossipErrorKind {
     NodeUnreachable,
     NotListening,
+    NotQuorate,
+    /// The namespace doesn't exist, or we may not publish within it.
+    AccessDenied,
+    InvalidSnapshot,
+    /// The peer refused our credentials.
+    AuthFailure,
+    /// The peer speaks a protocol (version) we don't understand.
+    ProtocolMismatch,
+    /// Not available on this platform.
+    Unsupported,
+    /// The configuration has fatal problems, see `Config::validate`.
+    InvalidConfig,
+    /// Too much is waiting to go out already, try again in a bit.
+    Overloaded,
     UnknownError,
     IoError(io::IoError)
 }
 
+/// Whether retrying an operation that failed with an error makes sense.
+#[deriving(Show, PartialEq)]
+pub enum ErrorClass {
+    /// Timeouts, refused connections and the like. Trying again later
+    /// might very well work.
+    Transient,
+    /// Retrying won't help until something changes (i.e., configuration).
+    Permanent
+}
+
 impl GossipError {
     pub fn new<T: IntoMaybeOwned<'static>>(desc: T, kind: GossipErrorKind) -> GossipError {
         GossipError {
@@ -35,4 +59,44 @@ impl GossipError {
             desc: desc.into_maybe_owned(),
         }
     }
+
+    pub fn kind<'a>(&'a self) -> &'a GossipErrorKind {
+        &self.kind
+    }
+
+    pub fn desc<'a>(&'a self) -> &'a str {
+        self.desc.as_slice()
+    }
+
+    pub fn class(&self) -> ErrorClass {
+        match self.kind {
+            AuthFailure | ProtocolMismatch | InvalidSnapshot | Unsupported |
+                InvalidConfig => Permanent,
+            IoError(ref err) => match err.kind {
+                io::PermissionDenied | io::InvalidInput | io::MismatchedFileTypeForOperation
+                    | io::PathAlreadyExists | io::PathDoesntExist => Permanent,
+                _ => Transient
+            },
+            _ => Transient
+        }
+    }
+
+    pub fn is_transient(&self) -> bool {
+        self.class() == Transient
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn classify_errors() {
+        assert!(GossipError::new("unreachable", NodeUnreachable).is_transient());
+        assert!(!GossipError::new("bad key", AuthFailure).is_transient());
+
+        let refused = io::standard_error(io::ConnectionRefused);
+        assert!(io_err(refused).is_transient());
+    }
 }
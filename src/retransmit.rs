@@ -0,0 +1,157 @@
+//! A limited-broadcast queue for membership updates, following the same
+//! semantics as memberlist's `TransmitLimitedQueue`. Every update is sent
+//! a bounded number of times (scaled by the cluster size) and updates that
+//! have been transmitted the least go first, with the most recent ones
+//! breaking ties. That way fresh, urgent changes propagate before old ones
+//! that are nearly exhausted anyway.
+
+use std::cmp::{Ordering, Equal};
+use std::collections::PriorityQueue;
+use std::mem;
+
+use digest::supersedes;
+use message::Update;
+
+struct Queued {
+    update: Update,
+    transmits: uint,
+    /// Insertion order, so newer updates win ties.
+    seq: u64
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Queued) -> bool {
+        self.transmits == other.transmits && self.seq == other.seq
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Queued) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    /// The heap pops the greatest item first: fewer transmits are greater,
+    /// then higher (newer) sequence numbers.
+    fn cmp(&self, other: &Queued) -> Ordering {
+        match other.transmits.cmp(&self.transmits) {
+            Equal => self.seq.cmp(&other.seq),
+            ordering => ordering
+        }
+    }
+}
+
+pub struct RetransmitQueue {
+    mult: uint,
+    seq: u64,
+    queue: PriorityQueue<Queued>
+}
+
+/// How many times each update is transmitted in a cluster of `n` members.
+pub fn retransmit_limit(mult: uint, n: uint) -> uint {
+    let scale = ((n + 1) as f64).log10().ceil() as uint;
+    mult * scale
+}
+
+impl RetransmitQueue {
+    pub fn new(mult: uint) -> RetransmitQueue {
+        RetransmitQueue {
+            mult: mult,
+            seq: 0,
+            queue: PriorityQueue::new()
+        }
+    }
+
+    pub fn len(&self) -> uint {
+        self.queue.len()
+    }
+
+    /// Queue an update, replacing any queued update it supersedes.
+    pub fn push(&mut self, update: Update) {
+        if self.queue.len() > 0 {
+            let queued = mem::replace(&mut self.queue, PriorityQueue::new()).into_vec();
+            let kept = queued.move_iter().filter(|q| !supersedes(&q.update, &update)).collect();
+            self.queue = PriorityQueue::from_vec(kept);
+        }
+
+        self.seq += 1;
+        self.queue.push(Queued {
+            update: update,
+            transmits: 0,
+            seq: self.seq
+        });
+    }
+
+    /// Take up to `max` updates to send out in a cluster of `n` members.
+    /// Updates that haven't hit their transmit limit yet are requeued.
+    pub fn take(&mut self, max: uint, n: uint) -> Vec<Update> {
+        let limit = retransmit_limit(self.mult, n);
+        let mut taken = Vec::new();
+        let mut requeue = Vec::new();
+
+        while taken.len() < max {
+            match self.queue.pop() {
+                Some(mut queued) => {
+                    taken.push(queued.update.clone());
+                    queued.transmits += 1;
+                    if queued.transmits < limit {
+                        requeue.push(queued);
+                    }
+                },
+                None => break
+            }
+        }
+
+        for queued in requeue.move_iter() {
+            self.queue.push(queued);
+        }
+
+        taken
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use member::{Draining, Active, Dead};
+    use message::{StatusUpdate, Liveness};
+    use uuid::Uuid;
+
+    #[test]
+    fn limit_scales_with_cluster_size() {
+        assert_eq!(retransmit_limit(4, 0), 0);
+        assert_eq!(retransmit_limit(4, 5), 4);
+        assert_eq!(retransmit_limit(4, 50), 8);
+    }
+
+    #[test]
+    fn least_transmitted_and_newest_first() {
+        let mut queue = RetransmitQueue::new(2);
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        queue.push(StatusUpdate(a, Draining));
+        queue.push(Liveness(b, Dead));
+        assert_eq!(queue.take(1, 5), vec![Liveness(b, Dead)]);
+
+        queue.push(StatusUpdate(c, Draining));
+        assert_eq!(queue.take(2, 5), vec![StatusUpdate(c, Draining), StatusUpdate(a, Draining)]);
+
+        // Everything has now been sent once; each goes out at most twice.
+        assert_eq!(queue.take(10, 5).len(), 3);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn superseded_updates_are_dropped() {
+        let mut queue = RetransmitQueue::new(2);
+        let a = Uuid::new_v4();
+
+        queue.push(StatusUpdate(a, Draining));
+        queue.push(StatusUpdate(a, Active));
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.take(10, 5), vec![StatusUpdate(a, Active)]);
+    }
+}
@@ -0,0 +1,155 @@
+//! A consistent-hash ring over the members of the cluster. Each member is
+//! placed on the ring `vnodes * weight` times so keys spread evenly and
+//! only a fraction of them move when a member comes or goes.
+//!
+//! Every node derives the ring from it's own membership view. While the
+//! views differ, so do the rings. Without a crypto provider to hash with
+//! (see `Config::crypto`) the ring stays empty, nobody owns anything.
+
+use std::cmp;
+use std::io::MemWriter;
+use uuid::Uuid;
+
+use crypto::Crypto;
+
+/// Weights come off the wire, any above this one are taken for it. So a
+/// member can't put millions of points on the ring (and in our memory).
+pub static MAX_WEIGHT: uint = 64;
+
+pub struct Ring {
+    vnodes: uint,
+    crypto: Crypto,
+    /// The members (with their weight) the ring was built from, sorted.
+    members: Vec<(Uuid, uint)>,
+    /// Points on the ring, sorted by their hash.
+    points: Vec<(u64, Uuid)>
+}
+
+impl Ring {
+    pub fn new(vnodes: uint, crypto: Crypto) -> Ring {
+        Ring {
+            vnodes: vnodes,
+            crypto: crypto,
+            members: Vec::new(),
+            points: Vec::new()
+        }
+    }
+
+    /// Rebuild the ring over the members and their weights. Returns `false`
+    /// (and leaves the ring alone) if they didn't change.
+    pub fn rebuild(&mut self, members: Vec<(Uuid, uint)>) -> bool {
+        let mut members: Vec<(Uuid, uint)> = members.move_iter().map(|(id, weight)| {
+            (id, cmp::min(weight, MAX_WEIGHT))
+        }).collect();
+        members.sort_by(|a, b| a.ref0().as_bytes().cmp(&b.ref0().as_bytes()));
+        if members == self.members {
+            return false;
+        }
+
+        self.points.clear();
+        'members: for &(id, weight) in members.iter() {
+            for i in range(0, self.vnodes * weight) {
+                let mut point = MemWriter::new();
+                // Writing to memory can't fail.
+                point.write(id.as_bytes()).unwrap();
+                point.write_be_u64(i as u64).unwrap();
+                match self.crypto.hash(point.get_ref()) {
+                    Ok(hash) => self.points.push((hash, id)),
+                    Err(_) => {
+                        self.points.clear();
+                        break 'members;
+                    }
+                }
+            }
+        }
+
+        self.points.sort_by(|a, b| a.ref0().cmp(b.ref0()));
+        self.members = members;
+        true
+    }
+
+    /// The member owning `key`, if there's anyone on the ring.
+    pub fn owner(&self, key: &[u8]) -> Option<Uuid> {
+        self.preference_list(key, 1).pop()
+    }
+
+    /// The first `n` distinct members found walking the ring clockwise from
+    /// `key`, the owner first. Fewer are returned if there aren't that many.
+    pub fn preference_list(&self, key: &[u8], n: uint) -> Vec<Uuid> {
+        let mut list = Vec::new();
+        if self.points.is_empty() {
+            return list;
+        }
+
+        let hash = match self.crypto.hash(key) {
+            Ok(hash) => hash,
+            Err(_) => return list
+        };
+        let start = match self.points.iter().position(|&(point, _)| point >= hash) {
+            Some(i) => i,
+            None => 0
+        };
+
+        for i in range(0, self.points.len()) {
+            if list.len() == n || list.len() == self.members.len() {
+                break;
+            }
+
+            let id = *self.points.get((start + i) % self.points.len()).ref1();
+            if !list.contains(&id) {
+                list.push(id);
+            }
+        }
+
+        list
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::Crypto;
+    use uuid::Uuid;
+
+    #[test]
+    fn preference_lists_are_distinct() {
+        let mut ring = Ring::new(16, Crypto::default());
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        assert_eq!(ring.owner(b"key"), None);
+        assert!(ring.rebuild(vec![(a, 1), (b, 1), (c, 2)]));
+        assert!(!ring.rebuild(vec![(c, 2), (b, 1), (a, 1)]));
+
+        let list = ring.preference_list(b"key", 5);
+        assert_eq!(list.len(), 3);
+        assert_eq!(ring.owner(b"key"), Some(*list.get(0)));
+        assert!(list.contains(&a) && list.contains(&b) && list.contains(&c));
+    }
+
+    #[test]
+    fn only_some_keys_move() {
+        let mut ring = Ring::new(64, Crypto::default());
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        ring.rebuild(vec![(a, 1), (b, 1)]);
+
+        let keys: Vec<String> = range(0u, 100).map(|i| i.to_string()).collect();
+        let before: Vec<Option<Uuid>> = keys.iter().map(|k| ring.owner(k.as_bytes())).collect();
+
+        ring.rebuild(vec![(a, 1), (b, 1), (c, 1)]);
+        for (key, owner) in keys.iter().zip(before.iter()) {
+            let now = ring.owner(key.as_bytes());
+            assert!(now == *owner || now == Some(c));
+        }
+    }
+
+    #[test]
+    fn cap_the_weight() {
+        let mut ring = Ring::new(4, Crypto::default());
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+
+        assert!(ring.rebuild(vec![(a, 1), (b, ::std::uint::MAX)]));
+        assert_eq!(ring.points.len(), 4 * (1 + MAX_WEIGHT));
+        // Anything over the cap is the same weight.
+        assert!(!ring.rebuild(vec![(a, 1), (b, MAX_WEIGHT + 1)]));
+    }
+}
@@ -0,0 +1,81 @@
+//! When to gossip a round, see `Config::gossip_interval`. Every round
+//! comes up to `Config::gossip_jitter` earlier or later than the interval
+//! at random, the first one included, so members that started (or ticked)
+//! at the same time drift apart rather than all gossiping at once.
+
+use std::cmp;
+use std::rand::{task_rng, Rng, XorShiftRng};
+
+pub struct Schedule {
+    /// `None` makes every round due.
+    interval: Option<u64>,
+    jitter: u64,
+    next: u64,
+    rng: XorShiftRng
+}
+
+impl Schedule {
+    pub fn new(interval: Option<u64>, jitter: u64, now: u64) -> Schedule {
+        let mut schedule = Schedule {
+            interval: interval,
+            // Rounds never come due before the previous one.
+            jitter: cmp::min(jitter, interval.unwrap_or(0)),
+            next: now,
+            rng: task_rng().gen()
+        };
+        schedule.next = now + schedule.wait();
+        schedule
+    }
+
+    /// Whether a round is due, scheduling the next one if so.
+    pub fn due(&mut self, now: u64) -> bool {
+        match self.interval {
+            None => true,
+            Some(_) if now >= self.next => {
+                self.next = now + self.wait();
+                true
+            },
+            Some(_) => false
+        }
+    }
+
+    /// When the next round is due.
+    pub fn next(&self) -> u64 {
+        self.next
+    }
+
+    /// How long until the round after this one, jitter and all.
+    fn wait(&mut self) -> u64 {
+        let interval = self.interval.unwrap_or(0);
+        interval - self.jitter + self.rng.gen_range(0, 2 * self.jitter + 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn every_round_without_an_interval() {
+        let mut schedule = Schedule::new(None, 100, 0);
+        assert!(schedule.due(0));
+        assert!(schedule.due(0));
+    }
+
+    #[test]
+    fn jitter_around_the_interval() {
+        let mut schedule = Schedule::new(Some(1_000), 200, 0);
+        let mut now = 0;
+        for _ in range(0u, 50) {
+            let next = schedule.next();
+            assert!(next >= now + 800 && next <= now + 1_200);
+            assert!(!schedule.due(next - 1));
+            assert!(schedule.due(next));
+            now = next;
+        }
+
+        // Too much jitter is as much as the interval.
+        let schedule = Schedule::new(Some(100), 500, 0);
+        assert!(schedule.next() <= 200);
+    }
+}
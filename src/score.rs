@@ -0,0 +1,184 @@
+//! Peer scoring. A single malformed frame doesn't say much about a peer (a
+//! flipped bit, a newer version we only half understand), so rather than
+//! dropping the connection right away every violation costs the peer some
+//! of it's score. Peers below `Config::score_demote_threshold` are moved to
+//! the lazy set (they stop receiving payloads from us), and below
+//! `Config::score_disconnect_threshold` we disconnect from them altogether.
+//! Scores recover over time, at `Config::score_recovery` points a second.
+
+use std::cmp;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// The score of a peer that never misbehaved (or long ago).
+pub static MAX_SCORE: u64 = 100;
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum Violation {
+    /// A frame from the peer didn't decode.
+    Undecodable,
+    /// The peer went over it's rate limit.
+    RateLimited,
+    /// The peer sent something that decoded fine but made no sense, i.e.,
+    /// an unexpected message during the handshake.
+    ProtocolViolation
+}
+
+impl Violation {
+    pub fn penalty(&self) -> u64 {
+        match *self {
+            Undecodable => 10,
+            RateLimited => 5,
+            ProtocolViolation => 25
+        }
+    }
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum Standing {
+    Good,
+    /// Only in our lazy set.
+    Demoted,
+    Disconnected
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub struct PeerScore {
+    pub score: u64,
+    pub standing: Standing,
+    /// How many violations we've seen from the peer overall.
+    pub violations: uint
+}
+
+struct Entry {
+    peer: PeerScore,
+    /// When the score last changed.
+    updated: u64
+}
+
+pub struct Scores {
+    demote_below: u64,
+    disconnect_below: u64,
+    /// Points a second.
+    recovery: u64,
+    /// Peers back at `MAX_SCORE` aren't kept around.
+    peers: HashMap<Uuid, Entry>
+}
+
+impl Scores {
+    pub fn new(demote_below: u64, disconnect_below: u64, recovery: u64) -> Scores {
+        Scores {
+            demote_below: demote_below,
+            disconnect_below: disconnect_below,
+            recovery: recovery,
+            peers: HashMap::new()
+        }
+    }
+
+    /// Charge the peer for a violation, returning it's new standing if it
+    /// changed.
+    pub fn penalize(&mut self, id: Uuid, violation: Violation, now: u64) -> Option<Standing> {
+        self.recover(now);
+
+        let entry = self.peers.find_or_insert_with(id, |_| Entry {
+            peer: PeerScore { score: MAX_SCORE, standing: Good, violations: 0 },
+            updated: now
+        });
+
+        entry.peer.score -= cmp::min(entry.peer.score, violation.penalty());
+        entry.peer.violations += 1;
+        entry.updated = now;
+
+        let standing = standing(entry.peer.score, self.demote_below, self.disconnect_below);
+        if standing == entry.peer.standing {
+            None
+        } else {
+            entry.peer.standing = standing.clone();
+            Some(standing)
+        }
+    }
+
+    /// Let the scores recover for the time that passed, returning the peers
+    /// whose standing improved.
+    pub fn recover(&mut self, now: u64) -> Vec<(Uuid, Standing)> {
+        let mut improved = Vec::new();
+        let mut recovered = Vec::new();
+
+        if self.recovery > 0 {
+            for (id, entry) in self.peers.mut_iter() {
+                // Only the whole points earned so far, the rest of the time
+                // still counts towards the next one.
+                let points = (now - entry.updated) * self.recovery / 1000;
+                if points == 0 {
+                    continue;
+                }
+
+                entry.peer.score = cmp::min(MAX_SCORE, entry.peer.score + points);
+                entry.updated += points * 1000 / self.recovery;
+
+                let standing = standing(entry.peer.score, self.demote_below,
+                                        self.disconnect_below);
+                if standing != entry.peer.standing {
+                    entry.peer.standing = standing.clone();
+                    improved.push((*id, standing));
+                }
+
+                if entry.peer.score == MAX_SCORE {
+                    recovered.push(*id);
+                }
+            }
+        }
+
+        for id in recovered.iter() {
+            self.peers.remove(id);
+        }
+
+        improved
+    }
+
+    pub fn find(&self, id: &Uuid) -> Option<PeerScore> {
+        self.peers.find(id).map(|entry| entry.peer.clone())
+    }
+
+    /// Every peer that misbehaved recently, along with it's score.
+    pub fn list(&self) -> Vec<(Uuid, PeerScore)> {
+        self.peers.iter().map(|(id, entry)| (*id, entry.peer.clone())).collect()
+    }
+}
+
+fn standing(score: u64, demote_below: u64, disconnect_below: u64) -> Standing {
+    if score < disconnect_below {
+        Disconnected
+    } else if score < demote_below {
+        Demoted
+    } else {
+        Good
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn degrade_then_recover() {
+        let mut scores = Scores::new(50, 20, 10);
+        let id = Uuid::new_v4();
+
+        for _ in range(0u, 5) {
+            assert_eq!(scores.penalize(id, Undecodable, 0), None);
+        }
+        assert_eq!(scores.penalize(id, ProtocolViolation, 0), Some(Demoted));
+        assert_eq!(scores.penalize(id, ProtocolViolation, 0), Some(Disconnected));
+        assert_eq!(scores.find(&id).unwrap().score, 0);
+        assert_eq!(scores.find(&id).unwrap().violations, 7);
+
+        assert_eq!(scores.recover(1_999), vec![]);
+        assert_eq!(scores.recover(2_000), vec![(id, Demoted)]);
+        assert_eq!(scores.recover(5_000), vec![(id, Good)]);
+
+        scores.recover(10_000);
+        assert_eq!(scores.find(&id), None);
+    }
+}
@@ -0,0 +1,537 @@
+//! The threaded driver with the per-peer work spread over several tasks,
+//! for clusters too large for a single core to keep up encoding and writing
+//! everything the engine wants sent. The engine still runs within a single
+//! task; every other task (a shard) owns the peers hashing to it (by their
+//! id, see `shard_of`) and carries out their outputs through it's own
+//! handler:
+//!
+//! * Messages and broadcasts to a single member go to it's shard only.
+//! * Those to the cluster (or our eager peers) are split up into one per
+//!   member, each going to the member's shard. A handler only ever hears
+//!   of it's own peers.
+//! * Everything local (deliveries, events, snapshots...) goes to the first
+//!   shard.
+//!
+//! Each shard probes it's own peers too (see `swim`), rather than the engine
+//! probing all of them. The acks for a shard's probes are handed to it, and
+//! what it makes of them goes back to the engine (a `Detected`), which
+//! spreads the word as usual. The shards probe with the default failure
+//! detector whatever the engine's is, and without scaling their timeouts by
+//! the local health.
+//!
+//! Shards look members up (i.e., their address) in a snapshot of the
+//! membership shared by all of them and taken every tick, see `MemberView`,
+//! rather than asking the engine.
+
+use std::collections::HashMap;
+use std::hash;
+use std::io::Timer;
+use std::sync::{Arc, RWLock};
+use uuid::Uuid;
+
+use engine::{Engine, Output, Input, WallClock, Tick, Received, ReceivedFrom, Detected};
+use engine::{Gossip, SendTo, Relay, RelayBatch, RelayTo, Reconnect, Disconnect};
+use member::{Member, Membership, Alive, Suspect, Dead};
+use message::{Ping, PingReq, Ack};
+use swim::{Swim, Probe, ProbeThrough, Suspected, Confirmed, Answered};
+use driver::Handler;
+use driver;
+use clock;
+
+/// Each shard numbers it's probes from the next multiple of this on, the
+/// engine's pings on behalf of others stay below it.
+static SEQS: u64 = 1 << 48;
+
+/// Whose probe is acked under the sequence number, the engine's for `None`.
+fn prober(seq: u64) -> Option<uint> {
+    if seq >= SEQS { Some((seq / SEQS - 1) as uint) } else { None }
+}
+
+/// Which of the shards the member belongs to.
+pub fn shard_of(id: &Uuid, shards: uint) -> uint {
+    (hash::hash(id) % shards as u64) as uint
+}
+
+/// The members as of the engine's last tick. Clones share the snapshot.
+#[deriving(Clone)]
+pub struct MemberView {
+    members: Arc<RWLock<HashMap<Uuid, Member>>>
+}
+
+impl MemberView {
+    pub fn new() -> MemberView {
+        MemberView {
+            members: Arc::new(RWLock::new(HashMap::new()))
+        }
+    }
+
+    pub fn find(&self, id: &Uuid) -> Option<Member> {
+        self.members.read().find(id).map(|member| member.clone())
+    }
+
+    /// The members of the shard.
+    pub fn shard(&self, shard: uint, shards: uint) -> Vec<Member> {
+        self.members.read().values()
+                    .filter(|member| shard_of(&member.id(), shards) == shard)
+                    .map(|member| member.clone())
+                    .collect()
+    }
+
+    pub fn len(&self) -> uint {
+        self.members.read().len()
+    }
+
+    fn update(&self, membership: &Membership) {
+        let members = membership.iter().map(|member| (member.id(), member.clone())).collect();
+        *self.members.write() = members;
+    }
+}
+
+/// What a shard is handed.
+enum Work {
+    Perform(Output),
+    /// An ack for one of the shard's probes: the member's (the first id), as
+    /// it reached us from the second.
+    Acked(Uuid, Uuid, u64)
+}
+
+/// A shard's probe round, over the members of the shard.
+struct Probes {
+    /// Ours, i.e., the engine's.
+    id: Uuid,
+    view: MemberView,
+    swim: Swim,
+    engine: Sender<Input>,
+    tick_interval: u64
+}
+
+impl Probes {
+    fn new(engine: &Engine, view: MemberView, tx: Sender<Input>, shard: uint) -> Probes {
+        let config = engine.config();
+        let now = clock::now();
+        let mut swim = Swim::new(config.probe_interval, config.probe_timeout,
+                                 config.indirect_probes, config.suspicion_timeout, now);
+        swim.set_seq((shard as u64 + 1) * SEQS);
+        Probes {
+            id: engine.id(),
+            view: view,
+            swim: swim,
+            engine: tx,
+            tick_interval: config.tick_interval
+        }
+    }
+
+    /// Move the probe round along, returning what to send. Returns `None`
+    /// if the engine is gone.
+    fn tick(&mut self, shard: uint, shards: uint, now: u64) -> Option<Vec<Output>> {
+        let members: Vec<Uuid> = self.view.shard(shard, shards).iter()
+                                     .filter(|member| member.state != Dead)
+                                     .map(|member| member.id())
+                                     .collect();
+        let mut outputs = Vec::new();
+        for action in self.swim.tick(members.as_slice(), now).move_iter() {
+            let detected = match action {
+                Probe(id, seq) => {
+                    outputs.push(SendTo(id, Ping(self.id, seq)));
+                    continue;
+                },
+                // The helpers are of the shard as well.
+                ProbeThrough(helpers, id, seq) => {
+                    for helper in helpers.move_iter() {
+                        outputs.push(SendTo(helper, PingReq(self.id, id, seq)));
+                    }
+                    continue;
+                },
+                Suspected(id) => Detected(id, Suspect),
+                Confirmed(id) => Detected(id, Dead)
+            };
+            if self.engine.send_opt(detected).is_err() {
+                return None;
+            }
+        }
+        Some(outputs)
+    }
+
+    /// Take in the ack. Returns `false` if the engine is gone.
+    fn acked(&mut self, id: Uuid, via: Uuid, seq: u64, now: u64) -> bool {
+        if self.swim.ack(&id, &via, seq, now) != Answered {
+            return true;
+        }
+        self.engine.send_opt(Detected(id, Alive)).is_ok()
+    }
+}
+
+/// Carry out the shard's work with the handler, and probe it's members if
+/// there are `probes`.
+fn run<H: Handler>(mut handler: H, rx: Receiver<Work>, probes: Option<Probes>, shard: uint,
+                   shards: uint) {
+    let mut probes = match probes {
+        Some(probes) => probes,
+        None => {
+            for work in rx.iter() {
+                match work {
+                    Perform(output) => handler.perform(output),
+                    Acked(..) => {}
+                }
+            }
+            return;
+        }
+    };
+
+    let mut timer = Timer::new().unwrap();
+    let ticks = timer.periodic(probes.tick_interval);
+    loop {
+        select! {
+            work = rx.recv_opt() => match work {
+                Ok(Perform(output)) => handler.perform(output),
+                Ok(Acked(id, via, seq)) => {
+                    if !probes.acked(id, via, seq, clock::now()) {
+                        return;
+                    }
+                },
+                Err(_) => return
+            },
+            () = ticks.recv() => match probes.tick(shard, shards, clock::now()) {
+                Some(outputs) => {
+                    for output in outputs.move_iter() {
+                        handler.perform(output);
+                    }
+                },
+                None => return
+            }
+        }
+    }
+}
+
+/// The handlers of the shards, each running within it's own task.
+pub struct Shards {
+    txs: Vec<Sender<Work>>
+}
+
+impl Shards {
+    pub fn spawn<H: Handler + Send>(handlers: Vec<H>) -> Shards {
+        let probes = handlers.iter().map(|_| None).collect();
+        Shards::spawn_probing(handlers, probes)
+    }
+
+    fn spawn_probing<H: Handler + Send>(handlers: Vec<H>, probes: Vec<Option<Probes>>) -> Shards {
+        assert!(!handlers.is_empty(), "at least one shard is needed");
+
+        let shards = handlers.len();
+        let txs = handlers.move_iter().zip(probes.move_iter()).enumerate()
+                          .map(|(shard, (handler, probes))| {
+            let (tx, rx) = channel();
+            spawn(proc() {
+                run(handler, rx, probes, shard, shards);
+            });
+            tx
+        }).collect();
+
+        Shards {
+            txs: txs
+        }
+    }
+
+    pub fn len(&self) -> uint {
+        self.txs.len()
+    }
+
+    /// Hand the output to the shard(s) it's for: those to the `cluster` or
+    /// our `eager` peers are split up by member. Returns `false` if one of
+    /// them is gone (i.e., it's handler failed).
+    pub fn route(&self, output: Output, cluster: &[Uuid], eager: &[Uuid]) -> bool {
+        let member = match output {
+            SendTo(id, _) | RelayTo(id, _) | Reconnect(id, _) | Disconnect(id) => Some(id),
+            _ => None
+        };
+
+        match output {
+            Gossip(msg) => cluster.iter().all(|id| self.to(id, SendTo(*id, msg.clone()))),
+            Relay(broadcast) => {
+                eager.iter().all(|id| self.to(id, RelayTo(*id, broadcast.clone())))
+            },
+            RelayBatch(batch) => eager.iter().all(|id| {
+                batch.iter().all(|broadcast| self.to(id, RelayTo(*id, broadcast.clone())))
+            }),
+            output => match member {
+                Some(id) => self.to(&id, output),
+                None => self.txs.get(0).send_opt(Perform(output)).is_ok()
+            }
+        }
+    }
+
+    fn to(&self, id: &Uuid, output: Output) -> bool {
+        self.txs.get(shard_of(id, self.txs.len())).send_opt(Perform(output)).is_ok()
+    }
+
+    /// Hand the ack to the shard that probed, if there's such a shard.
+    /// Returns `false` if it's gone.
+    fn acked(&self, shard: uint, id: Uuid, via: Uuid, seq: u64) -> bool {
+        shard >= self.txs.len() || self.txs.get(shard).send_opt(Acked(id, via, seq)).is_ok()
+    }
+}
+
+/// Whether the output goes to the cluster, or our eager peers.
+fn fans_out(output: &Output) -> bool {
+    match *output {
+        Gossip(_) | Relay(_) | RelayBatch(_) => true,
+        _ => false
+    }
+}
+
+/// A handle on the engine's task. The engine and the shards exit once every
+/// handle (see `clone`) is dropped, or a shard failed.
+#[deriving(Clone)]
+pub struct Sharded {
+    tx: Sender<Input>
+}
+
+impl Sharded {
+    /// Run the engine with a shard per handler, sharing the view of the
+    /// members with them. The shards probe their own members, unless the
+    /// engine's `Config::probe_interval` is `None`.
+    pub fn spawn<H: Handler + Send>(engine: Engine, view: MemberView,
+                                    handlers: Vec<H>) -> Sharded {
+        let (tx, rx) = channel();
+        // What the shards detect comes in on it's own, so the engine still
+        // exits once every handle is dropped.
+        let (detected_tx, detected_rx) = channel();
+        let mut engine = engine;
+        let interval = engine.config().tick_interval;
+        let probes = if engine.config().probe_interval.is_some() {
+            engine.probe_elsewhere();
+            Vec::from_fn(handlers.len(), |shard| {
+                Some(Probes::new(&engine, view.clone(), detected_tx.clone(), shard))
+            })
+        } else {
+            handlers.iter().map(|_| None).collect()
+        };
+        let shards = Shards::spawn_probing(handlers, probes);
+
+        spawn(proc() {
+            let mut engine = engine;
+            let _detected_tx = detected_tx;
+            let mut timer = Timer::new().unwrap();
+            let ticks = timer.periodic(interval);
+            engine.handle(WallClock(clock::wall()), clock::now());
+            view.update(engine.members());
+
+            loop {
+                let input = select! {
+                    input = rx.recv_opt() => match input {
+                        Ok(input) => input,
+                        Err(_) => break
+                    },
+                    detected = detected_rx.recv() => detected,
+                    () = ticks.recv() => {
+                        engine.handle(WallClock(clock::wall()), clock::now());
+                        Tick
+                    }
+                };
+
+                // The acks for the shards' probes go to whichever probed.
+                let acked = match input {
+                    Received(Ack(ref id, seq)) => prober(seq).map(|shard| (shard, *id, *id, seq)),
+                    ReceivedFrom(ref via, Ack(ref id, seq)) => {
+                        prober(seq).map(|shard| (shard, *id, *via, seq))
+                    },
+                    _ => None
+                };
+                match acked {
+                    Some((shard, id, via, seq)) => {
+                        if !shards.acked(shard, id, via, seq) {
+                            return;
+                        }
+                        continue;
+                    },
+                    None => {}
+                }
+
+                let ticked = match input { Tick => true, _ => false };
+                let outputs = driver::handle(&mut engine, input, clock::now());
+                let (cluster, eager) = if outputs.iter().any(fans_out) {
+                    let cluster: Vec<Uuid> = engine.members().iter()
+                                                   .filter(|member| member.state != Dead)
+                                                   .map(|member| member.id())
+                                                   .collect();
+                    (cluster, engine.eager_peers())
+                } else {
+                    (Vec::new(), Vec::new())
+                };
+                for output in outputs.move_iter() {
+                    if !shards.route(output, cluster.as_slice(), eager.as_slice()) {
+                        return;
+                    }
+                }
+                if ticked {
+                    view.update(engine.members());
+                }
+            }
+        });
+
+        Sharded {
+            tx: tx
+        }
+    }
+
+    /// Queue an input for the engine. Returns `false` if the task is gone.
+    pub fn send(&self, input: Input) -> bool {
+        self.tx.send_opt(input).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::SEQS;
+    use serialize::json;
+    use test::Bencher;
+    use uuid::Uuid;
+    use engine::{Engine, Output, Publish, Received, Tick, Deliver, Gossip, SendTo, Relay};
+    use engine::RelayTo;
+    use driver::Handler;
+    use broadcast::Broadcast;
+    use config::Config;
+    use message::{RingEpoch, JoinRequest, Digest, Joined, Ping};
+    use protocol::Peer;
+
+    #[test]
+    fn route_to_the_member_shard() {
+        let (first, first_rx) = channel();
+        let (second, second_rx) = channel();
+        let shards = Shards::spawn(vec![first, second]);
+        let id = Uuid::new_v4();
+        let other = range(0u, 1_000).map(|_| Uuid::new_v4())
+                                    .find(|other| shard_of(other, 2) != shard_of(&id, 2))
+                                    .unwrap();
+
+        assert!(shards.route(SendTo(id, RingEpoch(1)), &[], &[]));
+        let (rx, other_rx) = if shard_of(&id, 2) == 0 {
+            (&first_rx, &second_rx)
+        } else {
+            (&second_rx, &first_rx)
+        };
+        match rx.recv() {
+            SendTo(to, _) => assert_eq!(to, id),
+            _ => fail!("expected the message on the member's shard")
+        }
+
+        // Gossip is split up by member, each shard only hears of it's own.
+        assert!(shards.route(Gossip(RingEpoch(2)), &[id, other], &[]));
+        assert!(shards.route(Relay(Broadcast::new(vec![1u8]).unwrap()), &[id, other], &[id]));
+        for &(rx, member) in [(rx, id), (other_rx, other)].iter() {
+            match rx.recv() {
+                SendTo(to, RingEpoch(2)) => assert_eq!(to, member),
+                _ => fail!("expected the gossip on the member's shard")
+            }
+        }
+        match rx.recv() {
+            RelayTo(to, _) => assert_eq!(to, id),
+            _ => fail!("expected the relay on the eager peer's shard")
+        }
+
+        // Locally it's the first.
+        assert!(shards.route(Deliver(Broadcast::new(vec![1u8]).unwrap()), &[], &[]));
+        match first_rx.recv() {
+            Deliver(_) => {},
+            _ => fail!("expected the delivery on the first shard")
+        }
+        assert!(first_rx.try_recv().is_err() && second_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn share_the_members_every_tick() {
+        let (tx, rx) = channel();
+        let view = MemberView::new();
+        let peer = Peer::empty();
+        let id = peer.id();
+        let driver = Sharded::spawn(Engine::new(Uuid::new_v4(), Config::new()), view.clone(),
+                                    vec![tx.clone(), tx]);
+
+        assert!(driver.send(Received(JoinRequest(peer))));
+        assert!(driver.send(Tick));
+        assert!(driver.send(Publish(Broadcast::new(vec![1u8]).unwrap())));
+        loop {
+            match rx.recv() {
+                Deliver(_) => break,
+                _ => {}
+            }
+        }
+        assert!(view.find(&id).is_some());
+        assert_eq!(view.shard(shard_of(&id, 2), 2).len(), 1);
+    }
+
+    #[test]
+    fn probe_on_the_shards() {
+        let mut config = Config::new();
+        config.probe_interval = Some(20);
+        config.tick_interval = 10;
+        let (tx, rx) = channel();
+        let mut engine = Engine::new(Uuid::new_v4(), config);
+        let peer = Peer::empty();
+        let id = peer.id();
+        engine.handle(Received(Digest(vec![Joined(peer)])), 0);
+        let _driver = Sharded::spawn(engine, MemberView::new(), vec![tx]);
+
+        // It's the shard probing, not the engine.
+        loop {
+            match rx.recv() {
+                SendTo(to, Ping(_, seq)) => {
+                    assert_eq!(to, id);
+                    assert!(seq > SEQS);
+                    break;
+                },
+                _ => {}
+            }
+        }
+    }
+
+    /// Encodes the broadcasts relayed to members, like a handler writing to
+    /// sockets would, and tells once it's done with each.
+    struct Encoding {
+        done: Sender<()>
+    }
+
+    impl Handler for Encoding {
+        fn perform(&mut self, output: Output) {
+            match output {
+                RelayTo(_, broadcast) => {
+                    json::encode(&broadcast.as_bytes().to_vec());
+                    self.done.send(());
+                },
+                _ => {}
+            }
+        }
+    }
+
+    /// Publish through the driver, relayed to a thousand members over the
+    /// shards.
+    fn relay_to_members(b: &mut Bencher, shards: uint) {
+        let (done, finished) = channel();
+        let mut engine = Engine::new(Uuid::new_v4(), Config::new());
+        let joined = Vec::from_fn(1_000, |i| {
+            Joined(Peer::new(Uuid::new_v4(), "10.0.0.1", 4_000 + i as u16))
+        });
+        engine.handle(Received(Digest(joined)), 0);
+        let eager = engine.eager_peers().len();
+        let driver = Sharded::spawn(engine, MemberView::new(),
+                                    Vec::from_fn(shards, |_| Encoding { done: done.clone() }));
+
+        b.iter(|| {
+            driver.send(Publish(Broadcast::new(Vec::from_elem(256, 1u8)).unwrap()));
+            for _ in range(0, eager) {
+                finished.recv();
+            }
+        });
+    }
+
+    #[bench]
+    fn relay_on_a_single_shard(b: &mut Bencher) {
+        relay_to_members(b, 1);
+    }
+
+    #[bench]
+    fn relay_on_four_shards(b: &mut Bencher) {
+        relay_to_members(b, 4);
+    }
+}
@@ -0,0 +1,122 @@
+//! A rough estimate of how far the wall clocks of the other members are
+//! off from ours, so a deadline set by an origin can be checked against
+//! the origin's clock rather than ours.
+//!
+//! Every broadcast carries the origin's wall clock at the time it was sent.
+//! `sent - received` is the clock offset minus the transit delay, so the
+//! highest of the recent samples is the closest to the actual offset. The
+//! estimate errs on the side of the origin's clock being behind, which
+//! makes deadlines lenient rather than strict.
+//!
+//! Measuring latency that way would take the fastest transit for no
+//! latency at all, so latency goes by a moving average of the samples
+//! instead (`smoothed_now`).
+
+use std::collections::{HashMap, RingBuf, Deque};
+use uuid::Uuid;
+
+/// The number of samples kept per member.
+static SAMPLES: uint = 8;
+
+/// How much a sample moves the moving average.
+static ALPHA: f64 = 0.125;
+
+pub struct Skew {
+    samples: HashMap<Uuid, RingBuf<i64>>,
+    smoothed: HashMap<Uuid, f64>
+}
+
+impl Skew {
+    pub fn new() -> Skew {
+        Skew {
+            samples: HashMap::new(),
+            smoothed: HashMap::new()
+        }
+    }
+
+    /// Record that something `id` sent at `remote` (it's wall clock) reached
+    /// us at `local` (ours).
+    pub fn sample(&mut self, id: Uuid, remote: u64, local: u64) {
+        if !self.samples.contains_key(&id) {
+            self.samples.insert(id, RingBuf::new());
+        }
+
+        let sample = remote as i64 - local as i64;
+        let samples = self.samples.find_mut(&id).unwrap();
+        if samples.len() == SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+
+        let smoothed = match self.smoothed.find(&id) {
+            Some(smoothed) => *smoothed + ALPHA * (sample as f64 - *smoothed),
+            None => sample as f64
+        };
+        self.smoothed.insert(id, smoothed);
+    }
+
+    /// How far (in milliseconds) the member's clock is ahead of ours.
+    /// Members we know nothing about are assumed to be in sync.
+    pub fn offset(&self, id: &Uuid) -> i64 {
+        match self.samples.find(id) {
+            Some(samples) => samples.iter().map(|s| *s).max().unwrap_or(0),
+            None => 0
+        }
+    }
+
+    /// Our wall clock translated to the member's clock.
+    pub fn remote_now(&self, id: &Uuid, local: u64) -> u64 {
+        translate(local, self.offset(id))
+    }
+
+    /// Same, but by the moving average of the offsets rather than the
+    /// largest one.
+    pub fn smoothed_now(&self, id: &Uuid, local: u64) -> u64 {
+        let offset = self.smoothed.find(id).map(|s| *s as i64).unwrap_or(0);
+        translate(local, offset)
+    }
+}
+
+fn translate(local: u64, offset: i64) -> u64 {
+    let now = local as i64 + offset;
+    if now < 0 { 0 } else { now as u64 }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn estimate_offsets() {
+        let mut skew = Skew::new();
+        let (ahead, unknown) = (Uuid::new_v4(), Uuid::new_v4());
+
+        // Five seconds ahead of us, with varying transit delays.
+        skew.sample(ahead, 105_000, 100_040);
+        skew.sample(ahead, 106_000, 101_010);
+        assert_eq!(skew.offset(&ahead), 4_990);
+        assert_eq!(skew.remote_now(&ahead, 200_000), 204_990);
+        assert_eq!(skew.offset(&unknown), 0);
+
+        for i in range(0u64, 8) {
+            skew.sample(ahead, 110_000 + i, 110_000 + i);
+        }
+        assert_eq!(skew.offset(&ahead), 0);
+    }
+
+    #[test]
+    fn smooth_over_the_transit() {
+        let mut skew = Skew::new();
+        let ahead = Uuid::new_v4();
+
+        skew.sample(ahead, 105_000, 100_000);
+        assert_eq!(skew.smoothed_now(&ahead, 200_000), 205_000);
+
+        // A slow one moves the average an eighth of the way, and the
+        // fastest doesn't get to decide.
+        skew.sample(ahead, 105_000, 100_800);
+        assert_eq!(skew.smoothed_now(&ahead, 200_000), 204_900);
+        assert_eq!(skew.remote_now(&ahead, 200_000), 205_000);
+    }
+}
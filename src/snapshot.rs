@@ -0,0 +1,327 @@
+//! A point-in-time copy of the node's state (membership and broadcast
+//! history) encoded as a single blob with a checksum. Joiners receive the
+//! whole snapshot in one go instead of having it trickle in item-by-item
+//! through gossip, and the same blob is what we persist to disk.
+//!
+//! Format:
+//!
+//! ```notrust
+//! bitdata Snapshot {
+//!     Snapshot {
+//!         magic: [u8, ..4], // "GSNP"
+//!         version: u8,
+//!         payload_size: u32,
+//!         payload: &[u8],
+//!         checksum: u32 // crc32 of the payload
+//!     }
+//! }
+//! ```
+//!
+//! As of version 2 the payload keeps every broadcast whole (it's id,
+//! origin, namespace and so on), version 1 only kept the raw bytes. Those
+//! are still decoded, as broadcasts of our own.
+
+use std::io::{File, MemWriter, BufReader, IoResult};
+use std::io::fs;
+use uuid::Uuid;
+
+use broadcast::Broadcast;
+use codec::WireBroadcast;
+use crc::crc32;
+use protocol::Peer;
+use result::{GossipResult, GossipError, InvalidSnapshot, io_err};
+
+static MAGIC: &'static [u8] = b"GSNP";
+static VERSION: u8 = 2;
+/// The oldest version we still decode.
+static MIN_VERSION: u8 = 1;
+
+/// What to do when the snapshot can't be persisted, i.e., because the disk
+/// is full or broken.
+#[deriving(Show, Clone, PartialEq)]
+pub enum StoragePolicy {
+    /// Shut the node down. For deployments that rather lose a node than
+    /// have it run without it's state on disk.
+    FailStop,
+    /// Keep going with the snapshot in memory only. The node stays a full
+    /// member, but reports a `Red` health until it's restarted.
+    MemoryOnly
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub struct Snapshot {
+    pub members: Vec<Peer>,
+    /// Every broadcast in the history.
+    pub broadcasts: Vec<WireBroadcast>
+}
+
+fn invalid(desc: &'static str) -> GossipError {
+    GossipError::new(desc, InvalidSnapshot)
+}
+
+fn read_uuid(rd: &mut BufReader) -> GossipResult<Uuid> {
+    let bytes = try!(rd.read_exact(16).map_err(io_err));
+    match Uuid::from_bytes(bytes.as_slice()) {
+        Some(id) => Ok(id),
+        None => Err(invalid("invalid id"))
+    }
+}
+
+fn read_string(rd: &mut BufReader) -> GossipResult<String> {
+    let len = try!(rd.read_be_u16().map_err(io_err)) as uint;
+    match String::from_utf8(try!(rd.read_exact(len).map_err(io_err))) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(invalid("invalid string"))
+    }
+}
+
+fn write_broadcast(wr: &mut MemWriter, broadcast: &WireBroadcast) -> IoResult<()> {
+    try!(wr.write(broadcast.id.as_bytes()));
+    try!(wr.write(broadcast.origin.as_bytes()));
+    try!(wr.write_be_u16(broadcast.namespace.len() as u16));
+    try!(wr.write(broadcast.namespace.as_bytes()));
+    try!(wr.write_be_u32(broadcast.hops));
+    match broadcast.trace {
+        Some(ref trace) => {
+            try!(wr.write_u8(1));
+            try!(wr.write_be_u16(trace.len() as u16));
+            try!(wr.write(trace.as_bytes()));
+        },
+        None => try!(wr.write_u8(0))
+    }
+    try!(wr.write_be_u64(broadcast.sent_at));
+    match broadcast.deadline {
+        Some(deadline) => {
+            try!(wr.write_u8(1));
+            try!(wr.write_be_u64(deadline));
+        },
+        None => try!(wr.write_u8(0))
+    }
+    try!(wr.write_u8(broadcast.sealed as u8));
+    try!(wr.write_be_u32(broadcast.bytes.len() as u32));
+    wr.write(broadcast.bytes.as_slice())
+}
+
+/// A single broadcast as snapshots have it, also what the broadcasts still
+/// waiting on an ack are kept as (see `Config::ack_path`).
+pub fn encode_broadcast(broadcast: &WireBroadcast) -> Vec<u8> {
+    let mut wr = MemWriter::new();
+    // Writing to memory can't fail.
+    write_broadcast(&mut wr, broadcast).unwrap();
+    wr.unwrap()
+}
+
+pub fn decode_broadcast(bytes: &[u8]) -> GossipResult<WireBroadcast> {
+    read_broadcast(&mut BufReader::new(bytes))
+}
+
+fn read_broadcast(rd: &mut BufReader) -> GossipResult<WireBroadcast> {
+    let id = try!(read_uuid(rd));
+    let origin = try!(read_uuid(rd));
+    let namespace = try!(read_string(rd));
+    let hops = try!(rd.read_be_u32().map_err(io_err));
+    let trace = match try!(rd.read_u8().map_err(io_err)) {
+        0 => None,
+        _ => Some(try!(read_string(rd)))
+    };
+    let sent_at = try!(rd.read_be_u64().map_err(io_err));
+    let deadline = match try!(rd.read_u8().map_err(io_err)) {
+        0 => None,
+        _ => Some(try!(rd.read_be_u64().map_err(io_err)))
+    };
+    let sealed = try!(rd.read_u8().map_err(io_err)) != 0;
+    let len = try!(rd.read_be_u32().map_err(io_err)) as uint;
+    let bytes = try!(rd.read_exact(len).map_err(io_err));
+
+    Ok(WireBroadcast {
+        id: id,
+        origin: origin,
+        namespace: namespace,
+        hops: hops,
+        trace: trace,
+        sent_at: sent_at,
+        deadline: deadline,
+        sealed: sealed,
+        bytes: bytes
+    })
+}
+
+impl Snapshot {
+    pub fn new(members: Vec<Peer>, broadcasts: Vec<WireBroadcast>) -> Snapshot {
+        Snapshot {
+            members: members,
+            broadcasts: broadcasts
+        }
+    }
+
+    /// Encode the snapshot into a single blob.
+    pub fn encode(&self) -> Vec<u8> {
+        let payload = self.encode_payload().unwrap();
+        let mut wr = MemWriter::new();
+
+        // Writing to memory can't fail.
+        wr.write(MAGIC).unwrap();
+        wr.write_u8(VERSION).unwrap();
+        wr.write_be_u32(payload.len() as u32).unwrap();
+        wr.write(payload.as_slice()).unwrap();
+        wr.write_be_u32(crc32(payload.as_slice())).unwrap();
+        wr.unwrap()
+    }
+
+    fn encode_payload(&self) -> IoResult<Vec<u8>> {
+        let mut wr = MemWriter::new();
+
+        try!(wr.write_be_u32(self.members.len() as u32));
+        for peer in self.members.iter() {
+            try!(wr.write(peer.id().as_bytes()));
+            try!(wr.write_be_u16(peer.addr().ip.len() as u16));
+            try!(wr.write(peer.addr().ip.as_bytes()));
+            try!(wr.write_be_u16(peer.addr().port));
+        }
+
+        try!(wr.write_be_u32(self.broadcasts.len() as u32));
+        for broadcast in self.broadcasts.iter() {
+            try!(write_broadcast(&mut wr, broadcast));
+        }
+
+        Ok(wr.unwrap())
+    }
+
+    /// Decode a blob produced by `encode`. Nothing is returned unless the
+    /// whole blob is intact, so callers can apply the result atomically.
+    pub fn decode(blob: &[u8]) -> GossipResult<Snapshot> {
+        let mut rd = BufReader::new(blob);
+
+        let magic = try!(rd.read_exact(MAGIC.len()).map_err(io_err));
+        if magic.as_slice() != MAGIC {
+            return Err(invalid("not a snapshot"));
+        }
+
+        let version = try!(rd.read_u8().map_err(io_err));
+        if version < MIN_VERSION || version > VERSION {
+            return Err(invalid("unsupported snapshot version"));
+        }
+
+        let size = try!(rd.read_be_u32().map_err(io_err)) as uint;
+        let payload = try!(rd.read_exact(size).map_err(io_err));
+        let checksum = try!(rd.read_be_u32().map_err(io_err));
+
+        if crc32(payload.as_slice()) != checksum {
+            return Err(invalid("snapshot checksum mismatch"));
+        }
+
+        Snapshot::decode_payload(payload.as_slice(), version)
+    }
+
+    fn decode_payload(payload: &[u8], version: u8) -> GossipResult<Snapshot> {
+        let mut rd = BufReader::new(payload);
+        let mut members = Vec::new();
+        let mut broadcasts = Vec::new();
+
+        let count = try!(rd.read_be_u32().map_err(io_err));
+        for _ in range(0, count) {
+            let id = try!(read_uuid(&mut rd));
+            let ip = try!(read_string(&mut rd));
+            let port = try!(rd.read_be_u16().map_err(io_err));
+            members.push(Peer::new(id, ip.as_slice(), port));
+        }
+
+        let count = try!(rd.read_be_u32().map_err(io_err));
+        for _ in range(0, count) {
+            if version >= 2 {
+                broadcasts.push(try!(read_broadcast(&mut rd)));
+                continue;
+            }
+
+            let len = try!(rd.read_be_u32().map_err(io_err)) as uint;
+            match Broadcast::new(try!(rd.read_exact(len).map_err(io_err))) {
+                Ok(broadcast) => broadcasts.push(WireBroadcast::new(&broadcast)),
+                Err(_) => {}
+            }
+        }
+
+        Ok(Snapshot::new(members, broadcasts))
+    }
+
+    /// Persist the snapshot to `path`. The blob is written to a temporary
+    /// file first so a crash never leaves a half-written snapshot behind.
+    pub fn save(&self, path: &Path) -> GossipResult<()> {
+        let tmp = path.with_extension("tmp");
+
+        {
+            let mut file = try!(File::create(&tmp).map_err(io_err));
+            try!(file.write(self.encode().as_slice()).map_err(io_err));
+            try!(file.fsync().map_err(io_err));
+        }
+
+        fs::rename(&tmp, path).map_err(io_err)
+    }
+
+    pub fn load(path: &Path) -> GossipResult<Snapshot> {
+        let blob = try!(File::open(path).read_to_end().map_err(io_err));
+        Snapshot::decode(blob.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::MemWriter;
+    use broadcast::Broadcast;
+    use codec::WireBroadcast;
+    use crc::crc32;
+    use protocol::Peer;
+    use uuid::Uuid;
+
+    fn snapshot() -> Snapshot {
+        let members = vec![Peer::new(Uuid::new_v4(), "10.0.0.1", 4000),
+                           Peer::new(Uuid::new_v4(), "10.0.0.2", 4001)];
+        let mut broadcast = Broadcast::new(vec![1u8, 2, 3]).unwrap();
+        broadcast.set_origin(Uuid::new_v4());
+        broadcast.set_namespace("billing");
+        broadcast.hop();
+        broadcast.set_deadline(1_000);
+        let plain = Broadcast::new(vec![4u8]).unwrap();
+        Snapshot::new(members, vec![WireBroadcast::new(&broadcast), WireBroadcast::new(&plain)])
+    }
+
+    #[test]
+    fn roundtrip() {
+        let snapshot = snapshot();
+        let blob = snapshot.encode();
+        assert_eq!(Snapshot::decode(blob.as_slice()).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn reject_corrupted() {
+        let mut blob = snapshot().encode();
+        let middle = blob.len() / 2;
+        *blob.get_mut(middle) ^= 0xFF;
+        assert!(Snapshot::decode(blob.as_slice()).is_err());
+
+        let blob = snapshot().encode();
+        assert!(Snapshot::decode(blob.slice_to(blob.len() - 1)).is_err());
+    }
+
+    #[test]
+    fn decode_version_1() {
+        let mut payload = MemWriter::new();
+        payload.write_be_u32(0).unwrap();
+        payload.write_be_u32(1).unwrap();
+        payload.write_be_u32(1).unwrap();
+        payload.write(&[7u8]).unwrap();
+        let payload = payload.unwrap();
+
+        let mut blob = MemWriter::new();
+        blob.write(b"GSNP").unwrap();
+        blob.write_u8(1).unwrap();
+        blob.write_be_u32(payload.len() as u32).unwrap();
+        blob.write(payload.as_slice()).unwrap();
+        blob.write_be_u32(crc32(payload.as_slice())).unwrap();
+
+        // Only the bytes were kept, the rest is made up.
+        let snapshot = Snapshot::decode(blob.unwrap().as_slice()).unwrap();
+        assert_eq!(snapshot.broadcasts.len(), 1);
+        assert_eq!(snapshot.broadcasts.get(0).bytes, vec![7u8]);
+    }
+}
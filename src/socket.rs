@@ -0,0 +1,140 @@
+//! Everything platform specific about the sockets, so the rest of the
+//! transport reads the same everywhere. The implementations are picked
+//! with `cfg` and are expected to behave the same, which the tests below
+//! check on every platform CI runs on.
+//!
+//! Differences worth knowing about:
+//!
+//! * Windows has no unix domain sockets (the std library maps them onto
+//!   named pipes, which behave differently enough), so `bind_local` fails
+//!   with `Unsupported` there.
+//! * Windows doesn't let us set the keepalive idle time through the socket
+//!   options, so keepalives are simply turned on and the system's interval
+//!   applies.
+
+use std::io::TcpStream;
+use std::io::net::addrinfo::get_host_addresses;
+use std::io::net::ip::SocketAddr;
+use std::io::net::unix::{UnixListener, UnixAcceptor};
+use std::io::Listener;
+
+use stream::SockAddr;
+use result::{GossipResult, GossipError, NodeUnreachable, Unsupported, io_err};
+
+/// Options applied to every connection we open or accept.
+#[deriving(Show, Clone, PartialEq)]
+pub struct SocketOptions {
+    /// Disable Nagle's algorithm. Gossip messages are small and latency
+    /// matters more than the few bytes saved.
+    pub nodelay: bool,
+    /// Send keepalives after the connection was idle for this many seconds.
+    pub keepalive: Option<uint>,
+    /// Give up connecting after this many milliseconds.
+    pub connect_timeout: Option<u64>,
+    /// Hang up on a connection that sent nothing for this many
+    /// milliseconds, we dial again once there's something to say.
+    pub read_timeout: Option<u64>,
+    /// Give up on (and hang up) a frame that couldn't be written within
+    /// this many milliseconds, the peer stopped reading.
+    pub write_timeout: Option<u64>
+}
+
+impl SocketOptions {
+    pub fn new() -> SocketOptions {
+        SocketOptions {
+            nodelay: true,
+            keepalive: Some(30),
+            connect_timeout: Some(5_000),
+            read_timeout: Some(60_000),
+            write_timeout: Some(5_000)
+        }
+    }
+}
+
+/// Apply the options to a freshly opened (or accepted) stream.
+pub fn configure(stream: &mut TcpStream, options: &SocketOptions) -> GossipResult<()> {
+    try!(stream.set_nodelay(options.nodelay).map_err(io_err));
+    platform::keepalive(stream, options.keepalive).map_err(io_err)
+}
+
+/// Connect to the address, within the connect timeout if there's one.
+pub fn connect(addr: &SockAddr, options: &SocketOptions) -> GossipResult<TcpStream> {
+    let timeout = match options.connect_timeout {
+        Some(timeout) => timeout,
+        None => return TcpStream::connect(addr.ip.as_slice(), addr.port).map_err(io_err)
+    };
+
+    // Only the first address the name resolves to, like `connect` does.
+    let ips = try!(get_host_addresses(addr.ip.as_slice()).map_err(io_err));
+    match ips.as_slice().get(0) {
+        Some(&ip) => {
+            TcpStream::connect_timeout(SocketAddr { ip: ip, port: addr.port }, timeout)
+                .map_err(io_err)
+        },
+        None => Err(GossipError::new("the address doesn't resolve", NodeUnreachable))
+    }
+}
+
+/// Whether nodes on this platform can talk over unix domain sockets.
+pub fn supports_local() -> bool {
+    platform::LOCAL_SOCKETS
+}
+
+/// Listen on a unix domain socket, for nodes sharing a host.
+pub fn bind_local(path: &Path) -> GossipResult<UnixAcceptor> {
+    if !supports_local() {
+        return Err(GossipError::new("unix sockets aren't available on this platform",
+                                    Unsupported));
+    }
+
+    UnixListener::bind(path).listen().map_err(io_err)
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::io::{TcpStream, IoResult};
+
+    pub static LOCAL_SOCKETS: bool = true;
+
+    pub fn keepalive(stream: &mut TcpStream, seconds: Option<uint>) -> IoResult<()> {
+        stream.set_keepalive(seconds)
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::io::{TcpStream, IoResult};
+
+    pub static LOCAL_SOCKETS: bool = false;
+
+    /// Only on or off, the idle time needs `WSAIoctl`.
+    pub fn keepalive(stream: &mut TcpStream, seconds: Option<uint>) -> IoResult<()> {
+        stream.set_keepalive(seconds.map(|_| 0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{TcpListener, TcpStream, Listener};
+    use std::io::test::next_test_unix;
+
+    #[test]
+    fn configure_streams() {
+        let listener = TcpListener::bind("127.0.0.1", 0).unwrap();
+        let port = listener.socket_name().unwrap().port;
+        let _acceptor = listener.listen().unwrap();
+
+        let mut stream = TcpStream::connect("127.0.0.1", port).unwrap();
+        assert!(configure(&mut stream, &SocketOptions::new()).is_ok());
+
+        let mut options = SocketOptions::new();
+        options.keepalive = None;
+        assert!(configure(&mut stream, &options).is_ok());
+    }
+
+    #[test]
+    fn local_sockets_where_supported() {
+        assert_eq!(bind_local(&next_test_unix()).is_ok(), supports_local());
+    }
+}
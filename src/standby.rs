@@ -0,0 +1,320 @@
+//! Warm standbys. A primary shares it's identity, the namespaces it's
+//! subscribed to and the broadcasts it delivered lately through a file (see
+//! `Config::standby_path`); a standby started on the same file takes on the
+//! same identity and stays passive: it never relays, and instead of
+//! delivering broadcasts it holds on to the ones the primary might not have
+//! delivered yet. On failover (see `Node::take_over`) the standby delivers
+//! exactly those and carries on as the primary, from the primary's cursor:
+//! broadcasts the primary delivered that only reach us afterwards aren't
+//! delivered again.
+//!
+//! Format:
+//!
+//! ```notrust
+//! bitdata Shared {
+//!     Shared {
+//!         magic: [u8, ..4], // "GSBY"
+//!         version: u8,
+//!         id: [u8, ..16],
+//!         namespaces: u32, // followed by each (u32 length, utf-8)
+//!         delivered: u32, // followed by each id
+//!         checksum: u32 // crc32 of everything before it
+//!     }
+//! }
+//! ```
+
+use std::collections::{HashSet, RingBuf, Deque};
+use std::io::{File, MemWriter, BufReader, IoResult};
+use std::io::fs;
+use std::mem;
+use uuid::Uuid;
+
+use broadcast::Broadcast;
+use crc::crc32;
+use result::{GossipResult, GossipError, InvalidSnapshot, io_err};
+
+static MAGIC: &'static [u8] = b"GSBY";
+static VERSION: u8 = 1;
+
+/// How many of the latest deliveries the primary shares, and how many
+/// broadcasts the standby holds on to.
+pub static CURSOR_SIZE: uint = 4096;
+
+#[deriving(Show, Clone, PartialEq)]
+pub struct Shared {
+    pub id: Uuid,
+    /// Every namespace the primary has subscribers in, sorted.
+    pub namespaces: Vec<String>,
+    /// The broadcasts the primary delivered lately, oldest first.
+    pub delivered: Vec<Uuid>
+}
+
+fn invalid(desc: &'static str) -> GossipError {
+    GossipError::new(desc, InvalidSnapshot)
+}
+
+impl Shared {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut wr = MemWriter::new();
+
+        // Writing to memory can't fail.
+        wr.write(MAGIC).unwrap();
+        wr.write_u8(VERSION).unwrap();
+        wr.write(self.id.as_bytes()).unwrap();
+        wr.write_be_u32(self.namespaces.len() as u32).unwrap();
+        for namespace in self.namespaces.iter() {
+            wr.write_be_u32(namespace.len() as u32).unwrap();
+            wr.write_str(namespace.as_slice()).unwrap();
+        }
+        wr.write_be_u32(self.delivered.len() as u32).unwrap();
+        for id in self.delivered.iter() {
+            wr.write(id.as_bytes()).unwrap();
+        }
+
+        let checksum = crc32(wr.get_ref());
+        wr.write_be_u32(checksum).unwrap();
+        wr.unwrap()
+    }
+
+    pub fn decode(blob: &[u8]) -> GossipResult<Shared> {
+        if blob.len() < MAGIC.len() + 1 + 16 + 12 || !blob.starts_with(MAGIC) {
+            return Err(invalid("not a standby file"));
+        }
+        if blob[MAGIC.len()] != VERSION {
+            return Err(invalid("unsupported standby file version"));
+        }
+
+        let (body, checksum) = (blob.slice_to(blob.len() - 4), blob.slice_from(blob.len() - 4));
+        let checksum = BufReader::new(checksum).read_be_u32().unwrap();
+        if crc32(body) != checksum {
+            return Err(invalid("standby file checksum mismatch"));
+        }
+
+        Shared::decode_body(body.slice_from(MAGIC.len() + 1))
+            .map_err(|_| invalid("truncated standby file"))
+    }
+
+    fn decode_body(body: &[u8]) -> IoResult<Shared> {
+        let mut rd = BufReader::new(body);
+        let id = try!(read_id(&mut rd));
+
+        let mut namespaces = Vec::new();
+        for _ in range(0, try!(rd.read_be_u32())) {
+            let len = try!(rd.read_be_u32()) as uint;
+            let bytes = try!(rd.read_exact(len));
+            namespaces.push(String::from_utf8_lossy(bytes.as_slice()).into_string());
+        }
+
+        let mut delivered = Vec::new();
+        for _ in range(0, try!(rd.read_be_u32())) {
+            delivered.push(try!(read_id(&mut rd)));
+        }
+
+        Ok(Shared {
+            id: id,
+            namespaces: namespaces,
+            delivered: delivered
+        })
+    }
+
+    /// Written to a temporary file first, so the standby never reads a
+    /// half-written one.
+    pub fn save(&self, path: &Path) -> GossipResult<()> {
+        let tmp = path.with_extension("tmp");
+
+        {
+            let mut file = try!(File::create(&tmp).map_err(io_err));
+            try!(file.write(self.encode().as_slice()).map_err(io_err));
+            try!(file.fsync().map_err(io_err));
+        }
+
+        fs::rename(&tmp, path).map_err(io_err)
+    }
+
+    pub fn load(path: &Path) -> GossipResult<Shared> {
+        let blob = try!(File::open(path).read_to_end().map_err(io_err));
+        Shared::decode(blob.as_slice())
+    }
+}
+
+fn read_id(rd: &mut BufReader) -> IoResult<Uuid> {
+    let bytes = try!(rd.read_exact(16));
+    Ok(Uuid::from_bytes(bytes.as_slice()).unwrap())
+}
+
+/// The primary's side.
+pub struct Cursor {
+    delivered: RingBuf<Uuid>,
+    /// The same, to look them up.
+    ids: HashSet<Uuid>,
+    /// What we shared last, so unchanged state isn't written over and over.
+    shared: Option<Shared>
+}
+
+impl Cursor {
+    pub fn new() -> Cursor {
+        Cursor {
+            delivered: RingBuf::new(),
+            ids: HashSet::new(),
+            shared: None
+        }
+    }
+
+    /// Carry on from the primary's cursor, once we took over.
+    pub fn resume(delivered: &[Uuid]) -> Cursor {
+        let mut cursor = Cursor::new();
+        for id in delivered.iter() {
+            cursor.delivered(*id);
+        }
+        cursor
+    }
+
+    pub fn delivered(&mut self, id: Uuid) {
+        if self.delivered.len() == CURSOR_SIZE {
+            match self.delivered.pop_front() {
+                Some(oldest) => { self.ids.remove(&oldest); },
+                None => {}
+            }
+        }
+        self.delivered.push_back(id);
+        self.ids.insert(id);
+    }
+
+    /// Whether the broadcast is among the latest deliveries.
+    pub fn has_delivered(&self, id: &Uuid) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// What to share with the standby, if anything changed since last
+    /// time.
+    pub fn share(&mut self, id: Uuid, mut namespaces: Vec<String>) -> Option<Shared> {
+        namespaces.sort();
+        namespaces.dedup();
+
+        let shared = Shared {
+            id: id,
+            namespaces: namespaces,
+            delivered: self.delivered.iter().map(|id| *id).collect()
+        };
+
+        if self.shared.as_ref() == Some(&shared) {
+            return None;
+        }
+
+        self.shared = Some(shared.clone());
+        Some(shared)
+    }
+}
+
+/// The standby's side: every broadcast of the primary's namespaces it might
+/// not have delivered yet.
+pub struct Standby {
+    namespaces: Vec<String>,
+    /// What the primary delivered lately, oldest first.
+    history: Vec<Uuid>,
+    delivered: HashSet<Uuid>,
+    pending: RingBuf<Broadcast>
+}
+
+impl Standby {
+    pub fn new(shared: &Shared) -> Standby {
+        let mut standby = Standby {
+            namespaces: Vec::new(),
+            history: Vec::new(),
+            delivered: HashSet::new(),
+            pending: RingBuf::new()
+        };
+        standby.mirror(shared);
+        standby
+    }
+
+    /// Catch up with what the primary shared.
+    pub fn mirror(&mut self, shared: &Shared) {
+        self.namespaces = shared.namespaces.clone();
+        self.history = shared.delivered.clone();
+        self.delivered = shared.delivered.iter().map(|id| *id).collect();
+
+        let pending = mem::replace(&mut self.pending, RingBuf::new());
+        for broadcast in pending.move_iter() {
+            self.hold(broadcast);
+        }
+    }
+
+    /// Instead of delivering the broadcast.
+    pub fn hold(&mut self, broadcast: Broadcast) {
+        let namespace = broadcast.namespace().to_string();
+        if !self.namespaces.contains(&namespace) || self.delivered.contains(&broadcast.id()) {
+            return;
+        }
+
+        if self.pending.len() == CURSOR_SIZE {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(broadcast);
+    }
+
+    /// Everything the primary didn't deliver, in the order we got it, and
+    /// the cursor to carry on from.
+    pub fn take(self) -> (Vec<Broadcast>, Cursor) {
+        let cursor = Cursor::resume(self.history.as_slice());
+        (self.pending.move_iter().collect(), cursor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::TempDir;
+    use uuid::Uuid;
+
+    use broadcast::Broadcast;
+
+    #[test]
+    fn share_through_a_file() {
+        let mut cursor = Cursor::new();
+        let id = Uuid::new_v4();
+        cursor.delivered(Uuid::new_v4());
+
+        let shared = cursor.share(id, vec!["default".to_string(), "billing".to_string()]);
+        let shared = shared.unwrap();
+        assert_eq!(shared.namespaces, vec!["billing".to_string(), "default".to_string()]);
+        assert!(cursor.share(id, vec!["default".to_string(), "billing".to_string()]).is_none());
+
+        let dir = TempDir::new("standby").unwrap();
+        let path = dir.path().join("standby");
+        shared.save(&path).unwrap();
+        assert_eq!(Shared::load(&path).unwrap(), shared);
+
+        let mut blob = shared.encode();
+        *blob.get_mut(6) ^= 1;
+        assert!(Shared::decode(blob.as_slice()).is_err());
+    }
+
+    #[test]
+    fn hold_what_the_primary_didnt_deliver() {
+        let (a, b, c) = (Broadcast::new(vec![1u8]).unwrap(), Broadcast::new(vec![2u8]).unwrap(),
+                         Broadcast::new(vec![3u8]).unwrap());
+        let mut other = Broadcast::new(vec![4u8]).unwrap();
+        other.set_namespace("billing");
+
+        let mut shared = Shared {
+            id: Uuid::new_v4(),
+            namespaces: vec!["default".to_string()],
+            delivered: vec![a.id()]
+        };
+        let mut standby = Standby::new(&shared);
+        standby.hold(a.clone());
+        standby.hold(b.clone());
+        standby.hold(c.clone());
+        standby.hold(other);
+
+        shared.delivered.push(b.id());
+        standby.mirror(&shared);
+
+        let (pending, cursor) = standby.take();
+        let ids: Vec<Uuid> = pending.iter().map(|b| b.id()).collect();
+        assert_eq!(ids, vec![c.id()]);
+        assert!(cursor.has_delivered(&a.id()) && cursor.has_delivered(&b.id()));
+        assert!(!cursor.has_delivered(&c.id()));
+    }
+}
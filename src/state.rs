@@ -1,38 +1,199 @@
 use std::collections::hashmap::HashSet;
+use std::collections::{RingBuf, Deque};
 use protocol::{Health, Yellow};
 use broadcast::Broadcast;
+use uuid::Uuid;
+
+use journal::{Link, Eager, Lazy};
+use history::History;
+use merkle::Merkle;
 
 pub struct State {
     eager: HashSet<String>,
     lazy: HashSet<String>,
     health: Health,
-    broadcasts: Vec<Broadcast>
+    history: History,
+    /// The ids of the broadcasts we've seen lately, used to drop
+    /// duplicates. Past `max_seen` the oldest are forgotten, unless we still
+    /// retain the broadcast.
+    seen: HashSet<Uuid>,
+    /// The same ids, oldest first.
+    seen_order: RingBuf<Uuid>,
+    max_seen: uint
 }
 
 impl State {
     /// Create a new default State that starts a new cluster in a
-    /// Yellow state.
+    /// Yellow state, keeping every broadcast.
     pub fn new() -> State {
+        State::with_retention(None, None, ::std::uint::MAX)
+    }
+
+    /// Keep broadcasts for `max_age` milliseconds and up to `max_bytes`
+    /// (see `history`), and the ids of up to `max_seen` of them.
+    pub fn with_retention(max_age: Option<u64>, max_bytes: Option<uint>,
+                          max_seen: uint) -> State {
         State {
             eager: HashSet::new(),
             lazy: HashSet::new(),
             health: Yellow,
-            broadcasts: Vec::new()
+            history: History::new(max_age, max_bytes),
+            seen: HashSet::new(),
+            seen_order: RingBuf::new(),
+            max_seen: max_seen
+        }
+    }
+
+    pub fn eager<'a>(&'a self) -> &'a HashSet<String> {
+        &self.eager
+    }
+
+    pub fn lazy<'a>(&'a self) -> &'a HashSet<String> {
+        &self.lazy
+    }
+
+    /// The set the peer is in, if any.
+    pub fn link(&self, peer: &str) -> Option<Link> {
+        let peer = peer.to_string();
+        if self.eager.contains(&peer) {
+            Some(Eager)
+        } else if self.lazy.contains(&peer) {
+            Some(Lazy)
+        } else {
+            None
+        }
+    }
+
+    /// Move the peer into the set (or out of both), returning the one it
+    /// was in.
+    pub fn set_link(&mut self, peer: &str, link: Option<Link>) -> Option<Link> {
+        let previous = self.link(peer);
+        let peer = peer.to_string();
+
+        self.eager.remove(&peer);
+        self.lazy.remove(&peer);
+        match link {
+            Some(Eager) => { self.eager.insert(peer); },
+            Some(Lazy) => { self.lazy.insert(peer); },
+            None => {}
+        }
+
+        previous
+    }
+
+    /// Mark a broadcast as seen. Returns `false` if we'd already seen it.
+    pub fn observe(&mut self, id: Uuid) -> bool {
+        if !self.seen.insert(id) {
+            return false;
+        }
+        self.seen_order.push_back(id);
+
+        // Those we still retain go to the back of the line instead.
+        let mut passes = self.seen_order.len();
+        while self.seen.len() > self.max_seen && passes > 0 {
+            passes -= 1;
+            match self.seen_order.pop_front() {
+                Some(oldest) if self.history.find(&oldest).is_some() => {
+                    self.seen_order.push_back(oldest);
+                },
+                Some(oldest) => { self.seen.remove(&oldest); },
+                None => break
+            }
+        }
+        true
+    }
+
+    pub fn has_seen(&self, id: &Uuid) -> bool {
+        self.seen.contains(id)
+    }
+
+    /// The broadcasts we've seen and still retain, oldest first.
+    pub fn broadcasts<'a>(&'a self) -> Vec<&'a Broadcast> {
+        self.history.broadcasts()
+    }
+
+    /// A broadcast we still retain, i.e., to serve it to a peer that missed
+    /// it.
+    pub fn retained<'a>(&'a self, id: &Uuid) -> Option<&'a Broadcast> {
+        self.history.find(id)
+    }
+
+    /// How many broadcasts we retain.
+    pub fn retained_len(&self) -> uint {
+        self.history.len()
+    }
+
+    /// The summary of what we retain, see `merkle`.
+    pub fn tree<'a>(&'a self) -> &'a Merkle {
+        self.history.tree()
+    }
+
+    pub fn record(&mut self, broadcast: Broadcast, now: u64) {
+        self.history.record(broadcast, now);
+    }
+
+    /// Stop retaining the broadcasts past their retention window.
+    pub fn expire(&mut self, now: u64) {
+        self.history.expire(now);
+    }
+
+    pub fn health(&self) -> Health {
+        self.health.clone()
+    }
+
+    /// Update the health, returning `true` if it actually changed.
+    pub fn set_health(&mut self, health: Health) -> bool {
+        if self.health == health {
+            return false;
         }
+
+        self.health = health;
+        true
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use protocol::Yellow;
+    use protocol::{Yellow, Red};
+    use broadcast::Broadcast;
+    use uuid::Uuid;
 
     #[test]
     fn default_state() {
         let s = State::new();
         assert_eq!(s.eager.len(), 0);
         assert_eq!(s.lazy.len(), 0);
-        assert_eq!(s.broadcasts.len(), 0);
+        assert_eq!(s.history.len(), 0);
         assert_eq!(s.health, Yellow);
     }
+
+    #[test]
+    fn set_health() {
+        let mut s = State::new();
+        assert!(!s.set_health(Yellow));
+        assert!(s.set_health(Red));
+        assert_eq!(s.health(), Red);
+    }
+
+    #[test]
+    fn observe_once() {
+        let mut s = State::new();
+        let id = Uuid::new_v4();
+        assert!(s.observe(id));
+        assert!(!s.observe(id));
+    }
+
+    #[test]
+    fn forget_the_oldest_ids() {
+        let mut s = State::with_retention(None, None, 2);
+        let (a, b, c) = (Broadcast::new(vec![1u8]).unwrap(), Uuid::new_v4(), Uuid::new_v4());
+        assert!(s.observe(a.id()));
+        s.record(a.clone(), 0);
+        assert!(s.observe(b) && s.observe(c));
+
+        // Retained ones aren't forgotten, the oldest of the others is.
+        assert!(!s.observe(a.id()) && !s.observe(c));
+        assert!(s.observe(b));
+    }
 }
@@ -0,0 +1,220 @@
+//! Runtime statistics about how well the broadcast tree is doing. A
+//! healthy Plumtree has a redundancy ratio close to zero, since payloads
+//! only travel along the eager (tree) edges.
+//!
+//! The same counters are kept per topic as well, for application owners to
+//! see what their topic costs and whether it's broadcasts are being shed.
+//! Topics come off the wire, so past `MAX_TOPICS` of them the rest are
+//! counted together under `OTHER_TOPICS`.
+
+use std::collections::HashMap;
+
+pub static MAX_TOPICS: uint = 1024;
+pub static OTHER_TOPICS: &'static str = "<other>";
+
+/// Counters updated as broadcasts flow through the node.
+pub struct Counters {
+    unique: u64,
+    duplicates: u64,
+    expired: u64,
+    deferred: u64,
+    hop_samples: u64,
+    hop_total: u64,
+    /// Keyed by the namespace qualified topic, see `Broadcast::topic`.
+    topics: HashMap<String, TopicStats>
+}
+
+/// A point-in-time view of the protocol statistics.
+#[deriving(Show, Clone, PartialEq)]
+pub struct ProtocolStats {
+    /// Unique broadcasts received.
+    pub unique: u64,
+    /// Duplicate copies received of broadcasts we already had.
+    pub duplicates: u64,
+    /// Broadcasts dropped because they reached us after their deadline.
+    pub expired: u64,
+    /// Ticks that deferred their work because the CPU budget was spent.
+    pub budget_exceeded: u64,
+    /// `duplicates / unique`, if we've seen anything at all.
+    pub redundancy: Option<f64>,
+    /// The average number of hops it took a broadcast to reach us.
+    pub average_hops: Option<f64>,
+    pub eager: uint,
+    pub lazy: uint,
+    /// `eager / lazy`, if there are any lazy peers.
+    pub eager_lazy_ratio: Option<f64>
+}
+
+/// What a single topic costs the local node.
+#[deriving(Show, Clone, PartialEq)]
+pub struct TopicStats {
+    /// Broadcasts delivered to our subscribers, our own included.
+    pub delivered: u64,
+    /// Duplicate copies discarded.
+    pub deduped: u64,
+    /// Dropped because they reached us after their deadline.
+    pub expired: u64,
+    /// Shed because their origin went over it's quota.
+    pub dropped: u64,
+    /// The payload bytes delivered, see `average_size`.
+    pub bytes: u64
+}
+
+impl TopicStats {
+    fn new() -> TopicStats {
+        TopicStats {
+            delivered: 0,
+            deduped: 0,
+            expired: 0,
+            dropped: 0,
+            bytes: 0
+        }
+    }
+
+    /// The average size of the broadcasts delivered, in bytes.
+    pub fn average_size(&self) -> Option<f64> {
+        ratio(self.bytes as f64, self.delivered as f64)
+    }
+}
+
+impl Counters {
+    pub fn new() -> Counters {
+        Counters {
+            unique: 0,
+            duplicates: 0,
+            expired: 0,
+            deferred: 0,
+            hop_samples: 0,
+            hop_total: 0,
+            topics: HashMap::new()
+        }
+    }
+
+    /// A broadcast we hadn't seen before reached us after `hops` hops.
+    pub fn unique(&mut self, hops: u32) {
+        self.unique += 1;
+        self.hop_samples += 1;
+        self.hop_total += hops as u64;
+    }
+
+    pub fn duplicate(&mut self, topic: &str) {
+        self.duplicates += 1;
+        self.topic(topic).deduped += 1;
+    }
+
+    pub fn expired(&mut self, topic: &str) {
+        self.expired += 1;
+        self.topic(topic).expired += 1;
+    }
+
+    /// A broadcast of `len` bytes went to our subscribers.
+    pub fn delivered(&mut self, topic: &str, len: uint) {
+        let stats = self.topic(topic);
+        stats.delivered += 1;
+        stats.bytes += len as u64;
+    }
+
+    pub fn dropped(&mut self, topic: &str) {
+        self.topic(topic).dropped += 1;
+    }
+
+    pub fn deferred(&mut self) {
+        self.deferred += 1;
+    }
+
+    pub fn stats(&self, eager: uint, lazy: uint) -> ProtocolStats {
+        ProtocolStats {
+            unique: self.unique,
+            duplicates: self.duplicates,
+            expired: self.expired,
+            budget_exceeded: self.deferred,
+            redundancy: ratio(self.duplicates as f64, self.unique as f64),
+            average_hops: ratio(self.hop_total as f64, self.hop_samples as f64),
+            eager: eager,
+            lazy: lazy,
+            eager_lazy_ratio: ratio(eager as f64, lazy as f64)
+        }
+    }
+
+    /// Every topic we've seen a broadcast of, sorted by topic.
+    pub fn topics(&self) -> Vec<(String, TopicStats)> {
+        let mut topics: Vec<(String, TopicStats)> = self.topics.iter()
+            .map(|(topic, stats)| (topic.clone(), stats.clone()))
+            .collect();
+        topics.sort_by(|a, b| a.ref0().cmp(b.ref0()));
+        topics
+    }
+
+    fn topic<'a>(&'a mut self, topic: &str) -> &'a mut TopicStats {
+        let known = self.topics.contains_key_equiv(&topic);
+        let topic = if known || self.topics.len() < MAX_TOPICS { topic } else { OTHER_TOPICS };
+        self.topics.find_or_insert_with(topic.to_string(), |_| TopicStats::new())
+    }
+}
+
+fn ratio(a: f64, b: f64) -> Option<f64> {
+    if b == 0.0 {
+        None
+    } else {
+        Some(a / b)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ratios() {
+        let mut counters = Counters::new();
+        assert_eq!(counters.stats(0, 0).redundancy, None);
+
+        counters.unique(1);
+        counters.unique(3);
+        counters.duplicate("default/a");
+
+        let stats = counters.stats(3, 6);
+        assert_eq!(stats.redundancy, Some(0.5));
+        assert_eq!(stats.average_hops, Some(2.0));
+        assert_eq!(stats.eager_lazy_ratio, Some(0.5));
+    }
+
+    #[test]
+    fn per_topic() {
+        let mut counters = Counters::new();
+        counters.delivered("default/a", 10);
+        counters.delivered("default/a", 30);
+        counters.duplicate("default/a");
+        counters.dropped("default/b");
+        counters.expired("default/b");
+
+        let topics = counters.topics();
+        assert_eq!(topics.len(), 2);
+
+        let &(ref topic, ref a) = topics.get(0);
+        assert_eq!(topic.as_slice(), "default/a");
+        assert_eq!((a.delivered, a.deduped, a.average_size()), (2, 1, Some(20.0)));
+
+        let b = topics.get(1).ref1();
+        assert_eq!((b.dropped, b.expired, b.average_size()), (1, 1, None));
+        assert_eq!(counters.stats(0, 0).duplicates, 1);
+    }
+
+    #[test]
+    fn count_too_many_topics_together() {
+        let mut counters = Counters::new();
+        for i in range(0, MAX_TOPICS + 2) {
+            counters.delivered(format!("default/{}", i).as_slice(), 1);
+        }
+        counters.delivered("default/0", 1);
+
+        let topics = counters.topics();
+        assert_eq!(topics.len(), MAX_TOPICS + 1);
+        let delivered = |name: &str| {
+            let found = topics.iter().find(|&&(ref topic, _)| topic.as_slice() == name);
+            found.unwrap().ref1().delivered
+        };
+        assert_eq!(delivered(OTHER_TOPICS), 2);
+        assert_eq!(delivered("default/0"), 2);
+    }
+}
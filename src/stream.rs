@@ -33,19 +33,47 @@ impl Iterator<Callback> for Stream {
 
 pub struct Response {
     id: Uuid,
-    stream: Stream,
+    /// Broadcasts delivered locally (i.e., our own) don't have a stream
+    /// to respond on.
+    stream: Option<Stream>,
+    /// Set when the subscriber is expected to `ack` the broadcast.
+    acks: Option<Sender<Uuid>>,
     wr: MemWriter
 }
 
 impl Response {
-    pub fn new(id: Uuid, stream: Stream) -> Response {
+    pub fn new(id: Uuid, stream: Option<Stream>) -> Response {
         Response {
             id: id,
             stream: stream,
+            acks: None,
             wr: MemWriter::new()
         }
     }
 
+    pub fn with_acks(id: Uuid, stream: Option<Stream>, acks: Sender<Uuid>) -> Response {
+        Response {
+            id: id,
+            stream: stream,
+            acks: Some(acks),
+            wr: MemWriter::new()
+        }
+    }
+
+    /// Let the node know we're done processing the broadcast. Subscribers
+    /// created with `Node::incoming_acked` have any broadcast they don't ack
+    /// in time delivered again. For everybody else this is a no-op.
+    pub fn ack(&self) -> GossipResult<()> {
+        match self.acks {
+            Some(ref acks) => {
+                let _ = acks.send_opt(self.id);
+            },
+            None => {}
+        }
+
+        Ok(())
+    }
+
     /// Acknowledge the incoming broadcast with a simple OK
     /// message back. Responses aren't always required, but it's
     /// often very useful to have a nice short way of saying
@@ -55,7 +83,13 @@ impl Response {
     /// allow multiple responses. So the response will be moved and
     /// further responses won't be possible.
     pub fn ok(mut self) -> GossipResult<()> {
-        write!(self.stream.stream, "{},OK", self.id);
+        match self.stream {
+            Some(ref mut stream) => {
+                write!(stream.stream, "{},OK", self.id);
+            },
+            None => {}
+        }
+
         Ok(())
     }
 }
@@ -69,7 +103,7 @@ impl Drop for Response {
 
 /// We work with an ip and port a lot. Let's make it easier
 /// and bundle these in a single record.
-#[deriving(Show, Clone, Eq, PartialEq, Hash)]
+#[deriving(Show, Clone, Eq, PartialEq, Hash, Encodable, Decodable)]
 pub struct SockAddr {
     /// Most of the Rust APIs now use a string for the ip
     /// instead of the IpSockAddr enum variants (v4, v6).
@@ -0,0 +1,339 @@
+//! Local subscribers to broadcasts. A plain subscriber receives a copy of
+//! every broadcast within it's namespace. Subscribers registered within a
+//! group share the work instead: each broadcast goes to exactly one member
+//! of the group (round-robin), so heavy topics can be processed in parallel
+//! within a single process.
+//!
+//! Channels are unbounded, so a subscriber that stops reading it's own would
+//! have us queue for it forever. Whatever's been waiting on a subscriber for
+//! longer than `Config::stall_threshold` makes it stalled, and it's dealt
+//! with per `Config::stall_policy` until it catches up.
+
+use std::collections::{HashMap, RingBuf, Deque};
+use std::sync::Arc;
+use std::sync::atomics::{AtomicUint, SeqCst};
+
+/// What to do about a stalled subscriber, on top of the
+/// `SubscriberStalled` event.
+#[deriving(Show, Clone, PartialEq)]
+pub enum StallPolicy {
+    /// Keep queueing for it, up to this many values. What comes after that
+    /// is dropped until it caught up.
+    BufferStalled(uint),
+    /// Drop what it would have received until it caught up. Members of a
+    /// group still receive their share, the stalled ones are skipped.
+    DropStalled,
+    /// Deliver nothing to it ever again.
+    DisconnectStalled
+}
+
+/// How many values the subscriber took off it's channel so far, kept up by
+/// the other end (see `Incoming`).
+pub type Taken = Arc<AtomicUint>;
+
+/// A subscriber's channel, along with what's still queued on it.
+struct Feed<T> {
+    tx: Sender<T>,
+    taken: Taken,
+    /// When each value still queued was sent, oldest first.
+    queued: RingBuf<u64>,
+    /// How many were taken as of the last look at `taken`.
+    seen: uint,
+    stalled: bool
+}
+
+impl<T: Send> Feed<T> {
+    fn new(tx: Sender<T>, taken: Taken) -> Feed<T> {
+        Feed {
+            tx: tx,
+            taken: taken,
+            queued: RingBuf::new(),
+            seen: 0,
+            stalled: false
+        }
+    }
+
+    /// Hands the value back if the subscriber hung up. Only values sent at
+    /// some time are kept track of, see `Subscribers::deliver`.
+    fn send(&mut self, value: T, sent: Option<u64>) -> Result<(), T> {
+        try!(self.tx.send_opt(value));
+        match sent {
+            Some(now) => self.queued.push_back(now),
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Whatever's queued goes to a subscriber that stalled under
+    /// `DropStalled`, or that stalled with a full buffer.
+    fn dropping(&self, policy: &StallPolicy) -> bool {
+        match *policy {
+            DropStalled => self.stalled,
+            BufferStalled(max) => self.stalled && self.queued.len() >= max,
+            DisconnectStalled => false
+        }
+    }
+
+    /// Catch up with what the subscriber took, returning whether it stalled
+    /// just now (or caught up again, if not).
+    fn check(&mut self, threshold: u64, now: u64) -> bool {
+        let taken = self.taken.load(SeqCst);
+        while self.seen < taken && self.queued.pop_front().is_some() {
+            self.seen += 1;
+        }
+
+        let stalled = self.queued.front().map_or(false, |sent| now >= *sent + threshold);
+        let changed = stalled && !self.stalled;
+        self.stalled = stalled;
+        changed
+    }
+}
+
+struct Group<T> {
+    members: Vec<Feed<T>>,
+    next: uint
+}
+
+impl<T: Send> Group<T> {
+    /// Offer the value to the members in turn until one takes it. Members
+    /// that hung up are dropped along the way.
+    fn deliver(&mut self, mut value: T, policy: &StallPolicy, sent: Option<u64>) {
+        let mut skipped = 0;
+        while skipped < self.members.len() {
+            let i = self.next % self.members.len();
+            if self.members.get(i).dropping(policy) {
+                self.next = i + 1;
+                skipped += 1;
+                continue;
+            }
+
+            match self.members.get_mut(i).send(value, sent) {
+                Ok(()) => {
+                    self.next = i + 1;
+                    return;
+                },
+                Err(returned) => {
+                    value = returned;
+                    self.members.remove(i);
+                }
+            }
+        }
+    }
+}
+
+pub struct Subscribers<T> {
+    /// Plain subscribers along with their namespace.
+    plain: Vec<(String, Feed<T>)>,
+    /// Groups keyed by their namespace and name.
+    groups: HashMap<(String, String), Group<T>>
+}
+
+impl<T: Clone + Send> Subscribers<T> {
+    pub fn new() -> Subscribers<T> {
+        Subscribers {
+            plain: Vec::new(),
+            groups: HashMap::new()
+        }
+    }
+
+    pub fn subscribe(&mut self, namespace: &str, tx: Sender<T>, taken: Taken) {
+        self.plain.push((namespace.to_string(), Feed::new(tx, taken)));
+    }
+
+    /// Join (or create) the group called `group` within the namespace.
+    pub fn join(&mut self, namespace: &str, group: &str, tx: Sender<T>, taken: Taken) {
+        let key = (namespace.to_string(), group.to_string());
+
+        if !self.groups.contains_key(&key) {
+            self.groups.insert(key.clone(), Group { members: Vec::new(), next: 0 });
+        }
+
+        self.groups.find_mut(&key).unwrap().members.push(Feed::new(tx, taken));
+    }
+
+    /// Deliver a value published within `namespace` to every plain
+    /// subscriber and to one member of each group, but the stalled ones
+    /// the policy has us drop it for. Send it at some time to have it
+    /// count toward stalls, nothing's kept track of otherwise (i.e.,
+    /// without a `Config::stall_threshold`).
+    pub fn deliver(&mut self, namespace: &str, value: T, policy: &StallPolicy,
+                   sent: Option<u64>) {
+        let mut i = 0;
+        while i < self.plain.len() {
+            let alive = {
+                let (ref ns, ref mut feed) = *self.plain.get_mut(i);
+                ns.as_slice() != namespace || feed.dropping(policy)
+                || feed.send(value.clone(), sent).is_ok()
+            };
+
+            if alive {
+                i += 1;
+            } else {
+                self.plain.remove(i);
+            }
+        }
+
+        for (&(ref ns, _), group) in self.groups.mut_iter() {
+            if ns.as_slice() == namespace {
+                group.deliver(value.clone(), policy, sent);
+            }
+        }
+
+        self.remove_empty();
+    }
+
+    /// Look at how far behind every subscriber is. Returns the namespace
+    /// (and the group, for members of one) of those that stalled just now
+    /// along with how much is queued for each, having dealt with them per
+    /// the policy.
+    pub fn stalled(&mut self, threshold: u64, policy: &StallPolicy,
+                   now: u64) -> Vec<(String, Option<String>, uint)> {
+        let mut stalled = Vec::new();
+        for entry in self.plain.mut_iter() {
+            let (ref ns, ref mut feed) = *entry;
+            if feed.check(threshold, now) {
+                stalled.push((ns.clone(), None, feed.queued.len()));
+            }
+        }
+        for (&(ref ns, ref name), group) in self.groups.mut_iter() {
+            for feed in group.members.mut_iter() {
+                if feed.check(threshold, now) {
+                    stalled.push((ns.clone(), Some(name.clone()), feed.queued.len()));
+                }
+            }
+        }
+
+        if *policy == DisconnectStalled {
+            self.plain.retain(|&(_, ref feed)| !feed.stalled);
+            for (_, group) in self.groups.mut_iter() {
+                group.members.retain(|feed| !feed.stalled);
+            }
+            self.remove_empty();
+        }
+        stalled
+    }
+
+    fn remove_empty(&mut self) {
+        let empty: Vec<(String, String)> = self.groups.iter()
+            .filter(|&(_, group)| group.members.len() == 0)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in empty.iter() {
+            self.groups.remove(key);
+        }
+    }
+
+    /// Every namespace with a subscriber (or a group) in it, duplicates
+    /// included.
+    pub fn namespaces(&self) -> Vec<String> {
+        self.plain.iter().map(|&(ref ns, _)| ns.clone())
+            .chain(self.groups.keys().map(|&(ref ns, _)| ns.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomics::{AtomicUint, SeqCst};
+
+    fn taken() -> Taken {
+        Arc::new(AtomicUint::new(0))
+    }
+
+    #[test]
+    fn plain_subscribers_get_everything() {
+        let (mut subscribers, policy) = (Subscribers::new(), BufferStalled(10));
+        let (tx, rx) = channel();
+        subscribers.subscribe("default", tx, taken());
+
+        subscribers.deliver("default", 1u, &policy, Some(0));
+        subscribers.deliver("billing", 2u, &policy, Some(0));
+        subscribers.deliver("default", 3u, &policy, Some(0));
+
+        assert_eq!(rx.recv(), 1u);
+        assert_eq!(rx.recv(), 3u);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn groups_round_robin() {
+        let (mut subscribers, policy) = (Subscribers::new(), BufferStalled(10));
+        let (a_tx, a) = channel();
+        let (b_tx, b) = channel();
+        subscribers.join("default", "workers", a_tx, taken());
+        subscribers.join("default", "workers", b_tx, taken());
+
+        for i in range(0u, 4) {
+            subscribers.deliver("default", i, &policy, Some(0));
+        }
+
+        assert_eq!((a.recv(), a.recv()), (0u, 2u));
+        assert_eq!((b.recv(), b.recv()), (1u, 3u));
+    }
+
+    #[test]
+    fn skip_departed_group_members() {
+        let (mut subscribers, policy) = (Subscribers::new(), BufferStalled(10));
+        let (a_tx, a) = channel();
+        let (b_tx, b) = channel();
+        subscribers.join("default", "workers", a_tx, taken());
+        subscribers.join("default", "workers", b_tx, taken());
+        drop(a);
+
+        subscribers.deliver("default", 1u, &policy, Some(0));
+        subscribers.deliver("default", 2u, &policy, Some(0));
+        assert_eq!((b.recv(), b.recv()), (1u, 2u));
+    }
+
+    #[test]
+    fn deal_with_stalled_subscribers() {
+        let (mut subscribers, policy) = (Subscribers::new(), DropStalled);
+        let (tx, rx) = channel();
+        let progress = taken();
+        subscribers.subscribe("default", tx, progress.clone());
+
+        subscribers.deliver("default", 1u, &policy, Some(0));
+        assert_eq!(subscribers.stalled(100, &policy, 99), vec![]);
+        let stalled = vec![("default".to_string(), None, 1)];
+        assert_eq!(subscribers.stalled(100, &policy, 100), stalled);
+        assert_eq!(subscribers.stalled(100, &policy, 200), vec![]);
+        subscribers.deliver("default", 2u, &policy, Some(200));
+
+        // Once it caught up, it gets what comes next.
+        assert_eq!(rx.recv(), 1u);
+        progress.fetch_add(1, SeqCst);
+        assert_eq!(subscribers.stalled(100, &policy, 300), vec![]);
+        subscribers.deliver("default", 3u, &policy, Some(300));
+        assert_eq!(rx.recv(), 3u);
+
+        let policy = DisconnectStalled;
+        subscribers.stalled(100, &policy, 400);
+        subscribers.deliver("default", 4u, &policy, Some(400));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn buffer_for_stalled_subscribers_up_to_the_bound() {
+        let (mut subscribers, policy) = (Subscribers::new(), BufferStalled(2));
+        let (tx, rx) = channel();
+        subscribers.join("default", "workers", tx, taken());
+
+        subscribers.deliver("default", 1u, &policy, Some(0));
+        let stalled = vec![("default".to_string(), Some("workers".to_string()), 1)];
+        assert_eq!(subscribers.stalled(100, &policy, 100), stalled);
+        subscribers.deliver("default", 2u, &policy, Some(100));
+        subscribers.deliver("default", 3u, &policy, Some(100));
+        assert_eq!((rx.recv(), rx.recv()), (1u, 2u));
+        assert!(rx.try_recv().is_err());
+
+        // Without a threshold, nothing's kept track of at all.
+        let (tx, _rx) = channel();
+        subscribers.subscribe("default", tx, taken());
+        subscribers.deliver("default", 4u, &policy, None);
+        let (_, ref feed) = *subscribers.plain.get(0);
+        assert!(feed.queued.is_empty());
+    }
+}
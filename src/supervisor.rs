@@ -0,0 +1,160 @@
+//! Keeps the internal tasks alive. A task that fails (i.e., the acceptor
+//! tripping over a socket error) is logged and restarted after a backoff,
+//! instead of silently taking gossip down with it. A task that keeps on
+//! failing is escalated so the node shuts down as a whole rather than
+//! limping along.
+
+use std::any::{Any, AnyRefExt};
+use std::collections::RingBuf;
+use std::collections::Deque;
+use std::io::timer;
+use std::rand::task_rng;
+use std::task;
+
+use backoff::Backoff;
+use clock;
+
+/// How failing tasks are restarted.
+#[deriving(Show, Clone, PartialEq)]
+pub struct RestartPolicy {
+    /// How long to wait before restarting. The budget is ignored, that's
+    /// what `max_restarts` is for.
+    pub backoff: Backoff,
+    /// Give up (and escalate) once a task failed this many times within
+    /// the window.
+    pub max_restarts: uint,
+    pub window: u64
+}
+
+impl RestartPolicy {
+    pub fn new() -> RestartPolicy {
+        RestartPolicy {
+            backoff: Backoff::new(100, 10_000),
+            max_restarts: 5,
+            window: 60_000
+        }
+    }
+}
+
+/// What the supervisor reports back about a task (the name).
+#[deriving(Show, Clone, PartialEq)]
+pub enum Supervision {
+    /// The task failed and was restarted, for the nth time.
+    Restarted(String, uint),
+    /// The task failed too often and was given up on.
+    Escalated(String)
+}
+
+/// A task the supervisor can start over. Every (re)start runs a fresh
+/// clone, so whatever the worker needs to start from scratch has to live
+/// within it.
+pub trait Worker: Clone + Send {
+    fn run(self);
+}
+
+/// Run the worker within it's own task, restarting it per the policy
+/// whenever it fails. The supervisor exits once the worker returns.
+pub fn supervise<W: Worker>(name: &str, worker: W, policy: RestartPolicy,
+                            tx: Sender<Supervision>) {
+    let name = name.to_string();
+
+    spawn(proc() {
+        let mut failures = RingBuf::new();
+        let mut backoff = Backoff::new(policy.backoff.initial, policy.backoff.max).start(0);
+        let mut rng = task_rng();
+        let mut restarts = 0u;
+
+        loop {
+            let attempt = worker.clone();
+            let cause = match task::try(proc() attempt.run()) {
+                Ok(()) => return,
+                Err(cause) => cause
+            };
+
+            println!("Error: the {} task failed: {}", name, describe(&cause));
+
+            let now = clock::now();
+            failures.push_back(now);
+            while failures.front().map_or(false, |&at| at + policy.window <= now) {
+                failures.pop_front();
+            }
+
+            if failures.len() > policy.max_restarts {
+                let _ = tx.send_opt(Escalated(name.clone()));
+                return;
+            }
+
+            timer::sleep(backoff.next_delay(&mut rng, now).unwrap_or(policy.backoff.max));
+            restarts += 1;
+
+            if tx.send_opt(Restarted(name.clone(), restarts)).is_err() {
+                // Nobody is left to supervise for.
+                return;
+            }
+        }
+    });
+}
+
+/// Whatever the task failed with, if it's printable.
+fn describe(cause: &Box<Any + Send>) -> String {
+    match cause.as_ref::<&'static str>() {
+        Some(msg) => msg.to_string(),
+        None => match cause.as_ref::<String>() {
+            Some(msg) => msg.clone(),
+            None => "unknown cause".to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use backoff::Backoff;
+    use std::sync::atomics::{AtomicUint, SeqCst};
+
+    /// Fails until it ran `succeed_after` times.
+    #[deriving(Clone)]
+    struct Flaky {
+        runs: Arc<AtomicUint>,
+        succeed_after: uint
+    }
+
+    impl Worker for Flaky {
+        fn run(self) {
+            if self.runs.fetch_add(1, SeqCst) + 1 < self.succeed_after {
+                fail!("flaky");
+            }
+        }
+    }
+
+    fn policy() -> RestartPolicy {
+        let mut policy = RestartPolicy::new();
+        policy.backoff = Backoff::new(1, 1);
+        policy.max_restarts = 2;
+        policy
+    }
+
+    #[test]
+    fn restart_failed_workers() {
+        let (tx, rx) = channel();
+        let runs = Arc::new(AtomicUint::new(0));
+        supervise("flaky", Flaky { runs: runs.clone(), succeed_after: 3 }, policy(), tx);
+
+        assert_eq!(rx.recv(), Restarted("flaky".to_string(), 1));
+        assert_eq!(rx.recv(), Restarted("flaky".to_string(), 2));
+        assert!(rx.recv_opt().is_err());
+        assert_eq!(runs.load(SeqCst), 3);
+    }
+
+    #[test]
+    fn escalate_repeated_failures() {
+        let (tx, rx) = channel();
+        let runs = Arc::new(AtomicUint::new(0));
+        supervise("flaky", Flaky { runs: runs, succeed_after: 10 }, policy(), tx);
+
+        assert_eq!(rx.recv(), Restarted("flaky".to_string(), 1));
+        assert_eq!(rx.recv(), Restarted("flaky".to_string(), 2));
+        assert_eq!(rx.recv(), Escalated("flaky".to_string()));
+    }
+}
@@ -0,0 +1,401 @@
+//! SWIM failure detection. Every `Config::probe_interval` we probe the
+//! next member in line (all of them in turn, in an order shuffled anew
+//! every time around) with a `Ping`, expecting an `Ack` back. If none comes
+//! within `Config::probe_timeout`, we ask `Config::indirect_probes` other
+//! members at random to ping it for us (a `PingReq`), passing on the acks
+//! they get: the path from us to the member might be what's broken, not
+//! the member. No ack by the end of the interval makes the member suspect
+//! (or whenever the failure detector says so once the indirect probes are
+//! out, see `detector`), and a member staying suspect for
+//! `Config::suspicion_timeout` is confirmed dead.
+//!
+//! Suspicions and deaths are disseminated as `LivenessAt` changes, and every
+//! member hearing of a suspicion starts it's own timer so whoever gets
+//! there first confirms it. An ack for our probe clears the suspect, as
+//! long as it comes from the member itself or one of the members we asked
+//! to ping it. Anybody else's acks prove nothing.
+//!
+//! While our own host is struggling (see `pressure`), the acks we wait on
+//! might just be stuck with us. Every timeout is scaled by the local health
+//! multiplier then, as Lifeguard has it, so we're slower to suspect others
+//! of what's really our own fault.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::rand::{task_rng, Rng, XorShiftRng};
+use uuid::Uuid;
+
+use detector::{FailureDetector, Timeouts};
+
+/// What the detector wants done.
+#[deriving(Show, Clone, PartialEq)]
+pub enum Action {
+    /// Ping the member, to be acked under the sequence number.
+    Probe(Uuid, u64),
+    /// Ask those members to ping the member (the id) for us.
+    ProbeThrough(Vec<Uuid>, Uuid, u64),
+    /// The member never acked, directly or not.
+    Suspected(Uuid),
+    /// The member stayed suspect for too long.
+    Confirmed(Uuid)
+}
+
+/// What an ack amounts to, see `Swim::ack`.
+#[deriving(Show, Clone, PartialEq)]
+pub enum Acked {
+    /// It answers our probe, the member is alive.
+    Answered,
+    /// It answers a ping we sent on behalf of the member (the id), pass it
+    /// on under the sequence number.
+    PassOn(Uuid, u64),
+    /// Nobody asked for it, or it came from who can't vouch for it.
+    Unsolicited
+}
+
+/// The probe we're waiting on an ack for.
+struct InFlight {
+    target: Uuid,
+    seq: u64,
+    sent_at: u64,
+    /// Whether we asked others to probe it already.
+    indirect: bool,
+    /// Who we asked, the only ones passing acks on.
+    helpers: Vec<Uuid>
+}
+
+/// A ping we sent on behalf of another member.
+struct Relayed {
+    origin: Uuid,
+    /// Who we pinged, the only one to ack it.
+    target: Uuid,
+    /// The sequence number the origin gave it.
+    seq: u64,
+    expires: u64
+}
+
+pub struct Swim {
+    /// `None` doesn't detect anything.
+    interval: Option<u64>,
+    timeout: u64,
+    indirect: uint,
+    suspicion_timeout: u64,
+    seq: u64,
+    next: u64,
+    probe: Option<InFlight>,
+    /// Who's left to probe this time around, the next one last.
+    order: Vec<Uuid>,
+    relayed: HashMap<u64, Relayed>,
+    /// The members suspected, since when.
+    suspects: HashMap<Uuid, u64>,
+    detector: Box<FailureDetector + Send>,
+    /// What the timeouts are scaled by, see `set_multiplier`.
+    multiplier: u64,
+    rng: XorShiftRng
+}
+
+impl Swim {
+    pub fn new(interval: Option<u64>, timeout: u64, indirect: uint, suspicion_timeout: u64,
+               now: u64) -> Swim {
+        Swim {
+            interval: interval,
+            timeout: timeout,
+            indirect: indirect,
+            suspicion_timeout: suspicion_timeout,
+            seq: 0,
+            next: now + interval.unwrap_or(0),
+            probe: None,
+            order: Vec::new(),
+            relayed: HashMap::new(),
+            suspects: HashMap::new(),
+            detector: box Timeouts::new(interval.unwrap_or(0)) as Box<FailureDetector + Send>,
+            multiplier: 1,
+            rng: task_rng().gen()
+        }
+    }
+
+    /// Move the probes along. `members` are those worth probing, anybody
+    /// not known dead.
+    pub fn tick(&mut self, members: &[Uuid], now: u64) -> Vec<Action> {
+        let mut actions = Vec::new();
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return actions
+        };
+
+        let expired: Vec<u64> = self.relayed.iter()
+                                    .filter(|&(_, relayed)| now >= relayed.expires)
+                                    .map(|(seq, _)| *seq)
+                                    .collect();
+        for seq in expired.iter() {
+            self.relayed.remove(seq);
+        }
+
+        let multiplier = self.multiplier;
+        let timeout = self.suspicion_timeout * multiplier;
+        let confirmed: Vec<Uuid> = self.suspects.iter()
+                                       .filter(|&(_, since)| now >= *since + timeout)
+                                       .map(|(id, _)| *id)
+                                       .collect();
+        for id in confirmed.iter() {
+            self.suspects.remove(id);
+            self.detector.forget(id);
+            actions.push(Confirmed(*id));
+        }
+        // Those are dead as of now, no point probing them.
+        let members: Vec<Uuid> = members.iter()
+                                        .filter(|id| !confirmed.contains(*id))
+                                        .map(|id| *id)
+                                        .collect();
+        let members = members.as_slice();
+
+        let (target, seq, sent_at, indirect) = match self.probe {
+            Some(ref probe) => (probe.target, probe.seq, probe.sent_at, probe.indirect),
+            None => (Uuid::nil(), 0, now, true)
+        };
+        if self.probe.is_some() && !members.contains(&target) {
+            self.probe = None;
+        } else if self.probe.is_some() && indirect
+                  && self.detector.is_failing(&target, (now - sent_at) / multiplier) {
+            actions.push(Suspected(target));
+            self.probe = None;
+        } else if !indirect && now >= sent_at + self.timeout * multiplier {
+            let mut helpers: Vec<Uuid> = members.iter()
+                                                .filter(|id| **id != target)
+                                                .map(|id| *id)
+                                                .collect();
+            self.rng.shuffle(helpers.as_mut_slice());
+            helpers.truncate(self.indirect);
+            {
+                let probe = self.probe.as_mut().unwrap();
+                probe.indirect = true;
+                probe.helpers = helpers.clone();
+            }
+            if !helpers.is_empty() {
+                actions.push(ProbeThrough(helpers, target, seq));
+            }
+        }
+
+        if self.probe.is_none() && now >= self.next {
+            match self.next_target(members) {
+                Some(target) => {
+                    self.seq += 1;
+                    self.probe = Some(InFlight {
+                        target: target,
+                        seq: self.seq,
+                        sent_at: now,
+                        indirect: false,
+                        helpers: Vec::new()
+                    });
+                    self.next = now + interval;
+                    actions.push(Probe(target, self.seq));
+                },
+                None => {}
+            }
+        }
+        actions
+    }
+
+    fn next_target(&mut self, members: &[Uuid]) -> Option<Uuid> {
+        loop {
+            match self.order.pop() {
+                Some(id) if members.contains(&id) => return Some(id),
+                Some(_) => {},
+                None if members.is_empty() => return None,
+                None => {
+                    self.order = members.to_vec();
+                    self.rng.shuffle(self.order.as_mut_slice());
+                }
+            }
+        }
+    }
+
+    /// Ping a member (the target) on behalf of another (`origin`),
+    /// returning the sequence number to ping it with.
+    pub fn probe_for(&mut self, origin: Uuid, target: Uuid, seq: u64, now: u64) -> u64 {
+        self.seq += 1;
+        self.relayed.insert(self.seq, Relayed {
+            origin: origin,
+            target: target,
+            seq: seq,
+            expires: now + self.interval.unwrap_or(0)
+        });
+        self.seq
+    }
+
+    /// Take in the member's ack, as it reached us from `via`: the member
+    /// itself, or whoever passed it on.
+    pub fn ack(&mut self, id: &Uuid, via: &Uuid, seq: u64, now: u64) -> Acked {
+        let sent_at = match self.probe {
+            Some(ref probe) if probe.target == *id && probe.seq == seq
+                               && (via == id || probe.helpers.contains(via)) => {
+                Some(probe.sent_at)
+            },
+            _ => None
+        };
+        match sent_at {
+            Some(sent_at) => {
+                self.suspects.remove(id);
+                self.detector.acked(id, now - sent_at);
+                self.probe = None;
+                return Answered;
+            },
+            None => {}
+        }
+
+        let relayed = match self.relayed.find(&seq) {
+            Some(relayed) => relayed.target == *id && via == id,
+            None => false
+        };
+        if !relayed {
+            return Unsolicited;
+        }
+        let relayed = self.relayed.pop(&seq).unwrap();
+        PassOn(relayed.origin, relayed.seq)
+    }
+
+    /// Number our probes (and pings for others) from `seq` on, so their acks
+    /// can't be mixed up with those of another detector's.
+    pub fn set_seq(&mut self, seq: u64) {
+        self.seq = seq;
+    }
+
+    pub fn set_detector(&mut self, detector: Box<FailureDetector + Send>) {
+        self.detector = detector;
+    }
+
+    /// Scale the timeouts by the local health multiplier (see
+    /// `Engine::local_health_multiplier`), 1 being a healthy host's.
+    pub fn set_multiplier(&mut self, multiplier: u64) {
+        self.multiplier = cmp::max(multiplier, 1);
+    }
+
+    /// Start timing the suspicion, unless we are already.
+    pub fn suspect(&mut self, id: Uuid, now: u64) {
+        self.suspects.find_or_insert(id, now);
+    }
+
+    /// The member is alive, or dead already, either way not suspect.
+    pub fn clear(&mut self, id: &Uuid) {
+        self.suspects.remove(id);
+    }
+
+    /// The members suspected, along with when they're confirmed dead unless
+    /// they refute it.
+    pub fn suspects(&self) -> Vec<(Uuid, u64)> {
+        let timeout = self.suspicion_timeout * self.multiplier;
+        self.suspects.iter().map(|(id, since)| (*id, *since + timeout)).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn probe_then_probe_through_others_then_suspect() {
+        let mut swim = Swim::new(Some(1_000), 300, 2, 5_000, 0);
+        let members = Vec::from_fn(4, |_| Uuid::new_v4());
+        assert_eq!(swim.tick(members.as_slice(), 999), vec![]);
+
+        let (target, seq) = match swim.tick(members.as_slice(), 1_000).as_slice() {
+            [Probe(target, seq)] => (target, seq),
+            actions => fail!("expected a probe, got {}", actions)
+        };
+        match swim.tick(members.as_slice(), 1_300).as_slice() {
+            [ProbeThrough(ref helpers, to, s)] => {
+                assert_eq!(helpers.len(), 2);
+                assert!(!helpers.contains(&target));
+                assert_eq!((to, s), (target, seq));
+            },
+            actions => fail!("expected an indirect probe, got {}", actions)
+        }
+
+        let actions = swim.tick(members.as_slice(), 2_000);
+        assert_eq!(actions.get(0), &Suspected(target));
+        match actions.get(1) {
+            &Probe(next, _) => assert!(next != target),
+            action => fail!("expected the next probe, got {}", action)
+        }
+
+        // The next one acks in time, then every member gets it's turn.
+        let mut probed = vec![target];
+        for round in range(3u64, 6) {
+            let (next, seq) = match swim.probe {
+                Some(ref probe) => (probe.target, probe.seq),
+                None => fail!("expected a probe")
+            };
+            assert_eq!(swim.ack(&next, &next, seq, round * 1_000 - 500), Answered);
+            probed.push(next);
+            swim.tick(members.as_slice(), round * 1_000);
+        }
+        probed.sort_by(|a, b| a.to_string().cmp(&b.to_string()));
+        let mut all = members.clone();
+        all.sort_by(|a, b| a.to_string().cmp(&b.to_string()));
+        assert_eq!(probed, all);
+    }
+
+    #[test]
+    fn confirm_suspects_that_never_ack() {
+        let mut swim = Swim::new(Some(1_000), 300, 2, 5_000, 0);
+        let (slow, dead) = (Uuid::new_v4(), Uuid::new_v4());
+        swim.suspect(slow, 0);
+        swim.suspect(dead, 0);
+        swim.suspect(dead, 4_000);
+        swim.clear(&slow);
+        assert_eq!(swim.tick(&[], 4_999), vec![]);
+        assert_eq!(swim.tick(&[], 5_000), vec![Confirmed(dead)]);
+
+        // Acks for pings we sent for others go back to them, as long as
+        // they're from who we pinged.
+        let origin = Uuid::new_v4();
+        let seq = swim.probe_for(origin, slow, 7, 5_000);
+        assert_eq!(swim.ack(&slow, &origin, seq, 5_000), Unsolicited);
+        assert_eq!(swim.ack(&slow, &slow, seq, 5_000), PassOn(origin, 7));
+        assert_eq!(swim.ack(&slow, &slow, seq, 5_000), Unsolicited);
+    }
+
+    #[test]
+    fn take_acks_only_from_the_target_or_the_helpers() {
+        let mut swim = Swim::new(Some(1_000), 300, 2, 5_000, 0);
+        let members = Vec::from_fn(4, |_| Uuid::new_v4());
+        let (target, seq) = match swim.tick(members.as_slice(), 1_000).as_slice() {
+            [Probe(target, seq)] => (target, seq),
+            actions => fail!("expected a probe, got {}", actions)
+        };
+        let helpers = match swim.tick(members.as_slice(), 1_300).as_slice() {
+            [ProbeThrough(ref helpers, _, _)] => helpers.clone(),
+            actions => fail!("expected an indirect probe, got {}", actions)
+        };
+        let stranger = Uuid::new_v4();
+        swim.suspect(target, 1_300);
+
+        assert_eq!(swim.ack(&target, &stranger, seq, 1_400), Unsolicited);
+        assert_eq!(swim.ack(&target, &target, seq + 1, 1_400), Unsolicited);
+        assert_eq!(swim.ack(&target, helpers.get(0), seq, 1_400), Answered);
+        assert!(swim.suspects.is_empty());
+    }
+
+    #[test]
+    fn slow_down_while_our_host_struggles() {
+        let mut swim = Swim::new(Some(1_000), 300, 2, 5_000, 0);
+        let members = Vec::from_fn(4, |_| Uuid::new_v4());
+        swim.set_multiplier(3);
+
+        let target = match swim.tick(members.as_slice(), 1_000).as_slice() {
+            [Probe(target, _)] => target,
+            actions => fail!("expected a probe, got {}", actions)
+        };
+        assert_eq!(swim.tick(members.as_slice(), 1_899), vec![]);
+        match swim.tick(members.as_slice(), 1_900).as_slice() {
+            [ProbeThrough(_, to, _)] => assert_eq!(to, target),
+            actions => fail!("expected an indirect probe, got {}", actions)
+        }
+        assert_eq!(swim.tick(members.as_slice(), 3_999), vec![]);
+        assert_eq!(swim.tick(members.as_slice(), 4_000).get(0), &Suspected(target));
+
+        swim.suspect(target, 4_000);
+        assert_eq!(swim.tick(&[], 18_999), vec![]);
+        assert_eq!(swim.tick(&[], 19_000), vec![Confirmed(target)]);
+    }
+}
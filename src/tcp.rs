@@ -0,0 +1,301 @@
+//! The transport used unless another one is set, see `Transport`. Frames
+//! go out prefixed with their length and checked, see `framing`. Every
+//! connection is read from within it's own task; the acceptor runs under the
+//! supervisor (as `acceptor`), so a socket error doesn't stop us from
+//! accepting connections. Only `shutdown` does.
+
+use std::collections::HashMap;
+use std::io::{TcpListener, TcpStream, Listener, Acceptor, Timer, TimedOut};
+use std::io::net::ip::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomics::{AtomicBool, SeqCst};
+
+use stream::SockAddr;
+use result::{GossipResult, GossipError, NodeUnreachable, UnknownError, io_err};
+use socket::SocketOptions;
+use socket;
+use framing::{write_frame, read_frame};
+use supervisor::{Worker, RestartPolicy, Supervision, supervise};
+use transport::{Transport, TransportEvent, Connection, Accepted, Frame, Closed};
+
+/// How often (in milliseconds) the acceptor looks up whether the
+/// transport shut down.
+static POLL: u64 = 100;
+
+/// What the acceptor and the readers tell the transport.
+enum Io {
+    Opened(TcpStream, SockAddr),
+    Read(Connection, Vec<u8>),
+    HungUp(Connection)
+}
+
+pub struct TcpTransport {
+    options: SocketOptions,
+    policy: RestartPolicy,
+    supervisor_tx: Sender<Supervision>,
+    /// The write half of every open connection.
+    streams: HashMap<Connection, TcpStream>,
+    next: u64,
+    /// Set once we shut down, the acceptor stops.
+    stopped: Arc<AtomicBool>,
+    tx: Sender<Io>,
+    rx: Receiver<Io>
+}
+
+impl TcpTransport {
+    pub fn new(options: SocketOptions, policy: RestartPolicy,
+               supervisor_tx: Sender<Supervision>) -> TcpTransport {
+        let (tx, rx) = channel();
+
+        TcpTransport {
+            options: options,
+            policy: policy,
+            supervisor_tx: supervisor_tx,
+            streams: HashMap::new(),
+            next: 0,
+            stopped: Arc::new(AtomicBool::new(false)),
+            tx: tx,
+            rx: rx
+        }
+    }
+
+    /// Keep track of a freshly opened (or accepted) stream and start
+    /// reading from it.
+    fn open(&mut self, mut stream: TcpStream) -> Connection {
+        match socket::configure(&mut stream, &self.options) {
+            Ok(()) => {},
+            Err(e) => println!("Error: {}", e)
+        }
+
+        let conn = Connection(self.next);
+        self.next += 1;
+
+        let reader = stream.clone();
+        let (timeout, tx) = (self.options.read_timeout, self.tx.clone());
+        spawn(proc() read(reader, conn, timeout, tx));
+
+        self.streams.insert(conn, stream);
+        conn
+    }
+
+    fn event(&mut self, io: Io) -> TransportEvent {
+        match io {
+            Opened(stream, addr) => Accepted(self.open(stream), addr),
+            Read(conn, frame) => Frame(conn, frame),
+            HungUp(conn) => {
+                self.streams.remove(&conn);
+                Closed(conn)
+            }
+        }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn listen(&mut self, addr: &SockAddr) -> GossipResult<SockAddr> {
+        let (bound_tx, bound_rx) = channel();
+        let acceptor = AcceptorWorker {
+            addr: addr.clone(),
+            stopped: self.stopped.clone(),
+            tx: self.tx.clone(),
+            bound_tx: bound_tx
+        };
+        supervise("acceptor", acceptor, self.policy.clone(), self.supervisor_tx.clone());
+
+        match bound_rx.recv_opt() {
+            Ok(bound) => bound,
+            Err(()) => Err(GossipError::new("the acceptor never started", UnknownError))
+        }
+    }
+
+    fn connect(&mut self, addr: &SockAddr) -> GossipResult<Connection> {
+        let stream = try!(socket::connect(addr, &self.options));
+        Ok(self.open(stream))
+    }
+
+    fn send(&mut self, conn: &Connection, frame: &[u8]) -> GossipResult<()> {
+        let written = match self.streams.find_mut(conn) {
+            Some(stream) => {
+                // The timeout is a deadline from now on, set it anew for every frame.
+                stream.set_write_timeout(self.options.write_timeout);
+                write_frame(stream, frame).map_err(io_err)
+            },
+            None => return Err(GossipError::new("the connection is closed", NodeUnreachable))
+        };
+
+        // Whatever part of the frame went out garbles the ones after it.
+        if written.is_err() {
+            self.close(conn);
+        }
+        written
+    }
+
+    fn recv(&mut self, timeout: u64) -> GossipResult<Option<TransportEvent>> {
+        match self.rx.try_recv() {
+            Ok(io) => return Ok(Some(self.event(io))),
+            Err(_) => {}
+        }
+
+        let mut timer = try!(Timer::new().map_err(io_err));
+        let expired = timer.oneshot(timeout);
+        let io = {
+            let rx = &self.rx;
+            select! {
+                io = rx.recv_opt() => io.ok(),
+                () = expired.recv() => None
+            }
+        };
+
+        Ok(io.map(|io| self.event(io)))
+    }
+
+    fn close(&mut self, conn: &Connection) {
+        // The reader notices and reports the connection closed.
+        match self.streams.pop(conn) {
+            Some(mut stream) => {
+                let _ = stream.close_read();
+                let _ = stream.close_write();
+            },
+            None => {}
+        }
+    }
+
+    fn shutdown(&mut self) {
+        self.stopped.store(true, SeqCst);
+        let conns: Vec<Connection> = self.streams.keys().map(|conn| *conn).collect();
+        for conn in conns.iter() {
+            self.close(conn);
+        }
+    }
+}
+
+/// Everything needed to start the acceptor (over), see `supervise`.
+#[deriving(Clone)]
+struct AcceptorWorker {
+    addr: SockAddr,
+    stopped: Arc<AtomicBool>,
+    tx: Sender<Io>,
+    /// Where the first start reports the address it bound to.
+    bound_tx: Sender<GossipResult<SockAddr>>
+}
+
+impl Worker for AcceptorWorker {
+    fn run(self) {
+        let mut listener = match TcpListener::bind(self.addr.ip.as_slice(), self.addr.port) {
+            Ok(listener) => listener,
+            Err(e) => {
+                let _ = self.bound_tx.send_opt(Err(io_err(e)));
+                return;
+            }
+        };
+        let bound = listener.socket_name().map(addr).unwrap_or(self.addr.clone());
+        let mut acceptor = match listener.listen() {
+            Ok(acceptor) => acceptor,
+            Err(e) => {
+                let _ = self.bound_tx.send_opt(Err(io_err(e)));
+                return;
+            }
+        };
+
+        // Only the first start is waited on, restarts go unnoticed.
+        let _ = self.bound_tx.send_opt(Ok(bound));
+
+        while !self.stopped.load(SeqCst) {
+            acceptor.set_timeout(Some(POLL));
+            match acceptor.accept() {
+                Ok(mut stream) => {
+                    let peer = match stream.peer_name() {
+                        Ok(peer) => addr(peer),
+                        Err(e) => {
+                            println!("Error: {}", e);
+                            continue;
+                        }
+                    };
+
+                    // The transport is gone, and so are we.
+                    if self.tx.send_opt(Opened(stream, peer)).is_err() {
+                        return;
+                    }
+                },
+                Err(ref e) if e.kind == TimedOut => {},
+                Err(e) => println!("Error: {}", e)
+            }
+        }
+    }
+}
+
+fn addr(addr: SocketAddr) -> SockAddr {
+    SockAddr::new(addr.ip.to_string().as_slice(), addr.port)
+}
+
+/// Read frames off the stream until it's closed (or sends garbage, or
+/// nothing for longer than the timeout).
+fn read(mut stream: TcpStream, conn: Connection, timeout: Option<u64>, tx: Sender<Io>) {
+    loop {
+        stream.set_read_timeout(timeout);
+        match read_frame(&mut stream) {
+            Ok(frame) => {
+                if tx.send_opt(Read(conn, frame)).is_err() {
+                    return;
+                }
+            },
+            Err(_) => break
+        }
+    }
+
+    let _ = tx.send_opt(HungUp(conn));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::timer::sleep;
+
+    use stream::SockAddr;
+    use supervisor::RestartPolicy;
+    use socket::SocketOptions;
+    use transport::{Transport, Accepted, Frame, Closed};
+
+    #[test]
+    fn talk_over_loopback() {
+        let transport = || {
+            let (tx, _) = channel();
+            TcpTransport::new(SocketOptions::new(), RestartPolicy::new(), tx)
+        };
+        let (mut server, mut client) = (transport(), transport());
+
+        let bound = server.listen(&SockAddr::new("127.0.0.1", 0)).unwrap();
+        assert!(bound.port != 0);
+        let conn = client.connect(&bound).unwrap();
+        client.send(&conn, &[4u8, 2]).unwrap();
+
+        let accepted = match server.recv(1_000).unwrap() {
+            Some(Accepted(accepted, _)) => accepted,
+            event => fail!("unexpected {}", event)
+        };
+        assert_eq!(server.recv(1_000).unwrap(), Some(Frame(accepted, vec![4u8, 2])));
+
+        client.close(&conn);
+        sleep(10);
+        assert_eq!(server.recv(1_000).unwrap(), Some(Closed(accepted)));
+    }
+
+    #[test]
+    fn time_out_an_idle_connection() {
+        let mut options = SocketOptions::new();
+        options.read_timeout = Some(50);
+        let (tx, _) = channel();
+        let mut server = TcpTransport::new(options, RestartPolicy::new(), tx);
+        let (tx, _) = channel();
+        let mut client = TcpTransport::new(SocketOptions::new(), RestartPolicy::new(), tx);
+
+        let bound = server.listen(&SockAddr::new("127.0.0.1", 0)).unwrap();
+        let _conn = client.connect(&bound).unwrap();
+        let accepted = match server.recv(1_000).unwrap() {
+            Some(Accepted(accepted, _)) => accepted,
+            event => fail!("unexpected {}", event)
+        };
+
+        // The client never says a word.
+        assert_eq!(server.recv(1_000).unwrap(), Some(Closed(accepted)));
+    }
+}
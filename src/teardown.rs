@@ -0,0 +1,76 @@
+//! Cooperative cluster-wide shutdown, i.e., for tearing down a whole
+//! environment. The initiator broadcasts a command signed with the admin
+//! key, optionally limited to the members with a given `role` metadata.
+//! Every targeted member starts draining right away and shuts down in
+//! turn, staggered by `Config::shutdown_stagger` in the order of their ids,
+//! reporting it's progress back to the initiator along the way.
+//!
+//! The initiator itself never shuts down this way, that's up to whoever
+//! asked it to once every member reported back.
+
+use std::collections::HashMap;
+use std::io::MemWriter;
+use uuid::Uuid;
+
+/// The metadata key members advertise their role under.
+pub static ROLE_KEY: &'static str = "role";
+
+/// Commands older (or further in the future) than this many milliseconds
+/// are rejected, so old ones can't be replayed against a new cluster.
+pub static VALIDITY: u64 = 60_000;
+
+#[deriving(Show, Clone, PartialEq, Encodable, Decodable)]
+pub enum ShutdownPhase {
+    /// The member is draining, and shuts down once it's turn comes.
+    Stopping,
+    Stopped
+}
+
+/// What the admin key signs: the initiator, the role and when (the
+/// initiator's wall clock) the command was issued.
+pub fn signed(initiator: &Uuid, role: Option<&str>, issued_at: u64) -> Vec<u8> {
+    let mut wr = MemWriter::new();
+
+    // Writing to memory can't fail.
+    wr.write(initiator.as_bytes()).unwrap();
+    wr.write_be_u64(issued_at).unwrap();
+    match role {
+        Some(role) => wr.write_str(role).unwrap(),
+        None => {}
+    }
+
+    wr.unwrap()
+}
+
+/// Whether the metadata advertises the role. Every member has the role
+/// `None`.
+pub fn has_role(metadata: &HashMap<String, String>, role: Option<&str>) -> bool {
+    match role {
+        Some(role) => metadata.find_equiv(&ROLE_KEY).map(|r| r.as_slice()) == Some(role),
+        None => true
+    }
+}
+
+/// How many members shut down before we do.
+pub fn rank(local: &Uuid, mut targets: Vec<Uuid>) -> uint {
+    targets.sort_by(|a, b| a.as_bytes().cmp(&b.as_bytes()));
+    targets.iter().position(|id| id == local).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn stagger_by_id() {
+        let mut ids = vec![Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        ids.sort_by(|a, b| a.as_bytes().cmp(&b.as_bytes()));
+
+        let (a, b, c) = (*ids.get(0), *ids.get(1), *ids.get(2));
+        assert_eq!(rank(&a, vec![c, a, b]), 0);
+        assert_eq!(rank(&c, vec![c, a, b]), 2);
+
+        assert!(signed(&a, Some("web"), 1) != signed(&a, None, 1));
+    }
+}
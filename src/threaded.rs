@@ -0,0 +1,80 @@
+//! The blocking driver. The engine and the handler are moved into a
+//! dedicated task that waits for inputs and ticks the engine every
+//! `Config::tick_interval` milliseconds.
+
+use std::io::Timer;
+
+use engine::{Engine, Input, WallClock, Tick};
+use driver::Handler;
+use driver;
+use clock;
+
+/// A handle on an engine running within it's own task. The task exits
+/// once every handle (see `clone`) is dropped.
+#[deriving(Clone)]
+pub struct Threaded {
+    tx: Sender<Input>
+}
+
+impl Threaded {
+    pub fn spawn<H: Handler + Send>(engine: Engine, handler: H) -> Threaded {
+        let (tx, rx) = channel();
+        let interval = engine.config().tick_interval;
+
+        spawn(proc() {
+            let mut engine = engine;
+            let mut handler = handler;
+            let mut timer = Timer::new().unwrap();
+            let ticks = timer.periodic(interval);
+            engine.handle(WallClock(clock::wall()), clock::now());
+
+            loop {
+                let input = select! {
+                    input = rx.recv_opt() => match input {
+                        Ok(input) => input,
+                        Err(_) => break
+                    },
+                    () = ticks.recv() => {
+                        engine.handle(WallClock(clock::wall()), clock::now());
+                        Tick
+                    }
+                };
+
+                for output in driver::handle(&mut engine, input, clock::now()).move_iter() {
+                    handler.perform(output);
+                }
+            }
+        });
+
+        Threaded {
+            tx: tx
+        }
+    }
+
+    /// Queue an input for the engine. Returns `false` if the task is gone
+    /// (i.e., the handler failed).
+    pub fn send(&self, input: Input) -> bool {
+        self.tx.send_opt(input).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+    use engine::{Engine, Publish, Deliver};
+    use broadcast::Broadcast;
+    use config::Config;
+
+    #[test]
+    fn drive_from_a_task() {
+        let (tx, rx) = channel();
+        let driver = Threaded::spawn(Engine::new(Uuid::new_v4(), Config::new()), tx);
+
+        assert!(driver.send(Publish(Broadcast::new(vec![1u8]).unwrap())));
+        match rx.recv() {
+            Deliver(broadcast) => assert_eq!(broadcast.as_bytes(), &[1u8]),
+            _ => fail!("expected the broadcast to be delivered")
+        }
+    }
+}
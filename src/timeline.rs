@@ -0,0 +1,89 @@
+//! A bounded timeline of our health and how many members we knew in each
+//! state, one record every time either changed. Health events are gone as
+//! soon as they're handled, the timeline still shows a node that went red
+//! overnight (and how many members it lost sight of) come morning. See
+//! `Node::health_history`.
+
+use std::collections::{RingBuf, Deque};
+
+use protocol::Health;
+
+#[deriving(Show, Clone, PartialEq, Encodable, Decodable)]
+pub struct HealthRecord {
+    /// Our wall clock (in milliseconds since the unix epoch).
+    pub at: u64,
+    pub health: Health,
+    /// The other members, by what we make of their liveness.
+    pub alive: uint,
+    pub suspect: uint,
+    pub dead: uint
+}
+
+impl HealthRecord {
+    /// Whether the two tell the same story, whenever they're from.
+    fn same(&self, other: &HealthRecord) -> bool {
+        self.health == other.health && self.alive == other.alive
+        && self.suspect == other.suspect && self.dead == other.dead
+    }
+}
+
+pub struct Timeline {
+    capacity: uint,
+    records: RingBuf<HealthRecord>
+}
+
+impl Timeline {
+    pub fn new(capacity: uint) -> Timeline {
+        Timeline {
+            capacity: capacity,
+            records: RingBuf::new()
+        }
+    }
+
+    /// Record how things stand, unless nothing changed since the last
+    /// record. The oldest one goes once we're full.
+    pub fn observe(&mut self, record: HealthRecord) {
+        if self.capacity == 0 || self.records.back().map_or(false, |last| last.same(&record)) {
+            return;
+        }
+
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Every record we have, oldest first.
+    pub fn records(&self) -> Vec<HealthRecord> {
+        self.records.iter().map(|record| record.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use protocol::{Health, Green, Red};
+
+    fn record(at: u64, health: Health, alive: uint) -> HealthRecord {
+        HealthRecord {
+            at: at,
+            health: health,
+            alive: alive,
+            suspect: 0,
+            dead: 0
+        }
+    }
+
+    #[test]
+    fn record_the_changes_only() {
+        let mut timeline = Timeline::new(3);
+        timeline.observe(record(1, Green, 2));
+        timeline.observe(record(2, Green, 2));
+        timeline.observe(record(3, Green, 3));
+        timeline.observe(record(4, Red, 3));
+        timeline.observe(record(5, Green, 3));
+
+        let at: Vec<u64> = timeline.records().iter().map(|r| r.at).collect();
+        assert_eq!(at, vec![3, 4, 5]);
+    }
+}
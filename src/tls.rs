@@ -0,0 +1,484 @@
+//! TLS with mutual authentication on top of another transport (usually
+//! `TcpTransport`), so hosts without a certificate we trust can neither
+//! join nor read along. Both sides have to present a certificate: a peer
+//! that finishes the handshake without one is hung up on.
+//!
+//! Like with `Cipher`, we don't ship TLS ourselves. Plug in the library of
+//! your choice through `TlsConfig` (holding the certificates, the key and
+//! the authorities to trust) and `TlsSession` (a single connection's state,
+//! it's API follows the usual buffer-in buffer-out style). Whatever the
+//! session wants sent goes out as frames of the inner transport, and our
+//! own frames go through the session prefixed with their length and
+//! checked, see `framing`.
+//!
+//! A connection is only `Accepted` once it's handshake is done; frames sent
+//! on a dialed connection before that wait for it. Handshakes dragging on
+//! past `HANDSHAKE_TIMEOUT` are hung up on, and so are accepted connections
+//! beyond `MAX_HANDSHAKES` at once, so peers can't hold on to sessions by
+//! never finishing.
+
+use std::collections::{HashMap, RingBuf, Deque};
+use std::io::MemWriter;
+use std::mem;
+
+use clock;
+use stream::SockAddr;
+use result::{GossipResult, GossipError, NodeUnreachable, AccessDenied, Overloaded, io_err};
+use framing::{write_frame, split};
+use transport::{Transport, TransportEvent, Connection, Accepted, Frame, Closed};
+
+/// How long (in milliseconds) a handshake may take.
+static HANDSHAKE_TIMEOUT: u64 = 10_000;
+/// How many accepted connections may be handshaking at once.
+static MAX_HANDSHAKES: uint = 256;
+/// How many frames may wait for a dialed connection's handshake.
+static MAX_PENDING: uint = 1_024;
+
+/// Starts sessions, with our certificate and the authorities we trust.
+pub trait TlsConfig: Send {
+    /// For a connection we dialed to the address.
+    fn client(&self, server: &SockAddr) -> GossipResult<Box<TlsSession + Send>>;
+
+    /// For a connection we accepted. Has to ask for the client's
+    /// certificate.
+    fn server(&self) -> GossipResult<Box<TlsSession + Send>>;
+}
+
+pub trait TlsSession: Send {
+    /// Bytes the peer sent us. Fails if the handshake (i.e., the peer's
+    /// certificate) or a record doesn't check out.
+    fn read_tls(&mut self, bytes: &[u8]) -> GossipResult<()>;
+
+    /// Bytes to send the peer, if there are any.
+    fn write_tls(&mut self) -> Option<Vec<u8>>;
+
+    /// Whatever plaintext was decrypted since.
+    fn read(&mut self) -> Vec<u8>;
+
+    /// Encrypt the plaintext, it goes out with the next `write_tls`.
+    fn write(&mut self, plaintext: &[u8]) -> GossipResult<()>;
+
+    fn is_handshaking(&self) -> bool;
+
+    /// The chain the peer presented (end-entity first), once verified.
+    fn peer_certificates(&self) -> Vec<Vec<u8>>;
+}
+
+struct Session {
+    tls: Box<TlsSession + Send>,
+    /// Set on the connections we accepted.
+    addr: Option<SockAddr>,
+    /// When the handshake started.
+    started: u64,
+    established: bool,
+    /// Frames waiting for the handshake.
+    pending: Vec<Vec<u8>>,
+    /// Plaintext short of a whole frame.
+    buffer: Vec<u8>
+}
+
+pub struct TlsTransport<T> {
+    inner: T,
+    config: Box<TlsConfig + Send>,
+    sessions: HashMap<Connection, Session>,
+    /// Events waiting to be returned by `recv`.
+    events: RingBuf<TransportEvent>,
+    handshake_timeout: u64
+}
+
+impl<T: Transport> TlsTransport<T> {
+    pub fn new(inner: T, config: Box<TlsConfig + Send>) -> TlsTransport<T> {
+        TlsTransport {
+            inner: inner,
+            config: config,
+            sessions: HashMap::new(),
+            events: RingBuf::new(),
+            handshake_timeout: HANDSHAKE_TIMEOUT
+        }
+    }
+
+    fn handshaking(&self) -> uint {
+        self.sessions.values().filter(|s| !s.established && s.addr.is_some()).count()
+    }
+
+    /// Hang up on every handshake that took too long.
+    fn expire(&mut self, now: u64) {
+        let timeout = self.handshake_timeout;
+        let expired: Vec<Connection> = self.sessions.iter()
+                                           .filter(|&(_, s)| !s.established)
+                                           .filter(|&(_, s)| now >= s.started + timeout)
+                                           .map(|(conn, _)| *conn)
+                                           .collect();
+        for conn in expired.iter() {
+            self.hang_up(conn);
+        }
+    }
+
+    /// Send whatever the session has for the peer.
+    fn flush(&mut self, conn: &Connection) -> GossipResult<()> {
+        loop {
+            let bytes = match self.sessions.find_mut(conn).and_then(|s| s.tls.write_tls()) {
+                Some(bytes) => bytes,
+                None => return Ok(())
+            };
+            try!(self.inner.send(conn, bytes.as_slice()));
+        }
+    }
+
+    fn hang_up(&mut self, conn: &Connection) {
+        self.inner.close(conn);
+        match self.sessions.pop(conn) {
+            // Dialed connections were handed out already, accepted ones only
+            // once established.
+            Some(session) => if session.established || session.addr.is_none() {
+                self.events.push_back(Closed(*conn));
+            },
+            None => {}
+        }
+    }
+
+    fn event(&mut self, event: TransportEvent) -> GossipResult<()> {
+        match event {
+            Accepted(conn, addr) => {
+                if self.handshaking() >= MAX_HANDSHAKES {
+                    self.inner.close(&conn);
+                    return Err(GossipError::new("too many handshakes at once", Overloaded));
+                }
+
+                let tls = try!(self.config.server());
+                self.sessions.insert(conn, Session {
+                    tls: tls,
+                    addr: Some(addr),
+                    started: clock::now(),
+                    established: false,
+                    pending: Vec::new(),
+                    buffer: Vec::new()
+                });
+                Ok(())
+            },
+            Frame(conn, bytes) => {
+                if !self.sessions.contains_key(&conn) {
+                    return Ok(());
+                }
+
+                match self.read(&conn, bytes.as_slice()) {
+                    Ok(()) => Ok(()),
+                    Err(e) => {
+                        self.hang_up(&conn);
+                        Err(e)
+                    }
+                }
+            },
+            Closed(conn) => {
+                self.hang_up(&conn);
+                Ok(())
+            }
+        }
+    }
+
+    fn read(&mut self, conn: &Connection, bytes: &[u8]) -> GossipResult<()> {
+        try!(self.sessions.get_mut(conn).tls.read_tls(bytes));
+        try!(self.flush(conn));
+
+        let established = {
+            let session = self.sessions.get_mut(conn);
+            if session.established || session.tls.is_handshaking() {
+                None
+            } else if session.tls.peer_certificates().is_empty() {
+                return Err(GossipError::new("the peer presented no certificate", AccessDenied));
+            } else {
+                session.established = true;
+                Some((session.addr.clone(), mem::replace(&mut session.pending, Vec::new())))
+            }
+        };
+
+        match established {
+            Some((addr, pending)) => {
+                match addr {
+                    Some(addr) => self.events.push_back(Accepted(*conn, addr)),
+                    None => {}
+                }
+                for frame in pending.iter() {
+                    try!(self.send(conn, frame.as_slice()));
+                }
+            },
+            None => {}
+        }
+
+        let session = self.sessions.get_mut(conn);
+        let plaintext = session.tls.read();
+        session.buffer.push_all(plaintext.as_slice());
+        for frame in try!(split(&mut session.buffer)).move_iter() {
+            self.events.push_back(Frame(*conn, frame));
+        }
+        Ok(())
+    }
+}
+
+impl<T: Transport> Transport for TlsTransport<T> {
+    fn listen(&mut self, addr: &SockAddr) -> GossipResult<SockAddr> {
+        self.inner.listen(addr)
+    }
+
+    fn connect(&mut self, addr: &SockAddr) -> GossipResult<Connection> {
+        let tls = try!(self.config.client(addr));
+        let conn = try!(self.inner.connect(addr));
+        self.sessions.insert(conn, Session {
+            tls: tls,
+            addr: None,
+            started: clock::now(),
+            established: false,
+            pending: Vec::new(),
+            buffer: Vec::new()
+        });
+
+        match self.flush(&conn) {
+            Ok(()) => Ok(conn),
+            Err(e) => {
+                self.inner.close(&conn);
+                self.sessions.remove(&conn);
+                Err(e)
+            }
+        }
+    }
+
+    fn send(&mut self, conn: &Connection, frame: &[u8]) -> GossipResult<()> {
+        {
+            let session = match self.sessions.find_mut(conn) {
+                Some(session) => session,
+                None => return Err(GossipError::new("the connection is closed", NodeUnreachable))
+            };
+
+            if !session.established {
+                if session.pending.len() >= MAX_PENDING {
+                    return Err(GossipError::new("too many frames wait for the handshake",
+                                                Overloaded));
+                }
+                session.pending.push(frame.to_vec());
+                return Ok(());
+            }
+
+            let mut wr = MemWriter::new();
+            try!(write_frame(&mut wr, frame).map_err(io_err));
+            try!(session.tls.write(wr.get_ref()));
+        }
+
+        self.flush(conn)
+    }
+
+    fn recv(&mut self, timeout: u64) -> GossipResult<Option<TransportEvent>> {
+        let deadline = clock::now() + timeout;
+
+        loop {
+            match self.events.pop_front() {
+                Some(event) => return Ok(Some(event)),
+                None => {}
+            }
+
+            let now = clock::now();
+            self.expire(now);
+            if !self.events.is_empty() {
+                continue;
+            }
+            if now >= deadline {
+                return Ok(None);
+            }
+
+            match try!(self.inner.recv(deadline - now)) {
+                Some(event) => match self.event(event) {
+                    Ok(()) => {},
+                    Err(e) => println!("Error: {}", e)
+                },
+                None => return Ok(None)
+            }
+        }
+    }
+
+    fn close(&mut self, conn: &Connection) {
+        self.inner.close(conn);
+        self.sessions.remove(conn);
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown();
+        self.sessions.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::MAX_PENDING;
+    use std::collections::{RingBuf, Deque};
+    use std::mem;
+
+    use memory::{InMemoryTransport, Network};
+    use stream::SockAddr;
+    use result::{GossipResult, GossipError, AccessDenied};
+    use transport::{Transport, Accepted, Frame, Closed};
+
+    /// Certificates are names, trusted ones are listed. Not to be confused
+    /// with actual security.
+    struct Toy {
+        name: Option<Vec<u8>>,
+        trusted: Vec<Vec<u8>>
+    }
+
+    struct ToySession {
+        name: Option<Vec<u8>>,
+        trusted: Vec<Vec<u8>>,
+        client: bool,
+        peer: Option<Vec<u8>>,
+        outgoing: RingBuf<Vec<u8>>,
+        plaintext: Vec<u8>
+    }
+
+    impl Toy {
+        fn session(&self, client: bool) -> Box<TlsSession + Send> {
+            let mut session = ToySession {
+                name: self.name.clone(),
+                trusted: self.trusted.clone(),
+                client: client,
+                peer: None,
+                outgoing: RingBuf::new(),
+                plaintext: Vec::new()
+            };
+            if client {
+                session.hello();
+            }
+            box session as Box<TlsSession + Send>
+        }
+    }
+
+    impl TlsConfig for Toy {
+        fn client(&self, _: &SockAddr) -> GossipResult<Box<TlsSession + Send>> {
+            Ok(self.session(true))
+        }
+
+        fn server(&self) -> GossipResult<Box<TlsSession + Send>> {
+            Ok(self.session(false))
+        }
+    }
+
+    impl ToySession {
+        fn hello(&mut self) {
+            let mut hello = vec![0u8];
+            hello.push_all(self.name.clone().unwrap_or(Vec::new()).as_slice());
+            self.outgoing.push_back(hello);
+        }
+    }
+
+    impl TlsSession for ToySession {
+        fn read_tls(&mut self, bytes: &[u8]) -> GossipResult<()> {
+            if bytes[0] == 1 {
+                self.plaintext.extend(bytes.slice_from(1).iter().map(|b| *b ^ 0x5a));
+                return Ok(());
+            }
+
+            let name = bytes.slice_from(1).to_vec();
+            if !name.is_empty() && !self.trusted.contains(&name) {
+                return Err(GossipError::new("untrusted certificate", AccessDenied));
+            }
+            self.peer = Some(name);
+            if !self.client {
+                self.hello();
+            }
+            Ok(())
+        }
+
+        fn write_tls(&mut self) -> Option<Vec<u8>> {
+            self.outgoing.pop_front()
+        }
+
+        fn read(&mut self) -> Vec<u8> {
+            mem::replace(&mut self.plaintext, Vec::new())
+        }
+
+        fn write(&mut self, plaintext: &[u8]) -> GossipResult<()> {
+            let mut record = vec![1u8];
+            record.extend(plaintext.iter().map(|b| *b ^ 0x5a));
+            self.outgoing.push_back(record);
+            Ok(())
+        }
+
+        fn is_handshaking(&self) -> bool {
+            self.peer.is_none()
+        }
+
+        fn peer_certificates(&self) -> Vec<Vec<u8>> {
+            match self.peer {
+                Some(ref name) if !name.is_empty() => vec![name.clone()],
+                _ => Vec::new()
+            }
+        }
+    }
+
+    fn transport(network: &Network, name: Option<&str>) -> TlsTransport<InMemoryTransport> {
+        let toy = Toy {
+            name: name.map(|name| name.as_bytes().to_vec()),
+            trusted: vec![b"a".to_vec(), b"b".to_vec()]
+        };
+        TlsTransport::new(InMemoryTransport::new(network), box toy as Box<TlsConfig + Send>)
+    }
+
+    #[test]
+    fn talk_once_both_sides_are_trusted() {
+        let network = Network::new();
+        let (mut a, mut b) = (transport(&network, Some("a")), transport(&network, Some("b")));
+        let addr = a.listen(&SockAddr::new("memory", 0)).unwrap();
+
+        let dialed = b.connect(&addr).unwrap();
+        // Waits for the handshake.
+        b.send(&dialed, &[1u8, 2]).unwrap();
+
+        let accepted = match a.recv(100).unwrap() {
+            Some(Accepted(conn, _)) => conn,
+            event => fail!("unexpected {}", event)
+        };
+        assert_eq!(b.recv(100).unwrap(), None);
+        assert_eq!(a.recv(100).unwrap(), Some(Frame(accepted, vec![1u8, 2])));
+
+        a.send(&accepted, &[3u8]).unwrap();
+        assert_eq!(b.recv(100).unwrap(), Some(Frame(dialed, vec![3u8])));
+    }
+
+    #[test]
+    fn refuse_peers_without_a_certificate() {
+        let network = Network::new();
+        let (mut a, mut b) = (transport(&network, Some("a")), transport(&network, None));
+        let addr = a.listen(&SockAddr::new("memory", 0)).unwrap();
+
+        let dialed = b.connect(&addr).unwrap();
+        b.send(&dialed, &[1u8]).unwrap();
+        assert_eq!(a.recv(100).unwrap(), None);
+        assert!(a.sessions.is_empty());
+    }
+
+    #[test]
+    fn give_up_on_stalled_handshakes() {
+        let network = Network::new();
+        let mut a = transport(&network, Some("a"));
+        a.handshake_timeout = 50;
+        let addr = a.listen(&SockAddr::new("memory", 0)).unwrap();
+
+        // A peer that never says hello.
+        let mut silent = InMemoryTransport::new(&network);
+        let dialed = silent.connect(&addr).unwrap();
+        assert_eq!(a.recv(100).unwrap(), None);
+        assert_eq!(a.recv(10).unwrap(), None);
+        assert!(a.sessions.is_empty());
+        assert_eq!(silent.recv(100).unwrap(), Some(Closed(dialed)));
+
+        // Nor answers ours, what we send has to wait and only so much can.
+        let mut b = transport(&network, Some("b"));
+        b.handshake_timeout = 50;
+        let addr = silent.listen(&SockAddr::new("memory", 0)).unwrap();
+        let dialed = b.connect(&addr).unwrap();
+        for _ in range(0, MAX_PENDING) {
+            b.send(&dialed, &[1u8]).unwrap();
+        }
+        assert!(b.send(&dialed, &[1u8]).is_err());
+        assert_eq!(b.recv(100).unwrap(), None);
+        assert_eq!(b.recv(10).unwrap(), Some(Closed(dialed)));
+    }
+}
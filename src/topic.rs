@@ -0,0 +1,192 @@
+//! Per-topic wire settings. Big binary blobs want a compact codec with
+//! heavy compression, while tiny status beacons aren't worth compressing
+//! at all. Each topic (a broadcast's tag) can override the defaults, and
+//! the settings actually used with a peer are negotiated against the
+//! capabilities it advertised.
+
+use std::collections::HashMap;
+
+/// How a broadcast's payload is encoded on the wire.
+#[deriving(Show, Clone, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    /// Bytes are sent exactly as the application handed them to us.
+    /// Every node supports this.
+    Raw,
+    MsgPack,
+    Bincode
+}
+
+#[deriving(Show, Clone, PartialEq, Eq, Hash)]
+pub enum Compression {
+    /// Every node supports this.
+    Uncompressed,
+    Lz4,
+    /// Not built in yet (see `compress`), so never advertised: topics
+    /// asking for it go out uncompressed.
+    Zstd
+}
+
+impl Encoding {
+    fn flag(&self) -> u32 {
+        match *self {
+            Raw => 0,
+            MsgPack => 1 << 0,
+            Bincode => 1 << 1
+        }
+    }
+}
+
+impl Compression {
+    fn flag(&self) -> u32 {
+        match *self {
+            Uncompressed => 0,
+            Lz4 => 1 << 8,
+            Zstd => 1 << 9
+        }
+    }
+}
+
+/// Broadcasts relayed together packed into the same frames, see `codec`.
+static PACKING: u32 = 1 << 16;
+
+/// The set of encodings and compressions a node understands (and whether
+/// it takes packed frames), advertised to every peer.
+#[deriving(Show, Clone, PartialEq, Eq, Encodable, Decodable)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// What every node is guaranteed to support.
+    pub fn baseline() -> Capabilities {
+        Capabilities(0)
+    }
+
+    /// Everything this build supports.
+    pub fn local() -> Capabilities {
+        Capabilities::baseline()
+            .with_encoding(MsgPack)
+            .with_encoding(Bincode)
+            .with_compression(Lz4)
+            .with_packing()
+    }
+
+    pub fn with_encoding(self, encoding: Encoding) -> Capabilities {
+        let Capabilities(flags) = self;
+        Capabilities(flags | encoding.flag())
+    }
+
+    pub fn with_compression(self, compression: Compression) -> Capabilities {
+        let Capabilities(flags) = self;
+        Capabilities(flags | compression.flag())
+    }
+
+    pub fn supports_encoding(&self, encoding: Encoding) -> bool {
+        let Capabilities(flags) = *self;
+        flags & encoding.flag() == encoding.flag()
+    }
+
+    pub fn supports_compression(&self, compression: Compression) -> bool {
+        let Capabilities(flags) = *self;
+        flags & compression.flag() == compression.flag()
+    }
+
+    pub fn with_packing(self) -> Capabilities {
+        let Capabilities(flags) = self;
+        Capabilities(flags | PACKING)
+    }
+
+    pub fn supports_packing(&self) -> bool {
+        let Capabilities(flags) = *self;
+        flags & PACKING == PACKING
+    }
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub struct TopicSettings {
+    pub encoding: Encoding,
+    pub compression: Compression
+}
+
+impl TopicSettings {
+    pub fn new(encoding: Encoding, compression: Compression) -> TopicSettings {
+        TopicSettings {
+            encoding: encoding,
+            compression: compression
+        }
+    }
+
+    /// Downgrade the settings to something the peer understands. Anything
+    /// unsupported falls back to the baseline rather than failing.
+    pub fn negotiate(&self, peer: &Capabilities) -> TopicSettings {
+        TopicSettings {
+            encoding: if peer.supports_encoding(self.encoding) {
+                self.encoding
+            } else {
+                Raw
+            },
+            compression: if peer.supports_compression(self.compression) {
+                self.compression
+            } else {
+                Uncompressed
+            }
+        }
+    }
+}
+
+/// The wire settings for every topic.
+#[deriving(Show, Clone)]
+pub struct Topics {
+    default: TopicSettings,
+    overrides: HashMap<String, TopicSettings>
+}
+
+impl Topics {
+    pub fn new(default: TopicSettings, overrides: HashMap<String, TopicSettings>) -> Topics {
+        Topics {
+            default: default,
+            overrides: overrides
+        }
+    }
+
+    /// The configured settings for a topic, before negotiation.
+    pub fn settings(&self, topic: &str) -> TopicSettings {
+        match self.overrides.find_equiv(&topic) {
+            Some(settings) => settings.clone(),
+            None => self.default.clone()
+        }
+    }
+
+    /// The settings to use when sending `topic` to a peer with the given
+    /// capabilities.
+    pub fn negotiate(&self, topic: &str, peer: &Capabilities) -> TopicSettings {
+        self.settings(topic).negotiate(peer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn per_topic_overrides() {
+        let mut overrides = HashMap::new();
+        overrides.insert("blobs".to_string(), TopicSettings::new(Bincode, Zstd));
+
+        let topics = Topics::new(TopicSettings::new(MsgPack, Uncompressed), overrides);
+        assert_eq!(topics.settings("blobs"), TopicSettings::new(Bincode, Zstd));
+        assert_eq!(topics.settings("beacon"), TopicSettings::new(MsgPack, Uncompressed));
+    }
+
+    #[test]
+    fn negotiate_down_to_peer() {
+        let settings = TopicSettings::new(Bincode, Lz4);
+
+        assert_eq!(settings.negotiate(&Capabilities::local()), settings);
+        assert_eq!(settings.negotiate(&Capabilities::baseline()),
+                   TopicSettings::new(Raw, Uncompressed));
+
+        let peer = Capabilities::baseline().with_encoding(Bincode).with_compression(Zstd);
+        assert_eq!(settings.negotiate(&peer), TopicSettings::new(Bincode, Uncompressed));
+        assert!(!Capabilities::local().supports_compression(Zstd));
+    }
+}
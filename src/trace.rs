@@ -0,0 +1,138 @@
+//! W3C trace context (i.e., the `traceparent` header) carried within
+//! broadcasts, so a workflow that crosses nodes through the gossip shows
+//! up as a single trace in tracing backends.
+//!
+//! ```notrust
+//! traceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01
+//!              version-trace id-parent id-flags
+//! ```
+
+use std::hash::sip;
+use std::num;
+use uuid::Uuid;
+
+#[deriving(Show, Clone, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: [u8, ..16],
+    /// The span the broadcast was sent (or relayed) within.
+    pub parent_id: [u8, ..8],
+    pub flags: u8
+}
+
+impl TraceContext {
+    /// Parse a `traceparent` header. Invalid headers give `None`, in which
+    /// case the spec says to start a new trace.
+    pub fn parse(header: &str) -> Option<TraceContext> {
+        let parts: Vec<&str> = header.trim().split('-').collect();
+        let mut version = [0u8];
+        let mut context = TraceContext {
+            trace_id: [0u8, ..16],
+            parent_id: [0u8, ..8],
+            flags: 0
+        };
+        let mut flags = [0u8];
+
+        // Later versions may append more fields, but version 00 has exactly
+        // four and ff is forbidden altogether.
+        if parts.len() < 4 || !hex(*parts.get(0), &mut version) || version[0] == 0xff ||
+           (version[0] == 0 && parts.len() != 4) {
+            return None;
+        }
+
+        if !hex(*parts.get(1), &mut context.trace_id) ||
+           !hex(*parts.get(2), &mut context.parent_id) ||
+           !hex(*parts.get(3), &mut flags) {
+            return None;
+        }
+
+        // All zeroes are invalid ids.
+        if context.trace_id.iter().all(|b| *b == 0) || context.parent_id.iter().all(|b| *b == 0) {
+            return None;
+        }
+
+        context.flags = flags[0];
+        Some(context)
+    }
+
+    /// Whether the origin decided to record the trace.
+    pub fn sampled(&self) -> bool {
+        self.flags & 1 == 1
+    }
+
+    /// The context for a span of ours within the same trace, i.e., when
+    /// relaying the broadcast. The span id is derived from the broadcast
+    /// and the node so it's the same every time.
+    pub fn child(&self, broadcast: &Uuid, node: &Uuid) -> TraceContext {
+        let hash = sip::hash(&(broadcast, node));
+        let mut parent_id = [0u8, ..8];
+        for i in range(0u, 8) {
+            parent_id[i] = (hash >> (56 - i * 8)) as u8;
+        }
+
+        TraceContext {
+            trace_id: self.trace_id,
+            parent_id: parent_id,
+            flags: self.flags
+        }
+    }
+
+    /// Format the context as a version 00 `traceparent` header.
+    pub fn to_header(&self) -> String {
+        format!("00-{}-{}-{:02x}", to_hex(&self.trace_id), to_hex(&self.parent_id), self.flags)
+    }
+}
+
+/// Decode exactly `out.len()` bytes of lowercase hex.
+fn hex(s: &str, out: &mut [u8]) -> bool {
+    if s.len() != out.len() * 2 || !s.chars().all(|c| c.is_digit() || (c >= 'a' && c <= 'f')) {
+        return false;
+    }
+
+    for (i, byte) in out.mut_iter().enumerate() {
+        match num::from_str_radix::<u8>(s.slice(i * 2, i * 2 + 2), 16) {
+            Some(b) => *byte = b,
+            None => return false
+        }
+    }
+
+    true
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", *b)).collect::<Vec<String>>().concat()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    static HEADER: &'static str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn parse_traceparent() {
+        let context = TraceContext::parse(HEADER).unwrap();
+        assert!(context.sampled());
+        assert_eq!(context.parent_id, [0x00u8, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7]);
+        assert_eq!(context.to_header().as_slice(), HEADER);
+
+        assert!(TraceContext::parse("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01")
+                .is_none());
+        assert!(TraceContext::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01")
+                .is_none());
+        assert!(TraceContext::parse("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+                .is_none());
+        assert!(TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-01").is_none());
+    }
+
+    #[test]
+    fn child_spans_keep_the_trace() {
+        let context = TraceContext::parse(HEADER).unwrap();
+        let (broadcast, node) = (Uuid::new_v4(), Uuid::new_v4());
+        let child = context.child(&broadcast, &node);
+
+        assert_eq!(child.trace_id, context.trace_id);
+        assert!(child.parent_id != context.parent_id);
+        assert_eq!(child, context.child(&broadcast, &node));
+    }
+}
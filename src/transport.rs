@@ -0,0 +1,88 @@
+//! Whatever moves our bytes between members, behind one interface so TCP,
+//! UDP and the transports the tests (and the simulation) use are
+//! interchangeable. Hand one to `Node::set_transport` before `listen`.
+//!
+//! A transport deals in frames, not messages: it's up to the node to make
+//! sense of them. Datagram transports don't have connections as such, a
+//! `Connection` is then just whoever we've exchanged frames with.
+
+use stream::SockAddr;
+use result::GossipResult;
+
+/// The transports `Node::listen` picks from unless one is set, see
+/// `Config::transport`.
+#[deriving(Show, Clone, PartialEq)]
+pub enum TransportKind {
+    Tcp,
+    /// For small payloads on networks where connections are expensive.
+    /// Frames (broadcasts) have to fit within a single datagram.
+    Udp,
+    /// For nodes behind HTTP-only load balancers. Plain TCP peers can still
+    /// connect, see `Config::websocket_peers`.
+    WebSocket
+}
+
+/// A handle on a connection, only meaningful to the transport that made it.
+#[deriving(Show, Clone, PartialEq, Eq, Hash)]
+pub struct Connection(pub u64);
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum TransportEvent {
+    /// A peer connected to us from the address.
+    Accepted(Connection, SockAddr),
+    /// A whole frame read off the connection.
+    Frame(Connection, Vec<u8>),
+    /// The connection is gone, whichever side closed it.
+    Closed(Connection)
+}
+
+/// Driven from a dedicated task, so it's fine for `recv` to block (up to
+/// it's timeout).
+pub trait Transport: Send {
+    /// Start accepting connections on the address. Returns the address we
+    /// actually bound to (i.e., for port 0).
+    fn listen(&mut self, addr: &SockAddr) -> GossipResult<SockAddr>;
+
+    fn connect(&mut self, addr: &SockAddr) -> GossipResult<Connection>;
+
+    /// Put a whole frame on the connection.
+    fn send(&mut self, conn: &Connection, frame: &[u8]) -> GossipResult<()>;
+
+    /// Wait up to `timeout` milliseconds for something to happen on any
+    /// connection, `None` if nothing did.
+    fn recv(&mut self, timeout: u64) -> GossipResult<Option<TransportEvent>>;
+
+    /// Closing a connection that's already gone does nothing.
+    fn close(&mut self, conn: &Connection);
+
+    /// Stop accepting connections and close every one that's open, the
+    /// transport is done for.
+    fn shutdown(&mut self);
+}
+
+/// So wrappers (i.e., `PaddedTransport`) take on whichever transport is set.
+impl Transport for Box<Transport + Send> {
+    fn listen(&mut self, addr: &SockAddr) -> GossipResult<SockAddr> {
+        (**self).listen(addr)
+    }
+
+    fn connect(&mut self, addr: &SockAddr) -> GossipResult<Connection> {
+        (**self).connect(addr)
+    }
+
+    fn send(&mut self, conn: &Connection, frame: &[u8]) -> GossipResult<()> {
+        (**self).send(conn, frame)
+    }
+
+    fn recv(&mut self, timeout: u64) -> GossipResult<Option<TransportEvent>> {
+        (**self).recv(timeout)
+    }
+
+    fn close(&mut self, conn: &Connection) {
+        (**self).close(conn)
+    }
+
+    fn shutdown(&mut self) {
+        (**self).shutdown()
+    }
+}
@@ -0,0 +1,177 @@
+//! Typed handles on topics, so publishers and subscribers agree on what a
+//! topic carries at compile time rather than by convention:
+//!
+//! ```notrust
+//! let config = node.topic::<ConfigUpdate>("config");
+//! config.publish(&mut node, &update).unwrap();
+//!
+//! for (update, res) in config.subscribe(&mut node) {
+//!     // `update` is a `ConfigUpdate` already.
+//! }
+//! ```
+//!
+//! Values go out as JSON, tagged with the topic's name (see
+//! `Broadcast::tagged`). Subscriptions only hand out the broadcasts tagged
+//! with their topic, and skip those that don't decode to the type (i.e.,
+//! sent by a build that disagrees on it).
+
+use std::io::IoError;
+use std::str;
+use serialize::{json, Encodable, Decodable};
+use uuid::Uuid;
+
+use broadcast::Broadcast;
+use protocol::{Node, Incoming};
+use result::GossipResult;
+use stream::Response;
+
+pub struct Topic<T> {
+    namespace: String,
+    name: String
+}
+
+impl<T> Topic<T> {
+    pub fn new(namespace: &str, name: &str) -> Topic<T> {
+        Topic {
+            namespace: namespace.to_string(),
+            name: name.to_string()
+        }
+    }
+
+    pub fn name<'a>(&'a self) -> &'a str {
+        self.name.as_slice()
+    }
+}
+
+impl<'a, T: Encodable<json::Encoder<'a>, IoError> + Decodable<json::Decoder, json::DecoderError>>
+    Topic<T> {
+    pub fn publish(&self, node: &mut Node, value: &T) -> GossipResult<()> {
+        node.broadcast(self.broadcast(value))
+    }
+
+    /// Publish to the member `id` alone, see `Node::broadcast_to`.
+    pub fn publish_to(&self, node: &mut Node, id: Uuid, value: &T) -> GossipResult<()> {
+        node.broadcast_to(id, self.broadcast(value))
+    }
+
+    /// The broadcast carrying the value, i.e., to publish it in a batch.
+    pub fn broadcast(&self, value: &T) -> Broadcast {
+        let mut broadcast = Broadcast::tagged(self.name.as_slice(),
+                                              json::Encoder::buffer_encode(value).as_slice());
+        broadcast.set_namespace(self.namespace.as_slice());
+        broadcast
+    }
+
+    /// The value the broadcast carries, `None` if it's not of this topic.
+    pub fn decode(&self, broadcast: &Broadcast) -> Option<T> {
+        decode(self.name.as_slice(), broadcast)
+    }
+
+    pub fn subscribe(&self, node: &mut Node) -> Subscription<T> {
+        Subscription {
+            incoming: node.incoming_in(self.namespace.as_slice()),
+            name: self.name.clone()
+        }
+    }
+}
+
+fn decode<T: Decodable<json::Decoder, json::DecoderError>>(name: &str, broadcast: &Broadcast)
+                                                          -> Option<T> {
+    let data = match broadcast.tagged_data() {
+        Some((tag, data)) if tag == name => data,
+        _ => return None
+    };
+    str::from_utf8(data).and_then(|s| json::from_str(s).ok()).and_then(|json| {
+        Decodable::decode(&mut json::Decoder::new(json)).ok()
+    })
+}
+
+/// The values published to a topic, along with the response to each.
+pub struct Subscription<T> {
+    incoming: Incoming,
+    name: String
+}
+
+impl<T: Decodable<json::Decoder, json::DecoderError>> Subscription<T> {
+    /// Like `next`, but `None` right away unless a value is waiting.
+    pub fn try_next(&mut self) -> Option<(T, Response)> {
+        self.try_next_from().map(|(value, _, res)| (value, res))
+    }
+
+    /// Like `try_next`, along with the origin of the broadcast. Unlike
+    /// whatever the value claims, that's who actually published it.
+    pub fn try_next_from(&mut self) -> Option<(T, Uuid, Response)> {
+        loop {
+            let (broadcast, res) = match self.incoming.try_next() {
+                Some(callback) => callback,
+                None => return None
+            };
+            match decode(self.name.as_slice(), &broadcast) {
+                Some(value) => return Some((value, broadcast.origin(), res)),
+                None => {}
+            }
+        }
+    }
+}
+
+impl<T: Decodable<json::Decoder, json::DecoderError>> Iterator<(T, Response)>
+    for Subscription<T> {
+    fn next(&mut self) -> Option<(T, Response)> {
+        loop {
+            let (broadcast, res) = match self.incoming.next() {
+                Some(callback) => callback,
+                None => return None
+            };
+            match decode(self.name.as_slice(), &broadcast) {
+                Some(value) => return Some((value, res)),
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use broadcast::Broadcast;
+    use protocol::Node;
+
+    #[deriving(Show, PartialEq, Encodable, Decodable)]
+    struct ConfigUpdate {
+        key: String,
+        version: u64
+    }
+
+    fn update(version: u64) -> ConfigUpdate {
+        ConfigUpdate { key: "timeout".to_string(), version: version }
+    }
+
+    #[test]
+    fn only_decode_the_topic() {
+        let config: Topic<ConfigUpdate> = Topic::new("default", "config");
+        let other: Topic<ConfigUpdate> = Topic::new("default", "other");
+        let broadcast = config.broadcast(&update(3));
+
+        assert_eq!(broadcast.tag(), "config");
+        assert_eq!(config.decode(&broadcast), Some(update(3)));
+        assert_eq!(other.decode(&broadcast), None);
+        assert_eq!(config.decode(&Broadcast::new(vec![1u8, 2, 3]).unwrap()), None);
+
+        let numbers: Topic<u64> = Topic::new("default", "config");
+        assert_eq!(numbers.decode(&broadcast), None);
+    }
+
+    #[test]
+    fn publish_and_subscribe() {
+        let mut node = Node::new();
+        let config = node.topic::<ConfigUpdate>("config");
+        let mut updates = config.subscribe(&mut node);
+
+        node.broadcast(Broadcast::new(vec![1u8]).unwrap()).unwrap();
+        config.publish(&mut node, &update(1)).unwrap();
+
+        let (received, res) = updates.next().unwrap();
+        assert_eq!(received, update(1));
+        assert!(res.ok().is_ok());
+    }
+}
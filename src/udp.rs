@@ -0,0 +1,889 @@
+//! A transport for gossip over udp, see
+//! `Config::transport`. Every frame is a single datagram: a kind byte, a
+//! sequence number (a big-endian u32) and the frame itself. Receivers ack
+//! every frame and drop the ones they've already seen; frames that aren't
+//! acked within `Config::udp_retransmit_timeout` are sent again (waiting
+//! twice as long every time) so lossy networks still converge. A peer that
+//! doesn't ack after `Config::udp_max_retransmits` is considered gone.
+//! Sequence numbers start out at a random one for every connection, so
+//! whoever can't see our frames can't ack them either.
+//!
+//! Frames that don't fit within the path MTU to the peer go as fragments
+//! instead, each one acked on it's own, see `fragment`. The path MTU starts
+//! out at `Config::udp_mtu` and is probed for more, see `mtu`. Probes are
+//! datagrams of their own, padded to the size probed (the sequence number
+//! field carries it) and acked the same way, the ack echoing the probe's
+//! nonce:
+//!
+//! ```notrust
+//! bitdata Probe {
+//!     Probe {
+//!         kind: u8, // 3, 4 for the ack
+//!         size: u32,
+//!         nonce: u32, // random for every connection
+//!         padding: &[u8] // up to the size, none in the ack
+//!     }
+//! }
+//! ```
+//!
+//! Nobody gets a connection (nor anything but a header back) without a
+//! handshake first, so spoofing the source address gets nowhere. Whoever
+//! connects says hello, and we answer with a cookie made from their
+//! address, keeping no state. Only a hello bringing the cookie back (the
+//! sequence number field carries it) gets them `Accepted` and welcomed,
+//! frames sent before that wait for the welcome. A peer saying hello again
+//! after it sent us frames restarted, it's connection is `Closed` and it
+//! gets a new one:
+//!
+//! ```notrust
+//! HELLO 0 ->
+//!           <- COOKIE c
+//! HELLO c ->
+//!           <- WELCOME c
+//! ```
+
+use std::collections::{HashMap, HashSet, RingBuf, Deque};
+use std::hash::sip;
+use std::rand::{task_rng, Rng};
+use std::io::{BufReader, MemWriter, TimedOut};
+use std::io::net::ip::{SocketAddr, IpAddr};
+use std::io::net::udp::UdpSocket;
+use std::io::net::addrinfo::get_host_addresses;
+use std::cmp;
+use std::mem;
+use std::sync::Arc;
+
+use clock;
+use fragment;
+use fragment::Reassembly;
+use framing;
+use mtu::PathMtu;
+use stream::SockAddr;
+use result::{GossipResult, GossipError, NodeUnreachable, io_err};
+use transport::{Transport, TransportEvent, Connection, Accepted, Frame, Closed};
+
+/// The largest datagram there is.
+static MAX_DATAGRAM: uint = 65_507;
+
+static HEADER: uint = 5;
+/// A probe's header and nonce.
+static PROBE_HEADER: uint = 9;
+static DATA: u8 = 0;
+static ACK: u8 = 1;
+static FRAGMENT: u8 = 2;
+static PROBE: u8 = 3;
+static PROBE_ACK: u8 = 4;
+static HELLO: u8 = 5;
+static COOKIE: u8 = 6;
+static WELCOME: u8 = 7;
+
+/// How many frames per peer wait for the handshake, at most.
+static PENDING: uint = 64;
+
+/// Datagrams larger than the base size that went unacked this many times
+/// mean the path stopped taking them.
+static BLACKHOLE_RETRANSMITS: uint = 2;
+
+/// How many sequence numbers per peer are remembered to drop duplicates.
+static SEEN: uint = 256;
+
+struct Peer {
+    addr: SocketAddr,
+    next_seq: u32,
+    /// The id of the next frame we fragment.
+    next_frame: u32,
+    seen: RingBuf<u32>,
+    path: PathMtu,
+    /// What our probes carry, and their acks have to echo.
+    nonce: u32,
+    /// Set once the handshake is done, see the top.
+    handshaken: bool,
+    /// What the peer made of our address, zero until it told us.
+    cookie: u32,
+    /// The frames sent before the handshake was done.
+    pending: Vec<Vec<u8>>,
+    /// When to say hello again, and how many times we did.
+    hello_due: u64,
+    hellos: uint
+}
+
+/// A frame waiting to be acked.
+struct Unacked {
+    datagram: Vec<u8>,
+    /// For fragments, the id and the whole of their frame, to fragment it
+    /// again should the path get smaller.
+    frame: Option<(u32, Arc<Vec<u8>>)>,
+    due: u64,
+    retransmits: uint
+}
+
+pub struct UdpTransport {
+    socket: Option<UdpSocket>,
+    retransmit_timeout: u64,
+    max_retransmits: uint,
+    /// The largest datagram (headers included) every path takes, and the
+    /// largest we probe for.
+    mtu: uint,
+    max_mtu: uint,
+    probe_interval: u64,
+    reassembly: Reassembly<Connection>,
+    conns: HashMap<SocketAddr, Connection>,
+    peers: HashMap<Connection, Peer>,
+    unacked: HashMap<(Connection, u32), Unacked>,
+    /// Events waiting to be returned by `recv`.
+    events: RingBuf<TransportEvent>,
+    next: u64,
+    /// What the cookies are made with.
+    secret: (u64, u64)
+}
+
+impl UdpTransport {
+    pub fn new(retransmit_timeout: u64, max_retransmits: uint, mtu: uint, max_mtu: uint,
+               probe_interval: u64, reassembly_timeout: u64) -> UdpTransport {
+        let mtu = cmp::min(cmp::max(mtu, HEADER + fragment::HEADER + 1), MAX_DATAGRAM);
+        UdpTransport {
+            socket: None,
+            retransmit_timeout: retransmit_timeout,
+            max_retransmits: max_retransmits,
+            mtu: mtu,
+            max_mtu: cmp::min(cmp::max(max_mtu, mtu), MAX_DATAGRAM),
+            probe_interval: probe_interval,
+            reassembly: Reassembly::new(reassembly_timeout),
+            conns: HashMap::new(),
+            peers: HashMap::new(),
+            unacked: HashMap::new(),
+            events: RingBuf::new(),
+            next: 0,
+            secret: task_rng().gen()
+        }
+    }
+
+    /// Connecting before listening sends from an ephemeral port.
+    fn socket<'a>(&'a mut self) -> GossipResult<&'a mut UdpSocket> {
+        if self.socket.is_none() {
+            let any = try!(resolve(&SockAddr::new("0.0.0.0", 0)));
+            self.socket = Some(try!(UdpSocket::bind(any).map_err(io_err)));
+        }
+
+        Ok(self.socket.get_mut_ref())
+    }
+
+    fn transmit(&mut self, to: SocketAddr, datagram: &[u8]) -> GossipResult<()> {
+        let socket = try!(self.socket());
+        socket.send_to(datagram, to).map_err(io_err)
+    }
+
+    /// A bare header, for acks and the handshake.
+    fn reply(&mut self, to: SocketAddr, kind: u8, seq: u32) {
+        match self.transmit(to, header(kind, seq).as_slice()) {
+            Ok(()) => {},
+            Err(e) => println!("Error: {}", e)
+        }
+    }
+
+    fn cookie(&self, addr: &SocketAddr) -> u32 {
+        let (k0, k1) = self.secret;
+        sip::hash_with_keys(k0, k1, addr) as u32
+    }
+
+    fn connection(&mut self, addr: SocketAddr) -> (Connection, bool) {
+        match self.conns.find_copy(&addr) {
+            Some(conn) => return (conn, false),
+            None => {}
+        }
+
+        let conn = Connection(self.next);
+        self.next += 1;
+        self.conns.insert(addr, conn);
+        let mut rng = task_rng();
+        self.peers.insert(conn, Peer {
+            addr: addr,
+            next_seq: rng.gen(),
+            next_frame: 0,
+            seen: RingBuf::new(),
+            path: PathMtu::new(self.mtu, self.max_mtu, self.retransmit_timeout,
+                               self.probe_interval, clock::now()),
+            nonce: rng.gen(),
+            handshaken: false,
+            cookie: 0,
+            pending: Vec::new(),
+            hello_due: clock::now() + self.retransmit_timeout,
+            hellos: 0
+        });
+        (conn, true)
+    }
+
+    /// The handshake with the peer is done, send whatever waited for it.
+    fn established(&mut self, conn: Connection) {
+        let pending = match self.peers.find_mut(&conn) {
+            Some(peer) => {
+                peer.handshaken = true;
+                mem::replace(&mut peer.pending, Vec::new())
+            },
+            None => return
+        };
+
+        for frame in pending.iter() {
+            match self.send(&conn, frame.as_slice()) {
+                Ok(()) => {},
+                Err(e) => println!("Error: {}", e)
+            }
+        }
+    }
+
+    /// Say hello again to the peers that didn't answer yet, giving up on
+    /// them like on frames that are never acked.
+    fn greet(&mut self, now: u64) {
+        let (timeout, max) = (self.retransmit_timeout, self.max_retransmits);
+        let mut hellos = Vec::new();
+        let mut silent = Vec::new();
+
+        for (conn, peer) in self.peers.mut_iter() {
+            if peer.handshaken || peer.hello_due > now {
+                continue;
+            }
+            if peer.hellos >= max {
+                silent.push(*conn);
+                continue;
+            }
+            peer.hellos += 1;
+            peer.hello_due = now + (timeout << peer.hellos);
+            hellos.push((peer.addr, peer.cookie));
+        }
+
+        for &(to, cookie) in hellos.iter() {
+            self.reply(to, HELLO, cookie);
+        }
+        for conn in silent.iter() {
+            self.forget(conn);
+            self.events.push_back(Closed(*conn));
+        }
+    }
+
+    fn forget(&mut self, conn: &Connection) {
+        match self.peers.pop(conn) {
+            Some(peer) => { self.conns.remove(&peer.addr); },
+            None => {}
+        }
+        self.reassembly.forget(conn);
+
+        let unacked: Vec<(Connection, u32)> = self.unacked.keys()
+                                                  .filter(|&&(c, _)| c == *conn)
+                                                  .map(|key| *key)
+                                                  .collect();
+        for key in unacked.iter() {
+            self.unacked.remove(key);
+        }
+    }
+
+    /// Make sense of a datagram from the address.
+    fn datagram(&mut self, datagram: &[u8], from: SocketAddr) {
+        if datagram.len() < HEADER {
+            return;
+        }
+
+        let kind = datagram[0];
+        let seq = match BufReader::new(datagram.slice(1, HEADER)).read_be_u32() {
+            Ok(seq) => seq,
+            Err(_) => return
+        };
+
+        if kind == ACK {
+            match self.conns.find_copy(&from) {
+                Some(conn) => { self.unacked.remove(&(conn, seq)); },
+                None => {}
+            }
+            return;
+        }
+
+        if kind == PROBE {
+            // Only whole probes count, and only from whoever we shook hands with.
+            let known = match self.conns.find_copy(&from) {
+                Some(conn) => self.peers.get(&conn).handshaken,
+                None => false
+            };
+            if known && datagram.len() == seq as uint && datagram.len() >= PROBE_HEADER {
+                let mut ack = header(PROBE_ACK, seq);
+                ack.push_all(datagram.slice(HEADER, PROBE_HEADER));
+                match self.transmit(from, ack.as_slice()) {
+                    Ok(()) => {},
+                    Err(e) => println!("Error: {}", e)
+                }
+            }
+            return;
+        }
+
+        if kind == HELLO {
+            let cookie = self.cookie(&from);
+            if seq != cookie {
+                // No state kept, and no more sent back than we got.
+                self.reply(from, COOKIE, cookie);
+                return;
+            }
+
+            let (mut conn, new) = self.connection(from);
+            // A peer we talked to saying hello again restarted, and remembers
+            // none of it. It's numbering starts anew, so does the connection.
+            let restarted = !new && {
+                let peer = self.peers.get(&conn);
+                peer.handshaken && !peer.seen.is_empty()
+            };
+            if restarted {
+                self.forget(&conn);
+                self.events.push_back(Closed(conn));
+                conn = self.connection(from).val0();
+            }
+            if new || restarted {
+                self.events.push_back(Accepted(conn, addr(from)));
+            }
+            self.established(conn);
+            self.reply(from, WELCOME, seq);
+            return;
+        }
+
+        // Only whoever we're saying hello to gets to answer.
+        let shaking = match self.conns.find_copy(&from) {
+            Some(conn) if !self.peers.get(&conn).handshaken => Some(conn),
+            _ => None
+        };
+        if kind == COOKIE {
+            match shaking {
+                Some(conn) => {
+                    self.peers.get_mut(&conn).cookie = seq;
+                    self.reply(from, HELLO, seq);
+                },
+                None => {}
+            }
+            return;
+        }
+        if kind == WELCOME {
+            match shaking {
+                Some(conn) if self.peers.get(&conn).cookie == seq => self.established(conn),
+                _ => {}
+            }
+            return;
+        }
+
+        if kind == PROBE_ACK {
+            let nonce = match BufReader::new(datagram.slice_from(HEADER)).read_be_u32() {
+                Ok(nonce) => nonce,
+                Err(_) => return
+            };
+            match self.conns.find_copy(&from) {
+                Some(conn) if self.peers.get(&conn).nonce == nonce => {
+                    self.peers.get_mut(&conn).path.acked(seq as uint, clock::now());
+                },
+                _ => {}
+            }
+            return;
+        }
+
+        if kind != DATA && kind != FRAGMENT {
+            return;
+        }
+
+        let conn = match self.conns.find_copy(&from) {
+            Some(conn) if self.peers.get(&conn).handshaken => conn,
+            _ => return
+        };
+        // Not acked, so it comes again once there's room.
+        if kind == FRAGMENT && !self.reassembly.has_room(conn, datagram.slice_from(HEADER)) {
+            return;
+        }
+
+        // Ack duplicates as well, the first ack might be what got lost.
+        self.reply(from, ACK, seq);
+
+        let peer = self.peers.find_mut(&conn).unwrap();
+        if peer.seen.iter().any(|s| *s == seq) {
+            return;
+        }
+        if peer.seen.len() == SEEN {
+            peer.seen.pop_front();
+        }
+        peer.seen.push_back(seq);
+
+        if kind == DATA {
+            self.events.push_back(Frame(conn, datagram.slice_from(HEADER).to_vec()));
+            return;
+        }
+
+        match self.reassembly.add(conn, datagram.slice_from(HEADER), clock::now()) {
+            Some(frame) => self.events.push_back(Frame(conn, frame)),
+            None => {}
+        }
+    }
+
+    /// Send a single datagram to the peer, to be acked.
+    fn send_datagram(&mut self, conn: &Connection, kind: u8, body: &[u8],
+                     frame: Option<(u32, Arc<Vec<u8>>)>) -> GossipResult<()> {
+        let (to, seq) = match self.peers.find_mut(conn) {
+            Some(peer) => {
+                let seq = peer.next_seq;
+                peer.next_seq += 1;
+                (peer.addr, seq)
+            },
+            None => return Err(GossipError::new("the connection is closed", NodeUnreachable))
+        };
+
+        let mut datagram = header(kind, seq);
+        datagram.push_all(body);
+        try!(self.transmit(to, datagram.as_slice()));
+
+        self.unacked.insert((*conn, seq), Unacked {
+            datagram: datagram,
+            frame: frame,
+            due: clock::now() + self.retransmit_timeout,
+            retransmits: 0
+        });
+        Ok(())
+    }
+
+    /// Send whatever wasn't acked in time again, giving up on peers that
+    /// never ack.
+    fn retransmit(&mut self, now: u64) {
+        let due: Vec<(Connection, u32)> = self.unacked.iter()
+                                              .filter(|&(_, unacked)| unacked.due <= now)
+                                              .map(|(key, _)| *key)
+                                              .collect();
+
+        for key in due.iter() {
+            let (conn, _) = *key;
+            if !self.unacked.contains_key(key) {
+                // Given up on along with an earlier frame.
+                continue;
+            }
+
+            let (datagram, retransmits) = {
+                let unacked = self.unacked.get(key);
+                (unacked.datagram.clone(), unacked.retransmits)
+            };
+
+            if retransmits >= self.max_retransmits {
+                self.forget(&conn);
+                self.events.push_back(Closed(conn));
+                continue;
+            }
+
+            if retransmits == BLACKHOLE_RETRANSMITS && datagram.len() > self.mtu {
+                let fell_back = self.peers.get_mut(&conn).path.blackhole(now);
+                if fell_back {
+                    self.refragment(conn);
+                    continue;
+                }
+            }
+
+            let to = self.peers.get(&conn).addr;
+            match self.transmit(to, datagram.as_slice()) {
+                Ok(()) => {},
+                Err(e) => println!("Error: {}", e)
+            }
+
+            let unacked = self.unacked.get_mut(key);
+            unacked.retransmits += 1;
+            unacked.due = now + (self.retransmit_timeout << unacked.retransmits);
+        }
+    }
+
+    /// Send whatever's waiting on an ack and too large for the path to the
+    /// peer again, fragmented to fit. Their old fragments are given up on,
+    /// the peer gives up on them too.
+    fn refragment(&mut self, conn: Connection) {
+        let mtu = self.peers.get(&conn).path.current();
+        let mut stuck: Vec<u32> = self.unacked.iter()
+                                      .filter(|&(&(c, _), unacked)| {
+                                          c == conn && unacked.datagram.len() > mtu
+                                      })
+                                      .map(|(&(_, seq), _)| seq)
+                                      .collect();
+        stuck.sort();
+
+        let mut frames = Vec::new();
+        let mut refragmented = HashSet::new();
+        for seq in stuck.iter() {
+            let unacked = self.unacked.pop(&(conn, *seq)).unwrap();
+            match unacked.frame {
+                Some((id, frame)) => if refragmented.insert(id) {
+                    frames.push(frame);
+                },
+                None => frames.push(Arc::new(unacked.datagram.slice_from(HEADER).to_vec()))
+            }
+        }
+
+        for frame in frames.iter() {
+            match self.send(&conn, frame.as_slice()) {
+                Ok(()) => {},
+                Err(e) => println!("Error: {}", e)
+            }
+        }
+    }
+
+    /// Probe the paths to the peers that finished the handshake, anyone
+    /// else could have us send large probes to whoever they please.
+    fn probe(&mut self, now: u64) {
+        let probes: Vec<(SocketAddr, uint, u32)> = self.peers.mut_iter().filter_map(|(_, peer)| {
+            if !peer.handshaken {
+                return None;
+            }
+            peer.path.probe(now).map(|size| (peer.addr, size, peer.nonce))
+        }).collect();
+
+        for &(to, size, nonce) in probes.iter() {
+            let mut probe = header(PROBE, size as u32);
+            probe.push_all(nonce_bytes(nonce).as_slice());
+            probe.grow(size - PROBE_HEADER, &0u8);
+            match self.transmit(to, probe.as_slice()) {
+                Ok(()) => {},
+                Err(e) => println!("Error: {}", e)
+            }
+        }
+    }
+}
+
+impl Transport for UdpTransport {
+    fn listen(&mut self, addr: &SockAddr) -> GossipResult<SockAddr> {
+        let mut socket = try!(UdpSocket::bind(try!(resolve(addr))).map_err(io_err));
+        let bound = try!(socket.socket_name().map_err(io_err));
+        self.socket = Some(socket);
+        Ok(self::addr(bound))
+    }
+
+    fn connect(&mut self, addr: &SockAddr) -> GossipResult<Connection> {
+        let to = try!(resolve(addr));
+        let (conn, new) = self.connection(to);
+        if new {
+            try!(self.transmit(to, header(HELLO, 0).as_slice()));
+        }
+        Ok(conn)
+    }
+
+    fn send(&mut self, conn: &Connection, frame: &[u8]) -> GossipResult<()> {
+        let (id, mtu) = match self.peers.find_mut(conn) {
+            Some(peer) if !peer.handshaken => {
+                if peer.pending.len() == PENDING {
+                    return Err(GossipError::new("the handshake is still going on",
+                                                NodeUnreachable));
+                }
+                peer.pending.push(frame.to_vec());
+                return Ok(());
+            },
+            Some(peer) => (peer.next_frame, peer.path.current()),
+            None => return Err(GossipError::new("the connection is closed", NodeUnreachable))
+        };
+
+        if frame.len() + HEADER <= mtu {
+            return self.send_datagram(conn, DATA, frame, None);
+        }
+        if frame.len() > framing::MAX_FRAME {
+            return Err(GossipError::new("the frame is too large", NodeUnreachable));
+        }
+        self.peers.get_mut(conn).next_frame += 1;
+
+        let fragments = match fragment::split(frame, id, mtu - HEADER) {
+            Some(fragments) => fragments,
+            None => return Err(GossipError::new("the frame takes too many fragments",
+                                                NodeUnreachable))
+        };
+        let whole = Arc::new(frame.to_vec());
+        for fragment in fragments.iter() {
+            try!(self.send_datagram(conn, FRAGMENT, fragment.as_slice(),
+                                    Some((id, whole.clone()))));
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self, timeout: u64) -> GossipResult<Option<TransportEvent>> {
+        let deadline = clock::now() + timeout;
+        let mut buf = Vec::from_elem(MAX_DATAGRAM, 0u8);
+
+        loop {
+            let now = clock::now();
+            self.retransmit(now);
+            self.greet(now);
+            self.probe(now);
+            self.reassembly.expire(now);
+
+            match self.events.pop_front() {
+                Some(event) => return Ok(Some(event)),
+                None => {}
+            }
+            if now >= deadline {
+                return Ok(None);
+            }
+
+            // Wake up in time for the next retransmit.
+            let wait = cmp::min(deadline - now, self.retransmit_timeout);
+            let received = {
+                let socket = try!(self.socket());
+                socket.set_read_timeout(Some(wait));
+                socket.recv_from(buf.as_mut_slice())
+            };
+
+            match received {
+                Ok((len, from)) => self.datagram(buf.slice_to(len), from),
+                Err(ref e) if e.kind == TimedOut => {},
+                Err(e) => return Err(io_err(e))
+            }
+        }
+    }
+
+    fn close(&mut self, conn: &Connection) {
+        self.forget(conn);
+    }
+
+    fn shutdown(&mut self) {
+        let conns: Vec<Connection> = self.peers.keys().map(|conn| *conn).collect();
+        for conn in conns.iter() {
+            self.forget(conn);
+        }
+        self.socket = None;
+    }
+}
+
+fn header(kind: u8, seq: u32) -> Vec<u8> {
+    let mut wr = MemWriter::with_capacity(HEADER);
+    let _ = wr.write_u8(kind);
+    let _ = wr.write_be_u32(seq);
+    wr.unwrap()
+}
+
+fn nonce_bytes(nonce: u32) -> Vec<u8> {
+    let mut wr = MemWriter::with_capacity(PROBE_HEADER - HEADER);
+    let _ = wr.write_be_u32(nonce);
+    wr.unwrap()
+}
+
+fn resolve(addr: &SockAddr) -> GossipResult<SocketAddr> {
+    let ip: IpAddr = match from_str(addr.ip.as_slice()) {
+        Some(ip) => ip,
+        None => {
+            let ips = try!(get_host_addresses(addr.ip.as_slice()).map_err(io_err));
+            match ips.move_iter().next() {
+                Some(ip) => ip,
+                None => return Err(GossipError::new("the host doesn't resolve", NodeUnreachable))
+            }
+        }
+    };
+
+    Ok(SocketAddr { ip: ip, port: addr.port })
+}
+
+fn addr(addr: SocketAddr) -> SockAddr {
+    SockAddr::new(addr.ip.to_string().as_slice(), addr.port)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::{resolve, addr, header, nonce_bytes, HEADER, DATA, ACK, PROBE_ACK, HELLO, COOKIE};
+    use clock;
+    use mtu::PathMtu;
+    use std::io::net::udp::UdpSocket;
+    use stream::SockAddr;
+    use transport::{Transport, TransportEvent, Accepted, Frame, Closed};
+
+    fn pair() -> (UdpTransport, UdpTransport, SockAddr) {
+        let mut server = UdpTransport::new(20, 2, 1_400, 9_000, 60_000, 1_000);
+        let bound = server.listen(&SockAddr::new("127.0.0.1", 0)).unwrap();
+        (server, UdpTransport::new(20, 2, 1_400, 9_000, 60_000, 1_000), bound)
+    }
+
+    /// Take turns until the server has something to tell, the handshake
+    /// takes a couple of round trips.
+    fn next(server: &mut UdpTransport, client: &mut UdpTransport) -> Option<TransportEvent> {
+        for _ in range(0u, 50) {
+            match server.recv(10).unwrap() {
+                Some(event) => return Some(event),
+                None => {}
+            }
+            assert_eq!(client.recv(10).unwrap(), None);
+        }
+        None
+    }
+
+    #[test]
+    fn deliver_once_and_ack() {
+        let (mut server, mut client, bound) = pair();
+        let conn = client.connect(&bound).unwrap();
+        client.send(&conn, &[1u8, 2]).unwrap();
+
+        let accepted = match next(&mut server, &mut client) {
+            Some(Accepted(accepted, _)) => accepted,
+            event => fail!("unexpected {}", event)
+        };
+        assert_eq!(next(&mut server, &mut client), Some(Frame(accepted, vec![1u8, 2])));
+
+        // The ack stops the retransmits, nothing shows up twice.
+        assert_eq!(client.recv(200).unwrap(), None);
+        assert!(client.unacked.is_empty());
+        assert_eq!(server.recv(200).unwrap(), None);
+    }
+
+    #[test]
+    fn give_up_on_silent_peers() {
+        let (server, mut client, bound) = pair();
+        drop(server);
+
+        let conn = client.connect(&bound).unwrap();
+        client.send(&conn, &[1u8]).unwrap();
+
+        // 20ms, then 40ms and 80ms for the retransmits.
+        assert_eq!(client.recv(1_000).unwrap(), Some(Closed(conn)));
+        assert!(client.send(&conn, &[1u8]).is_err());
+    }
+
+    #[test]
+    fn fragment_large_frames() {
+        let (mut server, mut client, bound) = pair();
+        let conn = client.connect(&bound).unwrap();
+        let frame: Vec<u8> = range(0u, 5_000).map(|i| i as u8).collect();
+        client.send(&conn, frame.as_slice()).unwrap();
+        assert!(client.unacked.is_empty());
+
+        let accepted = match next(&mut server, &mut client) {
+            Some(Accepted(accepted, _)) => accepted,
+            event => fail!("unexpected {}", event)
+        };
+        // The welcome sends what waited for it.
+        assert_eq!(client.recv(10).unwrap(), None);
+        assert_eq!(client.unacked.len(), 4);
+        assert_eq!(server.recv(500).unwrap(), Some(Frame(accepted, frame)));
+        assert_eq!(client.recv(200).unwrap(), None);
+        assert!(client.unacked.is_empty());
+    }
+
+    #[test]
+    fn discover_the_path_mtu() {
+        let (mut server, mut client, bound) = pair();
+        let conn = client.connect(&bound).unwrap();
+
+        // Loopback takes the largest we probe for.
+        for _ in range(0u, 20) {
+            client.recv(20).unwrap();
+            server.recv(20).unwrap();
+        }
+        assert_eq!(client.peers.get(&conn).path.current(), 9_000);
+
+        client.send(&conn, Vec::from_elem(5_000, 1u8).as_slice()).unwrap();
+        assert_eq!(client.unacked.len(), 1);
+    }
+
+    #[test]
+    fn accept_nobody_without_the_handshake() {
+        let (mut server, _, bound) = pair();
+        let mut spoofer = UdpSocket::bind(resolve(&SockAddr::new("127.0.0.1", 0)).unwrap())
+            .unwrap();
+        let to = resolve(&bound).unwrap();
+
+        // Frames out of the blue and made up cookies get nowhere.
+        let mut frame = header(DATA, 0);
+        frame.push_all(&[1u8, 2]);
+        spoofer.send_to(frame.as_slice(), to).unwrap();
+        spoofer.send_to(header(HELLO, 7).as_slice(), to).unwrap();
+        assert_eq!(server.recv(100).unwrap(), None);
+        assert!(server.peers.is_empty());
+
+        // All a hello gets back is the cookie, no larger than the hello.
+        let mut buf = [0u8, ..64];
+        let (len, _) = spoofer.recv_from(&mut buf).unwrap();
+        assert_eq!(buf.slice_to(len)[0], COOKIE);
+        assert_eq!(len, HEADER);
+    }
+
+    #[test]
+    fn probe_only_handshaken_peers() {
+        let mut silent = UdpSocket::bind(resolve(&SockAddr::new("127.0.0.1", 0)).unwrap())
+            .unwrap();
+        let bound = silent.socket_name().unwrap();
+        let mut client = UdpTransport::new(20, 2, 1_400, 9_000, 60_000, 1_000);
+        client.connect(&addr(bound)).unwrap();
+        match client.recv(500).unwrap() {
+            Some(Closed(_)) => {},
+            event => fail!("unexpected {}", event)
+        }
+
+        // Hellos until it gave up, never a probe.
+        silent.set_timeout(Some(50));
+        let mut buf = [0u8, ..9_000];
+        let mut hellos = 0u;
+        loop {
+            match silent.recv_from(&mut buf) {
+                Ok((len, _)) => {
+                    assert_eq!((len, buf[0]), (HEADER, HELLO));
+                    hellos += 1;
+                },
+                Err(_) => break
+            }
+        }
+        assert_eq!(hellos, 3);
+    }
+
+    #[test]
+    fn start_over_with_a_restarted_peer() {
+        let (mut server, mut client, bound) = pair();
+        let port = client.listen(&SockAddr::new("127.0.0.1", 0)).unwrap().port;
+        let conn = client.connect(&bound).unwrap();
+        client.send(&conn, &[1u8]).unwrap();
+        let accepted = match next(&mut server, &mut client) {
+            Some(Accepted(accepted, _)) => accepted,
+            event => fail!("unexpected {}", event)
+        };
+        assert_eq!(next(&mut server, &mut client), Some(Frame(accepted, vec![1u8])));
+        assert_eq!(client.recv(100).unwrap(), None);
+
+        // Same port, no memory of what it sent.
+        drop(client);
+        let mut client = UdpTransport::new(20, 2, 1_400, 9_000, 60_000, 1_000);
+        client.listen(&SockAddr::new("127.0.0.1", port)).unwrap();
+        let conn = client.connect(&bound).unwrap();
+        client.send(&conn, &[1u8]).unwrap();
+
+        assert_eq!(next(&mut server, &mut client), Some(Closed(accepted)));
+        let restarted = match next(&mut server, &mut client) {
+            Some(Accepted(restarted, _)) => restarted,
+            event => fail!("unexpected {}", event)
+        };
+        assert!(restarted != accepted);
+        assert_eq!(next(&mut server, &mut client), Some(Frame(restarted, vec![1u8])));
+    }
+
+    #[test]
+    fn ignore_blind_acks() {
+        let (mut server, mut client, bound) = pair();
+        let conn = client.connect(&bound).unwrap();
+        client.send(&conn, &[1u8]).unwrap();
+        match next(&mut server, &mut client) {
+            Some(Accepted(..)) => {},
+            event => fail!("unexpected {}", event)
+        }
+        // The welcome sends the frame, the server didn't get to ack it yet.
+        assert_eq!(client.recv(10).unwrap(), None);
+        assert_eq!(client.unacked.len(), 1);
+
+        // Acks guessing the sequence numbers get nowhere, they start out
+        // at a random one.
+        let from = resolve(&bound).unwrap();
+        for seq in range(0u32, 256) {
+            client.datagram(header(ACK, seq).as_slice(), from);
+        }
+        assert_eq!(client.unacked.len(), 1);
+
+        // Nor do probe acks without the probe's nonce.
+        let now = clock::now();
+        let nonce = {
+            let peer = client.peers.get_mut(&conn);
+            peer.path = PathMtu::new(1_400, 9_000, 20, 60_000, now);
+            peer.nonce
+        };
+        let size = client.peers.get_mut(&conn).path.probe(now).unwrap();
+        let mut ack = header(PROBE_ACK, size as u32);
+        ack.push_all(nonce_bytes(!nonce).as_slice());
+        client.datagram(ack.as_slice(), from);
+        assert_eq!(client.peers.get(&conn).path.current(), 1_400);
+
+        let mut ack = header(PROBE_ACK, size as u32);
+        ack.push_all(nonce_bytes(nonce).as_slice());
+        client.datagram(ack.as_slice(), from);
+        assert_eq!(client.peers.get(&conn).path.current(), size);
+    }
+}
@@ -0,0 +1,293 @@
+//! Catching incoherent configurations up front, see `Config::validate`. A
+//! setting that can't possibly work (i.e., ticking every 0 milliseconds) is
+//! fatal; one that works, but surely not the way it was meant to (i.e.,
+//! heartbeats more often than the engine ticks), is only a warning.
+
+use config::Config;
+use score::MAX_SCORE;
+use snapshot::FailStop;
+use transport::{Udp, WebSocket};
+use identity::FromName;
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum Severity {
+    /// The node refuses to start, see `Node::try_with_config`.
+    Fatal,
+    Warning
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub struct Problem {
+    pub severity: Severity,
+    /// The `Config` field at fault (or the first of them).
+    pub field: &'static str,
+    pub message: String
+}
+
+/// Every problem with the configuration, fatal ones first.
+pub fn validate(config: &Config) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    let fatal = |field: &'static str, message: &str| Problem {
+        severity: Fatal,
+        field: field,
+        message: message.to_string()
+    };
+
+    if config.tick_interval == 0 {
+        problems.push(fatal("tick_interval", "the engine has to tick every so often"));
+    }
+    if config.quorum == Some(0) {
+        problems.push(fatal("quorum", "a quorum of nobody is always quorate, use `None`"));
+    }
+    if config.retransmit_mult == 0 {
+        problems.push(fatal("retransmit_mult", "membership changes would never go out"));
+    }
+    if config.max_updates_per_round == 0 {
+        problems.push(fatal("max_updates_per_round",
+                            "membership changes would never go out"));
+    }
+    if config.max_queued_frames == 0 {
+        problems.push(fatal("max_queued_frames", "nothing could ever be published"));
+    }
+    if config.max_seen == 0 {
+        problems.push(fatal("max_seen", "every broadcast would be taken for a new one"));
+    }
+    if config.max_epoch_jump == 0 {
+        problems.push(fatal("max_epoch_jump", "we'd never catch up with the ring epoch"));
+    }
+    if config.cpu_budget.is_some() && config.cpu_budget_interval == 0 {
+        problems.push(fatal("cpu_budget_interval", "the budget needs an interval"));
+    }
+    match config.node_id {
+        FromName(ref name) if name.is_empty() => {
+            problems.push(fatal("node_id", "every node would derive the same id"));
+        },
+        _ => {}
+    }
+    if config.crypto.hash(b"").is_err() {
+        problems.push(fatal("crypto", "the crypto provider can't hash, plug one in"));
+    }
+    if config.standby && config.standby_path.is_none() {
+        problems.push(fatal("standby", "standbys need the primary's `standby_path`"));
+    }
+    match config.phi_threshold {
+        Some(threshold) if threshold.is_nan() => {
+            problems.push(fatal("phi_threshold", "the threshold isn't a number"));
+        },
+        Some(threshold) if threshold <= 0.0 => {
+            problems.push(fatal("phi_threshold", "every member would always be failing"));
+        },
+        _ => {}
+    }
+    if config.transport == Udp && config.udp_retransmit_timeout == 0 {
+        problems.push(fatal("udp_retransmit_timeout", "frames would be retransmitted in a loop"));
+    }
+    if config.transport == WebSocket && !config.websocket_path.as_slice().starts_with("/") {
+        problems.push(fatal("websocket_path", "the resource has to start with a slash"));
+    }
+    let socket = &config.socket;
+    if [socket.connect_timeout, socket.read_timeout, socket.write_timeout].contains(&Some(0)) {
+        problems.push(fatal("socket", "a timeout of 0 gives up on every connection right away"));
+    }
+    if config.padding_buckets.iter().any(|bucket| *bucket == 0) {
+        problems.push(fatal("padding_buckets", "frames can't be padded to nothing"));
+    }
+    if config.graft_promotions == Some(0) {
+        problems.push(fatal("graft_promotions", "peers would take over without a single graft"));
+    }
+    if config.chatter_interval == Some(0) {
+        problems.push(fatal("chatter_interval", "chatter would go out in a loop"));
+    }
+    if config.score_disconnect_threshold > config.score_demote_threshold {
+        problems.push(fatal("score_disconnect_threshold",
+                            "peers have to be demoted before they're disconnected from"));
+    }
+    if config.probe_interval.is_some() {
+        if config.probe_timeout == 0 {
+            problems.push(fatal("probe_timeout", "every probe would time out right away"));
+        }
+        if config.suspicion_timeout == 0 {
+            problems.push(fatal("suspicion_timeout",
+                                "suspects would be taken for dead before they can refute it"));
+        }
+    }
+    match config.probe_interval {
+        Some(interval) if config.probe_timeout >= interval => {
+            problems.push(fatal("probe_timeout", format!(
+                "not under `probe_interval` ({}), members are never probed indirectly",
+                interval).as_slice()));
+        },
+        _ => {}
+    }
+    if config.score_demote_threshold > MAX_SCORE {
+        problems.push(fatal("score_demote_threshold",
+                            "every peer would be demoted, scores top out at 100"));
+    }
+
+    let warning = |field: &'static str, message: String| Problem {
+        severity: Warning,
+        field: field,
+        message: message
+    };
+
+    if config.heartbeat_interval < config.tick_interval {
+        problems.push(warning("heartbeat_interval", format!(
+            "heartbeats go out at most every tick ({}ms)", config.tick_interval)));
+    }
+    match config.gossip_interval {
+        Some(interval) if interval < config.tick_interval => {
+            problems.push(warning("gossip_interval", format!(
+                "rounds go out at most every tick ({}ms)", config.tick_interval)));
+        },
+        Some(interval) if config.gossip_jitter > interval => {
+            problems.push(warning("gossip_jitter", format!(
+                "more than `gossip_interval` ({}), it's capped at that", interval)));
+        },
+        None if config.gossip_jitter != 0 => {
+            problems.push(warning("gossip_jitter",
+                                  "rounds go out every tick without an interval".to_string()));
+        },
+        _ => {}
+    }
+    if config.digest_window != 0 && config.digest_window < config.tick_interval {
+        problems.push(warning("digest_window", format!(
+            "digests go out at most every tick ({}ms)", config.tick_interval)));
+    }
+    match config.cpu_budget {
+        Some(budget) if budget >= config.cpu_budget_interval * 1000 => {
+            problems.push(warning("cpu_budget", format!(
+                "a budget of {}us every {}ms never runs out", budget,
+                config.cpu_budget_interval)));
+        },
+        _ => {}
+    }
+    if config.minority_lockdown && config.quorum.is_none() {
+        problems.push(warning("minority_lockdown",
+                              "without a quorum we never lock down".to_string()));
+    }
+    match (config.eviction_confirmations, config.quorum) {
+        (Some(confirmations), Some(quorum)) if confirmations > quorum => {
+            problems.push(warning("eviction_confirmations", format!(
+                "{} confirmations are more than a quorum ({}), a partition might never \
+                 evict anybody", confirmations, quorum)));
+        },
+        _ => {}
+    }
+    if config.stall_threshold == Some(0) {
+        problems.push(warning("stall_threshold",
+                              "every subscriber stalls as soon as anything's queued".to_string()));
+    }
+    if config.max_versions == Some(0) {
+        problems.push(warning("max_versions",
+                              "at least one version runs, we'd always warn".to_string()));
+    }
+    if config.breaker_transient_threshold == 0 {
+        problems.push(warning("breaker_transient_threshold",
+                              "transient failures trip breakers right away".to_string()));
+    }
+    if config.latency_sampling == Some(0) {
+        problems.push(warning("latency_sampling",
+                              "nothing is ever sampled, use `None`".to_string()));
+    }
+    if config.history_max_bytes == Some(0) {
+        problems.push(warning("history_max_bytes",
+                              "peers that miss a broadcast can't pull it from us".to_string()));
+    }
+    if config.transport == Udp && config.udp_max_mtu < config.udp_mtu {
+        problems.push(warning("udp_max_mtu", format!(
+            "smaller than `udp_mtu` ({}), paths are never probed", config.udp_mtu)));
+    }
+    if config.graft_promotions == Some(1) {
+        problems.push(warning("graft_promotions",
+                              "every graft answered reshapes the tree".to_string()));
+    }
+    if config.commit_max_delay != 0 && config.commit_max_batch <= 1 {
+        problems.push(warning("commit_max_batch",
+                              "snapshots are written one by one, whatever the delay".to_string()));
+    }
+    if config.ihave_max_ids == 0 {
+        problems.push(warning("ihave_max_ids",
+                              "broadcasts are announced one per message".to_string()));
+    }
+    if config.probe_interval.is_some() && config.indirect_probes == 0 {
+        problems.push(warning("indirect_probes",
+                              "members that miss an ack are suspected right away".to_string()));
+    }
+    if config.probe_interval.is_none() && config.phi_threshold.is_some() {
+        problems.push(warning("phi_threshold",
+                              "without a `probe_interval` nothing is detected".to_string()));
+    }
+    match config.history_retention {
+        Some(retention) if retention < config.graft_timeout => {
+            problems.push(warning("history_retention", format!(
+                "under `graft_timeout` ({}), grafts for a broadcast come in once it's gone",
+                config.graft_timeout)));
+        },
+        _ => {}
+    }
+    if config.transport != WebSocket && !config.websocket_peers.is_empty() {
+        problems.push(warning("websocket_peers",
+                              "only the websocket transport dials over websocket".to_string()));
+    }
+    if config.storage_policy == FailStop && config.snapshot_path.is_none()
+       && config.peer_cache_path.is_none() {
+        problems.push(warning("storage_policy",
+                              "nothing is ever persisted, there's nothing to fail".to_string()));
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config::Config;
+
+    #[test]
+    fn defaults_are_fine() {
+        assert_eq!(validate(&Config::new()), vec![]);
+    }
+
+    #[test]
+    fn cross_field_checks() {
+        let mut config = Config::new();
+        config.tick_interval = 100;
+        config.heartbeat_interval = 50;
+        config.score_disconnect_threshold = 60;
+        config.minority_lockdown = true;
+
+        let problems: Vec<(Severity, &'static str)> = validate(&config).move_iter()
+            .map(|p| (p.severity, p.field))
+            .collect();
+        assert_eq!(problems, vec![(Fatal, "score_disconnect_threshold"),
+                                  (Warning, "heartbeat_interval"),
+                                  (Warning, "minority_lockdown")]);
+    }
+
+    #[test]
+    fn reject_a_phi_threshold_that_isnt_a_number() {
+        let mut config = Config::new();
+        config.probe_interval = Some(1_000);
+        config.phi_threshold = Some(0.0f64 / 0.0);
+        let problems: Vec<(Severity, &'static str)> = validate(&config).move_iter()
+            .map(|p| (p.severity, p.field))
+            .collect();
+        assert_eq!(problems, vec![(Fatal, "phi_threshold")]);
+    }
+
+    #[test]
+    fn probing_has_to_be_possible() {
+        let mut config = Config::new();
+        config.probe_interval = Some(1_000);
+        config.probe_timeout = 1_000;
+        config.suspicion_timeout = 0;
+        config.indirect_probes = 0;
+
+        let problems: Vec<(Severity, &'static str)> = validate(&config).move_iter()
+            .map(|p| (p.severity, p.field))
+            .collect();
+        assert_eq!(problems, vec![(Fatal, "suspicion_timeout"), (Fatal, "probe_timeout"),
+                                  (Warning, "indirect_probes")]);
+    }
+}
@@ -0,0 +1,93 @@
+//! Which versions the cluster runs, going by the `version` every member
+//! advertises within it's metadata. Meant for keeping an eye on rolling
+//! upgrades: a healthy cluster only ever runs a couple of versions at once,
+//! more than that usually means an upgrade got stuck halfway.
+
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use member::{Membership, Alive};
+
+/// The metadata key members advertise their version under.
+pub static VERSION_KEY: &'static str = "version";
+
+/// The version of this crate, advertised unless the application sets it's
+/// own through `Config::version`.
+pub static CRATE_VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+/// The live members (ourselves included) per version they run.
+#[deriving(Show, Clone, PartialEq)]
+pub struct VersionReport {
+    pub versions: HashMap<String, Vec<Uuid>>,
+    /// Members that didn't advertise any version (yet).
+    pub unknown: Vec<Uuid>
+}
+
+impl VersionReport {
+    pub fn new(local: Uuid, version: &str, members: &Membership) -> VersionReport {
+        let mut report = VersionReport {
+            versions: HashMap::new(),
+            unknown: Vec::new()
+        };
+
+        report.add(local, Some(version));
+        for member in members.iter().filter(|m| m.state == Alive) {
+            let version = member.metadata.find_equiv(&VERSION_KEY).map(|v| v.as_slice());
+            report.add(member.id(), version);
+        }
+
+        report
+    }
+
+    fn add(&mut self, id: Uuid, version: Option<&str>) {
+        match version {
+            Some(version) => {
+                self.versions.find_or_insert_with(version.to_string(), |_| Vec::new()).push(id);
+            },
+            None => self.unknown.push(id)
+        }
+    }
+
+    /// Whether more versions run than the policy allows.
+    pub fn is_skewed(&self, max_versions: uint) -> bool {
+        self.versions.len() > max_versions
+    }
+
+    /// Every version that runs, sorted.
+    pub fn list(&self) -> Vec<String> {
+        let mut versions: Vec<String> = self.versions.keys().map(|v| v.clone()).collect();
+        versions.sort();
+        versions
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use uuid::Uuid;
+    use member::{Membership, Dead};
+    use protocol::Peer;
+
+    #[test]
+    fn versions_of_live_members() {
+        let mut members = Membership::new();
+        let (a, b, c) = (Peer::empty(), Peer::empty(), Peer::empty());
+        for peer in vec![a.clone(), b.clone(), c.clone()].move_iter() {
+            members.insert(peer);
+        }
+
+        let mut metadata = HashMap::new();
+        metadata.insert(VERSION_KEY.to_string(), "1.1.0".to_string());
+        members.set_metadata(&a.id(), metadata.clone());
+        members.set_metadata(&c.id(), metadata);
+        members.set_state(&c.id(), Dead);
+
+        let report = VersionReport::new(Uuid::new_v4(), "1.0.0", &members);
+        assert_eq!(report.list(), vec!["1.0.0".to_string(), "1.1.0".to_string()]);
+        assert_eq!(report.versions.find_equiv(&"1.1.0"), Some(&vec![a.id()]));
+        assert_eq!(report.unknown, vec![b.id()]);
+        assert!(report.is_skewed(1));
+        assert!(!report.is_skewed(2));
+    }
+}
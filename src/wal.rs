@@ -0,0 +1,145 @@
+//! The write-ahead log of the broadcasts we deliver (see
+//! `Config::wal_path`), replayed into the history when the node starts
+//! listening again so a restart doesn't forget what we retained, nor take
+//! it for new when it comes around once more. Appends are batched with
+//! group commit (see `commit`), a write and an fsync on behalf of every
+//! broadcast in the batch. Once the log holds a lot more than we retain,
+//! it's rewritten with only that.
+//!
+//! Format:
+//!
+//! ```notrust
+//! bitdata Log {
+//!     Log {
+//!         magic: [u8, ..4], // "GWAL"
+//!         version: u8,
+//!         records: &[Record]
+//!     }
+//! }
+//!
+//! bitdata Record {
+//!     Record {
+//!         size: u32,
+//!         checksum: u32, // crc32 of the record
+//!         record: &[u8] // a broadcast, as the codec encodes it
+//!     }
+//! }
+//! ```
+//!
+//! A crash mid-append leaves a torn record at the end, it and whatever
+//! follows are ignored.
+
+use std::io::{File, MemWriter, BufReader, Append, Write, FileNotFound};
+use std::io::fs;
+
+use crc::crc32;
+use result::{GossipResult, GossipError, InvalidSnapshot, io_err};
+
+static MAGIC: &'static [u8] = b"GWAL";
+static VERSION: u8 = 1;
+
+fn encode(records: &[Vec<u8>], header: bool) -> Vec<u8> {
+    let mut wr = MemWriter::new();
+
+    // Writing to memory can't fail.
+    if header {
+        wr.write(MAGIC).unwrap();
+        wr.write_u8(VERSION).unwrap();
+    }
+    for record in records.iter() {
+        wr.write_be_u32(record.len() as u32).unwrap();
+        wr.write_be_u32(crc32(record.as_slice())).unwrap();
+        wr.write(record.as_slice()).unwrap();
+    }
+    wr.unwrap()
+}
+
+/// Append the records to the log at `path` (creating it if need be), all
+/// in one write.
+pub fn append(path: &Path, records: &[Vec<u8>]) -> GossipResult<()> {
+    let mut file = try!(File::open_mode(path, Append, Write).map_err(io_err));
+    let empty = try!(file.stat().map_err(io_err)).size == 0;
+    try!(file.write(encode(records, empty).as_slice()).map_err(io_err));
+    file.fsync().map_err(io_err)
+}
+
+/// Replace the log at `path` with one of the records. Like snapshots, it's
+/// written to a temporary file first.
+pub fn rewrite(path: &Path, records: &[Vec<u8>]) -> GossipResult<()> {
+    let tmp = path.with_extension("tmp");
+
+    {
+        let mut file = try!(File::create(&tmp).map_err(io_err));
+        try!(file.write(encode(records, true).as_slice()).map_err(io_err));
+        try!(file.fsync().map_err(io_err));
+    }
+
+    fs::rename(&tmp, path).map_err(io_err)
+}
+
+/// Every intact record of the log at `path`, oldest first. There are none
+/// without a log.
+pub fn replay(path: &Path) -> GossipResult<Vec<Vec<u8>>> {
+    let blob = match File::open(path).read_to_end() {
+        Ok(blob) => blob,
+        Err(ref err) if err.kind == FileNotFound => return Ok(Vec::new()),
+        Err(err) => return Err(io_err(err))
+    };
+    if blob.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut rd = BufReader::new(blob.as_slice());
+    let magic = try!(rd.read_exact(MAGIC.len()).map_err(io_err));
+    if magic.as_slice() != MAGIC || try!(rd.read_u8().map_err(io_err)) != VERSION {
+        return Err(GossipError::new("not a log we understand", InvalidSnapshot));
+    }
+
+    let mut records = Vec::new();
+    loop {
+        let (size, checksum) = match (rd.read_be_u32(), rd.read_be_u32()) {
+            (Ok(size), Ok(checksum)) => (size as uint, checksum),
+            _ => break
+        };
+        match rd.read_exact(size) {
+            Ok(record) if crc32(record.as_slice()) == checksum => records.push(record),
+            _ => break
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{File, Append, Write, TempDir};
+
+    #[test]
+    fn append_and_replay() {
+        let dir = TempDir::new("wal").unwrap();
+        let path = dir.path().join("log");
+        assert_eq!(replay(&path).unwrap(), Vec::new());
+
+        append(&path, &[vec![1u8, 2], vec![]]).unwrap();
+        append(&path, &[vec![3u8]]).unwrap();
+        assert_eq!(replay(&path).unwrap(), vec![vec![1u8, 2], vec![], vec![3u8]]);
+
+        rewrite(&path, &[vec![3u8]]).unwrap();
+        assert_eq!(replay(&path).unwrap(), vec![vec![3u8]]);
+    }
+
+    #[test]
+    fn ignore_a_torn_record() {
+        let dir = TempDir::new("wal").unwrap();
+        let path = dir.path().join("log");
+        append(&path, &[vec![1u8, 2]]).unwrap();
+
+        // Half a record, as a crash would leave it.
+        {
+            let mut file = File::open_mode(&path, Append, Write).unwrap();
+            file.write_be_u32(4).unwrap();
+            file.write(&[0u8, 0, 0, 0, 1]).unwrap();
+        }
+        assert_eq!(replay(&path).unwrap(), vec![vec![1u8, 2]]);
+    }
+}
@@ -0,0 +1,633 @@
+//! A transport for nodes behind HTTP-only infrastructure (load balancers,
+//! proxies), see `Config::transport`. It listens for both: connections
+//! starting with an HTTP upgrade request speak WebSocket (each of our frames
+//! a binary message), anything else is taken for plain length-prefixed
+//! frames like `TcpTransport`'s. Which of the two we dial with is decided
+//! per peer, only those listed in `Config::websocket_peers` are dialed over
+//! WebSocket.
+//!
+//! Only what RFC 6455 asks of us is implemented: the handshake, masking
+//! from clients, fragmented messages and answering pings. There's no
+//! compression, and text messages are taken for binary ones.
+
+use std::collections::HashMap;
+use std::io::{TcpListener, TcpStream, Listener, Acceptor, Timer, IoResult, IoError};
+use std::io::{InvalidInput, TimedOut};
+use std::io::net::ip::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomics::{AtomicBool, SeqCst};
+use std::rand::{task_rng, Rng};
+use std::ascii::StrAsciiExt;
+use std::str;
+use serialize::base64::{ToBase64, STANDARD};
+
+use stream::SockAddr;
+use result::{GossipResult, GossipError, NodeUnreachable, UnknownError, io_err};
+use socket::SocketOptions;
+use socket;
+use supervisor::{Worker, RestartPolicy, Supervision, supervise};
+use framing::{MAX_FRAME, write_frame, read_frame, read_payload};
+use transport::{Transport, TransportEvent, Connection, Accepted, Frame, Closed};
+
+static GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// The most we read of an HTTP request (or response) head.
+static MAX_HEAD: uint = 8 * 1024;
+/// How often (in milliseconds) the acceptor looks up whether the
+/// transport shut down.
+static POLL: u64 = 100;
+
+static CONTINUATION: u8 = 0x0;
+static TEXT: u8 = 0x1;
+static BINARY: u8 = 0x2;
+static CLOSE: u8 = 0x8;
+static PING: u8 = 0x9;
+static PONG: u8 = 0xA;
+
+/// What a connection speaks.
+#[deriving(Show, Clone, PartialEq)]
+enum Mode {
+    Plain,
+    /// Clients mask everything they send, servers don't.
+    WebSocket(bool)
+}
+
+/// A whole message read off a WebSocket.
+#[deriving(Show, PartialEq)]
+pub enum Message {
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Close
+}
+
+/// What the acceptor and the readers tell the transport.
+enum Io {
+    /// Along with the first frame, read while telling plain connections
+    /// apart.
+    Opened(TcpStream, SockAddr, Mode, Option<Vec<u8>>),
+    Read(Connection, Vec<u8>),
+    Pinged(Connection, Vec<u8>),
+    HungUp(Connection)
+}
+
+struct Stream {
+    stream: TcpStream,
+    mode: Mode
+}
+
+pub struct WebSocketTransport {
+    options: SocketOptions,
+    policy: RestartPolicy,
+    supervisor_tx: Sender<Supervision>,
+    /// The peers we dial over WebSocket.
+    peers: Vec<SockAddr>,
+    /// The resource upgrade requests go to.
+    path: String,
+    /// The write half of every open connection.
+    streams: HashMap<Connection, Stream>,
+    next: u64,
+    /// Set once we shut down, the acceptor stops.
+    stopped: Arc<AtomicBool>,
+    tx: Sender<Io>,
+    rx: Receiver<Io>
+}
+
+impl WebSocketTransport {
+    pub fn new(options: SocketOptions, policy: RestartPolicy, supervisor_tx: Sender<Supervision>,
+               peers: Vec<SockAddr>, path: &str) -> WebSocketTransport {
+        let (tx, rx) = channel();
+
+        WebSocketTransport {
+            options: options,
+            policy: policy,
+            supervisor_tx: supervisor_tx,
+            peers: peers,
+            path: path.to_string(),
+            streams: HashMap::new(),
+            next: 0,
+            stopped: Arc::new(AtomicBool::new(false)),
+            tx: tx,
+            rx: rx
+        }
+    }
+
+    /// Keep track of a freshly opened (or accepted) stream and start
+    /// reading from it, after the frame already read off it (if any).
+    fn open(&mut self, mut stream: TcpStream, mode: Mode, first: Option<Vec<u8>>) -> Connection {
+        match socket::configure(&mut stream, &self.options) {
+            Ok(()) => {},
+            Err(e) => println!("Error: {}", e)
+        }
+
+        let conn = Connection(self.next);
+        self.next += 1;
+
+        match first {
+            Some(frame) => { let _ = self.tx.send_opt(Read(conn, frame)); },
+            None => {}
+        }
+
+        let reader = stream.clone();
+        let tx = self.tx.clone();
+        let reading = mode.clone();
+        spawn(proc() read(reader, reading, conn, tx));
+
+        self.streams.insert(conn, Stream { stream: stream, mode: mode });
+        conn
+    }
+
+    fn event(&mut self, io: Io) -> Option<TransportEvent> {
+        match io {
+            Opened(stream, addr, mode, first) => {
+                Some(Accepted(self.open(stream, mode, first), addr))
+            },
+            Read(conn, frame) => Some(Frame(conn, frame)),
+            Pinged(conn, payload) => {
+                match self.streams.find_mut(&conn) {
+                    Some(s) => {
+                        let masked = s.mode == WebSocket(true);
+                        let _ = write_message(&mut s.stream, PONG, payload.as_slice(), masked);
+                    },
+                    None => {}
+                }
+                None
+            },
+            HungUp(conn) => {
+                self.streams.remove(&conn);
+                Some(Closed(conn))
+            }
+        }
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn listen(&mut self, addr: &SockAddr) -> GossipResult<SockAddr> {
+        let (bound_tx, bound_rx) = channel();
+        let acceptor = AcceptorWorker {
+            addr: addr.clone(),
+            path: self.path.clone(),
+            stopped: self.stopped.clone(),
+            tx: self.tx.clone(),
+            bound_tx: bound_tx
+        };
+        supervise("websocket-acceptor", acceptor, self.policy.clone(),
+                  self.supervisor_tx.clone());
+
+        match bound_rx.recv_opt() {
+            Ok(bound) => bound,
+            Err(()) => Err(GossipError::new("the acceptor never started", UnknownError))
+        }
+    }
+
+    fn connect(&mut self, addr: &SockAddr) -> GossipResult<Connection> {
+        let mut stream = try!(socket::connect(addr, &self.options));
+
+        if !self.peers.contains(addr) {
+            return Ok(self.open(stream, Plain, None));
+        }
+
+        try!(upgrade(&mut stream, addr, self.path.as_slice()).map_err(io_err));
+        Ok(self.open(stream, WebSocket(true), None))
+    }
+
+    fn send(&mut self, conn: &Connection, frame: &[u8]) -> GossipResult<()> {
+        match self.streams.find_mut(conn) {
+            Some(s) => match s.mode {
+                Plain => write_frame(&mut s.stream, frame).map_err(io_err),
+                WebSocket(masked) => {
+                    write_message(&mut s.stream, BINARY, frame, masked).map_err(io_err)
+                }
+            },
+            None => Err(GossipError::new("the connection is closed", NodeUnreachable))
+        }
+    }
+
+    fn recv(&mut self, timeout: u64) -> GossipResult<Option<TransportEvent>> {
+        let mut timer = try!(Timer::new().map_err(io_err));
+        let expired = timer.oneshot(timeout);
+
+        loop {
+            let io = match self.rx.try_recv() {
+                Ok(io) => Some(io),
+                Err(_) => {
+                    let rx = &self.rx;
+                    select! {
+                        io = rx.recv_opt() => io.ok(),
+                        () = expired.recv() => None
+                    }
+                }
+            };
+
+            match io {
+                // Pongs go out without the node knowing.
+                Some(io) => match self.event(io) {
+                    Some(event) => return Ok(Some(event)),
+                    None => {}
+                },
+                None => return Ok(None)
+            }
+        }
+    }
+
+    fn close(&mut self, conn: &Connection) {
+        // The reader notices and reports the connection closed.
+        match self.streams.pop(conn) {
+            Some(mut s) => {
+                if s.mode != Plain {
+                    let masked = s.mode == WebSocket(true);
+                    let _ = write_message(&mut s.stream, CLOSE, &[], masked);
+                }
+                let _ = s.stream.close_read();
+                let _ = s.stream.close_write();
+            },
+            None => {}
+        }
+    }
+
+    fn shutdown(&mut self) {
+        self.stopped.store(true, SeqCst);
+        let conns: Vec<Connection> = self.streams.keys().map(|conn| *conn).collect();
+        for conn in conns.iter() {
+            self.close(conn);
+        }
+    }
+}
+
+/// Everything needed to start the acceptor (over), see `supervise`.
+#[deriving(Clone)]
+struct AcceptorWorker {
+    addr: SockAddr,
+    path: String,
+    stopped: Arc<AtomicBool>,
+    tx: Sender<Io>,
+    /// Where the first start reports the address it bound to.
+    bound_tx: Sender<GossipResult<SockAddr>>
+}
+
+impl Worker for AcceptorWorker {
+    fn run(self) {
+        let mut listener = match TcpListener::bind(self.addr.ip.as_slice(), self.addr.port) {
+            Ok(listener) => listener,
+            Err(e) => {
+                let _ = self.bound_tx.send_opt(Err(io_err(e)));
+                return;
+            }
+        };
+        let bound = listener.socket_name().map(addr).unwrap_or(self.addr.clone());
+        let mut acceptor = match listener.listen() {
+            Ok(acceptor) => acceptor,
+            Err(e) => {
+                let _ = self.bound_tx.send_opt(Err(io_err(e)));
+                return;
+            }
+        };
+
+        // Only the first start is waited on, restarts go unnoticed.
+        let _ = self.bound_tx.send_opt(Ok(bound));
+
+        while !self.stopped.load(SeqCst) {
+            acceptor.set_timeout(Some(POLL));
+            match acceptor.accept() {
+                Ok(stream) => {
+                    // A slow handshake shouldn't hold up the others.
+                    let (path, tx) = (self.path.clone(), self.tx.clone());
+                    spawn(proc() handshake(stream, path, tx));
+                },
+                Err(ref e) if e.kind == TimedOut => {},
+                Err(e) => println!("Error: {}", e)
+            }
+        }
+    }
+}
+
+/// Tell what the accepted stream speaks, upgrading it if it's WebSocket.
+/// Plain connections are only accepted once they sent a frame.
+fn handshake(mut stream: TcpStream, path: String, tx: Sender<Io>) {
+    let peer = match stream.peer_name() {
+        Ok(peer) => addr(peer),
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    };
+
+    let opened = match stream.read_exact(4) {
+        Ok(ref prefix) if prefix.as_slice() == b"GET " => {
+            accept(&mut stream, path.as_slice()).map(|()| (WebSocket(false), None))
+        },
+        Ok(prefix) => {
+            let len = (prefix[0] as uint << 24) | (prefix[1] as uint << 16)
+                    | (prefix[2] as uint << 8) | prefix[3] as uint;
+            read_payload(&mut stream, len).map(|frame| (Plain, Some(frame)))
+        },
+        Err(e) => Err(e)
+    };
+
+    match opened {
+        Ok((mode, first)) => { let _ = tx.send_opt(Opened(stream, peer, mode, first)); },
+        Err(e) => println!("Error: {}", e)
+    }
+}
+
+/// The server's side of the upgrade, the request's method already read.
+fn accept(stream: &mut TcpStream, path: &str) -> IoResult<()> {
+    let head = try!(read_head(stream));
+    let target = head.as_slice().split(' ').next().unwrap_or("");
+    let key = match header(head.as_slice(), "sec-websocket-key") {
+        Some(key) if target == path => key,
+        _ => {
+            let _ = stream.write(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n");
+            return Err(invalid("not a websocket upgrade"));
+        }
+    };
+
+    try!(stream.write_str(format!("HTTP/1.1 101 Switching Protocols\r\n\
+                                   Upgrade: websocket\r\n\
+                                   Connection: Upgrade\r\n\
+                                   Sec-WebSocket-Accept: {}\r\n\r\n",
+                                  accept_key(key.as_slice())).as_slice()));
+    stream.flush()
+}
+
+/// The client's side of the upgrade.
+fn upgrade(stream: &mut TcpStream, addr: &SockAddr, path: &str) -> IoResult<()> {
+    let nonce: Vec<u8> = range(0u, 16).map(|_| task_rng().gen::<u8>()).collect();
+    let key = nonce.as_slice().to_base64(STANDARD);
+
+    try!(stream.write_str(format!("GET {} HTTP/1.1\r\n\
+                                   Host: {}:{}\r\n\
+                                   Upgrade: websocket\r\n\
+                                   Connection: Upgrade\r\n\
+                                   Sec-WebSocket-Key: {}\r\n\
+                                   Sec-WebSocket-Version: 13\r\n\r\n",
+                                  path, addr.ip, addr.port, key).as_slice()));
+    try!(stream.flush());
+
+    let head = try!(read_head(stream));
+    if !head.as_slice().starts_with("HTTP/1.1 101") {
+        return Err(invalid("the upgrade was refused"));
+    }
+    match header(head.as_slice(), "sec-websocket-accept") {
+        Some(ref accept) if *accept == accept_key(key.as_slice()) => Ok(()),
+        _ => Err(invalid("the upgrade wasn't acknowledged"))
+    }
+}
+
+/// What the server has to answer the key with.
+pub fn accept_key(key: &str) -> String {
+    let mut data = key.as_bytes().to_vec();
+    data.push_all(GUID.as_bytes());
+    sha1(data.as_slice()).as_slice().to_base64(STANDARD)
+}
+
+fn read_head<R: Reader>(rd: &mut R) -> IoResult<String> {
+    let mut head = Vec::new();
+    while !head.as_slice().ends_with(b"\r\n\r\n") {
+        if head.len() > MAX_HEAD {
+            return Err(invalid("request too large"));
+        }
+        head.push(try!(rd.read_byte()));
+    }
+
+    match str::from_utf8(head.as_slice()) {
+        Some(head) => Ok(head.to_string()),
+        None => Err(invalid("the request isn't utf-8"))
+    }
+}
+
+/// The value of the header (by it's lowercase name).
+fn header(head: &str, name: &str) -> Option<String> {
+    head.lines().skip(1).filter_map(|line| {
+        let mut parts = line.splitn(':', 1);
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if key.trim().to_ascii_lower().as_slice() == name => {
+                Some(value.trim().to_string())
+            },
+            _ => None
+        }
+    }).next()
+}
+
+/// Read messages off the stream until it's closed (or sends garbage).
+fn read(mut stream: TcpStream, mode: Mode, conn: Connection, tx: Sender<Io>) {
+    loop {
+        let io = match mode {
+            Plain => read_frame(&mut stream).map(|frame| Read(conn, frame)),
+            WebSocket(_) => match read_message(&mut stream) {
+                Ok(Binary(frame)) => Ok(Read(conn, frame)),
+                Ok(Ping(payload)) => Ok(Pinged(conn, payload)),
+                Ok(Close) => break,
+                Err(e) => Err(e)
+            }
+        };
+
+        match io {
+            Ok(io) => {
+                if tx.send_opt(io).is_err() {
+                    return;
+                }
+            },
+            Err(_) => break
+        }
+    }
+
+    let _ = tx.send_opt(HungUp(conn));
+}
+
+/// Write a whole (unfragmented) message, masked with a fresh key unless
+/// we're the server.
+pub fn write_message<W: Writer>(wr: &mut W, opcode: u8, payload: &[u8],
+                                masked: bool) -> IoResult<()> {
+    let mask_bit = if masked { 0x80u8 } else { 0 };
+    try!(wr.write_u8(0x80 | opcode));
+
+    let len = payload.len();
+    if len < 126 {
+        try!(wr.write_u8(mask_bit | len as u8));
+    } else if len < 65_536 {
+        try!(wr.write_u8(mask_bit | 126));
+        try!(wr.write_be_u16(len as u16));
+    } else {
+        try!(wr.write_u8(mask_bit | 127));
+        try!(wr.write_be_u64(len as u64));
+    }
+
+    if masked {
+        let key: Vec<u8> = range(0u, 4).map(|_| task_rng().gen::<u8>()).collect();
+        try!(wr.write(key.as_slice()));
+        let masked: Vec<u8> = payload.iter().enumerate()
+                                     .map(|(i, b)| *b ^ *key.get(i % 4))
+                                     .collect();
+        try!(wr.write(masked.as_slice()));
+    } else {
+        try!(wr.write(payload));
+    }
+    wr.flush()
+}
+
+/// Read a whole message, putting fragments back together. Pongs are
+/// skipped.
+pub fn read_message<R: Reader>(rd: &mut R) -> IoResult<Message> {
+    let mut message = Vec::new();
+
+    loop {
+        let first = try!(rd.read_u8());
+        let (fin, opcode) = (first & 0x80 != 0, first & 0x0f);
+
+        let second = try!(rd.read_u8());
+        let len = match second & 0x7f {
+            126 => try!(rd.read_be_u16()) as uint,
+            127 => try!(rd.read_be_u64()) as uint,
+            len => len as uint
+        };
+        if message.len() + len > MAX_FRAME {
+            return Err(invalid("frame too large"));
+        }
+
+        let key = if second & 0x80 != 0 { Some(try!(rd.read_exact(4))) } else { None };
+        let mut payload = try!(rd.read_exact(len));
+        match key {
+            Some(key) => for (i, b) in payload.mut_iter().enumerate() {
+                *b ^= *key.get(i % 4);
+            },
+            None => {}
+        }
+
+        match opcode {
+            BINARY | TEXT | CONTINUATION => {
+                message.push_all(payload.as_slice());
+                if fin {
+                    return Ok(Binary(message));
+                }
+            },
+            PING => return Ok(Ping(payload)),
+            PONG => {},
+            CLOSE => return Ok(Close),
+            _ => return Err(invalid("unknown opcode"))
+        }
+    }
+}
+
+fn invalid(desc: &'static str) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: desc,
+        detail: None
+    }
+}
+
+fn addr(addr: SocketAddr) -> SockAddr {
+    SockAddr::new(addr.ip.to_string().as_slice(), addr.port)
+}
+
+/// Only for the handshake, SHA-1 isn't worth anything as a hash anymore.
+fn sha1(data: &[u8]) -> Vec<u8> {
+    let rotl = |x: u32, n: uint| (x << n) | (x >> (32 - n));
+    let mut h = [0x67452301u32, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    let bits = data.len() as u64 * 8;
+    for i in range(0u, 8).rev() {
+        padded.push((bits >> (i * 8)) as u8);
+    }
+
+    for chunk in padded.as_slice().chunks(64) {
+        let mut w = [0u32, ..80];
+        for i in range(0u, 16) {
+            w[i] = (chunk[i * 4] as u32 << 24) | (chunk[i * 4 + 1] as u32 << 16)
+                 | (chunk[i * 4 + 2] as u32 << 8) | chunk[i * 4 + 3] as u32;
+        }
+        for i in range(16u, 80) {
+            w[i] = rotl(w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16], 1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for i in range(0u, 80) {
+            let (f, k) = match i / 20 {
+                0 => ((b & c) | (!b & d), 0x5A827999u32),
+                1 => (b ^ c ^ d, 0x6ED9EBA1),
+                2 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6)
+            };
+            let temp = rotl(a, 5) + f + e + k + w[i];
+            e = d;
+            d = c;
+            c = rotl(b, 30);
+            b = a;
+            a = temp;
+        }
+
+        h[0] += a;
+        h[1] += b;
+        h[2] += c;
+        h[3] += d;
+        h[4] += e;
+    }
+
+    let mut digest = Vec::with_capacity(20);
+    for word in h.iter() {
+        digest.push_all(&[(*word >> 24) as u8, (*word >> 16) as u8, (*word >> 8) as u8,
+                          *word as u8]);
+    }
+    digest
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{MemWriter, MemReader};
+
+    use stream::SockAddr;
+    use supervisor::RestartPolicy;
+    use socket::SocketOptions;
+    use transport::{Transport, Accepted, Frame};
+
+    #[test]
+    fn messages_roundtrip() {
+        // The example from RFC 6455.
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ==").as_slice(),
+                   "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let big = Vec::from_elem(70_000, 7u8);
+        let mut wr = MemWriter::new();
+        write_message(&mut wr, 0x2, &[1u8, 2, 3], true).unwrap();
+        write_message(&mut wr, 0x9, &[4u8], false).unwrap();
+        write_message(&mut wr, 0x2, big.as_slice(), false).unwrap();
+
+        let mut rd = MemReader::new(wr.unwrap());
+        assert_eq!(read_message(&mut rd).unwrap(), Binary(vec![1u8, 2, 3]));
+        assert_eq!(read_message(&mut rd).unwrap(), Ping(vec![4u8]));
+        assert_eq!(read_message(&mut rd).unwrap(), Binary(big));
+        assert!(read_message(&mut rd).is_err());
+    }
+
+    #[test]
+    fn dial_per_peer() {
+        let transport = |peers: Vec<SockAddr>| {
+            let (tx, _) = channel();
+            WebSocketTransport::new(SocketOptions::new(), RestartPolicy::new(), tx, peers,
+                                    "/gossip")
+        };
+
+        let mut server = transport(Vec::new());
+        let bound = server.listen(&SockAddr::new("127.0.0.1", 0)).unwrap();
+        let (mut upgraded, mut plain) = (transport(vec![bound.clone()]), transport(Vec::new()));
+
+        for client in [&mut upgraded, &mut plain].mut_iter() {
+            let conn = client.connect(&bound).unwrap();
+            client.send(&conn, &[4u8, 2]).unwrap();
+
+            let accepted = match server.recv(1_000).unwrap() {
+                Some(Accepted(accepted, _)) => accepted,
+                event => fail!("unexpected {}", event)
+            };
+            assert_eq!(server.recv(1_000).unwrap(), Some(Frame(accepted, vec![4u8, 2])));
+
+            server.send(&accepted, &[1u8]).unwrap();
+            assert_eq!(client.recv(1_000).unwrap(), Some(Frame(conn, vec![1u8])));
+        }
+    }
+}
@@ -1,6 +1,78 @@
 extern crate gossip;
 
+use std::io::timer::sleep;
+use gossip::{Node, Broadcast, Incoming, Transport, InMemoryTransport, Network};
+
+/// Tick every node until `done` holds, or give up after a couple seconds.
+fn settle(nodes: &mut [Node], done: |&mut [Node]| -> bool) -> bool {
+    for _ in range(0u, 400) {
+        if done(nodes) {
+            return true;
+        }
+        for node in nodes.mut_iter() {
+            node.tick();
+        }
+        sleep(5);
+    }
+    done(nodes)
+}
+
+/// Everything delivered to `incoming` so far.
+fn drain(incoming: &mut Incoming) -> Vec<Vec<u8>> {
+    let mut delivered = Vec::new();
+    loop {
+        match incoming.try_next() {
+            Some((broadcast, res)) => {
+                delivered.push(broadcast.as_bytes().to_vec());
+                res.ok().unwrap();
+            },
+            None => return delivered
+        }
+    }
+}
+
 #[test]
 fn three_node_cluster() {
+    let network = Network::new();
+    let mut nodes: Vec<Node> = range(0u16, 3).map(|i| {
+        let mut node = Node::new();
+        node.set_transport(box InMemoryTransport::new(&network) as Box<Transport + Send>);
+        node.listen("127.0.0.1", 5000 + i).unwrap();
+        node
+    }).collect();
+
+    // The last two only know of the first, they hear of each other from it.
+    nodes.get_mut(1).join("127.0.0.1", 5000).unwrap();
+    nodes.get_mut(2).join("127.0.0.1", 5000).unwrap();
+    assert!(settle(nodes.as_mut_slice(), |nodes| {
+        nodes.iter().all(|node| node.alive_members() == 3)
+    }));
+
+    // A broadcast reaches everyone, once.
+    let mut incoming: Vec<Incoming> = nodes.mut_iter().map(|node| node.incoming()).collect();
+    nodes.get_mut(1).broadcast(Broadcast::new(vec![1u8, 2]).unwrap()).unwrap();
+    let mut delivered = vec![Vec::new(), Vec::new(), Vec::new()];
+    assert!(settle(nodes.as_mut_slice(), |_| {
+        for (i, incoming) in incoming.mut_iter().enumerate() {
+            delivered.get_mut(i).push_all_move(drain(incoming));
+        }
+        delivered.iter().all(|d| !d.is_empty())
+    }));
+    for d in delivered.iter() {
+        assert_eq!(d, &vec![vec![1u8, 2]]);
+    }
 
+    // One sent to a single member goes over the connection it introduced
+    // itself on, and nobody else hears of it.
+    let to = nodes.get(2).id();
+    nodes.get_mut(1).broadcast_to(to, Broadcast::new(vec![3u8]).unwrap()).unwrap();
+    let mut received = Vec::new();
+    assert!(settle(nodes.as_mut_slice(), |_| {
+        received.push_all_move(drain(incoming.get_mut(2)));
+        !received.is_empty()
+    }));
+    assert_eq!(received, vec![vec![3u8]]);
+    settle(nodes.as_mut_slice(), |_| false);
+    assert!(drain(incoming.get_mut(0)).is_empty());
+    assert!(drain(incoming.get_mut(1)).is_empty());
 }
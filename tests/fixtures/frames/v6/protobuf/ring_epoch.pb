@@ -0,0 +1 @@
+Z	
\ No newline at end of file
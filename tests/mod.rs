@@ -1,5 +1,6 @@
 extern crate gossip;
 extern crate simulation;
+extern crate uuid;
 
 pub mod proxy;
 pub mod simulations;
@@ -0,0 +1,73 @@
+//! Deadlines under skewed clocks: the receiver's clock is way ahead of the
+//! origin's (and drifting), yet broadcasts only expire once they're
+//! actually past their deadline.
+
+use uuid::Uuid;
+use gossip::{Config, Broadcast, Peer};
+use gossip::engine::{Engine, Input, Output, Publish, Received, Relayed, WallClock, Relay, Deliver};
+use gossip::message::JoinRequest;
+use simulation::clock::Clock;
+
+static EPOCH: u64 = 1_400_000_000_000;
+
+struct Node {
+    engine: Engine,
+    clock: Clock
+}
+
+impl Node {
+    fn new(clock: Clock) -> Node {
+        let mut engine = Engine::new(Uuid::new_v4(), Config::new());
+        engine.handle(WallClock(clock.wall(EPOCH, 0)), 0);
+        Node {
+            engine: engine,
+            clock: clock
+        }
+    }
+
+    /// Everything happens at the true `time`, the engine only sees it's own
+    /// clock.
+    fn handle(&mut self, input: Input, time: u64) -> Vec<Output> {
+        let now = self.clock.now(time);
+        self.engine.handle(WallClock(self.clock.wall(EPOCH, time)), now);
+        self.engine.handle(input, now)
+    }
+}
+
+fn relayed(outputs: Vec<Output>) -> Broadcast {
+    for output in outputs.move_iter() {
+        match output {
+            Relay(broadcast) => return broadcast,
+            _ => {}
+        }
+    }
+    fail!("nothing was relayed");
+}
+
+fn delivered(outputs: &Vec<Output>) -> bool {
+    outputs.iter().any(|output| match *output {
+        Deliver(_) => true,
+        _ => false
+    })
+}
+
+#[test]
+fn deadlines_hold_under_skew() {
+    let mut origin = Node::new(Clock::skewed(-2_000, -200));
+    let mut receiver = Node::new(Clock::skewed(30_000, 500));
+    origin.handle(Received(JoinRequest(Peer::empty())), 0);
+
+    // Five seconds to live on the origin's clock, reaching the receiver
+    // `transit` milliseconds after being published at `time`.
+    let mut send = |time: u64, transit: u64| {
+        let mut broadcast = Broadcast::new(vec![1u8]).unwrap();
+        broadcast.set_deadline(origin.clock.wall(EPOCH, time) + 5_000);
+        let broadcast = relayed(origin.handle(Publish(broadcast), time));
+        delivered(&receiver.handle(Relayed(broadcast), time + transit))
+    };
+
+    assert!(send(1_000, 50));
+    assert!(send(60_000, 1_000));
+    assert!(!send(120_000, 10_000));
+    assert!(send(600_000, 4_000));
+}
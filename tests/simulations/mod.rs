@@ -1 +1 @@
-
+mod clock_skew;
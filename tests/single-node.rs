@@ -0,0 +1,19 @@
+extern crate gossip;
+
+use gossip::{Node, Broadcast};
+
+#[test]
+fn single_node_cluster() {
+    let mut node = Node::new();
+    let mut incoming = node.incoming();
+
+    for i in range(0u8, 3) {
+        node.broadcast(Broadcast::new(vec![1u8, i]).unwrap()).unwrap();
+    }
+
+    for i in range(0u8, 3) {
+        let (broadcast, res) = incoming.next().unwrap();
+        assert_eq!(broadcast.as_bytes(), &[1u8, i]);
+        res.ok().unwrap();
+    }
+}